@@ -8,6 +8,7 @@ use std::sync::mpsc;
 use std::sync::{Arc, Mutex, RwLock};
 
 use futures::future::{self, Either};
+use futures::sync::oneshot;
 use futures::{Future, Stream};
 
 use hyper::{self, Chunk, Headers, Method, StatusCode};
@@ -19,12 +20,17 @@ use request::actions::ActionBody;
 use request::drive::PatchDrivePayload;
 use request::{GenerateHyperResponse, IntoParsedRequest, ParsedRequest};
 use sys_util::EventFd;
+use vmm::vmm_config::balloon::{BalloonConfig, BalloonUpdateConfig};
 use vmm::vmm_config::boot_source::BootSourceConfig;
+use vmm::vmm_config::coredump::CoreDumpConfig;
 use vmm::vmm_config::drive::BlockDeviceConfig;
 use vmm::vmm_config::instance_info::InstanceInfo;
 use vmm::vmm_config::logger::LoggerConfig;
 use vmm::vmm_config::machine_config::VmConfig;
+use vmm::vmm_config::migration::MigrationConfig;
 use vmm::vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceUpdateConfig};
+use vmm::vmm_config::snapshot::SnapshotConfig;
+use vmm::vmm_config::vm_state::VmStateConfig;
 #[cfg(feature = "vsock")]
 use vmm::vmm_config::vsock::VsockDeviceConfig;
 use vmm::VmmAction;
@@ -267,6 +273,32 @@ fn parse_logger_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'
     }
 }
 
+// Turns a PUT /coredump HTTP request into a ParsedRequest.
+fn parse_coredump_req<'a>(
+    path: &'a str,
+    method: Method,
+    body: &Chunk,
+) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+
+    match path_tokens[1..].len() {
+        0 if method == Method::Put => {
+            METRICS.put_api_requests.coredump_count.inc();
+            Ok(serde_json::from_slice::<CoreDumpConfig>(body)
+                .map_err(|e| {
+                    METRICS.put_api_requests.coredump_fails.inc();
+                    Error::SerdeJson(e)
+                })?
+                .into_parsed_request(None, method)
+                .map_err(|s| {
+                    METRICS.put_api_requests.coredump_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
 // Turns a GET/PUT /machine-config HTTP request into a ParsedRequest
 fn parse_machine_config_req<'a>(
     path: &'a str,
@@ -283,6 +315,14 @@ fn parse_machine_config_req<'a>(
                 mem_size_mib: None,
                 ht_enabled: None,
                 cpu_template: None,
+                on_poweroff: None,
+                on_reboot: None,
+                on_panic: None,
+                mem_backing: None,
+                vcpu_affinity: None,
+                vcpu_scheduling: None,
+                vmm_affinity: None,
+                vmm_scheduling: None,
             };
             Ok(empty_machine_config
                 .into_parsed_request(None, method)
@@ -309,6 +349,57 @@ fn parse_machine_config_req<'a>(
     }
 }
 
+// Turns a GET/PUT/PATCH /balloon or a GET /balloon/statistics HTTP request into a ParsedRequest.
+fn parse_balloon_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+
+    match path_tokens[1..].len() {
+        0 if method == Method::Get => {
+            METRICS.get_api_requests.balloon_count.inc();
+            let (sender, receiver) = oneshot::channel();
+            Ok(ParsedRequest::Sync(
+                VmmAction::GetBalloonConfig(sender),
+                receiver,
+            ))
+        }
+        1 if method == Method::Get && path_tokens[1] == "statistics" => {
+            METRICS.get_api_requests.balloon_count.inc();
+            let (sender, receiver) = oneshot::channel();
+            Ok(ParsedRequest::Sync(
+                VmmAction::GetBalloonStats(sender),
+                receiver,
+            ))
+        }
+        0 if method == Method::Put => {
+            METRICS.put_api_requests.balloon_count.inc();
+            Ok(serde_json::from_slice::<BalloonConfig>(body)
+                .map_err(|e| {
+                    METRICS.put_api_requests.balloon_fails.inc();
+                    Error::SerdeJson(e)
+                })?
+                .into_parsed_request(None, method)
+                .map_err(|s| {
+                    METRICS.put_api_requests.balloon_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
+        0 if method == Method::Patch => {
+            METRICS.patch_api_requests.balloon_count.inc();
+            Ok(serde_json::from_slice::<BalloonUpdateConfig>(body)
+                .map_err(|e| {
+                    METRICS.patch_api_requests.balloon_fails.inc();
+                    Error::SerdeJson(e)
+                })?
+                .into_parsed_request(None, method)
+                .map_err(|s| {
+                    METRICS.patch_api_requests.balloon_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
 // Turns a GET/PUT /network-interfaces HTTP request into a ParsedRequest
 fn parse_netif_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'a, ParsedRequest> {
     let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
@@ -351,6 +442,88 @@ fn parse_netif_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'a
     }
 }
 
+// Turns a PATCH /vm HTTP request into a ParsedRequest.
+fn parse_vm_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+
+    match path_tokens[1..].len() {
+        1 if method == Method::Get && path_tokens[1] == "config" => {
+            METRICS.get_api_requests.vm_config_count.inc();
+            let (sender, receiver) = oneshot::channel();
+            Ok(ParsedRequest::Sync(
+                VmmAction::GetVmmConfiguration(sender),
+                receiver,
+            ))
+        }
+        0 if method == Method::Patch => {
+            METRICS.patch_api_requests.vm_count.inc();
+            Ok(serde_json::from_slice::<VmStateConfig>(body)
+                .map_err(|e| {
+                    METRICS.patch_api_requests.vm_fails.inc();
+                    Error::SerdeJson(e)
+                })?
+                .into_parsed_request(None, method)
+                .map_err(|s| {
+                    METRICS.patch_api_requests.vm_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
+// Turns a PUT /migration/send or /migration/receive HTTP request into a ParsedRequest.
+fn parse_migration_req<'a>(
+    path: &'a str,
+    method: Method,
+    body: &Chunk,
+) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+
+    match path_tokens[1..].len() {
+        1 if method == Method::Put && (path_tokens[1] == "send" || path_tokens[1] == "receive") => {
+            METRICS.put_api_requests.migration_count.inc();
+            Ok(serde_json::from_slice::<MigrationConfig>(body)
+                .map_err(|e| {
+                    METRICS.put_api_requests.migration_fails.inc();
+                    Error::SerdeJson(e)
+                })?
+                .into_parsed_request(Some(path_tokens[1].to_string()), method)
+                .map_err(|s| {
+                    METRICS.put_api_requests.migration_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
+// Turns a PUT /snapshot/create or /snapshot/load HTTP request into a ParsedRequest.
+fn parse_snapshot_req<'a>(
+    path: &'a str,
+    method: Method,
+    body: &Chunk,
+) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+
+    match path_tokens[1..].len() {
+        1 if method == Method::Put && (path_tokens[1] == "create" || path_tokens[1] == "load") => {
+            METRICS.put_api_requests.snapshot_count.inc();
+            Ok(serde_json::from_slice::<SnapshotConfig>(body)
+                .map_err(|e| {
+                    METRICS.put_api_requests.snapshot_fails.inc();
+                    Error::SerdeJson(e)
+                })?
+                .into_parsed_request(Some(path_tokens[1].to_string()), method)
+                .map_err(|s| {
+                    METRICS.put_api_requests.snapshot_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
 #[cfg(feature = "vsock")]
 // Turns a GET/PUT /vsocks HTTP request into a ParsedRequest.
 fn parse_vsocks_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'a, ParsedRequest> {
@@ -412,12 +585,17 @@ fn parse_request<'a>(method: Method, path: &'a str, body: &Chunk) -> Result<'a,
 
     match path_tokens[0] {
         "actions" => parse_actions_req(path, method, body),
+        "balloon" => parse_balloon_req(path, method, body),
         "boot-source" => parse_boot_source_req(path, method, body),
+        "coredump" => parse_coredump_req(path, method, body),
         "drives" => parse_drives_req(path, method, body),
         "logger" => parse_logger_req(path, method, body),
         "machine-config" => parse_machine_config_req(path, method, body),
+        "migration" => parse_migration_req(path, method, body),
         "network-interfaces" => parse_netif_req(path, method, body),
         "mmds" => parse_mmds_request(path, method, body),
+        "snapshot" => parse_snapshot_req(path, method, body),
+        "vm" => parse_vm_req(path, method, body),
         #[cfg(feature = "vsock")]
         "vsocks" => parse_vsocks_req(path, method, body),
         _ => Err(Error::InvalidPathMethod(path, method)),
@@ -578,7 +756,7 @@ impl hyper::server::Service for ApiServerHttpService {
                         let path_copy = path.clone();
                         let body_desc = match method_copy {
                             Method::Get => None,
-                            _ => Some(String::from_utf8_lossy(&b.to_vec()).to_string()),
+                            _ => Some(redact_secrets(&b)),
                         };
 
                         // We need to clone the description of the request because these are moved
@@ -641,6 +819,51 @@ fn log_received_api_request(api_description: String) {
     info!("The API server received a {}.", api_description);
 }
 
+/// Helper function for keeping secrets, such as the encryption keys of drives, out of the
+/// request bodies written to the log.
+fn redact_secrets(body: &[u8]) -> String {
+    let redacted = String::from("<redacted>");
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(mut value) => {
+            if redact_encryption_keys(&mut value) {
+                value.to_string()
+            } else {
+                String::from_utf8_lossy(body).to_string()
+            }
+        }
+        // A body which is not valid JSON could still hold a key.
+        Err(_) if str::from_utf8(body).map_or(true, |body| body.contains("encryption")) => redacted,
+        Err(_) => String::from_utf8_lossy(body).to_string(),
+    }
+}
+
+/// Replaces the key of every `encryption` object found at any depth of `value`, such as the
+/// ones in the `encryption_keys` of snapshot and migration requests. Returns whether any key
+/// was replaced.
+fn redact_encryption_keys(value: &mut serde_json::Value) -> bool {
+    match *value {
+        serde_json::Value::Object(ref mut object) => {
+            let mut redacted = false;
+            for (name, field) in object.iter_mut() {
+                if name == "encryption" {
+                    if let Some(key) = field.get_mut("key") {
+                        *key = serde_json::Value::String(String::from("<redacted>"));
+                        redacted = true;
+                    }
+                }
+                redacted |= redact_encryption_keys(field);
+            }
+            redacted
+        }
+        serde_json::Value::Array(ref mut array) => {
+            array.iter_mut().fold(false, |redacted, item| {
+                redact_encryption_keys(item) || redacted
+            })
+        }
+        _ => false,
+    }
+}
+
 /// Helper function for metric-logging purposes on API requests.
 ///
 /// # Arguments
@@ -673,6 +896,10 @@ mod tests {
     use futures::sync::oneshot;
     use hyper::header::{ContentType, Headers};
     use hyper::Body;
+    use vmm::vmm_config::drive::{
+        CacheType, DriveEncryptionKey, EncryptionConfig, ImageFormatType, IoEngine, NbdConfig,
+        OverlayConfig, VerityConfig,
+    };
     use vmm::vmm_config::logger::LoggerLevel;
     use vmm::vmm_config::machine_config::CpuFeaturesTemplate;
     use vmm::VmmAction;
@@ -982,6 +1209,18 @@ mod tests {
             partuuid: None,
             is_read_only: true,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         match drive_desc.into_parsed_request(Some(String::from("id_1")), Method::Put) {
@@ -992,6 +1231,351 @@ mod tests {
             _ => assert!(false),
         }
 
+        // PUT with the io_uring engine.
+        let json = "{
+                \"drive_id\": \"id_1\",
+                \"path_on_host\": \"/foo/bar\",
+                \"is_root_device\": true,
+                \"is_read_only\": true,
+                \"io_engine\": \"Async\"
+              }";
+        let drive_desc = BlockDeviceConfig {
+            drive_id: String::from("id_1"),
+            path_on_host: PathBuf::from(String::from("/foo/bar")),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: true,
+            rate_limiter: None,
+            io_engine: Some(IoEngine::Async),
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+        match drive_desc.into_parsed_request(Some(String::from("id_1")), Method::Put) {
+            Ok(pr) => match parse_drives_req(valid_drive_path, Method::Put, &Chunk::from(json)) {
+                Ok(pr_drive) => assert!(pr.eq(&pr_drive)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // PUT with a qcow2 image.
+        let json = "{
+                \"drive_id\": \"id_1\",
+                \"path_on_host\": \"/foo/bar.qcow2\",
+                \"is_root_device\": false,
+                \"is_read_only\": false,
+                \"image_format\": \"Qcow2\"
+              }";
+        let drive_desc = BlockDeviceConfig {
+            drive_id: String::from("id_1"),
+            path_on_host: PathBuf::from(String::from("/foo/bar.qcow2")),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: Some(ImageFormatType::Qcow2),
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+        match drive_desc.into_parsed_request(Some(String::from("id_1")), Method::Put) {
+            Ok(pr) => match parse_drives_req(valid_drive_path, Method::Put, &Chunk::from(json)) {
+                Ok(pr_drive) => assert!(pr.eq(&pr_drive)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // PUT with an overlay.
+        let json = "{
+                \"drive_id\": \"id_1\",
+                \"path_on_host\": \"/foo/rootfs.ext4\",
+                \"is_root_device\": true,
+                \"is_read_only\": false,
+                \"overlay\": {
+                    \"path_on_host\": \"/foo/overlay\"
+                }
+              }";
+        let drive_desc = BlockDeviceConfig {
+            drive_id: String::from("id_1"),
+            path_on_host: PathBuf::from(String::from("/foo/rootfs.ext4")),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: Some(OverlayConfig {
+                path_on_host: Some(PathBuf::from(String::from("/foo/overlay"))),
+            }),
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+        match drive_desc.into_parsed_request(Some(String::from("id_1")), Method::Put) {
+            Ok(pr) => match parse_drives_req(valid_drive_path, Method::Put, &Chunk::from(json)) {
+                Ok(pr_drive) => assert!(pr.eq(&pr_drive)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // PUT with a cache type.
+        let json = "{
+                \"drive_id\": \"id_1\",
+                \"path_on_host\": \"/foo/bar\",
+                \"is_root_device\": false,
+                \"is_read_only\": false,
+                \"cache_type\": \"None\"
+              }";
+        let drive_desc = BlockDeviceConfig {
+            drive_id: String::from("id_1"),
+            path_on_host: PathBuf::from(String::from("/foo/bar")),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: Some(CacheType::None),
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+        match drive_desc.into_parsed_request(Some(String::from("id_1")), Method::Put) {
+            Ok(pr) => match parse_drives_req(valid_drive_path, Method::Put, &Chunk::from(json)) {
+                Ok(pr_drive) => assert!(pr.eq(&pr_drive)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // PUT with multiple queues.
+        let json = "{
+                \"drive_id\": \"id_1\",
+                \"path_on_host\": \"/foo/bar\",
+                \"is_root_device\": false,
+                \"is_read_only\": false,
+                \"num_queues\": 4,
+                \"queue_size\": 512
+              }";
+        let drive_desc = BlockDeviceConfig {
+            drive_id: String::from("id_1"),
+            path_on_host: PathBuf::from(String::from("/foo/bar")),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: Some(4),
+            queue_size: Some(512),
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+        match drive_desc.into_parsed_request(Some(String::from("id_1")), Method::Put) {
+            Ok(pr) => match parse_drives_req(valid_drive_path, Method::Put, &Chunk::from(json)) {
+                Ok(pr_drive) => assert!(pr.eq(&pr_drive)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // PUT with a hash tree.
+        let json = "{
+                \"drive_id\": \"id_1\",
+                \"path_on_host\": \"/foo/bar\",
+                \"is_root_device\": true,
+                \"is_read_only\": true,
+                \"verity\": {
+                    \"hash_tree_path\": \"/foo/hash_tree\",
+                    \"root_hash\": \"0123\"
+                }
+              }";
+        let drive_desc = BlockDeviceConfig {
+            drive_id: String::from("id_1"),
+            path_on_host: PathBuf::from(String::from("/foo/bar")),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: true,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: Some(VerityConfig {
+                hash_tree_path: PathBuf::from(String::from("/foo/hash_tree")),
+                root_hash: String::from("0123"),
+                salt: None,
+            }),
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+        match drive_desc.into_parsed_request(Some(String::from("id_1")), Method::Put) {
+            Ok(pr) => match parse_drives_req(valid_drive_path, Method::Put, &Chunk::from(json)) {
+                Ok(pr_drive) => assert!(pr.eq(&pr_drive)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // PUT with an encryption key.
+        let json = "{
+                \"drive_id\": \"id_1\",
+                \"path_on_host\": \"/foo/bar\",
+                \"is_root_device\": true,
+                \"is_read_only\": false,
+                \"encryption\": {
+                    \"key\": \"0123\"
+                }
+              }";
+        let drive_desc = BlockDeviceConfig {
+            drive_id: String::from("id_1"),
+            path_on_host: PathBuf::from(String::from("/foo/bar")),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: Some(EncryptionConfig {
+                key: String::from("0123"),
+            }),
+            nbd: None,
+            vhost_user: None,
+        };
+        match drive_desc.into_parsed_request(Some(String::from("id_1")), Method::Put) {
+            Ok(pr) => match parse_drives_req(valid_drive_path, Method::Put, &Chunk::from(json)) {
+                Ok(pr_drive) => assert!(pr.eq(&pr_drive)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // PUT with an NBD server.
+        let json = "{
+                \"drive_id\": \"id_1\",
+                \"path_on_host\": \"/foo/nbd.sock\",
+                \"is_root_device\": true,
+                \"is_read_only\": false,
+                \"nbd\": {
+                    \"export_name\": \"disk\"
+                }
+              }";
+        let drive_desc = BlockDeviceConfig {
+            drive_id: String::from("id_1"),
+            path_on_host: PathBuf::from(String::from("/foo/nbd.sock")),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: Some(NbdConfig {
+                export_name: Some(String::from("disk")),
+            }),
+            vhost_user: None,
+        };
+        match drive_desc.into_parsed_request(Some(String::from("id_1")), Method::Put) {
+            Ok(pr) => match parse_drives_req(valid_drive_path, Method::Put, &Chunk::from(json)) {
+                Ok(pr_drive) => assert!(pr.eq(&pr_drive)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // PUT with a vhost-user back-end.
+        let json = "{
+                \"drive_id\": \"id_1\",
+                \"path_on_host\": \"/foo/vhost-user-blk.sock\",
+                \"is_root_device\": true,
+                \"is_read_only\": false,
+                \"num_queues\": 2,
+                \"vhost_user\": true
+              }";
+        let drive_desc = BlockDeviceConfig {
+            drive_id: String::from("id_1"),
+            path_on_host: PathBuf::from(String::from("/foo/vhost-user-blk.sock")),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: Some(2),
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: Some(true),
+        };
+        match drive_desc.into_parsed_request(Some(String::from("id_1")), Method::Put) {
+            Ok(pr) => match parse_drives_req(valid_drive_path, Method::Put, &Chunk::from(json)) {
+                Ok(pr_drive) => assert!(pr.eq(&pr_drive)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
         // Error Cases
         // Test Case for invalid payload (id from path does not match the id from the body).
         let expected_error = Err(Error::Generic(
@@ -1148,6 +1732,14 @@ mod tests {
             mem_size_mib: Some(1025),
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            on_poweroff: None,
+            on_reboot: None,
+            on_panic: None,
+            mem_backing: None,
+            vcpu_affinity: None,
+            vcpu_scheduling: None,
+            vmm_affinity: None,
+            vmm_scheduling: None,
         };
 
         match vm_config.into_parsed_request(None, Method::Put) {
@@ -1345,6 +1937,290 @@ mod tests {
         assert!(parse_mmds_request(path, Method::Get, &body) == expected_err);
     }
 
+    #[test]
+    fn test_parse_vm_req() {
+        let path = "/vm";
+        let body: Chunk = Chunk::from("{ \"state\": \"Paused\" }");
+
+        // PATCH
+        let (sender, receiver) = oneshot::channel();
+        match parse_vm_req(path, Method::Patch, &body) {
+            Ok(parsed_req) => {
+                assert!(parsed_req.eq(&ParsedRequest::Sync(VmmAction::PauseVm(sender), receiver)))
+            }
+            _ => assert!(false),
+        }
+        let body: Chunk = Chunk::from("{ \"state\": \"Resumed\" }");
+        let (sender, receiver) = oneshot::channel();
+        match parse_vm_req(path, Method::Patch, &body) {
+            Ok(parsed_req) => {
+                assert!(parsed_req.eq(&ParsedRequest::Sync(VmmAction::ResumeVm(sender), receiver)))
+            }
+            _ => assert!(false),
+        }
+
+        // Error cases
+        // Error Case: Invalid payload (unknown state).
+        let body: Chunk = Chunk::from("{ \"state\": \"Stopped\" }");
+        if let Err(Error::SerdeJson(e)) = parse_vm_req(path, Method::Patch, &body) {
+            assert!(e.is_data());
+        } else {
+            assert!(false);
+        }
+
+        // Error Case: Invalid method.
+        let expected_err = Err(Error::InvalidPathMethod(path, Method::Put));
+        assert!(parse_vm_req(path, Method::Put, &body) == expected_err);
+
+        // GET /vm/config
+        let (sender, receiver) = oneshot::channel();
+        match parse_vm_req("/vm/config", Method::Get, &Chunk::from("")) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::Sync(
+                VmmAction::GetVmmConfiguration(sender),
+                receiver
+            ))),
+            _ => assert!(false),
+        }
+
+        // Error Case: Invalid path.
+        let expected_err = Err(Error::InvalidPathMethod("/vm/foo", Method::Patch));
+        assert!(parse_vm_req("/vm/foo", Method::Patch, &body) == expected_err);
+        let expected_err = Err(Error::InvalidPathMethod("/vm/config", Method::Put));
+        assert!(parse_vm_req("/vm/config", Method::Put, &body) == expected_err);
+    }
+
+    #[test]
+    fn test_parse_balloon_req() {
+        let path = "/balloon";
+
+        // PUT
+        let body: Chunk = Chunk::from(
+            "{ \"amount_mib\": 64, \"deflate_on_oom\": true, \"free_page_reporting\": true }",
+        );
+        let config = BalloonConfig {
+            amount_mib: 64,
+            deflate_on_oom: true,
+            stats_polling_interval_s: 0,
+            free_page_reporting: true,
+        };
+        let (sender, receiver) = oneshot::channel();
+        match parse_balloon_req(path, Method::Put, &body) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::Sync(
+                VmmAction::InsertBalloonDevice(config, sender),
+                receiver
+            ))),
+            _ => assert!(false),
+        }
+
+        // PATCH
+        let body: Chunk = Chunk::from("{ \"amount_mib\": 32 }");
+        let (sender, receiver) = oneshot::channel();
+        match parse_balloon_req(path, Method::Patch, &body) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::Sync(
+                VmmAction::UpdateBalloon(BalloonUpdateConfig { amount_mib: 32 }, sender),
+                receiver
+            ))),
+            _ => assert!(false),
+        }
+
+        // GET
+        let (sender, receiver) = oneshot::channel();
+        match parse_balloon_req(path, Method::Get, &Chunk::from("")) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::Sync(
+                VmmAction::GetBalloonConfig(sender),
+                receiver
+            ))),
+            _ => assert!(false),
+        }
+        let (sender, receiver) = oneshot::channel();
+        match parse_balloon_req("/balloon/statistics", Method::Get, &Chunk::from("")) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::Sync(
+                VmmAction::GetBalloonStats(sender),
+                receiver
+            ))),
+            _ => assert!(false),
+        }
+
+        // Error cases
+        // Error Case: Invalid payload (only the size can be changed).
+        let body: Chunk = Chunk::from("{ \"amount_mib\": 32, \"deflate_on_oom\": true }");
+        if let Err(Error::SerdeJson(e)) = parse_balloon_req(path, Method::Patch, &body) {
+            assert!(e.is_data());
+        } else {
+            assert!(false);
+        }
+
+        // Error Case: Invalid path.
+        let expected_err = Err(Error::InvalidPathMethod("/balloon/statistics", Method::Put));
+        assert!(parse_balloon_req("/balloon/statistics", Method::Put, &body) == expected_err);
+        let expected_err = Err(Error::InvalidPathMethod("/balloon/foo", Method::Get));
+        assert!(parse_balloon_req("/balloon/foo", Method::Get, &body) == expected_err);
+    }
+
+    #[test]
+    fn test_parse_migration_req() {
+        let body: Chunk = Chunk::from("{ \"socket_path\": \"/foo/migration.sock\" }");
+        let config = MigrationConfig {
+            socket_path: PathBuf::from("/foo/migration.sock"),
+            encryption_keys: None,
+        };
+
+        // PUT send
+        let (sender, receiver) = oneshot::channel();
+        match parse_migration_req("/migration/send", Method::Put, &body) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::Sync(
+                VmmAction::SendMigration(config.clone(), sender),
+                receiver
+            ))),
+            _ => assert!(false),
+        }
+
+        // PUT receive
+        let (sender, receiver) = oneshot::channel();
+        match parse_migration_req("/migration/receive", Method::Put, &body) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::Sync(
+                VmmAction::ReceiveMigration(config, sender),
+                receiver
+            ))),
+            _ => assert!(false),
+        }
+
+        // Error cases
+        // Error Case: Invalid payload.
+        let invalid_body: Chunk = Chunk::from("{ \"path\": \"/foo/migration.sock\" }");
+        if let Err(Error::SerdeJson(e)) =
+            parse_migration_req("/migration/send", Method::Put, &invalid_body)
+        {
+            assert!(e.is_data());
+        } else {
+            assert!(false);
+        }
+
+        // Error Case: Invalid method.
+        let expected_err = Err(Error::InvalidPathMethod("/migration/send", Method::Get));
+        assert!(parse_migration_req("/migration/send", Method::Get, &body) == expected_err);
+
+        // Error Case: Invalid path.
+        let expected_err = Err(Error::InvalidPathMethod("/migration", Method::Put));
+        assert!(parse_migration_req("/migration", Method::Put, &body) == expected_err);
+        let expected_err = Err(Error::InvalidPathMethod("/migration/foo", Method::Put));
+        assert!(parse_migration_req("/migration/foo", Method::Put, &body) == expected_err);
+    }
+
+    #[test]
+    fn test_parse_snapshot_req() {
+        let body: Chunk = Chunk::from(
+            "{ \"snapshot_path\": \"/foo/snapshot\", \"mem_file_path\": \"/foo/mem\" }",
+        );
+        let config = SnapshotConfig {
+            snapshot_path: PathBuf::from("/foo/snapshot"),
+            mem_file_path: PathBuf::from("/foo/mem"),
+            encryption_keys: None,
+        };
+
+        // PUT create
+        let (sender, receiver) = oneshot::channel();
+        match parse_snapshot_req("/snapshot/create", Method::Put, &body) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::Sync(
+                VmmAction::CreateSnapshot(config.clone(), sender),
+                receiver
+            ))),
+            _ => assert!(false),
+        }
+
+        // PUT load
+        let (sender, receiver) = oneshot::channel();
+        match parse_snapshot_req("/snapshot/load", Method::Put, &body) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::Sync(
+                VmmAction::LoadSnapshot(config.clone(), sender),
+                receiver
+            ))),
+            _ => assert!(false),
+        }
+
+        // PUT load with the keys of the encrypted drives.
+        let body: Chunk = Chunk::from(
+            "{ \"snapshot_path\": \"/foo/snapshot\", \"mem_file_path\": \"/foo/mem\", \
+             \"encryption_keys\": [{ \"drive_id\": \"data\", \"encryption\": { \"key\": \"00\" } }] }",
+        );
+        let config = SnapshotConfig {
+            encryption_keys: Some(vec![DriveEncryptionKey {
+                drive_id: String::from("data"),
+                encryption: EncryptionConfig {
+                    key: String::from("00"),
+                },
+            }]),
+            ..config
+        };
+        let (sender, receiver) = oneshot::channel();
+        match parse_snapshot_req("/snapshot/load", Method::Put, &body) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::Sync(
+                VmmAction::LoadSnapshot(config, sender),
+                receiver
+            ))),
+            _ => assert!(false),
+        }
+
+        // Error cases
+        // Error Case: Invalid payload.
+        let invalid_body: Chunk = Chunk::from("{ \"snapshot_path\": \"/foo/snapshot\" }");
+        if let Err(Error::SerdeJson(e)) =
+            parse_snapshot_req("/snapshot/create", Method::Put, &invalid_body)
+        {
+            assert!(e.is_data());
+        } else {
+            assert!(false);
+        }
+
+        // Error Case: Invalid method.
+        let expected_err = Err(Error::InvalidPathMethod("/snapshot/load", Method::Get));
+        assert!(parse_snapshot_req("/snapshot/load", Method::Get, &body) == expected_err);
+
+        // Error Case: Invalid path.
+        let expected_err = Err(Error::InvalidPathMethod("/snapshot", Method::Put));
+        assert!(parse_snapshot_req("/snapshot", Method::Put, &body) == expected_err);
+        let expected_err = Err(Error::InvalidPathMethod("/snapshot/foo", Method::Put));
+        assert!(parse_snapshot_req("/snapshot/foo", Method::Put, &body) == expected_err);
+    }
+
+    #[test]
+    fn test_parse_coredump_req() {
+        let body: Chunk = Chunk::from("{ \"dump_path\": \"/foo/core\" }");
+        let config = CoreDumpConfig {
+            dump_path: PathBuf::from("/foo/core"),
+            resume: false,
+        };
+
+        // PUT
+        let (sender, receiver) = oneshot::channel();
+        match parse_coredump_req("/coredump", Method::Put, &body) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::Sync(
+                VmmAction::CreateCoreDump(config, sender),
+                receiver
+            ))),
+            _ => assert!(false),
+        }
+
+        // Error cases
+        // Error Case: Invalid payload.
+        let invalid_body: Chunk = Chunk::from("{ \"path\": \"/foo/core\" }");
+        if let Err(Error::SerdeJson(e)) =
+            parse_coredump_req("/coredump", Method::Put, &invalid_body)
+        {
+            assert!(e.is_data());
+        } else {
+            assert!(false);
+        }
+
+        // Error Case: Invalid method.
+        let expected_err = Err(Error::InvalidPathMethod("/coredump", Method::Get));
+        assert!(parse_coredump_req("/coredump", Method::Get, &body) == expected_err);
+
+        // Error Case: Invalid path.
+        let expected_err = Err(Error::InvalidPathMethod("/coredump/foo", Method::Put));
+        assert!(parse_coredump_req("/coredump/foo", Method::Put, &body) == expected_err);
+    }
+
     #[test]
     fn test_parse_request() {
         let body: Chunk = Chunk::from("{ \"foo\": \"bar\" }");
@@ -1394,6 +2270,37 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_redact_secrets() {
+        let body = "{ \"foo\": \"bar\" }";
+        assert_eq!(redact_secrets(body.as_bytes()), body);
+        let body = "{ \"drive_id\": \"1\", \"encryption\": { \"key\": \"0123\" } }";
+        assert_eq!(
+            redact_secrets(body.as_bytes()),
+            "{\"drive_id\":\"1\",\"encryption\":{\"key\":\"<redacted>\"}}"
+        );
+        let body = "{ \"encryption\": { \"key\": \"0123\" }";
+        assert_eq!(redact_secrets(body.as_bytes()), "<redacted>");
+
+        // The keys of the drives given when loading a snapshot.
+        let body = "{ \"snapshot_path\": \"foo\", \"mem_file_path\": \"bar\", \
+                    \"encryption_keys\": [ \
+                    { \"drive_id\": \"1\", \"encryption\": { \"key\": \"0123\" } }, \
+                    { \"drive_id\": \"2\", \"encryption\": { \"key\": \"4567\" } } ] }";
+        let redacted = redact_secrets(body.as_bytes());
+        assert!(!redacted.contains("0123"));
+        assert!(!redacted.contains("4567"));
+        assert_eq!(redacted.matches("<redacted>").count(), 2);
+        // The keys of the drives given when receiving a migration.
+        let body = "{ \"socket_path\": \"foo\", \"encryption_keys\": [ \
+                    { \"drive_id\": \"1\", \"encryption\": { \"key\": \"0123\" } } ] }";
+        assert_eq!(
+            redact_secrets(body.as_bytes()),
+            "{\"encryption_keys\":[{\"drive_id\":\"1\",\"encryption\":{\"key\":\"<redacted>\"}}],\
+             \"socket_path\":\"foo\"}"
+        );
+    }
+
     #[test]
     fn test_describe() {
         let body: String = String::from("{ \"foo\": \"bar\" }");
@@ -0,0 +1,98 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+
+use futures::sync::oneshot;
+use hyper::{Method, Response, StatusCode};
+use serde::Serialize;
+use serde_json;
+
+use http_service::{json_fault_message, json_response};
+use request::{IntoParsedRequest, ParsedRequest};
+use vmm::vmm_config::balloon::{BalloonConfig, BalloonUpdateConfig};
+use vmm::VmmAction;
+
+// Both the balloon configuration and the statistics reported by the guest are sent back as they
+// are serialized.
+pub fn balloon_response<T: Serialize>(data: &T) -> Response {
+    match serde_json::to_string(data) {
+        Ok(body) => json_response(StatusCode::Ok, body),
+        Err(e) => json_response(
+            StatusCode::InternalServerError,
+            json_fault_message(e.to_string()),
+        ),
+    }
+}
+
+impl IntoParsedRequest for BalloonConfig {
+    fn into_parsed_request(
+        self,
+        _: Option<String>,
+        _: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        let (sender, receiver) = oneshot::channel();
+        Ok(ParsedRequest::Sync(
+            VmmAction::InsertBalloonDevice(self, sender),
+            receiver,
+        ))
+    }
+}
+
+impl IntoParsedRequest for BalloonUpdateConfig {
+    fn into_parsed_request(
+        self,
+        _: Option<String>,
+        _: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        let (sender, receiver) = oneshot::channel();
+        Ok(ParsedRequest::Sync(
+            VmmAction::UpdateBalloon(self, sender),
+            receiver,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_parsed_request() {
+        let body = BalloonConfig {
+            amount_mib: 64,
+            deflate_on_oom: true,
+            stats_polling_interval_s: 1,
+            free_page_reporting: true,
+        };
+        let (sender, receiver) = oneshot::channel();
+        assert!(body
+            .clone()
+            .into_parsed_request(None, Method::Put)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::InsertBalloonDevice(body, sender),
+                receiver
+            ))));
+
+        let body = BalloonUpdateConfig { amount_mib: 32 };
+        let (sender, receiver) = oneshot::channel();
+        assert!(body
+            .clone()
+            .into_parsed_request(None, Method::Patch)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::UpdateBalloon(body, sender),
+                receiver
+            ))));
+    }
+
+    #[test]
+    fn test_balloon_response() {
+        let body = BalloonConfig {
+            amount_mib: 64,
+            deflate_on_oom: false,
+            stats_polling_interval_s: 0,
+            free_page_reporting: false,
+        };
+        assert_eq!(balloon_response(&body).status(), StatusCode::Ok);
+    }
+}
@@ -0,0 +1,48 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+
+use futures::sync::oneshot;
+use hyper::Method;
+
+use request::{IntoParsedRequest, ParsedRequest};
+use vmm::vmm_config::coredump::CoreDumpConfig;
+use vmm::VmmAction;
+
+impl IntoParsedRequest for CoreDumpConfig {
+    fn into_parsed_request(
+        self,
+        _: Option<String>,
+        _: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        let (sender, receiver) = oneshot::channel();
+        Ok(ParsedRequest::Sync(
+            VmmAction::CreateCoreDump(self, sender),
+            receiver,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_into_parsed_request() {
+        let config = CoreDumpConfig {
+            dump_path: PathBuf::from("/foo/core"),
+            resume: true,
+        };
+        let (sender, receiver) = oneshot::channel();
+        assert!(config
+            .clone()
+            .into_parsed_request(None, Method::Put)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::CreateCoreDump(config, sender),
+                receiver
+            ))));
+    }
+}
@@ -244,6 +244,21 @@ mod tests {
         assert!(
             pdp.into_parsed_request(None, Method::Put) == Err(String::from("Invalid method PUT!"))
         );
+
+        // PATCH with an empty path, which detaches the disk image of the drive.
+        let mut payload_map = Map::<String, Value>::new();
+        payload_map.insert(String::from("drive_id"), Value::String(String::from("foo")));
+        payload_map.insert(String::from("path_on_host"), Value::String(String::new()));
+        let pdp = PatchDrivePayload {
+            fields: Value::Object(payload_map),
+        };
+        let (sender, receiver) = oneshot::channel();
+        assert!(pdp
+            .into_parsed_request(Some("foo".to_string()), Method::Patch)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::UpdateBlockDevicePath("foo".to_string(), String::new(), sender),
+                receiver
+            ))));
     }
 
     #[test]
@@ -255,6 +270,18 @@ mod tests {
             is_read_only: true,
             partuuid: None,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
         assert!(
             desc.into_parsed_request(Some(String::from("foo")), Method::Options)
@@ -269,6 +296,18 @@ mod tests {
             is_read_only: true,
             partuuid: None,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
         let same_desc = BlockDeviceConfig {
             drive_id: String::from("foo"),
@@ -277,6 +316,18 @@ mod tests {
             is_read_only: true,
             partuuid: None,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
         let (sender, receiver) = oneshot::channel();
         assert!(desc
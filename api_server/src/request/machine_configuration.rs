@@ -8,7 +8,7 @@ use hyper::{Method, Response, StatusCode};
 
 use http_service::json_response;
 use request::{GenerateHyperResponse, IntoParsedRequest, ParsedRequest};
-use vmm::vmm_config::machine_config::VmConfig;
+use vmm::vmm_config::machine_config::{LifecycleAction, MemoryBackingType, PanicAction, VmConfig};
 use vmm::VmmAction;
 
 impl GenerateHyperResponse for VmConfig {
@@ -19,12 +19,16 @@ impl GenerateHyperResponse for VmConfig {
         let cpu_template = self
             .cpu_template
             .map_or("Uninitialized".to_string(), |c| c.to_string());
+        let on_poweroff = self.on_poweroff.unwrap_or(LifecycleAction::Exit);
+        let on_reboot = self.on_reboot.unwrap_or(LifecycleAction::Exit);
+        let on_panic = self.on_panic.unwrap_or(PanicAction::Ignore);
+        let mem_backing = self.mem_backing.unwrap_or(MemoryBackingType::Anonymous);
 
         json_response(
             StatusCode::Ok,
             format!(
-                "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?},  \"ht_enabled\": {:?},  \"cpu_template\": {:?} }}",
-                vcpu_count, mem_size, ht_enabled, cpu_template
+                "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?},  \"ht_enabled\": {:?},  \"cpu_template\": {:?}, \"on_poweroff\": \"{}\", \"on_reboot\": \"{}\", \"on_panic\": \"{}\", \"mem_backing\": \"{}\" }}",
+                vcpu_count, mem_size, ht_enabled, cpu_template, on_poweroff, on_reboot, on_panic, mem_backing
             ),
         )
     }
@@ -47,6 +51,10 @@ impl IntoParsedRequest for VmConfig {
                     && self.mem_size_mib.is_none()
                     && self.cpu_template.is_none()
                     && self.ht_enabled.is_none()
+                    && self.on_poweroff.is_none()
+                    && self.on_reboot.is_none()
+                    && self.on_panic.is_none()
+                    && self.mem_backing.is_none()
                 {
                     return Err(String::from("Empty request."));
                 }
@@ -72,6 +80,14 @@ mod tests {
             mem_size_mib: Some(1024),
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            on_poweroff: None,
+            on_reboot: None,
+            on_panic: None,
+            mem_backing: None,
+            vcpu_affinity: None,
+            vcpu_scheduling: None,
+            vmm_affinity: None,
+            vmm_scheduling: None,
         };
         let (sender, receiver) = oneshot::channel();
         assert!(body
@@ -86,6 +102,14 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: None,
             cpu_template: None,
+            on_poweroff: None,
+            on_reboot: None,
+            on_panic: None,
+            mem_backing: None,
+            vcpu_affinity: None,
+            vcpu_scheduling: None,
+            vmm_affinity: None,
+            vmm_scheduling: None,
         };
         assert!(uninitialized
             .clone()
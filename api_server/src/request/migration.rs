@@ -0,0 +1,71 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+
+use futures::sync::oneshot;
+use hyper::Method;
+
+use request::{IntoParsedRequest, ParsedRequest};
+use vmm::vmm_config::migration::MigrationConfig;
+use vmm::VmmAction;
+
+impl IntoParsedRequest for MigrationConfig {
+    // The resource id selects the side of the migration: `send` or `receive`.
+    fn into_parsed_request(
+        self,
+        direction: Option<String>,
+        _: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        let (sender, receiver) = oneshot::channel();
+        match direction.as_ref().map(String::as_str) {
+            Some("send") => Ok(ParsedRequest::Sync(
+                VmmAction::SendMigration(self, sender),
+                receiver,
+            )),
+            Some("receive") => Ok(ParsedRequest::Sync(
+                VmmAction::ReceiveMigration(self, sender),
+                receiver,
+            )),
+            _ => Err(String::from("Invalid migration direction.")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn get_config() -> MigrationConfig {
+        MigrationConfig {
+            socket_path: PathBuf::from("/foo/migration.sock"),
+            encryption_keys: None,
+        }
+    }
+
+    #[test]
+    fn test_into_parsed_request() {
+        let (sender, receiver) = oneshot::channel();
+        assert!(get_config()
+            .into_parsed_request(Some(String::from("send")), Method::Put)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::SendMigration(get_config(), sender),
+                receiver
+            ))));
+
+        let (sender, receiver) = oneshot::channel();
+        assert!(get_config()
+            .into_parsed_request(Some(String::from("receive")), Method::Put)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::ReceiveMigration(get_config(), sender),
+                receiver
+            ))));
+
+        assert!(get_config()
+            .into_parsed_request(Some(String::from("foo")), Method::Put)
+            .is_err());
+        assert!(get_config().into_parsed_request(None, Method::Put).is_err());
+    }
+}
@@ -2,11 +2,17 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod actions;
+pub mod balloon;
 pub mod boot_source;
+pub mod coredump;
 pub mod drive;
 pub mod logger;
 pub mod machine_configuration;
+pub mod migration;
 pub mod net;
+pub mod snapshot;
+pub mod vm_config;
+pub mod vm_state;
 #[cfg(feature = "vsock")]
 pub mod vsock;
 
@@ -56,7 +62,10 @@ impl GenerateHyperResponse for result::Result<VmmData, VmmActionError> {
 impl GenerateHyperResponse for VmmData {
     fn generate_response(&self) -> hyper::Response {
         match *self {
+            VmmData::BalloonConfig(ref balloon_config) => balloon::balloon_response(balloon_config),
+            VmmData::BalloonStatistics(ref statistics) => balloon::balloon_response(statistics),
             VmmData::MachineConfiguration(ref machine_config) => machine_config.generate_response(),
+            VmmData::VmmConfiguration(ref vmm_config) => vmm_config.generate_response(),
             VmmData::Empty => empty_response(StatusCode::NoContent),
         }
     }
@@ -164,11 +173,36 @@ mod tests {
             "vcpu_count": 1,
             "mem_size_mib": 128,
             "ht_enabled": false,
-            "cpu_template": "Uninitialized"
+            "cpu_template": "Uninitialized",
+            "on_poweroff": "Exit",
+            "on_reboot": "Exit",
+            "on_panic": "Ignore",
+            "mem_backing": "Anonymous"
         }"#;
         let vm_config_json: serde_json::Value = serde_json::from_str(vm_config_json).unwrap();
         assert_eq!(get_body(hyper_resp).unwrap(), vm_config_json);
 
+        // Test OK response from VMM that contains the whole microVM configuration.
+        let vmm_config_json = r#"{
+            "boot-source": {"kernel_image_path": "/vmlinux", "boot_args": "console=ttyS0"},
+            "drives": [],
+            "machine-config": {
+                "vcpu_count": 2,
+                "mem_size_mib": 256,
+                "ht_enabled": false,
+                "on_poweroff": "Exit",
+                "on_reboot": "Exit"
+            },
+            "network-interfaces": []
+        }"#;
+        let vmm_resp = Ok(VmmData::VmmConfiguration(
+            serde_json::from_str(vmm_config_json).unwrap(),
+        ));
+        let hyper_resp = vmm_resp.generate_response();
+        assert_eq!(hyper_resp.status(), StatusCode::Ok);
+        let vmm_config_json: serde_json::Value = serde_json::from_str(vmm_config_json).unwrap();
+        assert_eq!(get_body(hyper_resp).unwrap(), vmm_config_json);
+
         // Tests Error Cases
         // Tests for BootSource Errors.
         let vmm_resp =
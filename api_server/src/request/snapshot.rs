@@ -0,0 +1,72 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+
+use futures::sync::oneshot;
+use hyper::Method;
+
+use request::{IntoParsedRequest, ParsedRequest};
+use vmm::vmm_config::snapshot::SnapshotConfig;
+use vmm::VmmAction;
+
+impl IntoParsedRequest for SnapshotConfig {
+    // The resource id selects the snapshot operation: `create` or `load`.
+    fn into_parsed_request(
+        self,
+        operation: Option<String>,
+        _: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        let (sender, receiver) = oneshot::channel();
+        match operation.as_ref().map(String::as_str) {
+            Some("create") => Ok(ParsedRequest::Sync(
+                VmmAction::CreateSnapshot(self, sender),
+                receiver,
+            )),
+            Some("load") => Ok(ParsedRequest::Sync(
+                VmmAction::LoadSnapshot(self, sender),
+                receiver,
+            )),
+            _ => Err(String::from("Invalid snapshot operation.")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn get_config() -> SnapshotConfig {
+        SnapshotConfig {
+            snapshot_path: PathBuf::from("/foo/snapshot"),
+            mem_file_path: PathBuf::from("/foo/mem"),
+            encryption_keys: None,
+        }
+    }
+
+    #[test]
+    fn test_into_parsed_request() {
+        let (sender, receiver) = oneshot::channel();
+        assert!(get_config()
+            .into_parsed_request(Some(String::from("create")), Method::Put)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::CreateSnapshot(get_config(), sender),
+                receiver
+            ))));
+
+        let (sender, receiver) = oneshot::channel();
+        assert!(get_config()
+            .into_parsed_request(Some(String::from("load")), Method::Put)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::LoadSnapshot(get_config(), sender),
+                receiver
+            ))));
+
+        assert!(get_config()
+            .into_parsed_request(Some(String::from("foo")), Method::Put)
+            .is_err());
+        assert!(get_config().into_parsed_request(None, Method::Put).is_err());
+    }
+}
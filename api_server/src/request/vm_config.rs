@@ -0,0 +1,21 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use hyper::{Response, StatusCode};
+use serde_json;
+
+use http_service::{json_fault_message, json_response};
+use request::GenerateHyperResponse;
+use vmm::vmm_config::config_file::VmmConfig;
+
+impl GenerateHyperResponse for VmmConfig {
+    fn generate_response(&self) -> Response {
+        match serde_json::to_string(self) {
+            Ok(body) => json_response(StatusCode::Ok, body),
+            Err(e) => json_response(
+                StatusCode::InternalServerError,
+                json_fault_message(e.to_string()),
+            ),
+        }
+    }
+}
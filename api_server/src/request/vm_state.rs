@@ -0,0 +1,56 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+
+use futures::sync::oneshot;
+use hyper::Method;
+
+use request::{IntoParsedRequest, ParsedRequest};
+use vmm::vmm_config::vm_state::{VmState, VmStateConfig};
+use vmm::VmmAction;
+
+impl IntoParsedRequest for VmStateConfig {
+    fn into_parsed_request(
+        self,
+        _: Option<String>,
+        _: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        let (sender, receiver) = oneshot::channel();
+        match self.state {
+            VmState::Paused => Ok(ParsedRequest::Sync(VmmAction::PauseVm(sender), receiver)),
+            VmState::Resumed => Ok(ParsedRequest::Sync(VmmAction::ResumeVm(sender), receiver)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_parsed_request() {
+        let desc = VmStateConfig {
+            state: VmState::Paused,
+        };
+        format!("{:?}", desc);
+        let (sender, receiver) = oneshot::channel();
+        assert!(desc
+            .into_parsed_request(None, Method::Patch)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::PauseVm(sender),
+                receiver
+            ))));
+
+        let desc = VmStateConfig {
+            state: VmState::Resumed,
+        };
+        let (sender, receiver) = oneshot::channel();
+        assert!(desc
+            .into_parsed_request(None, Method::Patch)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::ResumeVm(sender),
+                receiver
+            ))));
+    }
+}
@@ -48,6 +48,7 @@ fn get_fdt_addr(mem: &GuestMemory) -> usize {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use memory_model::MemoryBacking;
 
     #[test]
     fn test_regions_lt_1024gb() {
@@ -68,15 +69,18 @@ mod tests {
     #[test]
     fn test_get_fdt_addr() {
         let regions = arch_memory_regions(layout::FDT_MAX_SIZE - 0x1000);
-        let mem = GuestMemory::new(&regions).expect("Cannot initialize memory");
+        let mem =
+            GuestMemory::new(&regions, MemoryBacking::Anonymous).expect("Cannot initialize memory");
         assert_eq!(get_fdt_addr(&mem), layout::DRAM_MEM_START);
 
         let regions = arch_memory_regions(layout::FDT_MAX_SIZE);
-        let mem = GuestMemory::new(&regions).expect("Cannot initialize memory");
+        let mem =
+            GuestMemory::new(&regions, MemoryBacking::Anonymous).expect("Cannot initialize memory");
         assert_eq!(get_fdt_addr(&mem), layout::DRAM_MEM_START);
 
         let regions = arch_memory_regions(layout::FDT_MAX_SIZE + 0x1000);
-        let mem = GuestMemory::new(&regions).expect("Cannot initialize memory");
+        let mem =
+            GuestMemory::new(&regions, MemoryBacking::Anonymous).expect("Cannot initialize memory");
         assert_eq!(get_fdt_addr(&mem), 0x1000 + layout::DRAM_MEM_START);
     }
 }
@@ -178,6 +178,7 @@ fn add_e820_entry(
 mod tests {
     use super::*;
     use arch_gen::x86::bootparam::e820entry;
+    use memory_model::MemoryBacking;
 
     #[test]
     fn regions_lt_4gb() {
@@ -206,7 +207,7 @@ mod tests {
     #[test]
     fn test_system_configuration() {
         let no_vcpus = 4;
-        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let config_err = configure_system(&gm, GuestAddress(0), 0, 1);
         assert!(config_err.is_err());
         assert_eq!(
@@ -219,19 +220,19 @@ mod tests {
         // Now assigning some memory that falls before the 32bit memory hole.
         let mem_size = 128 << 20;
         let arch_mem_regions = arch_memory_regions(mem_size);
-        let gm = GuestMemory::new(&arch_mem_regions).unwrap();
+        let gm = GuestMemory::new(&arch_mem_regions, MemoryBacking::Anonymous).unwrap();
         configure_system(&gm, GuestAddress(0), 0, no_vcpus).unwrap();
 
         // Now assigning some memory that is equal to the start of the 32bit memory hole.
         let mem_size = 3328 << 20;
         let arch_mem_regions = arch_memory_regions(mem_size);
-        let gm = GuestMemory::new(&arch_mem_regions).unwrap();
+        let gm = GuestMemory::new(&arch_mem_regions, MemoryBacking::Anonymous).unwrap();
         configure_system(&gm, GuestAddress(0), 0, no_vcpus).unwrap();
 
         // Now assigning some memory that falls after the 32bit memory hole.
         let mem_size = 3330 << 20;
         let arch_mem_regions = arch_memory_regions(mem_size);
-        let gm = GuestMemory::new(&arch_mem_regions).unwrap();
+        let gm = GuestMemory::new(&arch_mem_regions, MemoryBacking::Anonymous).unwrap();
         configure_system(&gm, GuestAddress(0), 0, no_vcpus).unwrap();
     }
 
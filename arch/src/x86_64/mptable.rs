@@ -284,6 +284,7 @@ pub fn setup_mptable(mem: &GuestMemory, num_cpus: u8) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use memory_model::MemoryBacking;
 
     fn table_entry_size(type_: u8) -> usize {
         match u32::from(type_) {
@@ -299,8 +300,11 @@ mod tests {
     #[test]
     fn bounds_check() {
         let num_cpus = 4;
-        let mem =
-            GuestMemory::new(&[(GuestAddress(MPTABLE_START), compute_mp_size(num_cpus))]).unwrap();
+        let mem = GuestMemory::new(
+            &[(GuestAddress(MPTABLE_START), compute_mp_size(num_cpus))],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
 
         setup_mptable(&mem, num_cpus).unwrap();
     }
@@ -308,8 +312,11 @@ mod tests {
     #[test]
     fn bounds_check_fails() {
         let num_cpus = 4;
-        let mem = GuestMemory::new(&[(GuestAddress(MPTABLE_START), compute_mp_size(num_cpus) - 1)])
-            .unwrap();
+        let mem = GuestMemory::new(
+            &[(GuestAddress(MPTABLE_START), compute_mp_size(num_cpus) - 1)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
 
         assert!(setup_mptable(&mem, num_cpus).is_err());
     }
@@ -317,8 +324,11 @@ mod tests {
     #[test]
     fn mpf_intel_checksum() {
         let num_cpus = 1;
-        let mem =
-            GuestMemory::new(&[(GuestAddress(MPTABLE_START), compute_mp_size(num_cpus))]).unwrap();
+        let mem = GuestMemory::new(
+            &[(GuestAddress(MPTABLE_START), compute_mp_size(num_cpus))],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
 
         setup_mptable(&mem, num_cpus).unwrap();
 
@@ -334,8 +344,11 @@ mod tests {
     #[test]
     fn mpc_table_checksum() {
         let num_cpus = 4;
-        let mem =
-            GuestMemory::new(&[(GuestAddress(MPTABLE_START), compute_mp_size(num_cpus))]).unwrap();
+        let mem = GuestMemory::new(
+            &[(GuestAddress(MPTABLE_START), compute_mp_size(num_cpus))],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
 
         setup_mptable(&mem, num_cpus).unwrap();
 
@@ -365,10 +378,13 @@ mod tests {
 
     #[test]
     fn cpu_entry_count() {
-        let mem = GuestMemory::new(&[(
-            GuestAddress(MPTABLE_START),
-            compute_mp_size(MAX_SUPPORTED_CPUS as u8),
-        )])
+        let mem = GuestMemory::new(
+            &[(
+                GuestAddress(MPTABLE_START),
+                compute_mp_size(MAX_SUPPORTED_CPUS as u8),
+            )],
+            MemoryBacking::Anonymous,
+        )
         .unwrap();
 
         for i in 0..MAX_SUPPORTED_CPUS as u8 {
@@ -401,8 +417,11 @@ mod tests {
     #[test]
     fn cpu_entry_count_max() {
         let cpus = MAX_SUPPORTED_CPUS + 1;
-        let mem = GuestMemory::new(&[(GuestAddress(MPTABLE_START), compute_mp_size(cpus as u8))])
-            .unwrap();
+        let mem = GuestMemory::new(
+            &[(GuestAddress(MPTABLE_START), compute_mp_size(cpus as u8))],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
 
         let result = setup_mptable(&mem, cpus as u8).unwrap_err();
         assert_eq!(result, Error::TooManyCpus);
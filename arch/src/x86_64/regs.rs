@@ -288,10 +288,10 @@ fn create_msr_entries() -> Vec<kvm_msr_entry> {
 mod tests {
     use super::*;
     use kvm_ioctls::Kvm;
-    use memory_model::{GuestAddress, GuestMemory};
+    use memory_model::{GuestAddress, GuestMemory, MemoryBacking};
 
     fn create_guest_mem() -> GuestMemory {
-        GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap()
+        GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap()
     }
 
     fn read_u64(gm: &GuestMemory, offset: usize) -> u64 {
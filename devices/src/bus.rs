@@ -12,6 +12,10 @@ use std::collections::btree_map::BTreeMap;
 use std::fmt;
 use std::result;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use logger::metrics::LatencyHistogram;
+use logger::METRICS;
 
 /// Trait for devices that respond to reads or writes in an arbitrary address space.
 ///
@@ -68,13 +72,30 @@ impl PartialOrd for BusRange {
     }
 }
 
+#[derive(Clone)]
+struct BusEntry {
+    device: Arc<Mutex<BusDevice>>,
+    // How long the device takes to handle the accesses to its range, if the bus measures it.
+    latency: Option<Arc<LatencyHistogram>>,
+}
+
+impl BusEntry {
+    fn record_latency(&self, start: Option<Instant>) {
+        if let (Some(latency), Some(start)) = (self.latency.as_ref(), start) {
+            latency.record(start.elapsed());
+        }
+    }
+}
+
 /// A device container for routing reads and writes over some address space.
 ///
 /// This doesn't have any restrictions on what kind of device or address space this applies to. The
 /// only restriction is that no two devices can overlap in this address space.
 #[derive(Clone, Default)]
 pub struct Bus {
-    devices: BTreeMap<BusRange, Arc<Mutex<BusDevice>>>,
+    devices: BTreeMap<BusRange, BusEntry>,
+    // Prefix of the names under which the access latency of each range is reported.
+    latency_metrics: Option<&'static str>,
 }
 
 impl Bus {
@@ -82,29 +103,44 @@ impl Bus {
     pub fn new() -> Bus {
         Bus {
             devices: BTreeMap::new(),
+            latency_metrics: None,
         }
     }
 
-    fn first_before(&self, addr: u64) -> Option<(BusRange, &Mutex<BusDevice>)> {
+    /// Constructs a bus with an empty address space, which reports how long each device takes to
+    /// handle an access in the `bus_latency` metrics, as `<kind>_<base address>`.
+    pub fn with_latency_metrics(kind: &'static str) -> Bus {
+        Bus {
+            devices: BTreeMap::new(),
+            latency_metrics: Some(kind),
+        }
+    }
+
+    fn first_before(&self, addr: u64) -> Option<(BusRange, &BusEntry)> {
         // for when we switch to rustc 1.17: self.devices.range(..addr).iter().rev().next()
-        for (range, dev) in self.devices.iter().rev() {
+        for (range, entry) in self.devices.iter().rev() {
             if range.0 <= addr {
-                return Some((*range, dev));
+                return Some((*range, entry));
             }
         }
         None
     }
 
-    pub fn get_device(&self, addr: u64) -> Option<(u64, &Mutex<BusDevice>)> {
-        if let Some((BusRange(start, len), dev)) = self.first_before(addr) {
+    fn get_entry(&self, addr: u64) -> Option<(u64, &BusEntry)> {
+        if let Some((BusRange(start, len), entry)) = self.first_before(addr) {
             let offset = addr - start;
             if offset < len {
-                return Some((offset, dev));
+                return Some((offset, entry));
             }
         }
         None
     }
 
+    pub fn get_device(&self, addr: u64) -> Option<(u64, &Mutex<BusDevice>)> {
+        self.get_entry(addr)
+            .map(|(offset, entry)| (offset, entry.device.as_ref()))
+    }
+
     /// Puts the given device at the given address space.
     pub fn insert(&mut self, device: Arc<Mutex<BusDevice>>, base: u64, len: u64) -> Result<()> {
         if len == 0 {
@@ -128,7 +164,16 @@ impl Bus {
             }
         }
 
-        if self.devices.insert(BusRange(base, len), device).is_some() {
+        let latency = self.latency_metrics.map(|kind| {
+            METRICS
+                .bus_latency
+                .register(format!("{}_{:#x}", kind, base))
+        });
+        if self
+            .devices
+            .insert(BusRange(base, len), BusEntry { device, latency })
+            .is_some()
+        {
             return Err(Error::Overlap);
         }
 
@@ -139,11 +184,15 @@ impl Bus {
     ///
     /// Returns true on success, otherwise `data` is untouched.
     pub fn read(&self, addr: u64, data: &mut [u8]) -> bool {
-        if let Some((offset, dev)) = self.get_device(addr) {
+        if let Some((offset, entry)) = self.get_entry(addr) {
+            let start = entry.latency.as_ref().map(|_| Instant::now());
             // OK to unwrap as lock() failing is a serious error condition and should panic.
-            dev.lock()
+            entry
+                .device
+                .lock()
                 .expect("Failed to acquire device lock")
                 .read(offset, data);
+            entry.record_latency(start);
             true
         } else {
             false
@@ -154,11 +203,15 @@ impl Bus {
     ///
     /// Returns true on success, otherwise `data` is untouched.
     pub fn write(&self, addr: u64, data: &[u8]) -> bool {
-        if let Some((offset, dev)) = self.get_device(addr) {
+        if let Some((offset, entry)) = self.get_entry(addr) {
+            let start = entry.latency.as_ref().map(|_| Instant::now());
             // OK to unwrap as lock() failing is a serious error condition and should panic.
-            dev.lock()
+            entry
+                .device
+                .lock()
                 .expect("Failed to acquire device lock")
                 .write(offset, data);
+            entry.record_latency(start);
             true
         } else {
             false
@@ -241,6 +294,25 @@ mod tests {
         assert!(bus.write(0x15, &values));
     }
 
+    #[test]
+    fn bus_latency_metrics() {
+        let mut bus = Bus::with_latency_metrics("test");
+        let dummy = Arc::new(Mutex::new(DummyDevice));
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+        assert!(bus.read(0x10, &mut [0, 0, 0, 0]));
+        assert!(bus.write(0x11, &[0, 0, 0, 0]));
+        assert!(!bus.read(0x20, &mut [0, 0, 0, 0]));
+
+        let latency = METRICS.bus_latency.register("test_0x10".to_string());
+        assert_eq!(latency.count(), 2);
+
+        // A bus without latency metrics does not report anything.
+        let mut bus = Bus::new();
+        assert!(bus.insert(dummy.clone(), 0x30, 0x10).is_ok());
+        assert!(bus.read(0x30, &mut [0, 0, 0, 0]));
+        assert!(bus.devices.values().all(|entry| entry.latency.is_none()));
+    }
+
     #[test]
     fn busrange_cmp_and_clone() {
         assert_eq!(BusRange(0x10, 2), BusRange(0x10, 3));
@@ -6,8 +6,10 @@
 // found in the THIRD-PARTY file.
 
 mod i8042;
+mod pvpanic;
 mod serial;
 
 pub use self::i8042::Error as I8042DeviceError;
 pub use self::i8042::I8042Device;
+pub use self::pvpanic::{PvPanic, PVPANIC_CRASH_LOADED, PVPANIC_PANICKED};
 pub use self::serial::Serial;
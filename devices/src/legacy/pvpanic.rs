@@ -0,0 +1,138 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io;
+
+use logger::{Metric, METRICS};
+use sys_util::EventFd;
+
+use BusDevice;
+
+/// The guest kernel panicked.
+pub const PVPANIC_PANICKED: u8 = 1 << 0;
+/// The guest kernel crashed and loaded a crash kernel, which is about to take over.
+pub const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+const PVPANIC_SUPPORTED_EVENTS: u8 = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+
+/// A pvpanic device, through which the guest kernel reports that it panicked.
+///
+/// The device has a single byte-wide register. Reading it returns the events the device
+/// supports, and the guest writes the bits of the events it reports.
+pub struct PvPanic {
+    /// Panic eventfd. We will set this event when the guest reports an event.
+    panic_evt: EventFd,
+
+    /// The events reported since the VMM last took them.
+    events: u8,
+}
+
+impl PvPanic {
+    /// Constructs a pvpanic device that will signal the given event when the guest reports an
+    /// event.
+    pub fn new(panic_evt: EventFd) -> PvPanic {
+        PvPanic {
+            panic_evt,
+            events: 0,
+        }
+    }
+
+    /// Returns a clone of the panic event fd.
+    pub fn get_panic_evt_clone(&self) -> io::Result<EventFd> {
+        self.panic_evt.try_clone()
+    }
+
+    /// Returns the events reported since the last call, as `PVPANIC_*` bits, and forgets them.
+    pub fn take_events(&mut self) -> u8 {
+        let events = self.events;
+        self.events = 0;
+        events
+    }
+}
+
+impl BusDevice for PvPanic {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        // The register is byte-wide. We don't know how to handle any wider data.
+        if offset != 0 || data.len() != 1 {
+            METRICS.pvpanic.missed_read_count.inc();
+            return;
+        }
+        data[0] = PVPANIC_SUPPORTED_EVENTS;
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if offset != 0 || data.len() != 1 {
+            METRICS.pvpanic.missed_write_count.inc();
+            return;
+        }
+
+        let events = data[0] & PVPANIC_SUPPORTED_EVENTS;
+        if events & PVPANIC_PANICKED != 0 {
+            METRICS.pvpanic.panic_count.inc();
+            error!("The guest kernel panicked.");
+        }
+        if events & PVPANIC_CRASH_LOADED != 0 {
+            METRICS.pvpanic.crash_loaded_count.inc();
+            error!("The guest kernel crashed and loaded a crash kernel.");
+        }
+        if events != 0 {
+            self.events |= events;
+            if let Err(e) = self.panic_evt.write(1) {
+                error!("Failed to trigger pvpanic event: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pvpanic_read_write_and_event() {
+        let mut pvpanic = PvPanic::new(EventFd::new().unwrap());
+        let panic_evt = pvpanic.get_panic_evt_clone().unwrap();
+
+        let mut data = [0];
+        pvpanic.read(0, &mut data);
+        assert_eq!(data[0], PVPANIC_PANICKED | PVPANIC_CRASH_LOADED);
+
+        let panics = METRICS.pvpanic.panic_count.count();
+        pvpanic.write(0, &[PVPANIC_PANICKED]);
+        assert_eq!(panic_evt.read().unwrap(), 1);
+        assert_eq!(METRICS.pvpanic.panic_count.count(), panics + 1);
+        assert_eq!(pvpanic.take_events(), PVPANIC_PANICKED);
+        assert_eq!(pvpanic.take_events(), 0);
+
+        // The events accumulate until the VMM takes them.
+        let crashes = METRICS.pvpanic.crash_loaded_count.count();
+        pvpanic.write(0, &[PVPANIC_CRASH_LOADED]);
+        pvpanic.write(0, &[PVPANIC_PANICKED]);
+        assert_eq!(panic_evt.read().unwrap(), 2);
+        assert_eq!(METRICS.pvpanic.crash_loaded_count.count(), crashes + 1);
+        assert_eq!(
+            pvpanic.take_events(),
+            PVPANIC_PANICKED | PVPANIC_CRASH_LOADED
+        );
+
+        // Unknown events are ignored.
+        pvpanic.write(0, &[0x80]);
+        assert_eq!(pvpanic.take_events(), 0);
+
+        // Check invalid `read`s and `write`s.
+        let before = METRICS.pvpanic.missed_read_count.count();
+        let mut data = [0, 0];
+        pvpanic.read(0, &mut data);
+        assert_eq!(data, [0, 0]);
+        let mut data = [0];
+        pvpanic.read(1, &mut data);
+        assert_eq!(data, [0]);
+        assert_eq!(METRICS.pvpanic.missed_read_count.count(), before + 2);
+
+        let before = METRICS.pvpanic.missed_write_count.count();
+        pvpanic.write(1, &[PVPANIC_PANICKED]);
+        pvpanic.write(0, &[PVPANIC_PANICKED, 0]);
+        assert_eq!(METRICS.pvpanic.missed_write_count.count(), before + 2);
+        assert_eq!(pvpanic.take_events(), 0);
+    }
+}
@@ -6,9 +6,13 @@
 // found in the THIRD-PARTY file.
 
 //! Emulates virtual and hardware devices.
+extern crate aes;
 extern crate byteorder;
 extern crate epoll;
 extern crate libc;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 
 extern crate dumbo;
 #[macro_use]
@@ -18,15 +22,15 @@ extern crate net_gen;
 extern crate net_util;
 extern crate rate_limiter;
 extern crate sys_util;
-#[cfg(feature = "vsock")]
+extern crate timerfd;
 extern crate vhost_backend;
 #[cfg(feature = "vsock")]
 extern crate vhost_gen;
 extern crate virtio_gen;
 
 use rate_limiter::{Error as RateLimiterError, TokenBucket};
-use std::fs::File;
 use std::io;
+use std::os::unix::io::RawFd;
 
 mod bus;
 pub mod legacy;
@@ -40,8 +44,8 @@ pub type DeviceEventT = u16;
 /// needs to be changed.
 #[allow(clippy::large_enum_variant)]
 pub enum EpollHandlerPayload {
-    /// DrivePayload(disk_image)
-    DrivePayload(File),
+    /// DrivePayload(disk_image), where `None` detaches the disk image of the drive.
+    DrivePayload(Option<Box<virtio::DiskFile>>),
     /// Used to mutate current RateLimiter settings. The buckets are rx_bytes, rx_ops,
     /// tx_bytes, and tx_ops, respectively.
     NetRateLimiterPayload {
@@ -63,6 +67,10 @@ pub trait EpollHandler: Send {
         event_flags: u32,
         payload: EpollHandlerPayload,
     ) -> Result<()>;
+
+    /// Returns the file descriptors of the events this handler was registered for, so that
+    /// they can be removed from the epoll instance when the device stops.
+    fn event_fds(&self) -> Vec<RawFd>;
 }
 
 #[derive(Debug)]
@@ -0,0 +1,1214 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use epoll;
+use std::cmp;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+
+use super::super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DescriptorChain, EpollHandlerPayload, Queue, VirtioDevice,
+    TYPE_BALLOON, VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING,
+};
+use logger::{Metric, METRICS};
+use memory_model::{GuestAddress, GuestMemory, GuestMemoryError};
+use sys_util::EventFd;
+use virtio_gen::virtio_balloon::*;
+use {DeviceEventT, EpollHandler};
+
+const CONFIG_SPACE_SIZE: usize = 8;
+const QUEUE_SIZE: u16 = 256;
+// The inflate and deflate queues, followed by the statistics queue and the free page reporting
+// queue, each only when it is enabled.
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; 4];
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+const STATS_QUEUE: usize = 2;
+
+// The balloon always works with 4 KiB pages, whatever the page size of the guest.
+const PAGE_SHIFT: u32 = VIRTIO_BALLOON_PFN_SHIFT;
+const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+/// Number of balloon pages in one MiB.
+pub const PAGES_PER_MIB: u32 = 1 << (20 - PAGE_SHIFT);
+// A page frame number, as found in the inflate and deflate buffers.
+const PFN_SIZE: usize = 4;
+// A `struct virtio_balloon_stat`: a 16 bit tag followed by a 64 bit value, packed.
+const STAT_SIZE: usize = 10;
+
+// The guest gave pages to the balloon.
+const INFLATE_QUEUE_EVENT: DeviceEventT = 0;
+// The guest took pages back from the balloon.
+const DEFLATE_QUEUE_EVENT: DeviceEventT = 1;
+// The guest reported its memory statistics.
+const STATS_QUEUE_EVENT: DeviceEventT = 2;
+// It is time to ask the guest for fresh statistics.
+const STATS_TIMER_EVENT: DeviceEventT = 3;
+// The guest reported free pages.
+const REPORTING_QUEUE_EVENT: DeviceEventT = 4;
+// Number of DeviceEventT events supported by this implementation.
+pub const BALLOON_EVENTS_COUNT: usize = 5;
+// The target size of the balloon changed. This event is faked by the VMM, so that the device
+// notifies the guest.
+pub const CONFIG_CHANGE_FAKE_EVENT: DeviceEventT = BALLOON_EVENTS_COUNT as DeviceEventT;
+
+#[derive(Debug)]
+enum Error {
+    /// Guest gave us bad memory addresses.
+    GuestMemory(GuestMemoryError),
+    /// Guest gave us a write only descriptor that protocol says to read from.
+    UnexpectedWriteOnlyDescriptor,
+    /// Guest gave us a read only descriptor that protocol says to write to.
+    UnexpectedReadOnlyDescriptor,
+    /// Guest gave us a buffer whose length is not a multiple of the size of its elements.
+    InvalidBufferLength(u32),
+}
+
+/// The size of the balloon, along with the memory statistics last reported by the guest. A
+/// statistic is left out until the guest reports it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct BalloonStatistics {
+    /// Number of 4 KiB pages the guest is asked to give to the balloon.
+    pub target_pages: u32,
+    /// Number of 4 KiB pages the guest reports to be in the balloon.
+    pub actual_pages: u32,
+    /// The target size of the balloon, in MiB.
+    pub target_mib: u32,
+    /// The actual size of the balloon, in MiB.
+    pub actual_mib: u32,
+    /// Amount of memory swapped in, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap_in: Option<u64>,
+    /// Amount of memory swapped out to disk, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap_out: Option<u64>,
+    /// Number of major page faults.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub major_faults: Option<u64>,
+    /// Number of minor page faults.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minor_faults: Option<u64>,
+    /// Amount of memory not used for any purpose, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_memory: Option<u64>,
+    /// Total amount of memory available to the guest, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_memory: Option<u64>,
+    /// Estimate of the memory available for starting new applications, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_memory: Option<u64>,
+    /// Amount of memory used by the disk caches, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_caches: Option<u64>,
+    /// Number of successful hugetlb page allocations.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hugetlb_allocations: Option<u64>,
+    /// Number of failed hugetlb page allocations.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hugetlb_failures: Option<u64>,
+}
+
+impl BalloonStatistics {
+    fn update_stat(&mut self, tag: u16, value: u64) {
+        let stat = match u32::from(tag) {
+            VIRTIO_BALLOON_S_SWAP_IN => &mut self.swap_in,
+            VIRTIO_BALLOON_S_SWAP_OUT => &mut self.swap_out,
+            VIRTIO_BALLOON_S_MAJFLT => &mut self.major_faults,
+            VIRTIO_BALLOON_S_MINFLT => &mut self.minor_faults,
+            VIRTIO_BALLOON_S_MEMFREE => &mut self.free_memory,
+            VIRTIO_BALLOON_S_MEMTOT => &mut self.total_memory,
+            VIRTIO_BALLOON_S_AVAIL => &mut self.available_memory,
+            VIRTIO_BALLOON_S_CACHES => &mut self.disk_caches,
+            VIRTIO_BALLOON_S_HTLB_PGALLOC => &mut self.hugetlb_allocations,
+            VIRTIO_BALLOON_S_HTLB_PGFAIL => &mut self.hugetlb_failures,
+            // Newer guests may report statistics we don't know about.
+            _ => return,
+        };
+        *stat = Some(value);
+    }
+}
+
+/// The state of the balloon which outlives the activation of the device. It is shared with the
+/// VMM, which changes the target size and reads the statistics while the guest runs.
+#[derive(Default)]
+pub struct BalloonState {
+    // The `num_pages` field of the config space.
+    num_pages: u32,
+    // The `actual` field of the config space, written by the guest.
+    actual_pages: u32,
+    stats: BalloonStatistics,
+}
+
+impl BalloonState {
+    /// Returns the number of pages the guest is asked to give to the balloon.
+    pub fn target_pages(&self) -> u32 {
+        self.num_pages
+    }
+
+    /// Sets the number of pages the guest is asked to give to the balloon. The guest only
+    /// notices the change once the device raises a configuration change interrupt.
+    pub fn set_target_pages(&mut self, num_pages: u32) {
+        self.num_pages = num_pages;
+    }
+
+    /// Returns the number of pages the guest reports to be in the balloon.
+    pub fn actual_pages(&self) -> u32 {
+        self.actual_pages
+    }
+
+    /// Returns the size of the balloon and the last statistics reported by the guest.
+    pub fn statistics(&self) -> BalloonStatistics {
+        BalloonStatistics {
+            target_pages: self.num_pages,
+            actual_pages: self.actual_pages,
+            target_mib: self.num_pages / PAGES_PER_MIB,
+            actual_mib: self.actual_pages / PAGES_PER_MIB,
+            ..self.stats.clone()
+        }
+    }
+
+    fn config_space(&self) -> [u8; CONFIG_SPACE_SIZE] {
+        // The config space is little endian.
+        let mut config = [0u8; CONFIG_SPACE_SIZE];
+        LittleEndian::write_u32(&mut config[0..4], self.num_pages);
+        LittleEndian::write_u32(&mut config[4..8], self.actual_pages);
+        config
+    }
+}
+
+// Reads the whole buffer of a descriptor, which the guest must have made readable.
+fn read_buffer(desc: &DescriptorChain, mem: &GuestMemory) -> result::Result<Vec<u8>, Error> {
+    if desc.is_write_only() {
+        return Err(Error::UnexpectedWriteOnlyDescriptor);
+    }
+    let mut buf = vec![0u8; desc.len as usize];
+    mem.read_slice_at_addr(&mut buf, desc.addr)
+        .map_err(Error::GuestMemory)?;
+    Ok(buf)
+}
+
+// Gives the pages listed in an inflate buffer back to the host. Consecutive page frame numbers
+// are released together, since the guest usually hands over runs of them.
+fn release_pages(buf: &[u8], mem: &GuestMemory) -> result::Result<(), Error> {
+    if buf.len() % PFN_SIZE != 0 {
+        return Err(Error::InvalidBufferLength(buf.len() as u32));
+    }
+    let mut pfns: Vec<u64> = buf
+        .chunks(PFN_SIZE)
+        .map(|pfn| u64::from(LittleEndian::read_u32(pfn)))
+        .collect();
+    pfns.sort_unstable();
+    pfns.dedup();
+
+    let mut ranges: Vec<(u64, usize)> = Vec::new();
+    for pfn in pfns {
+        match ranges.last_mut() {
+            Some(&mut (first, ref mut count)) if first + *count as u64 == pfn => *count += 1,
+            _ => ranges.push((pfn, 1)),
+        }
+    }
+    // Try all the ranges, even if some fail, and report the first failure.
+    let mut result = Ok(());
+    for (first, count) in ranges {
+        if let Err(e) = mem.remove_range(
+            GuestAddress((first << PAGE_SHIFT) as usize),
+            count * PAGE_SIZE,
+        ) {
+            if result.is_ok() {
+                result = Err(Error::GuestMemory(e));
+            }
+        }
+    }
+    result
+}
+
+// Gives the free pages reported in a chain of buffers back to the host. Each buffer is a range of
+// free guest memory, which the guest leaves alone until the device returns the chain.
+fn release_reported_pages(
+    desc_chain: DescriptorChain,
+    mem: &GuestMemory,
+) -> result::Result<(), Error> {
+    let mut next_desc = Some(desc_chain);
+    // Try all the ranges, even if some fail, and report the first failure.
+    let mut result = Ok(());
+    while let Some(desc) = next_desc {
+        let released = if desc.is_write_only() {
+            mem.remove_range(desc.addr, desc.len as usize)
+                .map_err(Error::GuestMemory)
+        } else {
+            Err(Error::UnexpectedReadOnlyDescriptor)
+        };
+        if result.is_ok() {
+            result = released;
+        }
+        next_desc = desc.next_descriptor();
+    }
+    result
+}
+
+// Parses a buffer of `struct virtio_balloon_stat`s into `stats`.
+fn parse_stats(buf: &[u8], stats: &mut BalloonStatistics) -> result::Result<(), Error> {
+    if buf.len() % STAT_SIZE != 0 {
+        return Err(Error::InvalidBufferLength(buf.len() as u32));
+    }
+    for stat in buf.chunks(STAT_SIZE) {
+        stats.update_stat(
+            LittleEndian::read_u16(&stat[0..2]),
+            LittleEndian::read_u64(&stat[2..10]),
+        );
+    }
+    Ok(())
+}
+
+struct BalloonEpollHandler {
+    queues: Vec<Queue>,
+    mem: GuestMemory,
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: EventFd,
+    queue_evts: Vec<EventFd>,
+    state: Arc<Mutex<BalloonState>>,
+    stats_timer: Option<TimerFd>,
+    // The statistics buffer last filled in by the guest. The device holds on to it, and hands it
+    // back to the guest to ask for fresh statistics.
+    stats_desc_index: Option<u16>,
+    // The index of the free page reporting queue, when it is enabled.
+    reporting_queue: Option<usize>,
+}
+
+impl BalloonEpollHandler {
+    // Processes the inflate or the deflate queue, depending on `queue_index`.
+    fn process_page_queue(&mut self, queue_index: usize) -> bool {
+        let mem = &self.mem;
+        let queue = &mut self.queues[queue_index];
+        let mut used_desc_heads = [0u16; QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        for avail_desc in queue.iter(mem) {
+            if queue_index == INFLATE_QUEUE {
+                METRICS.balloon.inflate_count.inc();
+                if let Err(e) =
+                    read_buffer(&avail_desc, mem).and_then(|buf| release_pages(&buf, mem))
+                {
+                    error!("Failed to release the pages of the balloon: {:?}", e);
+                    METRICS.balloon.release_fails.inc();
+                }
+            } else {
+                // The pages are faulted back in when the guest touches them.
+                METRICS.balloon.deflate_count.inc();
+            }
+            used_desc_heads[used_count] = avail_desc.index;
+            used_count += 1;
+        }
+
+        for &desc_index in &used_desc_heads[..used_count] {
+            queue.add_used(mem, desc_index, 0);
+        }
+        used_count != 0
+    }
+
+    fn process_stats_queue(&mut self) -> bool {
+        let queue = &mut self.queues[STATS_QUEUE];
+        let mut used_desc_heads = [0u16; QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        for avail_desc in queue.iter(&self.mem) {
+            // The guest only has one statistics buffer, so it should not send another one before
+            // getting the previous one back. Return the old one just in case.
+            if let Some(desc_index) = self.stats_desc_index.take() {
+                used_desc_heads[used_count] = desc_index;
+                used_count += 1;
+            }
+            METRICS.balloon.stats_updates_count.inc();
+            let mut state = self.state.lock().expect("Poisoned balloon state lock");
+            if let Err(e) = read_buffer(&avail_desc, &self.mem)
+                .and_then(|buf| parse_stats(&buf, &mut state.stats))
+            {
+                error!("Failed to read the balloon statistics: {:?}", e);
+                METRICS.balloon.stats_update_fails.inc();
+            }
+            self.stats_desc_index = Some(avail_desc.index);
+        }
+
+        for &desc_index in &used_desc_heads[..used_count] {
+            queue.add_used(&self.mem, desc_index, 0);
+        }
+        used_count != 0
+    }
+
+    fn process_reporting_queue(&mut self, queue_index: usize) -> bool {
+        let mem = &self.mem;
+        let queue = &mut self.queues[queue_index];
+        let mut used_desc_heads = [0u16; QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        for avail_desc in queue.iter(mem) {
+            METRICS.balloon.reporting_count.inc();
+            used_desc_heads[used_count] = avail_desc.index;
+            if let Err(e) = release_reported_pages(avail_desc, mem) {
+                error!(
+                    "Failed to release the free pages reported by the guest: {:?}",
+                    e
+                );
+                METRICS.balloon.reporting_fails.inc();
+            }
+            used_count += 1;
+        }
+
+        for &desc_index in &used_desc_heads[..used_count] {
+            queue.add_used(mem, desc_index, 0);
+        }
+        used_count != 0
+    }
+
+    // Hands the statistics buffer back to the guest, which fills it in again.
+    fn request_stats(&mut self) -> bool {
+        match self.stats_desc_index.take() {
+            Some(desc_index) => {
+                self.queues[STATS_QUEUE].add_used(&self.mem, desc_index, 0);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn signal(&self, interrupt: u32) -> result::Result<(), DeviceError> {
+        self.interrupt_status
+            .fetch_or(interrupt as usize, Ordering::SeqCst);
+        self.interrupt_evt.write(1).map_err(|e| {
+            error!("Failed to signal the balloon interrupt: {:?}", e);
+            METRICS.balloon.event_fails.inc();
+            DeviceError::FailedSignalingUsedQueue(e)
+        })
+    }
+
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.signal(VIRTIO_MMIO_INT_VRING)
+    }
+
+    fn read_queue_evt(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.queue_evts[queue_index]
+            .read()
+            .map(|_| ())
+            .map_err(|e| {
+                error!("Failed to get queue event: {:?}", e);
+                METRICS.balloon.event_fails.inc();
+                DeviceError::FailedReadingQueue {
+                    event_type: "queue event",
+                    underlying: e,
+                }
+            })
+    }
+}
+
+impl EpollHandler for BalloonEpollHandler {
+    fn handle_event(
+        &mut self,
+        device_event: DeviceEventT,
+        _: u32,
+        _: EpollHandlerPayload,
+    ) -> result::Result<(), DeviceError> {
+        match device_event {
+            INFLATE_QUEUE_EVENT | DEFLATE_QUEUE_EVENT => {
+                let queue_index = if device_event == INFLATE_QUEUE_EVENT {
+                    INFLATE_QUEUE
+                } else {
+                    DEFLATE_QUEUE
+                };
+                self.read_queue_evt(queue_index)?;
+                if self.process_page_queue(queue_index) {
+                    self.signal_used_queue()
+                } else {
+                    Ok(())
+                }
+            }
+            STATS_QUEUE_EVENT => {
+                self.read_queue_evt(STATS_QUEUE)?;
+                if self.process_stats_queue() {
+                    self.signal_used_queue()
+                } else {
+                    Ok(())
+                }
+            }
+            STATS_TIMER_EVENT => {
+                if let Some(ref mut timer) = self.stats_timer {
+                    // Only the expiration matters, not how many times it happened.
+                    timer.read();
+                }
+                if self.request_stats() {
+                    self.signal_used_queue()
+                } else {
+                    Ok(())
+                }
+            }
+            REPORTING_QUEUE_EVENT if self.reporting_queue.is_some() => {
+                let queue_index = self.reporting_queue.unwrap();
+                self.read_queue_evt(queue_index)?;
+                if self.process_reporting_queue(queue_index) {
+                    self.signal_used_queue()
+                } else {
+                    Ok(())
+                }
+            }
+            CONFIG_CHANGE_FAKE_EVENT => self.signal(VIRTIO_MMIO_INT_CONFIG),
+            unknown => Err(DeviceError::UnknownEvent {
+                device: "balloon",
+                event: unknown,
+            }),
+        }
+    }
+
+    fn event_fds(&self) -> Vec<RawFd> {
+        self.queue_evts
+            .iter()
+            .map(|evt| evt.as_raw_fd())
+            .chain(self.stats_timer.as_ref().map(|timer| timer.as_raw_fd()))
+            .collect()
+    }
+}
+
+pub struct EpollConfig {
+    inflate_token: u64,
+    deflate_token: u64,
+    stats_token: u64,
+    stats_timer_token: u64,
+    reporting_token: u64,
+    epoll_raw_fd: RawFd,
+    sender: mpsc::Sender<Box<EpollHandler>>,
+}
+
+impl EpollConfig {
+    pub fn new(
+        first_token: u64,
+        epoll_raw_fd: RawFd,
+        sender: mpsc::Sender<Box<EpollHandler>>,
+    ) -> Self {
+        EpollConfig {
+            inflate_token: first_token + u64::from(INFLATE_QUEUE_EVENT),
+            deflate_token: first_token + u64::from(DEFLATE_QUEUE_EVENT),
+            stats_token: first_token + u64::from(STATS_QUEUE_EVENT),
+            stats_timer_token: first_token + u64::from(STATS_TIMER_EVENT),
+            reporting_token: first_token + u64::from(REPORTING_QUEUE_EVENT),
+            epoll_raw_fd,
+            sender,
+        }
+    }
+}
+
+/// Virtio device which lets the host reclaim memory from the guest. The pages the guest puts in
+/// the balloon are released to the host, as are the free pages the guest may report, and the
+/// guest may report its memory statistics.
+pub struct Balloon {
+    avail_features: u64,
+    acked_features: u64,
+    state: Arc<Mutex<BalloonState>>,
+    stats_timer: Option<TimerFd>,
+    stats_polling_interval_s: u16,
+    epoll_config: EpollConfig,
+    activated: bool,
+}
+
+impl Balloon {
+    /// Creates a new virtio balloon device, which asks the guest for `num_pages` 4 KiB pages.
+    /// The guest reports its memory statistics every `stats_polling_interval_s` seconds, unless
+    /// the interval is zero, and reports its free pages if `free_page_reporting` is set.
+    pub fn new(
+        num_pages: u32,
+        deflate_on_oom: bool,
+        stats_polling_interval_s: u16,
+        free_page_reporting: bool,
+        epoll_config: EpollConfig,
+    ) -> io::Result<Balloon> {
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        if deflate_on_oom {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
+        }
+        if free_page_reporting {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_REPORTING;
+        }
+        let stats_timer = if stats_polling_interval_s > 0 {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_STATS_VQ;
+            Some(TimerFd::new_custom(ClockId::Monotonic, true, true)?)
+        } else {
+            None
+        };
+
+        Ok(Balloon {
+            avail_features,
+            acked_features: 0u64,
+            state: Arc::new(Mutex::new(BalloonState {
+                num_pages,
+                ..Default::default()
+            })),
+            stats_timer,
+            stats_polling_interval_s,
+            epoll_config,
+            activated: false,
+        })
+    }
+
+    /// Returns the state shared by the device with its owner.
+    pub fn state(&self) -> Arc<Mutex<BalloonState>> {
+        self.state.clone()
+    }
+
+    fn stats_enabled(&self) -> bool {
+        self.avail_features & (1u64 << VIRTIO_BALLOON_F_STATS_VQ) != 0
+    }
+
+    fn reporting_enabled(&self) -> bool {
+        self.avail_features & (1u64 << VIRTIO_BALLOON_F_REPORTING) != 0
+    }
+
+    // The queues of the disabled features are left out, so the free page reporting queue comes
+    // right after the deflate queue when the statistics are disabled.
+    fn num_queues(&self) -> usize {
+        STATS_QUEUE + self.stats_enabled() as usize + self.reporting_enabled() as usize
+    }
+}
+
+impl VirtioDevice for Balloon {
+    fn device_type(&self) -> u32 {
+        TYPE_BALLOON
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &QUEUE_SIZES[..self.num_queues()]
+    }
+
+    fn features(&self, page: u32) -> u32 {
+        match page {
+            // Get the lower 32-bits of the features bitfield.
+            0 => self.avail_features as u32,
+            // Get the upper 32-bits of the features bitfield.
+            1 => (self.avail_features >> 32) as u32,
+            _ => {
+                warn!("Received request for unknown features page.");
+                0u32
+            }
+        }
+    }
+
+    fn ack_features(&mut self, page: u32, value: u32) {
+        let mut v = match page {
+            0 => u64::from(value),
+            1 => u64::from(value) << 32,
+            _ => {
+                warn!("Cannot acknowledge unknown features page.");
+                0u64
+            }
+        };
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_space = self
+            .state
+            .lock()
+            .expect("Poisoned balloon state lock")
+            .config_space();
+        let config_len = config_space.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            METRICS.balloon.cfg_fails.inc();
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_space[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let mut state = self.state.lock().expect("Poisoned balloon state lock");
+        let mut config_space = state.config_space();
+        // The guest can only write the `actual` field.
+        let data_len = data.len() as u64;
+        if offset < 4 || offset + data_len > config_space.len() as u64 {
+            error!("Failed to write config space");
+            METRICS.balloon.cfg_fails.inc();
+            return;
+        }
+        config_space[offset as usize..(offset + data_len) as usize].copy_from_slice(data);
+        state.actual_pages = LittleEndian::read_u32(&config_space[4..8]);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt_evt: EventFd,
+        status: Arc<AtomicUsize>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        let num_queues = self.num_queues();
+        if queues.len() != num_queues || queue_evts.len() != num_queues {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                num_queues,
+                queues.len()
+            );
+            METRICS.balloon.activate_fails.inc();
+            return Err(ActivateError::BadActivate);
+        }
+        if self.activated {
+            METRICS.balloon.activate_fails.inc();
+            return Err(ActivateError::BadActivate);
+        }
+
+        let queue_raw_fds: Vec<RawFd> = queue_evts.iter().map(|evt| evt.as_raw_fd()).collect();
+        let mut stats_timer = self.stats_timer.take();
+        if let Some(ref mut timer) = stats_timer {
+            let interval = Duration::from_secs(u64::from(self.stats_polling_interval_s));
+            timer.set_state(
+                TimerState::Periodic {
+                    current: interval,
+                    interval,
+                },
+                SetTimeFlags::Default,
+            );
+        }
+        let stats_timer_raw_fd = stats_timer.as_ref().map(|timer| timer.as_raw_fd());
+
+        let handler = BalloonEpollHandler {
+            queues,
+            mem,
+            interrupt_status: status,
+            interrupt_evt,
+            queue_evts,
+            state: self.state.clone(),
+            stats_timer,
+            stats_desc_index: None,
+            reporting_queue: if self.reporting_enabled() {
+                Some(num_queues - 1)
+            } else {
+                None
+            },
+        };
+
+        // The channel should be open at this point.
+        self.epoll_config
+            .sender
+            .send(Box::new(handler))
+            .expect("Failed to send through the channel");
+        self.activated = true;
+
+        let mut tokens = vec![
+            self.epoll_config.inflate_token,
+            self.epoll_config.deflate_token,
+        ];
+        if self.stats_enabled() {
+            tokens.push(self.epoll_config.stats_token);
+        }
+        if self.reporting_enabled() {
+            tokens.push(self.epoll_config.reporting_token);
+        }
+        let events = queue_raw_fds
+            .iter()
+            .zip(tokens.iter())
+            .map(|(&fd, &token)| (fd, token))
+            .chain(
+                stats_timer_raw_fd
+                    .into_iter()
+                    .map(|fd| (fd, self.epoll_config.stats_timer_token)),
+            );
+        for (fd, token) in events {
+            epoll::ctl(
+                self.epoll_config.epoll_raw_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, token),
+            )
+            .map_err(|e| {
+                METRICS.balloon.activate_fails.inc();
+                ActivateError::EpollCtl(e)
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory_model::MemoryBacking;
+
+    use libc;
+    use std::sync::mpsc::Receiver;
+    use std::u32;
+
+    use virtio::queue::tests::*;
+
+    const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+    const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+    struct DummyBalloon {
+        balloon: Balloon,
+        epoll_raw_fd: i32,
+        receiver: Receiver<Box<EpollHandler>>,
+    }
+
+    impl DummyBalloon {
+        fn new(
+            deflate_on_oom: bool,
+            stats_polling_interval_s: u16,
+            free_page_reporting: bool,
+        ) -> Self {
+            let epoll_raw_fd = epoll::create(true).unwrap();
+            let (sender, receiver) = mpsc::channel();
+            let epoll_config = EpollConfig::new(0, epoll_raw_fd, sender);
+            DummyBalloon {
+                balloon: Balloon::new(
+                    0x100,
+                    deflate_on_oom,
+                    stats_polling_interval_s,
+                    free_page_reporting,
+                    epoll_config,
+                )
+                .unwrap(),
+                epoll_raw_fd,
+                receiver,
+            }
+        }
+    }
+
+    impl Drop for DummyBalloon {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.epoll_raw_fd) };
+        }
+    }
+
+    fn activate_balloon(
+        b: &mut Balloon,
+        mem: &GuestMemory,
+        queues: Vec<Queue>,
+    ) -> (ActivateResult, Vec<EventFd>, EventFd, Arc<AtomicUsize>) {
+        let queue_evts: Vec<EventFd> = queues.iter().map(|_| EventFd::new().unwrap()).collect();
+        let handler_evts = queue_evts.iter().map(|e| e.try_clone().unwrap()).collect();
+        let interrupt_evt = EventFd::new().unwrap();
+        let status = Arc::new(AtomicUsize::new(0));
+        let result = b.activate(
+            mem.clone(),
+            interrupt_evt.try_clone().unwrap(),
+            status.clone(),
+            queues,
+            handler_evts,
+        );
+        (result, queue_evts, interrupt_evt, status)
+    }
+
+    #[test]
+    fn test_virtio_device() {
+        let mut dummy = DummyBalloon::new(true, 1, false);
+        let b = &mut dummy.balloon;
+
+        assert_eq!(b.device_type(), TYPE_BALLOON);
+        assert_eq!(b.queue_max_sizes(), &QUEUE_SIZES[..3]);
+
+        // Test `features()` and `ack_features()`.
+        let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_BALLOON_F_STATS_VQ)
+            | (1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM);
+        assert_eq!(b.features(0), features as u32);
+        assert_eq!(b.features(1), (features >> 32) as u32);
+        assert_eq!(b.features(2), 0u32);
+        for i in 0..3 {
+            b.ack_features(i, u32::MAX);
+        }
+        assert_eq!(b.acked_features, features);
+
+        // Test `read_config()`.
+        let mut config = [0u8; CONFIG_SPACE_SIZE];
+        b.read_config(0, &mut config);
+        assert_eq!(config, [0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+        let before = METRICS.balloon.cfg_fails.count();
+        b.read_config(CONFIG_SPACE_SIZE as u64, &mut config);
+        assert_eq!(METRICS.balloon.cfg_fails.count(), before + 1);
+
+        // Test `write_config()`: only the `actual` field is writable.
+        b.write_config(4, &[0x80, 0, 0, 0]);
+        assert_eq!(b.state().lock().unwrap().actual_pages(), 0x80);
+        let before = METRICS.balloon.cfg_fails.count();
+        b.write_config(0, &[0xff; 8]);
+        b.write_config(6, &[0xff; 4]);
+        assert_eq!(METRICS.balloon.cfg_fails.count(), before + 2);
+        b.read_config(0, &mut config);
+        assert_eq!(config, [0x00, 0x01, 0, 0, 0x80, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_features_without_stats() {
+        let dummy = DummyBalloon::new(false, 0, false);
+        let b = &dummy.balloon;
+        assert_eq!(b.features(0), 0);
+        assert_eq!(b.features(1), 1);
+        // Without statistics, the guest does not set up the statistics queue.
+        assert_eq!(b.queue_max_sizes(), &QUEUE_SIZES[..2]);
+        assert!(b.stats_timer.is_none());
+    }
+
+    #[test]
+    fn test_features_with_reporting() {
+        let dummy = DummyBalloon::new(false, 0, true);
+        let b = &dummy.balloon;
+        assert_eq!(b.features(0), 1 << VIRTIO_BALLOON_F_REPORTING);
+        assert_eq!(b.features(1), 1);
+        // The free page reporting queue takes the place of the missing statistics queue.
+        assert_eq!(b.queue_max_sizes(), &QUEUE_SIZES[..3]);
+
+        let dummy = DummyBalloon::new(false, 1, true);
+        assert_eq!(dummy.balloon.queue_max_sizes(), QUEUE_SIZES);
+    }
+
+    #[test]
+    fn test_activate() {
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let mut dummy = DummyBalloon::new(false, 0, false);
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+
+        // The statistics queue is not expected.
+        let queues = vec![vq.create_queue(), vq.create_queue(), vq.create_queue()];
+        let before = METRICS.balloon.activate_fails.count();
+        match activate_balloon(&mut dummy.balloon, &mem, queues).0 {
+            Err(ActivateError::BadActivate) => (),
+            _ => panic!("Activating with too many queues should fail."),
+        }
+        assert_eq!(METRICS.balloon.activate_fails.count(), before + 1);
+
+        let queues = vec![vq.create_queue(), vq.create_queue()];
+        assert!(activate_balloon(&mut dummy.balloon, &mem, queues).0.is_ok());
+        assert!(dummy.receiver.try_recv().is_ok());
+
+        // The device can only be activated once.
+        let queues = vec![vq.create_queue(), vq.create_queue()];
+        match activate_balloon(&mut dummy.balloon, &mem, queues).0 {
+            Err(ActivateError::BadActivate) => (),
+            _ => panic!("Activating twice should fail."),
+        }
+    }
+
+    #[test]
+    fn test_release_pages() {
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        for pfn in 0..0x10 {
+            mem.write_obj_at_addr(0xffu8, GuestAddress(pfn << PAGE_SHIFT))
+                .unwrap();
+        }
+
+        // Pages 2, 3, 4 and 8, out of order and with a duplicate.
+        let mut buf = vec![];
+        for pfn in &[8u32, 3, 2, 4, 3] {
+            let mut bytes = [0u8; PFN_SIZE];
+            LittleEndian::write_u32(&mut bytes, *pfn);
+            buf.extend_from_slice(&bytes);
+        }
+        release_pages(&buf, &mem).unwrap();
+        for pfn in 0..0x10 {
+            let expected = match pfn {
+                2 | 3 | 4 | 8 => 0,
+                _ => 0xff,
+            };
+            assert_eq!(
+                mem.read_obj_from_addr::<u8>(GuestAddress(pfn << PAGE_SHIFT))
+                    .unwrap(),
+                expected
+            );
+        }
+
+        // A truncated page frame number.
+        match release_pages(&buf[..3], &mem) {
+            Err(Error::InvalidBufferLength(3)) => (),
+            e => panic!("Unexpected result {:?}", e),
+        }
+        // A page outside of the guest memory.
+        LittleEndian::write_u32(&mut buf[0..4], 0x100);
+        match release_pages(&buf[..4], &mem) {
+            Err(Error::GuestMemory(_)) => (),
+            e => panic!("Unexpected result {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_release_reported_pages() {
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x20000)], MemoryBacking::Anonymous).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut queue = vq.create_queue();
+        for pfn in 0x10..0x20 {
+            mem.write_obj_at_addr(0xffu8, GuestAddress(pfn << PAGE_SHIFT))
+                .unwrap();
+        }
+
+        // Pages 0x10 and 0x11, then page 0x18.
+        vq.dtable[0].set(0x10000, 0x2000, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x18000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        // A read only range is skipped, but the next one is still released.
+        vq.dtable[2].set(0x1a000, 0x1000, VIRTQ_DESC_F_NEXT, 3);
+        vq.dtable[3].set(0x1c000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        // A range which is not page aligned.
+        vq.dtable[4].set(0x1e800, 0x800, VIRTQ_DESC_F_WRITE, 0);
+        for i in 0..3 {
+            vq.avail.ring[i].set([0, 2, 4][i]);
+        }
+        vq.avail.idx.set(3);
+
+        let mut chains = queue.iter(&mem);
+        release_reported_pages(chains.next().unwrap(), &mem).unwrap();
+        match release_reported_pages(chains.next().unwrap(), &mem) {
+            Err(Error::UnexpectedReadOnlyDescriptor) => (),
+            e => panic!("Unexpected result {:?}", e),
+        }
+        match release_reported_pages(chains.next().unwrap(), &mem) {
+            Err(Error::GuestMemory(_)) => (),
+            e => panic!("Unexpected result {:?}", e),
+        }
+        for pfn in 0x10..0x20 {
+            let expected = match pfn {
+                0x10 | 0x11 | 0x18 | 0x1c => 0,
+                _ => 0xff,
+            };
+            assert_eq!(
+                mem.read_obj_from_addr::<u8>(GuestAddress(pfn << PAGE_SHIFT))
+                    .unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_stats() {
+        let mut stats = BalloonStatistics::default();
+        let mut buf = vec![0u8; 3 * STAT_SIZE];
+        for (i, &(tag, value)) in [
+            (VIRTIO_BALLOON_S_MEMFREE, 0x1000),
+            (VIRTIO_BALLOON_S_MEMTOT, 0x2000),
+            // Unknown tags are ignored.
+            (VIRTIO_BALLOON_S_NR, 0x3000),
+        ]
+        .iter()
+        .enumerate()
+        {
+            LittleEndian::write_u16(&mut buf[i * STAT_SIZE..], tag as u16);
+            LittleEndian::write_u64(&mut buf[i * STAT_SIZE + 2..], value);
+        }
+        parse_stats(&buf, &mut stats).unwrap();
+        assert_eq!(stats.free_memory, Some(0x1000));
+        assert_eq!(stats.total_memory, Some(0x2000));
+        assert_eq!(stats.swap_in, None);
+
+        match parse_stats(&buf[..STAT_SIZE + 1], &mut stats) {
+            Err(Error::InvalidBufferLength(11)) => (),
+            e => panic!("Unexpected result {:?}", e),
+        }
+    }
+
+    #[test]
+    #[allow(clippy::cyclomatic_complexity)]
+    fn test_handler() {
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x20000)], MemoryBacking::Anonymous).unwrap();
+        let inflate_vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let deflate_vq = VirtQueue::new(GuestAddress(0x1000), &mem, 16);
+        let stats_vq = VirtQueue::new(GuestAddress(0x2000), &mem, 16);
+        let mut dummy = DummyBalloon::new(true, 1, false);
+        let state = dummy.balloon.state();
+        let queues = vec![
+            inflate_vq.create_queue(),
+            deflate_vq.create_queue(),
+            stats_vq.create_queue(),
+        ];
+        let (result, queue_evts, interrupt_evt, status) =
+            activate_balloon(&mut dummy.balloon, &mem, queues);
+        result.unwrap();
+        let mut h = dummy.receiver.try_recv().unwrap();
+
+        // Inflate the balloon with pages 0x10 and 0x11.
+        mem.write_obj_at_addr(0xffu8, GuestAddress(0x11000))
+            .unwrap();
+        mem.write_obj_at_addr(0x10u32, GuestAddress(0x8000))
+            .unwrap();
+        mem.write_obj_at_addr(0x11u32, GuestAddress(0x8004))
+            .unwrap();
+        inflate_vq.dtable[0].set(0x8000, 8, 0, 0);
+        inflate_vq.avail.ring[0].set(0);
+        inflate_vq.avail.idx.set(1);
+        queue_evts[INFLATE_QUEUE].write(1).unwrap();
+        let before = METRICS.balloon.inflate_count.count();
+        h.handle_event(INFLATE_QUEUE_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(METRICS.balloon.inflate_count.count(), before + 1);
+        assert_eq!(inflate_vq.used.idx.get(), 1);
+        assert_eq!(inflate_vq.used.ring[0].get().id, 0);
+        assert_eq!(
+            mem.read_obj_from_addr::<u8>(GuestAddress(0x11000)).unwrap(),
+            0
+        );
+        assert_eq!(
+            status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING as usize
+        );
+        assert_eq!(interrupt_evt.read().unwrap(), 1);
+
+        // A write only buffer is returned without releasing anything.
+        mem.write_obj_at_addr(0xffu8, GuestAddress(0x11000))
+            .unwrap();
+        inflate_vq.dtable[1].set(0x8000, 8, VIRTQ_DESC_F_WRITE, 0);
+        inflate_vq.avail.ring[1].set(1);
+        inflate_vq.avail.idx.set(2);
+        queue_evts[INFLATE_QUEUE].write(1).unwrap();
+        let before = METRICS.balloon.release_fails.count();
+        h.handle_event(INFLATE_QUEUE_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(METRICS.balloon.release_fails.count(), before + 1);
+        assert_eq!(inflate_vq.used.idx.get(), 2);
+        assert_eq!(
+            mem.read_obj_from_addr::<u8>(GuestAddress(0x11000)).unwrap(),
+            0xff
+        );
+
+        // Deflating only returns the buffer.
+        deflate_vq.dtable[0].set(0x8000, 8, 0, 0);
+        deflate_vq.avail.ring[0].set(0);
+        deflate_vq.avail.idx.set(1);
+        queue_evts[DEFLATE_QUEUE].write(1).unwrap();
+        h.handle_event(DEFLATE_QUEUE_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(deflate_vq.used.idx.get(), 1);
+        assert_eq!(
+            mem.read_obj_from_addr::<u8>(GuestAddress(0x11000)).unwrap(),
+            0xff
+        );
+
+        // The guest reports its statistics, and the device holds on to the buffer.
+        mem.write_obj_at_addr(VIRTIO_BALLOON_S_MEMFREE as u16, GuestAddress(0x9000))
+            .unwrap();
+        mem.write_obj_at_addr(0x1234u64, GuestAddress(0x9002))
+            .unwrap();
+        stats_vq.dtable[0].set(0x9000, STAT_SIZE as u32, 0, 0);
+        stats_vq.avail.ring[0].set(0);
+        stats_vq.avail.idx.set(1);
+        queue_evts[STATS_QUEUE].write(1).unwrap();
+        h.handle_event(STATS_QUEUE_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(stats_vq.used.idx.get(), 0);
+        let stats = state.lock().unwrap().statistics();
+        assert_eq!(stats.free_memory, Some(0x1234));
+        assert_eq!(stats.target_pages, 0x100);
+        assert_eq!(stats.target_mib, 1);
+
+        // The timer hands the buffer back to ask for fresh statistics.
+        h.handle_event(STATS_TIMER_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(stats_vq.used.idx.get(), 1);
+        assert_eq!(stats_vq.used.ring[0].get().id, 0);
+        // Nothing to hand back until the guest answers.
+        h.handle_event(STATS_TIMER_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(stats_vq.used.idx.get(), 1);
+
+        // A new target size is announced with a config change interrupt.
+        status.store(0, Ordering::SeqCst);
+        state.lock().unwrap().set_target_pages(0x200);
+        h.handle_event(CONFIG_CHANGE_FAKE_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(
+            status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_CONFIG as usize
+        );
+        let mut config = [0u8; CONFIG_SPACE_SIZE];
+        dummy.balloon.read_config(0, &mut config);
+        assert_eq!(config, [0x00, 0x02, 0, 0, 0, 0, 0, 0]);
+
+        match h.handle_event(
+            BALLOON_EVENTS_COUNT as DeviceEventT + 1,
+            0,
+            EpollHandlerPayload::Empty,
+        ) {
+            Err(DeviceError::UnknownEvent { device, event }) => {
+                assert_eq!(device, "balloon");
+                assert_eq!(event, BALLOON_EVENTS_COUNT as DeviceEventT + 1);
+            }
+            _ => panic!("Unknown events should be rejected."),
+        }
+        // Free page reporting is not enabled.
+        match h.handle_event(REPORTING_QUEUE_EVENT, 0, EpollHandlerPayload::Empty) {
+            Err(DeviceError::UnknownEvent { .. }) => (),
+            _ => panic!("The free page reporting queue is not enabled."),
+        }
+    }
+
+    #[test]
+    fn test_handler_reporting() {
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x20000)], MemoryBacking::Anonymous).unwrap();
+        let inflate_vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let deflate_vq = VirtQueue::new(GuestAddress(0x1000), &mem, 16);
+        let reporting_vq = VirtQueue::new(GuestAddress(0x2000), &mem, 16);
+        let mut dummy = DummyBalloon::new(false, 0, true);
+        let queues = vec![
+            inflate_vq.create_queue(),
+            deflate_vq.create_queue(),
+            reporting_vq.create_queue(),
+        ];
+        let (result, queue_evts, interrupt_evt, status) =
+            activate_balloon(&mut dummy.balloon, &mem, queues);
+        result.unwrap();
+        let mut h = dummy.receiver.try_recv().unwrap();
+
+        // The guest reports pages 0x10 to 0x13 as free.
+        for pfn in 0x10..0x14 {
+            mem.write_obj_at_addr(0xffu8, GuestAddress(pfn << PAGE_SHIFT))
+                .unwrap();
+        }
+        reporting_vq.dtable[0].set(0x10000, 0x4000, VIRTQ_DESC_F_WRITE, 0);
+        reporting_vq.avail.ring[0].set(0);
+        reporting_vq.avail.idx.set(1);
+        queue_evts[2].write(1).unwrap();
+        let before = METRICS.balloon.reporting_count.count();
+        h.handle_event(REPORTING_QUEUE_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(METRICS.balloon.reporting_count.count(), before + 1);
+        assert_eq!(reporting_vq.used.idx.get(), 1);
+        assert_eq!(reporting_vq.used.ring[0].get().id, 0);
+        for pfn in 0x10..0x14 {
+            assert_eq!(
+                mem.read_obj_from_addr::<u8>(GuestAddress(pfn << PAGE_SHIFT))
+                    .unwrap(),
+                0
+            );
+        }
+        assert_eq!(
+            status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING as usize
+        );
+        assert_eq!(interrupt_evt.read().unwrap(), 1);
+
+        // A report the device fails to release is still returned to the guest.
+        reporting_vq.dtable[1].set(0x10000, 0x4000, 0, 0);
+        reporting_vq.avail.ring[1].set(1);
+        reporting_vq.avail.idx.set(2);
+        queue_evts[2].write(1).unwrap();
+        let before = METRICS.balloon.reporting_fails.count();
+        h.handle_event(REPORTING_QUEUE_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(METRICS.balloon.reporting_fails.count(), before + 1);
+        assert_eq!(reporting_vq.used.idx.get(), 2);
+    }
+}
@@ -0,0 +1,461 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Puts a copy-on-write overlay on top of a read-only base image, so that many microVMs can
+//! share a base image, each writing to its own overlay.
+//!
+//! The overlay file starts with a header, followed by a bitmap with a bit for each block of
+//! the disk, set once the block is in the overlay. The blocks follow, each at its offset in the
+//! disk, so the overlay file stays as sparse as the blocks written to it.
+
+use std::cmp;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::Path;
+use std::result;
+
+use byteorder::{ByteOrder, LittleEndian};
+use libc;
+
+use super::disk::{seek_position, DiskFile};
+use sys_util::{PunchHole, WriteZeroes};
+
+/// The magic number at the start of overlay files, "FCOW".
+pub const COW_MAGIC: u32 = 0x574f_4346;
+const COW_VERSION: u32 = 1;
+/// The granularity at which blocks are copied from the base image to the overlay.
+pub const COW_BLOCK_SIZE: u64 = 4096;
+// The header is padded to a block, and the bitmap starts right after it.
+const HEADER_SIZE: u64 = COW_BLOCK_SIZE;
+// The length of the header fields: magic, version, block size, padding and disk size.
+const HEADER_FIELDS_SIZE: usize = 24;
+
+/// Errors setting up a copy-on-write overlay.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot create the memfd of an in-memory overlay.
+    CreateMemfd(io::Error),
+    /// The overlay has a bad magic number, version or block size.
+    InvalidHeader,
+    /// Reading or formatting the overlay, or sizing the base image, failed.
+    Io(io::Error),
+    /// Cannot open or create the overlay file.
+    OpenOverlay(io::Error),
+    /// The overlay was created for a base image of a different size.
+    SizeMismatch {
+        /// The size of the base image.
+        base: u64,
+        /// The size the overlay was created for.
+        overlay: u64,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match *self {
+            CreateMemfd(ref e) => write!(f, "Cannot create the in-memory overlay: {}", e),
+            InvalidHeader => write!(f, "The overlay file is not valid."),
+            Io(ref e) => write!(f, "Cannot set up the overlay: {}", e),
+            OpenOverlay(ref e) => write!(f, "Cannot open the overlay file: {}", e),
+            SizeMismatch { base, overlay } => write!(
+                f,
+                "The overlay was created for a base image of {} bytes, not {} bytes.",
+                overlay, base
+            ),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// A disk made of the blocks written to an overlay, on top of a read-only base image.
+///
+/// Blocks are copied to the overlay the first time they are written. Blocks which were never
+/// written are read from the base image, which is never written to.
+pub struct CowFile {
+    base: Box<DiskFile>,
+    overlay: File,
+    disk_size: u64,
+    // A bit for each block, set once the block is in the overlay.
+    bitmap: Vec<u8>,
+    // Where the blocks start in the overlay.
+    data_offset: u64,
+    position: u64,
+}
+
+impl CowFile {
+    /// Puts the overlay file at `path` on top of `base`. The overlay is created if it does not
+    /// exist, and has to match the size of `base` otherwise.
+    pub fn open(base: Box<DiskFile>, path: &Path) -> Result<CowFile> {
+        let overlay = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .map_err(Error::OpenOverlay)?;
+        CowFile::new(base, overlay)
+    }
+
+    /// Puts an overlay kept in memory on top of `base`. The written blocks are lost when the
+    /// `CowFile` is dropped.
+    pub fn in_memory(base: Box<DiskFile>) -> Result<CowFile> {
+        let name = CString::new("block_overlay").expect("Invalid memfd name");
+        // This is safe because the name is a valid C string and we check the return value.
+        let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::CreateMemfd(io::Error::last_os_error()));
+        }
+        // This is safe because we just created the file descriptor and nothing else owns it.
+        let overlay = unsafe { File::from_raw_fd(fd as RawFd) };
+        CowFile::new(base, overlay)
+    }
+
+    /// Puts `overlay` on top of `base`, setting up the overlay if it is empty.
+    pub fn new(mut base: Box<DiskFile>, mut overlay: File) -> Result<CowFile> {
+        let disk_size = base.seek(SeekFrom::End(0)).map_err(Error::Io)?;
+        let blocks = (disk_size + COW_BLOCK_SIZE - 1) / COW_BLOCK_SIZE;
+        let bitmap_size = (blocks + 7) / 8;
+        let data_offset =
+            (HEADER_SIZE + bitmap_size + COW_BLOCK_SIZE - 1) / COW_BLOCK_SIZE * COW_BLOCK_SIZE;
+
+        let mut header = [0u8; HEADER_FIELDS_SIZE];
+        let mut bitmap = vec![0u8; bitmap_size as usize];
+        if overlay.metadata().map_err(Error::Io)?.len() == 0 {
+            LittleEndian::write_u32(&mut header[0..], COW_MAGIC);
+            LittleEndian::write_u32(&mut header[4..], COW_VERSION);
+            LittleEndian::write_u32(&mut header[8..], COW_BLOCK_SIZE as u32);
+            LittleEndian::write_u64(&mut header[16..], disk_size);
+            overlay.write_all(&header).map_err(Error::Io)?;
+            // The bitmap and the blocks start out as holes, which read as zeroes.
+            overlay
+                .set_len(data_offset + disk_size)
+                .map_err(Error::Io)?;
+        } else {
+            overlay.seek(SeekFrom::Start(0)).map_err(Error::Io)?;
+            overlay.read_exact(&mut header).map_err(Error::Io)?;
+            if LittleEndian::read_u32(&header[0..]) != COW_MAGIC
+                || LittleEndian::read_u32(&header[4..]) != COW_VERSION
+                || u64::from(LittleEndian::read_u32(&header[8..])) != COW_BLOCK_SIZE
+            {
+                return Err(Error::InvalidHeader);
+            }
+            let overlay_size = LittleEndian::read_u64(&header[16..]);
+            if overlay_size != disk_size {
+                return Err(Error::SizeMismatch {
+                    base: disk_size,
+                    overlay: overlay_size,
+                });
+            }
+            overlay
+                .seek(SeekFrom::Start(HEADER_SIZE))
+                .map_err(Error::Io)?;
+            overlay.read_exact(&mut bitmap).map_err(Error::Io)?;
+        }
+
+        Ok(CowFile {
+            base,
+            overlay,
+            disk_size,
+            bitmap,
+            data_offset,
+            position: 0,
+        })
+    }
+
+    fn is_allocated(&self, block: u64) -> bool {
+        self.bitmap[(block / 8) as usize] & (1 << (block % 8)) != 0
+    }
+
+    // Marks `block` as being in the overlay, once its data was written there.
+    fn set_allocated(&mut self, block: u64) -> io::Result<()> {
+        let index = (block / 8) as usize;
+        self.bitmap[index] |= 1 << (block % 8);
+        self.overlay
+            .seek(SeekFrom::Start(HEADER_SIZE + index as u64))?;
+        self.overlay.write_all(&self.bitmap[index..=index])
+    }
+
+    // Returns the length of `block`, as the last block can be partly past the end of the disk.
+    fn block_len(&self, block: u64) -> u64 {
+        cmp::min(COW_BLOCK_SIZE, self.disk_size - block * COW_BLOCK_SIZE)
+    }
+
+    // Copies `block` from the base image to the overlay.
+    fn copy_up(&mut self, block: u64) -> io::Result<()> {
+        let offset = block * COW_BLOCK_SIZE;
+        let mut data = vec![0u8; self.block_len(block) as usize];
+        self.base.seek(SeekFrom::Start(offset))?;
+        self.base.read_exact(&mut data)?;
+        self.overlay
+            .seek(SeekFrom::Start(self.data_offset + offset))?;
+        self.overlay.write_all(&data)?;
+        self.set_allocated(block)
+    }
+
+    // Returns the length of the next part of a transfer of `len` bytes at `offset`, within a
+    // single block and within the disk.
+    fn chunk_len(&self, offset: u64, len: u64) -> u64 {
+        cmp::min(
+            len,
+            cmp::min(
+                COW_BLOCK_SIZE - offset % COW_BLOCK_SIZE,
+                self.disk_size - offset,
+            ),
+        )
+    }
+
+    // Zeroes the `length` bytes at `offset` with `zero`, one block at a time, copying up the
+    // blocks which are only partly zeroed.
+    fn zero_range<F>(&mut self, offset: u64, length: u64, mut zero: F) -> io::Result<()>
+    where
+        F: FnMut(&mut File, u64, u64) -> io::Result<()>,
+    {
+        let end = cmp::min(offset.saturating_add(length), self.disk_size);
+        let mut offset = offset;
+        while offset < end {
+            let len = self.chunk_len(offset, end - offset);
+            let block = offset / COW_BLOCK_SIZE;
+            if !self.is_allocated(block) && len < self.block_len(block) {
+                self.copy_up(block)?;
+            }
+            zero(&mut self.overlay, self.data_offset + offset, len)?;
+            if !self.is_allocated(block) {
+                self.set_allocated(block)?;
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+}
+
+impl Read for CowFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() && self.position < self.disk_size {
+            let len = self.chunk_len(self.position, (buf.len() - done) as u64) as usize;
+            let dst = &mut buf[done..done + len];
+            if self.is_allocated(self.position / COW_BLOCK_SIZE) {
+                self.overlay
+                    .seek(SeekFrom::Start(self.data_offset + self.position))?;
+                self.overlay.read_exact(dst)?;
+            } else {
+                self.base.seek(SeekFrom::Start(self.position))?;
+                self.base.read_exact(dst)?;
+            }
+            self.position += len as u64;
+            done += len;
+        }
+        Ok(done)
+    }
+}
+
+impl Write for CowFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() && self.position < self.disk_size {
+            let len = self.chunk_len(self.position, (buf.len() - done) as u64) as usize;
+            let block = self.position / COW_BLOCK_SIZE;
+            let allocated = self.is_allocated(block);
+            // The rest of a partly written block has to keep its data from the base image.
+            if !allocated && (len as u64) < self.block_len(block) {
+                self.copy_up(block)?;
+            }
+            self.overlay
+                .seek(SeekFrom::Start(self.data_offset + self.position))?;
+            self.overlay.write_all(&buf[done..done + len])?;
+            if !self.is_allocated(block) {
+                self.set_allocated(block)?;
+            }
+            self.position += len as u64;
+            done += len;
+        }
+        Ok(done)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.overlay.flush()
+    }
+}
+
+impl Seek for CowFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(self.position, self.disk_size, pos)?;
+        Ok(self.position)
+    }
+}
+
+impl PunchHole for CowFile {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        // The blocks read as zeroes from the overlay, instead of reading from the base image.
+        self.zero_range(offset, length, |overlay, offset, len| {
+            overlay.punch_hole(offset, len)
+        })
+    }
+}
+
+impl WriteZeroes for CowFile {
+    fn write_zeroes(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.zero_range(offset, length, |overlay, offset, len| {
+            overlay.write_zeroes(offset, len)
+        })
+    }
+}
+
+impl DiskFile for CowFile {
+    fn image_file(&self) -> &File {
+        &self.overlay
+    }
+
+    fn is_raw(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::{tempdir, tempfile};
+    use super::*;
+
+    // A base image of three and a half blocks, each filled with its index.
+    fn base_image() -> File {
+        let mut base = tempfile().unwrap();
+        for i in 0..4 {
+            let len = if i == 3 {
+                0x800
+            } else {
+                COW_BLOCK_SIZE as usize
+            };
+            base.write_all(&vec![i as u8 + 1; len]).unwrap();
+        }
+        base
+    }
+
+    fn base_contents() -> Vec<u8> {
+        let mut contents = Vec::new();
+        let mut base = base_image();
+        base.seek(SeekFrom::Start(0)).unwrap();
+        base.read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    fn read_at(disk: &mut DiskFile, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        disk.seek(SeekFrom::Start(offset)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn write_at(disk: &mut DiskFile, offset: u64, data: &[u8]) {
+        disk.seek(SeekFrom::Start(offset)).unwrap();
+        disk.write_all(data).unwrap();
+    }
+
+    #[test]
+    fn test_read_write() {
+        let mut base = base_image();
+        let mut cow =
+            CowFile::new(Box::new(base.try_clone().unwrap()), tempfile().unwrap()).unwrap();
+        let mut expected = base_contents();
+        assert_eq!(cow.seek(SeekFrom::End(0)).unwrap(), expected.len() as u64);
+        assert_eq!(read_at(&mut cow, 0, expected.len()), expected);
+
+        // The write covers the end of a block and the start of the next one.
+        write_at(&mut cow, 0xf00, &[0xaa; 0x200]);
+        for byte in &mut expected[0xf00..0x1100] {
+            *byte = 0xaa;
+        }
+        // Whole blocks, and the partial last one.
+        write_at(&mut cow, 0x2000, &[0xbb; 0x1800]);
+        for byte in &mut expected[0x2000..] {
+            *byte = 0xbb;
+        }
+        assert_eq!(read_at(&mut cow, 0, expected.len()), expected);
+        assert!((0..4).all(|block| cow.is_allocated(block)));
+
+        // Nothing goes past the end of the disk.
+        let mut buf = [0u8; 0x10];
+        cow.seek(SeekFrom::End(-8)).unwrap();
+        assert_eq!(cow.read(&mut buf).unwrap(), 8);
+        assert_eq!(cow.write(&buf).unwrap(), 0);
+
+        // The base image is left alone.
+        let mut contents = Vec::new();
+        base.seek(SeekFrom::Start(0)).unwrap();
+        base.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, base_contents());
+    }
+
+    #[test]
+    fn test_persistent_overlay() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("overlay");
+        let mut cow = CowFile::open(Box::new(base_image()), &path).unwrap();
+        write_at(&mut cow, 0x1010, &[0xaa; 0x10]);
+        drop(cow);
+
+        let mut expected = base_contents();
+        for byte in &mut expected[0x1010..0x1020] {
+            *byte = 0xaa;
+        }
+        let mut cow = CowFile::open(Box::new(base_image()), &path).unwrap();
+        assert!(!cow.is_allocated(0));
+        assert!(cow.is_allocated(1));
+        assert_eq!(read_at(&mut cow, 0, expected.len()), expected);
+        // The unwritten blocks take no space in the overlay.
+        assert_eq!(
+            cow.overlay.metadata().unwrap().len(),
+            cow.data_offset + expected.len() as u64
+        );
+        drop(cow);
+
+        let mut other_base = tempfile().unwrap();
+        other_base.set_len(COW_BLOCK_SIZE).unwrap();
+        match CowFile::open(Box::new(other_base), &path) {
+            Err(Error::SizeMismatch { base, overlay }) => {
+                assert_eq!(base, COW_BLOCK_SIZE);
+                assert_eq!(overlay, expected.len() as u64);
+            }
+            _ => panic!("Expected a size mismatch."),
+        }
+        let mut not_overlay = tempfile().unwrap();
+        not_overlay.write_all(&[0xffu8; 0x1000]).unwrap();
+        match CowFile::new(Box::new(base_image()), not_overlay) {
+            Err(Error::InvalidHeader) => {}
+            _ => panic!("Expected an invalid header."),
+        }
+    }
+
+    #[test]
+    fn test_in_memory_overlay() {
+        let mut cow = CowFile::in_memory(Box::new(base_image())).unwrap();
+        write_at(&mut cow, 0, &[0xaa; 0x10]);
+        assert_eq!(read_at(&mut cow, 0, 0x10), vec![0xaa; 0x10]);
+        assert_eq!(read_at(&mut cow, 0x10, 0x10), vec![1; 0x10]);
+        assert!(!cow.is_raw());
+    }
+
+    #[test]
+    fn test_discard() {
+        let mut cow = CowFile::in_memory(Box::new(base_image())).unwrap();
+        let mut expected = base_contents();
+
+        // Whole blocks read as zeroes, and the rest of partial blocks from the base image.
+        cow.punch_hole(0x800, 0x1000).unwrap();
+        cow.write_zeroes(0x2ff0, 0x20).unwrap();
+        cow.punch_hole(0x3000, 0x1000).unwrap();
+        for range in &[(0x800, 0x1800), (0x2ff0, 0x3010), (0x3000, 0x3800)] {
+            for byte in &mut expected[range.0..range.1] {
+                *byte = 0;
+            }
+        }
+        assert_eq!(read_at(&mut cow, 0, expected.len()), expected);
+        assert!((0..4).all(|block| cow.is_allocated(block)));
+    }
+}
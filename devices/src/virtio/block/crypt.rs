@@ -0,0 +1,464 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encrypts a raw disk image at rest with AES-XTS, in the format of the `aes-xts-plain64`
+//! cipher of dm-crypt, so that the data of the guest cannot be read from the host storage
+//! without the key of the drive.
+//!
+//! Each 512-byte sector is encrypted on its own, with its sector number as the tweak, so that
+//! sectors can be read and written independently and identical sectors do not look alike.
+//!
+//! The AES blocks go through the `aes` crate, which uses the AES instructions of the host CPU
+//! when it has them, and a constant-time implementation otherwise, so that the key does not
+//! leak to the other tenants of the host through cache timings.
+
+use std::cmp;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::result;
+
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::{Aes128, Aes256, Block};
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::disk::{seek_position, DiskFile};
+use super::SECTOR_SIZE;
+use sys_util::{PunchHole, WriteZeroes};
+
+// The number of sectors encrypted or decrypted for a single access to the disk image.
+const CHUNK_SECTORS: u64 = 128;
+// The length of an AES block, in bytes, and the number of blocks in a sector.
+const BLOCK_SIZE: usize = 16;
+const SECTOR_BLOCKS: usize = SECTOR_SIZE as usize / BLOCK_SIZE;
+
+/// Errors setting up the encryption of a disk image.
+#[derive(Debug)]
+pub enum Error {
+    /// The size of the disk image is not a multiple of the sector size.
+    DiskSize(u64),
+    /// The key is neither 32 nor 64 bytes long, or its halves are the same.
+    InvalidKey,
+    /// Cannot size the disk image.
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match *self {
+            DiskSize(size) => write!(
+                f,
+                "The disk image size {} is not a multiple of {} bytes.",
+                size, SECTOR_SIZE
+            ),
+            InvalidKey => write!(f, "The key is not a valid AES-XTS key."),
+            Io(ref e) => write!(f, "Cannot size the disk image: {}", e),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// One half of an AES-XTS key, expanded.
+enum Cipher {
+    Aes128(Aes128),
+    Aes256(Aes256),
+}
+
+impl Cipher {
+    fn new(key: &[u8]) -> Result<Cipher> {
+        match key.len() {
+            16 => Aes128::new_from_slice(key).map(Cipher::Aes128),
+            32 => Aes256::new_from_slice(key).map(Cipher::Aes256),
+            _ => return Err(Error::InvalidKey),
+        }
+        .map_err(|_| Error::InvalidKey)
+    }
+
+    fn encrypt_blocks(&self, blocks: &mut [Block]) {
+        match *self {
+            Cipher::Aes128(ref aes) => aes.encrypt_blocks(blocks),
+            Cipher::Aes256(ref aes) => aes.encrypt_blocks(blocks),
+        }
+    }
+
+    fn decrypt_blocks(&self, blocks: &mut [Block]) {
+        match *self {
+            Cipher::Aes128(ref aes) => aes.decrypt_blocks(blocks),
+            Cipher::Aes256(ref aes) => aes.decrypt_blocks(blocks),
+        }
+    }
+}
+
+/// A disk whose sectors are decrypted when read and encrypted when written.
+pub struct CryptFile {
+    disk: Box<DiskFile>,
+    // The first half of the key encrypts the data, the second half the tweaks.
+    data_cipher: Cipher,
+    tweak_cipher: Cipher,
+    disk_size: u64,
+    // The sectors being encrypted or decrypted, so that the data of the guest is not encrypted
+    // in place.
+    buffer: Vec<u8>,
+    // The blocks of the sector being encrypted or decrypted, and their tweaks.
+    blocks: [Block; SECTOR_BLOCKS],
+    tweaks: [Block; SECTOR_BLOCKS],
+    position: u64,
+}
+
+impl CryptFile {
+    /// Encrypts `disk` with `key`, which is 32 bytes long for AES-128-XTS, or 64 bytes long for
+    /// AES-256-XTS.
+    pub fn new(mut disk: Box<DiskFile>, key: &[u8]) -> Result<CryptFile> {
+        if key.len() != 32 && key.len() != 64 {
+            return Err(Error::InvalidKey);
+        }
+        let (data_key, tweak_key) = key.split_at(key.len() / 2);
+        // XTS is only secure with two independent keys.
+        if data_key == tweak_key {
+            return Err(Error::InvalidKey);
+        }
+        let disk_size = disk.seek(SeekFrom::End(0)).map_err(Error::Io)?;
+        if disk_size % SECTOR_SIZE != 0 {
+            return Err(Error::DiskSize(disk_size));
+        }
+
+        Ok(CryptFile {
+            disk,
+            data_cipher: Cipher::new(data_key)?,
+            tweak_cipher: Cipher::new(tweak_key)?,
+            disk_size,
+            buffer: Vec::new(),
+            blocks: Default::default(),
+            tweaks: Default::default(),
+            position: 0,
+        })
+    }
+
+    // Encrypts or decrypts the sectors in `self.buffer`, the first of which is `sector`.
+    fn crypt_buffer(&mut self, mut sector: u64, encrypt: bool) {
+        for data in self.buffer.chunks_mut(SECTOR_SIZE as usize) {
+            let mut tweak = Block::default();
+            LittleEndian::write_u64(&mut tweak, sector);
+            self.tweak_cipher
+                .encrypt_blocks(std::slice::from_mut(&mut tweak));
+            for ((block, block_tweak), data_block) in self
+                .blocks
+                .iter_mut()
+                .zip(self.tweaks.iter_mut())
+                .zip(data.chunks(BLOCK_SIZE))
+            {
+                *block_tweak = tweak;
+                xor_block(block, data_block, &tweak);
+                next_tweak(&mut tweak);
+            }
+            // The blocks of a sector go through the cipher together, which lets it process
+            // several of them at once.
+            if encrypt {
+                self.data_cipher.encrypt_blocks(&mut self.blocks);
+            } else {
+                self.data_cipher.decrypt_blocks(&mut self.blocks);
+            }
+            for ((data_block, block), block_tweak) in data
+                .chunks_mut(BLOCK_SIZE)
+                .zip(self.blocks.iter())
+                .zip(self.tweaks.iter())
+            {
+                xor_block(data_block, block, block_tweak);
+            }
+            sector += 1;
+        }
+    }
+
+    // Reads and decrypts `sectors` sectors from `sector` into `self.buffer`.
+    fn read_sectors(&mut self, sector: u64, sectors: u64) -> io::Result<()> {
+        self.buffer.resize((sectors * SECTOR_SIZE) as usize, 0);
+        self.disk.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        self.disk.read_exact(&mut self.buffer)?;
+        self.crypt_buffer(sector, false);
+        Ok(())
+    }
+
+    // Returns the first sector and the number of sectors of the next chunk covering `len`
+    // bytes from the current position.
+    fn next_chunk(&self, len: usize) -> (u64, u64) {
+        let offset = self.position % SECTOR_SIZE;
+        let sectors = (offset + len as u64 + SECTOR_SIZE - 1) / SECTOR_SIZE;
+        (
+            self.position / SECTOR_SIZE,
+            cmp::min(sectors, CHUNK_SECTORS),
+        )
+    }
+
+    // Overwrites `length` bytes from `offset` with encrypted zeroes.
+    fn zero_range(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        let position = self.position;
+        let zeroes = vec![0u8; (CHUNK_SECTORS * SECTOR_SIZE) as usize];
+        self.position = offset;
+        let mut remaining = length;
+        let result = loop {
+            if remaining == 0 {
+                break Ok(());
+            }
+            let len = cmp::min(remaining, zeroes.len() as u64) as usize;
+            if let Err(e) = self.write_all(&zeroes[..len]) {
+                break Err(e);
+            }
+            remaining -= len as u64;
+        };
+        self.position = position;
+        result
+    }
+}
+
+// Sets `out` to `a` xor `b`.
+fn xor_block(out: &mut [u8], a: &[u8], b: &[u8]) {
+    for ((byte, a), b) in out.iter_mut().zip(a).zip(b) {
+        *byte = a ^ b;
+    }
+}
+
+// Multiplies the tweak by x in GF(2^128), as it moves to the next block of the sector.
+fn next_tweak(tweak: &mut Block) {
+    let carry = tweak[BLOCK_SIZE - 1] >> 7;
+    for i in (1..BLOCK_SIZE).rev() {
+        tweak[i] = (tweak[i] << 1) | (tweak[i - 1] >> 7);
+    }
+    tweak[0] = (tweak[0] << 1) ^ (carry * 0x87);
+}
+
+impl Read for CryptFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(
+            buf.len() as u64,
+            self.disk_size.saturating_sub(self.position),
+        );
+        let mut done = 0;
+        while done < len as usize {
+            let (sector, sectors) = self.next_chunk(len as usize - done);
+            self.read_sectors(sector, sectors)?;
+            let offset = (self.position % SECTOR_SIZE) as usize;
+            let count = cmp::min(len as usize - done, self.buffer.len() - offset);
+            buf[done..done + count].copy_from_slice(&self.buffer[offset..offset + count]);
+            self.position += count as u64;
+            done += count;
+        }
+        Ok(done)
+    }
+}
+
+impl Write for CryptFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = cmp::min(
+            buf.len() as u64,
+            self.disk_size.saturating_sub(self.position),
+        );
+        let mut done = 0;
+        while done < len as usize {
+            let (sector, sectors) = self.next_chunk(len as usize - done);
+            let offset = (self.position % SECTOR_SIZE) as usize;
+            let count = cmp::min(
+                len as usize - done,
+                (sectors * SECTOR_SIZE) as usize - offset,
+            );
+            // Sectors only partly written keep the rest of their data.
+            if offset != 0 || count as u64 % SECTOR_SIZE != 0 {
+                self.read_sectors(sector, sectors)?;
+            } else {
+                self.buffer.resize(count, 0);
+            }
+            self.buffer[offset..offset + count].copy_from_slice(&buf[done..done + count]);
+            self.crypt_buffer(sector, true);
+            self.disk.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+            self.disk.write_all(&self.buffer)?;
+            self.position += count as u64;
+            done += count;
+        }
+        Ok(done)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.disk.flush()
+    }
+}
+
+impl Seek for CryptFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(self.position, self.disk_size, pos)?;
+        Ok(self.position)
+    }
+}
+
+// Zeroed ranges are written as encrypted zeroes, as holes in the disk image would neither read
+// back as zeroes nor hide which parts of the disk are in use.
+impl PunchHole for CryptFile {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.zero_range(offset, length)
+    }
+}
+
+impl WriteZeroes for CryptFile {
+    fn write_zeroes(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.zero_range(offset, length)
+    }
+}
+
+impl DiskFile for CryptFile {
+    fn image_file(&self) -> &File {
+        self.disk.image_file()
+    }
+
+    fn is_raw(&self) -> bool {
+        false
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.disk.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempfile;
+    use super::*;
+
+    fn decode(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn crypt_file(size: u64, key: &[u8]) -> (File, CryptFile) {
+        let disk = tempfile().unwrap();
+        disk.set_len(size).unwrap();
+        let crypt = CryptFile::new(Box::new(disk.try_clone().unwrap()), key).unwrap();
+        (disk, crypt)
+    }
+
+    fn read_at<F: Read + Seek>(file: &mut F, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_vectors() {
+        // The 512-byte data units of the XTS-AES-128 and XTS-AES-256 vectors 4 and 10 of
+        // IEEE 1619, as the sectors 0 and 0xff.
+        let vectors = [
+            (
+                "27182818284590452353602874713526\
+                 31415926535897932384626433832795",
+                0,
+                "27a7479befa1d476489f308cd4cfa6e2a96e4bbe3208ff25287dd3819616e89c",
+                "eb4a427d1923ce3ff262735779a418f20a282df920147beabe421ee5319d0568",
+            ),
+            (
+                "2718281828459045235360287471352662497757247093699959574966967627\
+                 3141592653589793238462643383279502884197169399375105820974944592",
+                0xff,
+                "1c3b3a102f770386e4836c99e370cf9bea00803f5e482357a4ae12d414a3e63b",
+                "773dad38014bd2092fa755c824bb5e54c4f36ffda9fcea70b9c6e693e148c151",
+            ),
+        ];
+        let plaintext: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        for &(key, sector, first, last) in &vectors {
+            let (mut disk, mut crypt) = crypt_file(0x100 * SECTOR_SIZE, &decode(key));
+            crypt.seek(SeekFrom::Start(sector * SECTOR_SIZE)).unwrap();
+            crypt.write_all(&plaintext).unwrap();
+            let ciphertext = read_at(&mut disk, sector * SECTOR_SIZE, plaintext.len());
+            assert_eq!(&ciphertext[..32], &decode(first)[..]);
+            assert_eq!(&ciphertext[480..], &decode(last)[..]);
+            assert_eq!(
+                read_at(&mut crypt, sector * SECTOR_SIZE, plaintext.len()),
+                plaintext
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_write() {
+        let key: Vec<u8> = (0..64).collect();
+        let (mut disk, mut crypt) = crypt_file(0x10000, &key);
+        assert_eq!(crypt.seek(SeekFrom::End(0)).unwrap(), 0x10000);
+        let mut expected = read_at(&mut crypt, 0, 0x10000);
+
+        // Writes which do not line up with the sectors keep the rest of the sectors.
+        let data: Vec<u8> = (0..0x1234).map(|i| (i % 251) as u8).collect();
+        for &offset in &[0x1000, 0x50ff, 0xf000 - 0x1234] {
+            crypt.seek(SeekFrom::Start(offset)).unwrap();
+            crypt.write_all(&data).unwrap();
+            expected[offset as usize..offset as usize + data.len()].copy_from_slice(&data);
+        }
+        assert_eq!(read_at(&mut crypt, 0, 0x10000), expected);
+        assert_eq!(
+            read_at(&mut crypt, 0x50fe, 0x1300),
+            &expected[0x50fe..0x63fe]
+        );
+        // Only the ciphertext reaches the disk image.
+        let ciphertext = read_at(&mut disk, 0x1000, 0x1234);
+        assert_ne!(ciphertext, data);
+        assert!(ciphertext.windows(16).all(|window| window != &data[..16]));
+
+        // The same data is encrypted differently in each sector.
+        crypt.seek(SeekFrom::Start(0x2000)).unwrap();
+        crypt.write_all(&[0x5a; 0x400]).unwrap();
+        assert_ne!(
+            read_at(&mut disk, 0x2000, 0x200),
+            read_at(&mut disk, 0x2200, 0x200)
+        );
+
+        // Zeroed ranges read back as zeroes, without holes in the disk image.
+        crypt.punch_hole(0x1100, 0x200).unwrap();
+        crypt.write_zeroes(0x2000, 0x400).unwrap();
+        for byte in &mut expected[0x1100..0x1300] {
+            *byte = 0;
+        }
+        for byte in &mut expected[0x2000..0x2400] {
+            *byte = 0;
+        }
+        assert_eq!(read_at(&mut crypt, 0, 0x10000), expected);
+        assert!(read_at(&mut disk, 0x2000, 0x400)
+            .iter()
+            .any(|&byte| byte != 0));
+
+        // The disk image does not grow.
+        crypt.seek(SeekFrom::Start(0xfff0)).unwrap();
+        assert_eq!(crypt.write(&[0u8; 0x20]).unwrap(), 0x10);
+        assert_eq!(crypt.write(&[0u8; 0x20]).unwrap(), 0);
+        assert_eq!(disk.metadata().unwrap().len(), 0x10000);
+    }
+
+    #[test]
+    fn test_new_errors() {
+        let disk = || {
+            let disk = tempfile().unwrap();
+            disk.set_len(0x1000).unwrap();
+            Box::new(disk)
+        };
+        let key: Vec<u8> = (0..64).collect();
+        for key_len in &[16, 24, 48] {
+            match CryptFile::new(disk(), &key[..*key_len]) {
+                Err(Error::InvalidKey) => (),
+                _ => panic!("Only AES-128-XTS and AES-256-XTS keys are supported."),
+            }
+        }
+        match CryptFile::new(disk(), &[7u8; 64]) {
+            Err(Error::InvalidKey) => (),
+            _ => panic!("The halves of the key have to be different."),
+        }
+
+        let unaligned_disk = disk();
+        unaligned_disk.set_len(0x1001).unwrap();
+        match CryptFile::new(unaligned_disk, &key) {
+            Err(Error::DiskSize(size)) => assert_eq!(size, 0x1001),
+            _ => panic!("The disk has to be made of whole sectors."),
+        }
+    }
+}
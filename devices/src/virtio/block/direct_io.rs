@@ -0,0 +1,143 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::io::{self, Read, Write};
+use std::slice;
+
+use super::DiskFile;
+
+/// The alignment of the buffers, offsets and lengths of transfers to and from disk images
+/// opened with O_DIRECT, which is the sector size of the guest.
+pub const DIRECT_IO_ALIGNMENT: usize = 512;
+
+/// Returns whether the `len` bytes at `addr` can be transferred with O_DIRECT as they are.
+pub(super) fn is_aligned(addr: *const u8, len: usize) -> bool {
+    len % DIRECT_IO_ALIGNMENT == 0 && (len == 0 || addr as usize % DIRECT_IO_ALIGNMENT == 0)
+}
+
+/// A buffer aligned for O_DIRECT transfers, standing in for a guest buffer which is not.
+pub(super) struct BounceBuffer {
+    addr: *mut u8,
+    layout: Layout,
+}
+
+// The buffer is owned by the `BounceBuffer`, like the buffer of a `Vec`.
+unsafe impl Send for BounceBuffer {}
+
+impl BounceBuffer {
+    /// Allocates a zeroed buffer of `len` bytes, which must not be 0.
+    pub fn new(len: usize) -> BounceBuffer {
+        let layout = Layout::from_size_align(len, DIRECT_IO_ALIGNMENT)
+            .expect("Invalid bounce buffer length");
+        // This is safe because the length of the layout is not 0.
+        let addr = unsafe { alloc_zeroed(layout) };
+        if addr.is_null() {
+            handle_alloc_error(layout);
+        }
+        BounceBuffer { addr, layout }
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // This is safe because the buffer holds `layout.size()` initialized bytes.
+        unsafe { slice::from_raw_parts(self.addr, self.layout.size()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // This is safe because the buffer holds `layout.size()` initialized bytes, and is
+        // borrowed mutably.
+        unsafe { slice::from_raw_parts_mut(self.addr, self.layout.size()) }
+    }
+}
+
+impl Drop for BounceBuffer {
+    fn drop(&mut self) {
+        // This is safe because the buffer was allocated with this layout.
+        unsafe { dealloc(self.addr, self.layout) };
+    }
+}
+
+/// Reads and writes a disk image opened with O_DIRECT, going through a bounce buffer for the
+/// buffers which are not aligned.
+pub(super) struct DirectIo<'a> {
+    disk: &'a mut DiskFile,
+}
+
+impl<'a> DirectIo<'a> {
+    pub fn new(disk: &'a mut DiskFile) -> DirectIo<'a> {
+        DirectIo { disk }
+    }
+}
+
+impl<'a> Read for DirectIo<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if is_aligned(buf.as_ptr(), buf.len()) {
+            return self.disk.read(buf);
+        }
+        let mut bounce = BounceBuffer::new(buf.len());
+        let read = self.disk.read(bounce.as_mut_slice())?;
+        buf[..read].copy_from_slice(&bounce.as_slice()[..read]);
+        Ok(read)
+    }
+}
+
+impl<'a> Write for DirectIo<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if is_aligned(buf.as_ptr(), buf.len()) {
+            return self.disk.write(buf);
+        }
+        let mut bounce = BounceBuffer::new(buf.len());
+        bounce.as_mut_slice().copy_from_slice(buf);
+        self.disk.write(bounce.as_slice())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.disk.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::fs::File;
+    use std::io::{Seek, SeekFrom};
+
+    use self::tempfile::tempfile;
+    use super::*;
+
+    #[test]
+    fn test_bounce_buffer() {
+        let mut bounce = BounceBuffer::new(0x1000);
+        assert!(is_aligned(bounce.as_ptr(), 0x1000));
+        assert!(bounce.as_slice().iter().all(|&byte| byte == 0));
+        bounce.as_mut_slice()[0xfff] = 0xaa;
+        assert_eq!(bounce.as_slice()[0xfff], 0xaa);
+
+        assert!(!is_aligned(bounce.as_ptr(), 0x100));
+        assert!(!is_aligned(unsafe { bounce.as_ptr().add(1) }, 0x200));
+        assert!(is_aligned(unsafe { bounce.as_ptr().add(1) }, 0));
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let mut file: File = tempfile().unwrap();
+        let data: Vec<u8> = (0..0x800).map(|i| i as u8).collect();
+        let mut buf = vec![0u8; 0x801];
+        // The buffers of the first write and of the read are misaligned on purpose.
+        {
+            let mut disk = DirectIo::new(&mut file);
+            disk.write_all(&data[1..0x201]).unwrap();
+            disk.write_all(&data[0x201..]).unwrap();
+        }
+        file.seek(SeekFrom::Start(0)).unwrap();
+        DirectIo::new(&mut file)
+            .read_exact(&mut buf[1..0x800])
+            .unwrap();
+        assert_eq!(&buf[1..0x800], &data[1..]);
+    }
+}
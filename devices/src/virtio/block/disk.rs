@@ -0,0 +1,108 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::result;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::qcow::{Error as QcowError, QcowFile, QCOW_MAGIC};
+use sys_util::{PunchHole, WriteZeroes};
+
+/// A disk image, addressed by its offsets as seen by the guest.
+pub trait DiskFile: Read + Seek + Write + PunchHole + WriteZeroes + Send {
+    /// Returns the host file the disk image is stored in.
+    fn image_file(&self) -> &File;
+
+    /// Returns whether the guest offsets of the disk image are the offsets in its host file,
+    /// so that requests can go straight to the file.
+    fn is_raw(&self) -> bool;
+
+    /// Makes the writes to the disk image so far durable.
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.image_file().sync_all()
+    }
+
+    /// Lets the disk image deallocate a range, which reads back as anything afterwards.
+    fn discard(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.punch_hole(offset, length)
+    }
+}
+
+impl DiskFile for File {
+    fn image_file(&self) -> &File {
+        self
+    }
+
+    fn is_raw(&self) -> bool {
+        true
+    }
+}
+
+// Returns the position a seek to `pos` leads to, from `position` in a disk image of `size` bytes.
+pub(super) fn seek_position(position: u64, size: u64, pos: SeekFrom) -> io::Result<u64> {
+    let checked_offset = |base: u64, offset: i64| {
+        if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        }
+    };
+    match pos {
+        SeekFrom::Start(offset) => Some(offset),
+        SeekFrom::End(offset) => checked_offset(size, offset),
+        SeekFrom::Current(offset) => checked_offset(position, offset),
+    }
+    .ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
+/// The formats a disk image can be stored in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    /// The disk contents as they are.
+    Raw,
+    /// The QEMU copy-on-write format, version 2 or 3.
+    Qcow2,
+}
+
+/// Tells the format of the disk image in `file` from its first bytes. Images which do not
+/// start with a known header are raw.
+pub fn detect_image_format(file: &mut File) -> io::Result<ImageFormat> {
+    let mut magic = [0u8; 4];
+    file.seek(SeekFrom::Start(0))?;
+    let read = file.read(&mut magic);
+    file.seek(SeekFrom::Start(0))?;
+    if read? == magic.len() && BigEndian::read_u32(&magic) == QCOW_MAGIC {
+        Ok(ImageFormat::Qcow2)
+    } else {
+        Ok(ImageFormat::Raw)
+    }
+}
+
+/// Opens the disk image in `file`, stored at `path`, in `format`, or in the format detected from
+/// its contents if `format` is `None`.
+///
+/// The format should not be detected for images the guest can write to, since the guest could
+/// then write a header making the image look like another format the next time it is opened.
+pub fn open_disk_image(
+    mut file: File,
+    path: &Path,
+    format: Option<ImageFormat>,
+) -> result::Result<Box<DiskFile>, QcowError> {
+    let format = match format {
+        Some(format) => format,
+        None => detect_image_format(&mut file).map_err(QcowError::Io)?,
+    };
+    match format {
+        ImageFormat::Raw => Ok(Box::new(file)),
+        ImageFormat::Qcow2 => Ok(Box::new(QcowFile::open(file, path)?)),
+    }
+}
@@ -0,0 +1,353 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+
+use super::direct_io::{is_aligned, BounceBuffer};
+use super::{
+    complete_request, CacheMode, CachePolicy, DiskFile, ExecuteError, Request, RequestType,
+};
+use logger::{Metric, METRICS};
+use memory_model::GuestMemory;
+use sys_util::io_uring::{CompletionEntry, IoUring, SubmissionEntry};
+use sys_util::EventFd;
+use virtio::Queue;
+
+// A request taken off the virtio queue, which has not been added to the used ring yet.
+struct PendingRequest {
+    queue_index: usize,
+    desc_index: u16,
+    user_data: u64,
+    // The request waiting for its io_uring operation, if any.
+    request: Option<Request>,
+    // The length to add to the used ring, once the request is complete.
+    used_len: Option<u32>,
+    // The aligned buffer the request transfers from or into, in place of its guest buffer.
+    bounce: Option<BounceBuffer>,
+    // The entry last submitted for the request, if it is a read or a write.
+    entry: Option<SubmissionEntry>,
+    // The number of bytes the read or write transferred so far.
+    transferred: u32,
+}
+
+/// Executes block requests asynchronously through an io_uring, straight from and into the
+/// guest memory buffers, so that slow disks do not stall the VMM thread.
+///
+/// Requests can complete in any order, but they are added to the used ring of their queue in
+/// the order they were taken off its available ring, so the used ring index keeps telling which
+/// requests are done.
+pub struct IoUringEngine {
+    ring: IoUring,
+    completion_evt: EventFd,
+    pending: VecDeque<PendingRequest>,
+    next_user_data: u64,
+}
+
+impl IoUringEngine {
+    /// Sets up an io_uring for up to `entries` requests in flight, which should be enough for
+    /// all the virtio queues of the device to be full.
+    pub fn new(entries: u32) -> io::Result<IoUringEngine> {
+        let ring = IoUring::new(entries)?;
+        let completion_evt = EventFd::new()?;
+        ring.register_eventfd(&completion_evt)?;
+        Ok(IoUringEngine {
+            ring,
+            completion_evt,
+            pending: VecDeque::with_capacity(entries as usize),
+            next_user_data: 0,
+        })
+    }
+
+    /// Returns the event signaled whenever a request completes.
+    pub fn completion_evt(&self) -> &EventFd {
+        &self.completion_evt
+    }
+
+    /// Returns whether no more requests can be taken off the virtio queue until some of the
+    /// pending ones complete.
+    pub(super) fn is_full(&self) -> bool {
+        self.pending.len() >= self.ring.sq_entries() as usize
+    }
+
+    /// Queues a request which is already complete, to be added to the used ring of the queue
+    /// at `queue_index` after the requests before it.
+    pub(super) fn push_completed(&mut self, queue_index: usize, desc_index: u16, used_len: u32) {
+        let user_data = self.next_user_data();
+        self.pending.push_back(PendingRequest {
+            queue_index,
+            desc_index,
+            user_data,
+            request: None,
+            used_len: Some(used_len),
+            bounce: None,
+            entry: None,
+            transferred: 0,
+        });
+    }
+
+    /// Starts executing `request`. Reads, writes and flushes go through the io_uring, while the
+    /// other requests are executed right away.
+    #[allow(clippy::ptr_arg, clippy::too_many_arguments)]
+    pub(super) fn push_request(
+        &mut self,
+        queue_index: usize,
+        desc_index: u16,
+        request: Request,
+        disk: &mut DiskFile,
+        disk_nsectors: u64,
+        mem: &GuestMemory,
+        disk_id: &Vec<u8>,
+        cache: CachePolicy,
+    ) {
+        let user_data = self.next_user_data();
+        let mut bounce = None;
+        let mut submitted = None;
+        let asynchronous = match request.request_type {
+            RequestType::In | RequestType::Out => true,
+            RequestType::Flush => cache.sync_on_flush(),
+            _ => false,
+        };
+        let used_len = if asynchronous {
+            let pushed = self
+                .submission_entry(
+                    &request,
+                    disk.image_file().as_raw_fd(),
+                    disk_nsectors,
+                    mem,
+                    user_data,
+                    cache,
+                    &mut bounce,
+                )
+                .and_then(|entry| {
+                    // This is safe because the buffer is either in guest memory, which stays
+                    // mapped for as long as the device exists, or a bounce buffer, which is kept
+                    // along with the request until it completes.
+                    unsafe { self.ring.push(entry) }
+                        .map(|()| entry)
+                        .map_err(|e| {
+                            METRICS.block.io_submit_fails.inc();
+                            ExecuteError::AsyncIo(e)
+                        })
+                });
+            match pushed {
+                Ok(entry) => {
+                    if request.request_type != RequestType::Flush {
+                        submitted = Some(entry);
+                    }
+                    None
+                }
+                Err(e) => Some(complete_request(mem, &request, Err(e))),
+            }
+        } else {
+            let result = request.execute(disk, disk_nsectors, mem, disk_id, cache);
+            Some(complete_request(mem, &request, result))
+        };
+        self.pending.push_back(PendingRequest {
+            queue_index,
+            desc_index,
+            user_data,
+            request: if used_len.is_none() {
+                Some(request)
+            } else {
+                None
+            },
+            used_len,
+            bounce,
+            entry: submitted,
+            transferred: 0,
+        });
+    }
+
+    /// Submits the requests pushed so far to the kernel.
+    pub(super) fn submit(&mut self) {
+        // The requests which could not be submitted stay on the submission ring, and are
+        // submitted along with the next ones.
+        if let Err(e) = self.ring.submit() {
+            error!("Failed to submit block requests to the io_uring: {:?}", e);
+            METRICS.block.io_submit_fails.inc();
+        }
+    }
+
+    /// Collects the completed requests, and adds those which are next in line to the used ring
+    /// of their queue. Returns whether any request was added to a used ring.
+    pub(super) fn complete(&mut self, queues: &mut [Queue], mem: &GuestMemory) -> bool {
+        while let Some(completion) = self.ring.pop_completion() {
+            self.complete_entry(completion, mem);
+        }
+        // Submit the rest of the short transfers, along with the requests the kernel could not
+        // take before.
+        self.submit();
+
+        let mut used = false;
+        // A queue is blocked once one of its requests is still in flight, so that the requests
+        // after it wait for their turn.
+        let mut blocked = vec![false; queues.len()];
+        self.pending.retain(|pending| {
+            let queue_index = pending.queue_index;
+            match pending.used_len {
+                Some(used_len) if !blocked[queue_index] => {
+                    queues[queue_index].add_used(mem, pending.desc_index, used_len);
+                    used = true;
+                    false
+                }
+                _ => {
+                    blocked[queue_index] = true;
+                    true
+                }
+            }
+        });
+        used
+    }
+
+    /// Waits for all the requests in flight to complete, and adds every pending request to the
+    /// used ring of its queue, so that the kernel is done with the guest memory. Returns whether
+    /// any request was added to a used ring.
+    pub(super) fn drain(&mut self, queues: &mut [Queue], mem: &GuestMemory) -> io::Result<bool> {
+        let mut used = self.complete(queues, mem);
+        while self.pending.iter().any(|pending| pending.request.is_some()) {
+            self.ring.wait()?;
+            used |= self.complete(queues, mem);
+        }
+        Ok(used)
+    }
+
+    fn next_user_data(&mut self) -> u64 {
+        let user_data = self.next_user_data;
+        self.next_user_data = self.next_user_data.wrapping_add(1);
+        user_data
+    }
+
+    // Returns the entry to submit for `request`. A read or write goes through a bounce buffer,
+    // put in `bounce`, if its guest buffer is not aligned for O_DIRECT.
+    #[allow(clippy::too_many_arguments)]
+    fn submission_entry(
+        &self,
+        request: &Request,
+        fd: RawFd,
+        disk_nsectors: u64,
+        mem: &GuestMemory,
+        user_data: u64,
+        cache: CachePolicy,
+        bounce: &mut Option<BounceBuffer>,
+    ) -> result::Result<SubmissionEntry, ExecuteError> {
+        request.check_capacity(disk_nsectors)?;
+        let offset = request.sector << super::SECTOR_SHIFT;
+        let len = request.data_len as usize;
+        match request.request_type {
+            RequestType::In => {
+                let mut buf = mem
+                    .get_host_address_range(request.data_addr, len)
+                    .map_err(ExecuteError::Read)?;
+                if cache.mode == CacheMode::Direct && !is_aligned(buf, len) {
+                    let buffer = BounceBuffer::new(len);
+                    buf = buffer.as_ptr();
+                    *bounce = Some(buffer);
+                }
+                Ok(SubmissionEntry::read(
+                    fd,
+                    buf,
+                    request.data_len,
+                    offset,
+                    user_data,
+                ))
+            }
+            RequestType::Out => {
+                let mut buf = mem
+                    .get_host_address_range(request.data_addr, len)
+                    .map_err(ExecuteError::Write)?;
+                if cache.mode == CacheMode::Direct && !is_aligned(buf, len) {
+                    let mut buffer = BounceBuffer::new(len);
+                    mem.read_slice_at_addr(buffer.as_mut_slice(), request.data_addr)
+                        .map_err(ExecuteError::Write)?;
+                    buf = buffer.as_ptr();
+                    *bounce = Some(buffer);
+                }
+                let entry = SubmissionEntry::write(fd, buf, request.data_len, offset, user_data);
+                if cache.sync_on_write() {
+                    Ok(entry.dsync())
+                } else {
+                    Ok(entry)
+                }
+            }
+            _ => Ok(SubmissionEntry::fsync(fd, user_data)),
+        }
+    }
+
+    fn complete_entry(&mut self, completion: CompletionEntry, mem: &GuestMemory) {
+        let pending = match self
+            .pending
+            .iter_mut()
+            .find(|pending| pending.user_data == completion.user_data())
+        {
+            Some(pending) => pending,
+            None => {
+                error!(
+                    "Received a completion for an unknown block request: {}",
+                    completion.user_data()
+                );
+                return;
+            }
+        };
+        let request = match pending.request.take() {
+            Some(request) => request,
+            None => return,
+        };
+        let bounce = pending.bounce.take();
+
+        let mut result = completion.result().map_err(ExecuteError::AsyncIo);
+        if let (Ok(transferred), Some(entry)) = (result.as_ref(), pending.entry) {
+            pending.transferred += *transferred;
+            if pending.transferred < request.data_len {
+                // A read or write can transfer less than it was asked to, e.g. when it is
+                // interrupted, and then the rest of it is submitted again. Transferring nothing
+                // means the end of the file was reached.
+                result = if *transferred > 0 {
+                    let entry = entry.advance(*transferred);
+                    // This is safe because the buffer is kept until the request completes, as
+                    // when it was first submitted.
+                    match unsafe { self.ring.push(entry) } {
+                        Ok(()) => {
+                            pending.request = Some(request);
+                            pending.bounce = bounce;
+                            pending.entry = Some(entry);
+                            return;
+                        }
+                        Err(e) => {
+                            METRICS.block.io_submit_fails.inc();
+                            Err(ExecuteError::AsyncIo(e))
+                        }
+                    }
+                } else {
+                    Err(ExecuteError::AsyncIo(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "short transfer",
+                    )))
+                };
+            }
+        }
+
+        let transferred = pending.transferred;
+        let result = result.and_then(|_| match request.request_type {
+            RequestType::Flush => {
+                METRICS.block.flush_count.inc();
+                Ok(0)
+            }
+            RequestType::In => {
+                if let Some(ref bounce) = bounce {
+                    mem.write_slice_at_addr(bounce.as_slice(), request.data_addr)
+                        .map_err(ExecuteError::Read)?;
+                }
+                METRICS.block.read_count.add(transferred as usize);
+                Ok(transferred)
+            }
+            _ => {
+                METRICS.block.write_count.add(transferred as usize);
+                Ok(0)
+            }
+        });
+        pending.used_len = Some(complete_request(mem, &request, result));
+    }
+}
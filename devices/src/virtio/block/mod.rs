@@ -0,0 +1,3003 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Portions Copyright 2017 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the THIRD-PARTY file.
+
+mod cow;
+mod crypt;
+mod direct_io;
+mod disk;
+mod io_uring;
+mod nbd;
+mod qcow;
+mod sha256;
+mod verity;
+
+use epoll;
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use self::direct_io::DirectIo;
+use super::super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DescriptorChain, EpollHandlerPayload, Queue, VirtioDevice,
+    TYPE_BLOCK, VIRTIO_MMIO_INT_VRING,
+};
+use logger::{Metric, METRICS};
+use memory_model::{DataInit, GuestAddress, GuestMemory, GuestMemoryError};
+use rate_limiter::{RateLimiter, TokenType};
+use sys_util::EventFd;
+use virtio_gen::virtio_blk::*;
+use {DeviceEventT, EpollHandler};
+
+pub use self::cow::{CowFile, Error as CowError};
+pub use self::crypt::{CryptFile, Error as CryptError};
+pub use self::direct_io::DIRECT_IO_ALIGNMENT;
+pub use self::disk::{detect_image_format, open_disk_image, DiskFile, ImageFormat};
+pub use self::io_uring::IoUringEngine;
+pub use self::nbd::{Error as NbdError, NbdDisk};
+pub use self::qcow::{Error as QcowError, QcowFile};
+pub use self::verity::{Error as VerityError, VerityFile, VERITY_BLOCK_SIZE};
+
+// The configuration space, up to the discard and write zeroes fields.
+pub(crate) const CONFIG_SPACE_SIZE: usize = 60;
+// The capacity field, at the start of the configuration space.
+const CAPACITY_SIZE: usize = 8;
+// Offset of the write cache field in the configuration space.
+pub(crate) const WRITEBACK_OFFSET: usize = 32;
+// Offset of the number of queues in the configuration space.
+pub(crate) const NUM_QUEUES_OFFSET: usize = 34;
+// Offsets of the discard and write zeroes fields in the configuration space.
+const MAX_DISCARD_SECTORS_OFFSET: usize = 36;
+const MAX_DISCARD_SEG_OFFSET: usize = 40;
+const DISCARD_SECTOR_ALIGNMENT_OFFSET: usize = 44;
+const MAX_WRITE_ZEROES_SECTORS_OFFSET: usize = 48;
+const MAX_WRITE_ZEROES_SEG_OFFSET: usize = 52;
+const WRITE_ZEROES_MAY_UNMAP_OFFSET: usize = 56;
+// The number of segments a discard or write zeroes request can hold.
+const MAX_DISCARD_SEGMENTS: u32 = 1;
+const SECTOR_SHIFT: u8 = 9;
+pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
+/// The default size of the virtio queues.
+pub const QUEUE_SIZE: u16 = 256;
+/// The largest size of the virtio queues.
+pub const MAX_QUEUE_SIZE: u16 = 1024;
+/// The largest number of virtio queues.
+pub const MAX_QUEUES: u16 = 32;
+
+// Rate limiter budget is now available.
+const RATE_LIMITER_EVENT: DeviceEventT = 0;
+// Backing file on the host has changed.
+pub const FS_UPDATE_EVENT: DeviceEventT = 1;
+// Requests submitted to the io_uring have completed.
+const IO_COMPLETION_EVENT: DeviceEventT = 2;
+// The requests in flight have to complete before the guest memory is saved.
+pub const DRAIN_EVENT: DeviceEventT = 3;
+// New descriptors are pending on the first virtio queue. The events of the other queues follow.
+const QUEUE_AVAIL_EVENT: DeviceEventT = 4;
+
+/// Returns the number of DeviceEventT events of a block device with `num_queues` queues.
+pub fn block_events_count(num_queues: usize) -> usize {
+    QUEUE_AVAIL_EVENT as usize + num_queues
+}
+
+#[derive(Debug)]
+enum Error {
+    /// Guest gave us bad memory addresses.
+    GuestMemory(GuestMemoryError),
+    /// Guest gave us offsets that would have overflowed a usize.
+    CheckedOffset(GuestAddress, usize),
+    /// Guest gave us a write only descriptor that protocol says to read from.
+    UnexpectedWriteOnlyDescriptor,
+    /// Guest gave us a read only descriptor that protocol says to write to.
+    UnexpectedReadOnlyDescriptor,
+    /// Guest gave us too few descriptors in a descriptor chain.
+    DescriptorChainTooShort,
+    /// Guest gave us a descriptor that was too short to use.
+    DescriptorLengthTooSmall,
+    /// Getting a block's metadata fails for any reason.
+    GetFileMetadata,
+    /// The requested operation would cause a seek beyond disk end.
+    InvalidOffset,
+    /// Guest gave us a discard or write zeroes request with a bad number of segments.
+    InvalidSegmentCount(u32),
+}
+
+#[derive(Debug)]
+enum ExecuteError {
+    AsyncIo(io::Error),
+    BadRequest(Error),
+    Discard(io::Error),
+    Flush(io::Error),
+    // The drive has no disk image attached.
+    NoDiskImage,
+    Read(GuestMemoryError),
+    Seek(io::Error),
+    Write(GuestMemoryError),
+    WriteZeroes(io::Error),
+    Unsupported(u32),
+}
+
+impl ExecuteError {
+    fn status(&self) -> u32 {
+        match *self {
+            ExecuteError::AsyncIo(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::BadRequest(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Discard(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Flush(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::NoDiskImage => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Read(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Seek(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Write(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::WriteZeroes(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+        }
+    }
+}
+
+/// How the writes of the guest reach the storage of the disk image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheMode {
+    /// Through the page cache of the host. The disk image is synced when the guest flushes.
+    Writeback,
+    /// Around the page cache of the host, for disk images opened with O_DIRECT, which have to
+    /// be raw. The disk image is synced when the guest flushes.
+    Direct,
+    /// Through the page cache of the host. Flushes are ignored, so the writes of the guest can
+    /// be lost if the host crashes.
+    Unsafe,
+}
+
+// The cache mode of the drive, along with the write cache setting of the driver.
+#[derive(Clone, Copy, Debug)]
+struct CachePolicy {
+    mode: CacheMode,
+    writeback: bool,
+}
+
+impl CachePolicy {
+    // Returns whether flush requests sync the disk image.
+    fn sync_on_flush(self) -> bool {
+        self.mode != CacheMode::Unsafe
+    }
+
+    // Returns whether writes sync the disk image before they complete, which is the case when
+    // the driver turns the write cache off.
+    fn sync_on_write(self) -> bool {
+        self.mode != CacheMode::Unsafe && !self.writeback
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RequestType {
+    In,
+    Out,
+    Flush,
+    GetDeviceID,
+    Discard,
+    WriteZeroes,
+    Unsupported(u32),
+}
+
+fn request_type(mem: &GuestMemory, desc_addr: GuestAddress) -> result::Result<RequestType, Error> {
+    let type_ = mem
+        .read_obj_from_addr(desc_addr)
+        .map_err(Error::GuestMemory)?;
+    match type_ {
+        VIRTIO_BLK_T_IN => Ok(RequestType::In),
+        VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
+        VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
+        VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceID),
+        VIRTIO_BLK_T_DISCARD => Ok(RequestType::Discard),
+        VIRTIO_BLK_T_WRITE_ZEROES => Ok(RequestType::WriteZeroes),
+        t => Ok(RequestType::Unsupported(t)),
+    }
+}
+
+fn sector(mem: &GuestMemory, desc_addr: GuestAddress) -> result::Result<u64, Error> {
+    const SECTOR_OFFSET: usize = 8;
+    let addr = match mem.checked_offset(desc_addr, SECTOR_OFFSET) {
+        Some(v) => v,
+        None => return Err(Error::CheckedOffset(desc_addr, SECTOR_OFFSET)),
+    };
+
+    mem.read_obj_from_addr(addr).map_err(Error::GuestMemory)
+}
+
+fn build_device_id(disk_image: &File) -> result::Result<String, Error> {
+    let blk_metadata = match disk_image.metadata() {
+        Err(_) => return Err(Error::GetFileMetadata),
+        Ok(m) => m,
+    };
+    // This is how kvmtool does it.
+    let device_id = format!(
+        "{}{}{}",
+        blk_metadata.st_dev(),
+        blk_metadata.st_rdev(),
+        blk_metadata.st_ino()
+    )
+    .to_owned();
+    Ok(device_id)
+}
+
+fn build_disk_image_id(disk_image: &File) -> Vec<u8> {
+    let mut default_disk_image_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
+    match build_device_id(disk_image) {
+        Err(_) => {
+            warn!("Could not generate device id. We'll use a default.");
+        }
+        Ok(m) => {
+            // The kernel only knows to read a maximum of VIRTIO_BLK_ID_BYTES.
+            // This will also zero out any leftover bytes.
+            let disk_id = m.as_bytes();
+            let bytes_to_copy = cmp::min(disk_id.len(), VIRTIO_BLK_ID_BYTES as usize);
+            default_disk_image_id[..bytes_to_copy].clone_from_slice(&disk_id[..bytes_to_copy])
+        }
+    }
+    default_disk_image_id
+}
+
+// A range of sectors to discard or zero, as laid out in the data of the request.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct DiscardSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+unsafe impl DataInit for DiscardSegment {}
+
+struct Request {
+    request_type: RequestType,
+    sector: u64,
+    data_addr: GuestAddress,
+    data_len: u32,
+    status_addr: GuestAddress,
+}
+
+impl Request {
+    fn parse(avail_desc: &DescriptorChain, mem: &GuestMemory) -> result::Result<Request, Error> {
+        // The head contains the request type which MUST be readable.
+        if avail_desc.is_write_only() {
+            return Err(Error::UnexpectedWriteOnlyDescriptor);
+        }
+
+        let mut req = Request {
+            request_type: request_type(&mem, avail_desc.addr)?,
+            sector: sector(&mem, avail_desc.addr)?,
+            data_addr: GuestAddress(0),
+            data_len: 0,
+            status_addr: GuestAddress(0),
+        };
+
+        let data_desc;
+        let status_desc;
+        let desc = avail_desc
+            .next_descriptor()
+            .ok_or(Error::DescriptorChainTooShort)?;
+
+        if !desc.has_next() {
+            status_desc = desc;
+            // Only flush requests are allowed to skip the data descriptor.
+            if req.request_type != RequestType::Flush {
+                return Err(Error::DescriptorChainTooShort);
+            }
+        } else {
+            data_desc = desc;
+            status_desc = data_desc
+                .next_descriptor()
+                .ok_or(Error::DescriptorChainTooShort)?;
+
+            if data_desc.is_write_only()
+                && (req.request_type == RequestType::Out
+                    || req.request_type == RequestType::Discard
+                    || req.request_type == RequestType::WriteZeroes)
+            {
+                return Err(Error::UnexpectedWriteOnlyDescriptor);
+            }
+            if !data_desc.is_write_only() && req.request_type == RequestType::In {
+                return Err(Error::UnexpectedReadOnlyDescriptor);
+            }
+            if !data_desc.is_write_only() && req.request_type == RequestType::GetDeviceID {
+                return Err(Error::UnexpectedReadOnlyDescriptor);
+            }
+
+            req.data_addr = data_desc.addr;
+            req.data_len = data_desc.len;
+        }
+
+        // The status MUST always be writable.
+        if !status_desc.is_write_only() {
+            return Err(Error::UnexpectedReadOnlyDescriptor);
+        }
+
+        if status_desc.len < 1 {
+            return Err(Error::DescriptorLengthTooSmall);
+        }
+
+        req.status_addr = status_desc.addr;
+
+        Ok(req)
+    }
+
+    // Fails the requests which need a feature the driver did not acknowledge.
+    fn check_features(&self, acked_features: u64) -> result::Result<(), ExecuteError> {
+        let (feature, type_) = match self.request_type {
+            RequestType::Discard => (VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_T_DISCARD),
+            RequestType::WriteZeroes => (VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_T_WRITE_ZEROES),
+            _ => return Ok(()),
+        };
+        if acked_features & (1u64 << feature) == 0 {
+            return Err(ExecuteError::Unsupported(type_));
+        }
+        Ok(())
+    }
+
+    // Checks that the data of the request fits in a disk of `disk_nsectors` sectors.
+    fn check_capacity(&self, disk_nsectors: u64) -> result::Result<(), ExecuteError> {
+        let mut top: u64 = u64::from(self.data_len) / SECTOR_SIZE;
+        if u64::from(self.data_len) % SECTOR_SIZE != 0 {
+            top += 1;
+        }
+        top = top
+            .checked_add(self.sector)
+            .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+        if top > disk_nsectors {
+            return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+        }
+        Ok(())
+    }
+
+    // Discards or zeroes the ranges of the segments in the data of the request.
+    fn execute_discard(
+        &self,
+        disk: &mut DiskFile,
+        disk_nsectors: u64,
+        mem: &GuestMemory,
+    ) -> result::Result<u32, ExecuteError> {
+        let segment_size = mem::size_of::<DiscardSegment>() as u32;
+        let count = self.data_len / segment_size;
+        if self.data_len % segment_size != 0 || count == 0 || count > MAX_DISCARD_SEGMENTS {
+            return Err(ExecuteError::BadRequest(Error::InvalidSegmentCount(count)));
+        }
+
+        for i in 0..count {
+            let offset = (i * segment_size) as usize;
+            let addr =
+                mem.checked_offset(self.data_addr, offset)
+                    .ok_or(ExecuteError::BadRequest(Error::CheckedOffset(
+                        self.data_addr,
+                        offset,
+                    )))?;
+            let segment: DiscardSegment = mem
+                .read_obj_from_addr(addr)
+                .map_err(|e| ExecuteError::BadRequest(Error::GuestMemory(e)))?;
+
+            let top = segment
+                .sector
+                .checked_add(u64::from(segment.num_sectors))
+                .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+            if top > disk_nsectors {
+                return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+            }
+            let start = segment.sector << SECTOR_SHIFT;
+            let len = u64::from(segment.num_sectors) << SECTOR_SHIFT;
+
+            if self.request_type == RequestType::Discard {
+                // Discard segments have no flags.
+                if segment.flags != 0 {
+                    return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_DISCARD));
+                }
+                disk.discard(start, len).map_err(ExecuteError::Discard)?;
+                METRICS.block.discard_count.add(len as usize);
+            } else {
+                if segment.flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 {
+                    return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES));
+                }
+                // The driver lets us deallocate the range, which reads back as zeroes as well.
+                if segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 {
+                    disk.punch_hole(start, len)
+                } else {
+                    disk.write_zeroes(start, len)
+                }
+                .map_err(ExecuteError::WriteZeroes)?;
+                METRICS.block.write_zeroes_count.add(len as usize);
+            }
+        }
+        Ok(0)
+    }
+
+    #[allow(clippy::ptr_arg)]
+    fn execute(
+        &self,
+        mut disk: &mut DiskFile,
+        disk_nsectors: u64,
+        mem: &GuestMemory,
+        disk_id: &Vec<u8>,
+        cache: CachePolicy,
+    ) -> result::Result<u32, ExecuteError> {
+        // The ranges of discard and write zeroes requests are in their segments instead.
+        if self.request_type != RequestType::Discard
+            && self.request_type != RequestType::WriteZeroes
+        {
+            self.check_capacity(disk_nsectors)?;
+
+            disk.seek(SeekFrom::Start(self.sector << SECTOR_SHIFT))
+                .map_err(ExecuteError::Seek)?;
+        }
+
+        match self.request_type {
+            RequestType::In => {
+                if cache.mode == CacheMode::Direct {
+                    mem.read_to_memory(
+                        self.data_addr,
+                        &mut DirectIo::new(disk),
+                        self.data_len as usize,
+                    )
+                } else {
+                    mem.read_to_memory(self.data_addr, &mut disk, self.data_len as usize)
+                }
+                .map_err(ExecuteError::Read)?;
+                METRICS.block.read_count.add(self.data_len as usize);
+                return Ok(self.data_len);
+            }
+            RequestType::Out => {
+                if cache.mode == CacheMode::Direct {
+                    mem.write_from_memory(
+                        self.data_addr,
+                        &mut DirectIo::new(disk),
+                        self.data_len as usize,
+                    )
+                } else {
+                    mem.write_from_memory(self.data_addr, &mut disk, self.data_len as usize)
+                }
+                .map_err(ExecuteError::Write)?;
+                METRICS.block.write_count.add(self.data_len as usize);
+                if cache.sync_on_write() {
+                    disk.sync().map_err(ExecuteError::Flush)?;
+                }
+            }
+            RequestType::Flush => {
+                if cache.sync_on_flush() {
+                    disk.sync().map_err(ExecuteError::Flush)?;
+                }
+                METRICS.block.flush_count.inc();
+                return Ok(0);
+            }
+            RequestType::GetDeviceID => {
+                if (self.data_len as usize) < disk_id.len() {
+                    return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+                }
+                mem.write_slice_at_addr(&disk_id.as_slice(), self.data_addr)
+                    .map_err(ExecuteError::Write)?;
+            }
+            RequestType::Discard | RequestType::WriteZeroes => {
+                self.execute_discard(disk, disk_nsectors, mem)?;
+                if cache.sync_on_write() {
+                    disk.sync().map_err(ExecuteError::Flush)?;
+                }
+            }
+            RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
+        };
+        Ok(0)
+    }
+}
+
+// Writes the status of a request which finished with `result`, and returns the length to add to
+// the used ring.
+fn complete_request(
+    mem: &GuestMemory,
+    request: &Request,
+    result: result::Result<u32, ExecuteError>,
+) -> u32 {
+    let (status, len) = match result {
+        Ok(l) => (VIRTIO_BLK_S_OK, l),
+        Err(e) => {
+            error!("Failed to execute request: {:?}", e);
+            METRICS.block.invalid_reqs_count.inc();
+            // We need at least 1 byte for the status.
+            (e.status(), 1)
+        }
+    };
+    // We use unwrap because the request parsing process already checked that the
+    // status_addr was valid.
+    mem.write_obj_at_addr(status, request.status_addr).unwrap();
+    len
+}
+
+struct BlockEpollHandler {
+    queues: Vec<Queue>,
+    mem: GuestMemory,
+    // The drive is empty when it has no disk image.
+    disk_image: Option<Box<DiskFile>>,
+    disk_nsectors: u64,
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: EventFd,
+    queue_evts: Vec<EventFd>,
+    // Shared by all the queues.
+    rate_limiter: RateLimiter,
+    disk_image_id: Vec<u8>,
+    io_uring: Option<IoUringEngine>,
+    acked_features: u64,
+    cache_mode: CacheMode,
+    // Whether the write cache is on, which the driver can change through the configuration
+    // space.
+    writeback: Arc<AtomicBool>,
+}
+
+impl BlockEpollHandler {
+    fn process_queue(&mut self, queue_index: usize) -> bool {
+        let queue = &mut self.queues[queue_index];
+        let mut rate_limited = false;
+        let mut io_uring_full = false;
+
+        let cache = CachePolicy {
+            mode: self.cache_mode,
+            writeback: self.writeback.load(Ordering::SeqCst),
+        };
+
+        let mut used_desc_heads = [(0, 0); MAX_QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        for avail_desc in queue.iter(&self.mem) {
+            if self.io_uring.as_ref().map_or(false, IoUringEngine::is_full) {
+                io_uring_full = true;
+                break;
+            }
+
+            let len;
+            match Request::parse(&avail_desc, &self.mem) {
+                Ok(request) => {
+                    // If limiter.consume() fails it means there is no more TokenType::Ops
+                    // budget and rate limiting is in effect.
+                    if !self.rate_limiter.consume(1, TokenType::Ops) {
+                        rate_limited = true;
+                        // stop processing the queue
+                        break;
+                    }
+                    // Exercise the rate limiter only if this request is of data transfer type.
+                    if request.request_type == RequestType::In
+                        || request.request_type == RequestType::Out
+                    {
+                        // If limiter.consume() fails it means there is no more TokenType::Bytes
+                        // budget and rate limiting is in effect.
+                        if !self
+                            .rate_limiter
+                            .consume(u64::from(request.data_len), TokenType::Bytes)
+                        {
+                            rate_limited = true;
+                            // Revert the OPS consume().
+                            self.rate_limiter.manual_replenish(1, TokenType::Ops);
+                            // Stop processing the queue.
+                            break;
+                        }
+                    }
+                    if let Err(e) = request.check_features(self.acked_features) {
+                        len = complete_request(&self.mem, &request, Err(e));
+                    } else if let Some(ref mut disk_image) = self.disk_image {
+                        if let Some(ref mut io_uring) = self.io_uring {
+                            io_uring.push_request(
+                                queue_index,
+                                avail_desc.index,
+                                request,
+                                &mut **disk_image,
+                                self.disk_nsectors,
+                                &self.mem,
+                                &self.disk_image_id,
+                                cache,
+                            );
+                            continue;
+                        }
+                        let result = request.execute(
+                            &mut **disk_image,
+                            self.disk_nsectors,
+                            &self.mem,
+                            &self.disk_image_id,
+                            cache,
+                        );
+                        len = complete_request(&self.mem, &request, result);
+                    } else {
+                        len = complete_request(&self.mem, &request, Err(ExecuteError::NoDiskImage));
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to parse available descriptor chain: {:?}", e);
+                    METRICS.block.execute_fails.inc();
+                    len = 0;
+                }
+            }
+            if let Some(ref mut io_uring) = self.io_uring {
+                // Keep the used ring in order with the requests still in flight.
+                io_uring.push_completed(queue_index, avail_desc.index, len);
+                continue;
+            }
+            used_desc_heads[used_count] = (avail_desc.index, len);
+            used_count += 1;
+        }
+        if rate_limited || io_uring_full {
+            // If rate limiting kicked in, or there is no room left for in-flight requests,
+            // queue had advanced one element that we aborted processing; go back one element
+            // so it can be processed next time.
+            queue.go_to_previous_position();
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            queue.add_used(&self.mem, desc_index, len);
+        }
+        if let Some(ref mut io_uring) = self.io_uring {
+            io_uring.submit();
+            return io_uring.complete(&mut self.queues, &self.mem);
+        }
+        used_count > 0
+    }
+
+    // Processes all the queues, as long as the rate limiter lets requests through.
+    fn process_queues(&mut self) -> bool {
+        let mut used = false;
+        for queue_index in 0..self.queues.len() {
+            if self.rate_limiter.is_blocked() {
+                break;
+            }
+            used |= self.process_queue(queue_index);
+        }
+        used
+    }
+
+    fn process_io_completions(&mut self) -> bool {
+        let used = match self.io_uring {
+            Some(ref mut io_uring) => io_uring.complete(&mut self.queues, &self.mem),
+            None => false,
+        };
+        // The queues may hold requests which did not fit in the io_uring before.
+        let processed = self.process_queues();
+        used || processed
+    }
+
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
+        self.interrupt_evt.write(1).map_err(|e| {
+            error!("Failed to signal used queue: {:?}", e);
+            METRICS.block.event_fails.inc();
+            DeviceError::FailedSignalingUsedQueue(e)
+        })
+    }
+
+    // Switches the drive to `disk_image`, or detaches its disk image if `disk_image` is `None`.
+    fn update_disk_image(
+        &mut self,
+        disk_image: Option<Box<DiskFile>>,
+    ) -> result::Result<(), DeviceError> {
+        let raw = disk_image
+            .as_ref()
+            .map_or(true, |disk_image| disk_image.is_raw());
+        if self.io_uring.is_some() && !raw {
+            error!("Cannot switch a block device using io_uring to an image which is not raw.");
+            return Err(DeviceError::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "io_uring needs a raw disk image",
+            )));
+        }
+        if self.cache_mode == CacheMode::Direct && !raw {
+            error!("Cannot switch a block device using O_DIRECT to an image which is not raw.");
+            return Err(DeviceError::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "O_DIRECT needs a raw disk image",
+            )));
+        }
+        // The requests in flight, and those left on the submission ring, refer to the current
+        // disk image by its file descriptor, which could be reused by the next disk image once
+        // the current one is closed. So they have to complete before it is replaced.
+        let drained = match self.io_uring {
+            Some(ref mut io_uring) => io_uring.drain(&mut self.queues, &self.mem),
+            None => Ok(false),
+        };
+        let used = drained.map_err(|e| {
+            error!("Failed to wait for the block requests in flight: {:?}", e);
+            DeviceError::IoError(e)
+        })?;
+        match disk_image {
+            Some(mut disk_image) => {
+                self.disk_nsectors = disk_image
+                    .seek(SeekFrom::End(0))
+                    .map_err(DeviceError::IoError)?
+                    / SECTOR_SIZE;
+                self.disk_image_id = build_disk_image_id(disk_image.image_file());
+                self.disk_image = Some(disk_image);
+            }
+            None => {
+                // The requests still queued fail when they are processed.
+                self.disk_nsectors = 0;
+                self.disk_image = None;
+            }
+        }
+        METRICS.block.update_count.inc();
+        if used {
+            self.signal_used_queue()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl EpollHandler for BlockEpollHandler {
+    fn handle_event(
+        &mut self,
+        device_event: DeviceEventT,
+        _: u32,
+        payload: EpollHandlerPayload,
+    ) -> result::Result<(), DeviceError> {
+        match device_event {
+            RATE_LIMITER_EVENT => {
+                METRICS.block.rate_limiter_event_count.inc();
+                // Upon rate limiter event, call the rate limiter handler
+                // and restart processing the queues.
+                if self.rate_limiter.event_handler().is_ok() && self.process_queues() {
+                    self.signal_used_queue()
+                } else {
+                    Ok(())
+                }
+            }
+            IO_COMPLETION_EVENT => {
+                METRICS.block.io_completion_event_count.inc();
+                let read = match self.io_uring {
+                    Some(ref io_uring) => io_uring.completion_evt().read(),
+                    None => {
+                        return Err(DeviceError::UnknownEvent {
+                            device: "block",
+                            event: device_event,
+                        })
+                    }
+                };
+                if let Err(e) = read {
+                    error!("Failed to get io completion event: {:?}", e);
+                    METRICS.block.event_fails.inc();
+                    Err(DeviceError::FailedReadingQueue {
+                        event_type: "io completion event",
+                        underlying: e,
+                    })
+                } else if self.process_io_completions() {
+                    self.signal_used_queue()
+                } else {
+                    Ok(())
+                }
+            }
+            FS_UPDATE_EVENT => {
+                if let EpollHandlerPayload::DrivePayload(disk_image) = payload {
+                    self.update_disk_image(disk_image)
+                } else {
+                    Err(DeviceError::PayloadExpected)
+                }
+            }
+            DRAIN_EVENT => {
+                let drained = match self.io_uring {
+                    Some(ref mut io_uring) => io_uring.drain(&mut self.queues, &self.mem),
+                    None => Ok(false),
+                };
+                match drained {
+                    Ok(true) => self.signal_used_queue(),
+                    Ok(false) => Ok(()),
+                    Err(e) => {
+                        error!("Failed to wait for the block requests in flight: {:?}", e);
+                        METRICS.block.event_fails.inc();
+                        Err(DeviceError::IoError(e))
+                    }
+                }
+            }
+            queue_event
+                if queue_event >= QUEUE_AVAIL_EVENT
+                    && ((queue_event - QUEUE_AVAIL_EVENT) as usize) < self.queues.len() =>
+            {
+                let queue_index = (queue_event - QUEUE_AVAIL_EVENT) as usize;
+                METRICS.block.queue_event_count.inc();
+                if let Err(e) = self.queue_evts[queue_index].read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    METRICS.block.event_fails.inc();
+                    Err(DeviceError::FailedReadingQueue {
+                        event_type: "queue event",
+                        underlying: e,
+                    })
+                } else if !self.rate_limiter.is_blocked() && self.process_queue(queue_index) {
+                    self.signal_used_queue()
+                } else {
+                    // While limiter is blocked, don't process any more requests.
+                    Ok(())
+                }
+            }
+            unknown => Err(DeviceError::UnknownEvent {
+                device: "block",
+                event: unknown,
+            }),
+        }
+    }
+
+    fn event_fds(&self) -> Vec<RawFd> {
+        let mut fds: Vec<RawFd> = self.queue_evts.iter().map(EventFd::as_raw_fd).collect();
+        let rate_limiter_rawfd = self.rate_limiter.as_raw_fd();
+        if rate_limiter_rawfd != -1 {
+            fds.push(rate_limiter_rawfd);
+        }
+        if let Some(ref io_uring) = self.io_uring {
+            fds.push(io_uring.completion_evt().as_raw_fd());
+        }
+        fds
+    }
+}
+
+pub struct EpollConfig {
+    q_avail_token: u64,
+    rate_limiter_token: u64,
+    io_completion_token: u64,
+    epoll_raw_fd: RawFd,
+    sender: mpsc::Sender<Box<EpollHandler>>,
+}
+
+impl EpollConfig {
+    pub fn new(
+        first_token: u64,
+        epoll_raw_fd: RawFd,
+        sender: mpsc::Sender<Box<EpollHandler>>,
+    ) -> Self {
+        EpollConfig {
+            q_avail_token: first_token + u64::from(QUEUE_AVAIL_EVENT),
+            rate_limiter_token: first_token + u64::from(RATE_LIMITER_EVENT),
+            io_completion_token: first_token + u64::from(IO_COMPLETION_EVENT),
+            epoll_raw_fd,
+            sender,
+        }
+    }
+}
+
+/// Virtio device for exposing block level read/write operations on a host file.
+pub struct Block {
+    disk_image: Option<Box<DiskFile>>,
+    disk_nsectors: u64,
+    activated: bool,
+    avail_features: u64,
+    acked_features: u64,
+    config_space: Vec<u8>,
+    epoll_config: EpollConfig,
+    rate_limiter: Option<RateLimiter>,
+    io_uring: Option<IoUringEngine>,
+    cache_mode: CacheMode,
+    writeback: Arc<AtomicBool>,
+    queue_sizes: Vec<u16>,
+}
+
+pub fn build_config_space(disk_size: u64) -> Vec<u8> {
+    // This only builds the disk size, which uses the first two words of the configuration space.
+    // If the image is not a multiple of the sector size, the tail bits are not exposed.
+    // The config space is little endian.
+    let mut config = Vec::with_capacity(CAPACITY_SIZE);
+    let num_sectors = disk_size >> SECTOR_SHIFT;
+    for i in 0..8 {
+        config.push((num_sectors >> (8 * i)) as u8);
+    }
+    config
+}
+
+// Writes a little endian `value` at `offset` in the configuration space.
+fn set_config_u32(config: &mut [u8], offset: usize, value: u32) {
+    for i in 0..4 {
+        config[offset + i] = (value >> (8 * i)) as u8;
+    }
+}
+
+impl Block {
+    /// Create a new virtio block device that operates on the given disk image, or an empty
+    /// drive of 0 bytes if `disk_image` is `None`.
+    ///
+    /// Requests are executed through `io_uring` if given, which needs a raw disk image, and
+    /// synchronously otherwise. The guest can deallocate ranges of the disk if
+    /// `discard` is set, and zero them without transferring the zeroes if `write_zeroes` is set.
+    /// Both are left out for read-only disks. The guest can turn the write cache of writable
+    /// disks off, making every write sync the disk image, unless `cache_mode` is `Unsafe`.
+    /// The device has a virtio queue of each size in `queue_sizes`, all of them sharing
+    /// `rate_limiter`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        mut disk_image: Option<Box<DiskFile>>,
+        is_disk_read_only: bool,
+        epoll_config: EpollConfig,
+        rate_limiter: Option<RateLimiter>,
+        io_uring: Option<IoUringEngine>,
+        discard: bool,
+        write_zeroes: bool,
+        cache_mode: CacheMode,
+        queue_sizes: Vec<u16>,
+    ) -> io::Result<Block> {
+        if queue_sizes.is_empty() || queue_sizes.len() > MAX_QUEUES as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid number of queues",
+            ));
+        }
+        if queue_sizes
+            .iter()
+            .any(|&size| size == 0 || size > MAX_QUEUE_SIZE || !size.is_power_of_two())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid queue size",
+            ));
+        }
+        let raw = disk_image
+            .as_ref()
+            .map_or(true, |disk_image| disk_image.is_raw());
+        if io_uring.is_some() && !raw {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "io_uring needs a raw disk image",
+            ));
+        }
+        if cache_mode == CacheMode::Direct && !raw {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "O_DIRECT needs a raw disk image",
+            ));
+        }
+        let disk_size = match disk_image {
+            Some(ref mut disk_image) => disk_image.seek(SeekFrom::End(0))? as u64,
+            None => 0,
+        };
+        if disk_size % SECTOR_SIZE != 0 {
+            warn!(
+                "Disk size {} is not a multiple of sector size {}; \
+                 the remainder will not be visible to the guest.",
+                disk_size, SECTOR_SIZE
+            );
+        }
+
+        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH);
+
+        let mut config_space = build_config_space(disk_size);
+        if is_disk_read_only {
+            avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        } else {
+            // The write cache starts out on.
+            avail_features |= 1u64 << VIRTIO_BLK_F_CONFIG_WCE;
+            config_space.resize(CONFIG_SPACE_SIZE, 0);
+            config_space[WRITEBACK_OFFSET] = 1;
+            if discard {
+                avail_features |= 1u64 << VIRTIO_BLK_F_DISCARD;
+                set_config_u32(&mut config_space, MAX_DISCARD_SECTORS_OFFSET, u32::MAX);
+                set_config_u32(
+                    &mut config_space,
+                    MAX_DISCARD_SEG_OFFSET,
+                    MAX_DISCARD_SEGMENTS,
+                );
+                set_config_u32(&mut config_space, DISCARD_SECTOR_ALIGNMENT_OFFSET, 1);
+            }
+            if write_zeroes {
+                avail_features |= 1u64 << VIRTIO_BLK_F_WRITE_ZEROES;
+                set_config_u32(&mut config_space, MAX_WRITE_ZEROES_SECTORS_OFFSET, u32::MAX);
+                set_config_u32(
+                    &mut config_space,
+                    MAX_WRITE_ZEROES_SEG_OFFSET,
+                    MAX_DISCARD_SEGMENTS,
+                );
+                config_space[WRITE_ZEROES_MAY_UNMAP_OFFSET] = 1;
+            }
+        }
+        if queue_sizes.len() > 1 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+            config_space.resize(CONFIG_SPACE_SIZE, 0);
+            let num_queues = queue_sizes.len() as u16;
+            config_space[NUM_QUEUES_OFFSET] = num_queues as u8;
+            config_space[NUM_QUEUES_OFFSET + 1] = (num_queues >> 8) as u8;
+        }
+
+        Ok(Block {
+            disk_image,
+            disk_nsectors: disk_size / SECTOR_SIZE,
+            activated: false,
+            avail_features,
+            acked_features: 0u64,
+            config_space,
+            epoll_config,
+            rate_limiter,
+            io_uring,
+            cache_mode,
+            writeback: Arc::new(AtomicBool::new(true)),
+            queue_sizes,
+        })
+    }
+}
+
+impl VirtioDevice for Block {
+    fn device_type(&self) -> u32 {
+        TYPE_BLOCK
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn required_queues(&self) -> usize {
+        // The driver sets up as many queues as it has use for, usually one per vCPU.
+        1
+    }
+
+    fn features(&self, page: u32) -> u32 {
+        match page {
+            // Get the lower 32-bits of the features bitfield.
+            0 => self.avail_features as u32,
+            // Get the upper 32-bits of the features bitfield.
+            1 => (self.avail_features >> 32) as u32,
+            _ => {
+                warn!("Received request for unknown features page.");
+                0u32
+            }
+        }
+    }
+
+    fn ack_features(&mut self, page: u32, value: u32) {
+        let mut v = match page {
+            0 => u64::from(value),
+            1 => u64::from(value) << 32,
+            _ => {
+                warn!("Cannot acknowledge unknown features page.");
+                0u64
+            }
+        };
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_len = self.config_space.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            METRICS.block.cfg_fails.inc();
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&self.config_space[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let data_len = data.len() as u64;
+        let config_len = self.config_space.len() as u64;
+        if offset + data_len > config_len {
+            error!("Failed to write config space");
+            METRICS.block.cfg_fails.inc();
+            return;
+        }
+        let (_, right) = self.config_space.split_at_mut(offset as usize);
+        right[..data.len()].copy_from_slice(&data[..]);
+        // The driver turns the write cache on and off through the writeback field.
+        if self.acked_features & (1u64 << VIRTIO_BLK_F_CONFIG_WCE) != 0
+            && offset <= WRITEBACK_OFFSET as u64
+            && offset + data_len > WRITEBACK_OFFSET as u64
+        {
+            self.writeback
+                .store(self.config_space[WRITEBACK_OFFSET] != 0, Ordering::SeqCst);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt_evt: EventFd,
+        status: Arc<AtomicUsize>,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        let num_queues = self.queue_sizes.len();
+        if queues.len() != num_queues || queue_evts.len() != num_queues {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                num_queues,
+                queues.len()
+            );
+            METRICS.block.activate_fails.inc();
+            return Err(ActivateError::BadActivate);
+        }
+
+        if !self.activated {
+            self.activated = true;
+            // Only the queues the driver has set up are used.
+            let used_queues = queues
+                .iter()
+                .position(|queue| !queue.ready)
+                .unwrap_or(num_queues);
+            queues.truncate(used_queues);
+            queue_evts.truncate(used_queues);
+            let queue_evt_raw_fds: Vec<RawFd> = queue_evts.iter().map(EventFd::as_raw_fd).collect();
+
+            let disk_image = self.disk_image.take();
+            let disk_image_id = disk_image.as_ref().map_or_else(
+                || vec![0; VIRTIO_BLK_ID_BYTES as usize],
+                |disk_image| build_disk_image_id(disk_image.image_file()),
+            );
+            let handler = BlockEpollHandler {
+                queues,
+                mem,
+                disk_image,
+                disk_nsectors: self.disk_nsectors,
+                interrupt_status: status,
+                interrupt_evt,
+                queue_evts,
+                rate_limiter: self.rate_limiter.take().unwrap_or_default(),
+                disk_image_id,
+                io_uring: self.io_uring.take(),
+                acked_features: self.acked_features,
+                cache_mode: self.cache_mode,
+                writeback: self.writeback.clone(),
+            };
+            let rate_limiter_rawfd = handler.rate_limiter.as_raw_fd();
+            let io_completion_rawfd = handler
+                .io_uring
+                .as_ref()
+                .map(|io_uring| io_uring.completion_evt().as_raw_fd());
+
+            // The channel should be open at this point.
+            self.epoll_config
+                .sender
+                .send(Box::new(handler))
+                .expect("Failed to send through the channel");
+
+            //TODO: barrier needed here by any chance?
+            for (i, queue_evt_raw_fd) in queue_evt_raw_fds.into_iter().enumerate() {
+                epoll::ctl(
+                    self.epoll_config.epoll_raw_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    queue_evt_raw_fd,
+                    epoll::Event::new(
+                        epoll::Events::EPOLLIN,
+                        self.epoll_config.q_avail_token + i as u64,
+                    ),
+                )
+                .map_err(|e| {
+                    METRICS.block.activate_fails.inc();
+                    ActivateError::EpollCtl(e)
+                })?;
+            }
+
+            if rate_limiter_rawfd != -1 {
+                epoll::ctl(
+                    self.epoll_config.epoll_raw_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    rate_limiter_rawfd,
+                    epoll::Event::new(epoll::Events::EPOLLIN, self.epoll_config.rate_limiter_token),
+                )
+                .map_err(|e| {
+                    METRICS.block.activate_fails.inc();
+                    ActivateError::EpollCtl(e)
+                })?;
+            }
+
+            if let Some(io_completion_rawfd) = io_completion_rawfd {
+                epoll::ctl(
+                    self.epoll_config.epoll_raw_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    io_completion_rawfd,
+                    epoll::Event::new(
+                        epoll::Events::EPOLLIN,
+                        self.epoll_config.io_completion_token,
+                    ),
+                )
+                .map_err(|e| {
+                    METRICS.block.activate_fails.inc();
+                    ActivateError::EpollCtl(e)
+                })?;
+            }
+
+            return Ok(());
+        }
+        METRICS.block.activate_fails.inc();
+        Err(ActivateError::BadActivate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::{tempdir, tempfile, NamedTempFile};
+    use super::*;
+    use memory_model::MemoryBacking;
+
+    use libc;
+    use std::ffi::CString;
+    use std::fs::{metadata, OpenOptions};
+    use std::os::unix::io::FromRawFd;
+    use std::sync::mpsc::Receiver;
+    use std::thread;
+    use std::time::Duration;
+    use std::u32;
+
+    use virtio::queue::tests::*;
+
+    /// Will read $metric, run the code in $block, then assert metric has increased by $delta.
+    macro_rules! check_metric_after_block {
+        ($metric:expr, $delta:expr, $block:expr) => {{
+            let before = $metric.count();
+            let _ = $block;
+            assert_eq!($metric.count(), before + $delta, "unexpected metric value");
+        }};
+    }
+
+    impl BlockEpollHandler {
+        fn set_queue(&mut self, idx: usize, q: Queue) {
+            self.queues[idx] = q;
+        }
+
+        fn get_rate_limiter(&self) -> &RateLimiter {
+            &self.rate_limiter
+        }
+
+        fn set_rate_limiter(&mut self, rate_limiter: RateLimiter) {
+            self.rate_limiter = rate_limiter;
+        }
+
+        fn disk_image(&mut self) -> &mut DiskFile {
+            &mut **self.disk_image.as_mut().unwrap()
+        }
+    }
+
+    struct DummyBlock {
+        block: Block,
+        epoll_raw_fd: i32,
+        _receiver: Receiver<Box<EpollHandler>>,
+    }
+
+    impl DummyBlock {
+        fn new(is_disk_read_only: bool) -> Self {
+            let epoll_raw_fd = epoll::create(true).unwrap();
+            let (sender, _receiver) = mpsc::channel();
+
+            let epoll_config = EpollConfig::new(0, epoll_raw_fd, sender);
+
+            let f: File = tempfile().unwrap();
+            f.set_len(0x1000).unwrap();
+
+            // Rate limiting is enabled but with a high operation rate (10 million ops/s).
+            let rate_limiter = RateLimiter::new(0, None, 0, 100_000, None, 10).unwrap();
+            DummyBlock {
+                block: Block::new(
+                    Some(Box::new(f)),
+                    is_disk_read_only,
+                    epoll_config,
+                    Some(rate_limiter),
+                    None,
+                    false,
+                    false,
+                    CacheMode::Writeback,
+                    vec![QUEUE_SIZE],
+                )
+                .unwrap(),
+                epoll_raw_fd,
+                _receiver,
+            }
+        }
+
+        fn block(&mut self) -> &mut Block {
+            &mut self.block
+        }
+    }
+
+    impl Drop for DummyBlock {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.epoll_raw_fd) };
+        }
+    }
+
+    #[allow(clippy::needless_lifetimes)]
+    fn default_test_blockepollhandler<'a>(
+        mem: &'a GuestMemory,
+    ) -> (BlockEpollHandler, VirtQueue<'a>) {
+        let mut dummy = DummyBlock::new(false);
+        let b = dummy.block();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+
+        assert!(vq.end().0 < 0x1000);
+
+        let queues = vec![vq.create_queue()];
+        let mut disk_image = b.disk_image.take().unwrap();
+        let disk_nsectors = disk_image.seek(SeekFrom::End(0)).unwrap() / SECTOR_SIZE;
+        let status = Arc::new(AtomicUsize::new(0));
+        let interrupt_evt = EventFd::new().unwrap();
+        let queue_evts = vec![EventFd::new().unwrap()];
+
+        let disk_image_id_str = build_device_id(disk_image.image_file()).unwrap();
+        let mut disk_image_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
+        let disk_image_id_bytes = disk_image_id_str.as_bytes();
+        let bytes_to_copy = cmp::min(disk_image_id_bytes.len(), VIRTIO_BLK_ID_BYTES as usize);
+        disk_image_id[..bytes_to_copy].clone_from_slice(&disk_image_id_bytes[..bytes_to_copy]);
+        (
+            BlockEpollHandler {
+                queues,
+                mem: mem.clone(),
+                disk_image: Some(disk_image),
+                disk_nsectors,
+                interrupt_status: status,
+                interrupt_evt,
+                queue_evts,
+                rate_limiter: RateLimiter::default(),
+                disk_image_id,
+                io_uring: None,
+                acked_features: 0,
+                cache_mode: CacheMode::Writeback,
+                writeback: Arc::new(AtomicBool::new(true)),
+            },
+            vq,
+        )
+    }
+
+    // Helper function for varying the parameters of the function activating a block device.
+    fn activate_block_with_modifiers(
+        b: &mut Block,
+        bad_qlen: bool,
+        bad_evtlen: bool,
+    ) -> ActivateResult {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
+        let ievt = EventFd::new().unwrap();
+        let stat = Arc::new(AtomicUsize::new(0));
+
+        let vq = VirtQueue::new(GuestAddress(0), &m, 16);
+        let mut queues = vec![vq.create_queue()];
+        let mut queue_evts = vec![EventFd::new().unwrap()];
+
+        // Invalidate queues list to test this failure case.
+        if bad_qlen {
+            queues.pop();
+        }
+
+        // Invalidate queue-events list to test this failure case.
+        if bad_evtlen {
+            queue_evts.pop();
+        }
+
+        b.activate(m.clone(), ievt, stat, queues, queue_evts)
+    }
+
+    fn invoke_handler_for_queue_event(h: &mut BlockEpollHandler) {
+        // leave at least one event here so that reading it later won't block
+        h.interrupt_evt.write(1).unwrap();
+        // trigger the queue event
+        h.queue_evts[0].write(1).unwrap();
+        // handle event
+        h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        // validate the queue operation finished successfully
+        assert_eq!(h.interrupt_evt.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_request_type() {
+        let m = &GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
+        let a = GuestAddress(0);
+
+        // We write values associated with different request type at an address in memory,
+        // and verify the request type is parsed correctly.
+
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, a).unwrap();
+        assert_eq!(request_type(m, a).unwrap(), RequestType::In);
+
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, a).unwrap();
+        assert_eq!(request_type(m, a).unwrap(), RequestType::Out);
+
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_FLUSH, a).unwrap();
+        assert_eq!(request_type(m, a).unwrap(), RequestType::Flush);
+
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_GET_ID, a).unwrap();
+        assert_eq!(request_type(m, a).unwrap(), RequestType::GetDeviceID);
+
+        // The value written here should be invalid.
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_FLUSH + 10, a)
+            .unwrap();
+        assert_eq!(
+            request_type(m, a).unwrap(),
+            RequestType::Unsupported(VIRTIO_BLK_T_FLUSH + 10)
+        );
+
+        // The provided address cannot be read, as it's outside the memory space.
+        let a = GuestAddress(0x1000);
+        assert!(request_type(m, a).is_err())
+    }
+
+    #[test]
+    fn test_sector() {
+        let m = &GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
+        let a = GuestAddress(0);
+
+        // Here we test that a sector number is parsed correctly from memory. The actual sector
+        // number is expected to be found 8 bytes after the address provided as parameter to the
+        // sector() function.
+
+        m.write_obj_at_addr::<u64>(123_454_321, a.checked_add(8).unwrap())
+            .unwrap();
+        assert_eq!(sector(m, a).unwrap(), 123_454_321);
+
+        // Reading from a slightly different address should not lead a correct result in this case.
+        assert_ne!(sector(m, a.checked_add(1).unwrap()).unwrap(), 123_454_321);
+
+        // The provided address is outside the valid memory range.
+        assert!(sector(m, a.checked_add(0x1000).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let m = &GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &m, 16);
+
+        assert!(vq.end().0 < 0x1000);
+
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        {
+            let mut q = vq.create_queue();
+            // write only request type descriptor
+            vq.dtable[0].set(0x1000, 0x1000, VIRTQ_DESC_F_WRITE, 1);
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(114, GuestAddress(0x1000 + 8))
+                .unwrap();
+            assert!(match Request::parse(&q.iter(m).next().unwrap(), m) {
+                Err(Error::UnexpectedWriteOnlyDescriptor) => true,
+                _ => false,
+            });
+        }
+
+        {
+            let mut q = vq.create_queue();
+            // chain too short; no data_desc
+            vq.dtable[0].flags.set(0);
+            assert!(match Request::parse(&q.iter(m).next().unwrap(), m) {
+                Err(Error::DescriptorChainTooShort) => true,
+                _ => false,
+            });
+        }
+
+        {
+            let mut q = vq.create_queue();
+            // chain too short; no status desc
+            vq.dtable[0].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].set(0x2000, 0x1000, 0, 2);
+            assert!(match Request::parse(&q.iter(m).next().unwrap(), m) {
+                Err(Error::DescriptorChainTooShort) => true,
+                _ => false,
+            });
+        }
+
+        {
+            let mut q = vq.create_queue();
+            // write only data for OUT
+            vq.dtable[1]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            vq.dtable[2].set(0x3000, 0, 0, 0);
+            assert!(match Request::parse(&q.iter(m).next().unwrap(), m) {
+                Err(Error::UnexpectedWriteOnlyDescriptor) => true,
+                _ => false,
+            });
+        }
+
+        {
+            let mut q = vq.create_queue();
+            // read only data for IN
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+                .unwrap();
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            assert!(match Request::parse(&q.iter(m).next().unwrap(), m) {
+                Err(Error::UnexpectedReadOnlyDescriptor) => true,
+                _ => false,
+            });
+        }
+
+        {
+            let mut q = vq.create_queue();
+            // status desc not writable
+            vq.dtable[1]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            assert!(match Request::parse(&q.iter(m).next().unwrap(), m) {
+                Err(Error::UnexpectedReadOnlyDescriptor) => true,
+                _ => false,
+            });
+        }
+
+        {
+            let mut q = vq.create_queue();
+            // status desc too small
+            vq.dtable[2].flags.set(VIRTQ_DESC_F_WRITE);
+            assert!(match Request::parse(&q.iter(m).next().unwrap(), m) {
+                Err(Error::DescriptorLengthTooSmall) => true,
+                _ => false,
+            });
+        }
+
+        {
+            let mut q = vq.create_queue();
+            // should be OK now
+            vq.dtable[2].len.set(0x1000);
+            let r = Request::parse(&q.iter(m).next().unwrap(), m).unwrap();
+
+            assert_eq!(r.request_type, RequestType::In);
+            assert_eq!(r.sector, 114);
+            assert_eq!(r.data_addr, GuestAddress(0x2000));
+            assert_eq!(r.data_len, 0x1000);
+            assert_eq!(r.status_addr, GuestAddress(0x3000));
+        }
+    }
+
+    #[test]
+    #[allow(clippy::cyclomatic_complexity)]
+    fn test_virtio_device() {
+        let mut dummy = DummyBlock::new(true);
+        let b = dummy.block();
+
+        // Test `device_type()`.
+        {
+            assert_eq!(b.device_type(), TYPE_BLOCK);
+        }
+
+        // Test `queue_max_sizes()`.
+        {
+            let x = b.queue_max_sizes();
+            assert_eq!(x, &[QUEUE_SIZE]);
+
+            // power of 2?
+            for &y in x {
+                assert!(y > 0 && y & (y - 1) == 0);
+            }
+        }
+
+        // Test `read_config()`.
+        {
+            let mut num_sectors = [0u8; 4];
+            b.read_config(0, &mut num_sectors);
+            // size is 0x1000, so num_sectors is 8 (4096/512).
+            assert_eq!([0x08, 0x00, 0x00, 0x00], num_sectors);
+            let mut msw_sectors = [0u8; 4];
+            b.read_config(4, &mut msw_sectors);
+            // size is 0x1000, so msw_sectors is 0.
+            assert_eq!([0x00, 0x00, 0x00, 0x00], msw_sectors);
+
+            // Invalid read.
+            num_sectors = [0xd, 0xe, 0xa, 0xd];
+            check_metric_after_block!(
+                &METRICS.block.cfg_fails,
+                1,
+                b.read_config(CONFIG_SPACE_SIZE as u64 + 1, &mut num_sectors)
+            );
+            // Validate read failed.
+            assert_eq!(num_sectors, [0xd, 0xe, 0xa, 0xd]);
+        }
+
+        // Test `features()` and `ack_features()`.
+        {
+            let features: u64 = (1u64 << VIRTIO_BLK_F_RO)
+                | (1u64 << VIRTIO_F_VERSION_1)
+                | (1u64 << VIRTIO_BLK_F_FLUSH);
+
+            assert_eq!(b.features(0), features as u32);
+            assert_eq!(b.features(1), (features >> 32) as u32);
+            for i in 2..10 {
+                assert_eq!(b.features(i), 0u32);
+            }
+
+            for i in 0..10 {
+                b.ack_features(i, u32::MAX);
+            }
+            assert_eq!(b.acked_features, features);
+        }
+
+        // Test `activate()`.
+        {
+            // It should fail when not enough queues and/or evts are provided.
+            check_metric_after_block!(
+                &METRICS.block.activate_fails,
+                1,
+                assert!(match activate_block_with_modifiers(b, true, false) {
+                    Err(ActivateError::BadActivate) => true,
+                    _ => false,
+                })
+            );
+            check_metric_after_block!(
+                &METRICS.block.activate_fails,
+                1,
+                assert!(match activate_block_with_modifiers(b, false, true) {
+                    Err(ActivateError::BadActivate) => true,
+                    _ => false,
+                })
+            );
+            check_metric_after_block!(
+                &METRICS.block.activate_fails,
+                1,
+                assert!(match activate_block_with_modifiers(b, true, true) {
+                    Err(ActivateError::BadActivate) => true,
+                    _ => false,
+                })
+            );
+            // Otherwise, it should be ok.
+            assert!(activate_block_with_modifiers(b, false, false).is_ok());
+
+            // Second activate shouldn't be ok anymore.
+            check_metric_after_block!(
+                &METRICS.block.activate_fails,
+                1,
+                assert!(match activate_block_with_modifiers(b, false, false) {
+                    Err(ActivateError::BadActivate) => true,
+                    _ => false,
+                })
+            );
+        }
+
+        // Test `write_config()`.
+        {
+            let new_config: [u8; 8] = [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+            b.write_config(0, &new_config);
+            let mut new_config_read = [0u8; 8];
+            b.read_config(0, &mut new_config_read);
+            assert_eq!(new_config, new_config_read);
+            // Invalid write.
+            check_metric_after_block!(&METRICS.block.cfg_fails, 1, b.write_config(5, &new_config));
+            // Make sure nothing got written.
+            new_config_read = [0u8; 8];
+            b.read_config(0, &mut new_config_read);
+            assert_eq!(new_config, new_config_read);
+        }
+    }
+
+    #[test]
+    fn test_invalid_event_handler() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let (mut h, _vq) = default_test_blockepollhandler(&m);
+        let r = h.handle_event(
+            block_events_count(1) as DeviceEventT,
+            0,
+            EpollHandlerPayload::Empty,
+        );
+        match r {
+            Err(DeviceError::UnknownEvent { event, device }) => {
+                assert_eq!(event, block_events_count(1) as DeviceEventT);
+                assert_eq!(device, "block");
+            }
+            _ => panic!("invalid"),
+        }
+    }
+
+    // Cannot easily test failures for
+    //  * queue_evt.read
+    //  * interrupt_evt.write
+
+    #[test]
+    fn test_fs_update_event_error() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let (mut h, _vq) = default_test_blockepollhandler(&m);
+        // This should panic because payload is empty for event type FS_UPDATE_EVENT.
+        let r = h.handle_event(FS_UPDATE_EVENT, 0, EpollHandlerPayload::Empty);
+        match r {
+            Err(DeviceError::PayloadExpected) => (),
+            _ => panic!("invalid"),
+        }
+    }
+
+    #[test]
+    #[allow(clippy::cyclomatic_complexity)]
+    fn test_handler() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+
+        let blk_metadata = h.disk_image().image_file().metadata();
+
+        for i in 0..3 {
+            vq.avail.ring[i].set(i as u16);
+            vq.dtable[i].set(
+                (0x1000 * (i + 1)) as u64,
+                0x1000,
+                VIRTQ_DESC_F_NEXT,
+                (i + 1) as u16,
+            );
+        }
+
+        vq.dtable[1]
+            .flags
+            .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        vq.dtable[2].flags.set(VIRTQ_DESC_F_WRITE);
+        vq.avail.idx.set(1);
+
+        // dtable[1] is the data descriptor
+        let data_addr = GuestAddress(vq.dtable[1].addr.get() as usize);
+        // dtable[2] is the status descriptor
+        let status_addr = GuestAddress(vq.dtable[2].addr.get() as usize);
+
+        {
+            // let's start with a request that does not parse
+            // request won't be valid bc the first desc is write-only
+            vq.dtable[0]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut h);
+
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+        }
+
+        // now we generate some request execute failures
+
+        {
+            // reset the queue to reuse descriptors & memory
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            // first desc no longer writable
+            vq.dtable[0].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            // let's generate a seek execute error caused by a very large sector number
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(0x000f_ffff_ffff, GuestAddress(0x1000 + 8))
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut h);
+
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+
+        {
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            vq.dtable[1]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            // set sector to a valid number but large enough that the full 0x1000 read will fail
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(10, GuestAddress(0x1000 + 8))
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut h);
+
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+
+        // test unsupported block commands
+        // currently 0, 1, 4, 8 are supported
+
+        {
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            // set sector to 0
+            m.write_obj_at_addr::<u64>(0, GuestAddress(0x1000 + 8))
+                .unwrap();
+            // ... but generate an unsupported request
+            m.write_obj_at_addr::<u32>(16, GuestAddress(0x1000))
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut h);
+
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_UNSUPP
+            );
+        }
+
+        // now let's write something and read it back
+
+        {
+            // write
+
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
+                .unwrap();
+            // make data read only, 8 bytes in len, and set the actual value to be written
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].len.set(8);
+            m.write_obj_at_addr::<u64>(123_456_789, data_addr).unwrap();
+
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+        }
+
+        {
+            // read
+
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+                .unwrap();
+            vq.dtable[1]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, vq.dtable[1].len.get());
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+            assert_eq!(m.read_obj_from_addr::<u64>(data_addr).unwrap(), 123_456_789);
+        }
+
+        {
+            // testing that the flush request completes successfully,
+            // when a data descriptor is provided
+
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_FLUSH, GuestAddress(0x1000))
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+        }
+
+        {
+            // testing that the flush request completes successfully,
+            // without a data descriptor
+
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+            vq.dtable[0].next.set(2);
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_FLUSH, GuestAddress(0x1000))
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+
+            vq.dtable[0].next.set(1);
+        }
+
+        {
+            // testing that the driver receives the correct device id
+
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+            vq.dtable[1].len.set(VIRTIO_BLK_ID_BYTES);
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_GET_ID, GuestAddress(0x1000))
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+
+            assert!(blk_metadata.is_ok());
+            let blk_meta = blk_metadata.unwrap();
+            let expected_device_id = format!(
+                "{}{}{}",
+                blk_meta.st_dev(),
+                blk_meta.st_rdev(),
+                blk_meta.st_ino()
+            );
+
+            let mut buf = [0; VIRTIO_BLK_ID_BYTES as usize];
+            assert_eq!(
+                m.read_slice_at_addr(&mut buf, data_addr).unwrap(),
+                VIRTIO_BLK_ID_BYTES as usize
+            );
+            let chars_to_trim: &[char] = &['\u{0}'];
+            let received_device_id = String::from_utf8(buf.to_ascii_lowercase())
+                .unwrap()
+                .trim_matches(chars_to_trim)
+                .to_string();
+            assert_eq!(received_device_id, expected_device_id);
+        }
+
+        {
+            // test that a device ID request will fail, if it fails to provide enough buffer space
+
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+            vq.dtable[1].len.set(VIRTIO_BLK_ID_BYTES - 1);
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_GET_ID, GuestAddress(0x1000))
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+
+        // test the bandwidth rate limiter
+        {
+            // create bandwidth rate limiter that allows only 80 bytes/s with bucket size of 8 bytes
+            let mut rl = RateLimiter::new(8, None, 100, 0, None, 0).unwrap();
+            // use up the budget
+            assert!(rl.consume(8, TokenType::Bytes));
+
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+            h.set_rate_limiter(rl);
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
+                .unwrap();
+            // make data read only, 8 bytes in len, and set the actual value to be written
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].len.set(8);
+            m.write_obj_at_addr::<u64>(123_456_789, data_addr).unwrap();
+
+            // following write procedure should fail because of bandwidth rate limiting
+            {
+                // leave at least one event here so that reading it later won't block
+                h.interrupt_evt.write(1).unwrap();
+                // trigger the attempt to write
+                h.queue_evts[0].write(1).unwrap();
+                h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+                    .unwrap();
+
+                // assert that limiter is blocked
+                assert!(h.get_rate_limiter().is_blocked());
+                // assert that no operation actually completed (limiter blocked it)
+                assert_eq!(h.interrupt_evt.read().unwrap(), 1);
+                // make sure the data is still queued for processing
+                assert_eq!(vq.used.idx.get(), 0);
+            }
+
+            // wait for 100ms to give the rate-limiter timer a chance to replenish
+            // wait for an extra 50ms to make sure the timerfd event makes its way from the kernel
+            thread::sleep(Duration::from_millis(150));
+
+            // following write procedure should succeed because bandwidth should now be available
+            {
+                // leave at least one event here so that reading it later won't block
+                h.interrupt_evt.write(1).unwrap();
+                h.handle_event(RATE_LIMITER_EVENT, 0, EpollHandlerPayload::Empty)
+                    .unwrap();
+                // validate the rate_limiter is no longer blocked
+                assert!(!h.get_rate_limiter().is_blocked());
+                // make sure the virtio queue operation completed this time
+                assert_eq!(h.interrupt_evt.read().unwrap(), 2);
+
+                // make sure the data queue advanced
+                assert_eq!(vq.used.idx.get(), 1);
+                assert_eq!(vq.used.ring[0].get().id, 0);
+                assert_eq!(vq.used.ring[0].get().len, 0);
+                assert_eq!(
+                    m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                    VIRTIO_BLK_S_OK
+                );
+            }
+        }
+
+        // test the ops/s rate limiter
+        {
+            // create ops rate limiter that allows only 10 ops/s with bucket size of 1 ops
+            let mut rl = RateLimiter::new(0, None, 0, 1, None, 100).unwrap();
+            // use up the budget
+            assert!(rl.consume(1, TokenType::Ops));
+
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+            h.set_rate_limiter(rl);
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
+                .unwrap();
+            // make data read only, 8 bytes in len, and set the actual value to be written
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].len.set(8);
+            m.write_obj_at_addr::<u64>(123_456_789, data_addr).unwrap();
+
+            // following write procedure should fail because of ops rate limiting
+            {
+                // leave at least one event here so that reading it later won't block
+                h.interrupt_evt.write(1).unwrap();
+                // trigger the attempt to write
+                h.queue_evts[0].write(1).unwrap();
+                h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+                    .unwrap();
+
+                // assert that limiter is blocked
+                assert!(h.get_rate_limiter().is_blocked());
+                // assert that no operation actually completed (limiter blocked it)
+                assert_eq!(h.interrupt_evt.read().unwrap(), 1);
+                // make sure the data is still queued for processing
+                assert_eq!(vq.used.idx.get(), 0);
+            }
+
+            // do a second write that still fails but this time on the fast path
+            {
+                // leave at least one event here so that reading it later won't block
+                h.interrupt_evt.write(1).unwrap();
+                // trigger the attempt to write
+                h.queue_evts[0].write(1).unwrap();
+                h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+                    .unwrap();
+
+                // assert that limiter is blocked
+                assert!(h.get_rate_limiter().is_blocked());
+                // assert that no operation actually completed (limiter blocked it)
+                assert_eq!(h.interrupt_evt.read().unwrap(), 1);
+                // make sure the data is still queued for processing
+                assert_eq!(vq.used.idx.get(), 0);
+            }
+
+            // wait for 100ms to give the rate-limiter timer a chance to replenish
+            // wait for an extra 50ms to make sure the timerfd event makes its way from the kernel
+            thread::sleep(Duration::from_millis(150));
+
+            // following write procedure should succeed because ops budget should now be available
+            {
+                // leave at least one event here so that reading it later won't block
+                h.interrupt_evt.write(1).unwrap();
+                h.handle_event(RATE_LIMITER_EVENT, 0, EpollHandlerPayload::Empty)
+                    .unwrap();
+                // validate the rate_limiter is no longer blocked
+                assert!(!h.get_rate_limiter().is_blocked());
+                // make sure the virtio queue operation completed this time
+                assert_eq!(h.interrupt_evt.read().unwrap(), 2);
+
+                // make sure the data queue advanced
+                assert_eq!(vq.used.idx.get(), 1);
+                assert_eq!(vq.used.ring[0].get().id, 0);
+                assert_eq!(vq.used.ring[0].get().len, 0);
+                assert_eq!(
+                    m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                    VIRTIO_BLK_S_OK
+                );
+            }
+        }
+
+        // test block device update handler
+        {
+            let f = NamedTempFile::new().unwrap();
+            let path = f.path().to_path_buf();
+            let mdata = metadata(&path).unwrap();
+            let mut id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
+            let str_id = format!("{}{}{}", mdata.st_dev(), mdata.st_rdev(), mdata.st_ino());
+            let part_id = str_id.as_bytes();
+            id[..cmp::min(part_id.len(), VIRTIO_BLK_ID_BYTES as usize)].clone_from_slice(
+                &part_id[..cmp::min(part_id.len(), VIRTIO_BLK_ID_BYTES as usize)],
+            );
+
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .unwrap();
+            let payload = EpollHandlerPayload::DrivePayload(Some(Box::new(file)));
+            h.handle_event(FS_UPDATE_EVENT, 0, payload).unwrap();
+
+            assert_eq!(
+                h.disk_image().image_file().metadata().unwrap().st_ino(),
+                mdata.st_ino()
+            );
+            assert_eq!(h.disk_image_id, id);
+        }
+    }
+
+    // Waits for the io_uring of the handler to complete some requests, and handles them.
+    fn handle_io_completion(h: &mut BlockEpollHandler) {
+        let mut pollfd = libc::pollfd {
+            fd: h.io_uring.as_ref().unwrap().completion_evt().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 5000) }, 1);
+        h.handle_event(IO_COMPLETION_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_io_uring_handler() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        h.io_uring = Some(IoUringEngine::new(u32::from(QUEUE_SIZE)).unwrap());
+        assert_eq!(
+            h.event_fds().last(),
+            Some(&h.io_uring.as_ref().unwrap().completion_evt().as_raw_fd())
+        );
+
+        // Two requests, each made of a header, a data and a status descriptor.
+        for i in 0..6 {
+            let (flags, next) = match i % 3 {
+                2 => (VIRTQ_DESC_F_WRITE, 0),
+                _ => (VIRTQ_DESC_F_NEXT, i + 1),
+            };
+            vq.dtable[i].set((0x1000 * (i + 1)) as u64, 8, flags, next as u16);
+        }
+        vq.avail.ring[0].set(0);
+        vq.avail.ring[1].set(3);
+        vq.avail.idx.set(2);
+
+        // The first request writes to the disk through the io_uring.
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
+            .unwrap();
+        m.write_obj_at_addr::<u64>(1, GuestAddress(0x1000 + 8))
+            .unwrap();
+        m.write_obj_at_addr::<u64>(123_456_789, GuestAddress(0x2000))
+            .unwrap();
+        // The second one is unsupported, so it fails right away.
+        m.write_obj_at_addr::<u32>(16, GuestAddress(0x4000))
+            .unwrap();
+        m.write_obj_at_addr::<u64>(0, GuestAddress(0x4000 + 8))
+            .unwrap();
+
+        h.queue_evts[0].write(1).unwrap();
+        h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        while vq.used.idx.get() < 2 {
+            assert!(vq.used.idx.get() == 0);
+            handle_io_completion(&mut h);
+        }
+        // The requests are used in the order they were made.
+        assert_eq!(vq.used.ring[0].get().id, 0);
+        assert_eq!(vq.used.ring[0].get().len, 0);
+        assert_eq!(vq.used.ring[1].get().id, 3);
+        assert_eq!(vq.used.ring[1].get().len, 1);
+        assert_eq!(
+            m.read_obj_from_addr::<u32>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(
+            m.read_obj_from_addr::<u32>(GuestAddress(0x6000)).unwrap(),
+            VIRTIO_BLK_S_UNSUPP
+        );
+
+        let mut buf = [0u8; 8];
+        h.disk_image().seek(SeekFrom::Start(SECTOR_SIZE)).unwrap();
+        h.disk_image().read_exact(&mut buf).unwrap();
+        assert_eq!(u64::from_le_bytes(buf), 123_456_789);
+
+        // Read the data back.
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+            .unwrap();
+        m.write_obj_at_addr::<u64>(0, GuestAddress(0x2000)).unwrap();
+        vq.dtable[1]
+            .flags
+            .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        vq.avail.ring[2].set(0);
+        vq.avail.idx.set(3);
+
+        h.queue_evts[0].write(1).unwrap();
+        h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        while vq.used.idx.get() < 3 {
+            handle_io_completion(&mut h);
+        }
+        assert_eq!(vq.used.ring[2].get().id, 0);
+        assert_eq!(vq.used.ring[2].get().len, 8);
+        assert_eq!(
+            m.read_obj_from_addr::<u64>(GuestAddress(0x2000)).unwrap(),
+            123_456_789
+        );
+
+        // Requests beyond the end of the disk fail before reaching the io_uring.
+        m.write_obj_at_addr::<u64>(8, GuestAddress(0x1000 + 8))
+            .unwrap();
+        vq.avail.ring[3].set(0);
+        vq.avail.idx.set(4);
+        h.queue_evts[0].write(1).unwrap();
+        h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(vq.used.idx.get(), 4);
+        assert_eq!(vq.used.ring[3].get().len, 1);
+        assert_eq!(
+            m.read_obj_from_addr::<u32>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_BLK_S_IOERR
+        );
+    }
+
+    #[test]
+    fn test_io_uring_short_transfer() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        h.io_uring = Some(IoUringEngine::new(u32::from(QUEUE_SIZE)).unwrap());
+        // Reading from a pipe only transfers what was written to it so far.
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut pipe_in = unsafe { File::from_raw_fd(fds[1]) };
+        h.disk_image = Some(Box::new(unsafe { File::from_raw_fd(fds[0]) }));
+
+        vq.dtable[0].set(0x1000, 16, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x2000, 8, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 2);
+        vq.dtable[2].set(0x3000, 1, VIRTQ_DESC_F_WRITE, 0);
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+            .unwrap();
+        m.write_obj_at_addr::<u64>(0, GuestAddress(0x1000 + 8))
+            .unwrap();
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        pipe_in.write_all(&[1, 2, 3, 4]).unwrap();
+        h.queue_evts[0].write(1).unwrap();
+        h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        // The rest of the read is submitted again, and waits for more data.
+        handle_io_completion(&mut h);
+        assert_eq!(vq.used.idx.get(), 0);
+
+        pipe_in.write_all(&[5, 6, 7, 8]).unwrap();
+        handle_io_completion(&mut h);
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().len, 8);
+        assert_eq!(
+            m.read_obj_from_addr::<u32>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+        let mut buf = [0u8; 8];
+        m.read_slice_at_addr(&mut buf, GuestAddress(0x2000))
+            .unwrap();
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // The end of the file fails the read.
+        drop(pipe_in);
+        vq.avail.ring[1].set(0);
+        vq.avail.idx.set(2);
+        h.queue_evts[0].write(1).unwrap();
+        h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        while vq.used.idx.get() < 2 {
+            handle_io_completion(&mut h);
+        }
+        assert_eq!(
+            m.read_obj_from_addr::<u32>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_BLK_S_IOERR
+        );
+    }
+
+    #[test]
+    fn test_io_uring_drain() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+
+        // Without an io_uring, no request is ever in flight.
+        h.handle_event(DRAIN_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert!(h.interrupt_evt.read().is_err());
+
+        h.io_uring = Some(IoUringEngine::new(u32::from(QUEUE_SIZE)).unwrap());
+        // Two requests reading from the disk, each made of a header, a data and a status
+        // descriptor.
+        for i in 0..6 {
+            let (flags, next) = match i % 3 {
+                0 => (VIRTQ_DESC_F_NEXT, i + 1),
+                1 => (VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, i + 1),
+                _ => (VIRTQ_DESC_F_WRITE, 0),
+            };
+            vq.dtable[i].set((0x1000 * (i + 1)) as u64, 8, flags, next as u16);
+        }
+        for addr in &[0x1000, 0x4000] {
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(*addr))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(0, GuestAddress(addr + 8))
+                .unwrap();
+        }
+        vq.avail.ring[0].set(0);
+        vq.avail.ring[1].set(3);
+        vq.avail.idx.set(2);
+
+        h.queue_evts[0].write(1).unwrap();
+        h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        // Once drained, every request is on the used ring without any completion event being
+        // handled.
+        h.handle_event(DRAIN_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(vq.used.idx.get(), 2);
+        assert_eq!(vq.used.ring[0].get().id, 0);
+        assert_eq!(vq.used.ring[1].get().id, 3);
+        for addr in &[0x3000, 0x6000] {
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(GuestAddress(*addr)).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+        }
+        assert!(h.interrupt_evt.read().is_ok());
+
+        // There is nothing left to drain.
+        h.handle_event(DRAIN_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert!(h.interrupt_evt.read().is_err());
+        handle_io_completion(&mut h);
+        assert_eq!(vq.used.idx.get(), 2);
+    }
+
+    #[test]
+    fn test_empty_drive() {
+        // The guest sees a drive without a disk image as a disk of 0 bytes.
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, _receiver) = mpsc::channel();
+        let mut b = Block::new(
+            None,
+            false,
+            EpollConfig::new(0, epoll_raw_fd, sender),
+            None,
+            None,
+            false,
+            false,
+            CacheMode::Writeback,
+            vec![QUEUE_SIZE],
+        )
+        .unwrap();
+        let mut capacity = [0xffu8; CAPACITY_SIZE];
+        b.read_config(0, &mut capacity);
+        assert_eq!(capacity, [0u8; CAPACITY_SIZE]);
+        assert!(activate_block_with_modifiers(&mut b, false, false).is_ok());
+        // A device is only activated once.
+        assert!(activate_block_with_modifiers(&mut b, false, false).is_err());
+        unsafe { libc::close(epoll_raw_fd) };
+
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        vq.dtable[0].set(0x1000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x3000, 0x10, VIRTQ_DESC_F_WRITE, 0);
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_FLUSH, GuestAddress(0x1000))
+            .unwrap();
+        m.write_obj_at_addr::<u64>(0, GuestAddress(0x1000 + 8))
+            .unwrap();
+        let run = |h: &mut BlockEpollHandler| -> u32 {
+            vq.used.idx.set(0);
+            vq.avail.ring[0].set(0);
+            vq.avail.idx.set(1);
+            h.set_queue(0, vq.create_queue());
+            h.queue_evts[0].write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+                .unwrap();
+            assert_eq!(vq.used.idx.get(), 1);
+            m.read_obj_from_addr::<u32>(GuestAddress(0x3000)).unwrap()
+        };
+
+        // Requests fail once the disk image is detached, and succeed again once another one is
+        // attached.
+        assert_eq!(run(&mut h), VIRTIO_BLK_S_OK);
+        check_metric_after_block!(
+            &METRICS.block.update_count,
+            1,
+            h.handle_event(FS_UPDATE_EVENT, 0, EpollHandlerPayload::DrivePayload(None))
+                .unwrap()
+        );
+        assert!(h.disk_image.is_none());
+        assert_eq!(h.disk_nsectors, 0);
+        assert_eq!(run(&mut h), VIRTIO_BLK_S_IOERR);
+
+        let f: File = tempfile().unwrap();
+        f.set_len(0x1000).unwrap();
+        h.handle_event(
+            FS_UPDATE_EVENT,
+            0,
+            EpollHandlerPayload::DrivePayload(Some(Box::new(f))),
+        )
+        .unwrap();
+        assert_eq!(h.disk_nsectors, 0x1000 / SECTOR_SIZE);
+        assert_eq!(run(&mut h), VIRTIO_BLK_S_OK);
+    }
+
+    #[test]
+    fn test_io_uring_update_disk_image() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        h.io_uring = Some(IoUringEngine::new(u32::from(QUEUE_SIZE)).unwrap());
+
+        // A read from an empty FIFO stays in flight until something is written to it.
+        let dir = tempdir().unwrap();
+        let path = CString::new(dir.path().join("fifo").to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        let mut fifo = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dir.path().join("fifo"))
+            .unwrap();
+        h.disk_image = Some(Box::new(fifo.try_clone().unwrap()));
+
+        vq.dtable[0].set(0x1000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x2000, 0x200, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 2);
+        vq.dtable[2].set(0x3000, 0x10, VIRTQ_DESC_F_WRITE, 0);
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+            .unwrap();
+        m.write_obj_at_addr::<u64>(0, GuestAddress(0x1000 + 8))
+            .unwrap();
+        let read = |h: &mut BlockEpollHandler| {
+            vq.avail.ring[0].set(0);
+            vq.avail.idx.set(vq.avail.idx.get() + 1);
+            h.queue_evts[0].write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+                .unwrap();
+        };
+        read(&mut h);
+        assert_eq!(vq.used.idx.get(), 0);
+
+        // The read in flight completes from the previous disk image, before the drive switches
+        // to the new one.
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            fifo.write_all(&[0xaa; 0x200]).unwrap();
+        });
+        let mut f: File = tempfile().unwrap();
+        f.write_all(&[0x55; 0x1000]).unwrap();
+        h.handle_event(
+            FS_UPDATE_EVENT,
+            0,
+            EpollHandlerPayload::DrivePayload(Some(Box::new(f))),
+        )
+        .unwrap();
+        writer.join().unwrap();
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().len, 0x200);
+        assert_eq!(
+            m.read_obj_from_addr::<u8>(GuestAddress(0x2000)).unwrap(),
+            0xaa
+        );
+        assert_eq!(
+            m.read_obj_from_addr::<u32>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+        assert!(h.interrupt_evt.read().is_ok());
+        assert_eq!(h.disk_nsectors, 0x1000 / SECTOR_SIZE);
+
+        // The next read goes to the new disk image.
+        read(&mut h);
+        while vq.used.idx.get() < 2 {
+            handle_io_completion(&mut h);
+        }
+        assert_eq!(
+            m.read_obj_from_addr::<u8>(GuestAddress(0x2000)).unwrap(),
+            0x55
+        );
+        assert_eq!(
+            m.read_obj_from_addr::<u32>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+
+        // Once the disk image is detached, the requests fail.
+        h.handle_event(FS_UPDATE_EVENT, 0, EpollHandlerPayload::DrivePayload(None))
+            .unwrap();
+        read(&mut h);
+        assert_eq!(vq.used.idx.get(), 3);
+        assert_eq!(
+            m.read_obj_from_addr::<u32>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_BLK_S_IOERR
+        );
+    }
+
+    #[test]
+    fn test_discard_write_zeroes() {
+        // The features and limits are only exposed for writable disks.
+        for &read_only in &[false, true] {
+            let epoll_raw_fd = epoll::create(true).unwrap();
+            let (sender, _receiver) = mpsc::channel();
+            let f: File = tempfile().unwrap();
+            f.set_len(0x1000).unwrap();
+            let b = Block::new(
+                Some(Box::new(f)),
+                read_only,
+                EpollConfig::new(0, epoll_raw_fd, sender),
+                None,
+                None,
+                true,
+                true,
+                CacheMode::Writeback,
+                vec![QUEUE_SIZE],
+            )
+            .unwrap();
+            let features = u64::from(b.features(0)) | u64::from(b.features(1)) << 32;
+            let mut max_discard_sectors = [0u8; 4];
+            b.read_config(MAX_DISCARD_SECTORS_OFFSET as u64, &mut max_discard_sectors);
+            if read_only {
+                assert_eq!(features & (1u64 << VIRTIO_BLK_F_DISCARD), 0);
+                assert_eq!(features & (1u64 << VIRTIO_BLK_F_WRITE_ZEROES), 0);
+                assert_eq!(max_discard_sectors, [0u8; 4]);
+            } else {
+                assert_ne!(features & (1u64 << VIRTIO_BLK_F_DISCARD), 0);
+                assert_ne!(features & (1u64 << VIRTIO_BLK_F_WRITE_ZEROES), 0);
+                assert_eq!(max_discard_sectors, [0xffu8; 4]);
+                let mut may_unmap = [0u8; 1];
+                b.read_config(WRITE_ZEROES_MAY_UNMAP_OFFSET as u64, &mut may_unmap);
+                assert_eq!(may_unmap, [1]);
+            }
+            unsafe { libc::close(epoll_raw_fd) };
+        }
+
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        h.disk_image().seek(SeekFrom::Start(0)).unwrap();
+        h.disk_image().write_all(&[0xffu8; 0x1000]).unwrap();
+
+        vq.dtable[0].set(0x1000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x2000, 0x10, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable[2].set(0x3000, 0x10, VIRTQ_DESC_F_WRITE, 0);
+        let segment_addr = GuestAddress(0x2000);
+        let status_addr = GuestAddress(0x3000);
+
+        // Runs a single request of `request_type` on a segment, and returns its status.
+        let run = |h: &mut BlockEpollHandler, request_type: u32, segment: DiscardSegment| -> u32 {
+            m.write_obj_at_addr::<u32>(request_type, GuestAddress(0x1000))
+                .unwrap();
+            m.write_obj_at_addr(segment, segment_addr).unwrap();
+            vq.used.idx.set(0);
+            vq.avail.ring[0].set(0);
+            vq.avail.idx.set(1);
+            h.set_queue(0, vq.create_queue());
+            invoke_handler_for_queue_event(h);
+            assert_eq!(vq.used.idx.get(), 1);
+            m.read_obj_from_addr::<u32>(status_addr).unwrap()
+        };
+        let segment = |sector: u64, num_sectors: u32, flags: u32| DiscardSegment {
+            sector,
+            num_sectors,
+            flags,
+        };
+
+        // The driver did not acknowledge the features.
+        assert_eq!(
+            run(&mut h, VIRTIO_BLK_T_DISCARD, segment(1, 2, 0)),
+            VIRTIO_BLK_S_UNSUPP
+        );
+        h.acked_features = (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+
+        check_metric_after_block!(
+            &METRICS.block.discard_count,
+            0x400,
+            assert_eq!(
+                run(&mut h, VIRTIO_BLK_T_DISCARD, segment(1, 2, 0)),
+                VIRTIO_BLK_S_OK
+            )
+        );
+        check_metric_after_block!(
+            &METRICS.block.write_zeroes_count,
+            0x200,
+            assert_eq!(
+                run(&mut h, VIRTIO_BLK_T_WRITE_ZEROES, segment(4, 1, 0)),
+                VIRTIO_BLK_S_OK
+            )
+        );
+        assert_eq!(
+            run(
+                &mut h,
+                VIRTIO_BLK_T_WRITE_ZEROES,
+                segment(6, 1, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP)
+            ),
+            VIRTIO_BLK_S_OK
+        );
+
+        let mut contents = vec![0u8; 0x1000];
+        h.disk_image().seek(SeekFrom::Start(0)).unwrap();
+        h.disk_image().read_exact(&mut contents).unwrap();
+        for (sector, data) in contents.chunks(SECTOR_SIZE as usize).enumerate() {
+            let zeroed = sector == 1 || sector == 2 || sector == 4 || sector == 6;
+            assert!(data.iter().all(|&b| b == if zeroed { 0 } else { 0xff }));
+        }
+
+        // Discard segments have no flags.
+        assert_eq!(
+            run(
+                &mut h,
+                VIRTIO_BLK_T_DISCARD,
+                segment(1, 1, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP)
+            ),
+            VIRTIO_BLK_S_UNSUPP
+        );
+        assert_eq!(
+            run(&mut h, VIRTIO_BLK_T_WRITE_ZEROES, segment(1, 1, 2)),
+            VIRTIO_BLK_S_UNSUPP
+        );
+        // The range must be within the disk.
+        assert_eq!(
+            run(&mut h, VIRTIO_BLK_T_DISCARD, segment(7, 2, 0)),
+            VIRTIO_BLK_S_IOERR
+        );
+        assert_eq!(
+            run(
+                &mut h,
+                VIRTIO_BLK_T_DISCARD,
+                segment(u64::max_value(), 1, 0)
+            ),
+            VIRTIO_BLK_S_IOERR
+        );
+        // Only whole segments, up to the advertised number of them, are accepted.
+        vq.dtable[1].len.set(0x18);
+        assert_eq!(
+            run(&mut h, VIRTIO_BLK_T_DISCARD, segment(1, 1, 0)),
+            VIRTIO_BLK_S_IOERR
+        );
+        vq.dtable[1].len.set(0x20);
+        assert_eq!(
+            run(&mut h, VIRTIO_BLK_T_WRITE_ZEROES, segment(1, 1, 0)),
+            VIRTIO_BLK_S_IOERR
+        );
+    }
+
+    #[test]
+    fn test_cache_modes() {
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, _receiver) = mpsc::channel();
+        let new_block = |disk_image: Box<DiskFile>, cache_mode: CacheMode| {
+            Block::new(
+                Some(disk_image),
+                false,
+                EpollConfig::new(0, epoll_raw_fd, sender.clone()),
+                None,
+                None,
+                false,
+                false,
+                cache_mode,
+                vec![QUEUE_SIZE],
+            )
+        };
+
+        // O_DIRECT only works on raw images.
+        let overlay = CowFile::in_memory(Box::new(tempfile().unwrap())).unwrap();
+        assert_eq!(
+            new_block(Box::new(overlay), CacheMode::Direct)
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        // The driver can turn the write cache off, once it acknowledged the feature.
+        let mut b = new_block(Box::new(tempfile().unwrap()), CacheMode::Writeback).unwrap();
+        assert_ne!(b.features(0) & (1u32 << VIRTIO_BLK_F_CONFIG_WCE), 0);
+        let mut writeback = [0u8; 1];
+        b.read_config(WRITEBACK_OFFSET as u64, &mut writeback);
+        assert_eq!(writeback, [1]);
+        b.write_config(WRITEBACK_OFFSET as u64, &[0]);
+        assert!(b.writeback.load(Ordering::SeqCst));
+        b.ack_features(0, 1u32 << VIRTIO_BLK_F_CONFIG_WCE);
+        b.write_config(WRITEBACK_OFFSET as u64, &[0]);
+        assert!(!b.writeback.load(Ordering::SeqCst));
+        b.write_config(WRITEBACK_OFFSET as u64 - 1, &[0, 1]);
+        assert!(b.writeback.load(Ordering::SeqCst));
+        unsafe { libc::close(epoll_raw_fd) };
+
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        h.cache_mode = CacheMode::Direct;
+        let data: Vec<u8> = (0..0x200).map(|i| i as u8).collect();
+        vq.dtable[0].set(0x1000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[2].set(0x3000, 0x10, VIRTQ_DESC_F_WRITE, 0);
+        m.write_obj_at_addr::<u64>(1, GuestAddress(0x1000 + 8))
+            .unwrap();
+        let status_addr = GuestAddress(0x3000);
+
+        // Runs a single request of `request_type`, with its data at `data_addr`, and returns
+        // its status.
+        let run = |h: &mut BlockEpollHandler, request_type: u32, data_addr: u64| -> u32 {
+            m.write_obj_at_addr::<u32>(request_type, GuestAddress(0x1000))
+                .unwrap();
+            let flags = match request_type {
+                VIRTIO_BLK_T_IN => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+                _ => VIRTQ_DESC_F_NEXT,
+            };
+            vq.dtable[1].set(data_addr, 0x200, flags, 2);
+            vq.used.idx.set(0);
+            vq.avail.ring[0].set(0);
+            vq.avail.idx.set(1);
+            h.set_queue(0, vq.create_queue());
+            h.queue_evts[0].write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+                .unwrap();
+            while vq.used.idx.get() < 1 {
+                handle_io_completion(h);
+            }
+            m.read_obj_from_addr::<u32>(status_addr).unwrap()
+        };
+
+        // Guest buffers which are not aligned go through bounce buffers, with either engine.
+        m.write_slice_at_addr(&data, GuestAddress(0x2001)).unwrap();
+        assert_eq!(run(&mut h, VIRTIO_BLK_T_OUT, 0x2001), VIRTIO_BLK_S_OK);
+        let mut buf = vec![0u8; 0x200];
+        h.disk_image().seek(SeekFrom::Start(SECTOR_SIZE)).unwrap();
+        h.disk_image().read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(run(&mut h, VIRTIO_BLK_T_IN, 0x4003), VIRTIO_BLK_S_OK);
+        m.read_slice_at_addr(&mut buf, GuestAddress(0x4003))
+            .unwrap();
+        assert_eq!(buf, data);
+
+        h.io_uring = Some(IoUringEngine::new(u32::from(QUEUE_SIZE)).unwrap());
+        assert_eq!(run(&mut h, VIRTIO_BLK_T_IN, 0x5005), VIRTIO_BLK_S_OK);
+        m.read_slice_at_addr(&mut buf, GuestAddress(0x5005))
+            .unwrap();
+        assert_eq!(buf, data);
+        // Writes complete once synced when the write cache is off.
+        h.writeback.store(false, Ordering::SeqCst);
+        m.write_slice_at_addr(&[0xaa; 0x200], GuestAddress(0x2001))
+            .unwrap();
+        assert_eq!(run(&mut h, VIRTIO_BLK_T_OUT, 0x2001), VIRTIO_BLK_S_OK);
+        h.disk_image().seek(SeekFrom::Start(SECTOR_SIZE)).unwrap();
+        h.disk_image().read_exact(&mut buf).unwrap();
+        assert_eq!(buf, vec![0xaa; 0x200]);
+
+        // Flushes are ignored in the unsafe mode, but still complete.
+        h.io_uring = None;
+        h.cache_mode = CacheMode::Unsafe;
+        check_metric_after_block!(
+            &METRICS.block.flush_count,
+            1,
+            assert_eq!(run(&mut h, VIRTIO_BLK_T_FLUSH, 0x2000), VIRTIO_BLK_S_OK)
+        );
+    }
+
+    #[test]
+    fn test_multi_queue() {
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let new_block = |queue_sizes: Vec<u16>| {
+            Block::new(
+                Some(Box::new(tempfile().unwrap())),
+                true,
+                EpollConfig::new(0, epoll_raw_fd, sender.clone()),
+                None,
+                None,
+                false,
+                false,
+                CacheMode::Writeback,
+                queue_sizes,
+            )
+        };
+
+        let invalid_queue_sizes = vec![
+            vec![],
+            vec![QUEUE_SIZE; MAX_QUEUES as usize + 1],
+            vec![QUEUE_SIZE, 100],
+            vec![2 * MAX_QUEUE_SIZE],
+        ];
+        for queue_sizes in invalid_queue_sizes {
+            assert_eq!(
+                new_block(queue_sizes).err().unwrap().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+
+        // A single queue does not need the feature.
+        let b = new_block(vec![QUEUE_SIZE]).unwrap();
+        assert_eq!(b.features(0) & (1u32 << VIRTIO_BLK_F_MQ), 0);
+
+        // Read-only disks expose the number of queues too.
+        let mut b = new_block(vec![MAX_QUEUE_SIZE; 4]).unwrap();
+        assert_ne!(b.features(0) & (1u32 << VIRTIO_BLK_F_MQ), 0);
+        let mut num_queues = [0u8; 2];
+        b.read_config(NUM_QUEUES_OFFSET as u64, &mut num_queues);
+        assert_eq!(u16::from_le_bytes(num_queues), 4);
+        assert_eq!(b.queue_max_sizes(), &[MAX_QUEUE_SIZE; 4]);
+        assert_eq!(b.required_queues(), 1);
+
+        // The queues after the first one the driver did not set up are left out.
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &m, 16);
+        let mut queues = vec![vq.create_queue(); 4];
+        queues[2].ready = false;
+        let queue_evts = (0..4).map(|_| EventFd::new().unwrap()).collect();
+        let status = Arc::new(AtomicUsize::new(0));
+        b.activate(
+            m.clone(),
+            EventFd::new().unwrap(),
+            status,
+            queues,
+            queue_evts,
+        )
+        .unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        assert_eq!(
+            receiver.recv().unwrap().event_fds().len(),
+            h.event_fds().len() + 1
+        );
+        unsafe { libc::close(epoll_raw_fd) };
+
+        // Each queue is processed on its own event, but they share the rate limiter, which only
+        // lets one request through.
+        let vq1 = VirtQueue::new(GuestAddress(0x8000), &m, 16);
+        h.queues.push(vq1.create_queue());
+        h.queue_evts.push(EventFd::new().unwrap());
+        h.set_rate_limiter(RateLimiter::new(0, None, 0, 1, None, 100).unwrap());
+        for &(vq, base) in [(&vq, 0x1000usize), (&vq1, 0x9000)].iter() {
+            vq.dtable[0].set(base as u64, 0x10, VIRTQ_DESC_F_NEXT, 1);
+            vq.dtable[1].set(base as u64 + 0x1000, 8, VIRTQ_DESC_F_NEXT, 2);
+            vq.dtable[2].set(base as u64 + 0x2000, 0x10, VIRTQ_DESC_F_WRITE, 0);
+            vq.avail.ring[0].set(0);
+            vq.avail.idx.set(1);
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(base))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(base as u64, GuestAddress(base + 0x1000))
+                .unwrap();
+        }
+        m.write_obj_at_addr::<u64>(1, GuestAddress(0x1000 + 8))
+            .unwrap();
+        m.write_obj_at_addr::<u64>(2, GuestAddress(0x9000 + 8))
+            .unwrap();
+
+        h.queue_evts[1].write(1).unwrap();
+        h.handle_event(QUEUE_AVAIL_EVENT + 1, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(vq.used.idx.get(), 0);
+        assert_eq!(vq1.used.idx.get(), 1);
+
+        h.queue_evts[0].write(1).unwrap();
+        h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert!(h.get_rate_limiter().is_blocked());
+        assert_eq!(vq.used.idx.get(), 0);
+
+        // Wait for the rate limiter to replenish.
+        thread::sleep(Duration::from_millis(150));
+        h.handle_event(RATE_LIMITER_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(vq.used.idx.get(), 1);
+
+        let mut buf = [0u8; 8];
+        for &(sector, data) in &[(1, 0x1000), (2, 0x9000)] {
+            h.disk_image()
+                .seek(SeekFrom::Start(sector * SECTOR_SIZE))
+                .unwrap();
+            h.disk_image().read_exact(&mut buf).unwrap();
+            assert_eq!(u64::from_le_bytes(buf), data);
+        }
+    }
+
+    #[test]
+    fn test_verity() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        // A disk of a single block takes no hash blocks, its hash being the root hash.
+        let mut disk: File = tempfile().unwrap();
+        disk.write_all(&[0xaa; VERITY_BLOCK_SIZE as usize]).unwrap();
+        let mut sha256 = sha256::Sha256::new();
+        sha256.update(&[0xaa; VERITY_BLOCK_SIZE as usize]);
+        h.disk_image = Some(Box::new(
+            VerityFile::new(
+                Box::new(disk.try_clone().unwrap()),
+                tempfile().unwrap(),
+                &sha256.finish(),
+                None,
+            )
+            .unwrap(),
+        ));
+
+        vq.dtable[0].set(0x1000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x2000, 0x200, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 2);
+        vq.dtable[2].set(0x3000, 0x10, VIRTQ_DESC_F_WRITE, 0);
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+            .unwrap();
+        m.write_obj_at_addr::<u64>(1, GuestAddress(0x1000 + 8))
+            .unwrap();
+        let run = |h: &mut BlockEpollHandler| -> u32 {
+            vq.used.idx.set(0);
+            vq.avail.ring[0].set(0);
+            vq.avail.idx.set(1);
+            h.set_queue(0, vq.create_queue());
+            h.queue_evts[0].write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+                .unwrap();
+            assert_eq!(vq.used.idx.get(), 1);
+            m.read_obj_from_addr::<u32>(GuestAddress(0x3000)).unwrap()
+        };
+
+        assert_eq!(run(&mut h), VIRTIO_BLK_S_OK);
+        assert_eq!(
+            m.read_obj_from_addr::<[u8; 32]>(GuestAddress(0x2000))
+                .unwrap(),
+            [0xaa; 32]
+        );
+
+        // The block no longer matches once changed on the host.
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.write_all(&[0xbb]).unwrap();
+        m.write_slice_at_addr(&[0; 0x200], GuestAddress(0x2000))
+            .unwrap();
+        check_metric_after_block!(
+            &METRICS.block.integrity_fails,
+            1,
+            assert_eq!(run(&mut h), VIRTIO_BLK_S_IOERR)
+        );
+        assert_eq!(
+            m.read_obj_from_addr::<[u8; 32]>(GuestAddress(0x2000))
+                .unwrap(),
+            [0; 32]
+        );
+    }
+
+    #[test]
+    fn test_nbd() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        let server = nbd::test_server::TestServer::new(0x1000, 0);
+        h.disk_image = Some(Box::new(server.connect(false)));
+        h.disk_nsectors = 0x1000 / SECTOR_SIZE;
+
+        vq.dtable[0].set(0x1000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x2000, 0x200, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable[2].set(0x3000, 0x10, VIRTQ_DESC_F_WRITE, 0);
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
+            .unwrap();
+        m.write_obj_at_addr::<u64>(1, GuestAddress(0x1000 + 8))
+            .unwrap();
+        m.write_slice_at_addr(&[0xcc; 0x200], GuestAddress(0x2000))
+            .unwrap();
+        let run = |h: &mut BlockEpollHandler| -> u32 {
+            vq.used.idx.set(0);
+            vq.avail.ring[0].set(0);
+            vq.avail.idx.set(1);
+            h.set_queue(0, vq.create_queue());
+            h.queue_evts[0].write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+                .unwrap();
+            assert_eq!(vq.used.idx.get(), 1);
+            m.read_obj_from_addr::<u32>(GuestAddress(0x3000)).unwrap()
+        };
+
+        assert_eq!(run(&mut h), VIRTIO_BLK_S_OK);
+        assert_eq!(
+            &server.state.lock().unwrap().data[0x200..0x400],
+            &[0xcc; 0x200][..]
+        );
+        // Requests go through a dropped connection, which is made again.
+        server.inject(nbd::test_server::Fault::Disconnect);
+        assert_eq!(run(&mut h), VIRTIO_BLK_S_OK);
+        assert_eq!(server.state.lock().unwrap().connections, 2);
+        // Errors of the server fail the requests.
+        server.inject(nbd::test_server::Fault::Error(libc::EIO as u32));
+        assert_eq!(run(&mut h), VIRTIO_BLK_S_IOERR);
+    }
+}
@@ -0,0 +1,764 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A disk served by an NBD server listening on a Unix socket.
+//!
+//! The client negotiates the export with the fixed newstyle handshake of the NBD protocol, then
+//! sends one request at a time and waits for its simple reply. When the connection fails, the
+//! client connects to the server again a bounded number of times, backing off in between, and
+//! sends the request again, so that a restart of the server does not fail the requests of the
+//! guest. Since the requests are served on the VMM thread, each of them fails once it has waited
+//! on the server for a few seconds, reconnections included.
+
+use std::cmp;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::disk::{seek_position, DiskFile};
+use sys_util::{PunchHole, WriteZeroes};
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const NBD_OPTION_MAGIC: u64 = 0x4948_4156_454f_5054;
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+// The handshake flags of the server, and the matching flags of the client.
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+// The zeroes ending the export information, unless the client asked to leave them out.
+const EXPORT_PADDING: usize = 124;
+
+// The transmission flags of the export.
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+// Asks the server to keep the zeroed range allocated.
+const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+const REQUEST_SIZE: usize = 28;
+const REPLY_SIZE: usize = 16;
+// The largest amount of data a request carries, which servers are required to accept.
+const MAX_PAYLOAD_SIZE: u64 = 32 << 20;
+// The largest range a request without data covers.
+const MAX_RANGE_SIZE: u64 = 0xffff_f000;
+
+// How many times the client connects to the server again before failing a request, and the
+// delay before the first attempt, which doubles with each attempt.
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY_MS: u64 = 10;
+// How long the client waits on the server for the handshake, or for a request along with the
+// reconnections it takes, before giving up.
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Errors connecting to an NBD server.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot connect to the socket of the server.
+    Connect(io::Error),
+    /// The handshake failed, which is also how servers turn down unknown exports.
+    Handshake(io::Error),
+    /// The server does not follow the fixed newstyle handshake.
+    InvalidHandshake,
+    /// The export is read-only, but the drive is not.
+    ReadOnlyExport,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match *self {
+            Connect(ref e) => write!(f, "Cannot connect to the socket of the server: {}", e),
+            Handshake(ref e) => write!(f, "The handshake with the server failed: {}", e),
+            InvalidHandshake => write!(
+                f,
+                "The server does not support the fixed newstyle handshake."
+            ),
+            ReadOnlyExport => write!(f, "The export is read-only."),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// The data a request carries to the server or expects back.
+enum Payload<'a> {
+    Length(u32),
+    Write(&'a [u8]),
+    Read(&'a mut [u8]),
+}
+
+impl<'a> Payload<'a> {
+    fn len(&self) -> u32 {
+        match *self {
+            Payload::Length(len) => len,
+            Payload::Write(ref data) => data.len() as u32,
+            Payload::Read(ref data) => data.len() as u32,
+        }
+    }
+}
+
+/// A disk served by an NBD server on a Unix socket.
+pub struct NbdDisk {
+    socket_path: PathBuf,
+    export_name: String,
+    read_only: bool,
+    // A handle on the socket, which identifies the disk to the guest.
+    socket_file: File,
+    // The connection to the server, which is dropped when it fails.
+    stream: Option<UnixStream>,
+    size: u64,
+    transmission_flags: u16,
+    handle: u64,
+    position: u64,
+}
+
+impl NbdDisk {
+    /// Connects to the NBD server listening on the socket at `path`, and negotiates the export
+    /// named `export_name`.
+    pub fn connect(path: &Path, export_name: &str, read_only: bool) -> Result<NbdDisk> {
+        let socket_file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH)
+            .open(path)
+            .map_err(Error::Connect)?;
+        let (stream, size, transmission_flags) =
+            handshake(path, export_name, Instant::now() + IO_TIMEOUT)?;
+        if !read_only && transmission_flags & NBD_FLAG_READ_ONLY != 0 {
+            return Err(Error::ReadOnlyExport);
+        }
+
+        Ok(NbdDisk {
+            socket_path: path.to_path_buf(),
+            export_name: export_name.to_string(),
+            read_only,
+            socket_file,
+            stream: Some(stream),
+            size,
+            transmission_flags,
+            handle: 0,
+            position: 0,
+        })
+    }
+
+    fn reconnect(&mut self, deadline: Instant) -> io::Result<()> {
+        let (stream, size, transmission_flags) =
+            handshake(&self.socket_path, &self.export_name, deadline)
+                .map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e.to_string()))?;
+        if size != self.size || (!self.read_only && transmission_flags & NBD_FLAG_READ_ONLY != 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the NBD export changed across the reconnection",
+            ));
+        }
+        self.stream = Some(stream);
+        self.transmission_flags = transmission_flags;
+        Ok(())
+    }
+
+    fn has_flag(&self, flag: u16) -> bool {
+        self.transmission_flags & flag != 0
+    }
+
+    // Sends a request to the server and waits for its reply. A connection which fails is
+    // dropped, and the request is sent again over a new one, while attempts and time are left.
+    // Errors the server replies with are returned as they are.
+    fn transmit(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        mut payload: Payload,
+    ) -> io::Result<()> {
+        let deadline = Instant::now() + IO_TIMEOUT;
+        let mut attempt = 0;
+        loop {
+            self.handle = self.handle.wrapping_add(1);
+            let handle = self.handle;
+            let result = match self.stream {
+                Some(ref mut stream) => set_deadline(stream, deadline)
+                    .and_then(|()| exchange(stream, handle, command, flags, offset, &mut payload)),
+                None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+            };
+            let error = match result {
+                Ok(0) => return Ok(()),
+                Ok(errno) => return Err(io::Error::from_raw_os_error(errno as i32)),
+                Err(e) => e,
+            };
+
+            // The replies left on a failed connection cannot be told apart anymore.
+            self.stream = None;
+            loop {
+                let delay = Duration::from_millis(RECONNECT_DELAY_MS << attempt);
+                if attempt == RECONNECT_ATTEMPTS || Instant::now() + delay >= deadline {
+                    return Err(error);
+                }
+                thread::sleep(delay);
+                attempt += 1;
+                match self.reconnect(deadline) {
+                    Ok(()) => break,
+                    Err(e) => warn!("Cannot reconnect to the NBD server: {}", e),
+                }
+            }
+        }
+    }
+
+    // Sends requests covering `length` bytes from `offset`, each carrying no data.
+    fn transmit_range(
+        &mut self,
+        command: u16,
+        flags: u16,
+        mut offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        let end = offset
+            .checked_add(length)
+            .filter(|&end| end <= self.size)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        while offset < end {
+            let len = cmp::min(end - offset, MAX_RANGE_SIZE);
+            self.transmit(command, flags, offset, Payload::Length(len as u32))?;
+            offset += len;
+        }
+        Ok(())
+    }
+
+    // Zeroes a range by writing zeroes to it, for servers without the write zeroes command.
+    fn write_zero_buffers(&mut self, mut offset: u64, length: u64) -> io::Result<()> {
+        let end = offset
+            .checked_add(length)
+            .filter(|&end| end <= self.size)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let zeroes = vec![0u8; cmp::min(length, MAX_PAYLOAD_SIZE) as usize];
+        while offset < end {
+            let len = cmp::min(end - offset, zeroes.len() as u64) as usize;
+            self.transmit(NBD_CMD_WRITE, 0, offset, Payload::Write(&zeroes[..len]))?;
+            offset += len as u64;
+        }
+        Ok(())
+    }
+}
+
+// Makes the reads and writes on `stream` time out at `deadline`.
+fn set_deadline(stream: &UnixStream, deadline: Instant) -> io::Result<()> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(io::Error::from(io::ErrorKind::TimedOut));
+    }
+    stream.set_read_timeout(Some(deadline - now))?;
+    stream.set_write_timeout(Some(deadline - now))
+}
+
+// Connects to the server at `path` and negotiates the export named `export_name`, by
+// `deadline`. Returns the connection, with the size and the transmission flags of the export.
+fn handshake(path: &Path, export_name: &str, deadline: Instant) -> Result<(UnixStream, u64, u16)> {
+    let mut stream = UnixStream::connect(path).map_err(Error::Connect)?;
+    set_deadline(&stream, deadline).map_err(Error::Connect)?;
+
+    let mut greeting = [0u8; 18];
+    stream.read_exact(&mut greeting).map_err(Error::Handshake)?;
+    let handshake_flags = BigEndian::read_u16(&greeting[16..]);
+    if BigEndian::read_u64(&greeting[..8]) != NBD_MAGIC
+        || BigEndian::read_u64(&greeting[8..16]) != NBD_OPTION_MAGIC
+        || handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0
+    {
+        return Err(Error::InvalidHandshake);
+    }
+    let no_zeroes = handshake_flags & NBD_FLAG_NO_ZEROES != 0;
+
+    let mut option = vec![0u8; 20];
+    let client_flags = if no_zeroes {
+        NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES
+    } else {
+        NBD_FLAG_C_FIXED_NEWSTYLE
+    };
+    BigEndian::write_u32(&mut option[..4], client_flags);
+    BigEndian::write_u64(&mut option[4..12], NBD_OPTION_MAGIC);
+    BigEndian::write_u32(&mut option[12..16], NBD_OPT_EXPORT_NAME);
+    BigEndian::write_u32(&mut option[16..20], export_name.len() as u32);
+    option.extend_from_slice(export_name.as_bytes());
+    stream.write_all(&option).map_err(Error::Handshake)?;
+
+    let mut export = [0u8; 10 + EXPORT_PADDING];
+    let export_len = if no_zeroes { 10 } else { export.len() };
+    stream
+        .read_exact(&mut export[..export_len])
+        .map_err(Error::Handshake)?;
+    Ok((
+        stream,
+        BigEndian::read_u64(&export[..8]),
+        BigEndian::read_u16(&export[8..10]),
+    ))
+}
+
+// Sends a request over `stream` and reads its reply. Returns the error of the reply, which is
+// 0 when the request succeeded.
+fn exchange(
+    stream: &mut UnixStream,
+    handle: u64,
+    command: u16,
+    flags: u16,
+    offset: u64,
+    payload: &mut Payload,
+) -> io::Result<u32> {
+    let mut request = [0u8; REQUEST_SIZE];
+    BigEndian::write_u32(&mut request[..4], NBD_REQUEST_MAGIC);
+    BigEndian::write_u16(&mut request[4..6], flags);
+    BigEndian::write_u16(&mut request[6..8], command);
+    BigEndian::write_u64(&mut request[8..16], handle);
+    BigEndian::write_u64(&mut request[16..24], offset);
+    BigEndian::write_u32(&mut request[24..28], payload.len());
+    stream.write_all(&request)?;
+    if let Payload::Write(data) = *payload {
+        stream.write_all(data)?;
+    }
+
+    let mut reply = [0u8; REPLY_SIZE];
+    stream.read_exact(&mut reply)?;
+    if BigEndian::read_u32(&reply[..4]) != NBD_SIMPLE_REPLY_MAGIC
+        || BigEndian::read_u64(&reply[8..16]) != handle
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid reply from the NBD server",
+        ));
+    }
+    let error = BigEndian::read_u32(&reply[4..8]);
+    if let Payload::Read(ref mut data) = *payload {
+        if error == 0 {
+            stream.read_exact(data)?;
+        }
+    }
+    Ok(error)
+}
+
+impl Read for NbdDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(
+            cmp::min(buf.len() as u64, MAX_PAYLOAD_SIZE),
+            self.size.saturating_sub(self.position),
+        ) as usize;
+        if len > 0 {
+            let offset = self.position;
+            self.transmit(NBD_CMD_READ, 0, offset, Payload::Read(&mut buf[..len]))?;
+            self.position += len as u64;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for NbdDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = cmp::min(
+            cmp::min(buf.len() as u64, MAX_PAYLOAD_SIZE),
+            self.size.saturating_sub(self.position),
+        ) as usize;
+        if len > 0 {
+            let offset = self.position;
+            self.transmit(NBD_CMD_WRITE, 0, offset, Payload::Write(&buf[..len]))?;
+            self.position += len as u64;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for NbdDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(self.position, self.size, pos)?;
+        Ok(self.position)
+    }
+}
+
+impl PunchHole for NbdDisk {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        // Trimmed ranges do not have to read back as zeroes, unlike zeroed ones the server is
+        // allowed to deallocate.
+        if self.has_flag(NBD_FLAG_SEND_WRITE_ZEROES) {
+            self.transmit_range(NBD_CMD_WRITE_ZEROES, 0, offset, length)
+        } else {
+            self.write_zero_buffers(offset, length)
+        }
+    }
+}
+
+impl WriteZeroes for NbdDisk {
+    fn write_zeroes(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        if self.has_flag(NBD_FLAG_SEND_WRITE_ZEROES) {
+            self.transmit_range(NBD_CMD_WRITE_ZEROES, NBD_CMD_FLAG_NO_HOLE, offset, length)
+        } else {
+            self.write_zero_buffers(offset, length)
+        }
+    }
+}
+
+impl DiskFile for NbdDisk {
+    fn image_file(&self) -> &File {
+        &self.socket_file
+    }
+
+    fn is_raw(&self) -> bool {
+        false
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.has_flag(NBD_FLAG_SEND_FLUSH) {
+            self.transmit(NBD_CMD_FLUSH, 0, 0, Payload::Length(0))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn discard(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        // Discarding is only a hint, which servers without the trim command can ignore.
+        if self.has_flag(NBD_FLAG_SEND_TRIM) {
+            self.transmit_range(NBD_CMD_TRIM, 0, offset, length)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A minimal NBD server on a Unix socket, which serves an export from memory.
+#[cfg(test)]
+pub(super) mod test_server {
+    extern crate tempfile;
+
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use self::tempfile::{tempdir, TempDir};
+    use super::*;
+
+    /// What the server does with a request, instead of serving it.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Fault {
+        /// Replies with this error.
+        Error(u32),
+        /// Closes the connection without replying.
+        Disconnect,
+        /// Keeps the connection open without replying.
+        Hang,
+    }
+
+    #[derive(Default)]
+    pub struct State {
+        pub data: Vec<u8>,
+        pub transmission_flags: u16,
+        // The faults to inject, in the order of the requests they replace.
+        pub faults: VecDeque<Fault>,
+        // The commands served, in order.
+        pub commands: Vec<(u16, u16, u64, u32)>,
+        pub connections: usize,
+        // Whether new connections are turned down, by closing them right away.
+        pub refuse: bool,
+    }
+
+    pub struct TestServer {
+        _dir: TempDir,
+        pub path: PathBuf,
+        pub state: Arc<Mutex<State>>,
+    }
+
+    impl TestServer {
+        pub fn new(size: usize, transmission_flags: u16) -> TestServer {
+            let dir = tempdir().unwrap();
+            let path = dir.path().join("nbd.sock");
+            let listener = UnixListener::bind(&path).unwrap();
+            let state = Arc::new(Mutex::new(State {
+                data: (0..size).map(|i| (i % 251) as u8).collect(),
+                transmission_flags: transmission_flags | 1,
+                ..Default::default()
+            }));
+            let server_state = state.clone();
+            // The thread is left blocked on the listener once the test is done.
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let state = server_state.clone();
+                    thread::spawn(move || serve(stream.unwrap(), &state));
+                }
+            });
+            TestServer {
+                _dir: dir,
+                path,
+                state,
+            }
+        }
+
+        pub fn connect(&self, read_only: bool) -> NbdDisk {
+            NbdDisk::connect(&self.path, "disk", read_only).unwrap()
+        }
+
+        pub fn inject(&self, fault: Fault) {
+            self.state.lock().unwrap().faults.push_back(fault);
+        }
+    }
+
+    fn serve(mut stream: UnixStream, state: &Mutex<State>) {
+        let transmission_flags = {
+            let mut state = state.lock().unwrap();
+            if state.refuse {
+                return;
+            }
+            state.connections += 1;
+            state.transmission_flags
+        };
+        let mut greeting = [0u8; 18];
+        BigEndian::write_u64(&mut greeting[..8], NBD_MAGIC);
+        BigEndian::write_u64(&mut greeting[8..16], NBD_OPTION_MAGIC);
+        BigEndian::write_u16(&mut greeting[16..], NBD_FLAG_FIXED_NEWSTYLE);
+        stream.write_all(&greeting).unwrap();
+        let mut option = [0u8; 20];
+        stream.read_exact(&mut option).unwrap();
+        assert_eq!(BigEndian::read_u32(&option[..4]), NBD_FLAG_C_FIXED_NEWSTYLE);
+        assert_eq!(BigEndian::read_u32(&option[12..16]), NBD_OPT_EXPORT_NAME);
+        let mut name = vec![0u8; BigEndian::read_u32(&option[16..20]) as usize];
+        stream.read_exact(&mut name).unwrap();
+        // Unknown exports are turned down by closing the connection.
+        if name != b"disk" {
+            return;
+        }
+        let mut export = [0u8; 10 + EXPORT_PADDING];
+        BigEndian::write_u64(&mut export[..8], state.lock().unwrap().data.len() as u64);
+        BigEndian::write_u16(&mut export[8..10], transmission_flags);
+        stream.write_all(&export).unwrap();
+
+        let mut request = [0u8; REQUEST_SIZE];
+        while stream.read_exact(&mut request).is_ok() {
+            assert_eq!(BigEndian::read_u32(&request[..4]), NBD_REQUEST_MAGIC);
+            let flags = BigEndian::read_u16(&request[4..6]);
+            let command = BigEndian::read_u16(&request[6..8]);
+            let offset = BigEndian::read_u64(&request[16..24]) as usize;
+            let len = BigEndian::read_u32(&request[24..28]) as usize;
+            let mut data = vec![0u8; len];
+            if command == NBD_CMD_WRITE {
+                stream.read_exact(&mut data).unwrap();
+            }
+
+            let mut state = state.lock().unwrap();
+            let error = match state.faults.pop_front() {
+                Some(Fault::Disconnect) => return,
+                Some(Fault::Hang) => {
+                    drop(state);
+                    // Longer than the client waits for a reply.
+                    thread::sleep(IO_TIMEOUT * 2);
+                    return;
+                }
+                Some(Fault::Error(error)) => error,
+                None => 0,
+            };
+            let mut reply = [0u8; REPLY_SIZE];
+            BigEndian::write_u32(&mut reply[..4], NBD_SIMPLE_REPLY_MAGIC);
+            BigEndian::write_u32(&mut reply[4..8], error);
+            reply[8..16].copy_from_slice(&request[8..16]);
+            if error == 0 {
+                state
+                    .commands
+                    .push((command, flags, offset as u64, len as u32));
+                match command {
+                    NBD_CMD_READ => {
+                        data.copy_from_slice(&state.data[offset..offset + len]);
+                    }
+                    NBD_CMD_WRITE => state.data[offset..offset + len].copy_from_slice(&data),
+                    NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES => {
+                        for byte in &mut state.data[offset..offset + len] {
+                            *byte = 0;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            stream.write_all(&reply).unwrap();
+            if error == 0 && command == NBD_CMD_READ {
+                stream.write_all(&data).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_server::{Fault, TestServer};
+    use super::*;
+
+    fn read_at(disk: &mut NbdDisk, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        disk.seek(SeekFrom::Start(offset))?;
+        disk.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn test_transmission() {
+        let server = TestServer::new(
+            0x10000,
+            NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_TRIM | NBD_FLAG_SEND_WRITE_ZEROES,
+        );
+        let mut disk = server.connect(false);
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), 0x10000);
+        let expected = server.state.lock().unwrap().data.clone();
+        assert_eq!(
+            read_at(&mut disk, 0x1234, 0x2000).unwrap(),
+            &expected[0x1234..0x3234]
+        );
+
+        disk.seek(SeekFrom::Start(0x4000)).unwrap();
+        disk.write_all(&[0xaa; 0x1000]).unwrap();
+        assert_eq!(
+            read_at(&mut disk, 0x4000, 0x1000).unwrap(),
+            vec![0xaa; 0x1000]
+        );
+        // The export does not grow.
+        disk.seek(SeekFrom::Start(0xfff0)).unwrap();
+        assert_eq!(disk.write(&[0u8; 0x20]).unwrap(), 0x10);
+        assert_eq!(disk.write(&[0u8; 0x20]).unwrap(), 0);
+
+        disk.sync().unwrap();
+        disk.discard(0x4000, 0x200).unwrap();
+        disk.punch_hole(0x4200, 0x200).unwrap();
+        disk.write_zeroes(0x4400, 0x200).unwrap();
+        assert_eq!(read_at(&mut disk, 0x4000, 0x600).unwrap(), vec![0; 0x600]);
+        let commands: Vec<(u16, u16)> = server
+            .state
+            .lock()
+            .unwrap()
+            .commands
+            .iter()
+            .map(|&(command, flags, _, _)| (command, flags))
+            .collect();
+        assert_eq!(
+            &commands[commands.len() - 5..],
+            &[
+                (NBD_CMD_FLUSH, 0),
+                (NBD_CMD_TRIM, 0),
+                (NBD_CMD_WRITE_ZEROES, 0),
+                (NBD_CMD_WRITE_ZEROES, NBD_CMD_FLAG_NO_HOLE),
+                (NBD_CMD_READ, 0),
+            ]
+        );
+        assert_eq!(
+            disk.write_zeroes(0xff00, 0x200).unwrap_err().raw_os_error(),
+            Some(libc::EINVAL)
+        );
+    }
+
+    #[test]
+    fn test_minimal_server() {
+        // Without the optional commands, flushes and discards are skipped, and zeroes are
+        // written out.
+        let server = TestServer::new(0x1000, 0);
+        let mut disk = server.connect(false);
+        disk.sync().unwrap();
+        disk.discard(0, 0x200).unwrap();
+        disk.write_zeroes(0x200, 0x200).unwrap();
+        disk.punch_hole(0x400, 0x200).unwrap();
+        assert_eq!(read_at(&mut disk, 0x200, 0x400).unwrap(), vec![0; 0x400]);
+        assert!(server
+            .state
+            .lock()
+            .unwrap()
+            .commands
+            .iter()
+            .all(|&(command, _, _, _)| command == NBD_CMD_WRITE || command == NBD_CMD_READ));
+    }
+
+    #[test]
+    fn test_errors() {
+        let server = TestServer::new(0x1000, NBD_FLAG_SEND_FLUSH);
+        let mut disk = server.connect(false);
+
+        // Errors of the server are passed on, without reconnecting.
+        server.inject(Fault::Error(libc::EIO as u32));
+        assert_eq!(
+            read_at(&mut disk, 0, 0x200).unwrap_err().raw_os_error(),
+            Some(libc::EIO)
+        );
+        server.inject(Fault::Error(libc::ENOSPC as u32));
+        assert_eq!(disk.sync().unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(server.state.lock().unwrap().connections, 1);
+
+        // A dropped connection is made again, and the request sent again.
+        server.inject(Fault::Disconnect);
+        server.inject(Fault::Disconnect);
+        disk.seek(SeekFrom::Start(0x200)).unwrap();
+        disk.write_all(&[0x5a; 0x200]).unwrap();
+        assert_eq!(server.state.lock().unwrap().connections, 3);
+        assert_eq!(read_at(&mut disk, 0x200, 0x200).unwrap(), vec![0x5a; 0x200]);
+
+        // Only so many times.
+        server.state.lock().unwrap().refuse = true;
+        server.inject(Fault::Disconnect);
+        assert!(read_at(&mut disk, 0, 0x200).is_err());
+        assert_eq!(server.state.lock().unwrap().connections, 3);
+        // The next requests try again.
+        server.state.lock().unwrap().refuse = false;
+        assert!(read_at(&mut disk, 0, 0x200).is_ok());
+        assert_eq!(server.state.lock().unwrap().connections, 4);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let server = TestServer::new(0x1000, 0);
+        let mut disk = server.connect(false);
+
+        // A server which stops replying fails the request once the client has waited on it for
+        // the time a request is given, reconnections included.
+        for _ in 0..=RECONNECT_ATTEMPTS {
+            server.inject(Fault::Hang);
+        }
+        let start = Instant::now();
+        assert!(read_at(&mut disk, 0, 0x200).is_err());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= IO_TIMEOUT);
+        assert!(elapsed < IO_TIMEOUT + Duration::from_secs(1));
+
+        // The connection is made again for the next request.
+        server.state.lock().unwrap().faults.clear();
+        assert!(read_at(&mut disk, 0, 0x200).is_ok());
+    }
+
+    #[test]
+    fn test_connect_errors() {
+        let server = TestServer::new(0x1000, NBD_FLAG_READ_ONLY);
+        match NbdDisk::connect(&server.path, "other", true) {
+            Err(Error::Handshake(_)) => (),
+            _ => panic!("The server only serves the disk export."),
+        }
+        match NbdDisk::connect(&server.path, "disk", false) {
+            Err(Error::ReadOnlyExport) => (),
+            _ => panic!("Read-only exports cannot back writable drives."),
+        }
+        let mut disk = server.connect(true);
+        assert!(read_at(&mut disk, 0, 0x1000).is_ok());
+        match NbdDisk::connect(&server.path.with_file_name("missing.sock"), "disk", true) {
+            Err(Error::Connect(_)) => (),
+            _ => panic!("There is no server on a missing socket."),
+        }
+    }
+}
@@ -0,0 +1,1230 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reads and writes disk images in the qcow2 format, as described in `docs/interop/qcow2.txt`
+//! of QEMU.
+//!
+//! Guest offsets are translated to offsets in the image file through a two-level table: the L1
+//! table, which is kept in memory, points to L2 tables, which point to the clusters holding the
+//! data. Clusters are allocated at the end of the file the first time they are written, and
+//! their reference counts are updated right away, so the image stays consistent for other qcow2
+//! tools. Clusters which were never written read from the backing file, if the image has one.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::result;
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+
+use super::disk::{detect_image_format, seek_position, DiskFile, ImageFormat};
+use sys_util::{PunchHole, WriteZeroes};
+
+/// The magic number at the start of qcow images, "QFI\xfb".
+pub const QCOW_MAGIC: u32 = 0x5146_49fb;
+
+// The length of the version 2 header, and of the version 3 header up to the header length field.
+const V2_HEADER_SIZE: usize = 72;
+const V3_HEADER_SIZE: usize = 104;
+// Offsets of the header fields which change as the image grows or is written.
+const REFCOUNT_TABLE_OFFSET_OFFSET: u64 = 48;
+const AUTOCLEAR_FEATURES_OFFSET: u64 = 88;
+
+// The cluster sizes QEMU can create, from 512 bytes to 2 MiB.
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+// The largest L1 and refcount tables QEMU accepts.
+const MAX_L1_TABLE_SIZE: u64 = 32 * 1024 * 1024;
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 * 1024 * 1024;
+// The only supported reference count width, 16 bits.
+const REFCOUNT_ORDER: u32 = 4;
+// The longest backing file name QEMU writes.
+const MAX_BACKING_FILE_NAME_SIZE: u32 = 1023;
+// How many backing files deep an image can be.
+const MAX_BACKING_DEPTH: u32 = 16;
+// How many L2 tables are cached.
+const L2_CACHE_SIZE: usize = 64;
+
+// The offset of the L2 table or cluster in L1 and L2 entries.
+const L1_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+// The table or cluster has a reference count of exactly one, so it can be written in place.
+const COPIED_FLAG: u64 = 1 << 63;
+const COMPRESSED_FLAG: u64 = 1 << 62;
+// The cluster reads as zeroes, in version 3 images.
+const ZERO_FLAG: u64 = 1;
+
+/// Errors opening a qcow2 image.
+#[derive(Debug)]
+pub enum Error {
+    /// The backing file chain is longer than `MAX_BACKING_DEPTH`.
+    BackingChainTooDeep,
+    /// The backing file at the path cannot be opened.
+    BackingFile(PathBuf, Box<Error>),
+    /// The image holds compressed clusters.
+    CompressedClusters,
+    /// The image is encrypted.
+    EncryptedImage,
+    /// The backing file name is too long, not valid UTF-8, or leaves the directory of the
+    /// image.
+    InvalidBackingFileName,
+    /// The cluster size is out of the supported range.
+    InvalidClusterSize(u32),
+    /// The L1 table is misaligned, too small or too large.
+    InvalidL1Table,
+    /// The image does not start with the qcow magic number.
+    InvalidMagic,
+    /// The refcount table is misaligned or too large.
+    InvalidRefcountTable,
+    /// Reading the image failed.
+    Io(io::Error),
+    /// The image needs the incompatible features in the bitmask.
+    UnsupportedFeatures(u64),
+    /// The reference counts are not 16 bits wide.
+    UnsupportedRefcountOrder(u32),
+    /// The image is neither version 2 nor 3.
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match *self {
+            BackingChainTooDeep => write!(
+                f,
+                "The backing file chain is longer than {} images.",
+                MAX_BACKING_DEPTH
+            ),
+            BackingFile(ref path, ref e) => {
+                write!(f, "Cannot open the backing file {}: {}", path.display(), e)
+            }
+            CompressedClusters => write!(f, "Compressed clusters are not supported."),
+            EncryptedImage => write!(f, "Encrypted images are not supported."),
+            InvalidBackingFileName => write!(f, "The backing file name is not valid."),
+            InvalidClusterSize(bits) => write!(
+                f,
+                "The cluster size of 2^{} bytes is not between 2^{} and 2^{} bytes.",
+                bits, MIN_CLUSTER_BITS, MAX_CLUSTER_BITS
+            ),
+            InvalidL1Table => write!(f, "The L1 table is not valid."),
+            InvalidMagic => write!(f, "The image is not in the qcow2 format."),
+            InvalidRefcountTable => write!(f, "The refcount table is not valid."),
+            Io(ref e) => write!(f, "Cannot read the image: {}", e),
+            UnsupportedFeatures(features) => write!(
+                f,
+                "The image needs unsupported features: {:#x}. A dirty image can be \
+                 repaired with `qemu-img check -r all`.",
+                features
+            ),
+            UnsupportedRefcountOrder(order) => {
+                write!(f, "Reference counts of 2^{} bits are not supported.", order)
+            }
+            UnsupportedVersion(version) => write!(
+                f,
+                "Version {} of the qcow format is not supported.",
+                version
+            ),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// The header fields this implementation uses.
+struct Header {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    incompatible_features: u64,
+    autoclear_features: u64,
+    refcount_order: u32,
+}
+
+impl Header {
+    fn read_from(file: &mut File) -> Result<Header> {
+        let mut buf = [0u8; V3_HEADER_SIZE];
+        file.seek(SeekFrom::Start(0)).map_err(Error::Io)?;
+        file.read_exact(&mut buf[..V2_HEADER_SIZE])
+            .map_err(Error::Io)?;
+        if BigEndian::read_u32(&buf[0..]) != QCOW_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let version = BigEndian::read_u32(&buf[4..]);
+        match version {
+            2 => {}
+            3 => file
+                .read_exact(&mut buf[V2_HEADER_SIZE..])
+                .map_err(Error::Io)?,
+            _ => return Err(Error::UnsupportedVersion(version)),
+        }
+
+        Ok(Header {
+            version,
+            backing_file_offset: BigEndian::read_u64(&buf[8..]),
+            backing_file_size: BigEndian::read_u32(&buf[16..]),
+            cluster_bits: BigEndian::read_u32(&buf[20..]),
+            size: BigEndian::read_u64(&buf[24..]),
+            crypt_method: BigEndian::read_u32(&buf[32..]),
+            l1_size: BigEndian::read_u32(&buf[36..]),
+            l1_table_offset: BigEndian::read_u64(&buf[40..]),
+            refcount_table_offset: BigEndian::read_u64(&buf[48..]),
+            refcount_table_clusters: BigEndian::read_u32(&buf[56..]),
+            // Version 2 images have none of the fields below, which default to 0 and 16 bit
+            // reference counts.
+            incompatible_features: BigEndian::read_u64(&buf[72..]),
+            autoclear_features: BigEndian::read_u64(&buf[88..]),
+            refcount_order: if version == 2 {
+                REFCOUNT_ORDER
+            } else {
+                BigEndian::read_u32(&buf[96..])
+            },
+        })
+    }
+}
+
+// Where the data of a guest cluster is.
+enum ClusterLocation {
+    // The cluster was never written, and reads from the backing file.
+    Unallocated,
+    // The cluster reads as zeroes.
+    Zero,
+    // The cluster is at this offset in the image file.
+    Data(u64),
+}
+
+// The error for writes which would change clusters that internal snapshots refer to.
+fn shared_cluster_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "cannot write to clusters shared with internal snapshots",
+    )
+}
+
+fn compressed_cluster_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "compressed clusters are not supported",
+    )
+}
+
+// Reads the table of `count` big endian entries at `offset`.
+fn read_table(file: &mut File, offset: u64, count: u64) -> io::Result<Vec<u64>> {
+    let mut table = vec![0u64; count as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_u64_into::<BigEndian>(&mut table)?;
+    Ok(table)
+}
+
+fn write_u64_at(file: &mut File, offset: u64, value: u64) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_u64::<BigEndian>(value)
+}
+
+/// A disk image in the qcow2 format.
+///
+/// Compressed clusters, encryption and the other incompatible features of version 3 are not
+/// supported, and images using them fail to open. Clusters shared with internal snapshots can
+/// be read, but not written.
+pub struct QcowFile {
+    file: File,
+    version: u32,
+    cluster_bits: u32,
+    cluster_size: u64,
+    virtual_size: u64,
+    l1_table_offset: u64,
+    l1_table: Vec<u64>,
+    // L2 tables by their offset in the file, kept in sync with the file.
+    l2_cache: HashMap<u64, Vec<u64>>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    autoclear_features: u64,
+    backing_file: Option<Box<DiskFile>>,
+    backing_size: u64,
+    // Where the next cluster is allocated, at the end of the file.
+    next_cluster_offset: u64,
+    // The guest offset the next read or write starts at.
+    position: u64,
+}
+
+impl QcowFile {
+    /// Opens the qcow2 image in `file`, which is stored at `path`. Relative backing file names
+    /// are looked up in the directory of `path`. Backing files are opened read-only, in the
+    /// format detected from their contents.
+    pub fn open(file: File, path: &Path) -> Result<QcowFile> {
+        QcowFile::open_backed(file, path, 0)
+    }
+
+    fn open_backed(mut file: File, path: &Path, depth: u32) -> Result<QcowFile> {
+        let header = Header::read_from(&mut file)?;
+        if header.crypt_method != 0 {
+            return Err(Error::EncryptedImage);
+        }
+        if header.incompatible_features != 0 {
+            return Err(Error::UnsupportedFeatures(header.incompatible_features));
+        }
+        if header.refcount_order != REFCOUNT_ORDER {
+            return Err(Error::UnsupportedRefcountOrder(header.refcount_order));
+        }
+        if header.cluster_bits < MIN_CLUSTER_BITS || header.cluster_bits > MAX_CLUSTER_BITS {
+            return Err(Error::InvalidClusterSize(header.cluster_bits));
+        }
+        let cluster_size = 1u64 << header.cluster_bits;
+
+        // Each L1 entry covers the clusters of a whole L2 table.
+        let l1_entry_coverage = cluster_size * (cluster_size / 8);
+        let l1_size = u64::from(header.l1_size);
+        if header.l1_table_offset % cluster_size != 0
+            || l1_size * 8 > MAX_L1_TABLE_SIZE
+            || l1_size < (header.size + l1_entry_coverage - 1) / l1_entry_coverage
+        {
+            return Err(Error::InvalidL1Table);
+        }
+        let refcount_table_size = u64::from(header.refcount_table_clusters) * cluster_size;
+        if header.refcount_table_offset % cluster_size != 0
+            || refcount_table_size > MAX_REFCOUNT_TABLE_SIZE
+        {
+            return Err(Error::InvalidRefcountTable);
+        }
+
+        let l1_table = read_table(&mut file, header.l1_table_offset, l1_size).map_err(Error::Io)?;
+        let refcount_table = read_table(
+            &mut file,
+            header.refcount_table_offset,
+            refcount_table_size / 8,
+        )
+        .map_err(Error::Io)?;
+        let file_size = file.seek(SeekFrom::End(0)).map_err(Error::Io)?;
+
+        let (backing_file, backing_size) =
+            match QcowFile::backing_file_path(&mut file, &header, path)? {
+                Some(backing_path) => {
+                    let mut backing = QcowFile::open_backing_file(&backing_path, depth)
+                        .map_err(|e| Error::BackingFile(backing_path, Box::new(e)))?;
+                    let backing_size = backing.seek(SeekFrom::End(0)).map_err(Error::Io)?;
+                    (Some(backing), backing_size)
+                }
+                None => (None, 0),
+            };
+
+        let mut qcow = QcowFile {
+            file,
+            version: header.version,
+            cluster_bits: header.cluster_bits,
+            cluster_size,
+            virtual_size: header.size,
+            l1_table_offset: header.l1_table_offset,
+            l1_table,
+            l2_cache: HashMap::new(),
+            refcount_table_offset: header.refcount_table_offset,
+            refcount_table,
+            autoclear_features: header.autoclear_features,
+            backing_file,
+            backing_size,
+            next_cluster_offset: (file_size + cluster_size - 1) & !(cluster_size - 1),
+            position: 0,
+        };
+        qcow.check_compressed_clusters()?;
+        Ok(qcow)
+    }
+
+    fn backing_file_path(file: &mut File, header: &Header, path: &Path) -> Result<Option<PathBuf>> {
+        if header.backing_file_offset == 0 {
+            return Ok(None);
+        }
+        if header.backing_file_size > MAX_BACKING_FILE_NAME_SIZE {
+            return Err(Error::InvalidBackingFileName);
+        }
+        let mut name = vec![0u8; header.backing_file_size as usize];
+        file.seek(SeekFrom::Start(header.backing_file_offset))
+            .map_err(Error::Io)?;
+        file.read_exact(&mut name).map_err(Error::Io)?;
+        let name =
+            PathBuf::from(String::from_utf8(name).map_err(|_| Error::InvalidBackingFileName)?);
+        // The name could have been written by the guest, so it can only name files next to or
+        // below the image, and not any file of the host.
+        if name.is_absolute() || name.components().any(|c| c == Component::ParentDir) {
+            return Err(Error::InvalidBackingFileName);
+        }
+        Ok(Some(match path.parent() {
+            Some(dir) => dir.join(name),
+            None => name,
+        }))
+    }
+
+    fn open_backing_file(path: &Path, depth: u32) -> Result<Box<DiskFile>> {
+        if depth >= MAX_BACKING_DEPTH {
+            return Err(Error::BackingChainTooDeep);
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(Error::Io)?;
+        match detect_image_format(&mut file).map_err(Error::Io)? {
+            ImageFormat::Raw => Ok(Box::new(file)),
+            ImageFormat::Qcow2 => Ok(Box::new(QcowFile::open_backed(file, path, depth + 1)?)),
+        }
+    }
+
+    // Compressed clusters are rejected up front, rather than failing the guest requests which
+    // happen to read them.
+    fn check_compressed_clusters(&mut self) -> Result<()> {
+        let l2_entries = self.l2_entries();
+        // The scan does not go through the L2 cache, to leave it empty.
+        for &l1_entry in &self.l1_table {
+            let l2_offset = l1_entry & L1_OFFSET_MASK;
+            if l2_offset == 0 {
+                continue;
+            }
+            let l2_table = read_table(&mut self.file, l2_offset, l2_entries).map_err(Error::Io)?;
+            if l2_table.iter().any(|&entry| entry & COMPRESSED_FLAG != 0) {
+                return Err(Error::CompressedClusters);
+            }
+        }
+        Ok(())
+    }
+
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size / 8
+    }
+
+    fn refcount_block_entries(&self) -> u64 {
+        self.cluster_size / 2
+    }
+
+    // Returns the offset of `offset` in its cluster.
+    fn cluster_offset(&self, offset: u64) -> u64 {
+        offset & (self.cluster_size - 1)
+    }
+
+    // Returns the indexes of the L1 and L2 entries for the cluster holding `guest_offset`.
+    fn table_indexes(&self, guest_offset: u64) -> (usize, usize) {
+        let cluster = guest_offset >> self.cluster_bits;
+        (
+            (cluster / self.l2_entries()) as usize,
+            (cluster % self.l2_entries()) as usize,
+        )
+    }
+
+    fn load_l2_table(&mut self, l2_offset: u64) -> io::Result<()> {
+        if self.l2_cache.contains_key(&l2_offset) {
+            return Ok(());
+        }
+        let l2_entries = self.l2_entries();
+        let table = read_table(&mut self.file, l2_offset, l2_entries)?;
+        self.cache_l2_table(l2_offset, table);
+        Ok(())
+    }
+
+    fn cache_l2_table(&mut self, l2_offset: u64, table: Vec<u64>) {
+        if self.l2_cache.len() >= L2_CACHE_SIZE {
+            // The cached tables match the file, so any of them can go.
+            if let Some(&evicted) = self.l2_cache.keys().next() {
+                self.l2_cache.remove(&evicted);
+            }
+        }
+        self.l2_cache.insert(l2_offset, table);
+    }
+
+    fn l2_entry(&mut self, l2_offset: u64, l2_index: usize) -> io::Result<u64> {
+        self.load_l2_table(l2_offset)?;
+        Ok(self.l2_cache[&l2_offset][l2_index])
+    }
+
+    fn set_l2_entry(&mut self, l2_offset: u64, l2_index: usize, entry: u64) -> io::Result<()> {
+        write_u64_at(&mut self.file, l2_offset + l2_index as u64 * 8, entry)?;
+        if let Some(table) = self.l2_cache.get_mut(&l2_offset) {
+            table[l2_index] = entry;
+        }
+        Ok(())
+    }
+
+    fn set_l1_entry(&mut self, l1_index: usize, entry: u64) -> io::Result<()> {
+        write_u64_at(
+            &mut self.file,
+            self.l1_table_offset + l1_index as u64 * 8,
+            entry,
+        )?;
+        self.l1_table[l1_index] = entry;
+        Ok(())
+    }
+
+    fn locate(&mut self, guest_offset: u64) -> io::Result<ClusterLocation> {
+        let (l1_index, l2_index) = self.table_indexes(guest_offset);
+        let l2_offset = self.l1_table[l1_index] & L1_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(ClusterLocation::Unallocated);
+        }
+        let entry = self.l2_entry(l2_offset, l2_index)?;
+        if entry & COMPRESSED_FLAG != 0 {
+            return Err(compressed_cluster_error());
+        }
+        if self.version >= 3 && entry & ZERO_FLAG != 0 {
+            return Ok(ClusterLocation::Zero);
+        }
+        match entry & L2_OFFSET_MASK {
+            0 => Ok(ClusterLocation::Unallocated),
+            cluster_offset => Ok(ClusterLocation::Data(cluster_offset)),
+        }
+    }
+
+    // Fills `buf` with the data of the backing file at `guest_offset`, which reads as zeroes
+    // past the end of the backing file.
+    fn read_backing_file(&mut self, guest_offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        buf.fill(0);
+        if let Some(ref mut backing_file) = self.backing_file {
+            if guest_offset < self.backing_size {
+                let len = cmp::min(buf.len() as u64, self.backing_size - guest_offset) as usize;
+                backing_file.seek(SeekFrom::Start(guest_offset))?;
+                backing_file.read_exact(&mut buf[..len])?;
+            }
+        }
+        Ok(())
+    }
+
+    // Clears the autoclear feature bits before the first change to the image, as these
+    // features, like persistent bitmaps, are not kept up to date by this implementation.
+    fn clear_autoclear_features(&mut self) -> io::Result<()> {
+        if self.autoclear_features != 0 {
+            write_u64_at(&mut self.file, AUTOCLEAR_FEATURES_OFFSET, 0)?;
+            self.autoclear_features = 0;
+        }
+        Ok(())
+    }
+
+    // Grows the file by a cluster, without a reference to it yet.
+    fn reserve_cluster(&mut self) -> io::Result<u64> {
+        let cluster_offset = self.next_cluster_offset;
+        self.next_cluster_offset += self.cluster_size;
+        // The new cluster reads as zeroes.
+        self.file.set_len(self.next_cluster_offset)?;
+        Ok(cluster_offset)
+    }
+
+    fn allocate_cluster(&mut self) -> io::Result<u64> {
+        let cluster_offset = self.reserve_cluster()?;
+        self.set_refcount(cluster_offset, 1)?;
+        Ok(cluster_offset)
+    }
+
+    fn set_refcount(&mut self, cluster_offset: u64, refcount: u16) -> io::Result<()> {
+        let cluster = cluster_offset >> self.cluster_bits;
+        let block_index = (cluster / self.refcount_block_entries()) as usize;
+        let block_entry = cluster % self.refcount_block_entries();
+        if block_index >= self.refcount_table.len() {
+            self.grow_refcount_table(block_index + 1)?;
+        }
+
+        let mut block_offset = self.refcount_table[block_index] & REFCOUNT_TABLE_OFFSET_MASK;
+        if block_offset == 0 {
+            block_offset = self.reserve_cluster()?;
+            write_u64_at(
+                &mut self.file,
+                self.refcount_table_offset + block_index as u64 * 8,
+                block_offset,
+            )?;
+            self.refcount_table[block_index] = block_offset;
+            // The new block is in the table, so this cannot allocate it again.
+            self.set_refcount(block_offset, 1)?;
+        }
+
+        self.file
+            .seek(SeekFrom::Start(block_offset + block_entry * 2))?;
+        self.file.write_u16::<BigEndian>(refcount)
+    }
+
+    // Moves the refcount table to the end of the file, with room for at least `min_entries`
+    // refcount blocks.
+    fn grow_refcount_table(&mut self, min_entries: usize) -> io::Result<()> {
+        let entries_per_cluster = (self.cluster_size / 8) as usize;
+        // Twice the entries leave room for the refcount blocks of the table itself.
+        let entries = cmp::max(min_entries, self.refcount_table.len()) * 2;
+        let clusters = (entries + entries_per_cluster - 1) / entries_per_cluster;
+        let table_size = clusters as u64 * self.cluster_size;
+        if table_size > MAX_REFCOUNT_TABLE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the refcount table cannot grow any larger",
+            ));
+        }
+
+        let mut table = self.refcount_table.clone();
+        table.resize(table_size as usize / 8, 0);
+        let table_offset = self.next_cluster_offset;
+        for _ in 0..clusters {
+            self.reserve_cluster()?;
+        }
+        self.file.seek(SeekFrom::Start(table_offset))?;
+        let mut buf = vec![0u8; table_size as usize];
+        BigEndian::write_u64_into(&table, &mut buf);
+        self.file.write_all(&buf)?;
+
+        self.file
+            .seek(SeekFrom::Start(REFCOUNT_TABLE_OFFSET_OFFSET))?;
+        self.file.write_u64::<BigEndian>(table_offset)?;
+        self.file.write_u32::<BigEndian>(clusters as u32)?;
+
+        let old_table_offset = self.refcount_table_offset;
+        let old_table_size = self.refcount_table.len() as u64 * 8;
+        self.refcount_table_offset = table_offset;
+        self.refcount_table = table;
+        for i in 0..clusters as u64 {
+            self.set_refcount(table_offset + i * self.cluster_size, 1)?;
+        }
+        let mut offset = old_table_offset;
+        while offset < old_table_offset + old_table_size {
+            self.set_refcount(offset, 0)?;
+            offset += self.cluster_size;
+        }
+        Ok(())
+    }
+
+    // Returns the offset of the L2 table for the L1 entry at `l1_index`, allocating the table if
+    // there is none yet.
+    fn l2_table_for_write(&mut self, l1_index: usize) -> io::Result<u64> {
+        let l1_entry = self.l1_table[l1_index];
+        let l2_offset = l1_entry & L1_OFFSET_MASK;
+        if l2_offset != 0 {
+            if l1_entry & COPIED_FLAG == 0 {
+                return Err(shared_cluster_error());
+            }
+            return Ok(l2_offset);
+        }
+
+        let l2_offset = self.allocate_cluster()?;
+        let l2_table = vec![0u64; self.l2_entries() as usize];
+        self.cache_l2_table(l2_offset, l2_table);
+        self.set_l1_entry(l1_index, l2_offset | COPIED_FLAG)?;
+        Ok(l2_offset)
+    }
+
+    // Returns the offset in the file of the cluster holding `guest_offset`, allocating the
+    // cluster if it is not in the file yet.
+    fn cluster_for_write(&mut self, guest_offset: u64) -> io::Result<u64> {
+        self.clear_autoclear_features()?;
+        let (l1_index, l2_index) = self.table_indexes(guest_offset);
+        let l2_offset = self.l2_table_for_write(l1_index)?;
+        let entry = self.l2_entry(l2_offset, l2_index)?;
+        if entry & COMPRESSED_FLAG != 0 {
+            return Err(compressed_cluster_error());
+        }
+        let is_zero = self.version >= 3 && entry & ZERO_FLAG != 0;
+        let cluster_offset = entry & L2_OFFSET_MASK;
+        if cluster_offset != 0 && entry & COPIED_FLAG == 0 {
+            return Err(shared_cluster_error());
+        }
+        if cluster_offset != 0 && !is_zero {
+            return Ok(cluster_offset);
+        }
+
+        // The parts of the cluster the write does not cover have to keep reading as before.
+        let mut data = vec![0u8; self.cluster_size as usize];
+        if !is_zero {
+            let cluster_start = guest_offset - self.cluster_offset(guest_offset);
+            self.read_backing_file(cluster_start, &mut data)?;
+        }
+        // Zero clusters may keep their old cluster, with stale data.
+        let reused = cluster_offset != 0;
+        let cluster_offset = if reused {
+            cluster_offset
+        } else {
+            self.allocate_cluster()?
+        };
+        if reused || data.iter().any(|&b| b != 0) {
+            self.file.seek(SeekFrom::Start(cluster_offset))?;
+            self.file.write_all(&data)?;
+        }
+        self.set_l2_entry(l2_offset, l2_index, cluster_offset | COPIED_FLAG)?;
+        Ok(cluster_offset)
+    }
+
+    // Makes the cluster at `guest_offset` read as zeroes, freeing it when possible.
+    fn deallocate_cluster(&mut self, guest_offset: u64) -> io::Result<()> {
+        if self.backing_file.is_some() && self.version < 3 {
+            // Without the zero flag, unallocated clusters would read from the backing file.
+            return self.zero_range(guest_offset, self.cluster_size);
+        }
+        let (l1_index, l2_index) = self.table_indexes(guest_offset);
+        if self.backing_file.is_none() && self.l1_table[l1_index] & L1_OFFSET_MASK == 0 {
+            return Ok(());
+        }
+
+        self.clear_autoclear_features()?;
+        let l2_offset = self.l2_table_for_write(l1_index)?;
+        let entry = self.l2_entry(l2_offset, l2_index)?;
+        let cluster_offset = entry & L2_OFFSET_MASK;
+        if entry & COMPRESSED_FLAG != 0 {
+            return Err(compressed_cluster_error());
+        }
+        if cluster_offset != 0 && entry & COPIED_FLAG == 0 {
+            return Err(shared_cluster_error());
+        }
+        let new_entry = if self.backing_file.is_some() {
+            ZERO_FLAG
+        } else {
+            0
+        };
+        if entry == new_entry {
+            return Ok(());
+        }
+
+        self.set_l2_entry(l2_offset, l2_index, new_entry)?;
+        if cluster_offset != 0 {
+            self.set_refcount(cluster_offset, 0)?;
+            self.file.punch_hole(cluster_offset, self.cluster_size)?;
+        }
+        Ok(())
+    }
+
+    // Zeroes `length` bytes at `guest_offset`, within a single cluster.
+    fn zero_range(&mut self, guest_offset: u64, length: u64) -> io::Result<()> {
+        match self.locate(guest_offset)? {
+            ClusterLocation::Zero => return Ok(()),
+            ClusterLocation::Unallocated if self.backing_file.is_none() => return Ok(()),
+            _ => {}
+        }
+        let cluster_offset = self.cluster_for_write(guest_offset)?;
+        self.file.seek(SeekFrom::Start(
+            cluster_offset + self.cluster_offset(guest_offset),
+        ))?;
+        self.file.write_all(&vec![0u8; length as usize])
+    }
+
+    // Calls `f` for each part of the `length` bytes at `offset` within a single cluster, and
+    // within the disk, with the guest offset and the length of the part.
+    fn for_each_cluster<F>(&mut self, offset: u64, length: u64, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&mut QcowFile, u64, u64) -> io::Result<()>,
+    {
+        let end = cmp::min(offset.saturating_add(length), self.virtual_size);
+        let mut guest_offset = offset;
+        while guest_offset < end {
+            let len = cmp::min(
+                self.cluster_size - self.cluster_offset(guest_offset),
+                end - guest_offset,
+            );
+            f(self, guest_offset, len)?;
+            guest_offset += len;
+        }
+        Ok(())
+    }
+
+    // Returns the length of the next part of a transfer of `len` bytes at the current position,
+    // within a single cluster and within the disk.
+    fn chunk_len(&self, len: usize) -> usize {
+        cmp::min(
+            len as u64,
+            cmp::min(
+                self.cluster_size - self.cluster_offset(self.position),
+                self.virtual_size - self.position,
+            ),
+        ) as usize
+    }
+}
+
+impl Read for QcowFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() && self.position < self.virtual_size {
+            let len = self.chunk_len(buf.len() - done);
+            let guest_offset = self.position;
+            let dst = &mut buf[done..done + len];
+            match self.locate(guest_offset)? {
+                ClusterLocation::Data(cluster_offset) => {
+                    self.file.seek(SeekFrom::Start(
+                        cluster_offset + self.cluster_offset(guest_offset),
+                    ))?;
+                    self.file.read_exact(dst)?;
+                }
+                ClusterLocation::Zero => dst.fill(0),
+                ClusterLocation::Unallocated => self.read_backing_file(guest_offset, dst)?,
+            }
+            self.position += len as u64;
+            done += len;
+        }
+        Ok(done)
+    }
+}
+
+impl Write for QcowFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() && self.position < self.virtual_size {
+            let len = self.chunk_len(buf.len() - done);
+            let guest_offset = self.position;
+            let cluster_offset = self.cluster_for_write(guest_offset)?;
+            self.file.seek(SeekFrom::Start(
+                cluster_offset + self.cluster_offset(guest_offset),
+            ))?;
+            self.file.write_all(&buf[done..done + len])?;
+            self.position += len as u64;
+            done += len;
+        }
+        Ok(done)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for QcowFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(self.position, self.virtual_size, pos)?;
+        Ok(self.position)
+    }
+}
+
+impl PunchHole for QcowFile {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        let virtual_size = self.virtual_size;
+        self.for_each_cluster(offset, length, |qcow, guest_offset, len| {
+            // The last cluster can be partly past the end of the disk.
+            if qcow.cluster_offset(guest_offset) == 0
+                && (len == qcow.cluster_size || guest_offset + len == virtual_size)
+            {
+                qcow.deallocate_cluster(guest_offset)
+            } else {
+                qcow.zero_range(guest_offset, len)
+            }
+        })
+    }
+}
+
+impl WriteZeroes for QcowFile {
+    fn write_zeroes(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.for_each_cluster(offset, length, |qcow, guest_offset, len| {
+            qcow.zero_range(guest_offset, len)
+        })
+    }
+}
+
+impl DiskFile for QcowFile {
+    fn image_file(&self) -> &File {
+        &self.file
+    }
+
+    fn is_raw(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::{tempdir, tempfile};
+    use super::*;
+
+    // Cluster size of the test images, small enough to cover the tables with little data.
+    const CLUSTER_BITS: u32 = 9;
+    const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+    // Where the test images keep their backing file name.
+    const BACKING_FILE_NAME_OFFSET: u64 = 0x100;
+
+    // Writes a new empty image of `virtual_size` bytes, with the L1 table right after the
+    // header, followed by a single cluster refcount table and block.
+    fn create_image(file: &mut File, version: u32, virtual_size: u64, backing_file: &str) {
+        let l1_entry_coverage = CLUSTER_SIZE * (CLUSTER_SIZE / 8);
+        let l1_size = (virtual_size + l1_entry_coverage - 1) / l1_entry_coverage;
+        let l1_clusters = (l1_size * 8 + CLUSTER_SIZE - 1) / CLUSTER_SIZE;
+        let l1_table_offset = CLUSTER_SIZE;
+        let refcount_table_offset = l1_table_offset + l1_clusters * CLUSTER_SIZE;
+        let refcount_block_offset = refcount_table_offset + CLUSTER_SIZE;
+        let clusters = refcount_block_offset / CLUSTER_SIZE + 1;
+
+        let mut header = vec![0u8; CLUSTER_SIZE as usize];
+        BigEndian::write_u32(&mut header[0..], QCOW_MAGIC);
+        BigEndian::write_u32(&mut header[4..], version);
+        if !backing_file.is_empty() {
+            BigEndian::write_u64(&mut header[8..], BACKING_FILE_NAME_OFFSET);
+            BigEndian::write_u32(&mut header[16..], backing_file.len() as u32);
+            let name_start = BACKING_FILE_NAME_OFFSET as usize;
+            header[name_start..name_start + backing_file.len()]
+                .copy_from_slice(backing_file.as_bytes());
+        }
+        BigEndian::write_u32(&mut header[20..], CLUSTER_BITS);
+        BigEndian::write_u64(&mut header[24..], virtual_size);
+        BigEndian::write_u32(&mut header[36..], l1_size as u32);
+        BigEndian::write_u64(&mut header[40..], l1_table_offset);
+        BigEndian::write_u64(&mut header[48..], refcount_table_offset);
+        BigEndian::write_u32(&mut header[56..], 1);
+        if version == 3 {
+            BigEndian::write_u32(&mut header[96..], REFCOUNT_ORDER);
+            BigEndian::write_u32(&mut header[100..], V3_HEADER_SIZE as u32);
+        }
+
+        file.set_len(0).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&header).unwrap();
+        file.set_len(clusters * CLUSTER_SIZE).unwrap();
+        write_u64_at(file, refcount_table_offset, refcount_block_offset).unwrap();
+        for i in 0..clusters {
+            file.seek(SeekFrom::Start(refcount_block_offset + i * 2))
+                .unwrap();
+            file.write_u16::<BigEndian>(1).unwrap();
+        }
+    }
+
+    fn create_file(path: &Path) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap()
+    }
+
+    fn new_image(version: u32, virtual_size: u64) -> QcowFile {
+        let mut file = tempfile().unwrap();
+        create_image(&mut file, version, virtual_size, "");
+        QcowFile::open(file, Path::new("")).unwrap()
+    }
+
+    fn reopen(qcow: QcowFile) -> QcowFile {
+        QcowFile::open(qcow.file, Path::new("")).unwrap()
+    }
+
+    fn read_at(disk: &mut DiskFile, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        disk.seek(SeekFrom::Start(offset)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn write_at(disk: &mut DiskFile, offset: u64, data: &[u8]) {
+        disk.seek(SeekFrom::Start(offset)).unwrap();
+        disk.write_all(data).unwrap();
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8 + 1).collect()
+    }
+
+    impl QcowFile {
+        fn refcount(&mut self, cluster_offset: u64) -> u16 {
+            let cluster = cluster_offset >> self.cluster_bits;
+            let block_offset = self.refcount_table
+                [(cluster / self.refcount_block_entries()) as usize]
+                & REFCOUNT_TABLE_OFFSET_MASK;
+            if block_offset == 0 {
+                return 0;
+            }
+            self.file
+                .seek(SeekFrom::Start(
+                    block_offset + (cluster % self.refcount_block_entries()) * 2,
+                ))
+                .unwrap();
+            self.file.read_u16::<BigEndian>().unwrap()
+        }
+
+        // Returns the clusters the tables point to.
+        fn referenced_clusters(&mut self) -> Vec<u64> {
+            let mut clusters = Vec::new();
+            for l1_entry in self.l1_table.clone() {
+                let l2_offset = l1_entry & L1_OFFSET_MASK;
+                if l2_offset == 0 {
+                    continue;
+                }
+                clusters.push(l2_offset);
+                for i in 0..self.l2_entries() as usize {
+                    let cluster_offset = self.l2_entry(l2_offset, i).unwrap() & L2_OFFSET_MASK;
+                    if cluster_offset != 0 {
+                        clusters.push(cluster_offset);
+                    }
+                }
+            }
+            clusters.extend(self.refcount_table.iter().filter(|&&offset| offset != 0));
+            clusters
+        }
+
+        fn check_refcounts(&mut self) {
+            for cluster_offset in self.referenced_clusters() {
+                assert_eq!(self.refcount(cluster_offset), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_detect_image_format() {
+        let mut file = tempfile().unwrap();
+        assert_eq!(detect_image_format(&mut file).unwrap(), ImageFormat::Raw);
+        file.write_all(&[0xffu8; 0x1000]).unwrap();
+        assert_eq!(detect_image_format(&mut file).unwrap(), ImageFormat::Raw);
+        create_image(&mut file, 3, 0x10000, "");
+        assert_eq!(detect_image_format(&mut file).unwrap(), ImageFormat::Qcow2);
+        assert_eq!(file.seek(SeekFrom::Current(0)).unwrap(), 0);
+
+        // Raw images open as they are.
+        let disk = super::super::open_disk_image(file, Path::new(""), Some(ImageFormat::Raw));
+        assert!(disk.unwrap().is_raw());
+        let mut raw = tempfile().unwrap();
+        raw.write_all(&[0u8; 0x1000]).unwrap();
+        match super::super::open_disk_image(raw, Path::new(""), Some(ImageFormat::Qcow2)) {
+            Err(Error::InvalidMagic) => {}
+            _ => panic!("Expected an invalid magic error."),
+        }
+    }
+
+    #[test]
+    fn test_read_write() {
+        for &version in &[2, 3] {
+            let virtual_size = 0x10_0000;
+            let mut qcow = new_image(version, virtual_size);
+            assert_eq!(qcow.seek(SeekFrom::End(0)).unwrap(), virtual_size);
+            assert_eq!(read_at(&mut qcow, 0, 0x1000), vec![0u8; 0x1000]);
+
+            // The data spans partial clusters on both ends, and L2 tables.
+            let data = pattern(0x9000);
+            let offset = 0x7f00;
+            write_at(&mut qcow, offset, &data);
+            assert_eq!(read_at(&mut qcow, offset, data.len()), data);
+            assert_eq!(read_at(&mut qcow, offset - 0x100, 0x100), vec![0u8; 0x100]);
+            write_at(&mut qcow, virtual_size - 0x10, &[0xaa; 0x10]);
+            assert_eq!(
+                read_at(&mut qcow, virtual_size - 0x10, 0x10),
+                vec![0xaa; 0x10]
+            );
+            qcow.check_refcounts();
+
+            // Nothing goes past the end of the disk.
+            let mut buf = [0u8; 0x20];
+            qcow.seek(SeekFrom::Start(virtual_size - 0x10)).unwrap();
+            assert_eq!(qcow.read(&mut buf).unwrap(), 0x10);
+            assert_eq!(qcow.write(&buf).unwrap(), 0);
+            assert!(qcow.seek(SeekFrom::Current(-0x20_0000)).is_err());
+
+            let mut qcow = reopen(qcow);
+            assert_eq!(read_at(&mut qcow, offset, data.len()), data);
+            assert_eq!(
+                read_at(&mut qcow, virtual_size - 0x10, 0x10),
+                vec![0xaa; 0x10]
+            );
+            // Rewriting allocated clusters does not allocate more.
+            let file_size = qcow.file.metadata().unwrap().len();
+            write_at(&mut qcow, offset, &vec![0x55; data.len()]);
+            assert_eq!(qcow.file.metadata().unwrap().len(), file_size);
+            assert_eq!(
+                read_at(&mut qcow, offset, data.len()),
+                vec![0x55; data.len()]
+            );
+        }
+    }
+
+    #[test]
+    fn test_refcount_table_growth() {
+        // A refcount table cluster covers 64 refcount blocks of 256 clusters, 8 MiB in all.
+        let virtual_size = 0x90_0000;
+        let mut qcow = new_image(3, virtual_size);
+        let old_table_offset = qcow.refcount_table_offset;
+        let data = pattern(virtual_size as usize);
+        write_at(&mut qcow, 0, &data);
+        assert!(qcow.refcount_table_offset > old_table_offset);
+        assert!(qcow.refcount_table.len() > (CLUSTER_SIZE / 8) as usize);
+        assert_eq!(qcow.refcount(old_table_offset), 0);
+        qcow.check_refcounts();
+
+        let mut qcow = reopen(qcow);
+        assert!(qcow.refcount_table_offset > old_table_offset);
+        assert_eq!(read_at(&mut qcow, 0, data.len()), data);
+        qcow.check_refcounts();
+    }
+
+    #[test]
+    fn test_backing_files() {
+        let dir = tempdir().unwrap();
+        let base_path = dir.path().join("base.raw");
+        let middle_path = dir.path().join("middle.qcow2");
+        let top_path = dir.path().join("top.qcow2");
+
+        // The raw base is smaller than the images on top of it.
+        let mut base = create_file(&base_path);
+        base.write_all(&[0xaa; 0x4000]).unwrap();
+        let mut middle = create_file(&middle_path);
+        create_image(&mut middle, 2, 0x10000, "base.raw");
+        let mut middle = QcowFile::open(middle, &middle_path).unwrap();
+        write_at(&mut middle, 0x1000, &[0xbb; 0x100]);
+
+        let mut top_file = create_file(&top_path);
+        create_image(&mut top_file, 3, 0x10000, "middle.qcow2");
+        let mut top = super::super::open_disk_image(top_file, &top_path, None).unwrap();
+        assert!(!top.is_raw());
+
+        assert_eq!(read_at(&mut *top, 0, 0x1000), vec![0xaa; 0x1000]);
+        assert_eq!(read_at(&mut *top, 0x1000, 0x100), vec![0xbb; 0x100]);
+        assert_eq!(
+            read_at(&mut *top, 0x3f00, 0x200)[..0x100],
+            [0xaa; 0x100][..]
+        );
+        assert_eq!(read_at(&mut *top, 0x3f00, 0x200)[0x100..], [0u8; 0x100][..]);
+
+        // Writes fill the rest of the cluster from the backing files, and leave them alone.
+        write_at(&mut *top, 0x1080, &[0xcc; 0x10]);
+        let mut expected = vec![0xbb; 0x100];
+        for byte in &mut expected[0x80..0x90] {
+            *byte = 0xcc;
+        }
+        assert_eq!(read_at(&mut *top, 0x1000, 0x100), expected);
+        assert_eq!(read_at(&mut middle, 0x1000, 0x100), vec![0xbb; 0x100]);
+        let mut contents = Vec::new();
+        File::open(&base_path)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, vec![0xaa; 0x4000]);
+
+        // Discarded clusters read as zeroes instead of the backing file.
+        top.punch_hole(0, CLUSTER_SIZE * 2 + 0x10).unwrap();
+        assert_eq!(read_at(&mut *top, 0, 0x410), vec![0u8; 0x410]);
+        assert_eq!(read_at(&mut *top, 0x410, 0x10), vec![0xaa; 0x10]);
+        top.write_zeroes(0x1000, 0x100).unwrap();
+        assert_eq!(read_at(&mut *top, 0x1000, 0x100), vec![0u8; 0x100]);
+        // Version 2 images have no zero clusters, so the zeroes are written.
+        middle.punch_hole(0, CLUSTER_SIZE).unwrap();
+        assert_eq!(read_at(&mut middle, 0, 0x400), {
+            let mut expected = vec![0u8; 0x200];
+            expected.extend_from_slice(&[0xaa; 0x200]);
+            expected
+        });
+
+        // A backing file cannot be outside the directory of the image.
+        for name in &[
+            base_path.to_str().unwrap(),
+            "../base.raw",
+            "sub/../../base.raw",
+        ] {
+            let mut escaping = tempfile().unwrap();
+            create_image(&mut escaping, 3, 0x10000, name);
+            match QcowFile::open(escaping, &top_path) {
+                Err(Error::InvalidBackingFileName) => {}
+                _ => panic!("Expected an invalid backing file name error."),
+            }
+        }
+
+        // A backing file cannot be missing, or back itself.
+        let mut orphan = tempfile().unwrap();
+        create_image(&mut orphan, 3, 0x10000, "missing.qcow2");
+        match QcowFile::open(orphan, &dir.path().join("orphan.qcow2")) {
+            Err(Error::BackingFile(ref path, _)) if *path == dir.path().join("missing.qcow2") => {}
+            _ => panic!("Expected a backing file error."),
+        }
+        let mut looped = create_file(&top_path);
+        create_image(&mut looped, 3, 0x10000, "top.qcow2");
+        let mut e = QcowFile::open(looped, &top_path).err().unwrap();
+        while let Error::BackingFile(_, inner) = e {
+            e = *inner;
+        }
+        match e {
+            Error::BackingChainTooDeep => {}
+            _ => panic!("Expected a backing chain error."),
+        }
+    }
+
+    #[test]
+    fn test_discard() {
+        let mut qcow = new_image(3, 0x10000);
+        write_at(&mut qcow, 0, &pattern(0x1000));
+        let (_, l2_index) = qcow.table_indexes(CLUSTER_SIZE);
+        let l2_offset = qcow.l1_table[0] & L1_OFFSET_MASK;
+        let cluster_offset = qcow.l2_entry(l2_offset, l2_index).unwrap() & L2_OFFSET_MASK;
+
+        // Whole clusters are freed, partial ones are zeroed.
+        qcow.punch_hole(CLUSTER_SIZE - 0x10, CLUSTER_SIZE + 0x20)
+            .unwrap();
+        assert_eq!(qcow.l2_entry(l2_offset, l2_index).unwrap(), 0);
+        assert_eq!(qcow.refcount(cluster_offset), 0);
+        assert_eq!(
+            read_at(&mut qcow, CLUSTER_SIZE - 0x10, CLUSTER_SIZE as usize + 0x20),
+            vec![0u8; CLUSTER_SIZE as usize + 0x20]
+        );
+        assert_eq!(read_at(&mut qcow, 0, 0x10), pattern(0x10));
+        qcow.check_refcounts();
+
+        // Zeroing unallocated clusters allocates nothing.
+        let file_size = qcow.file.metadata().unwrap().len();
+        qcow.write_zeroes(0x2000, 0x4000).unwrap();
+        qcow.punch_hole(0x2000, 0x4000).unwrap();
+        assert_eq!(qcow.file.metadata().unwrap().len(), file_size);
+    }
+
+    #[test]
+    fn test_unsupported_images() {
+        fn open_error(edit: &Fn(&mut File)) -> Error {
+            let mut file = tempfile().unwrap();
+            create_image(&mut file, 3, 0x10000, "");
+            edit(&mut file);
+            QcowFile::open(file, Path::new("")).err().unwrap()
+        }
+        fn write_u32_at(file: &mut File, offset: u64, value: u32) {
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_u32::<BigEndian>(value).unwrap();
+        }
+
+        match open_error(&|file: &mut File| write_u32_at(file, 4, 1)) {
+            Error::UnsupportedVersion(1) => {}
+            e => panic!("Unexpected error: {}", e),
+        }
+        match open_error(&|file: &mut File| write_u32_at(file, 32, 1)) {
+            Error::EncryptedImage => {}
+            e => panic!("Unexpected error: {}", e),
+        }
+        match open_error(&|file: &mut File| write_u64_at(file, 72, 1).unwrap()) {
+            Error::UnsupportedFeatures(1) => {}
+            e => panic!("Unexpected error: {}", e),
+        }
+        match open_error(&|file: &mut File| write_u32_at(file, 96, 5)) {
+            Error::UnsupportedRefcountOrder(5) => {}
+            e => panic!("Unexpected error: {}", e),
+        }
+        match open_error(&|file: &mut File| write_u32_at(file, 20, 30)) {
+            Error::InvalidClusterSize(30) => {}
+            e => panic!("Unexpected error: {}", e),
+        }
+        match open_error(&|file: &mut File| write_u32_at(file, 36, 0)) {
+            Error::InvalidL1Table => {}
+            e => panic!("Unexpected error: {}", e),
+        }
+
+        // Point the first L1 entry to an L2 table with a compressed cluster.
+        let compressed = |file: &mut File| {
+            let l2_offset = file.seek(SeekFrom::End(0)).unwrap();
+            file.set_len(l2_offset + CLUSTER_SIZE).unwrap();
+            write_u64_at(file, l2_offset, COMPRESSED_FLAG | 0x1000).unwrap();
+            write_u64_at(file, CLUSTER_SIZE, l2_offset | COPIED_FLAG).unwrap();
+        };
+        match open_error(&compressed) {
+            Error::CompressedClusters => {}
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_shared_clusters() {
+        let mut qcow = new_image(3, 0x10000);
+        write_at(&mut qcow, 0, &pattern(0x100));
+        // Clusters without the copied flag are also referenced by internal snapshots.
+        let l2_offset = qcow.l1_table[0] & L1_OFFSET_MASK;
+        let entry = qcow.l2_entry(l2_offset, 0).unwrap();
+        qcow.set_l2_entry(l2_offset, 0, entry & !COPIED_FLAG)
+            .unwrap();
+
+        assert_eq!(read_at(&mut qcow, 0, 0x100), pattern(0x100));
+        qcow.seek(SeekFrom::Start(0)).unwrap();
+        assert!(qcow.write_all(&[0u8; 0x10]).is_err());
+        assert!(qcow.punch_hole(0, CLUSTER_SIZE).is_err());
+        // The other clusters can still be written.
+        write_at(&mut qcow, CLUSTER_SIZE, &[0xaa; 0x10]);
+    }
+}
@@ -0,0 +1,535 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Verifies the blocks read from a read-only disk image against a Merkle hash tree, in the
+//! format of dm-verity, so that a disk image tampered with on the host is not fed to the guest.
+//!
+//! The SHA-256 hashes of the data blocks, each salted, fill the hash blocks of the lowest level
+//! of the tree. The hashes of those hash blocks fill the level above, and so on up to a single
+//! hash block, whose hash is the root hash. The hash tree file holds the levels from the top one
+//! down, each starting on a hash block, after the superblock `veritysetup format` writes, unless
+//! it was given `--no-superblock`.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::result;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::disk::{seek_position, DiskFile};
+use super::sha256::{Sha256, DIGEST_SIZE};
+use logger::{Metric, METRICS};
+use sys_util::{PunchHole, WriteZeroes};
+
+/// The size of both the data blocks and the hash blocks.
+pub const VERITY_BLOCK_SIZE: u64 = 4096;
+// The number of hashes in a hash block, as a power of 2.
+const HASHES_PER_BLOCK_SHIFT: u32 = 7;
+const MAX_SALT_SIZE: usize = 256;
+
+const SUPERBLOCK_SIGNATURE: &[u8] = b"verity\0\0";
+const SUPERBLOCK_VERSION: u32 = 1;
+// The hashes are salted at the start of the hashed block.
+const HASH_TYPE: u32 = 1;
+const ALGORITHM_OFFSET: usize = 32;
+const ALGORITHM_LEN: usize = 32;
+const DATA_BLOCK_SIZE_OFFSET: usize = 64;
+const HASH_BLOCK_SIZE_OFFSET: usize = 68;
+const DATA_BLOCKS_OFFSET: usize = 72;
+const SALT_SIZE_OFFSET: usize = 80;
+const SALT_OFFSET: usize = 88;
+
+/// Errors setting up the verification of a disk image.
+#[derive(Debug)]
+pub enum Error {
+    /// The size of the disk image is not a multiple of the block size.
+    DiskSize(u64),
+    /// The hash tree file is too small for the disk image.
+    HashTreeSize,
+    /// The superblock of the hash tree is not valid, or uses unsupported parameters.
+    InvalidSuperblock,
+    /// The root hash is not a SHA-256 digest, or the salt is too long.
+    InvalidRootHash,
+    /// Reading the hash tree, or sizing the disk image, failed.
+    Io(io::Error),
+    /// Cannot open the hash tree file.
+    OpenHashTree(io::Error),
+    /// The hash tree does not match the root hash.
+    RootHashMismatch,
+    /// The salt does not match the one in the superblock of the hash tree.
+    SaltMismatch,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match *self {
+            DiskSize(size) => write!(
+                f,
+                "The disk image size {} is not a multiple of {} bytes.",
+                size, VERITY_BLOCK_SIZE
+            ),
+            HashTreeSize => write!(f, "The hash tree is too small for the disk image."),
+            InvalidSuperblock => write!(f, "The hash tree superblock is not valid."),
+            InvalidRootHash => write!(f, "The root hash is not a SHA-256 digest."),
+            Io(ref e) => write!(f, "Cannot read the hash tree: {}", e),
+            OpenHashTree(ref e) => write!(f, "Cannot open the hash tree file: {}", e),
+            RootHashMismatch => write!(f, "The hash tree does not match the root hash."),
+            SaltMismatch => write!(f, "The salt does not match the hash tree superblock."),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// A read-only disk, whose blocks are checked against a hash tree before being read.
+///
+/// The hash blocks are kept in memory once verified, so that the host cannot change them
+/// afterwards.
+pub struct VerityFile {
+    disk: Box<DiskFile>,
+    hash_tree: File,
+    disk_size: u64,
+    salt: Vec<u8>,
+    root_hash: [u8; DIGEST_SIZE],
+    // The offsets of the levels in the hash tree file, from the lowest one up.
+    level_offsets: Vec<u64>,
+    // The verified hash blocks, by their offset in the hash tree file.
+    verified: HashMap<u64, Vec<u8>>,
+    position: u64,
+}
+
+impl VerityFile {
+    /// Verifies the reads from `disk` against the hash tree file at `path`.
+    pub fn open(
+        disk: Box<DiskFile>,
+        path: &Path,
+        root_hash: &[u8],
+        salt: Option<&[u8]>,
+    ) -> Result<VerityFile> {
+        let hash_tree = File::open(path).map_err(Error::OpenHashTree)?;
+        VerityFile::new(disk, hash_tree, root_hash, salt)
+    }
+
+    /// Verifies the reads from `disk` against the hash tree in `hash_tree`, with `root_hash` as
+    /// the root hash. The `salt` of a hash tree with a superblock is read from the superblock,
+    /// so it does not have to be given.
+    pub fn new(
+        mut disk: Box<DiskFile>,
+        mut hash_tree: File,
+        root_hash: &[u8],
+        salt: Option<&[u8]>,
+    ) -> Result<VerityFile> {
+        if root_hash.len() != DIGEST_SIZE || salt.map_or(false, |salt| salt.len() > MAX_SALT_SIZE) {
+            return Err(Error::InvalidRootHash);
+        }
+        let disk_size = disk.seek(SeekFrom::End(0)).map_err(Error::Io)?;
+        if disk_size % VERITY_BLOCK_SIZE != 0 {
+            return Err(Error::DiskSize(disk_size));
+        }
+        let data_blocks = disk_size / VERITY_BLOCK_SIZE;
+
+        let mut superblock = [0u8; 512];
+        hash_tree.seek(SeekFrom::Start(0)).map_err(Error::Io)?;
+        let superblock_len = hash_tree.read(&mut superblock).map_err(Error::Io)?;
+        let (hash_start, salt) = if superblock_len == superblock.len()
+            && &superblock[..SUPERBLOCK_SIGNATURE.len()] == SUPERBLOCK_SIGNATURE
+        {
+            let tree_salt = parse_superblock(&superblock, data_blocks)?;
+            if salt.map_or(false, |salt| salt != &tree_salt[..]) {
+                return Err(Error::SaltMismatch);
+            }
+            (VERITY_BLOCK_SIZE, tree_salt)
+        } else {
+            (0, salt.unwrap_or(&[]).to_vec())
+        };
+
+        // Each level has a hash for each block of the level below. The levels are laid out
+        // from the top one down.
+        let mut level_blocks = Vec::new();
+        let mut blocks = data_blocks;
+        while blocks > 1 {
+            blocks = (blocks + (1 << HASHES_PER_BLOCK_SHIFT) - 1) >> HASHES_PER_BLOCK_SHIFT;
+            level_blocks.push(blocks);
+        }
+        let mut level_offsets = vec![0; level_blocks.len()];
+        let mut offset = hash_start;
+        for (level, blocks) in level_blocks.iter().enumerate().rev() {
+            level_offsets[level] = offset;
+            offset += blocks * VERITY_BLOCK_SIZE;
+        }
+        if hash_tree.metadata().map_err(Error::Io)?.len() < offset {
+            return Err(Error::HashTreeSize);
+        }
+
+        let mut verity = VerityFile {
+            disk,
+            hash_tree,
+            disk_size,
+            salt,
+            root_hash: [0u8; DIGEST_SIZE],
+            level_offsets,
+            verified: HashMap::new(),
+            position: 0,
+        };
+        verity.root_hash.copy_from_slice(root_hash);
+        // Check the top of the tree right away, so that a wrong root hash or hash tree is
+        // caught before the guest reads anything.
+        let top_level = verity.level_offsets.len();
+        if top_level > 0 {
+            verity
+                .hash_block(top_level - 1, 0)
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::InvalidData => Error::RootHashMismatch,
+                    _ => Error::Io(e),
+                })?;
+        }
+        Ok(verity)
+    }
+
+    fn hash(&self, block: &[u8]) -> [u8; DIGEST_SIZE] {
+        let mut sha256 = Sha256::new();
+        sha256.update(&self.salt);
+        sha256.update(block);
+        sha256.finish()
+    }
+
+    // Returns the hash a block is expected to have, given the index of its hash in `level`, or
+    // the root hash above the top level.
+    fn expected_hash(&mut self, level: usize, index: u64) -> io::Result<[u8; DIGEST_SIZE]> {
+        if level == self.level_offsets.len() {
+            return Ok(self.root_hash);
+        }
+        let hashes = self.hash_block(level, index >> HASHES_PER_BLOCK_SHIFT)?;
+        let offset = (index & ((1 << HASHES_PER_BLOCK_SHIFT) - 1)) as usize * DIGEST_SIZE;
+        let mut hash = [0u8; DIGEST_SIZE];
+        hash.copy_from_slice(&hashes[offset..offset + DIGEST_SIZE]);
+        Ok(hash)
+    }
+
+    // Returns the hash block at `index` in `level`, once verified up to the root hash.
+    fn hash_block(&mut self, level: usize, index: u64) -> io::Result<&[u8]> {
+        let offset = self.level_offsets[level] + index * VERITY_BLOCK_SIZE;
+        if !self.verified.contains_key(&offset) {
+            let mut block = vec![0u8; VERITY_BLOCK_SIZE as usize];
+            self.hash_tree.seek(SeekFrom::Start(offset))?;
+            self.hash_tree.read_exact(&mut block)?;
+            let expected = self.expected_hash(level + 1, index)?;
+            check_hash(self.hash(&block), expected)?;
+            self.verified.insert(offset, block);
+        }
+        Ok(&self.verified[&offset])
+    }
+
+    // Reads the data block at `index` into `block`, once verified.
+    fn read_block(&mut self, index: u64, block: &mut [u8]) -> io::Result<()> {
+        self.disk.seek(SeekFrom::Start(index * VERITY_BLOCK_SIZE))?;
+        self.disk.read_exact(block)?;
+        let expected = self.expected_hash(0, index)?;
+        check_hash(self.hash(block), expected)
+    }
+}
+
+// Checks the superblock of a hash tree for a disk of `data_blocks` blocks, and returns its salt.
+fn parse_superblock(superblock: &[u8], data_blocks: u64) -> Result<Vec<u8>> {
+    let algorithm = &superblock[ALGORITHM_OFFSET..ALGORITHM_OFFSET + ALGORITHM_LEN];
+    let salt_size = LittleEndian::read_u16(&superblock[SALT_SIZE_OFFSET..]) as usize;
+    if LittleEndian::read_u32(&superblock[8..]) != SUPERBLOCK_VERSION
+        || LittleEndian::read_u32(&superblock[12..]) != HASH_TYPE
+        || !algorithm.starts_with(b"sha256\0")
+        || u64::from(LittleEndian::read_u32(
+            &superblock[DATA_BLOCK_SIZE_OFFSET..],
+        )) != VERITY_BLOCK_SIZE
+        || u64::from(LittleEndian::read_u32(
+            &superblock[HASH_BLOCK_SIZE_OFFSET..],
+        )) != VERITY_BLOCK_SIZE
+        || LittleEndian::read_u64(&superblock[DATA_BLOCKS_OFFSET..]) != data_blocks
+        || salt_size > MAX_SALT_SIZE
+    {
+        return Err(Error::InvalidSuperblock);
+    }
+    Ok(superblock[SALT_OFFSET..SALT_OFFSET + salt_size].to_vec())
+}
+
+fn check_hash(hash: [u8; DIGEST_SIZE], expected: [u8; DIGEST_SIZE]) -> io::Result<()> {
+    if hash == expected {
+        Ok(())
+    } else {
+        METRICS.block.integrity_fails.inc();
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "block does not match the hash tree",
+        ))
+    }
+}
+
+fn read_only_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "verified disks are read-only",
+    )
+}
+
+impl Read for VerityFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut block = vec![0u8; VERITY_BLOCK_SIZE as usize];
+        let mut done = 0;
+        // Whole blocks are read and verified before any of their data is handed out.
+        while done < buf.len() && self.position < self.disk_size {
+            let index = self.position / VERITY_BLOCK_SIZE;
+            let block_offset = (self.position % VERITY_BLOCK_SIZE) as usize;
+            let len = cmp::min(buf.len() - done, VERITY_BLOCK_SIZE as usize - block_offset);
+            self.read_block(index, &mut block)?;
+            buf[done..done + len].copy_from_slice(&block[block_offset..block_offset + len]);
+            self.position += len as u64;
+            done += len;
+        }
+        Ok(done)
+    }
+}
+
+impl Write for VerityFile {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(read_only_error())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for VerityFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(self.position, self.disk_size, pos)?;
+        Ok(self.position)
+    }
+}
+
+impl PunchHole for VerityFile {
+    fn punch_hole(&mut self, _offset: u64, _length: u64) -> io::Result<()> {
+        Err(read_only_error())
+    }
+}
+
+impl WriteZeroes for VerityFile {
+    fn write_zeroes(&mut self, _offset: u64, _length: u64) -> io::Result<()> {
+        Err(read_only_error())
+    }
+}
+
+impl DiskFile for VerityFile {
+    fn image_file(&self) -> &File {
+        self.disk.image_file()
+    }
+
+    fn is_raw(&self) -> bool {
+        false
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.disk.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempfile;
+    use super::*;
+
+    // Builds the hash tree of `data`, in the layout of `veritysetup format --no-superblock`,
+    // and returns it along with the root hash.
+    fn build_hash_tree(data: &[u8], salt: &[u8]) -> (Vec<u8>, [u8; DIGEST_SIZE]) {
+        let hash = |block: &[u8]| {
+            let mut sha256 = Sha256::new();
+            sha256.update(salt);
+            sha256.update(block);
+            sha256.finish()
+        };
+        let mut levels = Vec::new();
+        let mut blocks: Vec<Vec<u8>> = data
+            .chunks(VERITY_BLOCK_SIZE as usize)
+            .map(|block| block.to_vec())
+            .collect();
+        while blocks.len() > 1 {
+            let mut level = Vec::new();
+            for block in &blocks {
+                level.extend_from_slice(&hash(block));
+            }
+            let len = (level.len() + VERITY_BLOCK_SIZE as usize - 1) / VERITY_BLOCK_SIZE as usize;
+            level.resize(len * VERITY_BLOCK_SIZE as usize, 0);
+            blocks = level
+                .chunks(VERITY_BLOCK_SIZE as usize)
+                .map(|block| block.to_vec())
+                .collect();
+            levels.push(level);
+        }
+        let root_hash = hash(&blocks[0]);
+        levels.reverse();
+        (levels.concat(), root_hash)
+    }
+
+    fn file_with(contents: &[u8]) -> File {
+        let mut file = tempfile().unwrap();
+        file.write_all(contents).unwrap();
+        file
+    }
+
+    // A disk of 200 blocks, which takes a two-level tree, each block filled with its index.
+    fn disk_contents() -> Vec<u8> {
+        (0..200u32)
+            .flat_map(|i| vec![i as u8; VERITY_BLOCK_SIZE as usize])
+            .collect()
+    }
+
+    #[test]
+    fn test_read() {
+        let data = disk_contents();
+        let salt = [0x5a; 32];
+        let (tree, root_hash) = build_hash_tree(&data, &salt);
+        assert_eq!(tree.len(), 3 * VERITY_BLOCK_SIZE as usize);
+        let mut disk = file_with(&data);
+        let mut verity = VerityFile::new(
+            Box::new(disk.try_clone().unwrap()),
+            file_with(&tree),
+            &root_hash,
+            Some(&salt),
+        )
+        .unwrap();
+        assert_eq!(verity.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+
+        // The read covers the end of a block and the start of the next one.
+        let mut buf = vec![0u8; 0x1000];
+        verity.seek(SeekFrom::Start(0x80f00)).unwrap();
+        verity.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[0x80f00..0x81f00]);
+        let mut buf = Vec::new();
+        verity.seek(SeekFrom::Start(0)).unwrap();
+        verity.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(
+            verity.write(&buf).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+
+        // A block changed on the host fails to read, without its data being handed out.
+        disk.seek(SeekFrom::Start(130 * VERITY_BLOCK_SIZE + 1))
+            .unwrap();
+        disk.write_all(&[0xff]).unwrap();
+        let mut buf = vec![0u8; 0x2000];
+        verity
+            .seek(SeekFrom::Start(129 * VERITY_BLOCK_SIZE))
+            .unwrap();
+        let fails = METRICS.block.integrity_fails.count();
+        assert_eq!(
+            verity.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(METRICS.block.integrity_fails.count() > fails);
+        assert!(buf[VERITY_BLOCK_SIZE as usize..]
+            .iter()
+            .all(|&byte| byte == 0));
+        // The other blocks still read fine.
+        verity
+            .seek(SeekFrom::Start(131 * VERITY_BLOCK_SIZE))
+            .unwrap();
+        verity.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[131 * 0x1000..133 * 0x1000]);
+    }
+
+    #[test]
+    fn test_open_errors() {
+        let data = disk_contents();
+        let (mut tree, root_hash) = build_hash_tree(&data, &[]);
+        let open = |data: &[u8], tree: &[u8], root_hash: &[u8], salt: Option<&[u8]>| {
+            VerityFile::new(Box::new(file_with(data)), file_with(tree), root_hash, salt)
+        };
+
+        match open(&data, &tree, &root_hash, None) {
+            Ok(verity) => assert!(verity.salt.is_empty()),
+            Err(e) => panic!("{}", e),
+        }
+        match open(&data, &tree, &root_hash, Some(&[1])) {
+            Err(Error::RootHashMismatch) => (),
+            _ => panic!("The salt is part of the hashes."),
+        }
+        match open(&data, &tree, &root_hash[1..], None) {
+            Err(Error::InvalidRootHash) => (),
+            _ => panic!("The root hash has to be a SHA-256 digest."),
+        }
+        match open(&data[1..], &tree, &root_hash, None) {
+            Err(Error::DiskSize(size)) => assert_eq!(size, data.len() as u64 - 1),
+            _ => panic!("The disk has to be made of whole blocks."),
+        }
+        match open(&data, &tree[..0x2000], &root_hash, None) {
+            Err(Error::HashTreeSize) => (),
+            _ => panic!("The hash tree has to cover the whole disk."),
+        }
+        tree[0] ^= 1;
+        match open(&data, &tree, &root_hash, None) {
+            Err(Error::RootHashMismatch) => (),
+            _ => panic!("The top of the hash tree has to match the root hash."),
+        }
+    }
+
+    #[test]
+    fn test_superblock() {
+        let data = disk_contents();
+        let salt = [0xa5; 16];
+        let (tree, root_hash) = build_hash_tree(&data, &salt);
+        let mut superblock = vec![0u8; VERITY_BLOCK_SIZE as usize];
+        superblock[..8].copy_from_slice(SUPERBLOCK_SIGNATURE);
+        LittleEndian::write_u32(&mut superblock[8..], SUPERBLOCK_VERSION);
+        LittleEndian::write_u32(&mut superblock[12..], HASH_TYPE);
+        superblock[ALGORITHM_OFFSET..ALGORITHM_OFFSET + 6].copy_from_slice(b"sha256");
+        LittleEndian::write_u32(&mut superblock[DATA_BLOCK_SIZE_OFFSET..], 4096);
+        LittleEndian::write_u32(&mut superblock[HASH_BLOCK_SIZE_OFFSET..], 4096);
+        LittleEndian::write_u64(&mut superblock[DATA_BLOCKS_OFFSET..], 200);
+        LittleEndian::write_u16(&mut superblock[SALT_SIZE_OFFSET..], salt.len() as u16);
+        superblock[SALT_OFFSET..SALT_OFFSET + salt.len()].copy_from_slice(&salt);
+        let tree_file = file_with(&[superblock.clone(), tree.clone()].concat());
+
+        // The salt comes from the superblock.
+        let mut verity = VerityFile::new(
+            Box::new(file_with(&data)),
+            tree_file.try_clone().unwrap(),
+            &root_hash,
+            None,
+        )
+        .unwrap();
+        let mut buf = vec![0u8; 0x1000];
+        verity
+            .seek(SeekFrom::Start(199 * VERITY_BLOCK_SIZE))
+            .unwrap();
+        verity.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, vec![199; 0x1000]);
+
+        match VerityFile::new(
+            Box::new(file_with(&data)),
+            tree_file,
+            &root_hash,
+            Some(&[0xa5; 8]),
+        ) {
+            Err(Error::SaltMismatch) => (),
+            _ => panic!("The salt has to match the superblock."),
+        }
+
+        // Hash trees of other algorithms are rejected.
+        superblock[ALGORITHM_OFFSET..ALGORITHM_OFFSET + 6].copy_from_slice(b"sha512");
+        match VerityFile::new(
+            Box::new(file_with(&data)),
+            file_with(&[superblock, tree].concat()),
+            &root_hash,
+            None,
+        ) {
+            Err(Error::InvalidSuperblock) => (),
+            _ => panic!("Only SHA-256 hash trees are supported."),
+        }
+    }
+}
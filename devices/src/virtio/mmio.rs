@@ -28,7 +28,8 @@ const MMIO_VERSION: u32 = 2;
 ///
 /// The lifecycle of a virtio device is to be moved to a virtio transport, which will then query the
 /// device. Once the guest driver has configured the device, `VirtioDevice::activate` will be called
-/// and all the events, memory, and queues for device operation will be moved into the device.
+/// and all the events, memory, and queues for device operation will be moved into the device. The
+/// transport keeps its own handles to the queue events.
 /// Optionally, a virtio device can implement device reset in which it returns said resources and
 /// resets its internal.
 pub trait VirtioDevice: Send {
@@ -38,6 +39,12 @@ pub trait VirtioDevice: Send {
     /// The maximum size of each queue that this device supports.
     fn queue_max_sizes(&self) -> &[u16];
 
+    /// The number of queues, out of `queue_max_sizes`, which the driver has to set up before the
+    /// device can be activated. The driver may leave the queues after them unused.
+    fn required_queues(&self) -> usize {
+        self.queue_max_sizes().len()
+    }
+
     /// The set of feature bits shifted by `page * 32`.
     fn features(&self, page: u32) -> u32 {
         let _ = page;
@@ -70,6 +77,31 @@ pub trait VirtioDevice: Send {
     }
 }
 
+/// The saved state of the MMIO transport of a virtio device, used for snapshotting the device.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct MmioDeviceState {
+    /// The virtio device type.
+    pub device_type: u32,
+    /// Whether the device was activated by the driver.
+    pub device_activated: bool,
+    /// The features page selected by the driver.
+    pub features_select: u32,
+    /// The acked features page selected by the driver.
+    pub acked_features_select: u32,
+    /// The queue selected by the driver.
+    pub queue_select: u32,
+    /// The pending interrupts.
+    pub interrupt_status: usize,
+    /// The status set by the driver.
+    pub driver_status: u32,
+    /// The configuration generation counter.
+    pub config_generation: u32,
+    /// The features acked by the driver.
+    pub acked_features: u64,
+    /// The state of each queue.
+    pub queues: Vec<QueueState>,
+}
+
 /// Implements the
 /// [MMIO](http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-1090002)
 /// transport for virtio devices.
@@ -95,6 +127,8 @@ pub struct MmioDevice {
     interrupt_evt: Option<EventFd>,
     driver_status: u32,
     config_generation: u32,
+    // Features acked by the driver, kept for replaying them when restoring the device.
+    acked_features: u64,
     queues: Vec<Queue>,
     queue_evts: Vec<EventFd>,
     mem: Option<GuestMemory>,
@@ -122,6 +156,7 @@ impl MmioDevice {
             interrupt_evt: Some(EventFd::new()?),
             driver_status: DEVICE_INIT,
             config_generation: 0,
+            acked_features: 0,
             queues,
             queue_evts,
             mem: Some(mem),
@@ -140,13 +175,141 @@ impl MmioDevice {
         self.interrupt_evt.as_ref()
     }
 
+    /// Saves the state of the MMIO transport. The device must not be processing its queues while
+    /// this is called.
+    pub fn save_state(&self) -> MmioDeviceState {
+        let queues = self
+            .queues
+            .iter()
+            .map(|queue| {
+                let mut state = queue.save_state();
+                // Once the device is activated it works on its own copies of the queues, so the
+                // ring indexes kept here are stale. The device adds a descriptor to the used ring
+                // as soon as it is done with it, hence both indexes match the used ring index
+                // published to the driver.
+                if self.device_activated {
+                    if let Some(used_idx) =
+                        self.mem.as_ref().and_then(|mem| queue.read_used_idx(mem))
+                    {
+                        state.next_avail = used_idx;
+                        state.next_used = used_idx;
+                    }
+                }
+                state
+            })
+            .collect();
+
+        MmioDeviceState {
+            device_type: self.device.device_type(),
+            device_activated: self.device_activated,
+            features_select: self.features_select,
+            acked_features_select: self.acked_features_select,
+            queue_select: self.queue_select,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+            driver_status: self.driver_status,
+            config_generation: self.config_generation,
+            acked_features: self.acked_features,
+            queues,
+        }
+    }
+
+    /// Restores the state of the MMIO transport on a freshly created device. If the device was
+    /// active when its state was saved, it gets activated again.
+    pub fn restore_state(&mut self, state: &MmioDeviceState) -> ActivateResult {
+        if self.device_activated
+            || state.device_type != self.device.device_type()
+            || state.queues.len() != self.queues.len()
+        {
+            return Err(ActivateError::BadActivate);
+        }
+
+        for page in 0..2 {
+            let value = (state.acked_features >> (32 * page)) as u32;
+            if value != 0 {
+                self.device.ack_features(page, value);
+            }
+        }
+        self.features_select = state.features_select;
+        self.acked_features_select = state.acked_features_select;
+        self.queue_select = state.queue_select;
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        self.driver_status = state.driver_status;
+        self.config_generation = state.config_generation;
+        self.acked_features = state.acked_features;
+        self.queues = state.queues.iter().map(Queue::from_state).collect();
+
+        if state.device_activated {
+            // The driver may have made buffers available after the device last looked at its
+            // queues, so have the device check all of them once it is up.
+            for queue_evt in self.queue_evts.iter() {
+                if let Err(e) = queue_evt.write(1) {
+                    warn!("Failed to signal queue event on restore: {}", e);
+                }
+            }
+            let interrupt_evt = match self.interrupt_evt {
+                Some(ref evt) => evt.try_clone().map_err(ActivateError::EpollCtl)?,
+                None => return Err(ActivateError::BadActivate),
+            };
+            let mem = self.mem.clone().ok_or(ActivateError::BadActivate)?;
+            let queue_evts = self.clone_queue_evts().map_err(ActivateError::EpollCtl)?;
+            self.device.activate(
+                mem,
+                interrupt_evt,
+                self.interrupt_status.clone(),
+                self.queues.clone(),
+                queue_evts,
+            )?;
+            self.device_activated = true;
+        }
+        // Raise again the interrupts that the driver had not acknowledged yet. A spurious
+        // interrupt is harmless, a lost one could stall the driver.
+        if state.interrupt_status != 0 {
+            if let Some(ref interrupt_evt) = self.interrupt_evt {
+                if let Err(e) = interrupt_evt.write(1) {
+                    warn!("Failed to signal interrupt event on restore: {}", e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Puts the transport back into its initial state, with a fresh device behind it. This is
+    /// how the devices are reset when the guest reboots. If the previous device was activated,
+    /// its epoll handler must be dropped by its owner as well.
+    pub fn reset_device(&mut self, device: Box<VirtioDevice>) {
+        self.device = device;
+        self.device_activated = false;
+        self.reset();
+    }
+
+    /// Marks the device as broken, once its backend stopped working. The driver learns through a
+    /// configuration change interrupt that the device needs a reset.
+    pub fn set_device_needs_reset(&mut self) {
+        self.driver_status |= DEVICE_NEEDS_RESET;
+        if self.check_driver_status(DEVICE_DRIVER_OK, 0) {
+            self.interrupt(VIRTIO_MMIO_INT_CONFIG);
+        }
+    }
+
+    // The device gets its own handles to the queue events, so that the transport can hand them
+    // over again to a device which replaces it.
+    fn clone_queue_evts(&self) -> std::io::Result<Vec<EventFd>> {
+        self.queue_evts.iter().map(EventFd::try_clone).collect()
+    }
+
     fn check_driver_status(&self, set: u32, clr: u32) -> bool {
         self.driver_status & (set | clr) == set
     }
 
     fn are_queues_valid(&self) -> bool {
         if let Some(mem) = self.mem.as_ref() {
-            self.queues.iter().all(|q| q.is_valid(mem))
+            let required_queues = self.device.required_queues();
+            self.queues
+                .iter()
+                .enumerate()
+                .all(|(i, q)| (i >= required_queues && !q.ready) || q.is_valid(mem))
         } else {
             false
         }
@@ -192,6 +355,7 @@ impl MmioDevice {
         self.queue_select = 0;
         self.interrupt_status.store(0, Ordering::SeqCst);
         self.driver_status = 0;
+        self.acked_features = 0;
         // . Keep interrupt_evt and queue_evts as is. There may be pending
         //   notifications in those eventfds, but nothing will happen other
         //   than supurious wakeups.
@@ -236,7 +400,7 @@ impl MmioDevice {
                                     interrupt_evt.try_clone().expect("Failed to clone eventfd"),
                                     self.interrupt_status.clone(),
                                     self.queues.clone(),
-                                    self.queue_evts.split_off(0),
+                                    self.clone_queue_evts().expect("Failed to clone eventfd"),
                                 )
                                 .expect("Failed to activate device");
                             self.device_activated = true;
@@ -251,10 +415,8 @@ impl MmioDevice {
             _ if v == 0 => {
                 if self.device_activated {
                     match self.device.reset() {
-                        Some((_interrupt_evt, mut queue_evts)) => {
-                            self.device_activated = false;
-                            self.queue_evts.append(&mut queue_evts);
-                        }
+                        // The transport kept its own handles to the queue events.
+                        Some(_) => self.device_activated = false,
                         // Backend device driver doesn't support reset,
                         // just mark the device as FAILED.
                         None => {
@@ -333,6 +495,10 @@ impl BusDevice for MmioDevice {
                             .check_driver_status(DEVICE_DRIVER, DEVICE_FEATURES_OK | DEVICE_FAILED)
                         {
                             self.device.ack_features(self.acked_features_select, v);
+                            if self.acked_features_select < 2 {
+                                self.acked_features |=
+                                    u64::from(v) << (32 * self.acked_features_select);
+                            }
                         } else {
                             warn!(
                                 "ack virtio features in invalid state 0x{:x}",
@@ -399,6 +565,7 @@ mod tests {
     use std::sync::atomic::ATOMIC_USIZE_INIT;
 
     use super::*;
+    use memory_model::MemoryBacking;
 
     static DEVICE_RESET_ENABLED: AtomicUsize = ATOMIC_USIZE_INIT;
 
@@ -479,7 +646,7 @@ mod tests {
 
     #[test]
     fn test_new() {
-        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
         let mut dummy = DummyDevice::new();
         // Validate reset is no-op.
         assert!(dummy.reset().is_none());
@@ -521,7 +688,7 @@ mod tests {
 
     #[test]
     fn test_bus_device_read() {
-        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
         let mut d = MmioDevice::new(m, Box::new(DummyDevice::new())).unwrap();
 
         let mut buf = vec![0xff, 0, 0xfe, 0];
@@ -593,7 +760,7 @@ mod tests {
     #[test]
     #[allow(clippy::cyclomatic_complexity)]
     fn test_bus_device_write() {
-        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
 
         let dummy_box = Box::new(DummyDevice::new());
         let p = &dummy_box.acked_features as *const u32;
@@ -740,7 +907,7 @@ mod tests {
 
     #[test]
     fn test_bus_device_activate() {
-        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
         let mut d = MmioDevice::new(m, Box::new(DummyDevice::new())).unwrap();
 
         assert!(!d.are_queues_valid());
@@ -835,7 +1002,7 @@ mod tests {
 
     #[test]
     fn test_bus_device_reset() {
-        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
         let mut d = MmioDevice::new(m, Box::new(DummyDevice::new())).unwrap();
         let mut buf = vec![0; 4];
 
@@ -866,4 +1033,104 @@ mod tests {
         DEVICE_RESET_ENABLED.store(0, Ordering::SeqCst);
         activate_device(&mut d);
     }
+
+    #[test]
+    fn test_reset_device() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
+        let mut d = MmioDevice::new(m, Box::new(DummyDevice::new())).unwrap();
+        activate_device(&mut d);
+        assert_eq!(d.queue_evts().len(), 2);
+
+        d.reset_device(Box::new(DummyDevice::new()));
+        assert!(!d.device_activated);
+        assert_eq!(d.driver_status, DEVICE_INIT);
+        assert!(!d.are_queues_valid());
+        assert_eq!(d.queue_evts().len(), 2);
+
+        // The driver can bring up the new device.
+        activate_device(&mut d);
+    }
+
+    #[test]
+    fn test_set_device_needs_reset() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
+        let mut d = MmioDevice::new(m, Box::new(DummyDevice::new())).unwrap();
+        activate_device(&mut d);
+
+        d.set_device_needs_reset();
+        let mut buf = vec![0; 4];
+        d.read(0x70, &mut buf[..]);
+        assert_eq!(
+            LittleEndian::read_u32(&buf[..]),
+            DEVICE_ACKNOWLEDGE
+                | DEVICE_DRIVER
+                | DEVICE_FEATURES_OK
+                | DEVICE_DRIVER_OK
+                | DEVICE_NEEDS_RESET
+        );
+        assert_eq!(
+            d.interrupt_status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_CONFIG as usize
+        );
+        assert_eq!(d.interrupt_evt().unwrap().read().unwrap(), 1);
+
+        // The device is still broken after the driver resets it.
+        set_driver_status(&mut d, 0);
+        d.read(0x70, &mut buf[..]);
+        assert_ne!(LittleEndian::read_u32(&buf[..]) & DEVICE_FAILED, 0);
+    }
+
+    #[test]
+    fn test_save_restore_state() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
+        let mut d = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+
+        // A device which was not activated is restored as such.
+        let state = d.save_state();
+        assert!(!state.device_activated);
+        assert_eq!(state.queues.len(), 2);
+        let mut restored = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+        assert!(restored.restore_state(&state).is_ok());
+        assert!(!restored.device_activated);
+        assert_eq!(restored.save_state(), state);
+
+        // Ack the VIRTIO_F_VERSION_1 feature.
+        set_driver_status(&mut d, DEVICE_ACKNOWLEDGE);
+        set_driver_status(&mut d, DEVICE_ACKNOWLEDGE | DEVICE_DRIVER);
+        let mut buf = vec![0; 4];
+        LittleEndian::write_u32(&mut buf[..], 1);
+        d.write(0x24, &buf[..]);
+        d.write(0x20, &buf[..]);
+        activate_device(&mut d);
+        d.interrupt(VIRTIO_MMIO_INT_VRING);
+
+        // The device already used 5 descriptors of each queue.
+        m.write_obj_at_addr(5u16, GuestAddress(2)).unwrap();
+        let state = d.save_state();
+        assert!(state.device_activated);
+        assert_eq!(state.acked_features, 1 << 32);
+        assert_eq!(state.interrupt_status, VIRTIO_MMIO_INT_VRING as usize);
+        assert_eq!(state.queues[0].size, 16);
+        assert_eq!(state.queues[0].next_avail, 5);
+        assert_eq!(state.queues[0].next_used, 5);
+
+        let mut restored = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+        assert!(restored.restore_state(&state).is_ok());
+        assert!(restored.device_activated);
+        assert_eq!(restored.save_state(), state);
+        // The device got its own handles to the queue events.
+        assert_eq!(restored.queue_evts().len(), 2);
+
+        // An active device cannot be restored.
+        assert!(restored.restore_state(&state).is_err());
+
+        // The state of a different type of device cannot be restored.
+        let mut restored = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+        let mut bad_state = state.clone();
+        bad_state.device_type += 1;
+        assert!(restored.restore_state(&bad_state).is_err());
+        let mut bad_state = state.clone();
+        bad_state.queues.pop();
+        assert!(restored.restore_state(&bad_state).is_err());
+    }
 }
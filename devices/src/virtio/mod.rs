@@ -9,13 +9,14 @@
 use std;
 use std::io::Error as IOError;
 
+pub mod balloon;
 pub mod block;
 mod mmio;
 pub mod net;
 mod queue;
-#[cfg(feature = "vsock")]
 pub mod vhost;
 
+pub use self::balloon::*;
 pub use self::block::*;
 pub use self::mmio::*;
 pub use self::net::*;
@@ -30,11 +31,13 @@ const DEVICE_ACKNOWLEDGE: u32 = 0x01;
 const DEVICE_DRIVER: u32 = 0x02;
 const DEVICE_DRIVER_OK: u32 = 0x04;
 const DEVICE_FEATURES_OK: u32 = 0x08;
+const DEVICE_NEEDS_RESET: u32 = 0x40;
 const DEVICE_FAILED: u32 = 0x80;
 
 /// Types taken from linux/virtio_ids.h.
 const TYPE_NET: u32 = 1;
 const TYPE_BLOCK: u32 = 2;
+const TYPE_BALLOON: u32 = 5;
 
 /// Interrupt flags (re: interrupt status & acknowledge registers).
 /// See linux/virtio_mmio.h.
@@ -49,7 +52,6 @@ pub const NOTIFY_REG_OFFSET: u32 = 0x50;
 pub enum ActivateError {
     EpollCtl(IOError),
     BadActivate,
-    #[cfg(feature = "vsock")]
     BadVhostActivate(self::vhost::Error),
 }
 
@@ -617,6 +617,23 @@ impl EpollHandler for NetEpollHandler {
             }),
         }
     }
+
+    fn event_fds(&self) -> Vec<RawFd> {
+        let mut fds = vec![
+            self.tap.as_raw_fd(),
+            self.rx.queue_evt.as_raw_fd(),
+            self.tx.queue_evt.as_raw_fd(),
+        ];
+        for &rate_limiter_rawfd in &[
+            self.rx.rate_limiter.as_raw_fd(),
+            self.tx.rate_limiter.as_raw_fd(),
+        ] {
+            if rate_limiter_rawfd != -1 {
+                fds.push(rate_limiter_rawfd);
+            }
+        }
+        fds
+    }
 }
 
 pub struct EpollConfig {
@@ -959,7 +976,7 @@ mod tests {
     use libc;
 
     use super::*;
-    use memory_model::GuestAddress;
+    use memory_model::{GuestAddress, MemoryBacking};
     use virtio::queue::tests::*;
 
     use dumbo::pdu::{arp, ethernet};
@@ -1091,7 +1108,8 @@ mod tests {
     }
 
     fn activate_some_net(n: &mut Net, bad_qlen: bool, bad_evtlen: bool) -> ActivateResult {
-        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let interrupt_evt = EventFd::new().unwrap();
         let status = Arc::new(AtomicUsize::new(0));
 
@@ -1343,7 +1361,8 @@ mod tests {
 
     #[test]
     fn test_mmds_detour_and_injection() {
-        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
 
         let sha = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
@@ -1406,7 +1425,8 @@ mod tests {
 
     #[test]
     fn test_mac_spoofing_detection() {
-        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
 
         let guest_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
@@ -1475,7 +1495,8 @@ mod tests {
 
     #[test]
     fn test_handler_error_cases() {
-        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
 
         // RX rate limiter events should error since the limiter is not blocked.
@@ -1497,7 +1518,8 @@ mod tests {
 
     #[test]
     fn test_invalid_event_handler() {
-        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
 
         let bad_event = 1000;
@@ -1521,7 +1543,8 @@ mod tests {
         let test_mutators = TestMutators {
             tap_read_fail: true,
         };
-        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, test_mutators);
         let r = h.handle_event(RX_TAP_EVENT, 0, EpollHandlerPayload::Empty);
         match r {
@@ -1532,7 +1555,8 @@ mod tests {
 
     #[test]
     fn test_rx_rate_limited_event_handler() {
-        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
         let rl = RateLimiter::new(0, None, 0, 0, None, 0).unwrap();
         h.set_rx_rate_limiter(rl);
@@ -1545,7 +1569,8 @@ mod tests {
 
     #[test]
     fn test_tx_rate_limited_event_handler() {
-        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
         let rl = RateLimiter::new(0, None, 0, 0, None, 0).unwrap();
         h.set_tx_rate_limiter(rl);
@@ -1558,7 +1583,8 @@ mod tests {
 
     #[test]
     fn test_handler() {
-        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let (mut h, txq, rxq) = default_test_netepollhandler(&mem, TestMutators::default());
 
         let daddr = 0x2000;
@@ -1696,7 +1722,8 @@ mod tests {
             let test_mutators = TestMutators {
                 tap_read_fail: true,
             };
-            let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+            let mem =
+                GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
             let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, test_mutators);
 
             check_metric_after_block!(&METRICS.net.rx_fails, 1, h.process_rx());
@@ -1705,7 +1732,8 @@ mod tests {
 
     #[test]
     fn test_bandwidth_rate_limiter() {
-        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let (mut h, txq, rxq) = default_test_netepollhandler(&mem, TestMutators::default());
 
         let daddr = 0x2000;
@@ -1812,7 +1840,8 @@ mod tests {
 
     #[test]
     fn test_ops_rate_limiter() {
-        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let (mut h, txq, rxq) = default_test_netepollhandler(&mem, TestMutators::default());
 
         let daddr = 0x2000;
@@ -1927,7 +1956,8 @@ mod tests {
 
     #[test]
     fn test_patch_rate_limiters() {
-        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let (mut h, _, _) = default_test_netepollhandler(&mem, TestMutators::default());
 
         h.set_rx_rate_limiter(RateLimiter::new(10, None, 10, 2, None, 2).unwrap());
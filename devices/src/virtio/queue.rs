@@ -201,6 +201,27 @@ impl<'a, 'b> Iterator for AvailIter<'a, 'b> {
     }
 }
 
+/// The saved parameters of a virtio queue, used for snapshotting the queue.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct QueueState {
+    /// The maximal size in elements offered by the device.
+    pub max_size: u16,
+    /// The queue size in elements the driver selected.
+    pub size: u16,
+    /// Indicates if the queue is finished with configuration.
+    pub ready: bool,
+    /// Guest physical address of the descriptor table.
+    pub desc_table: u64,
+    /// Guest physical address of the available ring.
+    pub avail_ring: u64,
+    /// Guest physical address of the used ring.
+    pub used_ring: u64,
+    /// Index of the next available descriptor to be processed by the device.
+    pub next_avail: u16,
+    /// Index of the next used element to be written by the device.
+    pub next_used: u16,
+}
+
 #[derive(Clone)]
 /// A virtio queue's parameters.
 pub struct Queue {
@@ -369,6 +390,40 @@ impl Queue {
             .unwrap();
     }
 
+    /// Saves the parameters of this queue.
+    pub fn save_state(&self) -> QueueState {
+        QueueState {
+            max_size: self.max_size,
+            size: self.size,
+            ready: self.ready,
+            desc_table: self.desc_table.offset() as u64,
+            avail_ring: self.avail_ring.offset() as u64,
+            used_ring: self.used_ring.offset() as u64,
+            next_avail: self.next_avail.0,
+            next_used: self.next_used.0,
+        }
+    }
+
+    /// Constructs a virtio queue from previously saved parameters.
+    pub fn from_state(state: &QueueState) -> Queue {
+        Queue {
+            max_size: state.max_size,
+            size: state.size,
+            ready: state.ready,
+            desc_table: GuestAddress(state.desc_table as usize),
+            avail_ring: GuestAddress(state.avail_ring as usize),
+            used_ring: GuestAddress(state.used_ring as usize),
+            next_avail: Wrapping(state.next_avail),
+            next_used: Wrapping(state.next_used),
+        }
+    }
+
+    /// Reads the index of the used ring, as last published to the driver.
+    pub fn read_used_idx(&self, mem: &GuestMemory) -> Option<u16> {
+        let used_idx_addr = mem.checked_offset(self.used_ring, 2)?;
+        mem.read_obj_from_addr::<u16>(used_idx_addr).ok()
+    }
+
     /// Goes back one position in the available descriptor chain offered by the driver.
     /// Rust does not support bidirectional iterators. This is the only way to revert the effect
     /// of an iterator increment on the queue.
@@ -385,7 +440,7 @@ pub(crate) mod tests {
     use std::mem;
 
     pub use super::*;
-    use memory_model::{GuestAddress, GuestMemory};
+    use memory_model::{GuestAddress, GuestMemory, MemoryBacking};
 
     // Represents a location in GuestMemory which holds a given type.
     pub struct SomeplaceInMemory<'a, T> {
@@ -618,7 +673,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_checked_new_descriptor_chain() {
-        let m = &GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let m = &GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let vq = VirtQueue::new(GuestAddress(0), m, 16);
 
         assert!(vq.end().0 < 0x1000);
@@ -677,7 +732,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_queue_and_iterator() {
-        let m = &GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let m = &GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let vq = VirtQueue::new(GuestAddress(0), m, 16);
 
         let mut q = vq.create_queue();
@@ -782,7 +837,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_add_used() {
-        let m = &GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let m = &GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let vq = VirtQueue::new(GuestAddress(0), m, 16);
 
         let mut q = vq.create_queue();
@@ -9,7 +9,6 @@ use super::super::EpollHandlerPayload;
 use super::INTERRUPT_STATUS_USED_RING;
 
 use sys_util::EventFd;
-use vhost_backend::Vhost;
 use DeviceEventT;
 use EpollHandler;
 
@@ -26,18 +25,19 @@ pub const KILL_EVENT: DeviceEventT = 1;
 // VHOST_IRQ_AVAILABLE and KILL_EVENT. KILL_EVENT is unused yet.
 pub const VHOST_EVENTS_COUNT: usize = 2;
 
-pub struct VhostEpollHandler<T: Vhost> {
+pub struct VhostEpollHandler<T> {
     vhost_dev: T,
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: EventFd,
     queue_evt: EventFd,
 }
 
-impl<T: Vhost> VhostEpollHandler<T> {
+impl<T> VhostEpollHandler<T> {
     /// Construct a new, empty event handler for vhost-based devices.
     ///
     /// # Arguments
-    /// * `vhost_dev` - the vhost-based device info
+    /// * `vhost_dev` - the vhost-based device info, a kernel vhost device or the connection
+    ///   to a vhost-user back-end
     /// * `interrupt_status` - semaphore before triggering interrupt event
     /// * `interrupt_evt` EventFd for signaling an MMIO interrupt that the guest
     ///                   driver is listening to
@@ -73,7 +73,7 @@ impl<T: Vhost> VhostEpollHandler<T> {
     }
 }
 
-impl<T: Vhost> EpollHandler for VhostEpollHandler<T>
+impl<T> EpollHandler for VhostEpollHandler<T>
 where
     T: std::marker::Send,
 {
@@ -106,6 +106,10 @@ where
             }),
         }
     }
+
+    fn event_fds(&self) -> Vec<RawFd> {
+        vec![self.queue_evt.as_raw_fd()]
+    }
 }
 
 pub struct VhostEpollConfig {
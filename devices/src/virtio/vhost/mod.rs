@@ -10,9 +10,15 @@
 use std;
 use std::io;
 
+use super::ActivateError;
+
 pub mod handle;
+mod user_blk;
+#[cfg(feature = "vsock")]
 pub mod vsock;
 
+pub use self::user_blk::VhostUserBlock;
+
 #[derive(Debug)]
 pub enum Error {
     /// Creating kill eventfd failed.
@@ -51,7 +57,30 @@ pub enum Error {
     VhostIrqCreate(io::Error),
     /// Failed to read vhost eventfd.
     VhostIrqRead(io::Error),
+    /// Failed to connect to the vhost-user back-end.
+    VhostUserConnect(vhost_backend::Error),
+    /// Get protocol features failed.
+    VhostUserGetProtocolFeatures(vhost_backend::Error),
+    /// Set protocol features failed.
+    VhostUserSetProtocolFeatures(vhost_backend::Error),
+    /// Get queue num failed.
+    VhostUserGetQueueNum(vhost_backend::Error),
+    /// Get config failed.
+    VhostUserGetConfig(vhost_backend::Error),
+    /// Set vring enable failed.
+    VhostUserSetVringEnable(vhost_backend::Error),
+    /// The vhost-user back-end does not serve the configuration space of the device.
+    VhostUserConfigNotSupported,
+    /// The vhost-user back-end supports fewer queues than the device has.
+    VhostUserTooManyQueues(u64),
 }
 type Result<T> = std::result::Result<T, Error>;
 const INTERRUPT_STATUS_USED_RING: u32 = 0x1;
+#[cfg(feature = "vsock")]
 const TYPE_VSOCK: u32 = 19;
+
+impl std::convert::From<Error> for ActivateError {
+    fn from(error: Error) -> Self {
+        ActivateError::BadVhostActivate(error)
+    }
+}
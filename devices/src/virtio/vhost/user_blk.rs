@@ -0,0 +1,519 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A virtio block device whose requests are processed by a vhost-user back-end, in another
+//! process. The VMM only sets the device up, and relays the interrupts of the back-end.
+
+use std::path::Path;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+use super::super::block::{CONFIG_SPACE_SIZE, NUM_QUEUES_OFFSET};
+use super::super::{ActivateError, ActivateResult, Queue, VirtioDevice, TYPE_BLOCK};
+use super::handle::*;
+use super::*;
+
+use epoll;
+use memory_model::GuestMemory;
+use sys_util::EventFd;
+use vhost_backend::{
+    VhostUser, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_CONFIG,
+    VHOST_USER_PROTOCOL_F_MQ, VHOST_USER_PROTOCOL_F_REPLY_ACK,
+};
+use virtio_gen::virtio_blk::*;
+use virtio_gen::virtio_ring::{VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC};
+
+// The features of the back-end which are passed on to the driver.
+const SUPPORTED_FEATURES: &[u32] = &[
+    VIRTIO_BLK_F_SIZE_MAX,
+    VIRTIO_BLK_F_SEG_MAX,
+    VIRTIO_BLK_F_GEOMETRY,
+    VIRTIO_BLK_F_RO,
+    VIRTIO_BLK_F_BLK_SIZE,
+    VIRTIO_BLK_F_FLUSH,
+    VIRTIO_BLK_F_TOPOLOGY,
+    VIRTIO_BLK_F_CONFIG_WCE,
+    VIRTIO_BLK_F_DISCARD,
+    VIRTIO_BLK_F_WRITE_ZEROES,
+    VIRTIO_RING_F_INDIRECT_DESC,
+    VIRTIO_RING_F_EVENT_IDX,
+    VIRTIO_F_VERSION_1,
+];
+// The features which let the driver write to the disk.
+const WRITE_FEATURES: &[u32] = &[
+    VIRTIO_BLK_F_CONFIG_WCE,
+    VIRTIO_BLK_F_DISCARD,
+    VIRTIO_BLK_F_WRITE_ZEROES,
+];
+const PROTOCOL_FEATURES: u64 =
+    VHOST_USER_PROTOCOL_F_MQ | VHOST_USER_PROTOCOL_F_REPLY_ACK | VHOST_USER_PROTOCOL_F_CONFIG;
+
+fn feature_mask(features: &[u32]) -> u64 {
+    features.iter().fold(0, |mask, &bit| mask | (1u64 << bit))
+}
+
+/// Virtio block device backed by a vhost-user back-end.
+pub struct VhostUserBlock {
+    vhost_user: Option<VhostUser>,
+    avail_features: u64,
+    acked_features: u64,
+    // Whether the protocol features were negotiated with the back-end.
+    protocol_features: bool,
+    config_space: Vec<u8>,
+    queue_sizes: Vec<u16>,
+    epoll_config: VhostEpollConfig,
+    interrupt: Option<EventFd>,
+}
+
+impl VhostUserBlock {
+    /// Create a new vhost-user block device, connected to the back-end listening on the socket
+    /// at `socket_path`. The back-end has to serve the configuration space of the device, and
+    /// support as many queues as there are in `queue_sizes`. The device is read-only if
+    /// `read_only` is set, whatever the back-end offers.
+    pub fn new(
+        socket_path: &Path,
+        mem: &GuestMemory,
+        read_only: bool,
+        queue_sizes: Vec<u16>,
+        epoll_config: VhostEpollConfig,
+    ) -> Result<VhostUserBlock> {
+        let mut vhost_user =
+            VhostUser::connect(socket_path, mem).map_err(Error::VhostUserConnect)?;
+        vhost_user.set_owner().map_err(Error::VhostSetOwner)?;
+        let backend_features = vhost_user.get_features().map_err(Error::VhostGetFeatures)?;
+
+        let protocol_features = if backend_features & VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+            let features = vhost_user
+                .get_protocol_features()
+                .map_err(Error::VhostUserGetProtocolFeatures)?
+                & PROTOCOL_FEATURES;
+            vhost_user
+                .set_protocol_features(features)
+                .map_err(Error::VhostUserSetProtocolFeatures)?;
+            features
+        } else {
+            0
+        };
+        if protocol_features & VHOST_USER_PROTOCOL_F_CONFIG == 0 {
+            return Err(Error::VhostUserConfigNotSupported);
+        }
+
+        let num_queues = queue_sizes.len();
+        let max_queues = if backend_features & (1u64 << VIRTIO_BLK_F_MQ) != 0
+            && protocol_features & VHOST_USER_PROTOCOL_F_MQ != 0
+        {
+            vhost_user
+                .get_queue_num()
+                .map_err(Error::VhostUserGetQueueNum)?
+        } else {
+            1
+        };
+        if num_queues as u64 > max_queues {
+            return Err(Error::VhostUserTooManyQueues(max_queues));
+        }
+
+        let mut avail_features = backend_features & feature_mask(SUPPORTED_FEATURES);
+        if read_only {
+            avail_features &= !feature_mask(WRITE_FEATURES);
+            avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        }
+        let mut config_space = vhost_user
+            .get_config(0, CONFIG_SPACE_SIZE as u32)
+            .map_err(Error::VhostUserGetConfig)?;
+        // The driver only sees the queues of the device, out of the ones the back-end supports.
+        if num_queues > 1 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+            config_space[NUM_QUEUES_OFFSET] = num_queues as u8;
+            config_space[NUM_QUEUES_OFFSET + 1] = (num_queues >> 8) as u8;
+        }
+
+        Ok(VhostUserBlock {
+            vhost_user: Some(vhost_user),
+            avail_features,
+            acked_features: 0,
+            protocol_features: backend_features & VHOST_USER_F_PROTOCOL_FEATURES != 0,
+            config_space,
+            queue_sizes,
+            epoll_config,
+            interrupt: Some(EventFd::new().map_err(Error::VhostIrqCreate)?),
+        })
+    }
+}
+
+impl VirtioDevice for VhostUserBlock {
+    fn device_type(&self) -> u32 {
+        TYPE_BLOCK
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn required_queues(&self) -> usize {
+        // The driver sets up as many queues as it has use for, usually one per vCPU.
+        1
+    }
+
+    fn features(&self, page: u32) -> u32 {
+        match page {
+            // Get the lower 32-bits of the features bitfield.
+            0 => self.avail_features as u32,
+            // Get the upper 32-bits of the features bitfield.
+            1 => (self.avail_features >> 32) as u32,
+            _ => {
+                warn!("vhost-user-blk: Received request for unknown features page.");
+                0u32
+            }
+        }
+    }
+
+    fn ack_features(&mut self, page: u32, value: u32) {
+        let mut v = match page {
+            0 => u64::from(value),
+            1 => u64::from(value) << 32,
+            _ => {
+                warn!("vhost-user-blk: Cannot acknowledge unknown features page.");
+                0u64
+            }
+        };
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("vhost-user-blk: Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config_len = self.config_space.len() as u64;
+        match offset.checked_add(data.len() as u64) {
+            Some(end) if end <= config_len => {
+                data.copy_from_slice(&self.config_space[offset as usize..end as usize])
+            }
+            _ => error!("vhost-user-blk: Failed to read config space"),
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let config_len = self.config_space.len() as u64;
+        match offset.checked_add(data.len() as u64) {
+            Some(end) if end <= config_len => {
+                self.config_space[offset as usize..end as usize].copy_from_slice(data)
+            }
+            _ => {
+                error!("vhost-user-blk: Failed to write config space");
+                return;
+            }
+        }
+        // The back-end acts on the fields the driver writes, such as the write cache.
+        if let Some(ref mut vhost_user) = self.vhost_user {
+            if let Err(e) = vhost_user.set_config(offset as u32, data) {
+                error!("vhost-user-blk: Failed to write config space: {:?}", e);
+            }
+        }
+    }
+
+    fn activate(
+        &mut self,
+        _: GuestMemory,
+        interrupt_evt: EventFd,
+        interrupt_status: Arc<AtomicUsize>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        let num_queues = self.queue_sizes.len();
+        if queues.len() != num_queues || queue_evts.len() != num_queues {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                num_queues,
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        if let Some(mut vhost_user) = self.vhost_user.take() {
+            if let Some(interrupt) = self.interrupt.take() {
+                let mut features = self.acked_features;
+                if self.protocol_features {
+                    features |= VHOST_USER_F_PROTOCOL_FEATURES;
+                }
+                vhost_user
+                    .set_features(features)
+                    .map_err(Error::VhostSetFeatures)?;
+                vhost_user
+                    .set_mem_table()
+                    .map_err(Error::VhostSetMemTable)?;
+
+                // Only the queues the driver has set up are used.
+                let used_queues = queues.iter().take_while(|queue| queue.ready);
+                for (queue_index, queue) in used_queues.enumerate() {
+                    vhost_user
+                        .set_vring_num(queue_index, queue.actual_size())
+                        .map_err(Error::VhostSetVringNum)?;
+                    vhost_user
+                        .set_vring_addr(
+                            self.queue_sizes[queue_index],
+                            queue.actual_size(),
+                            queue_index,
+                            queue.desc_table,
+                            queue.used_ring,
+                            queue.avail_ring,
+                        )
+                        .map_err(Error::VhostSetVringAddr)?;
+                    vhost_user
+                        .set_vring_base(queue_index, 0)
+                        .map_err(Error::VhostSetVringBase)?;
+                    // The back-end signals all the queues through the same eventfd.
+                    vhost_user
+                        .set_vring_call(queue_index, &interrupt)
+                        .map_err(Error::VhostSetVringCall)?;
+                    vhost_user
+                        .set_vring_kick(queue_index, &queue_evts[queue_index])
+                        .map_err(Error::VhostSetVringKick)?;
+                    // With the protocol features, the vrings start out disabled.
+                    if self.protocol_features {
+                        vhost_user
+                            .set_vring_enable(queue_index, true)
+                            .map_err(Error::VhostUserSetVringEnable)?;
+                    }
+                }
+
+                // The handler keeps the connection to the back-end open.
+                let handler =
+                    VhostEpollHandler::new(vhost_user, interrupt_status, interrupt_evt, interrupt);
+                let queue_evt_raw_fd = handler.get_queue_evt();
+                //channel should be open and working
+                self.epoll_config
+                    .get_sender()
+                    .send(Box::new(handler))
+                    .unwrap();
+
+                epoll::ctl(
+                    self.epoll_config.get_raw_epoll_fd(),
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    queue_evt_raw_fd,
+                    epoll::Event::new(
+                        epoll::Events::EPOLLIN,
+                        self.epoll_config.get_queue_evt_token(),
+                    ),
+                )
+                .map_err(ActivateError::EpollCtl)?;
+
+                return Ok(());
+            }
+        }
+        Err(ActivateError::BadActivate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    use memory_model::{GuestAddress, MemoryBacking};
+    use virtio::queue::tests::VirtQueue;
+    use EpollHandler;
+
+    const GET_FEATURES: u32 = 1;
+    const SET_FEATURES: u32 = 2;
+    const SET_OWNER: u32 = 3;
+    const SET_MEM_TABLE: u32 = 5;
+    const SET_VRING_NUM: u32 = 8;
+    const SET_VRING_ADDR: u32 = 9;
+    const SET_VRING_BASE: u32 = 10;
+    const SET_VRING_KICK: u32 = 12;
+    const SET_VRING_CALL: u32 = 13;
+    const GET_PROTOCOL_FEATURES: u32 = 15;
+    const SET_PROTOCOL_FEATURES: u32 = 16;
+    const GET_QUEUE_NUM: u32 = 17;
+    const SET_VRING_ENABLE: u32 = 18;
+    const GET_CONFIG: u32 = 24;
+    const SET_CONFIG: u32 = 25;
+
+    // Serves a back-end offering `features` and `protocol_features`, with 4 queues, and passes
+    // the requests and their payloads on to the test. The file descriptors are dropped.
+    fn backend(
+        listener: UnixListener,
+        features: u64,
+        protocol_features: u64,
+    ) -> Receiver<(u32, Vec<u8>)> {
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0u8; 12];
+            while stream.read_exact(&mut header).is_ok() {
+                let request = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+                let size = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+                let mut payload = vec![0u8; size as usize];
+                stream.read_exact(&mut payload).unwrap();
+                let reply = match request {
+                    GET_FEATURES => Some(features.to_le_bytes().to_vec()),
+                    GET_PROTOCOL_FEATURES => Some(protocol_features.to_le_bytes().to_vec()),
+                    GET_QUEUE_NUM => Some(4u64.to_le_bytes().to_vec()),
+                    GET_CONFIG => {
+                        // A capacity of 0x800 sectors.
+                        let mut reply = payload.clone();
+                        reply[13] = 0x08;
+                        Some(reply)
+                    }
+                    _ => None,
+                };
+                if let Some(reply) = reply {
+                    reply_to(&mut stream, request, &reply);
+                }
+                sender.send((request, payload)).unwrap();
+            }
+        });
+        receiver
+    }
+
+    fn reply_to(stream: &mut UnixStream, request: u32, payload: &[u8]) {
+        stream.write_all(&request.to_le_bytes()).unwrap();
+        // The version and reply flags.
+        stream.write_all(&0x5u32.to_le_bytes()).unwrap();
+        stream
+            .write_all(&(payload.len() as u32).to_le_bytes())
+            .unwrap();
+        stream.write_all(payload).unwrap();
+    }
+
+    fn requests(messages: &Receiver<(u32, Vec<u8>)>, count: usize) -> Vec<u32> {
+        (0..count).map(|_| messages.recv().unwrap().0).collect()
+    }
+
+    fn epoll_config() -> (VhostEpollConfig, Receiver<Box<EpollHandler>>) {
+        let (sender, receiver) = channel();
+        (
+            VhostEpollConfig::new(0, epoll::create(true).unwrap(), sender),
+            receiver,
+        )
+    }
+
+    #[test]
+    fn test_vhost_user_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("vhost-user-blk.sock");
+        let mem = GuestMemory::new(
+            &[(GuestAddress(0), 0x10000)],
+            MemoryBacking::Memfd { hugepages: false },
+        )
+        .unwrap();
+        let features = VHOST_USER_F_PROTOCOL_FEATURES
+            | (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_BLK_F_FLUSH)
+            | (1u64 << VIRTIO_BLK_F_CONFIG_WCE)
+            | (1u64 << VIRTIO_BLK_F_MQ)
+            // Not passed on to the driver.
+            | (1u64 << VIRTIO_BLK_F_SCSI);
+        let protocol_features = VHOST_USER_PROTOCOL_F_MQ | VHOST_USER_PROTOCOL_F_CONFIG;
+
+        // The back-end supports 4 queues.
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let _messages = backend(listener, features, protocol_features);
+        match VhostUserBlock::new(&socket_path, &mem, false, vec![256; 8], epoll_config().0) {
+            Err(Error::VhostUserTooManyQueues(4)) => (),
+            _ => panic!("The back-end supports fewer queues."),
+        }
+
+        // The back-end has to serve the configuration space.
+        ::std::fs::remove_file(&socket_path).unwrap();
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let _messages = backend(listener, features, VHOST_USER_PROTOCOL_F_MQ);
+        match VhostUserBlock::new(&socket_path, &mem, false, vec![256], epoll_config().0) {
+            Err(Error::VhostUserConfigNotSupported) => (),
+            _ => panic!("The back-end does not serve the configuration space."),
+        }
+
+        ::std::fs::remove_file(&socket_path).unwrap();
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let messages = backend(listener, features, protocol_features);
+        let (epoll_config, handlers) = epoll_config();
+        let mut block =
+            VhostUserBlock::new(&socket_path, &mem, true, vec![16, 16], epoll_config).unwrap();
+        assert_eq!(
+            requests(&messages, 6),
+            vec![
+                SET_OWNER,
+                GET_FEATURES,
+                GET_PROTOCOL_FEATURES,
+                SET_PROTOCOL_FEATURES,
+                GET_QUEUE_NUM,
+                GET_CONFIG
+            ]
+        );
+        assert_eq!(block.device_type(), TYPE_BLOCK);
+        // The device is read-only, and has as many queues as configured.
+        assert_eq!(
+            block.avail_features,
+            (1u64 << VIRTIO_F_VERSION_1)
+                | (1u64 << VIRTIO_BLK_F_FLUSH)
+                | (1u64 << VIRTIO_BLK_F_RO)
+                | (1u64 << VIRTIO_BLK_F_MQ)
+        );
+        let mut config = [0u8; 8];
+        block.read_config(0, &mut config);
+        assert_eq!(config, [0, 0x08, 0, 0, 0, 0, 0, 0]);
+        let mut num_queues = [0u8; 2];
+        block.read_config(NUM_QUEUES_OFFSET as u64, &mut num_queues);
+        assert_eq!(num_queues, [2, 0]);
+
+        // The driver writes go to the back-end.
+        block.write_config(32, &[0]);
+        let (request, payload) = messages.recv().unwrap();
+        assert_eq!(request, SET_CONFIG);
+        assert_eq!(payload, [32, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        // The driver only sets up the first queue.
+        block.ack_features(1, 1);
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let queues = vec![vq.create_queue(), Queue::new(16)];
+        let queue_evts = vec![EventFd::new().unwrap(), EventFd::new().unwrap()];
+        block
+            .activate(
+                mem.clone(),
+                EventFd::new().unwrap(),
+                Arc::new(AtomicUsize::new(0)),
+                queues,
+                queue_evts,
+            )
+            .unwrap();
+        let (request, payload) = messages.recv().unwrap();
+        assert_eq!(request, SET_FEATURES);
+        assert_eq!(
+            payload,
+            ((1u64 << VIRTIO_F_VERSION_1) | VHOST_USER_F_PROTOCOL_FEATURES).to_le_bytes()
+        );
+        assert_eq!(
+            requests(&messages, 7),
+            vec![
+                SET_MEM_TABLE,
+                SET_VRING_NUM,
+                SET_VRING_ADDR,
+                SET_VRING_BASE,
+                SET_VRING_CALL,
+                SET_VRING_KICK,
+                SET_VRING_ENABLE
+            ]
+        );
+        assert!(handlers.try_recv().is_ok());
+        // The device can only be activated once.
+        assert!(block
+            .activate(
+                mem.clone(),
+                EventFd::new().unwrap(),
+                Arc::new(AtomicUsize::new(0)),
+                vec![vq.create_queue(), Queue::new(16)],
+                vec![EventFd::new().unwrap(), EventFd::new().unwrap()],
+            )
+            .is_err());
+    }
+}
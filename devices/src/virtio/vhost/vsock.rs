@@ -23,12 +23,6 @@ const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 3;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
 
-impl std::convert::From<super::Error> for ActivateError {
-    fn from(error: super::Error) -> Self {
-        ActivateError::BadVhostActivate(error)
-    }
-}
-
 pub struct Vsock {
     vsock_fd: Option<VhostVsockFd>,
     cid: u64,
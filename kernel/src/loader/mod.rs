@@ -261,13 +261,13 @@ pub fn load_cmdline(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use memory_model::{GuestAddress, GuestMemory};
+    use memory_model::{GuestAddress, GuestMemory, MemoryBacking};
     use std::io::Cursor;
 
     const MEM_SIZE: usize = 0x18_0000;
 
     fn create_guest_mem() -> GuestMemory {
-        GuestMemory::new(&[(GuestAddress(0x0), MEM_SIZE)]).unwrap()
+        GuestMemory::new(&[(GuestAddress(0x0), MEM_SIZE)], MemoryBacking::Anonymous).unwrap()
     }
 
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -297,7 +297,7 @@ mod tests {
 
     #[test]
     fn test_load_kernel_no_memory() {
-        let gm = GuestMemory::new(&[(GuestAddress(0x0), 79)]).unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), 79)], MemoryBacking::Anonymous).unwrap();
         let image = make_test_bin();
         assert_eq!(
             Err(Error::ReadKernelImage),
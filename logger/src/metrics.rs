@@ -24,9 +24,13 @@
 //! If if turns out this approach is not really what we want, it's pretty easy to resort to
 //! something else, while working behind the same interface.
 
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use chrono;
+use serde::ser::SerializeMap;
 use serde::{Serialize, Serializer};
 
 /// Used for defining new types of metrics that can be either incremented with an unit
@@ -136,6 +140,10 @@ pub struct ApiServerMetrics {
 /// Metrics specific to GET API Requests for counting user triggered actions and/or failures.
 #[derive(Default, Serialize)]
 pub struct GetRequestsMetrics {
+    /// Number of GETs for getting the balloon configuration or statistics.
+    pub balloon_count: SharedMetric,
+    /// Number of failures when getting the balloon configuration or statistics.
+    pub balloon_fails: SharedMetric,
     /// Number of GETs for getting information on the instance.
     pub instance_info_count: SharedMetric,
     /// Number of failures when obtaining information on the current instance.
@@ -144,6 +152,8 @@ pub struct GetRequestsMetrics {
     pub machine_cfg_count: SharedMetric,
     /// Number of failures during GETs for getting information on the instance.
     pub machine_cfg_fails: SharedMetric,
+    /// Number of GETs for getting the whole configuration of the microVM.
+    pub vm_config_count: SharedMetric,
 }
 
 /// Metrics specific to PUT API Requests for counting user triggered actions and/or failures.
@@ -153,10 +163,18 @@ pub struct PutRequestsMetrics {
     pub actions_count: SharedMetric,
     /// Number of failures in triggering an action on the VM.
     pub actions_fails: SharedMetric,
+    /// Number of PUTs for configuring the balloon device.
+    pub balloon_count: SharedMetric,
+    /// Number of failures in configuring the balloon device.
+    pub balloon_fails: SharedMetric,
     /// Number of PUTs for attaching source of boot.
     pub boot_source_count: SharedMetric,
     /// Number of failures during attaching source of boot.
     pub boot_source_fails: SharedMetric,
+    /// Number of PUTs for writing a core dump of the guest.
+    pub coredump_count: SharedMetric,
+    /// Number of failures in writing a core dump of the guest.
+    pub coredump_fails: SharedMetric,
     /// Number of PUTs triggering a block attach.
     pub drive_count: SharedMetric,
     /// Number of failures in attaching a block device.
@@ -169,15 +187,27 @@ pub struct PutRequestsMetrics {
     pub machine_cfg_count: SharedMetric,
     /// Number of failures in configuring the machine.
     pub machine_cfg_fails: SharedMetric,
+    /// Number of PUTs for sending or receiving a migrated microVM.
+    pub migration_count: SharedMetric,
+    /// Number of failures in sending or receiving a migrated microVM.
+    pub migration_fails: SharedMetric,
     /// Number of PUTs for creating a new network interface.
     pub network_count: SharedMetric,
     /// Number of failures in creating a new network interface.
     pub network_fails: SharedMetric,
+    /// Number of PUTs for creating or loading a snapshot.
+    pub snapshot_count: SharedMetric,
+    /// Number of failures in creating or loading a snapshot.
+    pub snapshot_fails: SharedMetric,
 }
 
 /// Metrics specific to PATCH API Requests for counting user triggered actions and/or failures.
 #[derive(Default, Serialize)]
 pub struct PatchRequestsMetrics {
+    /// Number of tries to PATCH the balloon device.
+    pub balloon_count: SharedMetric,
+    /// Number of failures in PATCHing the balloon device.
+    pub balloon_fails: SharedMetric,
     /// Number of tries to PATCH a block device.
     pub drive_count: SharedMetric,
     /// Number of failures in PATCHing a block device.
@@ -186,6 +216,35 @@ pub struct PatchRequestsMetrics {
     pub network_count: SharedMetric,
     /// Number of failures in PATCHing a net device.
     pub network_fails: SharedMetric,
+    /// Number of tries to PATCH the state of the microVM.
+    pub vm_count: SharedMetric,
+    /// Number of failures in PATCHing the state of the microVM.
+    pub vm_fails: SharedMetric,
+}
+
+/// Balloon Device associated metrics.
+#[derive(Default, Serialize)]
+pub struct BalloonDeviceMetrics {
+    /// Number of times when activate failed on the balloon device.
+    pub activate_fails: SharedMetric,
+    /// Number of times when interacting with the space config of the balloon device failed.
+    pub cfg_fails: SharedMetric,
+    /// Number of times when handling events on the balloon device failed.
+    pub event_fails: SharedMetric,
+    /// Number of buffers of page frame numbers received on the inflate queue.
+    pub inflate_count: SharedMetric,
+    /// Number of buffers of page frame numbers received on the deflate queue.
+    pub deflate_count: SharedMetric,
+    /// Number of failures in releasing the pages of an inflated balloon to the host.
+    pub release_fails: SharedMetric,
+    /// Number of statistics updates received from the guest.
+    pub stats_updates_count: SharedMetric,
+    /// Number of malformed statistics updates received from the guest.
+    pub stats_update_fails: SharedMetric,
+    /// Number of free page reports received from the guest.
+    pub reporting_count: SharedMetric,
+    /// Number of failures in releasing the free pages reported by the guest to the host.
+    pub reporting_fails: SharedMetric,
 }
 
 /// Block Device associated metrics.
@@ -199,6 +258,8 @@ pub struct BlockDeviceMetrics {
     pub event_fails: SharedMetric,
     /// Number of failures in executing a request on a block device.
     pub execute_fails: SharedMetric,
+    /// Number of blocks read from this block device which did not match its hash tree.
+    pub integrity_fails: SharedMetric,
     /// Number of invalid requests received for this block device.
     pub invalid_reqs_count: SharedMetric,
     /// Number of flushes operation triggered on this block device.
@@ -207,6 +268,10 @@ pub struct BlockDeviceMetrics {
     pub queue_event_count: SharedMetric,
     /// Number of events ratelimiter-related.
     pub rate_limiter_event_count: SharedMetric,
+    /// Number of completion events received from the io_uring of this block device.
+    pub io_completion_event_count: SharedMetric,
+    /// Number of failures to submit requests to the io_uring of this block device.
+    pub io_submit_fails: SharedMetric,
     /// Number of update operation triggered on this block device.
     pub update_count: SharedMetric,
     /// Number of failures while doing update on this block device.
@@ -215,6 +280,10 @@ pub struct BlockDeviceMetrics {
     pub read_count: SharedMetric,
     /// Number of bytes written by this block device.
     pub write_count: SharedMetric,
+    /// Number of bytes discarded by this block device.
+    pub discard_count: SharedMetric,
+    /// Number of bytes zeroed by this block device.
+    pub write_zeroes_count: SharedMetric,
 }
 
 /// Metrics specific to the i8042 device.
@@ -234,6 +303,19 @@ pub struct I8042DeviceMetrics {
     pub write_count: SharedMetric,
 }
 
+/// Metrics specific to the pvpanic device.
+#[derive(Default, Serialize)]
+pub struct PvPanicDeviceMetrics {
+    /// Number of panics reported by the guest kernel.
+    pub panic_count: SharedMetric,
+    /// Number of crash kernels the guest kernel reported to have loaded.
+    pub crash_loaded_count: SharedMetric,
+    /// Number of superfluous read intents on this pvpanic device.
+    pub missed_read_count: SharedMetric,
+    /// Number of superfluous write intents on this pvpanic device.
+    pub missed_write_count: SharedMetric,
+}
+
 /// Metrics for the logging subsystem.
 #[derive(Default, Serialize)]
 pub struct LoggerSystemMetrics {
@@ -346,11 +428,213 @@ pub struct VcpuMetrics {
     pub fitler_cpuid: SharedMetric,
 }
 
+/// Metrics of a single vCPU: how often each kind of KVM exit happened, and where the vCPU
+/// thread spent its time.
+#[derive(Default, Serialize)]
+pub struct PerVcpuMetrics {
+    /// Number of KVM exits for handling input IO.
+    pub exit_io_in: SharedMetric,
+    /// Number of KVM exits for handling output IO.
+    pub exit_io_out: SharedMetric,
+    /// Number of KVM exits for handling MMIO reads.
+    pub exit_mmio_read: SharedMetric,
+    /// Number of KVM exits for handling MMIO writes.
+    pub exit_mmio_write: SharedMetric,
+    /// Number of KVM_EXIT_UNKNOWN exits.
+    pub exit_unknown: SharedMetric,
+    /// Number of KVM_EXIT_EXCEPTION exits.
+    pub exit_exception: SharedMetric,
+    /// Number of KVM_EXIT_HYPERCALL exits.
+    pub exit_hypercall: SharedMetric,
+    /// Number of KVM_EXIT_DEBUG exits.
+    pub exit_debug: SharedMetric,
+    /// Number of KVM_EXIT_HLT exits.
+    pub exit_hlt: SharedMetric,
+    /// Number of KVM_EXIT_IRQ_WINDOW_OPEN exits.
+    pub exit_irq_window_open: SharedMetric,
+    /// Number of KVM_EXIT_SHUTDOWN exits.
+    pub exit_shutdown: SharedMetric,
+    /// Number of KVM_EXIT_FAIL_ENTRY exits.
+    pub exit_fail_entry: SharedMetric,
+    /// Number of KVM_EXIT_INTR exits.
+    pub exit_intr: SharedMetric,
+    /// Number of KVM_EXIT_SET_TPR exits.
+    pub exit_set_tpr: SharedMetric,
+    /// Number of KVM_EXIT_TPR_ACCESS exits.
+    pub exit_tpr_access: SharedMetric,
+    /// Number of KVM_EXIT_S390_SIEIC exits.
+    pub exit_s390_sieic: SharedMetric,
+    /// Number of KVM_EXIT_S390_RESET exits.
+    pub exit_s390_reset: SharedMetric,
+    /// Number of KVM_EXIT_DCR exits.
+    pub exit_dcr: SharedMetric,
+    /// Number of KVM_EXIT_NMI exits.
+    pub exit_nmi: SharedMetric,
+    /// Number of KVM_EXIT_INTERNAL_ERROR exits.
+    pub exit_internal_error: SharedMetric,
+    /// Number of KVM_EXIT_OSI exits.
+    pub exit_osi: SharedMetric,
+    /// Number of KVM_EXIT_PAPR_HCALL exits.
+    pub exit_papr_hcall: SharedMetric,
+    /// Number of KVM_EXIT_S390_UCONTROL exits.
+    pub exit_s390_ucontrol: SharedMetric,
+    /// Number of KVM_EXIT_WATCHDOG exits.
+    pub exit_watchdog: SharedMetric,
+    /// Number of KVM_EXIT_S390_TSCH exits.
+    pub exit_s390_tsch: SharedMetric,
+    /// Number of KVM_EXIT_EPR exits.
+    pub exit_epr: SharedMetric,
+    /// Number of KVM_EXIT_SYSTEM_EVENT exits.
+    pub exit_system_event: SharedMetric,
+    /// Number of KVM_EXIT_S390_STSI exits.
+    pub exit_s390_stsi: SharedMetric,
+    /// Number of KVM_EXIT_IOAPIC_EOI exits.
+    pub exit_ioapic_eoi: SharedMetric,
+    /// Number of KVM_EXIT_HYPERV exits.
+    pub exit_hyperv: SharedMetric,
+    /// Number of times KVM_RUN was interrupted by a signal.
+    pub run_interrupted: SharedMetric,
+    /// Number of times KVM_RUN failed.
+    pub run_failures: SharedMetric,
+    /// Time spent in KVM_RUN, in nanoseconds.
+    pub kvm_run_ns: SharedMetric,
+    /// Time spent handling the KVM exits in userspace, in nanoseconds.
+    pub emulation_ns: SharedMetric,
+}
+
+/// The metrics of every vCPU, serialized as a map from the vCPU ID to its metrics.
+#[derive(Default)]
+pub struct VcpuMetricsMap(RwLock<BTreeMap<u8, Arc<PerVcpuMetrics>>>);
+
+impl VcpuMetricsMap {
+    /// Returns the metrics of the given vCPU, which start being serialized from now on.
+    pub fn register(&self, vcpu_id: u8) -> Arc<PerVcpuMetrics> {
+        // Use expect() to crash if another thread poisoned this lock.
+        self.0
+            .write()
+            .expect("Failed to register vCPU metrics due to poisoned lock")
+            .entry(vcpu_id)
+            .or_insert_with(|| Arc::new(PerVcpuMetrics::default()))
+            .clone()
+    }
+}
+
+impl Serialize for VcpuMetricsMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Use expect() to crash if another thread poisoned this lock.
+        let vcpus = self
+            .0
+            .read()
+            .expect("Failed to serialize vCPU metrics due to poisoned lock");
+        let mut map = serializer.serialize_map(Some(vcpus.len()))?;
+        for (id, metrics) in vcpus.iter() {
+            map.serialize_entry(id, metrics.as_ref())?;
+        }
+        map.end()
+    }
+}
+
+/// The upper bounds of the buckets of a `LatencyHistogram`, in microseconds, along with the names
+/// of the buckets. The last bucket holds everything slower.
+const LATENCY_BUCKETS_US: &[(u64, &str)] = &[
+    (1, "1us"),
+    (2, "2us"),
+    (5, "5us"),
+    (10, "10us"),
+    (20, "20us"),
+    (50, "50us"),
+    (100, "100us"),
+    (200, "200us"),
+    (500, "500us"),
+    (1000, "1ms"),
+    (2000, "2ms"),
+    (5000, "5ms"),
+    (10000, "10ms"),
+];
+const LATENCY_OVERFLOW_BUCKET: &str = "more";
+
+/// Histogram of how long some operation took. Each bucket counts the operations which took at
+/// most as long as its name says, and longer than the previous bucket.
+#[derive(Default)]
+pub struct LatencyHistogram {
+    buckets: [SharedMetric; LATENCY_BUCKETS_US.len() + 1],
+}
+
+impl LatencyHistogram {
+    /// Counts an operation which took `duration`.
+    pub fn record(&self, duration: Duration) {
+        let ns = duration
+            .as_secs()
+            .saturating_mul(1_000_000_000)
+            .saturating_add(u64::from(duration.subsec_nanos()));
+        let index = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&(bound_us, _)| ns <= bound_us * 1000)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[index].inc();
+    }
+
+    /// Returns the number of operations counted so far.
+    pub fn count(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.count()).sum()
+    }
+}
+
+impl Serialize for LatencyHistogram {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.buckets.len()))?;
+        let names = LATENCY_BUCKETS_US
+            .iter()
+            .map(|&(_, name)| name)
+            .chain(Some(LATENCY_OVERFLOW_BUCKET));
+        for (name, bucket) in names.zip(self.buckets.iter()) {
+            map.serialize_entry(name, bucket)?;
+        }
+        map.end()
+    }
+}
+
+/// The latency of the MMIO and PIO exits handled by the devices, for each bus address range.
+/// Serialized as a map from the name of the range to its histogram.
+#[derive(Default)]
+pub struct BusLatencyMetrics(RwLock<BTreeMap<String, Arc<LatencyHistogram>>>);
+
+impl BusLatencyMetrics {
+    /// Returns the histogram of the given bus address range, which starts being serialized from
+    /// now on.
+    pub fn register(&self, range: String) -> Arc<LatencyHistogram> {
+        // Use expect() to crash if another thread poisoned this lock.
+        self.0
+            .write()
+            .expect("Failed to register bus latency metrics due to poisoned lock")
+            .entry(range)
+            .or_insert_with(|| Arc::new(LatencyHistogram::default()))
+            .clone()
+    }
+}
+
+impl Serialize for BusLatencyMetrics {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Use expect() to crash if another thread poisoned this lock.
+        let ranges = self
+            .0
+            .read()
+            .expect("Failed to serialize bus latency metrics due to poisoned lock");
+        let mut map = serializer.serialize_map(Some(ranges.len()))?;
+        for (range, histogram) in ranges.iter() {
+            map.serialize_entry(range, histogram.as_ref())?;
+        }
+        map.end()
+    }
+}
+
 /// Metrics specific to the machine manager as a whole.
 #[derive(Default, Serialize)]
 pub struct VmmMetrics {
     /// Number of device related events received for a VM.
     pub device_events: SharedMetric,
+    /// Number of devices stopped because their event handler failed.
+    pub device_failures: SharedMetric,
     /// Metric for signaling a panic has occurred.
     pub panic_count: SharedMetric,
 }
@@ -378,8 +662,12 @@ pub struct FirecrackerMetrics {
     utc_timestamp_ms: SerializeToUtcTimestampMs,
     /// API Server related metrics.
     pub api_server: ApiServerMetrics,
+    /// The balloon device's related metrics.
+    pub balloon: BalloonDeviceMetrics,
     /// A block device's related metrics.
     pub block: BlockDeviceMetrics,
+    /// Latency of the MMIO and PIO exits, for each bus address range.
+    pub bus_latency: BusLatencyMetrics,
     /// Metrics related to API GET requests.
     pub get_api_requests: GetRequestsMetrics,
     /// Metrics relaetd to the i8042 device.
@@ -394,6 +682,10 @@ pub struct FirecrackerMetrics {
     pub patch_api_requests: PatchRequestsMetrics,
     /// Metrics related to API PUT requests.
     pub put_api_requests: PutRequestsMetrics,
+    /// Metrics of each vCPU.
+    pub per_vcpu: VcpuMetricsMap,
+    /// Metrics related to the pvpanic device.
+    pub pvpanic: PvPanicDeviceMetrics,
     /// Metrics related to seccomp filtering.
     pub seccomp: SeccompMetrics,
     /// Metrics related to a vcpu's functioning.
@@ -464,6 +756,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_nanos(0));
+        histogram.record(Duration::from_nanos(1000));
+        histogram.record(Duration::from_nanos(1001));
+        histogram.record(Duration::from_micros(700));
+        histogram.record(Duration::from_millis(10));
+        histogram.record(Duration::from_secs(1));
+        assert_eq!(histogram.count(), 6);
+
+        let json = serde_json::to_value(&histogram).unwrap();
+        let buckets = json.as_object().unwrap();
+        assert_eq!(buckets.len(), LATENCY_BUCKETS_US.len() + 1);
+        assert_eq!(buckets["1us"], 2);
+        assert_eq!(buckets["2us"], 1);
+        assert_eq!(buckets["5us"], 0);
+        assert_eq!(buckets["1ms"], 1);
+        assert_eq!(buckets["10ms"], 1);
+        assert_eq!(buckets["more"], 1);
+
+        // The buckets are reset after being serialized.
+        let json = serde_json::to_value(&histogram).unwrap();
+        assert!(json.as_object().unwrap().values().all(|v| *v == 0));
+    }
+
+    #[test]
+    fn test_per_vcpu_metrics() {
+        let metrics = VcpuMetricsMap::default();
+        metrics.register(1).exit_hlt.inc();
+        metrics.register(0).exit_io_in.add(2);
+        // Registering the same vCPU again returns the same metrics.
+        metrics.register(1).exit_hlt.inc();
+
+        let json = serde_json::to_value(&metrics).unwrap();
+        let vcpus = json.as_object().unwrap();
+        assert_eq!(vcpus.len(), 2);
+        assert_eq!(vcpus["0"]["exit_io_in"], 2);
+        assert_eq!(vcpus["1"]["exit_hlt"], 2);
+        assert_eq!(vcpus["1"]["kvm_run_ns"], 0);
+    }
+
     #[test]
     fn test_serialize() {
         let s = serde_json::to_string(&FirecrackerMetrics::default());
@@ -8,11 +8,12 @@
 //! Track memory regions that are mapped to the guest microVM.
 
 use std::io::{Read, Write};
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
 use std::{mem, result};
 
 use guest_address::GuestAddress;
-use mmap::{self, MemoryMapping};
+use mmap::{self, MemoryBacking, MemoryMapping};
 use DataInit;
 
 /// Errors associated with handling guest memory regions.
@@ -24,7 +25,7 @@ pub enum Error {
     InvalidGuestAddressRange(GuestAddress, usize),
     /// Failure in accessing the memory located at some address.
     MemoryAccess(GuestAddress, mmap::Error),
-    /// Failure in creating an anonymous shared mapping.
+    /// Failure in creating the mapping of a memory region.
     MemoryMappingFailed(mmap::Error),
     /// Failure in initializing guest memory.
     MemoryNotInitialized,
@@ -35,8 +36,8 @@ pub enum Error {
 }
 type Result<T> = result::Result<T, Error>;
 
-/// Tracks a mapping of anonymous memory in the current process and the corresponding base address
-/// in the guest's memory space.
+/// Tracks a memory mapping in the current process and the corresponding base address in the
+/// guest's memory space.
 pub struct MemoryRegion {
     mapping: MemoryMapping,
     guest_base: GuestAddress,
@@ -62,7 +63,8 @@ pub struct GuestMemory {
 impl GuestMemory {
     /// Creates a container for guest memory regions.
     /// Valid memory regions are specified as a Vec of (Address, Size) tuples sorted by Address.
-    pub fn new(ranges: &[(GuestAddress, usize)]) -> Result<GuestMemory> {
+    /// Each region is mapped separately, with the memory described by `backing`.
+    pub fn new(ranges: &[(GuestAddress, usize)], backing: MemoryBacking) -> Result<GuestMemory> {
         if ranges.is_empty() {
             return Err(Error::NoMemoryRegions);
         }
@@ -79,7 +81,8 @@ impl GuestMemory {
                 }
             }
 
-            let mapping = MemoryMapping::new(range.1).map_err(Error::MemoryMappingFailed)?;
+            let mapping = MemoryMapping::new_with_backing(range.1, backing)
+                .map_err(Error::MemoryMappingFailed)?;
             regions.push(MemoryRegion {
                 mapping,
                 guest_base: range.0,
@@ -96,10 +99,10 @@ impl GuestMemory {
     /// # Examples
     ///
     /// ```
-    /// # use memory_model::{GuestAddress, GuestMemory, MemoryMapping};
+    /// # use memory_model::{GuestAddress, GuestMemory, MemoryBacking, MemoryMapping};
     /// # fn test_end_addr() -> Result<(), ()> {
     ///     let start_addr = GuestAddress(0x1000);
-    ///     let mut gm = GuestMemory::new(&vec![(start_addr, 0x400)]).map_err(|_| ())?;
+    ///     let mut gm = GuestMemory::new(&vec![(start_addr, 0x400)], MemoryBacking::Anonymous).map_err(|_| ())?;
     ///     assert_eq!(start_addr.checked_add(0x400), Some(gm.end_addr()));
     ///     Ok(())
     /// # }
@@ -138,6 +141,22 @@ impl GuestMemory {
         self.regions.len()
     }
 
+    /// Returns the memory behind the regions.
+    pub fn backing(&self) -> MemoryBacking {
+        // All the regions are created with the same backing.
+        self.regions
+            .first()
+            .map_or(MemoryBacking::Anonymous, |region| region.mapping.backing())
+    }
+
+    /// Returns the file descriptor of the memfd behind the region at `index`, if the memory is
+    /// backed by memfds. Other processes can map it to share the guest memory.
+    pub fn region_fd(&self, index: usize) -> Option<RawFd> {
+        self.regions
+            .get(index)
+            .and_then(|region| region.mapping.fd())
+    }
+
     /// Perform the specified action on each region's addresses.
     pub fn with_regions<F, E>(&self, cb: F) -> result::Result<(), E>
     where
@@ -179,10 +198,10 @@ impl GuestMemory {
     /// * Write a slice at guestaddress 0x200.
     ///
     /// ```
-    /// # use memory_model::{GuestAddress, GuestMemory, MemoryMapping};
+    /// # use memory_model::{GuestAddress, GuestMemory, MemoryBacking, MemoryMapping};
     /// # fn test_write_u64() -> Result<(), ()> {
     /// #   let start_addr = GuestAddress(0x1000);
-    /// #   let mut gm = GuestMemory::new(&vec![(start_addr, 0x400)]).map_err(|_| ())?;
+    /// #   let mut gm = GuestMemory::new(&vec![(start_addr, 0x400)], MemoryBacking::Anonymous).map_err(|_| ())?;
     ///     let res = gm.write_slice_at_addr(&[1,2,3,4,5], GuestAddress(0x200)).map_err(|_| ())?;
     ///     assert_eq!(5, res);
     ///     Ok(())
@@ -205,10 +224,10 @@ impl GuestMemory {
     /// * Read a slice of length 16 at guestaddress 0x200.
     ///
     /// ```
-    /// # use memory_model::{GuestAddress, GuestMemory, MemoryMapping};
+    /// # use memory_model::{GuestAddress, GuestMemory, MemoryBacking, MemoryMapping};
     /// # fn test_write_u64() -> Result<(), ()> {
     /// #   let start_addr = GuestAddress(0x1000);
-    /// #   let mut gm = GuestMemory::new(&vec![(start_addr, 0x400)]).map_err(|_| ())?;
+    /// #   let mut gm = GuestMemory::new(&vec![(start_addr, 0x400)], MemoryBacking::Anonymous).map_err(|_| ())?;
     ///     let buf = &mut [0u8; 16];
     ///     let res = gm.read_slice_at_addr(buf, GuestAddress(0x200)).map_err(|_| ())?;
     ///     assert_eq!(16, res);
@@ -239,11 +258,11 @@ impl GuestMemory {
     /// * Read a u64 from two areas of guest memory backed by separate mappings.
     ///
     /// ```
-    /// # use memory_model::{GuestAddress, GuestMemory, MemoryMapping};
+    /// # use memory_model::{GuestAddress, GuestMemory, MemoryBacking, MemoryMapping};
     /// # fn test_read_u64() -> Result<u64, ()> {
     /// #     let start_addr1 = GuestAddress(0x0);
     /// #     let start_addr2 = GuestAddress(0x400);
-    /// #     let mut gm = GuestMemory::new(&vec![(start_addr1, 0x400), (start_addr2, 0x400)])
+    /// #     let mut gm = GuestMemory::new(&vec![(start_addr1, 0x400), (start_addr2, 0x400)], MemoryBacking::Anonymous)
     /// #         .map_err(|_| ())?;
     ///       let num1: u64 = gm.read_obj_from_addr(GuestAddress(32)).map_err(|_| ())?;
     ///       let num2: u64 = gm.read_obj_from_addr(GuestAddress(0x400+32)).map_err(|_| ())?;
@@ -268,10 +287,10 @@ impl GuestMemory {
     /// * Write a u64 at guest address 0x1100.
     ///
     /// ```
-    /// # use memory_model::{GuestAddress, GuestMemory, MemoryMapping};
+    /// # use memory_model::{GuestAddress, GuestMemory, MemoryBacking, MemoryMapping};
     /// # fn test_write_u64() -> Result<(), ()> {
     /// #   let start_addr = GuestAddress(0x1000);
-    /// #   let mut gm = GuestMemory::new(&vec![(start_addr, 0x400)]).map_err(|_| ())?;
+    /// #   let mut gm = GuestMemory::new(&vec![(start_addr, 0x400)], MemoryBacking::Anonymous).map_err(|_| ())?;
     ///     gm.write_obj_at_addr(55u64, GuestAddress(0x1100))
     ///         .map_err(|_| ())
     /// # }
@@ -296,12 +315,12 @@ impl GuestMemory {
     /// * Read bytes from /dev/urandom
     ///
     /// ```
-    /// # use memory_model::{GuestAddress, GuestMemory, MemoryMapping};
+    /// # use memory_model::{GuestAddress, GuestMemory, MemoryBacking, MemoryMapping};
     /// # use std::fs::File;
     /// # use std::path::Path;
     /// # fn test_read_random() -> Result<u32, ()> {
     /// #     let start_addr = GuestAddress(0x1000);
-    /// #     let gm = GuestMemory::new(&vec![(start_addr, 0x400)]).map_err(|_| ())?;
+    /// #     let gm = GuestMemory::new(&vec![(start_addr, 0x400)], MemoryBacking::Anonymous).map_err(|_| ())?;
     ///       let mut file = File::open(Path::new("/dev/urandom")).map_err(|_| ())?;
     ///       let addr = GuestAddress(0x1010);
     ///       gm.read_to_memory(addr, &mut file, 128).map_err(|_| ())?;
@@ -338,12 +357,12 @@ impl GuestMemory {
     /// * Write 128 bytes to /dev/null
     ///
     /// ```
-    /// # use memory_model::{GuestAddress, GuestMemory, MemoryMapping};
+    /// # use memory_model::{GuestAddress, GuestMemory, MemoryBacking, MemoryMapping};
     /// # use std::fs::File;
     /// # use std::path::Path;
     /// # fn test_write_null() -> Result<(), ()> {
     /// #     let start_addr = GuestAddress(0x1000);
-    /// #     let gm = GuestMemory::new(&vec![(start_addr, 0x400)]).map_err(|_| ())?;
+    /// #     let gm = GuestMemory::new(&vec![(start_addr, 0x400)], MemoryBacking::Anonymous).map_err(|_| ())?;
     ///       let mut file = File::open(Path::new("/dev/null")).map_err(|_| ())?;
     ///       let addr = GuestAddress(0x1010);
     ///       gm.write_from_memory(addr, &mut file, 128).map_err(|_| ())?;
@@ -377,10 +396,10 @@ impl GuestMemory {
     /// # Examples
     ///
     /// ```
-    /// # use memory_model::{GuestAddress, GuestMemory};
+    /// # use memory_model::{GuestAddress, GuestMemory, MemoryBacking};
     /// # fn test_host_addr() -> Result<(), ()> {
     ///     let start_addr = GuestAddress(0x1000);
-    ///     let mut gm = GuestMemory::new(&vec![(start_addr, 0x500)]).map_err(|_| ())?;
+    ///     let mut gm = GuestMemory::new(&vec![(start_addr, 0x500)], MemoryBacking::Anonymous).map_err(|_| ())?;
     ///     let addr = gm.get_host_address(GuestAddress(0x1200)).unwrap();
     ///     println!("Host address is {:p}", addr);
     ///     Ok(())
@@ -394,6 +413,56 @@ impl GuestMemory {
         })
     }
 
+    /// Like `get_host_address`, but also checks that the `count` bytes starting at `guest_addr`
+    /// fit in a single memory region, so that they are contiguous in the address space of this
+    /// process. This is meant for handing guest buffers to asynchronous host I/O.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use memory_model::{GuestAddress, GuestMemory, MemoryBacking};
+    /// # fn test_host_range() -> Result<(), ()> {
+    ///     let gm = GuestMemory::new(&vec![(GuestAddress(0x1000), 0x500)], MemoryBacking::Anonymous).map_err(|_| ())?;
+    ///     assert!(gm.get_host_address_range(GuestAddress(0x1200), 0x300).is_ok());
+    ///     assert!(gm.get_host_address_range(GuestAddress(0x1200), 0x301).is_err());
+    ///     Ok(())
+    /// # }
+    /// ```
+    pub fn get_host_address_range(
+        &self,
+        guest_addr: GuestAddress,
+        count: usize,
+    ) -> Result<*mut u8> {
+        self.do_in_region(guest_addr, count, |mapping, offset| {
+            // This is safe; `do_in_region` already checks that the range is in bounds.
+            Ok(unsafe { mapping.as_ptr().add(offset) })
+        })
+    }
+
+    /// Discards `count` bytes of guest memory starting at `guest_addr`, releasing the backing
+    /// pages to the host. The guest reads the range back as zeroes. The range must be page
+    /// aligned and fit in a single memory region.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use memory_model::{GuestAddress, GuestMemory, MemoryBacking};
+    /// # fn test_remove_range() -> Result<(), ()> {
+    ///     let gm = GuestMemory::new(&vec![(GuestAddress(0x1000), 0x2000)], MemoryBacking::Anonymous).map_err(|_| ())?;
+    ///     gm.write_obj_at_addr(0x55u8, GuestAddress(0x2000)).map_err(|_| ())?;
+    ///     gm.remove_range(GuestAddress(0x2000), 0x1000).map_err(|_| ())?;
+    ///     assert_eq!(gm.read_obj_from_addr::<u8>(GuestAddress(0x2000)).map_err(|_| ())?, 0);
+    ///     Ok(())
+    /// # }
+    /// ```
+    pub fn remove_range(&self, guest_addr: GuestAddress, count: usize) -> Result<()> {
+        self.do_in_region(guest_addr, count, move |mapping, offset| {
+            mapping
+                .remove_range(offset, count)
+                .map_err(|e| Error::MemoryAccess(guest_addr, e))
+        })
+    }
+
     /// Applies two functions, specified as callbacks, on the inner memory regions.
     ///
     /// # Arguments
@@ -411,11 +480,11 @@ impl GuestMemory {
     ///   and dividing their sizes to 1024, then summing up the values in an accumulator.
     ///
     /// ```
-    /// # use memory_model::{GuestAddress, GuestMemory};
+    /// # use memory_model::{GuestAddress, GuestMemory, MemoryBacking};
     /// # fn test_map_fold() -> Result<(), ()> {
     ///     let start_addr1 = GuestAddress(0x0);
     ///     let start_addr2 = GuestAddress(0x400);
-    ///     let mem = GuestMemory::new(&vec![(start_addr1, 1024), (start_addr2, 2048)]).unwrap();
+    ///     let mem = GuestMemory::new(&vec![(start_addr1, 1024), (start_addr2, 2048)], MemoryBacking::Anonymous).unwrap();
     ///     let total_size = mem.map_and_fold(
     ///         0,
     ///         |(_, region)| region.size() / 1024,
@@ -475,13 +544,22 @@ mod tests {
     fn test_regions() {
         // No regions provided should return error.
         assert_eq!(
-            format!("{:?}", GuestMemory::new(&[]).err().unwrap()),
+            format!(
+                "{:?}",
+                GuestMemory::new(&[], MemoryBacking::Anonymous)
+                    .err()
+                    .unwrap()
+            ),
             format!("{:?}", Error::NoMemoryRegions)
         );
 
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x800);
-        let guest_mem = GuestMemory::new(&[(start_addr1, 0x400), (start_addr2, 0x400)]).unwrap();
+        let guest_mem = GuestMemory::new(
+            &[(start_addr1, 0x400), (start_addr2, 0x400)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
         assert_eq!(guest_mem.num_regions(), 2);
         assert!(guest_mem.address_in_range(GuestAddress(0x200)));
         assert!(!guest_mem.address_in_range(GuestAddress(0x600)));
@@ -498,7 +576,10 @@ mod tests {
     fn overlap_memory() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
-        let res = GuestMemory::new(&[(start_addr1, 0x2000), (start_addr2, 0x2000)]);
+        let res = GuestMemory::new(
+            &[(start_addr1, 0x2000), (start_addr2, 0x2000)],
+            MemoryBacking::Anonymous,
+        );
         assert_eq!(
             format!("{:?}", res.err().unwrap()),
             format!("{:?}", Error::MemoryRegionOverlap)
@@ -512,7 +593,11 @@ mod tests {
         let bad_addr = GuestAddress(0x2001);
         let bad_addr2 = GuestAddress(0x1ffc);
 
-        let gm = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        let gm = GuestMemory::new(
+            &[(start_addr1, 0x1000), (start_addr2, 0x1000)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
 
         let val1: u64 = 0xaa55_aa55_aa55_aa55;
         let val2: u64 = 0x55aa_55aa_55aa_55aa;
@@ -545,7 +630,7 @@ mod tests {
     #[test]
     fn write_and_read_slice() {
         let mut start_addr = GuestAddress(0x1000);
-        let gm = GuestMemory::new(&[(start_addr, 0x400)]).unwrap();
+        let gm = GuestMemory::new(&[(start_addr, 0x400)], MemoryBacking::Anonymous).unwrap();
         let sample_buf = &[1, 2, 3, 4, 5];
 
         assert_eq!(gm.write_slice_at_addr(sample_buf, start_addr).unwrap(), 5);
@@ -562,7 +647,8 @@ mod tests {
 
     #[test]
     fn read_to_and_write_from_mem() {
-        let gm = GuestMemory::new(&[(GuestAddress(0x1000), 0x400)]).unwrap();
+        let gm =
+            GuestMemory::new(&[(GuestAddress(0x1000), 0x400)], MemoryBacking::Anonymous).unwrap();
         let addr = GuestAddress(0x1010);
         gm.write_obj_at_addr(!0u32, addr).unwrap();
         gm.read_to_memory(
@@ -588,7 +674,7 @@ mod tests {
             (GuestAddress(0x1000), region_size),
         ];
         let mut iterated_regions = Vec::new();
-        let gm = GuestMemory::new(&regions).unwrap();
+        let gm = GuestMemory::new(&regions, MemoryBacking::Anonymous).unwrap();
 
         let res: Result<()> = gm.with_regions(|_, _, size, _| {
             assert_eq!(size, region_size);
@@ -615,7 +701,11 @@ mod tests {
     fn guest_to_host() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x100);
-        let mem = GuestMemory::new(&[(start_addr1, 0x100), (start_addr2, 0x400)]).unwrap();
+        let mem = GuestMemory::new(
+            &[(start_addr1, 0x100), (start_addr2, 0x400)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
 
         // Verify the host addresses match what we expect from the mappings.
         let addr1_base = get_mapping(&mem, start_addr1).unwrap();
@@ -628,13 +718,50 @@ mod tests {
         // Check that a bad address returns an error.
         let bad_addr = GuestAddress(0x12_3456);
         assert!(mem.get_host_address(bad_addr).is_err());
+
+        assert_eq!(
+            mem.get_host_address_range(start_addr2, 0x400).unwrap() as *const u8,
+            addr2_base
+        );
+        // The range has to fit in a single region.
+        assert!(mem.get_host_address_range(GuestAddress(0xff), 2).is_err());
+        assert!(mem.get_host_address_range(start_addr2, 0x401).is_err());
+    }
+
+    #[test]
+    fn test_remove_range() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x10000);
+        let mem = GuestMemory::new(
+            &[(start_addr1, 0x2000), (start_addr2, 0x2000)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
+
+        mem.write_obj_at_addr(0x55u64, GuestAddress(0x11000))
+            .unwrap();
+        mem.remove_range(GuestAddress(0x11000), 0x1000).unwrap();
+        assert_eq!(
+            mem.read_obj_from_addr::<u64>(GuestAddress(0x11000))
+                .unwrap(),
+            0
+        );
+
+        // The range cannot span two regions or go past the end of the memory.
+        assert!(mem.remove_range(GuestAddress(0x1000), 0x10000).is_err());
+        assert!(mem.remove_range(GuestAddress(0x11000), 0x2000).is_err());
+        assert!(mem.remove_range(GuestAddress(0x8000), 0x1000).is_err());
     }
 
     #[test]
     fn test_map_fold() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x400);
-        let mem = GuestMemory::new(&[(start_addr1, 1024), (start_addr2, 2048)]).unwrap();
+        let mem = GuestMemory::new(
+            &[(start_addr1, 1024), (start_addr2, 2048)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
 
         assert_eq!(
             mem.map_and_fold(
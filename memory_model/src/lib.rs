@@ -58,4 +58,4 @@ mod mmap;
 pub use guest_address::GuestAddress;
 pub use guest_memory::Error as GuestMemoryError;
 pub use guest_memory::GuestMemory;
-pub use mmap::{Error as MemoryMappingError, MemoryMapping};
+pub use mmap::{Error as MemoryMappingError, MemoryBacking, MemoryMapping, HUGEPAGE_SIZE};
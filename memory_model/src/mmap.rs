@@ -9,20 +9,37 @@
 //! mmap object leaves scope.
 
 use std;
+use std::ffi::CString;
+use std::fs::File;
 use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::ptr::null_mut;
 
 use libc;
 
 use DataInit;
 
+/// Size of the hugetlbfs pages which back the mappings created with hugepages.
+pub const HUGEPAGE_SIZE: usize = 2 << 20;
+
+// Selects 2 MiB pages for `MAP_HUGETLB` and `MFD_HUGETLB`: the log2 of the page size, shifted by
+// `MAP_HUGE_SHIFT`.
+const HUGE_2MB: libc::c_int = 21 << 26;
+
 /// Errors associated with memory mapping.
 #[derive(Debug)]
 pub enum Error {
+    /// The hugetlbfs pages could not be allocated, usually because too few are reserved on the
+    /// host.
+    HugepagesUnavailable(io::Error),
     /// Requested memory out of range.
     InvalidAddress,
+    /// The size of a mapping made of hugepages is not a multiple of the hugepage size.
+    InvalidHugepagesSize(usize),
     /// Requested memory range spans past the end of the region.
     InvalidRange(usize, usize),
+    /// Cannot create or resize the memfd backing the mapping.
+    Memfd(io::Error),
     /// Couldn't read from the given source.
     ReadFromSource(io::Error),
     /// `mmap` returned the given error.
@@ -34,10 +51,63 @@ pub enum Error {
 }
 type Result<T> = std::result::Result<T, Error>;
 
-/// Wraps an anonymous shared memory mapping in the current process.
+/// Describes the memory behind a `MemoryMapping`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryBacking {
+    /// Anonymous memory, private to the current process.
+    Anonymous,
+    /// Anonymous memory made of 2 MiB hugetlbfs pages, private to the current process.
+    Hugepages,
+    /// A memfd mapped as shared, which other processes can map too. The memfd is made of 2 MiB
+    /// hugetlbfs pages when `hugepages` is set.
+    Memfd {
+        /// Whether the memfd is made of 2 MiB hugetlbfs pages.
+        hugepages: bool,
+    },
+}
+
+impl MemoryBacking {
+    /// Returns whether the memory is made of 2 MiB hugetlbfs pages.
+    pub fn hugepages(self) -> bool {
+        match self {
+            MemoryBacking::Anonymous => false,
+            MemoryBacking::Hugepages => true,
+            MemoryBacking::Memfd { hugepages } => hugepages,
+        }
+    }
+}
+
+// Creates a memfd of `size` bytes, named after the guest memory so that it is easy to spot in
+// /proc/<pid>/fd.
+fn create_memfd(size: usize, hugepages: bool) -> Result<File> {
+    let name = CString::new("guest_mem").expect("Invalid memfd name");
+    let mut flags = libc::MFD_CLOEXEC;
+    if hugepages {
+        flags |= libc::MFD_HUGETLB | HUGE_2MB as libc::c_uint;
+    }
+    // This is safe because the name is a valid C string and we check the return value.
+    let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), flags) };
+    if fd < 0 {
+        let err = io::Error::last_os_error();
+        if hugepages {
+            return Err(Error::HugepagesUnavailable(err));
+        }
+        return Err(Error::Memfd(err));
+    }
+    // This is safe because we just created the file descriptor and nothing else owns it.
+    let file = unsafe { File::from_raw_fd(fd as RawFd) };
+    file.set_len(size as u64).map_err(Error::Memfd)?;
+    Ok(file)
+}
+
+/// Wraps a memory mapping in the current process, which is anonymous and private unless
+/// created with a different `MemoryBacking`.
 pub struct MemoryMapping {
     addr: *mut u8,
     size: usize,
+    backing: MemoryBacking,
+    // Keeps the memfd open for other processes to map, if the mapping is backed by one.
+    memfd: Option<File>,
 }
 
 // Send and Sync aren't automatically inherited for the raw address pointer.
@@ -48,29 +118,66 @@ unsafe impl Send for MemoryMapping {}
 unsafe impl Sync for MemoryMapping {}
 
 impl MemoryMapping {
-    /// Creates an anonymous shared mapping of `size` bytes.
+    /// Creates an anonymous private mapping of `size` bytes.
     ///
     /// # Arguments
     /// * `size` - Size of memory region in bytes.
     pub fn new(size: usize) -> Result<MemoryMapping> {
-        // This is safe because we are creating an anonymous mapping in a place not already used by
-        // any other area in this process.
+        MemoryMapping::new_with_backing(size, MemoryBacking::Anonymous)
+    }
+
+    /// Creates a mapping of `size` bytes backed by `backing`.
+    ///
+    /// # Arguments
+    /// * `size` - Size of memory region in bytes, a multiple of `HUGEPAGE_SIZE` for hugepages.
+    /// * `backing` - The memory behind the mapping.
+    pub fn new_with_backing(size: usize, backing: MemoryBacking) -> Result<MemoryMapping> {
+        if backing.hugepages() && size & (HUGEPAGE_SIZE - 1) != 0 {
+            return Err(Error::InvalidHugepagesSize(size));
+        }
+
+        // Private anonymous mappings are not reserved up front, so that pages discarded with
+        // `remove_range` are actually given back to the host. Hugepages are reserved, so that
+        // running out of them fails here rather than with a SIGBUS when the guest touches them.
+        let (flags, memfd) = match backing {
+            MemoryBacking::Anonymous => (
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+                None,
+            ),
+            MemoryBacking::Hugepages => (
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_HUGETLB | HUGE_2MB,
+                None,
+            ),
+            MemoryBacking::Memfd { hugepages } => {
+                (libc::MAP_SHARED, Some(create_memfd(size, hugepages)?))
+            }
+        };
+        let fd = memfd.as_ref().map_or(-1, |file| file.as_raw_fd());
+
+        // This is safe because we are creating a mapping in a place not already used by any other
+        // area in this process.
         let addr = unsafe {
             libc::mmap(
                 null_mut(),
                 size,
                 libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
-                -1,
+                flags,
+                fd,
                 0,
             )
         };
         if addr == libc::MAP_FAILED {
-            return Err(Error::SystemCallFailed(io::Error::last_os_error()));
+            let err = io::Error::last_os_error();
+            if backing.hugepages() {
+                return Err(Error::HugepagesUnavailable(err));
+            }
+            return Err(Error::SystemCallFailed(err));
         }
         Ok(MemoryMapping {
             addr: addr as *mut u8,
             size,
+            backing,
+            memfd,
         })
     }
 
@@ -85,6 +192,67 @@ impl MemoryMapping {
         self.size
     }
 
+    /// Returns the memory behind the mapping.
+    pub fn backing(&self) -> MemoryBacking {
+        self.backing
+    }
+
+    /// Returns the file descriptor of the memfd behind the mapping, if there is one. Other
+    /// processes can map it to share the memory.
+    pub fn fd(&self) -> Option<RawFd> {
+        self.memfd.as_ref().map(|file| file.as_raw_fd())
+    }
+
+    /// Discards `count` bytes of the memory region starting at `offset`, releasing the backing
+    /// pages to the host. The range reads back as zeroes afterwards. Both `offset` and `count`
+    /// must be multiples of the page size. For a mapping made of hugepages, only the hugepages
+    /// which lie entirely within the range are discarded.
+    ///
+    /// # Examples
+    /// * Discard the second page of a mapping.
+    ///
+    /// ```
+    /// #   use memory_model::MemoryMapping;
+    /// #   let mut mem_map = MemoryMapping::new(0x2000).unwrap();
+    ///     mem_map.write_obj(55u64, 0x1000).unwrap();
+    ///     assert!(mem_map.remove_range(0x1000, 0x1000).is_ok());
+    ///     assert_eq!(mem_map.read_obj::<u64>(0x1000).unwrap(), 0);
+    /// ```
+    pub fn remove_range(&self, offset: usize, count: usize) -> Result<()> {
+        let mut end = match offset.checked_add(count) {
+            Some(end) if end <= self.size => end,
+            _ => return Err(Error::InvalidRange(offset, count)),
+        };
+        let mut start = offset;
+        if self.backing.hugepages() {
+            // A hugepage can only be discarded as a whole.
+            start = (start + HUGEPAGE_SIZE - 1) & !(HUGEPAGE_SIZE - 1);
+            end &= !(HUGEPAGE_SIZE - 1);
+            if start >= end {
+                return Ok(());
+            }
+        }
+        // Shared pages stay allocated in the memfd until they are removed from it.
+        let advice = if self.memfd.is_some() {
+            libc::MADV_REMOVE
+        } else {
+            libc::MADV_DONTNEED
+        };
+        // This is safe because the range was checked to be within our mapping, and discarding
+        // pages of a mapping does not invalidate it.
+        let ret = unsafe {
+            libc::madvise(
+                self.addr.add(start) as *mut libc::c_void,
+                end - start,
+                advice,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::SystemCallFailed(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
     /// Writes a slice to the memory region at the specified offset.
     /// Returns the number of bytes written.  The number of bytes written can
     /// be less than the length of the slice if there isn't enough room in the
@@ -334,6 +502,79 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_remove_range() {
+        let m = MemoryMapping::new(0x3000).unwrap();
+        m.write_obj(0x55u8, 0x1000).unwrap();
+        m.write_obj(0x66u8, 0x2000).unwrap();
+        m.remove_range(0x1000, 0x1000).unwrap();
+        assert_eq!(m.read_obj::<u8>(0x1000).unwrap(), 0);
+        assert_eq!(m.read_obj::<u8>(0x2000).unwrap(), 0x66);
+
+        match m.remove_range(0x2000, 0x2000) {
+            Err(Error::InvalidRange(0x2000, 0x2000)) => (),
+            e => panic!("Unexpected result {:?}", e),
+        }
+        match m.remove_range(usize::max_value(), 0x1000) {
+            Err(Error::InvalidRange(_, _)) => (),
+            e => panic!("Unexpected result {:?}", e),
+        }
+        // The kernel rejects unaligned ranges.
+        match m.remove_range(0x10, 0x1000) {
+            Err(Error::SystemCallFailed(_)) => (),
+            e => panic!("Unexpected result {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_memfd_backing() {
+        let m = MemoryMapping::new_with_backing(0x3000, MemoryBacking::Memfd { hugepages: false })
+            .unwrap();
+        assert_eq!(m.backing(), MemoryBacking::Memfd { hugepages: false });
+        let fd = m.fd().unwrap();
+
+        // Another mapping of the memfd sees the same memory.
+        m.write_obj(0x55u8, 0x1000).unwrap();
+        let addr =
+            unsafe { libc::mmap(null_mut(), 0x3000, libc::PROT_READ, libc::MAP_SHARED, fd, 0) };
+        assert_ne!(addr, libc::MAP_FAILED);
+        assert_eq!(unsafe { *(addr as *const u8).add(0x1000) }, 0x55);
+
+        // Discarded pages are removed from the memfd.
+        m.remove_range(0x1000, 0x1000).unwrap();
+        assert_eq!(m.read_obj::<u8>(0x1000).unwrap(), 0);
+        assert_eq!(unsafe { *(addr as *const u8).add(0x1000) }, 0);
+        unsafe { libc::munmap(addr, 0x3000) };
+
+        assert!(MemoryMapping::new(0x1000).unwrap().fd().is_none());
+    }
+
+    #[test]
+    fn test_hugepages_backing() {
+        match MemoryMapping::new_with_backing(0x1000, MemoryBacking::Hugepages) {
+            Err(Error::InvalidHugepagesSize(0x1000)) => (),
+            e => panic!("Unexpected result {:?}", e.map(|m| m.size())),
+        }
+        match MemoryMapping::new_with_backing(0x1000, MemoryBacking::Memfd { hugepages: true }) {
+            Err(Error::InvalidHugepagesSize(0x1000)) => (),
+            e => panic!("Unexpected result {:?}", e.map(|m| m.size())),
+        }
+
+        // Whether hugepages are reserved depends on the host.
+        match MemoryMapping::new_with_backing(2 * HUGEPAGE_SIZE, MemoryBacking::Hugepages) {
+            Ok(m) => {
+                m.write_obj(0x55u8, 0).unwrap();
+                m.write_obj(0x66u8, HUGEPAGE_SIZE).unwrap();
+                // Only the hugepages entirely within the range are discarded.
+                m.remove_range(0x1000, 2 * HUGEPAGE_SIZE - 0x1000).unwrap();
+                assert_eq!(m.read_obj::<u8>(0).unwrap(), 0x55);
+                assert_eq!(m.read_obj::<u8>(HUGEPAGE_SIZE).unwrap(), 0);
+            }
+            Err(Error::HugepagesUnavailable(_)) => (),
+            Err(e) => panic!("Unexpected error {:?}", e),
+        }
+    }
+
     #[test]
     fn test_write_past_end() {
         let m = MemoryMapping::new(5).unwrap();
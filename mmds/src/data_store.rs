@@ -81,6 +81,15 @@ impl Mmds {
         Ok(())
     }
 
+    /// Returns the contents of the data store, or `None` if it was never initialized.
+    pub fn get_data(&self) -> Option<&Value> {
+        if self.is_initialized {
+            Some(&self.data_store)
+        } else {
+            None
+        }
+    }
+
     pub fn get_data_str(&self) -> String {
         if self.data_store.is_null() {
             return String::from("{}");
@@ -158,11 +167,14 @@ mod tests {
             "The MMDS resource does not exist.".to_string(),
         );
 
+        assert!(mmds.get_data().is_none());
+
         let mut mmds_json = "{\"meta-data\":{\"iam\":\"dummy\"},\"user-data\":\"1522850095\"}";
 
         mmds.put_data(serde_json::from_str(mmds_json).unwrap())
             .unwrap();
         assert!(mmds.check_data_store_initialized().is_ok());
+        assert_eq!(mmds.get_data().unwrap().to_string(), mmds_json);
 
         assert_eq!(mmds.get_data_str(), mmds_json);
 
@@ -120,6 +120,21 @@ impl Tap {
         Self::open_named("vmtap%d")
     }
 
+    /// Returns a new handle to the same tap interface queue.
+    pub fn try_clone(&self) -> IoResult<Tap> {
+        // This is safe because we own the tap fd and we check the return value.
+        let fd = unsafe { libc::dup(self.tap_file.as_raw_fd()) };
+        if fd < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        Ok(Tap {
+            // We just checked that the fd is valid.
+            tap_file: unsafe { File::from_raw_fd(fd) },
+            if_name: self.if_name,
+        })
+    }
+
     /// Set the host-side IP address for the tap interface.
     pub fn set_ip_addr(&self, ip_addr: net::Ipv4Addr) -> Result<()> {
         let sock = create_socket().map_err(Error::NetUtil)?;
@@ -459,6 +474,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_tap_try_clone() {
+        let tap = Tap::new().unwrap();
+        let clone = tap.try_clone().unwrap();
+        assert_eq!(clone, tap);
+        assert_ne!(clone.as_raw_fd(), tap.as_raw_fd());
+    }
+
     #[test]
     fn test_raw_fd() {
         let tap = Tap::new().unwrap();
@@ -21,7 +21,7 @@ use clap::{App, Arg};
 
 use std::io::ErrorKind;
 use std::panic;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::mpsc::channel;
 use std::sync::{Arc, RwLock};
@@ -30,6 +30,7 @@ use api_server::{ApiServer, Error};
 use fc_util::validators::validate_instance_id;
 use logger::{Metric, LOGGER, METRICS};
 use mmds::MMDS;
+use vmm::vmm_config::config_file::VmmConfig;
 use vmm::vmm_config::instance_info::{InstanceInfo, InstanceState};
 
 const DEFAULT_API_SOCK_PATH: &str = "/tmp/firecracker.socket";
@@ -102,6 +103,27 @@ fn main() {
                 .default_value("2")
                 .possible_values(&["0", "1", "2"]),
         )
+        .arg(
+            Arg::with_name("config-file")
+                .long("config-file")
+                .help("Path to a JSON file describing the microVM to boot straight away")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-api")
+                .long("no-api")
+                .help("Do not create the API socket; the microVM is described by the config file")
+                .requires("config-file"),
+        )
+        .arg(
+            Arg::with_name("gdb-socket")
+                .long("gdb-socket")
+                .help(
+                    "Path to a unix domain socket on which a GDB server waits to debug the guest \
+                     kernel. The guest does not run until GDB connects and continues it",
+                )
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("start-time-us")
                 .long("start-time-us")
@@ -148,12 +170,29 @@ fn main() {
             .expect("'start-time-cpu_us' parameter expected to be of 'u64' type.")
     });
 
+    let vmm_config = cmd_arguments.value_of("config-file").map(|path| {
+        VmmConfig::from_file(Path::new(path)).unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+        })
+    });
+    let no_api = cmd_arguments.is_present("no-api");
+
+    let mmds_info = MMDS.clone();
+    if let Some(data) = vmm_config.as_ref().and_then(|config| config.mmds.clone()) {
+        // The data was validated when reading the config file.
+        if let Err(e) = mmds_info.lock().expect("Poisoned MMDS lock").put_data(data) {
+            error!("Cannot initialize the MMDS: {}", e.to_string());
+            process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+        }
+    }
+
     let shared_info = Arc::new(RwLock::new(InstanceInfo {
         state: InstanceState::Uninitialized,
         id: instance_id,
         vmm_version: crate_version!().to_string(),
+        failed_devices: Vec::new(),
     }));
-    let mmds_info = MMDS.clone();
     let (to_vmm, from_api) = channel();
     let server =
         ApiServer::new(mmds_info, shared_info.clone(), to_vmm).expect("Cannot create API server");
@@ -162,8 +201,20 @@ fn main() {
         .get_event_fd_clone()
         .expect("Cannot clone API eventFD.");
 
-    let _vmm_thread_handle =
-        vmm::start_vmm_thread(shared_info, api_event_fd, from_api, seccomp_level);
+    let vmm_thread_handle = vmm::start_vmm_thread(
+        shared_info,
+        api_event_fd,
+        from_api,
+        seccomp_level,
+        vmm_config,
+        cmd_arguments.value_of("gdb-socket").map(PathBuf::from),
+    );
+
+    // The VMM thread terminates the process when the microVM stops.
+    if no_api {
+        vmm_thread_handle.join().expect("The VMM thread panicked");
+        return;
+    }
 
     match server.bind_and_run(bind_path, start_time_us, start_time_cpu_us, seccomp_level) {
         Ok(_) => (),
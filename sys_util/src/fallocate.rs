@@ -0,0 +1,123 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+
+use libc::{
+    c_int, fallocate64, off64_t, EOPNOTSUPP, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE,
+    FALLOC_FL_ZERO_RANGE,
+};
+
+use super::SyscallReturnCode;
+
+/// Deallocates a range of a file, which then reads back as zeroes.
+pub trait PunchHole {
+    /// Deallocates the `length` bytes at `offset`, without changing the size of the file.
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()>;
+}
+
+/// Zeroes a range of a file, keeping it allocated.
+pub trait WriteZeroes {
+    /// Zeroes the `length` bytes at `offset`, without changing the size of the file.
+    fn write_zeroes(&mut self, offset: u64, length: u64) -> io::Result<()>;
+}
+
+fn fallocate(file: &File, mode: c_int, offset: u64, length: u64) -> io::Result<()> {
+    // This is safe because fallocate only changes the file, and we check the return value.
+    SyscallReturnCode(unsafe {
+        fallocate64(file.as_raw_fd(), mode, offset as off64_t, length as off64_t)
+    })
+    .into_empty_result()
+}
+
+impl PunchHole for File {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        fallocate(
+            self,
+            FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+            offset,
+            length,
+        )
+    }
+}
+
+impl WriteZeroes for File {
+    fn write_zeroes(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        match fallocate(
+            self,
+            FALLOC_FL_ZERO_RANGE | FALLOC_FL_KEEP_SIZE,
+            offset,
+            length,
+        ) {
+            // Some file systems, like tmpfs, cannot zero ranges in place.
+            Err(ref e) if e.raw_os_error() == Some(EOPNOTSUPP) => {}
+            result => return result,
+        }
+
+        // Unlike fallocate, writing would grow the file past its end.
+        let end = self.metadata()?.len();
+        let length = cmp::min(length, end.saturating_sub(offset));
+        let zeroes = [0u8; 4096];
+        self.seek(SeekFrom::Start(offset))?;
+        let mut written = 0;
+        while written < length {
+            let chunk = cmp::min(length - written, zeroes.len() as u64) as usize;
+            self.write_all(&zeroes[..chunk])?;
+            written += chunk as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempfile;
+    use super::*;
+    use std::io::Read;
+
+    fn file_contents(file: &mut File) -> Vec<u8> {
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn test_punch_hole() {
+        let mut file = tempfile().unwrap();
+        file.write_all(&[0xffu8; 0x3000]).unwrap();
+
+        file.punch_hole(0x1000, 0x1000).unwrap();
+        let contents = file_contents(&mut file);
+        assert_eq!(contents.len(), 0x3000);
+        assert!(contents[..0x1000].iter().all(|&b| b == 0xff));
+        assert!(contents[0x1000..0x2000].iter().all(|&b| b == 0));
+        assert!(contents[0x2000..].iter().all(|&b| b == 0xff));
+
+        // Punching a hole past the end does not grow the file.
+        file.punch_hole(0x2800, 0x1000).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 0x3000);
+    }
+
+    #[test]
+    fn test_write_zeroes() {
+        let mut file = tempfile().unwrap();
+        file.write_all(&[0xffu8; 0x3000]).unwrap();
+
+        file.write_zeroes(0x800, 0x1000).unwrap();
+        let contents = file_contents(&mut file);
+        assert_eq!(contents.len(), 0x3000);
+        assert!(contents[..0x800].iter().all(|&b| b == 0xff));
+        assert!(contents[0x800..0x1800].iter().all(|&b| b == 0));
+        assert!(contents[0x1800..].iter().all(|&b| b == 0xff));
+
+        file.write_zeroes(0x2800, 0x1000).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 0x3000);
+        assert!(file_contents(&mut file)[0x2800..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,605 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal wrapper over the io_uring asynchronous I/O interface of Linux 5.6 or newer.
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use libc::{
+    c_long, c_uint, c_void, mmap, munmap, syscall, EBUSY, MAP_FAILED, MAP_POPULATE, MAP_SHARED,
+    PROT_READ, PROT_WRITE,
+};
+
+use super::{EventFd, SyscallReturnCode};
+
+/// The io_uring syscall numbers, which are the same on every architecture.
+pub const SYS_IO_URING_SETUP: c_long = 425;
+pub const SYS_IO_URING_ENTER: c_long = 426;
+pub const SYS_IO_URING_REGISTER: c_long = 427;
+
+// Offsets of the rings and of the submission entries in the io_uring file.
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x800_0000;
+const IORING_OFF_SQES: i64 = 0x1000_0000;
+
+// The submission and completion rings share a single mapping.
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+
+const IORING_REGISTER_EVENTFD: c_uint = 4;
+
+// Makes io_uring_enter wait for completions.
+const IORING_ENTER_GETEVENTS: c_uint = 1;
+
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+
+// Makes a write durable before it completes.
+const RWF_DSYNC: u32 = 2;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// An I/O operation to submit to an `IoUring`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SubmissionEntry {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+impl SubmissionEntry {
+    /// Reads `len` bytes at `offset` in the file `fd` into `buf`.
+    pub fn read(fd: RawFd, buf: *mut u8, len: u32, offset: u64, user_data: u64) -> Self {
+        SubmissionEntry {
+            opcode: IORING_OP_READ,
+            fd,
+            off: offset,
+            addr: buf as u64,
+            len,
+            user_data,
+            ..Default::default()
+        }
+    }
+
+    /// Writes `len` bytes from `buf` at `offset` in the file `fd`.
+    pub fn write(fd: RawFd, buf: *const u8, len: u32, offset: u64, user_data: u64) -> Self {
+        SubmissionEntry {
+            opcode: IORING_OP_WRITE,
+            fd,
+            off: offset,
+            addr: buf as u64,
+            len,
+            user_data,
+            ..Default::default()
+        }
+    }
+
+    /// Flushes the file `fd` to its storage.
+    pub fn fsync(fd: RawFd, user_data: u64) -> Self {
+        SubmissionEntry {
+            opcode: IORING_OP_FSYNC,
+            fd,
+            user_data,
+            ..Default::default()
+        }
+    }
+
+    /// Makes a write complete only once its data is on storage, as if it was followed by a
+    /// flush of the data.
+    pub fn dsync(mut self) -> Self {
+        self.op_flags |= RWF_DSYNC;
+        self
+    }
+
+    /// Returns the entry transferring the rest of a read or write, once its first `transferred`
+    /// bytes are done.
+    pub fn advance(mut self, transferred: u32) -> Self {
+        let transferred = cmp::min(transferred, self.len);
+        self.off += u64::from(transferred);
+        self.addr += u64::from(transferred);
+        self.len -= transferred;
+        self
+    }
+}
+
+/// The outcome of an I/O operation submitted to an `IoUring`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct CompletionEntry {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+impl CompletionEntry {
+    /// Returns the value the operation was submitted with.
+    pub fn user_data(&self) -> u64 {
+        self.user_data
+    }
+
+    /// Returns the number of bytes transferred by the operation, or its error.
+    pub fn result(&self) -> io::Result<u32> {
+        if self.res < 0 {
+            Err(io::Error::from_raw_os_error(-self.res))
+        } else {
+            Ok(self.res as u32)
+        }
+    }
+}
+
+// A shared mapping of the io_uring file.
+struct RingMapping {
+    addr: *mut u8,
+    len: usize,
+}
+
+impl RingMapping {
+    fn new(fd: RawFd, len: usize, offset: i64) -> io::Result<RingMapping> {
+        // This is safe because we map a new area and check the result.
+        let addr = unsafe {
+            mmap(
+                null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED | MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if addr == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RingMapping {
+            addr: addr as *mut u8,
+            len,
+        })
+    }
+
+    // Returns a pointer to the object at `offset` in the mapping. The kernel gave us the offsets,
+    // so they are within the mapping.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        // Safe because the offset is within the mapping.
+        unsafe { self.addr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for RingMapping {
+    fn drop(&mut self) {
+        // This is safe because we mapped the area ourselves and nobody else holds a reference
+        // to it once the ring is dropped.
+        unsafe {
+            munmap(self.addr as *mut c_void, self.len);
+        }
+    }
+}
+
+/// An io_uring instance, along with its submission and completion rings.
+pub struct IoUring {
+    file: File,
+    // The mappings hold the rings; the pointers below point into them.
+    _rings: Vec<RingMapping>,
+    sqes: RingMapping,
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const CompletionEntry,
+    // Entries pushed to the submission ring but not submitted to the kernel yet.
+    to_submit: u32,
+}
+
+// The pointers only refer to the mappings owned by the ring, so it can be moved to another thread.
+unsafe impl Send for IoUring {}
+
+impl IoUring {
+    /// Sets up an io_uring which holds up to `entries` operations, rounded up to a power of 2.
+    pub fn new(entries: u32) -> io::Result<IoUring> {
+        let mut params = Params::default();
+        // This is safe because the kernel only writes to the params we give it.
+        let fd = SyscallReturnCode(unsafe {
+            syscall(SYS_IO_URING_SETUP, entries, &mut params as *mut Params) as i32
+        })
+        .into_result()?;
+        // This is safe because the kernel just gave us this fd, which we own from now on.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize
+            + params.cq_entries as usize * std::mem::size_of::<CompletionEntry>();
+        let (sq_ring, cq_ring) = if params.features & IORING_FEAT_SINGLE_MMAP != 0 {
+            (
+                RingMapping::new(fd, std::cmp::max(sq_len, cq_len), IORING_OFF_SQ_RING)?,
+                None,
+            )
+        } else {
+            (
+                RingMapping::new(fd, sq_len, IORING_OFF_SQ_RING)?,
+                Some(RingMapping::new(fd, cq_len, IORING_OFF_CQ_RING)?),
+            )
+        };
+        let sqes = RingMapping::new(
+            fd,
+            params.sq_entries as usize * std::mem::size_of::<SubmissionEntry>(),
+            IORING_OFF_SQES,
+        )?;
+
+        let (sq_head, sq_tail, sq_array, sq_mask, sq_entries) = {
+            let off = &params.sq_off;
+            (
+                sq_ring.at(off.head),
+                sq_ring.at(off.tail),
+                sq_ring.at(off.array),
+                // Safe because the kernel set these up along with the ring.
+                unsafe { *sq_ring.at::<u32>(off.ring_mask) },
+                unsafe { *sq_ring.at::<u32>(off.ring_entries) },
+            )
+        };
+        let (cq_head, cq_tail, cqes, cq_mask) = {
+            let ring = cq_ring.as_ref().unwrap_or(&sq_ring);
+            let off = &params.cq_off;
+            (
+                ring.at(off.head),
+                ring.at(off.tail),
+                ring.at(off.cqes),
+                // Safe because the kernel set this up along with the ring.
+                unsafe { *ring.at::<u32>(off.ring_mask) },
+            )
+        };
+
+        let mut rings = vec![sq_ring];
+        rings.extend(cq_ring);
+        Ok(IoUring {
+            file,
+            _rings: rings,
+            sqes,
+            sq_head,
+            sq_tail,
+            sq_mask,
+            sq_entries,
+            sq_array,
+            cq_head,
+            cq_tail,
+            cq_mask,
+            cqes,
+            to_submit: 0,
+        })
+    }
+
+    /// Returns the number of entries of the submission ring.
+    pub fn sq_entries(&self) -> u32 {
+        self.sq_entries
+    }
+
+    /// Has the kernel signal `evt` whenever it posts a completion.
+    pub fn register_eventfd(&self, evt: &EventFd) -> io::Result<()> {
+        let fd: RawFd = evt.as_raw_fd();
+        // This is safe because the kernel only reads the fd we give it.
+        SyscallReturnCode(unsafe {
+            syscall(
+                SYS_IO_URING_REGISTER,
+                self.file.as_raw_fd(),
+                IORING_REGISTER_EVENTFD,
+                &fd as *const RawFd,
+                1,
+            ) as i32
+        })
+        .into_empty_result()
+    }
+
+    /// Queues `entry` on the submission ring, until the next call to `submit`. Fails with
+    /// `EBUSY` if the submission ring is full.
+    ///
+    /// # Safety
+    ///
+    /// The buffer of `entry` must stay valid until the operation completes.
+    pub unsafe fn push(&mut self, entry: SubmissionEntry) -> io::Result<()> {
+        let head = (*self.sq_head).load(Ordering::Acquire);
+        // Only we write the tail.
+        let tail = (*self.sq_tail).load(Ordering::Relaxed);
+        if tail.wrapping_sub(head) >= self.sq_entries {
+            return Err(io::Error::from_raw_os_error(EBUSY));
+        }
+
+        let index = tail & self.sq_mask;
+        *self.sqes.at::<SubmissionEntry>(0).add(index as usize) = entry;
+        *self.sq_array.add(index as usize) = index;
+        // The entry has to be visible before the tail which hands it to the kernel.
+        (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        self.to_submit += 1;
+        Ok(())
+    }
+
+    /// Submits the queued entries to the kernel, without waiting for their completion. The
+    /// entries the kernel does not take yet stay queued, and are submitted along with the next
+    /// ones.
+    pub fn submit(&mut self) -> io::Result<()> {
+        while self.to_submit > 0 {
+            // This is safe because the kernel only reads the rings we set up.
+            let submitted = SyscallReturnCode(unsafe {
+                syscall(
+                    SYS_IO_URING_ENTER,
+                    self.file.as_raw_fd(),
+                    self.to_submit,
+                    0,
+                    0,
+                    null_mut::<c_void>(),
+                    0,
+                ) as i32
+            })
+            .into_result()?;
+            // The kernel cannot take more entries for now.
+            if submitted == 0 {
+                break;
+            }
+            self.to_submit -= submitted as u32;
+        }
+        Ok(())
+    }
+
+    /// Submits the queued entries to the kernel, then waits until at least one completion is
+    /// on the completion ring.
+    pub fn wait(&mut self) -> io::Result<()> {
+        loop {
+            // This is safe because the kernel only reads the rings we set up.
+            let result = SyscallReturnCode(unsafe {
+                syscall(
+                    SYS_IO_URING_ENTER,
+                    self.file.as_raw_fd(),
+                    self.to_submit,
+                    1,
+                    IORING_ENTER_GETEVENTS,
+                    null_mut::<c_void>(),
+                    0,
+                ) as i32
+            })
+            .into_result();
+            match result {
+                Ok(submitted) => {
+                    self.to_submit -= submitted as u32;
+                    return Ok(());
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Takes the next completion off the completion ring, if any.
+    pub fn pop_completion(&mut self) -> Option<CompletionEntry> {
+        // This is safe because the pointers are within the rings, and the entry below the tail
+        // was written by the kernel.
+        unsafe {
+            // Only we write the head.
+            let head = (*self.cq_head).load(Ordering::Relaxed);
+            let tail = (*self.cq_tail).load(Ordering::Acquire);
+            if head == tail {
+                return None;
+            }
+            let entry = *self.cqes.add((head & self.cq_mask) as usize);
+            // The entry has to be read before the head hands its slot back to the kernel.
+            (*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+            Some(entry)
+        }
+    }
+}
+
+impl AsRawFd for IoUring {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempfile;
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::mem::size_of;
+
+    // Waits for the kernel to signal `evt`, then takes the completions off `ring`.
+    fn wait_completions(ring: &mut IoUring, evt: &EventFd) -> Vec<CompletionEntry> {
+        let mut pollfd = libc::pollfd {
+            fd: evt.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 5000) }, 1);
+        assert!(evt.read().is_ok());
+        let mut completions = Vec::new();
+        while let Some(completion) = ring.pop_completion() {
+            completions.push(completion);
+        }
+        completions
+    }
+
+    #[test]
+    fn test_abi_sizes() {
+        assert_eq!(size_of::<Params>(), 120);
+        assert_eq!(size_of::<SubmissionEntry>(), 64);
+        assert_eq!(size_of::<CompletionEntry>(), 16);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let mut ring = IoUring::new(4).unwrap();
+        assert_eq!(ring.sq_entries(), 4);
+        let evt = EventFd::new().unwrap();
+        assert!(ring.register_eventfd(&evt).is_ok());
+
+        let mut file = tempfile().unwrap();
+        file.write_all(&[0u8; 8]).unwrap();
+        let fd = file.as_raw_fd();
+
+        let data = [1u8, 2, 3, 4];
+        unsafe {
+            ring.push(SubmissionEntry::write(fd, data.as_ptr(), 4, 2, 1))
+                .unwrap();
+        }
+        assert!(ring.pop_completion().is_none());
+        ring.submit().unwrap();
+        let completions = wait_completions(&mut ring, &evt);
+        assert_eq!(completions.len(), 1);
+        assert_eq!(completions[0].user_data(), 1);
+        assert_eq!(completions[0].result().unwrap(), 4);
+
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, vec![0, 0, 1, 2, 3, 4, 0, 0]);
+
+        let mut buf = [0u8; 8];
+        unsafe {
+            ring.push(SubmissionEntry::read(fd, buf.as_mut_ptr(), 8, 0, 2))
+                .unwrap();
+            ring.push(SubmissionEntry::fsync(fd, 3)).unwrap();
+            // Reading from a bad fd completes with an error.
+            ring.push(SubmissionEntry::read(-1, buf.as_mut_ptr(), 8, 0, 4))
+                .unwrap();
+        }
+        ring.submit().unwrap();
+        let mut completions = Vec::new();
+        while completions.len() < 3 {
+            completions.extend(wait_completions(&mut ring, &evt));
+        }
+        completions.sort_by_key(CompletionEntry::user_data);
+        assert_eq!(completions[0].result().unwrap(), 8);
+        assert_eq!(buf, [0, 0, 1, 2, 3, 4, 0, 0]);
+        assert_eq!(completions[1].result().unwrap(), 0);
+        assert_eq!(
+            completions[2].result().unwrap_err().raw_os_error(),
+            Some(libc::EBADF)
+        );
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut ring = IoUring::new(4).unwrap();
+        let mut file = tempfile().unwrap();
+        file.write_all(&[1u8, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+
+        // Only the last 5 bytes of the read are left.
+        let mut buf = [0u8; 8];
+        let entry = SubmissionEntry::read(file.as_raw_fd(), buf.as_mut_ptr(), 8, 0, 1).advance(3);
+        unsafe {
+            ring.push(entry).unwrap();
+        }
+        ring.wait().unwrap();
+        assert_eq!(ring.pop_completion().unwrap().result().unwrap(), 5);
+        assert_eq!(buf, [0, 0, 0, 4, 5, 6, 7, 8]);
+
+        // Nothing is left once all the bytes are done.
+        let entry = SubmissionEntry::write(file.as_raw_fd(), buf.as_ptr(), 8, 0, 2).advance(9);
+        unsafe {
+            ring.push(entry).unwrap();
+        }
+        ring.wait().unwrap();
+        assert_eq!(ring.pop_completion().unwrap().result().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_wait() {
+        let mut ring = IoUring::new(4).unwrap();
+        let file = tempfile().unwrap();
+        unsafe {
+            ring.push(SubmissionEntry::fsync(file.as_raw_fd(), 1))
+                .unwrap();
+        }
+        // The entry is submitted along with the wait, and no eventfd is needed.
+        ring.wait().unwrap();
+        let completion = ring.pop_completion().unwrap();
+        assert_eq!(completion.user_data(), 1);
+        assert_eq!(completion.result().unwrap(), 0);
+        assert!(ring.pop_completion().is_none());
+    }
+
+    #[test]
+    fn test_full_ring() {
+        let mut ring = IoUring::new(2).unwrap();
+        let file = tempfile().unwrap();
+        unsafe {
+            assert!(ring
+                .push(SubmissionEntry::fsync(file.as_raw_fd(), 0))
+                .is_ok());
+            assert!(ring
+                .push(SubmissionEntry::fsync(file.as_raw_fd(), 1))
+                .is_ok());
+            assert_eq!(
+                ring.push(SubmissionEntry::fsync(file.as_raw_fd(), 2))
+                    .unwrap_err()
+                    .raw_os_error(),
+                Some(EBUSY)
+            );
+        }
+        ring.submit().unwrap();
+        // The submission ring has room again once the kernel took the entries.
+        unsafe {
+            assert!(ring
+                .push(SubmissionEntry::fsync(file.as_raw_fd(), 2))
+                .is_ok());
+        }
+    }
+}
@@ -14,12 +14,17 @@ use libc::c_int;
 pub mod ioctl;
 
 mod eventfd;
+mod fallocate;
+pub mod io_uring;
+mod sched;
 mod signal;
 mod struct_util;
 mod terminal;
 
 pub use eventfd::*;
+pub use fallocate::*;
 pub use ioctl::*;
+pub use sched::*;
 pub use signal::*;
 pub use struct_util::{read_struct, read_struct_slice};
 pub use terminal::*;
@@ -0,0 +1,135 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use libc::{
+    c_int, cpu_set_t, pthread_setaffinity_np, pthread_setschedparam, pthread_t, sched_param,
+    CPU_SET, CPU_ZERO, EINVAL,
+};
+use std::io;
+use std::mem;
+
+/// The number of host CPUs a CPU set can hold, `CPU_SETSIZE` in glibc.
+pub const MAX_HOST_CPUS: usize = 1024;
+
+/// Converts the return value of the pthread functions, which return the error number instead of
+/// setting `errno`.
+fn pthread_result(ret: c_int) -> io::Result<()> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(ret))
+    }
+}
+
+/// Restricts `thread` to running on the host CPUs in `cpus`.
+///
+/// Fails with `EINVAL` if `cpus` is empty or holds a CPU ID of `MAX_HOST_CPUS` or more.
+///
+/// # Safety
+///
+/// `thread` must be a thread which has not been joined or detached yet.
+pub unsafe fn set_cpu_affinity(thread: pthread_t, cpus: &[usize]) -> io::Result<()> {
+    if cpus.is_empty() || cpus.iter().any(|&cpu| cpu >= MAX_HOST_CPUS) {
+        return Err(io::Error::from_raw_os_error(EINVAL));
+    }
+
+    let mut cpu_set: cpu_set_t = mem::zeroed();
+    CPU_ZERO(&mut cpu_set);
+    for &cpu in cpus {
+        CPU_SET(cpu, &mut cpu_set);
+    }
+    pthread_result(pthread_setaffinity_np(
+        thread,
+        mem::size_of::<cpu_set_t>(),
+        &cpu_set,
+    ))
+}
+
+/// Sets the scheduling policy of `thread` to `policy`, one of the `SCHED_*` constants, with the
+/// static `priority`, which has to be 0 for the policies which are not real-time.
+///
+/// # Safety
+///
+/// `thread` must be a thread which has not been joined or detached yet.
+pub unsafe fn set_scheduling_policy(
+    thread: pthread_t,
+    policy: c_int,
+    priority: c_int,
+) -> io::Result<()> {
+    let param = sched_param {
+        sched_priority: priority,
+    };
+    pthread_result(pthread_setschedparam(thread, policy, &param))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::{pthread_self, sched_getaffinity, CPU_ISSET, SCHED_OTHER};
+
+    #[test]
+    fn test_max_host_cpus() {
+        assert_eq!(MAX_HOST_CPUS, 8 * mem::size_of::<cpu_set_t>());
+    }
+
+    #[test]
+    fn test_set_cpu_affinity() {
+        unsafe {
+            let mut allowed: cpu_set_t = mem::zeroed();
+            assert_eq!(
+                sched_getaffinity(0, mem::size_of::<cpu_set_t>(), &mut allowed),
+                0
+            );
+            let cpu = (0..MAX_HOST_CPUS)
+                .find(|&cpu| CPU_ISSET(cpu, &allowed))
+                .unwrap();
+
+            let thread = pthread_self();
+            assert_eq!(
+                set_cpu_affinity(thread, &[]).unwrap_err().raw_os_error(),
+                Some(EINVAL)
+            );
+            assert_eq!(
+                set_cpu_affinity(thread, &[MAX_HOST_CPUS])
+                    .unwrap_err()
+                    .raw_os_error(),
+                Some(EINVAL)
+            );
+            assert!(set_cpu_affinity(thread, &[cpu]).is_ok());
+
+            let mut current: cpu_set_t = mem::zeroed();
+            assert_eq!(
+                sched_getaffinity(0, mem::size_of::<cpu_set_t>(), &mut current),
+                0
+            );
+            assert!(CPU_ISSET(cpu, &current));
+            assert_eq!(
+                (0..MAX_HOST_CPUS)
+                    .filter(|&cpu| CPU_ISSET(cpu, &current))
+                    .count(),
+                1
+            );
+
+            // Give this test thread its CPUs back.
+            assert_eq!(
+                pthread_setaffinity_np(thread, mem::size_of::<cpu_set_t>(), &allowed),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn test_set_scheduling_policy() {
+        unsafe {
+            let thread = pthread_self();
+            assert!(set_scheduling_policy(thread, SCHED_OTHER, 0).is_ok());
+            // Non real-time policies only take priority 0.
+            assert_eq!(
+                set_scheduling_policy(thread, SCHED_OTHER, 1)
+                    .unwrap_err()
+                    .raw_os_error(),
+                Some(EINVAL)
+            );
+        }
+    }
+}
@@ -8,7 +8,12 @@ extern crate memory_model;
 extern crate sys_util;
 extern crate vhost_gen;
 
+mod vhost_user;
 mod vsock;
+pub use vhost_user::{
+    VhostUser, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_CONFIG,
+    VHOST_USER_PROTOCOL_F_MQ, VHOST_USER_PROTOCOL_F_REPLY_ACK,
+};
 pub use vsock::Vsock;
 
 use std::mem;
@@ -35,6 +40,19 @@ pub enum Error {
     AvailAddress(GuestMemoryError),
     /// Invalid log address.
     LogAddress(GuestMemoryError),
+    /// Cannot connect to the socket of a vhost-user back-end.
+    VhostUserConnect(std::io::Error),
+    /// Cannot send a message to a vhost-user back-end, or receive its reply.
+    VhostUserSocket(std::io::Error),
+    /// The reply of a vhost-user back-end does not match the request.
+    VhostUserInvalidReply,
+    /// A vhost-user back-end failed the request, of this type.
+    VhostUserRequestFailed(u32),
+    /// The guest memory is not backed by memfds, so it cannot be shared with a vhost-user
+    /// back-end.
+    VhostUserMemoryNotShared,
+    /// The guest memory has more regions than a vhost-user back-end can map.
+    VhostUserTooManyRegions,
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -42,6 +60,32 @@ fn ioctl_error<T>() -> Result<T> {
     Err(Error::IoctlError(std::io::Error::last_os_error()))
 }
 
+// Checks that the size of a vring is valid, and that its parts are in the guest memory.
+fn vring_in_memory(
+    mem: &GuestMemory,
+    queue_max_size: u16,
+    queue_size: u16,
+    desc_addr: GuestAddress,
+    avail_addr: GuestAddress,
+    used_addr: GuestAddress,
+) -> bool {
+    let desc_table_size = 16 * queue_size as usize;
+    let avail_ring_size = 6 + 2 * queue_size as usize;
+    let used_ring_size = 6 + 8 * queue_size as usize;
+    !(queue_size > queue_max_size
+        || queue_size == 0
+        || (queue_size & (queue_size - 1)) != 0
+        || desc_addr
+            .checked_add(desc_table_size)
+            .map_or(true, |v| !mem.address_in_range(v))
+        || avail_addr
+            .checked_add(avail_ring_size)
+            .map_or(true, |v| !mem.address_in_range(v))
+        || used_addr
+            .checked_add(used_ring_size)
+            .map_or(true, |v| !mem.address_in_range(v)))
+}
+
 /// An interface for setting up vhost-based virtio devices.  Vhost-based devices are different
 /// from regular virtio devices because the host kernel takes care of handling all the data
 /// transfer.  The device itself only needs to deal with setting up the kernel driver and
@@ -156,21 +200,14 @@ pub trait Vhost: AsRawFd + std::marker::Sized {
         avail_addr: GuestAddress,
         used_addr: GuestAddress,
     ) -> bool {
-        let desc_table_size = 16 * queue_size as usize;
-        let avail_ring_size = 6 + 2 * queue_size as usize;
-        let used_ring_size = 6 + 8 * queue_size as usize;
-        !(queue_size > queue_max_size
-            || queue_size == 0
-            || (queue_size & (queue_size - 1)) != 0
-            || desc_addr
-                .checked_add(desc_table_size)
-                .map_or(true, |v| !self.mem().address_in_range(v))
-            || avail_addr
-                .checked_add(avail_ring_size)
-                .map_or(true, |v| !self.mem().address_in_range(v))
-            || used_addr
-                .checked_add(used_ring_size)
-                .map_or(true, |v| !self.mem().address_in_range(v)))
+        vring_in_memory(
+            self.mem(),
+            queue_max_size,
+            queue_size,
+            desc_addr,
+            avail_addr,
+            used_addr,
+        )
     }
 
     /// Set the addresses for a given vring.
@@ -0,0 +1,734 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The front-end of the vhost-user protocol, which hands the virtqueues of a device to a
+//! back-end running in another process.
+//!
+//! Messages go over a Unix socket, with the file descriptors they carry attached as ancillary
+//! data. The back-end maps the guest memory from the file descriptors of its regions, so the
+//! guest memory has to be backed by memfds. It is then kicked through the queue eventfds, and
+//! signals used buffers through the call eventfds.
+
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use libc;
+
+use super::{vring_in_memory, Error, Result};
+use memory_model::{GuestAddress, GuestMemory};
+use sys_util::EventFd;
+
+/// The virtio feature bit the back-end offers when it supports the protocol features.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+/// The back-end supports more than one queue.
+pub const VHOST_USER_PROTOCOL_F_MQ: u64 = 1 << 0;
+/// The back-end acknowledges the messages which ask for it.
+pub const VHOST_USER_PROTOCOL_F_REPLY_ACK: u64 = 1 << 3;
+/// The back-end serves the configuration space of the device.
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u64 = 1 << 9;
+
+const VHOST_USER_GET_FEATURES: u32 = 1;
+const VHOST_USER_SET_FEATURES: u32 = 2;
+const VHOST_USER_SET_OWNER: u32 = 3;
+const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+const VHOST_USER_SET_VRING_NUM: u32 = 8;
+const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+const VHOST_USER_SET_VRING_BASE: u32 = 10;
+const VHOST_USER_GET_VRING_BASE: u32 = 11;
+const VHOST_USER_SET_VRING_KICK: u32 = 12;
+const VHOST_USER_SET_VRING_CALL: u32 = 13;
+const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+const VHOST_USER_GET_QUEUE_NUM: u32 = 17;
+const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+const VHOST_USER_GET_CONFIG: u32 = 24;
+const VHOST_USER_SET_CONFIG: u32 = 25;
+
+// The version of the protocol, in the flags of every message.
+const VHOST_USER_VERSION: u32 = 0x1;
+const VHOST_USER_REPLY: u32 = 0x4;
+const VHOST_USER_NEED_REPLY: u32 = 0x8;
+const HEADER_SIZE: usize = 12;
+// The largest payload of the messages the back-end replies with.
+const MAX_REPLY_SIZE: usize = 0x1000;
+// The back-end maps at most this many memory regions.
+const MAX_MEM_REGIONS: usize = 8;
+const MEM_REGION_SIZE: usize = 32;
+// How long the back-end has to reply, before it is considered gone.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The connection to a vhost-user back-end.
+pub struct VhostUser {
+    stream: UnixStream,
+    mem: GuestMemory,
+    // Whether the messages without a reply ask the back-end to acknowledge them.
+    reply_ack: bool,
+}
+
+impl VhostUser {
+    /// Connects to the back-end listening on the socket at `path`, which gets access to `mem`.
+    /// All the regions of `mem` have to be backed by memfds.
+    pub fn connect(path: &Path, mem: &GuestMemory) -> Result<VhostUser> {
+        if (0..mem.num_regions()).any(|index| mem.region_fd(index).is_none()) {
+            return Err(Error::VhostUserMemoryNotShared);
+        }
+        let stream = UnixStream::connect(path).map_err(Error::VhostUserConnect)?;
+        stream
+            .set_read_timeout(Some(REPLY_TIMEOUT))
+            .map_err(Error::VhostUserConnect)?;
+        Ok(VhostUser {
+            stream,
+            mem: mem.clone(),
+            reply_ack: false,
+        })
+    }
+
+    /// Get the guest memory mapping.
+    pub fn mem(&self) -> &GuestMemory {
+        &self.mem
+    }
+
+    /// Set the current process as the owner of the session.
+    pub fn set_owner(&mut self) -> Result<()> {
+        self.send_request(VHOST_USER_SET_OWNER, &[], &[])
+    }
+
+    /// Get a bitmask of the virtio features of the back-end.
+    pub fn get_features(&mut self) -> Result<u64> {
+        self.get_u64(VHOST_USER_GET_FEATURES)
+    }
+
+    /// Inform the back-end which features to enable, a subset of the ones it offered.
+    ///
+    /// # Arguments
+    /// * `features` - Bitmask of features to set.
+    pub fn set_features(&mut self, features: u64) -> Result<()> {
+        self.send_request(VHOST_USER_SET_FEATURES, &features.to_le_bytes(), &[])
+    }
+
+    /// Get a bitmask of the protocol features of the back-end. Only for back-ends offering
+    /// `VHOST_USER_F_PROTOCOL_FEATURES`.
+    pub fn get_protocol_features(&mut self) -> Result<u64> {
+        self.get_u64(VHOST_USER_GET_PROTOCOL_FEATURES)
+    }
+
+    /// Inform the back-end which protocol features to enable, a subset of the ones it offered.
+    ///
+    /// # Arguments
+    /// * `features` - Bitmask of protocol features to set.
+    pub fn set_protocol_features(&mut self, features: u64) -> Result<()> {
+        self.send_request(
+            VHOST_USER_SET_PROTOCOL_FEATURES,
+            &features.to_le_bytes(),
+            &[],
+        )?;
+        self.reply_ack = features & VHOST_USER_PROTOCOL_F_REPLY_ACK != 0;
+        Ok(())
+    }
+
+    /// Get the number of queues the back-end supports. Only with `VHOST_USER_PROTOCOL_F_MQ`.
+    pub fn get_queue_num(&mut self) -> Result<u64> {
+        self.get_u64(VHOST_USER_GET_QUEUE_NUM)
+    }
+
+    /// Share the guest memory with the back-end, which maps the memfds of its regions.
+    pub fn set_mem_table(&mut self) -> Result<()> {
+        let num_regions = self.mem.num_regions();
+        if num_regions > MAX_MEM_REGIONS {
+            return Err(Error::VhostUserTooManyRegions);
+        }
+        let mut payload = vec![0u8; 8 + num_regions * MEM_REGION_SIZE];
+        payload[..4].copy_from_slice(&(num_regions as u32).to_le_bytes());
+        let mut fds = Vec::with_capacity(num_regions);
+        let mem = &self.mem;
+        mem.with_regions_mut(|index, guest_addr, size, host_addr| {
+            let fd = mem
+                .region_fd(index)
+                .ok_or(Error::VhostUserMemoryNotShared)?;
+            let region = &mut payload[8 + index * MEM_REGION_SIZE..][..MEM_REGION_SIZE];
+            region[..8].copy_from_slice(&(guest_addr.offset() as u64).to_le_bytes());
+            region[8..16].copy_from_slice(&(size as u64).to_le_bytes());
+            region[16..24].copy_from_slice(&(host_addr as u64).to_le_bytes());
+            // Each region is mapped from the start of its own memfd.
+            region[24..].copy_from_slice(&0u64.to_le_bytes());
+            fds.push(fd);
+            Ok(())
+        })?;
+        self.send_request(VHOST_USER_SET_MEM_TABLE, &payload, &fds)
+    }
+
+    /// Set the number of descriptors in the vring.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to set descriptor count for.
+    /// * `num` - Number of descriptors in the queue.
+    pub fn set_vring_num(&mut self, queue_index: usize, num: u16) -> Result<()> {
+        self.set_vring_state(VHOST_USER_SET_VRING_NUM, queue_index, u32::from(num))
+    }
+
+    /// Set the addresses for a given vring, which the back-end finds in the guest memory
+    /// through the addresses of the regions in this process.
+    ///
+    /// # Arguments
+    /// * `queue_max_size` - Maximum queue size supported by the device.
+    /// * `queue_size` - Actual queue size negotiated by the driver.
+    /// * `queue_index` - Index of the queue to set addresses for.
+    /// * `desc_table_addr` - Descriptor table address.
+    /// * `used_ring_addr` - Used ring buffer address.
+    /// * `avail_ring_addr` - Available ring buffer address.
+    pub fn set_vring_addr(
+        &mut self,
+        queue_max_size: u16,
+        queue_size: u16,
+        queue_index: usize,
+        desc_table_addr: GuestAddress,
+        used_ring_addr: GuestAddress,
+        avail_ring_addr: GuestAddress,
+    ) -> Result<()> {
+        if !vring_in_memory(
+            &self.mem,
+            queue_max_size,
+            queue_size,
+            desc_table_addr,
+            avail_ring_addr,
+            used_ring_addr,
+        ) {
+            return Err(Error::InvalidQueue);
+        }
+
+        let desc_addr = self
+            .mem
+            .get_host_address(desc_table_addr)
+            .map_err(Error::DescriptorTableAddress)?;
+        let used_addr = self
+            .mem
+            .get_host_address(used_ring_addr)
+            .map_err(Error::UsedAddress)?;
+        let avail_addr = self
+            .mem
+            .get_host_address(avail_ring_addr)
+            .map_err(Error::AvailAddress)?;
+
+        let mut payload = [0u8; 40];
+        payload[..4].copy_from_slice(&(queue_index as u32).to_le_bytes());
+        payload[8..16].copy_from_slice(&(desc_addr as u64).to_le_bytes());
+        payload[16..24].copy_from_slice(&(used_addr as u64).to_le_bytes());
+        payload[24..32].copy_from_slice(&(avail_addr as u64).to_le_bytes());
+        self.send_request(VHOST_USER_SET_VRING_ADDR, &payload, &[])
+    }
+
+    /// Set the first index to look for available descriptors.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to modify.
+    /// * `num` - Index where available descriptors start.
+    pub fn set_vring_base(&mut self, queue_index: usize, num: u16) -> Result<()> {
+        self.set_vring_state(VHOST_USER_SET_VRING_BASE, queue_index, u32::from(num))
+    }
+
+    /// Stop the vring, and get the index of the next available descriptor the back-end would
+    /// have processed.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to stop.
+    pub fn get_vring_base(&mut self, queue_index: usize) -> Result<u16> {
+        let mut payload = [0u8; 8];
+        payload[..4].copy_from_slice(&(queue_index as u32).to_le_bytes());
+        self.send_message(VHOST_USER_GET_VRING_BASE, 0, &payload, &[])?;
+        let reply = self.recv_reply(VHOST_USER_GET_VRING_BASE)?;
+        if reply.len() != 8 || reply[..4] != payload[..4] {
+            return Err(Error::VhostUserInvalidReply);
+        }
+        Ok(u32_from_le(&reply[4..]) as u16)
+    }
+
+    /// Set the eventfd the back-end triggers when buffers have been used.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to modify.
+    /// * `fd` - EventFd to trigger.
+    pub fn set_vring_call(&mut self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        let payload = (queue_index as u64).to_le_bytes();
+        self.send_request(VHOST_USER_SET_VRING_CALL, &payload, &[fd.as_raw_fd()])
+    }
+
+    /// Set the eventfd that will be signaled by the guest when buffers are available for the
+    /// back-end to process.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to modify.
+    /// * `fd` - EventFd that will be signaled from guest.
+    pub fn set_vring_kick(&mut self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        let payload = (queue_index as u64).to_le_bytes();
+        self.send_request(VHOST_USER_SET_VRING_KICK, &payload, &[fd.as_raw_fd()])
+    }
+
+    /// Enable or disable a vring. The vrings start disabled when the protocol features were
+    /// negotiated.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to modify.
+    /// * `enable` - Whether the back-end processes the queue.
+    pub fn set_vring_enable(&mut self, queue_index: usize, enable: bool) -> Result<()> {
+        self.set_vring_state(VHOST_USER_SET_VRING_ENABLE, queue_index, u32::from(enable))
+    }
+
+    /// Read `size` bytes of the configuration space of the device from `offset`. Only with
+    /// `VHOST_USER_PROTOCOL_F_CONFIG`.
+    pub fn get_config(&mut self, offset: u32, size: u32) -> Result<Vec<u8>> {
+        let mut payload = config_header(offset, size);
+        payload.resize(payload.len() + size as usize, 0);
+        self.send_message(VHOST_USER_GET_CONFIG, 0, &payload, &[])?;
+        let reply = self.recv_reply(VHOST_USER_GET_CONFIG)?;
+        if reply.len() != payload.len() || reply[..8] != payload[..8] {
+            return Err(Error::VhostUserInvalidReply);
+        }
+        Ok(reply[12..].to_vec())
+    }
+
+    /// Write `data` to the configuration space of the device at `offset`. Only with
+    /// `VHOST_USER_PROTOCOL_F_CONFIG`.
+    pub fn set_config(&mut self, offset: u32, data: &[u8]) -> Result<()> {
+        let mut payload = config_header(offset, data.len() as u32);
+        payload.extend_from_slice(data);
+        self.send_request(VHOST_USER_SET_CONFIG, &payload, &[])
+    }
+
+    fn set_vring_state(&mut self, request: u32, queue_index: usize, num: u32) -> Result<()> {
+        let mut payload = [0u8; 8];
+        payload[..4].copy_from_slice(&(queue_index as u32).to_le_bytes());
+        payload[4..].copy_from_slice(&num.to_le_bytes());
+        self.send_request(request, &payload, &[])
+    }
+
+    fn get_u64(&mut self, request: u32) -> Result<u64> {
+        self.send_message(request, 0, &[], &[])?;
+        let reply = self.recv_reply(request)?;
+        if reply.len() != 8 {
+            return Err(Error::VhostUserInvalidReply);
+        }
+        Ok(u64_from_le(&reply))
+    }
+
+    // Sends a request the back-end does not reply to, unless asked to acknowledge it.
+    fn send_request(&mut self, request: u32, payload: &[u8], fds: &[RawFd]) -> Result<()> {
+        if !self.reply_ack {
+            return self.send_message(request, 0, payload, fds);
+        }
+        self.send_message(request, VHOST_USER_NEED_REPLY, payload, fds)?;
+        let reply = self.recv_reply(request)?;
+        if reply.len() != 8 {
+            return Err(Error::VhostUserInvalidReply);
+        }
+        match u64_from_le(&reply) {
+            0 => Ok(()),
+            _ => Err(Error::VhostUserRequestFailed(request)),
+        }
+    }
+
+    fn send_message(
+        &mut self,
+        request: u32,
+        flags: u32,
+        payload: &[u8],
+        fds: &[RawFd],
+    ) -> Result<()> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..4].copy_from_slice(&request.to_le_bytes());
+        header[4..8].copy_from_slice(&(VHOST_USER_VERSION | flags).to_le_bytes());
+        header[8..].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        let mut iovecs = [
+            libc::iovec {
+                iov_base: header.as_mut_ptr() as *mut libc::c_void,
+                iov_len: header.len(),
+            },
+            libc::iovec {
+                iov_base: payload.as_ptr() as *mut libc::c_void,
+                iov_len: payload.len(),
+            },
+        ];
+
+        let fds_len = mem::size_of_val(fds);
+        // u64 keeps the control messages aligned.
+        let mut control = vec![0u64; 8];
+        // Safe because the msghdr only points to buffers which outlive the call, with their
+        // sizes, and the control buffer fits the file descriptors, which are at most 8.
+        let sent = unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = iovecs.as_mut_ptr();
+            msg.msg_iovlen = if payload.is_empty() { 1 } else { 2 };
+            if !fds.is_empty() {
+                msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                msg.msg_controllen = libc::CMSG_SPACE(fds_len as u32) as usize;
+                let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                (*cmsg).cmsg_level = libc::SOL_SOCKET;
+                (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as u32) as usize;
+                std::ptr::copy_nonoverlapping(
+                    fds.as_ptr(),
+                    libc::CMSG_DATA(cmsg) as *mut RawFd,
+                    fds.len(),
+                );
+            }
+            libc::sendmsg(self.stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+        };
+        if sent < 0 {
+            return Err(Error::VhostUserSocket(io::Error::last_os_error()));
+        }
+        // The socket buffer takes whole messages of these sizes.
+        if sent as usize != header.len() + payload.len() {
+            return Err(Error::VhostUserSocket(io::Error::from(
+                io::ErrorKind::WriteZero,
+            )));
+        }
+        Ok(())
+    }
+
+    // Receives the reply to `request`, and returns its payload.
+    fn recv_reply(&mut self, request: u32) -> Result<Vec<u8>> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.stream
+            .read_exact(&mut header)
+            .map_err(Error::VhostUserSocket)?;
+        let size = u32_from_le(&header[8..]) as usize;
+        if u32_from_le(&header[..4]) != request
+            || u32_from_le(&header[4..8]) != VHOST_USER_VERSION | VHOST_USER_REPLY
+            || size > MAX_REPLY_SIZE
+        {
+            return Err(Error::VhostUserInvalidReply);
+        }
+        let mut payload = vec![0u8; size];
+        self.stream
+            .read_exact(&mut payload)
+            .map_err(Error::VhostUserSocket)?;
+        Ok(payload)
+    }
+}
+
+fn config_header(offset: u32, size: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(12 + size as usize);
+    header.extend_from_slice(&offset.to_le_bytes());
+    header.extend_from_slice(&size.to_le_bytes());
+    // The flags only matter to live migration.
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header
+}
+
+fn u32_from_le(bytes: &[u8]) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(buf)
+}
+
+fn u64_from_le(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+impl AsRawFd for VhostUser {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    use memory_model::MemoryBacking;
+
+    const FEATURES: u64 = VHOST_USER_F_PROTOCOL_FEATURES | 0x1_0000_0201;
+    const PROTOCOL_FEATURES: u64 = VHOST_USER_PROTOCOL_F_REPLY_ACK | VHOST_USER_PROTOCOL_F_CONFIG;
+
+    struct Message {
+        request: u32,
+        flags: u32,
+        payload: Vec<u8>,
+        fds: Vec<File>,
+    }
+
+    fn recv_message(stream: &UnixStream) -> Option<Message> {
+        let mut header = [0u8; HEADER_SIZE];
+        let mut payload = vec![0u8; MAX_REPLY_SIZE];
+        let mut iovecs = [
+            libc::iovec {
+                iov_base: header.as_mut_ptr() as *mut libc::c_void,
+                iov_len: header.len(),
+            },
+            libc::iovec {
+                iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+                iov_len: payload.len(),
+            },
+        ];
+        let mut control = vec![0u64; 8];
+        let mut fds = Vec::new();
+        // Safe because the buffers outlive the call, and the file descriptors received are
+        // owned by the Files they are put into.
+        unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = iovecs.as_mut_ptr();
+            msg.msg_iovlen = 2;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = control.len() * 8;
+            let len = libc::recvmsg(stream.as_raw_fd(), &mut msg, 0);
+            if len <= 0 {
+                return None;
+            }
+            payload.truncate(len as usize - HEADER_SIZE);
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            if !cmsg.is_null() {
+                let count = ((*cmsg).cmsg_len - libc::CMSG_LEN(0) as usize) / 4;
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..count {
+                    fds.push(File::from_raw_fd(*data.add(i)));
+                }
+            }
+        }
+        assert_eq!(u32_from_le(&header[8..]) as usize, payload.len());
+        Some(Message {
+            request: u32_from_le(&header[..4]),
+            flags: u32_from_le(&header[4..8]),
+            payload,
+            fds,
+        })
+    }
+
+    // Serves the requests of a front-end, and passes them on to the test. The requests to
+    // enable a vring fail.
+    fn backend(mut stream: UnixStream) -> Receiver<Message> {
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            while let Some(message) = recv_message(&stream) {
+                let ack = message.flags & VHOST_USER_NEED_REPLY != 0;
+                let reply = match message.request {
+                    VHOST_USER_GET_FEATURES => Some(FEATURES.to_le_bytes().to_vec()),
+                    VHOST_USER_GET_PROTOCOL_FEATURES => {
+                        Some(PROTOCOL_FEATURES.to_le_bytes().to_vec())
+                    }
+                    VHOST_USER_GET_VRING_BASE => {
+                        let mut reply = message.payload.clone();
+                        reply[4..].copy_from_slice(&7u32.to_le_bytes());
+                        Some(reply)
+                    }
+                    VHOST_USER_GET_CONFIG => {
+                        let mut reply = message.payload.clone();
+                        for (i, byte) in reply[12..].iter_mut().enumerate() {
+                            *byte = i as u8;
+                        }
+                        Some(reply)
+                    }
+                    VHOST_USER_SET_VRING_ENABLE if ack => Some(1u64.to_le_bytes().to_vec()),
+                    _ if ack => Some(0u64.to_le_bytes().to_vec()),
+                    _ => None,
+                };
+                if let Some(reply) = reply {
+                    let mut header = [0u8; HEADER_SIZE];
+                    header[..4].copy_from_slice(&message.request.to_le_bytes());
+                    header[4..8]
+                        .copy_from_slice(&(VHOST_USER_VERSION | VHOST_USER_REPLY).to_le_bytes());
+                    header[8..].copy_from_slice(&(reply.len() as u32).to_le_bytes());
+                    stream.write_all(&header).unwrap();
+                    stream.write_all(&reply).unwrap();
+                }
+                sender.send(message).unwrap();
+            }
+        });
+        receiver
+    }
+
+    fn connect(backing: MemoryBacking) -> (VhostUser, Receiver<Message>) {
+        let mem = GuestMemory::new(
+            &[(GuestAddress(0), 0x10000), (GuestAddress(0x20000), 0x10000)],
+            backing,
+        )
+        .unwrap();
+        let (stream, backend_stream) = UnixStream::pair().unwrap();
+        let vhost_user = VhostUser {
+            stream,
+            mem,
+            reply_ack: false,
+        };
+        (vhost_user, backend(backend_stream))
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let (mut vhost_user, messages) = connect(MemoryBacking::Anonymous);
+        assert_eq!(vhost_user.get_features().unwrap(), FEATURES);
+        vhost_user.set_features(FEATURES).unwrap();
+        let message = messages.recv().unwrap();
+        assert_eq!(message.request, VHOST_USER_GET_FEATURES);
+        assert_eq!(message.flags, VHOST_USER_VERSION);
+        let message = messages.recv().unwrap();
+        assert_eq!(message.request, VHOST_USER_SET_FEATURES);
+        assert_eq!(message.payload, FEATURES.to_le_bytes());
+
+        assert_eq!(
+            vhost_user.get_protocol_features().unwrap(),
+            PROTOCOL_FEATURES
+        );
+        vhost_user.set_protocol_features(PROTOCOL_FEATURES).unwrap();
+        messages.recv().unwrap();
+        messages.recv().unwrap();
+
+        // Once acknowledgements are negotiated, the requests wait for them.
+        vhost_user.set_owner().unwrap();
+        let message = messages.recv().unwrap();
+        assert_eq!(message.request, VHOST_USER_SET_OWNER);
+        assert_eq!(message.flags, VHOST_USER_VERSION | VHOST_USER_NEED_REPLY);
+        match vhost_user.set_vring_enable(1, true) {
+            Err(Error::VhostUserRequestFailed(VHOST_USER_SET_VRING_ENABLE)) => (),
+            _ => panic!("The back-end fails the request."),
+        }
+        assert_eq!(messages.recv().unwrap().payload, [1, 0, 0, 0, 1, 0, 0, 0]);
+
+        assert_eq!(vhost_user.get_config(4, 6).unwrap(), [0, 1, 2, 3, 4, 5]);
+        let message = messages.recv().unwrap();
+        assert_eq!(
+            &message.payload[..12],
+            &[4, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0]
+        );
+        vhost_user.set_config(32, &[1]).unwrap();
+        let message = messages.recv().unwrap();
+        assert_eq!(message.request, VHOST_USER_SET_CONFIG);
+        assert_eq!(&message.payload[12..], &[1]);
+    }
+
+    #[test]
+    fn test_vrings() {
+        let (mut vhost_user, messages) = connect(MemoryBacking::Anonymous);
+        vhost_user.set_vring_num(1, 256).unwrap();
+        assert_eq!(messages.recv().unwrap().payload, [1, 0, 0, 0, 0, 1, 0, 0]);
+
+        let host_addr = |addr| {
+            vhost_user
+                .mem()
+                .get_host_address(GuestAddress(addr))
+                .unwrap()
+        };
+        let (desc, used, avail) = (host_addr(0x1000), host_addr(0x3000), host_addr(0x2000));
+        vhost_user
+            .set_vring_addr(
+                256,
+                256,
+                1,
+                GuestAddress(0x1000),
+                GuestAddress(0x3000),
+                GuestAddress(0x2000),
+            )
+            .unwrap();
+        let payload = messages.recv().unwrap().payload;
+        assert_eq!(u64_from_le(&payload[8..]), desc as u64);
+        assert_eq!(u64_from_le(&payload[16..]), used as u64);
+        assert_eq!(u64_from_le(&payload[24..]), avail as u64);
+        // The rings have to be in the guest memory.
+        match vhost_user.set_vring_addr(
+            256,
+            256,
+            1,
+            GuestAddress(0xf000),
+            GuestAddress(0x3000),
+            GuestAddress(0x2000),
+        ) {
+            Err(Error::InvalidQueue) => (),
+            _ => panic!("The descriptor table is out of the guest memory."),
+        }
+
+        vhost_user.set_vring_base(1, 0).unwrap();
+        messages.recv().unwrap();
+        assert_eq!(vhost_user.get_vring_base(1).unwrap(), 7);
+        messages.recv().unwrap();
+
+        // The back-end gets the eventfds themselves.
+        let kick = EventFd::new().unwrap();
+        vhost_user.set_vring_kick(1, &kick).unwrap();
+        let mut message = messages.recv().unwrap();
+        assert_eq!(message.payload, 1u64.to_le_bytes());
+        assert_eq!(message.fds.len(), 1);
+        message.fds[0].write_all(&1u64.to_le_bytes()).unwrap();
+        assert_eq!(kick.read().unwrap(), 1);
+        let call = EventFd::new().unwrap();
+        vhost_user.set_vring_call(1, &call).unwrap();
+        let mut message = messages.recv().unwrap();
+        message.fds[0].write_all(&2u64.to_le_bytes()).unwrap();
+        assert_eq!(call.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_mem_table() {
+        let (mut vhost_user, _messages) = connect(MemoryBacking::Anonymous);
+        match vhost_user.set_mem_table() {
+            Err(Error::VhostUserMemoryNotShared) => (),
+            _ => panic!("Anonymous memory cannot be shared."),
+        }
+        match VhostUser::connect(Path::new("/foo/vhost-user.sock"), vhost_user.mem()) {
+            Err(Error::VhostUserMemoryNotShared) => (),
+            _ => panic!("Anonymous memory cannot be shared."),
+        }
+
+        let (mut vhost_user, messages) = connect(MemoryBacking::Memfd { hugepages: false });
+        vhost_user.set_mem_table().unwrap();
+        let message = messages.recv().unwrap();
+        let payload = message.payload;
+        assert_eq!(u32_from_le(&payload), 2);
+        assert_eq!(message.fds.len(), 2);
+        let region = &payload[8 + MEM_REGION_SIZE..];
+        assert_eq!(u64_from_le(region), 0x20000);
+        assert_eq!(u64_from_le(&region[8..]), 0x10000);
+        assert_eq!(
+            u64_from_le(&region[16..]),
+            vhost_user
+                .mem()
+                .get_host_address(GuestAddress(0x20000))
+                .unwrap() as u64
+        );
+        // The back-end sees the guest memory through the memfds.
+        vhost_user
+            .mem()
+            .write_obj_at_addr(0x1234_5678u32, GuestAddress(0x20010))
+            .unwrap();
+        let mut buf = [0u8; 4];
+        message.fds[1].read_exact_at(&mut buf, 0x10).unwrap();
+        assert_eq!(u32::from_le_bytes(buf), 0x1234_5678);
+    }
+
+    #[test]
+    fn test_invalid_reply() {
+        let (stream, mut backend_stream) = UnixStream::pair().unwrap();
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
+        let mut vhost_user = VhostUser {
+            stream,
+            mem,
+            reply_ack: false,
+        };
+        // A reply to another request.
+        let mut header = [0u8; HEADER_SIZE];
+        header[..4].copy_from_slice(&VHOST_USER_GET_QUEUE_NUM.to_le_bytes());
+        header[4..8].copy_from_slice(&(VHOST_USER_VERSION | VHOST_USER_REPLY).to_le_bytes());
+        header[8..].copy_from_slice(&8u32.to_le_bytes());
+        backend_stream.write_all(&header).unwrap();
+        backend_stream.write_all(&[0; 8]).unwrap();
+        match vhost_user.get_features() {
+            Err(Error::VhostUserInvalidReply) => (),
+            _ => panic!("The reply is for another request."),
+        }
+        // The back-end went away.
+        drop(backend_stream);
+        match vhost_user.get_queue_num() {
+            Err(Error::VhostUserSocket(_)) => (),
+            _ => panic!("There is no back-end to reply."),
+        }
+    }
+}
@@ -10,6 +10,7 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
+pub mod virtio_balloon;
 pub mod virtio_blk;
 pub mod virtio_net;
 pub mod virtio_ring;
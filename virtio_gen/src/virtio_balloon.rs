@@ -0,0 +1,27 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+/* automatically generated by rust-bindgen */
+
+pub const VIRTIO_ID_BALLOON: u32 = 5;
+pub const VIRTIO_F_NOTIFY_ON_EMPTY: u32 = 24;
+pub const VIRTIO_F_ANY_LAYOUT: u32 = 27;
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+pub const VIRTIO_BALLOON_F_MUST_TELL_HOST: u32 = 0;
+pub const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1;
+pub const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2;
+pub const VIRTIO_BALLOON_F_FREE_PAGE_HINT: u32 = 3;
+pub const VIRTIO_BALLOON_F_PAGE_POISON: u32 = 4;
+pub const VIRTIO_BALLOON_F_REPORTING: u32 = 5;
+pub const VIRTIO_BALLOON_PFN_SHIFT: u32 = 12;
+pub const VIRTIO_BALLOON_S_SWAP_IN: u32 = 0;
+pub const VIRTIO_BALLOON_S_SWAP_OUT: u32 = 1;
+pub const VIRTIO_BALLOON_S_MAJFLT: u32 = 2;
+pub const VIRTIO_BALLOON_S_MINFLT: u32 = 3;
+pub const VIRTIO_BALLOON_S_MEMFREE: u32 = 4;
+pub const VIRTIO_BALLOON_S_MEMTOT: u32 = 5;
+pub const VIRTIO_BALLOON_S_AVAIL: u32 = 6;
+pub const VIRTIO_BALLOON_S_CACHES: u32 = 7;
+pub const VIRTIO_BALLOON_S_HTLB_PGALLOC: u32 = 8;
+pub const VIRTIO_BALLOON_S_HTLB_PGFAIL: u32 = 9;
+pub const VIRTIO_BALLOON_S_NR: u32 = 10;
@@ -0,0 +1,384 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Writes the guest memory and the registers of the vCPUs to an ELF64 core file, in the layout
+//! `crash` and `gdb` expect from a physical memory dump.
+//!
+//! The file holds a `PT_NOTE` segment with one `NT_PRSTATUS` note per vCPU, followed by one
+//! `PT_LOAD` segment per guest memory region. The load segments are addressed by guest physical
+//! address, in both `p_paddr` and `p_vaddr`.
+
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::result;
+
+use kvm_bindings::{kvm_regs, kvm_sregs};
+
+use memory_model::{GuestAddress, GuestMemory};
+use snapshot;
+use vmm_config::coredump::CoreDumpError;
+
+type Result<T> = result::Result<T, CoreDumpError>;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+const NOTE_NAME: &[u8] = b"CORE\0";
+
+// Layout of `struct elf_prstatus` on x86_64.
+const PRSTATUS_SIZE: usize = 336;
+const PRSTATUS_PID_OFFSET: usize = 32;
+const PRSTATUS_REGS_OFFSET: usize = 112;
+
+// The memory segments start on a page boundary, so that the file can be mapped.
+const SEGMENT_ALIGN: usize = 4096;
+
+/// Writes a core file of the guest to `path`. The vCPUs must be paused, and `vcpu_registers`
+/// holds their registers in the order of the vCPU ids.
+pub fn write_core_dump(
+    path: &Path,
+    guest_memory: &GuestMemory,
+    vcpu_registers: &[(kvm_regs, kvm_sregs)],
+) -> Result<()> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(CoreDumpError::DumpFile)?;
+    let mut writer = BufWriter::new(file);
+    write_core(&mut writer, guest_memory, vcpu_registers)?;
+    writer
+        .into_inner()
+        .map_err(|e| CoreDumpError::DumpFile(e.into()))?
+        .sync_all()
+        .map_err(CoreDumpError::DumpFile)
+}
+
+fn write_core<W: Write>(
+    out: &mut W,
+    guest_memory: &GuestMemory,
+    vcpu_registers: &[(kvm_regs, kvm_sregs)],
+) -> Result<()> {
+    let regions = snapshot::memory_layout(guest_memory);
+    let program_header_count = regions.len() + 1;
+
+    let mut notes = Vec::new();
+    for (id, &(ref regs, ref sregs)) in vcpu_registers.iter().enumerate() {
+        // The note of each vCPU shows up as a thread, and thread ids start at 1.
+        write_note(
+            &mut notes,
+            NT_PRSTATUS,
+            &prstatus(id as u32 + 1, regs, sregs),
+        );
+    }
+
+    let notes_offset = ELF_HEADER_SIZE + program_header_count * PROGRAM_HEADER_SIZE;
+    let memory_offset = align_up(notes_offset + notes.len(), SEGMENT_ALIGN);
+
+    let mut headers = Vec::with_capacity(memory_offset);
+    write_elf_header(&mut headers, program_header_count as u16);
+    write_program_header(
+        &mut headers,
+        &ProgramHeader {
+            p_type: PT_NOTE,
+            p_flags: 0,
+            p_offset: notes_offset as u64,
+            p_addr: 0,
+            p_size: notes.len() as u64,
+            p_align: 0,
+        },
+    );
+    let mut offset = memory_offset;
+    for region in regions.iter() {
+        write_program_header(
+            &mut headers,
+            &ProgramHeader {
+                p_type: PT_LOAD,
+                p_flags: PF_R | PF_W | PF_X,
+                p_offset: offset as u64,
+                p_addr: region.guest_addr,
+                p_size: region.size as u64,
+                p_align: SEGMENT_ALIGN as u64,
+            },
+        );
+        offset += region.size;
+    }
+    headers.extend_from_slice(&notes);
+    headers.resize(memory_offset, 0);
+    out.write_all(&headers).map_err(CoreDumpError::DumpFile)?;
+
+    for region in regions.iter() {
+        guest_memory
+            .write_from_memory(
+                GuestAddress(region.guest_addr as usize),
+                &mut *out,
+                region.size,
+            )
+            .map_err(CoreDumpError::GuestMemory)?;
+    }
+    Ok(())
+}
+
+struct ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    // Used as both the physical and the virtual address.
+    p_addr: u64,
+    // Used as both the size in the file and in memory.
+    p_size: u64,
+    p_align: u64,
+}
+
+fn write_elf_header(buf: &mut Vec<u8>, program_header_count: u16) {
+    buf.extend_from_slice(&[0x7f, b'E', b'L', b'F', ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+    // The OS ABI, its version and the padding of `e_ident`.
+    buf.extend_from_slice(&[0; 9]);
+    buf.extend_from_slice(&ET_CORE.to_le_bytes());
+    buf.extend_from_slice(&EM_X86_64.to_le_bytes());
+    buf.extend_from_slice(&u32::from(EV_CURRENT).to_le_bytes());
+    // e_entry
+    buf.extend_from_slice(&0u64.to_le_bytes());
+    // e_phoff
+    buf.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    // e_shoff
+    buf.extend_from_slice(&0u64.to_le_bytes());
+    // e_flags
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    buf.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    buf.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    buf.extend_from_slice(&program_header_count.to_le_bytes());
+    // e_shentsize, e_shnum and e_shstrndx: there are no sections.
+    buf.extend_from_slice(&[0; 6]);
+}
+
+fn write_program_header(buf: &mut Vec<u8>, header: &ProgramHeader) {
+    buf.extend_from_slice(&header.p_type.to_le_bytes());
+    buf.extend_from_slice(&header.p_flags.to_le_bytes());
+    buf.extend_from_slice(&header.p_offset.to_le_bytes());
+    // p_vaddr and p_paddr
+    buf.extend_from_slice(&header.p_addr.to_le_bytes());
+    buf.extend_from_slice(&header.p_addr.to_le_bytes());
+    // p_filesz and p_memsz
+    buf.extend_from_slice(&header.p_size.to_le_bytes());
+    buf.extend_from_slice(&header.p_size.to_le_bytes());
+    buf.extend_from_slice(&header.p_align.to_le_bytes());
+}
+
+// The name and the descriptor of a note are padded to 4 bytes.
+fn write_note(buf: &mut Vec<u8>, note_type: u32, desc: &[u8]) {
+    buf.extend_from_slice(&(NOTE_NAME.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&note_type.to_le_bytes());
+    buf.extend_from_slice(NOTE_NAME);
+    let len = align_up(buf.len(), 4);
+    buf.resize(len, 0);
+    buf.extend_from_slice(desc);
+    let len = align_up(buf.len(), 4);
+    buf.resize(len, 0);
+}
+
+// Builds a `struct elf_prstatus` which only holds the pid and the general purpose registers,
+// in the order of `struct user_regs_struct`.
+fn prstatus(pid: u32, regs: &kvm_regs, sregs: &kvm_sregs) -> Vec<u8> {
+    let user_regs = [
+        regs.r15,
+        regs.r14,
+        regs.r13,
+        regs.r12,
+        regs.rbp,
+        regs.rbx,
+        regs.r11,
+        regs.r10,
+        regs.r9,
+        regs.r8,
+        regs.rax,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        // orig_rax
+        0,
+        regs.rip,
+        u64::from(sregs.cs.selector),
+        regs.rflags,
+        regs.rsp,
+        u64::from(sregs.ss.selector),
+        sregs.fs.base,
+        sregs.gs.base,
+        u64::from(sregs.ds.selector),
+        u64::from(sregs.es.selector),
+        u64::from(sregs.fs.selector),
+        u64::from(sregs.gs.selector),
+    ];
+
+    let mut desc = vec![0u8; PRSTATUS_SIZE];
+    desc[PRSTATUS_PID_OFFSET..PRSTATUS_PID_OFFSET + 4].copy_from_slice(&pid.to_le_bytes());
+    for (index, value) in user_regs.iter().enumerate() {
+        let offset = PRSTATUS_REGS_OFFSET + index * 8;
+        desc[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+    desc
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::NamedTempFile;
+    use super::*;
+
+    use std::fs;
+
+    use memory_model::MemoryBacking;
+
+    fn read_u16(buf: &[u8], offset: usize) -> u16 {
+        let mut bytes = [0u8; 2];
+        bytes.copy_from_slice(&buf[offset..offset + 2]);
+        u16::from_le_bytes(bytes)
+    }
+
+    fn read_u32(buf: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&buf[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn read_u64(buf: &[u8], offset: usize) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&buf[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn test_prstatus() {
+        let mut regs = kvm_regs::default();
+        regs.r15 = 0x15;
+        regs.rip = 0xffff_ffff_8100_0000;
+        regs.rsp = 0x8000;
+        let mut sregs = kvm_sregs::default();
+        sregs.cs.selector = 0x10;
+        sregs.gs.base = 0xffff_8880_0000_0000;
+
+        let desc = prstatus(3, &regs, &sregs);
+        assert_eq!(desc.len(), PRSTATUS_SIZE);
+        assert_eq!(read_u32(&desc, PRSTATUS_PID_OFFSET), 3);
+        assert_eq!(read_u64(&desc, PRSTATUS_REGS_OFFSET), 0x15);
+        assert_eq!(
+            read_u64(&desc, PRSTATUS_REGS_OFFSET + 16 * 8),
+            0xffff_ffff_8100_0000
+        );
+        assert_eq!(read_u64(&desc, PRSTATUS_REGS_OFFSET + 17 * 8), 0x10);
+        assert_eq!(read_u64(&desc, PRSTATUS_REGS_OFFSET + 19 * 8), 0x8000);
+        assert_eq!(
+            read_u64(&desc, PRSTATUS_REGS_OFFSET + 22 * 8),
+            0xffff_8880_0000_0000
+        );
+    }
+
+    #[test]
+    fn test_write_note() {
+        let mut buf = Vec::new();
+        write_note(&mut buf, NT_PRSTATUS, &[1, 2, 3, 4, 5]);
+        assert_eq!(buf.len(), 12 + 8 + 8);
+        assert_eq!(read_u32(&buf, 0), 5);
+        assert_eq!(read_u32(&buf, 4), 5);
+        assert_eq!(read_u32(&buf, 8), NT_PRSTATUS);
+        assert_eq!(&buf[12..17], NOTE_NAME);
+        assert_eq!(&buf[20..25], &[1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_write_core_dump() {
+        let guest_memory = GuestMemory::new(
+            &[(GuestAddress(0), 0x2000), (GuestAddress(0x10_0000), 0x1000)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
+        guest_memory
+            .write_slice_at_addr(b"first", GuestAddress(0x1000))
+            .unwrap();
+        guest_memory
+            .write_slice_at_addr(b"second", GuestAddress(0x10_0000))
+            .unwrap();
+        let mut regs = kvm_regs::default();
+        regs.rip = 0x10_0000;
+        let vcpu_registers = vec![(regs, kvm_sregs::default()); 2];
+
+        let file = NamedTempFile::new().unwrap();
+        write_core_dump(file.path(), &guest_memory, &vcpu_registers).unwrap();
+        let core = fs::read(file.path()).unwrap();
+
+        // The ELF header.
+        assert_eq!(&core[..4], b"\x7fELF");
+        assert_eq!(core[4], ELFCLASS64);
+        assert_eq!(core[5], ELFDATA2LSB);
+        assert_eq!(read_u16(&core, 16), ET_CORE);
+        assert_eq!(read_u16(&core, 18), EM_X86_64);
+        assert_eq!(read_u64(&core, 32), ELF_HEADER_SIZE as u64);
+        assert_eq!(read_u16(&core, 54), PROGRAM_HEADER_SIZE as u16);
+        assert_eq!(read_u16(&core, 56), 3);
+
+        // The note segment holds one NT_PRSTATUS per vCPU.
+        let note = ELF_HEADER_SIZE;
+        assert_eq!(read_u32(&core, note), PT_NOTE);
+        let notes_offset = read_u64(&core, note + 8) as usize;
+        let notes_size = read_u64(&core, note + 32) as usize;
+        assert_eq!(notes_offset, ELF_HEADER_SIZE + 3 * PROGRAM_HEADER_SIZE);
+        assert_eq!(notes_size, 2 * (12 + 8 + PRSTATUS_SIZE));
+        for id in 0..2 {
+            let desc = notes_offset + id * (12 + 8 + PRSTATUS_SIZE) + 12 + 8;
+            assert_eq!(read_u32(&core, desc - 20 + 8), NT_PRSTATUS);
+            assert_eq!(read_u32(&core, desc + PRSTATUS_PID_OFFSET), id as u32 + 1);
+            assert_eq!(
+                read_u64(&core, desc + PRSTATUS_REGS_OFFSET + 16 * 8),
+                0x10_0000
+            );
+        }
+
+        // One load segment per memory region, in page aligned file offsets.
+        let expected = [(0x1000, 0, 0x2000), (0x3000, 0x10_0000, 0x1000)];
+        for (index, &(offset, addr, size)) in expected.iter().enumerate() {
+            let load = ELF_HEADER_SIZE + (index + 1) * PROGRAM_HEADER_SIZE;
+            assert_eq!(read_u32(&core, load), PT_LOAD);
+            assert_eq!(read_u32(&core, load + 4), PF_R | PF_W | PF_X);
+            assert_eq!(read_u64(&core, load + 8), offset);
+            assert_eq!(read_u64(&core, load + 16), addr);
+            assert_eq!(read_u64(&core, load + 24), addr);
+            assert_eq!(read_u64(&core, load + 32), size);
+            assert_eq!(read_u64(&core, load + 40), size);
+        }
+        assert_eq!(core.len(), 0x4000);
+        assert_eq!(&core[0x2000..0x2005], b"first");
+        assert_eq!(&core[0x3000..0x3006], b"second");
+    }
+
+    #[test]
+    fn test_write_core_dump_errors() {
+        let guest_memory =
+            GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
+        match write_core_dump(Path::new("/foo/bar/core"), &guest_memory, &[]) {
+            Err(CoreDumpError::DumpFile(_)) => (),
+            _ => panic!("Writing a core dump to a missing directory should fail."),
+        }
+    }
+}
@@ -19,10 +19,12 @@ const FCNTL_F_SETFD: u64 = 2;
 const FUTEX_WAIT: u64 = 0;
 const FUTEX_WAKE: u64 = 1;
 const FUTEX_REQUEUE: u64 = 3;
+const FUTEX_WAIT_BITSET: u64 = 9;
 const FUTEX_PRIVATE_FLAG: u64 = 128;
 const FUTEX_WAIT_PRIVATE: u64 = FUTEX_WAIT | FUTEX_PRIVATE_FLAG;
 const FUTEX_WAKE_PRIVATE: u64 = FUTEX_WAKE | FUTEX_PRIVATE_FLAG;
 const FUTEX_REQUEUE_PRIVATE: u64 = FUTEX_REQUEUE | FUTEX_PRIVATE_FLAG;
+const FUTEX_WAIT_BITSET_PRIVATE: u64 = FUTEX_WAIT_BITSET | FUTEX_PRIVATE_FLAG;
 
 // See include/uapi/asm-generic/ioctls.h in the kernel code.
 const TCGETS: u64 = 0x5401;
@@ -54,6 +56,24 @@ const KVM_SET_LAPIC: u64 = 0x4400_ae8f;
 const KVM_GET_SREGS: u64 = 0x8138_ae83;
 const KVM_GET_LAPIC: u64 = 0x8400_ae8e;
 const KVM_GET_SUPPORTED_CPUID: u64 = 0xc008_ae05;
+const KVM_GET_CLOCK: u64 = 0x8030_ae7c;
+const KVM_GET_REGS: u64 = 0x8090_ae81;
+const KVM_GET_MSRS: u64 = 0xc008_ae88;
+const KVM_GET_MP_STATE: u64 = 0x8004_ae98;
+const KVM_GET_FPU: u64 = 0x81a0_ae8c;
+const KVM_GET_PIT2: u64 = 0x8070_ae9f;
+const KVM_GET_VCPU_EVENTS: u64 = 0x8040_ae9f;
+const KVM_GET_XSAVE: u64 = 0x9000_aea4;
+const KVM_GET_XCRS: u64 = 0x8188_aea6;
+const KVM_GET_IRQCHIP: u64 = 0xc208_ae62;
+const KVM_SET_CLOCK: u64 = 0x4030_ae7b;
+const KVM_SET_PIT2: u64 = 0x4070_aea0;
+const KVM_SET_VCPU_EVENTS: u64 = 0x4040_aea0;
+const KVM_SET_XSAVE: u64 = 0x5000_aea5;
+const KVM_SET_XCRS: u64 = 0x4188_aea7;
+const KVM_SET_IRQCHIP: u64 = 0x8208_ae63;
+const KVM_SET_MP_STATE: u64 = 0x4004_ae99;
+const KVM_SET_GUEST_DEBUG: u64 = 0x4048_ae9b;
 
 // See include/uapi/linux/if_tun.h in the kernel code.
 const TUNSETIFF: u64 = 0x4004_54ca;
@@ -123,8 +143,15 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_accept4),
             allow_syscall(libc::SYS_brk),
             allow_syscall(libc::SYS_clock_gettime),
+            // Used for backing off between the attempts to reconnect to the NBD server of a drive.
+            allow_syscall(libc::SYS_clock_nanosleep),
             allow_syscall(libc::SYS_close),
+            // Used for connecting to the destination of a migration, and to the NBD servers and
+            // vhost-user back-ends of drives.
+            allow_syscall(libc::SYS_connect),
             allow_syscall(libc::SYS_dup),
+            // Used for resetting the device events when the guest reboots.
+            allow_syscall(libc::SYS_epoll_create1),
             allow_syscall_if(
                 libc::SYS_epoll_ctl,
                 or![
@@ -138,6 +165,22 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_epoll_wait),
             allow_syscall(libc::SYS_exit),
             allow_syscall(libc::SYS_exit_group),
+            // Used by the block devices for the discard and write zeroes requests.
+            allow_syscall_if(
+                libc::SYS_fallocate,
+                or![
+                    and![Cond::new(
+                        1,
+                        Eq,
+                        (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u64
+                    )?],
+                    and![Cond::new(
+                        1,
+                        Eq,
+                        (libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE) as u64
+                    )?],
+                ],
+            ),
             allow_syscall_if(
                 libc::SYS_fcntl,
                 or![and![
@@ -146,24 +189,45 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
                 ]],
             ),
             allow_syscall(libc::SYS_fstat),
+            // Used for flushing the guest memory file when creating a snapshot.
+            allow_syscall(libc::SYS_fsync),
+            // Used for growing qcow2 disk images as clusters are allocated.
+            allow_syscall(libc::SYS_ftruncate),
             allow_syscall_if(
                 libc::SYS_futex,
                 or![
                     and![Cond::new(1, Eq, FUTEX_WAIT_PRIVATE)?],
                     and![Cond::new(1, Eq, FUTEX_WAKE_PRIVATE)?],
                     and![Cond::new(1, Eq, FUTEX_REQUEUE_PRIVATE)?],
+                    and![Cond::new(1, Eq, FUTEX_WAIT_BITSET_PRIVATE)?],
                 ],
             ),
+            // SYS_getpid and SYS_tgkill (SYS_tkill on musl) are used for kicking the vCPU
+            // threads out of KVM_RUN when pausing the microVM. Recent glibc versions also
+            // block signals with SYS_rt_sigprocmask around the kick.
+            #[cfg(target_env = "gnu")]
+            allow_syscall(libc::SYS_getpid),
             allow_syscall(libc::SYS_getrandom),
+            // Used by the block devices which execute their requests through an io_uring. The
+            // ring is set up again when the microVM is rebooted.
+            allow_syscall(sys_util::io_uring::SYS_IO_URING_ENTER),
+            allow_syscall(sys_util::io_uring::SYS_IO_URING_REGISTER),
+            allow_syscall(sys_util::io_uring::SYS_IO_URING_SETUP),
             allow_syscall_if(libc::SYS_ioctl, create_ioctl_seccomp_rule()?),
             allow_syscall(libc::SYS_lseek),
-            #[cfg(target_env = "musl")]
+            // The musl allocator and the balloon device give memory back with MADV_DONTNEED,
+            // or with MADV_REMOVE when the guest memory is backed by a memfd.
             allow_syscall_if(
                 libc::SYS_madvise,
-                or![and![Cond::new(2, Eq, libc::MADV_DONTNEED as u64)?],],
+                or![
+                    and![Cond::new(2, Eq, libc::MADV_DONTNEED as u64)?],
+                    and![Cond::new(2, Eq, libc::MADV_REMOVE as u64)?],
+                ],
             ),
             allow_syscall(libc::SYS_mmap),
             allow_syscall(libc::SYS_munmap),
+            // Used by older C libraries for sleeping, like when reconnecting to an NBD server.
+            allow_syscall(libc::SYS_nanosleep),
             #[cfg(target_env = "musl")]
             allow_syscall(libc::SYS_open),
             #[cfg(target_env = "gnu")]
@@ -171,10 +235,42 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_pipe),
             allow_syscall(libc::SYS_read),
             allow_syscall(libc::SYS_readv),
+            // Used for reading the replies of the NBD server or vhost-user back-end of a drive.
+            allow_syscall(libc::SYS_recvfrom),
             // SYS_rt_sigreturn is needed in case a fault does occur, so that the signal handler
             // can return. Otherwise we get stuck in a fault loop.
             allow_syscall(libc::SYS_rt_sigreturn),
+            #[cfg(target_env = "gnu")]
+            allow_syscall(libc::SYS_rt_sigprocmask),
+            // Used for passing the guest memory and the queue eventfds to the vhost-user
+            // back-end of a drive.
+            allow_syscall(libc::SYS_sendmsg),
+            // Used for sending requests to the NBD server of a drive.
+            allow_syscall(libc::SYS_sendto),
+            // Used for setting the timeouts of the connection to the NBD server or vhost-user
+            // back-end of a drive.
+            allow_syscall_if(
+                libc::SYS_setsockopt,
+                or![
+                    and![
+                        Cond::new(1, Eq, libc::SOL_SOCKET as u64)?,
+                        Cond::new(2, Eq, libc::SO_RCVTIMEO as u64)?,
+                    ],
+                    and![
+                        Cond::new(1, Eq, libc::SOL_SOCKET as u64)?,
+                        Cond::new(2, Eq, libc::SO_SNDTIMEO as u64)?,
+                    ],
+                ],
+            ),
+            allow_syscall_if(
+                libc::SYS_socket,
+                or![and![Cond::new(0, Eq, libc::AF_UNIX as u64)?],],
+            ),
             allow_syscall(libc::SYS_stat),
+            #[cfg(target_env = "gnu")]
+            allow_syscall(libc::SYS_tgkill),
+            #[cfg(target_env = "musl")]
+            allow_syscall(libc::SYS_tkill),
             allow_syscall(libc::SYS_timerfd_create),
             allow_syscall(libc::SYS_timerfd_settime),
             allow_syscall(libc::SYS_write),
@@ -218,6 +314,26 @@ fn create_common_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, Eq, KVM_SET_MSRS)?],
         and![Cond::new(1, Eq, KVM_SET_REGS)?],
         and![Cond::new(1, Eq, KVM_SET_SREGS)?],
+        // Needed for saving the state of a paused microVM to a snapshot.
+        and![Cond::new(1, Eq, KVM_GET_CLOCK)?],
+        and![Cond::new(1, Eq, KVM_GET_FPU)?],
+        and![Cond::new(1, Eq, KVM_GET_IRQCHIP)?],
+        and![Cond::new(1, Eq, KVM_GET_MP_STATE)?],
+        and![Cond::new(1, Eq, KVM_GET_MSRS)?],
+        and![Cond::new(1, Eq, KVM_GET_PIT2)?],
+        and![Cond::new(1, Eq, KVM_GET_REGS)?],
+        and![Cond::new(1, Eq, KVM_GET_VCPU_EVENTS)?],
+        and![Cond::new(1, Eq, KVM_GET_XCRS)?],
+        and![Cond::new(1, Eq, KVM_GET_XSAVE)?],
+        // Needed for resetting the microVM when the guest reboots.
+        and![Cond::new(1, Eq, KVM_SET_CLOCK)?],
+        and![Cond::new(1, Eq, KVM_SET_IRQCHIP)?],
+        and![Cond::new(1, Eq, KVM_SET_MP_STATE)?],
+        and![Cond::new(1, Eq, KVM_SET_GUEST_DEBUG)?],
+        and![Cond::new(1, Eq, KVM_SET_PIT2)?],
+        and![Cond::new(1, Eq, KVM_SET_VCPU_EVENTS)?],
+        and![Cond::new(1, Eq, KVM_SET_XCRS)?],
+        and![Cond::new(1, Eq, KVM_SET_XSAVE)?],
     ])
 }
 
@@ -37,13 +37,19 @@ impl fmt::Display for Error {
 
 type Result<T> = ::std::result::Result<T, Error>;
 
+/// The I/O port of the pvpanic device, the one QEMU uses.
+#[cfg(target_arch = "x86_64")]
+pub const PVPANIC_IO_PORT: u64 = 0x505;
+
 /// The `LegacyDeviceManager` is a wrapper that is used for registering legacy devices
-/// on an I/O Bus. It currently manages the uart and i8042 devices.
+/// on an I/O Bus. It currently manages the uart, i8042 and pvpanic devices. The pvpanic device
+/// only sits on the I/O Bus on x86_64, elsewhere it is registered by the `MMIODeviceManager`.
 /// The `LegacyDeviceManger` should be initialized only by using the constructor.
 pub struct LegacyDeviceManager {
     pub io_bus: devices::Bus,
     pub stdio_serial: Arc<Mutex<devices::legacy::Serial>>,
     pub i8042: Arc<Mutex<devices::legacy::I8042Device>>,
+    pub pvpanic: Arc<Mutex<devices::legacy::PvPanic>>,
 
     pub com_evt_1_3: EventFd,
     pub com_evt_2_4: EventFd,
@@ -52,9 +58,9 @@ pub struct LegacyDeviceManager {
 }
 
 impl LegacyDeviceManager {
-    /// Create a new DeviceManager handling legacy devices (uart, i8042).
+    /// Create a new DeviceManager handling legacy devices (uart, i8042, pvpanic).
     pub fn new() -> Result<Self> {
-        let io_bus = devices::Bus::new();
+        let io_bus = devices::Bus::with_latency_metrics("pio");
         let com_evt_1_3 = EventFd::new().map_err(Error::EventFd)?;
         let com_evt_2_4 = EventFd::new().map_err(Error::EventFd)?;
         let kbd_evt = EventFd::new().map_err(Error::EventFd)?;
@@ -70,10 +76,15 @@ impl LegacyDeviceManager {
             kbd_evt.try_clone().unwrap(),
         )));
 
+        // Create panic event for pvpanic
+        let panic_evt = EventFd::new().map_err(Error::EventFd)?;
+        let pvpanic = Arc::new(Mutex::new(devices::legacy::PvPanic::new(panic_evt)));
+
         Ok(LegacyDeviceManager {
             io_bus,
             stdio_serial,
             i8042,
+            pvpanic,
             com_evt_1_3,
             com_evt_2_4,
             kbd_evt,
@@ -120,6 +131,10 @@ impl LegacyDeviceManager {
         self.io_bus
             .insert(self.i8042.clone(), 0x060, 0x5)
             .map_err(Error::BusError)?;
+        #[cfg(target_arch = "x86_64")]
+        self.io_bus
+            .insert(self.pvpanic.clone(), PVPANIC_IO_PORT, 0x1)
+            .map_err(Error::BusError)?;
         Ok(())
     }
 }
@@ -132,7 +147,10 @@ mod tests {
     fn test_register_legacy_devices() {
         let ldm = LegacyDeviceManager::new();
         assert!(ldm.is_ok());
-        assert!(&ldm.unwrap().register_devices().is_ok());
+        let mut ldm = ldm.unwrap();
+        assert!(ldm.register_devices().is_ok());
+        #[cfg(target_arch = "x86_64")]
+        assert!(ldm.io_bus.get_device(PVPANIC_IO_PORT).is_some());
         // we need to reset the terminal otherwise stdin will remain in raw mode
         let stdin_handle = io::stdin();
         stdin_handle.lock().set_canon_mode().unwrap();
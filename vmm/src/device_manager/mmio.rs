@@ -30,6 +30,10 @@ pub enum Error {
     RegisterIoEvent(io::Error),
     /// Registering an IRQ FD failed.
     RegisterIrqFd(io::Error),
+    /// Restoring the state of a device failed.
+    RestoreDeviceState(devices::virtio::ActivateError),
+    /// The saved devices do not match the registered ones.
+    DeviceStateMismatch,
     /// Failed to update the mmio device.
     UpdateFailed,
 }
@@ -45,6 +49,12 @@ impl fmt::Display for Error {
             Error::IrqsExhausted => write!(f, "no more IRQs are available"),
             Error::RegisterIoEvent(ref e) => write!(f, "failed to register IO event: {}", e),
             Error::RegisterIrqFd(ref e) => write!(f, "failed to register irqfd: {}", e),
+            Error::RestoreDeviceState(ref e) => {
+                write!(f, "failed to restore the device state: {:?}", e)
+            }
+            Error::DeviceStateMismatch => {
+                write!(f, "the saved devices do not match the attached devices")
+            }
             Error::UpdateFailed => write!(f, "failed to update the mmio device"),
         }
     }
@@ -70,6 +80,10 @@ pub struct MMIODeviceManager {
     irq: u32,
     last_irq: u32,
     id_to_addr_map: HashMap<String, u64>,
+    // The registered devices along with their address, in registration order.
+    mmio_devices: Vec<(Arc<Mutex<devices::virtio::MmioDevice>>, u64)>,
+    // While the devices are being reset, the index of the next device to be reset.
+    reset_index: Option<usize>,
 }
 
 impl MMIODeviceManager {
@@ -84,8 +98,10 @@ impl MMIODeviceManager {
             mmio_base,
             irq: irq_interval.0,
             last_irq: irq_interval.1,
-            bus: devices::Bus::new(),
+            bus: devices::Bus::with_latency_metrics("mmio"),
             id_to_addr_map: HashMap::new(),
+            mmio_devices: Vec::new(),
+            reset_index: None,
         }
     }
 
@@ -97,6 +113,9 @@ impl MMIODeviceManager {
         cmdline: &mut kernel_cmdline::Cmdline,
         id: Option<String>,
     ) -> Result<u64> {
+        if let Some(index) = self.reset_index {
+            return self.reset_device(index, device);
+        }
         if self.irq > self.last_irq {
             return Err(Error::IrqsExhausted);
         }
@@ -117,9 +136,11 @@ impl MMIODeviceManager {
                 .map_err(Error::RegisterIrqFd)?;
         }
 
+        let mmio_device = Arc::new(Mutex::new(mmio_device));
         self.bus
-            .insert(Arc::new(Mutex::new(mmio_device)), self.mmio_base, MMIO_LEN)
+            .insert(mmio_device.clone(), self.mmio_base, MMIO_LEN)
             .map_err(Error::BusError)?;
+        self.mmio_devices.push((mmio_device, self.mmio_base));
 
         // as per doc, [virtio_mmio.]device=<size>@<baseaddr>:<irq> needs to be appended
         // to kernel commandline for virtio mmio devices to get recognized
@@ -143,6 +164,57 @@ impl MMIODeviceManager {
         Ok(ret)
     }
 
+    /// Register the pvpanic device on the MMIO Bus. The device does not need an interrupt, so it
+    /// only takes up an MMIO slot.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn register_pvpanic(
+        &mut self,
+        device: Arc<Mutex<devices::legacy::PvPanic>>,
+    ) -> Result<u64> {
+        self.bus
+            .insert(device, self.mmio_base, MMIO_LEN)
+            .map_err(Error::BusError)?;
+        let ret = self.mmio_base;
+        self.mmio_base += MMIO_LEN;
+        Ok(ret)
+    }
+
+    /// Starts resetting the registered devices, e.g. when the guest reboots. Until
+    /// `finish_reset()` is called, `register_device()` hands the given device over to the
+    /// next registered device instead, in registration order. Both the KVM events and the
+    /// kernel command line are left as they are.
+    pub fn start_reset(&mut self) {
+        self.reset_index = Some(0);
+    }
+
+    /// Stops resetting the registered devices.
+    pub fn finish_reset(&mut self) {
+        self.reset_index = None;
+    }
+
+    fn reset_device(
+        &mut self,
+        index: usize,
+        device: Box<devices::virtio::VirtioDevice>,
+    ) -> Result<u64> {
+        {
+            let (ref mmio_device, _) = *self
+                .mmio_devices
+                .get(index)
+                .ok_or(Error::DeviceStateMismatch)?;
+            // Use expect() to crash if another thread poisoned this lock.
+            let mut mmio_device = mmio_device
+                .lock()
+                .expect("Failed to reset device due to poisoned lock");
+            if mmio_device.save_state().device_type != device.device_type() {
+                return Err(Error::DeviceStateMismatch);
+            }
+            mmio_device.reset_device(device);
+        }
+        self.reset_index = Some(index + 1);
+        Ok(self.mmio_devices[index].1)
+    }
+
     /// Update a drive by rebuilding its config space and rewriting it on the bus.
     pub fn update_drive(&self, addr: u64, new_size: u64) -> Result<()> {
         if let Some((_, device)) = self.bus.get_device(addr) {
@@ -158,6 +230,55 @@ impl MMIODeviceManager {
         }
     }
 
+    /// Marks the device at the given address as needing a reset, once it stopped working.
+    pub fn set_device_needs_reset(&self, addr: u64) -> Result<()> {
+        let &(ref device, _) = self
+            .mmio_devices
+            .iter()
+            .find(|&&(_, device_addr)| device_addr == addr)
+            .ok_or(Error::UpdateFailed)?;
+        device
+            .lock()
+            .map_err(|_| Error::UpdateFailed)?
+            .set_device_needs_reset();
+        Ok(())
+    }
+
+    /// Saves the state of the MMIO transport of all the registered devices, in registration order.
+    pub fn save_state(&self) -> Vec<devices::virtio::MmioDeviceState> {
+        self.mmio_devices
+            .iter()
+            .map(|&(ref device, _)| {
+                // Use expect() to crash if another thread poisoned this lock.
+                device
+                    .lock()
+                    .expect("Failed to save device state due to poisoned lock")
+                    .save_state()
+            })
+            .collect()
+    }
+
+    /// Restores the state of the MMIO transport of the registered devices. The devices have to be
+    /// registered in the same order as when their state was saved.
+    pub fn restore_state(&self, states: &[devices::virtio::MmioDeviceState]) -> Result<()> {
+        if states.len() != self.mmio_devices.len() {
+            return Err(Error::DeviceStateMismatch);
+        }
+        for (&(ref device, _), state) in self.mmio_devices.iter().zip(states.iter()) {
+            // Use expect() to crash if another thread poisoned this lock.
+            let mut device = device
+                .lock()
+                .expect("Failed to restore device state due to poisoned lock");
+            if device.save_state().device_type != state.device_type {
+                return Err(Error::DeviceStateMismatch);
+            }
+            device
+                .restore_state(state)
+                .map_err(Error::RestoreDeviceState)?;
+        }
+        Ok(())
+    }
+
     /// Gets the address of the specified device on the bus.
     pub fn get_address(&self, id: &str) -> Option<&u64> {
         self.id_to_addr_map.get(id)
@@ -180,7 +301,7 @@ mod tests {
     use arch;
     use devices::virtio::{ActivateResult, VirtioDevice};
     use kernel_cmdline;
-    use memory_model::{GuestAddress, GuestMemory};
+    use memory_model::{GuestAddress, GuestMemory, MemoryBacking};
     use std::sync::atomic::AtomicUsize;
     use std::sync::mpsc::channel;
     use std::sync::{Arc, RwLock};
@@ -236,6 +357,7 @@ mod tests {
             state: InstanceState::Uninitialized,
             id: "TEST_ID".to_string(),
             vmm_version: "1.0".to_string(),
+            failed_devices: Vec::new(),
         }));
 
         let (_to_vmm, from_api) = channel();
@@ -252,7 +374,11 @@ mod tests {
     fn register_device() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
-        let guest_mem = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        let guest_mem = GuestMemory::new(
+            &[(start_addr1, 0x1000), (start_addr2, 0x1000)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
         let mut device_manager =
             MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
 
@@ -269,7 +395,11 @@ mod tests {
     fn register_too_many_devices() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
-        let guest_mem = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        let guest_mem = GuestMemory::new(
+            &[(start_addr1, 0x1000), (start_addr2, 0x1000)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
         let mut device_manager =
             MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
 
@@ -292,6 +422,64 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_reset_devices() {
+        let guest_mem =
+            GuestMemory::new(&[(GuestAddress(0x0), 0x1000)], MemoryBacking::Anonymous).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut vmm = create_vmm_object();
+        vmm.setup_interrupt_controller().unwrap();
+
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let addr = device_manager
+            .register_device(
+                vmm.vm.get_fd(),
+                Box::new(DummyDevice { dummy: 0 }),
+                &mut cmdline,
+                None,
+            )
+            .unwrap();
+        let cmdline_str = cmdline.as_str().to_string();
+
+        device_manager.start_reset();
+        assert_eq!(
+            device_manager
+                .register_device(
+                    vmm.vm.get_fd(),
+                    Box::new(DummyDevice { dummy: 0 }),
+                    &mut cmdline,
+                    None
+                )
+                .unwrap(),
+            addr
+        );
+        // There are no more devices to reset.
+        assert!(device_manager
+            .register_device(
+                vmm.vm.get_fd(),
+                Box::new(DummyDevice { dummy: 0 }),
+                &mut cmdline,
+                None
+            )
+            .is_err());
+        device_manager.finish_reset();
+        assert_eq!(cmdline.as_str(), cmdline_str);
+
+        // Devices get registered again.
+        assert_eq!(
+            device_manager
+                .register_device(
+                    vmm.vm.get_fd(),
+                    Box::new(DummyDevice { dummy: 0 }),
+                    &mut cmdline,
+                    None
+                )
+                .unwrap(),
+            addr + MMIO_LEN
+        );
+    }
+
     #[test]
     fn test_dummy_device() {
         let mut dummy = DummyDevice { dummy: 0 };
@@ -299,7 +487,7 @@ mod tests {
         assert_eq!(dummy.queue_max_sizes(), QUEUE_SIZES);
 
         // test activate
-        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
         let ievt = EventFd::new().unwrap();
         let stat = Arc::new(AtomicUsize::new(0));
         let queue_evts = vec![EventFd::new().unwrap()];
@@ -311,7 +499,11 @@ mod tests {
     fn test_error_messages() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
-        let guest_mem = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        let guest_mem = GuestMemory::new(
+            &[(start_addr1, 0x1000), (start_addr2, 0x1000)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
         let device_manager =
             MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
         let mut cmdline = kernel_cmdline::Cmdline::new(4096);
@@ -374,13 +566,80 @@ mod tests {
                 io::Error::from_raw_os_error(0)
             )
         );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::RestoreDeviceState(devices::virtio::ActivateError::BadActivate)
+            ),
+            "failed to restore the device state: BadActivate"
+        );
+        assert_eq!(
+            format!("{}", Error::DeviceStateMismatch),
+            "the saved devices do not match the attached devices"
+        );
+    }
+
+    #[test]
+    fn test_save_restore_state() {
+        // Registers a dummy device on a new VM.
+        fn setup() -> (Vmm, MMIODeviceManager) {
+            let guest_mem =
+                GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
+            let mut device_manager =
+                MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+            let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+            let vmm = create_vmm_object();
+            #[cfg(target_arch = "x86_64")]
+            vmm.vm
+                .setup_irqchip(
+                    &EventFd::new().unwrap(),
+                    &EventFd::new().unwrap(),
+                    &EventFd::new().unwrap(),
+                )
+                .unwrap();
+            device_manager
+                .register_device(
+                    vmm.vm.get_fd(),
+                    Box::new(DummyDevice { dummy: 0 }),
+                    &mut cmdline,
+                    None,
+                )
+                .unwrap();
+            (vmm, device_manager)
+        }
+
+        let (_vmm, device_manager) = setup();
+        let states = device_manager.save_state();
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].device_type, 0);
+        assert!(!states[0].device_activated);
+
+        let (_other_vmm, other_device_manager) = setup();
+        assert!(other_device_manager.restore_state(&states).is_ok());
+        assert_eq!(other_device_manager.save_state(), states);
+
+        // The number and the type of the devices have to match.
+        match other_device_manager.restore_state(&[]) {
+            Err(Error::DeviceStateMismatch) => (),
+            _ => panic!("Restoring a different number of devices should fail."),
+        }
+        let mut bad_states = states.clone();
+        bad_states[0].device_type = 1;
+        match other_device_manager.restore_state(&bad_states) {
+            Err(Error::DeviceStateMismatch) => (),
+            _ => panic!("Restoring a different type of device should fail."),
+        }
     }
 
     #[test]
     fn test_update_drive() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
-        let guest_mem = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        let guest_mem = GuestMemory::new(
+            &[(start_addr1, 0x1000), (start_addr2, 0x1000)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
         let mut device_manager =
             MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
         let mut cmdline = kernel_cmdline::Cmdline::new(4096);
@@ -398,11 +657,43 @@ mod tests {
         assert!(device_manager.update_drive(0xbeef, 1_048_576).is_err());
     }
 
+    #[test]
+    fn test_set_device_needs_reset() {
+        let guest_mem =
+            GuestMemory::new(&[(GuestAddress(0x0), 0x1000)], MemoryBacking::Anonymous).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut vmm = create_vmm_object();
+        vmm.setup_interrupt_controller().unwrap();
+
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let addr = device_manager
+            .register_device(
+                vmm.vm.get_fd(),
+                Box::new(DummyDevice { dummy: 0 }),
+                &mut cmdline,
+                None,
+            )
+            .unwrap();
+
+        assert!(device_manager.set_device_needs_reset(addr).is_ok());
+        // The device status register reports the DEVICE_NEEDS_RESET bit.
+        let mut status = [0u8; 4];
+        assert!(device_manager.bus.read(addr + 0x70, &mut status));
+        assert_eq!(status, [0x40, 0, 0, 0]);
+
+        assert!(device_manager.set_device_needs_reset(0xbeef).is_err());
+    }
+
     #[test]
     fn test_get_address() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
-        let guest_mem = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        let guest_mem = GuestMemory::new(
+            &[(start_addr1, 0x1000), (start_addr2, 0x1000)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
         let mut device_manager =
             MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
         let mut cmdline = kernel_cmdline::Cmdline::new(4096);
@@ -0,0 +1,1214 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A GDB remote serial protocol server, for debugging the guest kernel.
+//!
+//! The server listens on a Unix domain socket and serves one GDB client at a time, in all-stop
+//! mode: when one vCPU stops, they all do. Every vCPU is a GDB thread, whose id is the vCPU id
+//! plus one, since GDB reserves the ids 0 and -1.
+//!
+//! The vCPU threads cooperate with the server through the `Debugger`: they park themselves
+//! outside of `KVM_RUN` when a stop is requested or when they exit on a breakpoint or after a
+//! single step, and read or write their registers on behalf of the server while parked. The
+//! vCPUs start out stopped, so that breakpoints can be set before the guest runs its first
+//! instruction; the guest only runs once a client connects and lets it continue.
+
+mod packet;
+mod x86_64;
+
+use std::cmp::min;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvm_bindings::{kvm_guest_debug, kvm_regs, kvm_sregs};
+use libc::pthread_t;
+use memory_model::{GuestAddress, GuestMemory};
+use sys_util::Killable;
+use vstate::VCPU_RTSIG_OFFSET;
+
+use self::packet::{decode_hex, encode_hex, parse_hex_u64, Connection, Incoming};
+use self::x86_64::{
+    guest_debug, read_register, read_registers, translate_gva, write_register, write_registers,
+    GuestDebugConfig, MAX_HW_BREAKPOINTS, PAGE_SIZE, SW_BREAKPOINT_INSTR, TARGET_XML,
+};
+
+// The largest packet the client may send, in bytes. Advertised in hex by `qSupported`.
+const PACKET_SIZE: usize = 0x4000;
+
+// How often the server checks for a stopped vCPU while the guest runs.
+const RUNNING_POLL_INTERVAL_MS: u64 = 20;
+
+// Like for pausing the microVM, kicks can get lost, so stopping the vCPUs is retried.
+const STOP_KICK_ATTEMPTS: u32 = 100;
+const STOP_KICK_INTERVAL_MS: u64 = 10;
+
+// How long the server waits for a parked vCPU to serve a request.
+const VCPU_REQUEST_TIMEOUT_MS: u64 = 1000;
+
+// Signal numbers reported in stop replies.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// Errors associated with the GDB server.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot bind the Unix domain socket.
+    Bind(io::Error),
+    /// Cannot spawn the server thread.
+    Spawn(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match *self {
+            Bind(ref e) => write!(f, "Cannot bind the GDB socket: {}", e),
+            Spawn(ref e) => write!(f, "Cannot spawn the GDB server thread: {}", e),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+/// A request the server makes to a parked vCPU.
+#[derive(Debug)]
+pub enum VcpuRequest {
+    /// Read the registers.
+    GetRegisters,
+    /// Write the general purpose registers.
+    SetRegisters(kvm_regs),
+}
+
+/// The registers of a vCPU.
+#[derive(Clone, Copy)]
+pub struct VcpuRegisters {
+    /// The general purpose registers.
+    pub regs: kvm_regs,
+    /// The special registers, needed to walk the guest page tables.
+    pub sregs: kvm_sregs,
+}
+
+/// The answer of a vCPU to a request, with its registers once the request is carried out.
+pub type VcpuResponse = io::Result<VcpuRegisters>;
+
+// Why the vCPUs stopped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StopReason {
+    // The client asked for it, or just connected.
+    Interrupt,
+    // A software breakpoint set by the client.
+    SwBreakpoint,
+    // A hardware breakpoint set by the client.
+    HwBreakpoint,
+    // A single step completed.
+    Step,
+    // Any other debug exit.
+    Trap,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct StopEvent {
+    vcpu: u8,
+    reason: StopReason,
+}
+
+impl StopEvent {
+    fn reply(&self) -> Vec<u8> {
+        let (signal, extra) = match self.reason {
+            StopReason::Interrupt => (SIGINT, ""),
+            StopReason::SwBreakpoint => (SIGTRAP, "swbreak:;"),
+            StopReason::HwBreakpoint => (SIGTRAP, "hwbreak:;"),
+            StopReason::Step | StopReason::Trap => (SIGTRAP, ""),
+        };
+        format!("T{:02x}thread:{:x};{}", signal, thread_id(self.vcpu), extra).into_bytes()
+    }
+}
+
+// A vCPU thread, which the server kicks out of `KVM_RUN` to stop it.
+struct VcpuThread(pthread_t);
+
+// Safe because a vCPU thread unregisters itself, and thus drops its `VcpuThread`, before it
+// exits.
+unsafe impl Killable for VcpuThread {
+    fn pthread_handle(&self) -> pthread_t {
+        self.0
+    }
+}
+
+#[derive(Default)]
+struct DebugState {
+    vcpu_count: u8,
+    // Whether a client is connected.
+    attached: bool,
+    // The running vCPU threads, by vCPU id.
+    threads: BTreeMap<u8, VcpuThread>,
+    // vCPUs whose thread is gone, and which will never park again.
+    exited: BTreeSet<u8>,
+    parked: BTreeSet<u8>,
+    // The vCPUs allowed to run, and whether they single-step.
+    resumed: BTreeMap<u8, bool>,
+    // The first vCPU which stopped on its own since the last resume.
+    stop_event: Option<StopEvent>,
+    requests: BTreeMap<u8, VcpuRequest>,
+    responses: BTreeMap<u8, VcpuResponse>,
+    // The software breakpoints by guest virtual address, along with the guest physical address
+    // and the original byte of the instruction they replace.
+    sw_breakpoints: BTreeMap<u64, (GuestAddress, u8)>,
+    hw_breakpoints: Vec<u64>,
+}
+
+impl DebugState {
+    fn all_stopped(&self) -> bool {
+        (0..self.vcpu_count).all(|id| self.parked.contains(&id) || self.exited.contains(&id))
+    }
+}
+
+/// Coordinates the vCPU threads with the GDB server.
+pub struct Debugger {
+    // Fast path flag checked by the vCPU threads when KVM_RUN gets interrupted.
+    stop_requested: AtomicBool,
+    state: Mutex<DebugState>,
+    cvar: Condvar,
+}
+
+impl Debugger {
+    /// Creates a debugger for `vcpu_count` vCPUs, which start out stopped.
+    pub fn new(vcpu_count: u8) -> Self {
+        Debugger {
+            stop_requested: AtomicBool::new(true),
+            state: Mutex::new(DebugState {
+                vcpu_count,
+                ..Default::default()
+            }),
+            cvar: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DebugState> {
+        // Use expect() to crash if another thread poisoned this lock.
+        self.state.lock().expect("Poisoned debugger lock")
+    }
+
+    /// Called from the vCPU thread before it first enters `KVM_RUN`, so that it can be kicked.
+    pub fn register_vcpu(&self, id: u8) {
+        // Safe because pthread_self() has no preconditions and cannot fail.
+        let thread = VcpuThread(unsafe { libc::pthread_self() });
+        self.lock().threads.insert(id, thread);
+    }
+
+    /// Called from the vCPU thread before it exits.
+    pub fn unregister_vcpu(&self, id: u8) {
+        let mut state = self.lock();
+        state.threads.remove(&id);
+        state.exited.insert(id);
+        self.cvar.notify_all();
+    }
+
+    /// Returns whether some vCPUs should park themselves.
+    pub fn stop_requested(&self) -> bool {
+        self.stop_requested.load(Ordering::SeqCst)
+    }
+
+    /// Called from the vCPU thread when `KVM_RUN` exits on a debug event, with the address of
+    /// the instruction the vCPU stopped at. Returns whether the vCPU has to park itself. It does
+    /// not when the event is an `int3` of the guest's own, which has to be injected back into
+    /// the guest instead.
+    pub fn handle_debug_exit(&self, id: u8, rip: u64) -> bool {
+        let mut state = self.lock();
+        let reason = if state.resumed.get(&id) == Some(&true) {
+            StopReason::Step
+        } else if state.hw_breakpoints.contains(&rip) {
+            StopReason::HwBreakpoint
+        } else if state.sw_breakpoints.contains_key(&rip) {
+            StopReason::SwBreakpoint
+        } else if !state.sw_breakpoints.is_empty() {
+            return false;
+        } else {
+            StopReason::Trap
+        };
+
+        state.resumed.remove(&id);
+        if state.stop_event.is_none() {
+            state.stop_event = Some(StopEvent { vcpu: id, reason });
+        }
+        self.stop_requested.store(true, Ordering::SeqCst);
+        self.cvar.notify_all();
+        true
+    }
+
+    /// Called from the vCPU thread. Blocks until the server lets the vCPU run again, serving
+    /// the requests of the server with `handler` in the meantime. Returns right away if the
+    /// vCPU is allowed to run.
+    pub fn park<F>(&self, id: u8, mut handler: F)
+    where
+        F: FnMut(VcpuRequest) -> VcpuResponse,
+    {
+        let mut state = self.lock();
+        state.parked.insert(id);
+        self.cvar.notify_all();
+        loop {
+            if let Some(request) = state.requests.remove(&id) {
+                let response = handler(request);
+                state.responses.insert(id, response);
+                self.cvar.notify_all();
+                continue;
+            }
+            if state.resumed.contains_key(&id) {
+                break;
+            }
+            state = self.cvar.wait(state).expect("Poisoned debugger lock");
+        }
+        state.parked.remove(&id);
+    }
+
+    /// Returns the argument of `KVM_SET_GUEST_DEBUG` for the vCPU `id`, which injects a
+    /// breakpoint exception into the guest if `inject_breakpoint` is set.
+    pub fn guest_debug(&self, id: u8, inject_breakpoint: bool) -> kvm_guest_debug {
+        let state = self.lock();
+        if !state.attached {
+            return guest_debug(None);
+        }
+        guest_debug(Some(&GuestDebugConfig {
+            sw_breakpoints: !state.sw_breakpoints.is_empty(),
+            hw_breakpoints: &state.hw_breakpoints,
+            single_step: state.resumed.get(&id) == Some(&true),
+            inject_breakpoint,
+        }))
+    }
+
+    fn vcpu_count(&self) -> u8 {
+        self.lock().vcpu_count
+    }
+
+    fn set_attached(&self, attached: bool) {
+        self.lock().attached = attached;
+    }
+
+    // Stops all the vCPUs and waits for them to park. Returns whether they all did.
+    fn stop_all(&self) -> bool {
+        self.stop_requested.store(true, Ordering::SeqCst);
+        let mut state = self.lock();
+        state.resumed.clear();
+        for _ in 0..STOP_KICK_ATTEMPTS {
+            if state.all_stopped() {
+                return true;
+            }
+            for (id, thread) in state.threads.iter() {
+                if !state.parked.contains(id) {
+                    if let Err(e) = thread.kill(VCPU_RTSIG_OFFSET) {
+                        warn!("Cannot kick vCPU {}: {}", id, e);
+                    }
+                }
+            }
+            state = self
+                .cvar
+                .wait_timeout(state, Duration::from_millis(STOP_KICK_INTERVAL_MS))
+                .expect("Poisoned debugger lock")
+                .0;
+        }
+        state.all_stopped()
+    }
+
+    // Lets the vCPUs in `resumed` run, single-stepping those mapped to `true`.
+    fn resume(&self, resumed: BTreeMap<u8, bool>) {
+        let mut state = self.lock();
+        self.stop_requested
+            .store(resumed.len() < state.vcpu_count as usize, Ordering::SeqCst);
+        state.resumed = resumed;
+        state.stop_event = None;
+        self.cvar.notify_all();
+    }
+
+    fn resume_all(&self) {
+        let resumed = (0..self.vcpu_count()).map(|id| (id, false)).collect();
+        self.resume(resumed);
+    }
+
+    fn take_stop_event(&self) -> Option<StopEvent> {
+        self.lock().stop_event.take()
+    }
+
+    // Has the parked vCPU `id` carry out `request`. Returns `None` if the vCPU is not parked,
+    // or does not answer in time.
+    fn request(&self, id: u8, request: VcpuRequest) -> Option<VcpuResponse> {
+        let deadline = Instant::now() + Duration::from_millis(VCPU_REQUEST_TIMEOUT_MS);
+        let mut state = self.lock();
+        if !state.parked.contains(&id) {
+            return None;
+        }
+        state.requests.insert(id, request);
+        self.cvar.notify_all();
+        loop {
+            if let Some(response) = state.responses.remove(&id) {
+                return Some(response);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                state.requests.remove(&id);
+                return None;
+            }
+            state = self
+                .cvar
+                .wait_timeout(state, deadline - now)
+                .expect("Poisoned debugger lock")
+                .0;
+        }
+    }
+
+    fn has_sw_breakpoint(&self, gva: u64) -> bool {
+        self.lock().sw_breakpoints.contains_key(&gva)
+    }
+
+    fn insert_sw_breakpoint(&self, gva: u64, gpa: GuestAddress, orig: u8) {
+        self.lock().sw_breakpoints.insert(gva, (gpa, orig));
+    }
+
+    fn remove_sw_breakpoint(&self, gva: u64) -> Option<(GuestAddress, u8)> {
+        self.lock().sw_breakpoints.remove(&gva)
+    }
+
+    fn take_sw_breakpoints(&self) -> BTreeMap<u64, (GuestAddress, u8)> {
+        std::mem::take(&mut self.lock().sw_breakpoints)
+    }
+
+    fn insert_hw_breakpoint(&self, addr: u64) -> bool {
+        let mut state = self.lock();
+        if state.hw_breakpoints.contains(&addr) {
+            return true;
+        }
+        if state.hw_breakpoints.len() == MAX_HW_BREAKPOINTS {
+            return false;
+        }
+        state.hw_breakpoints.push(addr);
+        true
+    }
+
+    fn remove_hw_breakpoint(&self, addr: u64) -> bool {
+        let mut state = self.lock();
+        let count = state.hw_breakpoints.len();
+        state.hw_breakpoints.retain(|&a| a != addr);
+        state.hw_breakpoints.len() < count
+    }
+
+    fn clear_hw_breakpoints(&self) {
+        self.lock().hw_breakpoints.clear();
+    }
+}
+
+/// Starts the GDB server on the Unix domain socket at `path`, in its own thread.
+pub fn start_server(
+    path: &Path,
+    debugger: Arc<Debugger>,
+    guest_mem: GuestMemory,
+) -> Result<thread::JoinHandle<()>> {
+    let listener = UnixListener::bind(path).map_err(Error::Bind)?;
+    info!("Waiting for a GDB client on {}", path.display());
+    thread::Builder::new()
+        .name("fc_gdb".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        info!("GDB client connected");
+                        let mut session = Session::new(&debugger, &guest_mem, stream);
+                        if let Err(e) = session.run() {
+                            warn!("GDB session ended with an error: {}", e);
+                        }
+                        session.detach();
+                        info!("GDB client disconnected");
+                    }
+                    Err(e) => error!("Cannot accept a GDB connection: {}", e),
+                }
+            }
+        })
+        .map_err(Error::Spawn)
+}
+
+// The GDB thread id of vCPU `id`.
+fn thread_id(vcpu: u8) -> u64 {
+    u64::from(vcpu) + 1
+}
+
+// A thread id as found in the `H` and `vCont` packets.
+#[derive(Debug, PartialEq)]
+enum ThreadId {
+    All,
+    Any,
+    Vcpu(u8),
+}
+
+fn parse_thread_id(tid: &[u8]) -> Option<ThreadId> {
+    if tid == b"-1" {
+        return Some(ThreadId::All);
+    }
+    match parse_hex_u64(tid)? {
+        0 => Some(ThreadId::Any),
+        tid if tid <= u64::from(u8::max_value()) + 1 => Some(ThreadId::Vcpu((tid - 1) as u8)),
+        _ => None,
+    }
+}
+
+// Parses `<addr>,<length>`.
+fn parse_addr_len(args: &[u8]) -> Option<(u64, usize)> {
+    let mut parts = args.splitn(2, |&b| b == b',');
+    let addr = parse_hex_u64(parts.next()?)?;
+    let len = parse_hex_u64(parts.next()?)? as usize;
+    Some((addr, len))
+}
+
+// Parses the `vCont` actions into the vCPUs to resume, and whether they single-step. The
+// first action that applies to a vCPU wins, and vCPUs no action applies to stay stopped. The
+// signals of `C` and `S` are ignored, since there is no such thing for a virtual machine.
+fn parse_vcont(actions: &[u8], vcpu_count: u8) -> Option<BTreeMap<u8, bool>> {
+    let mut resumed = BTreeMap::new();
+    for action in actions.split(|&b| b == b';').filter(|a| !a.is_empty()) {
+        let mut parts = action.splitn(2, |&b| b == b':');
+        let step = match parts.next()?.first()? {
+            b'c' | b'C' => false,
+            b's' | b'S' => true,
+            _ => return None,
+        };
+        let vcpus: Vec<u8> = match parts.next().map(parse_thread_id) {
+            None | Some(Some(ThreadId::All)) | Some(Some(ThreadId::Any)) => {
+                (0..vcpu_count).collect()
+            }
+            Some(Some(ThreadId::Vcpu(id))) if id < vcpu_count => vec![id],
+            _ => return None,
+        };
+        for id in vcpus {
+            resumed.entry(id).or_insert(step);
+        }
+    }
+    Some(resumed)
+}
+
+// Replies to a `qXfer` read of `data` at `offset`, `length` bytes at most.
+fn xfer_reply(data: &[u8], offset: usize, length: usize) -> Vec<u8> {
+    let start = min(offset, data.len());
+    let end = min(start + length, data.len());
+    let mut reply = vec![if end == data.len() { b'l' } else { b'm' }];
+    reply.extend_from_slice(&data[start..end]);
+    reply
+}
+
+const OK: &[u8] = b"OK";
+// The error numbers are not interpreted by GDB. These are the Linux errno values.
+const EFAULT: &[u8] = b"E0e";
+const EINVAL: &[u8] = b"E16";
+const ENOSPC: &[u8] = b"E1c";
+
+// What a packet leads to.
+enum Action {
+    Reply(Vec<u8>),
+    Resume(BTreeMap<u8, bool>),
+    // The client is going away, and the guest keeps running without it.
+    Detach(Option<Vec<u8>>),
+}
+
+struct Session<'a> {
+    debugger: &'a Debugger,
+    guest_mem: &'a GuestMemory,
+    conn: Connection<UnixStream>,
+    // The vCPU which register and memory accesses go to.
+    current: u8,
+    last_stop: StopEvent,
+    // The registers of the stopped vCPUs, read on first use after each stop.
+    registers: BTreeMap<u8, VcpuRegisters>,
+}
+
+impl<'a> Session<'a> {
+    fn new(debugger: &'a Debugger, guest_mem: &'a GuestMemory, stream: UnixStream) -> Self {
+        Session {
+            debugger,
+            guest_mem,
+            conn: Connection::new(stream),
+            current: 0,
+            last_stop: StopEvent {
+                vcpu: 0,
+                reason: StopReason::Interrupt,
+            },
+            registers: BTreeMap::new(),
+        }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        self.debugger.set_attached(true);
+        if !self.debugger.stop_all() {
+            warn!("Not all vCPUs stopped for the GDB client");
+        }
+        if let Some(event) = self.debugger.take_stop_event() {
+            self.last_stop = event;
+        }
+
+        loop {
+            let packet = match self.conn.read()? {
+                Some(Incoming::Packet(packet)) => packet,
+                // The vCPUs are already stopped.
+                Some(Incoming::Interrupt) => continue,
+                None => return Ok(()),
+            };
+            match self.handle_packet(&packet) {
+                Action::Reply(reply) => self.conn.write_packet(&reply)?,
+                Action::Resume(resumed) => {
+                    if !self.resume(resumed)? {
+                        return Ok(());
+                    }
+                    let reply = self.last_stop.reply();
+                    self.conn.write_packet(&reply)?;
+                }
+                Action::Detach(reply) => {
+                    if let Some(reply) = reply {
+                        self.conn.write_packet(&reply)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Lets the guest run until a vCPU stops or the client interrupts it, then stops all the
+    // vCPUs. Returns false if the client went away in the meantime.
+    fn resume(&mut self, resumed: BTreeMap<u8, bool>) -> io::Result<bool> {
+        self.registers.clear();
+        self.debugger.resume(resumed);
+        self.conn
+            .stream()
+            .set_read_timeout(Some(Duration::from_millis(RUNNING_POLL_INTERVAL_MS)))?;
+
+        let event = loop {
+            if let Some(event) = self.debugger.take_stop_event() {
+                break event;
+            }
+            match self.conn.read() {
+                Ok(Some(Incoming::Interrupt)) => {
+                    break StopEvent {
+                        vcpu: self.current,
+                        reason: StopReason::Interrupt,
+                    }
+                }
+                // Only the non-stop mode allows packets while the guest runs.
+                Ok(Some(Incoming::Packet(_))) => (),
+                Ok(None) => return Ok(false),
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        };
+
+        if !self.debugger.stop_all() {
+            warn!("Not all vCPUs stopped for the GDB client");
+        }
+        self.conn.stream().set_read_timeout(None)?;
+        self.last_stop = event;
+        self.current = event.vcpu;
+        Ok(true)
+    }
+
+    // Removes the breakpoints and lets the guest run freely.
+    fn detach(&mut self) {
+        for &(gpa, orig) in self.debugger.take_sw_breakpoints().values() {
+            if let Err(e) = self.guest_mem.write_obj_at_addr(orig, gpa) {
+                error!("Cannot remove a breakpoint at {:#x}: {:?}", gpa.offset(), e);
+            }
+        }
+        self.debugger.clear_hw_breakpoints();
+        self.debugger.set_attached(false);
+        self.debugger.resume_all();
+    }
+
+    fn handle_packet(&mut self, packet: &[u8]) -> Action {
+        let (&command, args) = match packet.split_first() {
+            Some(split) => split,
+            None => return Action::Reply(Vec::new()),
+        };
+        let reply = match command {
+            b'?' => self.last_stop.reply(),
+            b'c' => return Action::Resume(self.all_vcpus(false)),
+            b's' => {
+                let mut resumed = BTreeMap::new();
+                resumed.insert(self.current, true);
+                return Action::Resume(resumed);
+            }
+            b'D' => return Action::Detach(Some(OK.to_vec())),
+            b'k' => return Action::Detach(None),
+            b'g' => self.read_registers(),
+            b'G' => self.write_registers(args),
+            b'p' => self.read_register(args),
+            b'P' => self.write_register(args),
+            b'm' => self.read_memory(args),
+            b'M' => self.write_memory(args),
+            b'H' => self.set_thread(args),
+            b'T' => match parse_thread_id(args) {
+                Some(ThreadId::Vcpu(id)) if id < self.debugger.vcpu_count() => OK.to_vec(),
+                _ => EINVAL.to_vec(),
+            },
+            b'Z' | b'z' => self.breakpoint(command == b'Z', args),
+            b'q' | b'Q' => self.query(packet),
+            b'v' => {
+                if packet == b"vCont?" {
+                    b"vCont;c;C;s;S".to_vec()
+                } else if packet.starts_with(b"vCont;") {
+                    return match parse_vcont(&packet[5..], self.debugger.vcpu_count()) {
+                        Some(resumed) => Action::Resume(resumed),
+                        None => Action::Reply(EINVAL.to_vec()),
+                    };
+                } else {
+                    Vec::new()
+                }
+            }
+            // An empty reply tells the client the packet is not supported.
+            _ => Vec::new(),
+        };
+        Action::Reply(reply)
+    }
+
+    fn query(&mut self, packet: &[u8]) -> Vec<u8> {
+        if packet.starts_with(b"qSupported") {
+            format!(
+                "PacketSize={:x};QStartNoAckMode+;swbreak+;hwbreak+;vContSupported+;\
+                 qXfer:features:read+",
+                PACKET_SIZE
+            )
+            .into_bytes()
+        } else if packet == b"QStartNoAckMode" {
+            self.conn.start_no_ack_mode();
+            OK.to_vec()
+        } else if packet == b"qAttached" {
+            // Detaching leaves the microVM running.
+            b"1".to_vec()
+        } else if packet == b"qC" {
+            format!("QC{:x}", thread_id(self.current)).into_bytes()
+        } else if packet == b"qfThreadInfo" {
+            let ids: Vec<String> = (0..self.debugger.vcpu_count())
+                .map(|id| format!("{:x}", thread_id(id)))
+                .collect();
+            format!("m{}", ids.join(",")).into_bytes()
+        } else if packet == b"qsThreadInfo" {
+            b"l".to_vec()
+        } else if let Some(tid) = packet.strip_prefix(&b"qThreadExtraInfo,"[..]) {
+            match parse_thread_id(tid) {
+                Some(ThreadId::Vcpu(id)) => encode_hex(format!("vCPU {}", id).as_bytes()),
+                _ => EINVAL.to_vec(),
+            }
+        } else if let Some(args) = packet.strip_prefix(&b"qXfer:features:read:target.xml:"[..]) {
+            match parse_addr_len(args) {
+                Some((offset, length)) => {
+                    xfer_reply(TARGET_XML.as_bytes(), offset as usize, length)
+                }
+                None => EINVAL.to_vec(),
+            }
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn all_vcpus(&self, step: bool) -> BTreeMap<u8, bool> {
+        (0..self.debugger.vcpu_count())
+            .map(|id| (id, step))
+            .collect()
+    }
+
+    fn set_thread(&mut self, args: &[u8]) -> Vec<u8> {
+        // Both `Hg` and `Hc` select the current vCPU, since `vCont` names the vCPUs to resume.
+        match args.split_first().map(|(_, tid)| parse_thread_id(tid)) {
+            Some(Some(ThreadId::Vcpu(id))) if id < self.debugger.vcpu_count() => {
+                self.current = id;
+                OK.to_vec()
+            }
+            Some(Some(ThreadId::All)) | Some(Some(ThreadId::Any)) => OK.to_vec(),
+            _ => EINVAL.to_vec(),
+        }
+    }
+
+    fn registers(&mut self, vcpu: u8) -> Option<VcpuRegisters> {
+        if let Some(registers) = self.registers.get(&vcpu) {
+            return Some(*registers);
+        }
+        match self.debugger.request(vcpu, VcpuRequest::GetRegisters)? {
+            Ok(registers) => {
+                self.registers.insert(vcpu, registers);
+                Some(registers)
+            }
+            Err(e) => {
+                error!("Cannot read the registers of vCPU {}: {}", vcpu, e);
+                None
+            }
+        }
+    }
+
+    fn set_registers(&mut self, vcpu: u8, regs: kvm_regs) -> bool {
+        match self.debugger.request(vcpu, VcpuRequest::SetRegisters(regs)) {
+            Some(Ok(registers)) => {
+                self.registers.insert(vcpu, registers);
+                true
+            }
+            Some(Err(e)) => {
+                error!("Cannot write the registers of vCPU {}: {}", vcpu, e);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn read_registers(&mut self) -> Vec<u8> {
+        let current = self.current;
+        match self.registers(current) {
+            Some(r) => encode_hex(&read_registers(&r.regs, &r.sregs)),
+            None => EFAULT.to_vec(),
+        }
+    }
+
+    fn write_registers(&mut self, args: &[u8]) -> Vec<u8> {
+        let current = self.current;
+        let (data, mut regs) = match (decode_hex(args), self.registers(current)) {
+            (Some(data), Some(registers)) => (data, registers.regs),
+            _ => return EINVAL.to_vec(),
+        };
+        if !write_registers(&mut regs, &data) {
+            return EINVAL.to_vec();
+        }
+        if self.set_registers(current, regs) {
+            OK.to_vec()
+        } else {
+            EFAULT.to_vec()
+        }
+    }
+
+    fn read_register(&mut self, args: &[u8]) -> Vec<u8> {
+        let current = self.current;
+        let reg = match parse_hex_u64(args) {
+            Some(reg) => reg as usize,
+            None => return EINVAL.to_vec(),
+        };
+        match self.registers(current) {
+            Some(r) => read_register(&r.regs, &r.sregs, reg)
+                .map_or_else(|| EINVAL.to_vec(), |value| encode_hex(&value)),
+            None => EFAULT.to_vec(),
+        }
+    }
+
+    fn write_register(&mut self, args: &[u8]) -> Vec<u8> {
+        let current = self.current;
+        let mut parts = args.splitn(2, |&b| b == b'=');
+        let reg = parts.next().and_then(parse_hex_u64);
+        let value = parts.next().and_then(decode_hex);
+        let (reg, value, mut regs) = match (reg, value, self.registers(current)) {
+            (Some(reg), Some(value), Some(registers)) => (reg as usize, value, registers.regs),
+            _ => return EINVAL.to_vec(),
+        };
+        if !write_register(&mut regs, reg, &value) {
+            return EINVAL.to_vec();
+        }
+        if self.set_registers(current, regs) {
+            OK.to_vec()
+        } else {
+            EFAULT.to_vec()
+        }
+    }
+
+    // Translates the guest virtual address `gva` through the page tables of the current vCPU.
+    fn translate(&mut self, gva: u64) -> Option<GuestAddress> {
+        let current = self.current;
+        let sregs = self.registers(current)?.sregs;
+        translate_gva(self.guest_mem, &sregs, gva)
+    }
+
+    // Calls `f` with the guest physical address and the length of each page-bounded chunk of
+    // the `len` bytes at `gva`, and the offset of the chunk. Stops at the first unmapped page
+    // or failing chunk. Returns the number of bytes `f` went through.
+    fn for_each_chunk<F>(&mut self, gva: u64, len: usize, mut f: F) -> usize
+    where
+        F: FnMut(&GuestMemory, GuestAddress, usize, usize) -> bool,
+    {
+        let mut done = 0;
+        while done < len {
+            let addr = gva.wrapping_add(done as u64);
+            let chunk = min(len - done, (PAGE_SIZE - (addr & (PAGE_SIZE - 1))) as usize);
+            let gpa = match self.translate(addr) {
+                Some(gpa) => gpa,
+                None => break,
+            };
+            if !f(self.guest_mem, gpa, done, chunk) {
+                break;
+            }
+            done += chunk;
+        }
+        done
+    }
+
+    fn read_memory(&mut self, args: &[u8]) -> Vec<u8> {
+        let (gva, len) = match parse_addr_len(args) {
+            Some((gva, len)) => (gva, min(len, PACKET_SIZE / 2)),
+            None => return EINVAL.to_vec(),
+        };
+        let mut data = vec![0u8; len];
+        let read = self.for_each_chunk(gva, len, |mem, gpa, offset, chunk| {
+            mem.read_slice_at_addr(&mut data[offset..offset + chunk], gpa)
+                .map(|count| count == chunk)
+                .unwrap_or(false)
+        });
+        // A partial read is fine, as long as some bytes were read.
+        if read == 0 && len > 0 {
+            return EFAULT.to_vec();
+        }
+        encode_hex(&data[..read])
+    }
+
+    fn write_memory(&mut self, args: &[u8]) -> Vec<u8> {
+        let mut parts = args.splitn(2, |&b| b == b':');
+        let addr_len = parts.next().and_then(parse_addr_len);
+        let data = parts.next().and_then(decode_hex);
+        let (gva, data) = match (addr_len, data) {
+            (Some((gva, len)), Some(data)) if data.len() == len => (gva, data),
+            _ => return EINVAL.to_vec(),
+        };
+        let written = self.for_each_chunk(gva, data.len(), |mem, gpa, offset, chunk| {
+            mem.write_slice_at_addr(&data[offset..offset + chunk], gpa)
+                .map(|count| count == chunk)
+                .unwrap_or(false)
+        });
+        if written == data.len() {
+            OK.to_vec()
+        } else {
+            EFAULT.to_vec()
+        }
+    }
+
+    // Handles `Z<type>,<addr>,<kind>` when `insert` is set, and `z<type>,<addr>,<kind>`
+    // otherwise. Only the software (0) and hardware (1) breakpoint types are supported.
+    fn breakpoint(&mut self, insert: bool, args: &[u8]) -> Vec<u8> {
+        let mut parts = args.splitn(3, |&b| b == b',');
+        let kind = parts.next();
+        let addr = match parts.next().and_then(parse_hex_u64) {
+            Some(addr) => addr,
+            None => return EINVAL.to_vec(),
+        };
+        match kind {
+            Some(b"0") if insert => self.insert_sw_breakpoint(addr),
+            Some(b"0") => {
+                if let Some((gpa, orig)) = self.debugger.remove_sw_breakpoint(addr) {
+                    if self.guest_mem.write_obj_at_addr(orig, gpa).is_err() {
+                        return EFAULT.to_vec();
+                    }
+                }
+                OK.to_vec()
+            }
+            Some(b"1") if insert => {
+                if self.debugger.insert_hw_breakpoint(addr) {
+                    OK.to_vec()
+                } else {
+                    ENOSPC.to_vec()
+                }
+            }
+            Some(b"1") => {
+                self.debugger.remove_hw_breakpoint(addr);
+                OK.to_vec()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn insert_sw_breakpoint(&mut self, gva: u64) -> Vec<u8> {
+        if self.debugger.has_sw_breakpoint(gva) {
+            return OK.to_vec();
+        }
+        let gpa = match self.translate(gva) {
+            Some(gpa) => gpa,
+            None => return EFAULT.to_vec(),
+        };
+        let orig: u8 = match self.guest_mem.read_obj_from_addr(gpa) {
+            Ok(orig) => orig,
+            Err(_) => return EFAULT.to_vec(),
+        };
+        if self
+            .guest_mem
+            .write_obj_at_addr(SW_BREAKPOINT_INSTR, gpa)
+            .is_err()
+        {
+            return EFAULT.to_vec();
+        }
+        self.debugger.insert_sw_breakpoint(gva, gpa, orig);
+        OK.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+
+    use memory_model::MemoryBacking;
+
+    #[test]
+    fn test_parse_thread_id() {
+        assert_eq!(parse_thread_id(b"-1"), Some(ThreadId::All));
+        assert_eq!(parse_thread_id(b"0"), Some(ThreadId::Any));
+        assert_eq!(parse_thread_id(b"1"), Some(ThreadId::Vcpu(0)));
+        assert_eq!(parse_thread_id(b"1a"), Some(ThreadId::Vcpu(25)));
+        assert_eq!(parse_thread_id(b"1000"), None);
+        assert_eq!(parse_thread_id(b"x"), None);
+    }
+
+    #[test]
+    fn test_parse_vcont() {
+        let resumed = parse_vcont(b";s:2;c", 3).unwrap();
+        assert_eq!(
+            resumed.into_iter().collect::<Vec<_>>(),
+            vec![(0, false), (1, true), (2, false)]
+        );
+        let resumed = parse_vcont(b";S05:1", 3).unwrap();
+        assert_eq!(resumed.into_iter().collect::<Vec<_>>(), vec![(0, true)]);
+        assert_eq!(parse_vcont(b";c:4", 3), None);
+        assert_eq!(parse_vcont(b";t", 3), None);
+    }
+
+    #[test]
+    fn test_xfer_reply() {
+        assert_eq!(xfer_reply(b"abcdef", 0, 4), b"mabcd".to_vec());
+        assert_eq!(xfer_reply(b"abcdef", 4, 4), b"lef".to_vec());
+        assert_eq!(xfer_reply(b"abcdef", 10, 4), b"l".to_vec());
+    }
+
+    #[test]
+    fn test_stop_reply() {
+        let event = StopEvent {
+            vcpu: 1,
+            reason: StopReason::SwBreakpoint,
+        };
+        assert_eq!(event.reply(), b"T05thread:2;swbreak:;".to_vec());
+        let event = StopEvent {
+            vcpu: 0,
+            reason: StopReason::Interrupt,
+        };
+        assert_eq!(event.reply(), b"T02thread:1;".to_vec());
+    }
+
+    #[test]
+    fn test_debugger() {
+        let debugger = Arc::new(Debugger::new(1));
+        assert!(debugger.stop_requested());
+        debugger.set_attached(true);
+
+        // A vCPU thread which parks until it gets resumed, serving register requests.
+        let vcpu_debugger = debugger.clone();
+        let vcpu = thread::spawn(move || {
+            vcpu_debugger.register_vcpu(0);
+            let mut regs = kvm_regs::default();
+            vcpu_debugger.park(0, |request| {
+                if let VcpuRequest::SetRegisters(new_regs) = request {
+                    regs = new_regs;
+                }
+                Ok(VcpuRegisters {
+                    regs,
+                    sregs: kvm_sregs::default(),
+                })
+            });
+            let single_step = vcpu_debugger.guest_debug(0, false).control;
+            // A single step completes.
+            assert!(vcpu_debugger.handle_debug_exit(0, 0x1000));
+            vcpu_debugger.unregister_vcpu(0);
+            single_step
+        });
+
+        assert!(debugger.stop_all());
+        let mut regs = kvm_regs::default();
+        regs.rip = 0x1000;
+        let registers = debugger
+            .request(0, VcpuRequest::SetRegisters(regs))
+            .unwrap()
+            .unwrap();
+        assert_eq!(registers.regs.rip, 0x1000);
+        let registers = debugger
+            .request(0, VcpuRequest::GetRegisters)
+            .unwrap()
+            .unwrap();
+        assert_eq!(registers.regs.rip, 0x1000);
+
+        let mut resumed = BTreeMap::new();
+        resumed.insert(0, true);
+        debugger.resume(resumed);
+        let control = vcpu.join().unwrap();
+        assert_eq!(
+            control,
+            guest_debug(Some(&GuestDebugConfig {
+                single_step: true,
+                ..Default::default()
+            }))
+            .control
+        );
+
+        assert!(debugger.stop_requested());
+        assert_eq!(
+            debugger.take_stop_event(),
+            Some(StopEvent {
+                vcpu: 0,
+                reason: StopReason::Step,
+            })
+        );
+        // The vCPU thread is gone, which counts as stopped.
+        assert!(debugger.stop_all());
+        assert!(debugger.request(0, VcpuRequest::GetRegisters).is_none());
+    }
+
+    #[test]
+    fn test_debug_exit() {
+        let debugger = Debugger::new(2);
+        debugger.set_attached(true);
+        debugger.resume_all();
+        assert!(!debugger.stop_requested());
+
+        // Without breakpoints, any debug exit is a trap.
+        assert!(debugger.handle_debug_exit(1, 0x2000));
+        assert_eq!(
+            debugger.take_stop_event(),
+            Some(StopEvent {
+                vcpu: 1,
+                reason: StopReason::Trap,
+            })
+        );
+
+        debugger.resume_all();
+        debugger.insert_sw_breakpoint(0x1000, GuestAddress(0x1000), 0x90);
+        assert!(debugger.insert_hw_breakpoint(0x3000));
+        // An int3 of the guest is handed back to it.
+        assert!(!debugger.handle_debug_exit(0, 0x2000));
+        assert!(!debugger.stop_requested());
+        assert!(debugger.handle_debug_exit(0, 0x3000));
+        assert!(debugger.handle_debug_exit(1, 0x1000));
+        // Only the first stop is reported.
+        assert_eq!(
+            debugger.take_stop_event(),
+            Some(StopEvent {
+                vcpu: 0,
+                reason: StopReason::HwBreakpoint,
+            })
+        );
+        assert_eq!(debugger.take_stop_event(), None);
+
+        let control = debugger.guest_debug(0, true).control;
+        assert_eq!(
+            control,
+            guest_debug(Some(&GuestDebugConfig {
+                sw_breakpoints: true,
+                hw_breakpoints: &[0x3000],
+                single_step: false,
+                inject_breakpoint: true,
+            }))
+            .control
+        );
+
+        for addr in 1..MAX_HW_BREAKPOINTS as u64 {
+            assert!(debugger.insert_hw_breakpoint(addr));
+        }
+        assert!(!debugger.insert_hw_breakpoint(0x4000));
+        assert!(debugger.remove_hw_breakpoint(0x3000));
+        assert!(!debugger.remove_hw_breakpoint(0x3000));
+
+        debugger.set_attached(false);
+        assert_eq!(debugger.guest_debug(0, false).control, 0);
+    }
+
+    // Sends `packet` and returns the reply, in no-acknowledgment mode.
+    fn exchange(client: &mut UnixStream, packet: &str) -> String {
+        let sum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        client
+            .write_all(format!("${}#{:02x}", packet, sum).as_bytes())
+            .unwrap();
+        let mut reply = Vec::new();
+        let mut byte = [0u8; 1];
+        loop {
+            client.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut sum = [0u8; 2];
+        client.read_exact(&mut sum).unwrap();
+        String::from_utf8(reply[1..].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
+        mem.write_slice_at_addr(&[0x90, 0x91, 0x92, 0x93], GuestAddress(0x1000))
+            .unwrap();
+        let debugger = Arc::new(Debugger::new(1));
+
+        let vcpu_debugger = debugger.clone();
+        let vcpu = thread::spawn(move || {
+            vcpu_debugger.register_vcpu(0);
+            vcpu_debugger.park(0, |_| {
+                let mut regs = kvm_regs::default();
+                regs.rip = 0x1000;
+                Ok(VcpuRegisters {
+                    regs,
+                    sregs: kvm_sregs::default(),
+                })
+            });
+            vcpu_debugger.guest_debug(0, false).control
+        });
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let session_debugger = debugger.clone();
+        let session_mem = mem.clone();
+        let session = thread::spawn(move || {
+            let mut session = Session::new(&session_debugger, &session_mem, server);
+            session.run().unwrap();
+            session.detach();
+        });
+
+        client.write_all(b"$QStartNoAckMode#b0").unwrap();
+        let mut ack = [0u8; 1];
+        client.read_exact(&mut ack).unwrap();
+        assert_eq!(&ack, b"+");
+        let mut reply = [0u8; 6];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"$OK#9a");
+
+        assert_eq!(exchange(&mut client, "?"), "T02thread:1;");
+        assert_eq!(exchange(&mut client, "qfThreadInfo"), "m1");
+        assert_eq!(exchange(&mut client, "qsThreadInfo"), "l");
+        assert_eq!(
+            exchange(&mut client, "qXfer:features:read:target.xml:0,1000"),
+            format!("l{}", TARGET_XML)
+        );
+        assert_eq!(exchange(&mut client, "Hg1"), "OK");
+        assert_eq!(exchange(&mut client, "Hg2"), "E16");
+        assert_eq!(exchange(&mut client, "p10"), "0010000000000000");
+        assert_eq!(exchange(&mut client, "m1000,4"), "90919293");
+        assert_eq!(exchange(&mut client, "M1001,2:aabb"), "OK");
+        assert_eq!(exchange(&mut client, "m1000,4"), "90aabb93");
+        assert_eq!(exchange(&mut client, "m20000,4"), "E0e");
+
+        assert_eq!(exchange(&mut client, "Z0,1000,1"), "OK");
+        assert_eq!(
+            mem.read_obj_from_addr::<u8>(GuestAddress(0x1000)).unwrap(),
+            0xcc
+        );
+        assert_eq!(exchange(&mut client, "Z1,2000,1"), "OK");
+        assert_eq!(exchange(&mut client, "z1,2000,1"), "OK");
+        assert_eq!(exchange(&mut client, "Z2,2000,1"), "");
+        assert_eq!(exchange(&mut client, "unknown"), "");
+
+        // Detaching removes the breakpoint and lets the vCPU go.
+        assert_eq!(exchange(&mut client, "D"), "OK");
+        session.join().unwrap();
+        assert_eq!(
+            mem.read_obj_from_addr::<u8>(GuestAddress(0x1000)).unwrap(),
+            0x90
+        );
+        assert_eq!(vcpu.join().unwrap(), 0);
+    }
+}
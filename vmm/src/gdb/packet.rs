@@ -0,0 +1,243 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Framing of the GDB remote serial protocol.
+//!
+//! A packet is sent as `$<data>#<checksum>`, where the checksum is the sum of the data bytes
+//! modulo 256, in two hex digits. The receiver acknowledges every packet with `+`, or asks for
+//! it again with `-`, until the client switches to the no-acknowledgment mode. A lone `0x03`
+//! byte is an interrupt request.
+
+use std::io::{self, Read, Write};
+
+const INTERRUPT: u8 = 0x03;
+const PACKET_START: u8 = b'$';
+const PACKET_END: u8 = b'#';
+const ACK: u8 = b'+';
+const NACK: u8 = b'-';
+
+/// What the client sent.
+#[derive(Debug, PartialEq)]
+pub enum Incoming {
+    /// The data of a packet with a valid checksum.
+    Packet(Vec<u8>),
+    /// The client asks for the running target to stop.
+    Interrupt,
+}
+
+/// A connection to a GDB client over `stream`.
+pub struct Connection<S: Read + Write> {
+    stream: S,
+    // Received bytes which are not part of a complete packet yet.
+    pending: Vec<u8>,
+    // The last packet sent, kept for when the client asks for it again.
+    last_sent: Vec<u8>,
+    no_ack: bool,
+}
+
+impl<S: Read + Write> Connection<S> {
+    /// Creates a connection which starts in the acknowledgment mode.
+    pub fn new(stream: S) -> Self {
+        Connection {
+            stream,
+            pending: Vec::new(),
+            last_sent: Vec::new(),
+            no_ack: false,
+        }
+    }
+
+    /// Returns the underlying stream.
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+
+    /// Stops acknowledging packets, after the client asked for it with `QStartNoAckMode`.
+    pub fn start_no_ack_mode(&mut self) {
+        self.no_ack = true;
+    }
+
+    /// Returns the next packet or interrupt request from the client, or `None` once the client
+    /// closed the connection. Errors from the stream, such as a read timeout, leave any partial
+    /// packet in place for the next call.
+    pub fn read(&mut self) -> io::Result<Option<Incoming>> {
+        loop {
+            if let Some(incoming) = self.parse()? {
+                return Ok(Some(incoming));
+            }
+            let mut buf = [0u8; 4096];
+            let count = self.stream.read(&mut buf)?;
+            if count == 0 {
+                return Ok(None);
+            }
+            self.pending.extend_from_slice(&buf[..count]);
+        }
+    }
+
+    /// Sends `data` as a packet.
+    pub fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(PACKET_START);
+        packet.extend_from_slice(data);
+        packet.push(PACKET_END);
+        packet.extend_from_slice(format!("{:02x}", checksum(data)).as_bytes());
+        self.stream.write_all(&packet)?;
+        self.stream.flush()?;
+        self.last_sent = packet;
+        Ok(())
+    }
+
+    // Consumes the pending bytes up to the end of the first packet or interrupt request.
+    fn parse(&mut self) -> io::Result<Option<Incoming>> {
+        while let Some(&byte) = self.pending.first() {
+            match byte {
+                INTERRUPT => {
+                    self.pending.remove(0);
+                    return Ok(Some(Incoming::Interrupt));
+                }
+                NACK => {
+                    self.pending.remove(0);
+                    let last_sent = self.last_sent.clone();
+                    self.stream.write_all(&last_sent)?;
+                }
+                PACKET_START => {
+                    let end = match self.pending.iter().position(|&b| b == PACKET_END) {
+                        // The two checksum digits have to be there as well.
+                        Some(end) if end + 2 < self.pending.len() => end,
+                        _ => return Ok(None),
+                    };
+                    let packet: Vec<u8> = self.pending.drain(..end + 3).collect();
+                    let data = packet[1..end].to_vec();
+                    let valid = parse_hex_u64(&packet[end + 1..])
+                        .map_or(false, |sum| sum == u64::from(checksum(&data)));
+                    if !self.no_ack {
+                        self.stream
+                            .write_all(if valid { &[ACK] } else { &[NACK] })?;
+                    }
+                    if valid {
+                        return Ok(Some(Incoming::Packet(data)));
+                    }
+                }
+                // Acknowledgments of our own packets, and noise between packets.
+                _ => {
+                    self.pending.remove(0);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// Encodes `data` as lowercase hex digits.
+pub fn encode_hex(data: &[u8]) -> Vec<u8> {
+    data.iter()
+        .flat_map(|b| format!("{:02x}", b).into_bytes())
+        .collect()
+}
+
+/// Decodes pairs of hex digits into bytes.
+pub fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| parse_hex_u64(pair).map(|b| b as u8))
+        .collect()
+}
+
+/// Parses a big-endian hex number, as used for addresses, lengths and thread ids.
+pub fn parse_hex_u64(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter().try_fold(0u64, |value, &digit| {
+        (digit as char)
+            .to_digit(16)
+            .map(|digit| (value << 4) | u64::from(digit))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    // A stream which reads from `input` and records what is written to it.
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl MockStream {
+        fn new(input: &[u8]) -> Self {
+            MockStream {
+                input: Cursor::new(input.to_vec()),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_read_packets() {
+        let mut conn = Connection::new(MockStream::new(b"+$g#67\x03$m10,4#bad$?#3f"));
+        assert_eq!(conn.read().unwrap(), Some(Incoming::Packet(b"g".to_vec())));
+        assert_eq!(conn.read().unwrap(), Some(Incoming::Interrupt));
+        // The packet with the bad checksum is skipped and asked for again.
+        assert_eq!(conn.read().unwrap(), Some(Incoming::Packet(b"?".to_vec())));
+        assert_eq!(conn.read().unwrap(), None);
+        assert_eq!(conn.stream().output, b"+-+".to_vec());
+    }
+
+    #[test]
+    fn test_no_ack_mode() {
+        let mut conn = Connection::new(MockStream::new(b"$g#67$g#67"));
+        conn.read().unwrap();
+        conn.start_no_ack_mode();
+        conn.read().unwrap();
+        assert_eq!(conn.stream().output, b"+".to_vec());
+    }
+
+    #[test]
+    fn test_write_packet() {
+        let mut conn = Connection::new(MockStream::new(b"-"));
+        conn.write_packet(b"OK").unwrap();
+        assert_eq!(conn.stream().output, b"$OK#9a".to_vec());
+        // A negative acknowledgment makes the packet go out again.
+        assert_eq!(conn.read().unwrap(), None);
+        assert_eq!(conn.stream().output, b"$OK#9a$OK#9a".to_vec());
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(encode_hex(&[0x00, 0xab, 0x10]), b"00ab10".to_vec());
+        assert_eq!(decode_hex(b"00AB10"), Some(vec![0x00, 0xab, 0x10]));
+        assert_eq!(decode_hex(b"0"), None);
+        assert_eq!(decode_hex(b"zz"), None);
+        assert_eq!(
+            parse_hex_u64(b"ffffffff81000000"),
+            Some(0xffff_ffff_8100_0000)
+        );
+        assert_eq!(parse_hex_u64(b""), None);
+        assert_eq!(parse_hex_u64(b"10000000000000000"), None);
+    }
+}
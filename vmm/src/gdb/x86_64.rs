@@ -0,0 +1,450 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The x86_64 specific parts of the GDB stub: the register layout GDB expects, the settings of
+//! `KVM_SET_GUEST_DEBUG` and the walk through the guest page tables.
+
+use kvm_bindings::{
+    kvm_guest_debug, kvm_regs, kvm_sregs, KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_INJECT_BP,
+    KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW_BP, KVM_GUESTDBG_USE_SW_BP,
+};
+use memory_model::{GuestAddress, GuestMemory};
+
+/// Number of hardware breakpoints, one per debug address register.
+pub const MAX_HW_BREAKPOINTS: usize = 4;
+
+/// The `int3` instruction written over the guest code for software breakpoints.
+pub const SW_BREAKPOINT_INSTR: u8 = 0xcc;
+
+/// Size of the smallest guest page.
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// The target description sent to GDB. Naming the architecture is enough for GDB to pick the
+/// register layout of `read_registers`, even without the guest kernel image at hand.
+pub const TARGET_XML: &str = "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+                              <target><architecture>i386:x86-64</architecture></target>";
+
+// GDB numbers the 16 general purpose registers, rip, eflags and the 6 segment selectors first.
+// The others (x87, SSE) are not reported, which GDB shows as unavailable.
+const NUM_CORE_REGS: usize = 24;
+const RIP_REG: usize = 16;
+const EFLAGS_REG: usize = 17;
+
+// Bits 9 and 10 of DR7, which the Intel SDM recommends setting.
+const DR7_GE_AND_RESERVED: u64 = 0x600;
+
+const CR0_PG: u64 = 1 << 31;
+const CR4_PSE: u64 = 1 << 4;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+const EFER_LMA: u64 = 1 << 10;
+
+const PTE_PRESENT: u64 = 1;
+const PTE_PAGE_SIZE: u64 = 1 << 7;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Returns the register at GDB register number `reg`, in target byte order.
+pub fn read_register(regs: &kvm_regs, sregs: &kvm_sregs, reg: usize) -> Option<Vec<u8>> {
+    let value = match reg {
+        0 => regs.rax,
+        1 => regs.rbx,
+        2 => regs.rcx,
+        3 => regs.rdx,
+        4 => regs.rsi,
+        5 => regs.rdi,
+        6 => regs.rbp,
+        7 => regs.rsp,
+        8 => regs.r8,
+        9 => regs.r9,
+        10 => regs.r10,
+        11 => regs.r11,
+        12 => regs.r12,
+        13 => regs.r13,
+        14 => regs.r14,
+        15 => regs.r15,
+        RIP_REG => regs.rip,
+        EFLAGS_REG => return Some((regs.rflags as u32).to_le_bytes().to_vec()),
+        18 => return Some(u32::from(sregs.cs.selector).to_le_bytes().to_vec()),
+        19 => return Some(u32::from(sregs.ss.selector).to_le_bytes().to_vec()),
+        20 => return Some(u32::from(sregs.ds.selector).to_le_bytes().to_vec()),
+        21 => return Some(u32::from(sregs.es.selector).to_le_bytes().to_vec()),
+        22 => return Some(u32::from(sregs.fs.selector).to_le_bytes().to_vec()),
+        23 => return Some(u32::from(sregs.gs.selector).to_le_bytes().to_vec()),
+        _ => return None,
+    };
+    Some(value.to_le_bytes().to_vec())
+}
+
+/// Writes `data` to the register at GDB register number `reg`. Only the general purpose
+/// registers, rip and eflags can be written. Returns whether the register was written.
+pub fn write_register(regs: &mut kvm_regs, reg: usize, data: &[u8]) -> bool {
+    if reg == EFLAGS_REG {
+        if data.len() != 4 {
+            return false;
+        }
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(data);
+        regs.rflags = u64::from(u32::from_le_bytes(bytes));
+        return true;
+    }
+    if data.len() != 8 {
+        return false;
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(data);
+    let value = u64::from_le_bytes(bytes);
+    let field = match reg {
+        0 => &mut regs.rax,
+        1 => &mut regs.rbx,
+        2 => &mut regs.rcx,
+        3 => &mut regs.rdx,
+        4 => &mut regs.rsi,
+        5 => &mut regs.rdi,
+        6 => &mut regs.rbp,
+        7 => &mut regs.rsp,
+        8 => &mut regs.r8,
+        9 => &mut regs.r9,
+        10 => &mut regs.r10,
+        11 => &mut regs.r11,
+        12 => &mut regs.r12,
+        13 => &mut regs.r13,
+        14 => &mut regs.r14,
+        15 => &mut regs.r15,
+        RIP_REG => &mut regs.rip,
+        _ => return false,
+    };
+    *field = value;
+    true
+}
+
+/// Returns the registers for a `g` packet, in GDB register number order.
+pub fn read_registers(regs: &kvm_regs, sregs: &kvm_sregs) -> Vec<u8> {
+    (0..NUM_CORE_REGS)
+        .filter_map(|reg| read_register(regs, sregs, reg))
+        .flat_map(|bytes| bytes)
+        .collect()
+}
+
+/// Writes the registers of a `G` packet. The segment selectors are left as they are. Returns
+/// whether `data` was long enough.
+pub fn write_registers(regs: &mut kvm_regs, data: &[u8]) -> bool {
+    let mut offset = 0;
+    for reg in 0..=EFLAGS_REG {
+        let size = if reg == EFLAGS_REG { 4 } else { 8 };
+        if data.len() < offset + size || !write_register(regs, reg, &data[offset..offset + size]) {
+            return false;
+        }
+        offset += size;
+    }
+    true
+}
+
+/// The debugging features a vCPU runs with.
+#[derive(Debug, Default)]
+pub struct GuestDebugConfig<'a> {
+    /// Software breakpoints are set, so `int3` exits to the VMM.
+    pub sw_breakpoints: bool,
+    /// Addresses of the hardware breakpoints.
+    pub hw_breakpoints: &'a [u64],
+    /// The vCPU exits after each instruction.
+    pub single_step: bool,
+    /// The `int3` the vCPU last exited on belongs to the guest, and has to be delivered to it.
+    pub inject_breakpoint: bool,
+}
+
+/// Builds the argument of `KVM_SET_GUEST_DEBUG` for `config`. Passing `None` disables
+/// debugging altogether.
+pub fn guest_debug(config: Option<&GuestDebugConfig>) -> kvm_guest_debug {
+    let mut debug = kvm_guest_debug::default();
+    let config = match config {
+        Some(config) => config,
+        None => return debug,
+    };
+
+    debug.control = KVM_GUESTDBG_ENABLE;
+    if config.sw_breakpoints {
+        debug.control |= KVM_GUESTDBG_USE_SW_BP;
+    }
+    if config.single_step {
+        debug.control |= KVM_GUESTDBG_SINGLESTEP;
+    }
+    if config.inject_breakpoint {
+        debug.control |= KVM_GUESTDBG_INJECT_BP;
+    }
+    if !config.hw_breakpoints.is_empty() {
+        debug.control |= KVM_GUESTDBG_USE_HW_BP;
+        debug.arch.debugreg[7] = DR7_GE_AND_RESERVED;
+        for (i, &addr) in config
+            .hw_breakpoints
+            .iter()
+            .take(MAX_HW_BREAKPOINTS)
+            .enumerate()
+        {
+            debug.arch.debugreg[i] = addr;
+            // Local enable, with the type and length bits left to 0 for an instruction
+            // breakpoint.
+            debug.arch.debugreg[7] |= 1 << (2 * i);
+        }
+    }
+    debug
+}
+
+/// Translates the guest virtual address `gva` to a guest physical address, by walking the page
+/// tables of the vCPU with the special registers `sregs`. Returns `None` when `gva` is not mapped.
+pub fn translate_gva(mem: &GuestMemory, sregs: &kvm_sregs, gva: u64) -> Option<GuestAddress> {
+    if sregs.cr0 & CR0_PG == 0 {
+        return Some(GuestAddress(gva as usize));
+    }
+
+    if sregs.efer & EFER_LMA != 0 {
+        let levels = if sregs.cr4 & CR4_LA57 != 0 { 5 } else { 4 };
+        walk(mem, sregs.cr3 & PTE_ADDR_MASK, gva, levels, 9, 8)
+    } else if sregs.cr4 & CR4_PAE != 0 {
+        // The 4 entries of the page directory pointer table are not a full level: they are
+        // indexed by bits 31:30 and have no page size bit.
+        let gva = gva & 0xffff_ffff;
+        let pdpte: u64 = mem
+            .read_obj_from_addr(GuestAddress(
+                ((sregs.cr3 & 0xffff_ffe0) + (gva >> 30) * 8) as usize,
+            ))
+            .ok()?;
+        if pdpte & PTE_PRESENT == 0 {
+            return None;
+        }
+        walk(mem, pdpte & PTE_ADDR_MASK, gva, 2, 9, 8)
+    } else {
+        let gva = gva & 0xffff_ffff;
+        // 4 MiB pages only exist with CR4.PSE.
+        let pde_addr = (sregs.cr3 & 0xffff_f000) + (gva >> 22) * 4;
+        let pde = u64::from(
+            mem.read_obj_from_addr::<u32>(GuestAddress(pde_addr as usize))
+                .ok()?,
+        );
+        if pde & PTE_PRESENT == 0 {
+            return None;
+        }
+        if pde & PTE_PAGE_SIZE != 0 && sregs.cr4 & CR4_PSE != 0 {
+            return Some(GuestAddress(
+                ((pde & 0xffc0_0000) | (gva & 0x3f_ffff)) as usize,
+            ));
+        }
+        walk(mem, pde & 0xffff_f000, gva, 1, 10, 4)
+    }
+}
+
+// Walks `levels` levels of page tables starting with the table at `table`. Each table is indexed
+// by `index_bits` bits of `gva` and holds entries of `entry_size` bytes. Large pages can be
+// mapped from any level but the first and the last.
+fn walk(
+    mem: &GuestMemory,
+    mut table: u64,
+    gva: u64,
+    levels: u32,
+    index_bits: u32,
+    entry_size: u64,
+) -> Option<GuestAddress> {
+    let index_mask = (1 << index_bits) - 1;
+    for level in (0..levels).rev() {
+        let shift = 12 + index_bits * level;
+        let entry_addr = table + ((gva >> shift) & index_mask) * entry_size;
+        let entry = if entry_size == 8 {
+            mem.read_obj_from_addr::<u64>(GuestAddress(entry_addr as usize))
+                .ok()?
+        } else {
+            u64::from(
+                mem.read_obj_from_addr::<u32>(GuestAddress(entry_addr as usize))
+                    .ok()?,
+            )
+        };
+        if entry & PTE_PRESENT == 0 {
+            return None;
+        }
+        if level > 0 && entry & PTE_PAGE_SIZE != 0 {
+            let offset_mask = (1u64 << shift) - 1;
+            return Some(GuestAddress(
+                ((entry & PTE_ADDR_MASK & !offset_mask) | (gva & offset_mask)) as usize,
+            ));
+        }
+        table = entry & PTE_ADDR_MASK;
+    }
+    Some(GuestAddress((table | (gva & (PAGE_SIZE - 1))) as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memory_model::MemoryBacking;
+
+    fn create_guest_mem() -> GuestMemory {
+        GuestMemory::new(&[(GuestAddress(0), 0x40_0000)], MemoryBacking::Anonymous).unwrap()
+    }
+
+    fn write_entry(mem: &GuestMemory, table: usize, index: u64, entry: u64) {
+        mem.write_obj_at_addr(entry, GuestAddress(table + index as usize * 8))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut regs = kvm_regs::default();
+        let mut sregs = kvm_sregs::default();
+        regs.rax = 0x1122_3344_5566_7788;
+        regs.rsp = 0x10;
+        regs.rip = 0xffff_ffff_8100_0000;
+        regs.rflags = 0x246;
+        sregs.cs.selector = 0x10;
+        sregs.gs.selector = 0x18;
+
+        let data = read_registers(&regs, &sregs);
+        assert_eq!(data.len(), 17 * 8 + 7 * 4);
+        assert_eq!(&data[..8], &0x1122_3344_5566_7788u64.to_le_bytes());
+        assert_eq!(&data[7 * 8..8 * 8], &0x10u64.to_le_bytes());
+        assert_eq!(&data[16 * 8..17 * 8], &regs.rip.to_le_bytes());
+        assert_eq!(&data[17 * 8..17 * 8 + 4], &0x246u32.to_le_bytes());
+        assert_eq!(&data[17 * 8 + 4..17 * 8 + 8], &0x10u32.to_le_bytes());
+        assert_eq!(&data[17 * 8 + 24..], &0x18u32.to_le_bytes());
+        assert_eq!(read_register(&regs, &sregs, 24), None);
+
+        let mut written = kvm_regs::default();
+        assert!(write_registers(&mut written, &data));
+        assert_eq!(written, regs);
+        assert!(!write_registers(&mut written, &data[..16]));
+
+        assert!(write_register(&mut written, 2, &7u64.to_le_bytes()));
+        assert_eq!(written.rcx, 7);
+        assert!(!write_register(&mut written, 2, &7u32.to_le_bytes()));
+        assert!(!write_register(&mut written, 18, &7u32.to_le_bytes()));
+    }
+
+    #[test]
+    fn test_guest_debug() {
+        assert_eq!(guest_debug(None).control, 0);
+
+        let hw_breakpoints = [0x1000, 0x2000];
+        let debug = guest_debug(Some(&GuestDebugConfig {
+            sw_breakpoints: true,
+            hw_breakpoints: &hw_breakpoints,
+            single_step: true,
+            inject_breakpoint: false,
+        }));
+        assert_eq!(
+            debug.control,
+            KVM_GUESTDBG_ENABLE
+                | KVM_GUESTDBG_USE_SW_BP
+                | KVM_GUESTDBG_USE_HW_BP
+                | KVM_GUESTDBG_SINGLESTEP
+        );
+        assert_eq!(debug.arch.debugreg[0], 0x1000);
+        assert_eq!(debug.arch.debugreg[1], 0x2000);
+        assert_eq!(debug.arch.debugreg[7], 0x605);
+
+        let debug = guest_debug(Some(&GuestDebugConfig {
+            inject_breakpoint: true,
+            ..Default::default()
+        }));
+        assert_eq!(debug.control, KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_INJECT_BP);
+        assert_eq!(debug.arch.debugreg[7], 0);
+    }
+
+    #[test]
+    fn test_translate_without_paging() {
+        let mem = create_guest_mem();
+        let sregs = kvm_sregs::default();
+        assert_eq!(
+            translate_gva(&mem, &sregs, 0x1234),
+            Some(GuestAddress(0x1234))
+        );
+    }
+
+    #[test]
+    fn test_translate_long_mode() {
+        let mem = create_guest_mem();
+        let mut sregs = kvm_sregs::default();
+        sregs.cr0 = CR0_PG;
+        sregs.cr4 = CR4_PAE;
+        sregs.efer = EFER_LMA;
+        sregs.cr3 = 0x1000;
+
+        // Map 0xffff_ffff_8000_0000 with a 2 MiB page at 0x20_0000, and the next 2 MiB with a
+        // page table whose second entry points to 0x5000.
+        let gva = 0xffff_ffff_8000_0000u64;
+        write_entry(&mem, 0x1000, (gva >> 39) & 0x1ff, 0x2003);
+        write_entry(&mem, 0x2000, (gva >> 30) & 0x1ff, 0x3003);
+        write_entry(&mem, 0x3000, 0, 0x20_0083);
+        write_entry(&mem, 0x3000, 1, 0x4003);
+        write_entry(&mem, 0x4000, 1, 0x5003);
+
+        assert_eq!(
+            translate_gva(&mem, &sregs, gva + 0x1_2345),
+            Some(GuestAddress(0x21_2345))
+        );
+        assert_eq!(
+            translate_gva(&mem, &sregs, gva + 0x20_1abc),
+            Some(GuestAddress(0x5abc))
+        );
+        // Not present.
+        assert_eq!(translate_gva(&mem, &sregs, gva + 0x20_2000), None);
+        assert_eq!(translate_gva(&mem, &sregs, 0x1000), None);
+
+        // A 1 GiB page.
+        write_entry(&mem, 0x1000, 0, 0x2003);
+        write_entry(&mem, 0x2000, 0, 0x4000_0083);
+        assert_eq!(
+            translate_gva(&mem, &sregs, 0x12_3456),
+            Some(GuestAddress(0x4012_3456))
+        );
+    }
+
+    #[test]
+    fn test_translate_pae() {
+        let mem = create_guest_mem();
+        let mut sregs = kvm_sregs::default();
+        sregs.cr0 = CR0_PG;
+        sregs.cr4 = CR4_PAE;
+        sregs.cr3 = 0x1000;
+
+        // 0xc000_0000 is in the fourth PDPT entry.
+        write_entry(&mem, 0x1000, 3, 0x2001);
+        write_entry(&mem, 0x2000, 0, 0x3003);
+        write_entry(&mem, 0x3000, 5, 0x7003);
+        write_entry(&mem, 0x2000, 1, 0x20_0083);
+
+        assert_eq!(
+            translate_gva(&mem, &sregs, 0xc000_5123),
+            Some(GuestAddress(0x7123))
+        );
+        assert_eq!(
+            translate_gva(&mem, &sregs, 0xc020_0010),
+            Some(GuestAddress(0x20_0010))
+        );
+        assert_eq!(translate_gva(&mem, &sregs, 0x8000_0000), None);
+    }
+
+    #[test]
+    fn test_translate_32bit() {
+        let mem = create_guest_mem();
+        let mut sregs = kvm_sregs::default();
+        sregs.cr0 = CR0_PG;
+        sregs.cr3 = 0x1000;
+
+        // A 4 MiB page, which needs CR4.PSE, and a 4 KiB page.
+        mem.write_obj_at_addr(0x0000_0083u32, GuestAddress(0x1000 + 3 * 4))
+            .unwrap();
+        mem.write_obj_at_addr(0x2003u32, GuestAddress(0x1000))
+            .unwrap();
+        mem.write_obj_at_addr(0x9003u32, GuestAddress(0x2000 + 4 * 4))
+            .unwrap();
+
+        assert_eq!(
+            translate_gva(&mem, &sregs, 0x4abc),
+            Some(GuestAddress(0x9abc))
+        );
+        sregs.cr4 = CR4_PSE;
+        assert_eq!(
+            translate_gva(&mem, &sregs, 0xc1_2345),
+            Some(GuestAddress(0x1_2345))
+        );
+        assert_eq!(translate_gva(&mem, &sregs, 0x40_0000), None);
+    }
+}
@@ -31,16 +31,24 @@ extern crate kernel;
 #[macro_use]
 extern crate logger;
 extern crate memory_model;
+extern crate mmds;
 extern crate net_util;
 extern crate rate_limiter;
 extern crate seccomp;
+#[macro_use]
 extern crate sys_util;
 
+#[cfg(target_arch = "x86_64")]
+mod coredump;
 /// Syscalls allowed through the seccomp filter.
 pub mod default_syscalls;
 mod device_manager;
+#[cfg(target_arch = "x86_64")]
+mod gdb;
+mod migration;
 /// Signal handling utilities for seccomp violations.
 mod sigsys_handler;
+mod snapshot;
 /// Wrappers over structures used to configure the VMM.
 pub mod vmm_config;
 mod vstate;
@@ -49,13 +57,17 @@ use futures::sync::oneshot;
 use std::collections::HashMap;
 use std::ffi::CString;
 use std::fmt::{Display, Formatter};
-use std::fs::{metadata, File, OpenOptions};
-use std::io;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::path::PathBuf;
+#[cfg(target_arch = "x86_64")]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::thread::JoinHandleExt;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
-use std::sync::{Arc, Barrier, RwLock};
+use std::sync::{Arc, Barrier, Mutex, RwLock};
 use std::thread;
 use std::time::Duration;
 
@@ -67,33 +79,56 @@ use device_manager::legacy::LegacyDeviceManager;
 use device_manager::mmio::MMIODeviceManager;
 use devices::legacy::I8042DeviceError;
 use devices::virtio;
+use devices::virtio::{
+    CacheMode, CryptError, CryptFile, DiskFile, NbdDisk, NbdError, VerityError, VerityFile,
+};
 use devices::{DeviceEventT, EpollHandler, EpollHandlerPayload};
 use fc_util::now_cputime_us;
 use kernel::cmdline as kernel_cmdline;
 use kernel::loader as kernel_loader;
 use logger::error::LoggerError;
 use logger::{AppInfo, Level, LogOption, Metric, LOGGER, METRICS};
-use memory_model::{GuestAddress, GuestMemory};
+use memory_model::{
+    GuestAddress, GuestMemory, GuestMemoryError, MemoryBacking, MemoryMappingError,
+};
+#[cfg(target_arch = "x86_64")]
+use migration::MigrationHeader;
 use net_util::TapError;
 #[cfg(target_arch = "aarch64")]
 use serde_json::Value;
 pub use sigsys_handler::setup_sigsys_handler;
-use sys_util::{EventFd, Terminal};
+#[cfg(target_arch = "x86_64")]
+use snapshot::MicrovmState;
+use snapshot::Snapshot;
+use sys_util::{EventFd, Killable, Terminal};
+use vmm_config::balloon::{BalloonConfig, BalloonError, BalloonUpdateConfig};
 use vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError};
-use vmm_config::drive::{BlockDeviceConfig, BlockDeviceConfigs, DriveError};
+use vmm_config::config_file::VmmConfig;
+use vmm_config::coredump::{CoreDumpConfig, CoreDumpError};
+use vmm_config::drive::{
+    BlockDeviceConfig, BlockDeviceConfigs, CacheType, DriveEncryptionKey, DriveError,
+    EncryptionConfig, IoEngine, NbdConfig, VerityConfig,
+};
 use vmm_config::instance_info::{InstanceInfo, InstanceState, StartMicrovmError};
 use vmm_config::logger::{LoggerConfig, LoggerConfigError, LoggerLevel};
-use vmm_config::machine_config::{VmConfig, VmConfigError};
+use vmm_config::machine_config::{
+    validate_cpu_affinity, LifecycleAction, MemoryBackingType, PanicAction, VmConfig, VmConfigError,
+};
+use vmm_config::migration::{MigrationConfig, MigrationError};
 use vmm_config::net::{
     NetworkInterfaceConfig, NetworkInterfaceConfigs, NetworkInterfaceError,
     NetworkInterfaceUpdateConfig,
 };
+use vmm_config::snapshot::{SnapshotConfig, SnapshotError};
+use vmm_config::vm_state::VmStateError;
 #[cfg(feature = "vsock")]
 use vmm_config::vsock::{VsockDeviceConfig, VsockDeviceConfigs, VsockError};
-use vstate::{Vcpu, Vm};
+use vstate::{Vcpu, VcpuExitReason, VcpuPauseControl, Vm, VCPU_RTSIG_OFFSET};
+#[cfg(target_arch = "x86_64")]
+use vstate::{VcpuKvmState, VmKvmState};
 
 /// Default guest kernel command line:
-/// - `reboot=k` shut down the guest on reboot, instead of well... rebooting;
+/// - `reboot=k` reboot through the i8042 controller, which Firecracker catches;
 /// - `panic=1` on panic, reboot after 1 second;
 /// - `pci=off` do not scan for PCI devices (save boot time);
 /// - `nomodules` disable loadable kernel module support;
@@ -105,6 +140,10 @@ use vstate::{Vcpu, Vm};
 const DEFAULT_KERNEL_CMDLINE: &str = "reboot=k panic=1 pci=off nomodules 8250.nr_uarts=0 \
                                       i8042.noaux i8042.nomux i8042.nopnp i8042.dumbkbd";
 const WRITE_METRICS_PERIOD_SECONDS: u64 = 60;
+// How many times the vCPUs are kicked out of KVM_RUN when pausing, before giving up.
+const VCPU_PAUSE_KICK_ATTEMPTS: u32 = 100;
+// How long to wait for the vCPUs to park themselves after each kick.
+const VCPU_PAUSE_KICK_INTERVAL_MS: u64 = 10;
 
 /// Success exit code.
 pub const FC_EXIT_CODE_OK: u8 = 0;
@@ -112,8 +151,16 @@ pub const FC_EXIT_CODE_OK: u8 = 0;
 pub const FC_EXIT_CODE_GENERIC_ERROR: u8 = 1;
 /// Generic exit code for an error considered not possible to occur if the program logic is sound.
 pub const FC_EXIT_CODE_UNEXPECTED_ERROR: u8 = 2;
+/// The guest powered off and the lifecycle action for it is `Exit`.
+pub const FC_EXIT_CODE_GUEST_POWEROFF: u8 = 3;
+/// The guest rebooted and the lifecycle action for it is `Exit`.
+pub const FC_EXIT_CODE_GUEST_REBOOT: u8 = 4;
+/// The guest kernel reported a panic and the panic action is `Exit`.
+pub const FC_EXIT_CODE_GUEST_PANIC: u8 = 5;
 /// Firecracker was shut down after intercepting a restricted system call.
 pub const FC_EXIT_CODE_BAD_SYSCALL: u8 = 148;
+/// The microVM described by the configuration file could not be booted.
+pub const FC_EXIT_CODE_BAD_CONFIGURATION: u8 = 152;
 
 /// Errors associated with the VMM internal logic. These errors cannot be generated by direct user
 /// input, but can result from bad configuration of the host (for example if Firecracker doesn't
@@ -195,9 +242,16 @@ impl PartialEq for ErrorKind {
 /// Wrapper for all errors associated with VMM actions.
 #[derive(Debug)]
 pub enum VmmActionError {
+    /// One of the actions `InsertBalloonDevice`, `UpdateBalloon`, `GetBalloonConfig` or
+    /// `GetBalloonStats` failed either because of bad user input (`ErrorKind::User`) or an
+    /// internal error (`ErrorKind::Internal`).
+    Balloon(ErrorKind, BalloonError),
     /// The action `ConfigureBootSource` failed either because of bad user input (`ErrorKind::User`)
     /// or an internal error (`ErrorKind::Internal`).
     BootSource(ErrorKind, BootSourceConfigError),
+    /// The action `CreateCoreDump` failed either because of bad user input (`ErrorKind::User`)
+    /// or an internal error (`ErrorKind::Internal`).
+    CoreDump(ErrorKind, CoreDumpError),
     /// One of the actions `InsertBlockDevice`, `RescanBlockDevice` or `UpdateBlockDevicePath`
     /// failed either because of bad user input (`ErrorKind::User`) or an
     /// internal error (`ErrorKind::Internal`).
@@ -208,6 +262,9 @@ pub enum VmmActionError {
     /// One of the actions `GetVmConfiguration` or `SetVmConfiguration` failed either because of bad
     /// input (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
     MachineConfig(ErrorKind, VmConfigError),
+    /// One of the actions `SendMigration` or `ReceiveMigration` failed either because of bad user
+    /// input (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
+    Migration(ErrorKind, MigrationError),
     /// The action `InsertNetworkDevice` failed either because of bad user input (`ErrorKind::User`)
     /// or an internal error (`ErrorKind::Internal`).
     NetworkConfig(ErrorKind, NetworkInterfaceError),
@@ -217,12 +274,34 @@ pub enum VmmActionError {
     /// The action `SendCtrlAltDel` failed. Details are provided by the device-specific error
     /// `I8042DeviceError`.
     SendCtrlAltDel(ErrorKind, I8042DeviceError),
+    /// One of the actions `CreateSnapshot` or `LoadSnapshot` failed either because of bad user
+    /// input (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
+    Snapshot(ErrorKind, SnapshotError),
+    /// One of the actions `PauseVm` or `ResumeVm` failed either because of bad user input
+    /// (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
+    VmState(ErrorKind, VmStateError),
     #[cfg(feature = "vsock")]
     /// The action `insert_vsock_device` failed either because of bad user input (`ErrorKind::User`)
     /// or an internal error (`ErrorKind::Internal`).
     VsockConfig(ErrorKind, VsockError),
 }
 
+// It's convenient to turn BalloonErrors into VmmActionErrors directly.
+impl std::convert::From<BalloonError> for VmmActionError {
+    fn from(e: BalloonError) -> Self {
+        let kind = match e {
+            // User errors.
+            BalloonError::DeviceNotFound
+            | BalloonError::TooManyPagesRequested
+            | BalloonError::StatisticsDisabled
+            | BalloonError::UpdateNotAllowedPostBoot => ErrorKind::User,
+            // Internal errors.
+            BalloonError::ConfigChangeFailed(_) => ErrorKind::Internal,
+        };
+        VmmActionError::Balloon(kind, e)
+    }
+}
+
 // It's convenient to turn DriveErrors into VmmActionErrors directly.
 impl std::convert::From<DriveError> for VmmActionError {
     fn from(e: DriveError) -> Self {
@@ -235,7 +314,23 @@ impl std::convert::From<DriveError> for VmmActionError {
             | DriveError::BlockDeviceUpdateFailed
             | DriveError::OperationNotAllowedPreBoot
             | DriveError::UpdateNotAllowedPostBoot
-            | DriveError::RootBlockDeviceAlreadyAdded => ErrorKind::User,
+            | DriveError::RootBlockDeviceAlreadyAdded
+            | DriveError::InvalidDiskImage(_)
+            | DriveError::AsyncIoEngineImageFormat
+            | DriveError::ReadOnlyOverlay
+            | DriveError::BaseImageUpdateNotAllowed
+            | DriveError::DirectIoImageFormat
+            | DriveError::InvalidQueueCount
+            | DriveError::InvalidQueueSize
+            | DriveError::VerityNotReadOnly
+            | DriveError::InvalidVerityHash
+            | DriveError::InvalidEncryptionKey
+            | DriveError::EncryptedImageFormat
+            | DriveError::NbdImageFormat
+            | DriveError::ConnectNbdServer(_)
+            | DriveError::VhostUserOption
+            | DriveError::VhostUserUpdateNotAllowed
+            | DriveError::EmptyDriveOption => ErrorKind::User,
         };
         VmmActionError::DriveConfig(kind, e)
     }
@@ -249,7 +344,10 @@ impl std::convert::From<VmConfigError> for VmmActionError {
                 // User errors.
                 VmConfigError::InvalidVcpuCount
                 | VmConfigError::InvalidMemorySize
-                | VmConfigError::UpdateNotAllowedPostBoot => ErrorKind::User,
+                | VmConfigError::UpdateNotAllowedPostBoot
+                | VmConfigError::InvalidCpuAffinity
+                | VmConfigError::InvalidVcpuAffinityCount
+                | VmConfigError::InvalidSchedulingPriority => ErrorKind::User,
             },
             e,
         )
@@ -288,25 +386,49 @@ impl std::convert::From<StartMicrovmError> for VmmActionError {
             // User errors.
             #[cfg(feature = "vsock")]
             StartMicrovmError::CreateVsockDevice(_) => ErrorKind::User,
-            StartMicrovmError::CreateBlockDevice(_)
+            #[cfg(target_arch = "x86_64")]
+            StartMicrovmError::GdbServer(gdb::Error::Bind(_)) => ErrorKind::User,
+            StartMicrovmError::AsyncIoEngineImageFormat
+            | StartMicrovmError::ConnectNbdServer(_)
+            | StartMicrovmError::CreateBlockDevice(_)
+            | StartMicrovmError::CreateEncryption(_)
+            | StartMicrovmError::CreateIoUring(_)
             | StartMicrovmError::CreateNetDevice(_)
+            | StartMicrovmError::CreateOverlay(_)
+            | StartMicrovmError::CreateVerity(_)
+            | StartMicrovmError::CreateVhostUserBlock(_)
+            | StartMicrovmError::DirectIoImageFormat
+            | StartMicrovmError::HugepagesUnavailable(_)
+            | StartMicrovmError::InvalidDiskImage(_)
+            | StartMicrovmError::InvalidHugepagesMemorySize
             | StartMicrovmError::KernelCmdline(_)
             | StartMicrovmError::KernelLoader(_)
             | StartMicrovmError::MicroVMAlreadyRunning
             | StartMicrovmError::MissingKernelConfig
             | StartMicrovmError::NetDeviceNotConfigured
             | StartMicrovmError::OpenBlockDevice(_)
-            | StartMicrovmError::VcpusNotConfigured => ErrorKind::User,
+            | StartMicrovmError::VcpuAffinity(_, _)
+            | StartMicrovmError::VcpuScheduling(_, _)
+            | StartMicrovmError::VcpusNotConfigured
+            | StartMicrovmError::VmmAffinity(_)
+            | StartMicrovmError::VmmScheduling(_) => ErrorKind::User,
             // Internal errors.
             #[cfg(feature = "vsock")]
             StartMicrovmError::RegisterVsockDevice(_) => ErrorKind::Internal,
-            StartMicrovmError::ConfigureSystem(_)
+            #[cfg(not(target_arch = "x86_64"))]
+            StartMicrovmError::RegisterPvPanicDevice(_) => ErrorKind::Internal,
+            #[cfg(target_arch = "x86_64")]
+            StartMicrovmError::GdbServer(gdb::Error::Spawn(_)) => ErrorKind::Internal,
+            StartMicrovmError::CloneTap(_)
+            | StartMicrovmError::ConfigureSystem(_)
             | StartMicrovmError::ConfigureVm(_)
+            | StartMicrovmError::CreateBalloonDevice(_)
             | StartMicrovmError::CreateRateLimiter(_)
             | StartMicrovmError::DeviceManager
             | StartMicrovmError::EventFd
             | StartMicrovmError::GuestMemory(_)
             | StartMicrovmError::LegacyIOBus(_)
+            | StartMicrovmError::RegisterBalloonDevice(_)
             | StartMicrovmError::RegisterBlockDevice(_)
             | StartMicrovmError::RegisterEvent
             | StartMicrovmError::RegisterNetDevice(_)
@@ -324,19 +446,118 @@ impl std::convert::From<StartMicrovmError> for VmmActionError {
     }
 }
 
+// It's convenient to turn VmStateErrors into VmmActionErrors directly.
+impl std::convert::From<VmStateError> for VmmActionError {
+    fn from(e: VmStateError) -> Self {
+        let kind = match e {
+            // User errors.
+            VmStateError::MicroVMNotRunning | VmStateError::MicroVMNotPaused => ErrorKind::User,
+            // Internal errors.
+            VmStateError::RebootNotSupported
+            | VmStateError::SignalVcpu(_)
+            | VmStateError::VcpuPauseTimeout
+            | VmStateError::DrainBlockDevice(_) => ErrorKind::Internal,
+        };
+        VmmActionError::VmState(kind, e)
+    }
+}
+
+// It's convenient to turn CoreDumpErrors into VmmActionErrors directly.
+impl std::convert::From<CoreDumpError> for VmmActionError {
+    fn from(e: CoreDumpError) -> Self {
+        let kind = match e {
+            // User errors.
+            CoreDumpError::MicroVMNotRunning
+            | CoreDumpError::UnsupportedArch
+            | CoreDumpError::DumpFile(_) => ErrorKind::User,
+            // Internal errors.
+            CoreDumpError::GuestMemory(_) | CoreDumpError::MissingVcpuState(_) => {
+                ErrorKind::Internal
+            }
+        };
+        VmmActionError::CoreDump(kind, e)
+    }
+}
+
+// It's convenient to turn SnapshotErrors into VmmActionErrors directly.
+impl std::convert::From<SnapshotError> for VmmActionError {
+    fn from(e: SnapshotError) -> Self {
+        let kind = match e {
+            // User errors.
+            SnapshotError::MicroVMNotPaused
+            | SnapshotError::MicroVMAlreadyRunning
+            | SnapshotError::VsockNotSupported
+            | SnapshotError::VhostUserNotSupported
+            | SnapshotError::InMemoryOverlayNotSupported
+            | SnapshotError::UnsupportedArch
+            | SnapshotError::SnapshotFile(_)
+            | SnapshotError::MemoryFile(_)
+            | SnapshotError::Deserialize(_)
+            | SnapshotError::InvalidVersion(_)
+            | SnapshotError::InvalidMemoryLayout
+            | SnapshotError::InvalidVcpuCount
+            | SnapshotError::MissingEncryptionKey(_)
+            | SnapshotError::RestoreDevices(_) => ErrorKind::User,
+            // Internal errors.
+            SnapshotError::GuestMemory(_)
+            | SnapshotError::Serialize(_)
+            | SnapshotError::MissingVcpuState(_)
+            | SnapshotError::SaveVmState(_)
+            | SnapshotError::RestoreVmState(_)
+            | SnapshotError::RestoreVcpuState(_) => ErrorKind::Internal,
+        };
+        VmmActionError::Snapshot(kind, e)
+    }
+}
+
+// It's convenient to turn MigrationErrors into VmmActionErrors directly.
+impl std::convert::From<MigrationError> for VmmActionError {
+    fn from(e: MigrationError) -> Self {
+        let kind = match e {
+            // User errors.
+            MigrationError::MicroVMNotRunning
+            | MigrationError::MicroVMAlreadyRunning
+            | MigrationError::VsockNotSupported
+            | MigrationError::VhostUserNotSupported
+            | MigrationError::InMemoryOverlayNotSupported
+            | MigrationError::UnsupportedArch
+            | MigrationError::Socket(_)
+            | MigrationError::InvalidStream
+            | MigrationError::InvalidVersion(_)
+            | MigrationError::InvalidMemoryLayout
+            | MigrationError::MissingEncryptionKey(_) => ErrorKind::User,
+            // Internal errors.
+            MigrationError::Send(_)
+            | MigrationError::Receive(_)
+            | MigrationError::UnexpectedMessage(_)
+            | MigrationError::GuestMemory(_)
+            | MigrationError::Serialize(_)
+            | MigrationError::Deserialize(_)
+            | MigrationError::DirtyPageTracking(_)
+            | MigrationError::DestinationFailed => ErrorKind::Internal,
+        };
+        VmmActionError::Migration(kind, e)
+    }
+}
+
 impl VmmActionError {
     /// Returns the error type.
     pub fn kind(&self) -> &ErrorKind {
         use self::VmmActionError::*;
 
         match *self {
+            Balloon(ref kind, _) => kind,
             BootSource(ref kind, _) => kind,
+            CoreDump(ref kind, _) => kind,
             DriveConfig(ref kind, _) => kind,
             Logger(ref kind, _) => kind,
             MachineConfig(ref kind, _) => kind,
+            Migration(ref kind, _) => kind,
             NetworkConfig(ref kind, _) => kind,
             StartMicrovm(ref kind, _) => kind,
             SendCtrlAltDel(ref kind, _) => kind,
+            Snapshot(ref kind, _) => kind,
+            VmState(ref kind, _) => kind,
             #[cfg(feature = "vsock")]
             VsockConfig(ref kind, _) => kind,
         }
@@ -348,13 +569,18 @@ impl Display for VmmActionError {
         use self::VmmActionError::*;
 
         match *self {
+            Balloon(_, ref err) => write!(f, "{}", err.to_string()),
             BootSource(_, ref err) => write!(f, "{}", err.to_string()),
+            CoreDump(_, ref err) => write!(f, "{}", err.to_string()),
             DriveConfig(_, ref err) => write!(f, "{}", err.to_string()),
             Logger(_, ref err) => write!(f, "{}", err.to_string()),
             MachineConfig(_, ref err) => write!(f, "{}", err.to_string()),
+            Migration(_, ref err) => write!(f, "{}", err.to_string()),
             NetworkConfig(_, ref err) => write!(f, "{}", err.to_string()),
             StartMicrovm(_, ref err) => write!(f, "{}", err.to_string()),
             SendCtrlAltDel(_, ref err) => write!(f, "{}", err.to_string()),
+            Snapshot(_, ref err) => write!(f, "{}", err.to_string()),
+            VmState(_, ref err) => write!(f, "{}", err.to_string()),
             #[cfg(feature = "vsock")]
             VsockConfig(_, ref err) => write!(f, "{}", err.to_string()),
         }
@@ -373,11 +599,34 @@ pub enum VmmAction {
     /// Configure the logger using as input the `LoggerConfig`. This action can only be called
     /// before the microVM has booted. The response is sent using the `OutcomeSender`.
     ConfigureLogger(LoggerConfig, OutcomeSender),
+    /// Pause the vCPUs and write the guest memory and their registers to the ELF core file
+    /// described by `CoreDumpConfig`. The microVM is then resumed or left paused, as requested.
+    /// This action can only be called after the microVM is started. The response is sent using
+    /// the `OutcomeSender`.
+    CreateCoreDump(CoreDumpConfig, OutcomeSender),
+    /// Save the state of a paused microVM to the snapshot file and the contents of its memory to
+    /// the memory file described by `SnapshotConfig`. The microVM stays paused. The response is
+    /// sent using the `OutcomeSender`.
+    CreateSnapshot(SnapshotConfig, OutcomeSender),
+    /// Get the configuration of the balloon device. The action response is sent using the
+    /// `OutcomeSender`.
+    GetBalloonConfig(OutcomeSender),
+    /// Get the latest memory statistics reported by the guest through the balloon device. This
+    /// action can only be called if the statistics were enabled. The action response is sent
+    /// using the `OutcomeSender`.
+    GetBalloonStats(OutcomeSender),
     /// Get the configuration of the microVM. The action response is sent using the `OutcomeSender`.
     GetVmConfiguration(OutcomeSender),
+    /// Get the whole configuration of the microVM, with the defaults filled in, in the format of
+    /// a configuration file. The action response is sent using the `OutcomeSender`.
+    GetVmmConfiguration(OutcomeSender),
     /// Flush the metrics. This action can only be called after the logger has been configured.
     /// The response is sent using the `OutcomeSender`.
     FlushMetrics(OutcomeSender),
+    /// Add the balloon device or replace its configuration using the `BalloonConfig` as input.
+    /// This action can only be called before the microVM has booted. The response is sent using
+    /// the `OutcomeSender`.
+    InsertBalloonDevice(BalloonConfig, OutcomeSender),
     /// Add a new block device or update one that already exists using the `BlockDeviceConfig` as
     /// input. This action can only be called before the microVM has booted. The response
     /// is sent using the `OutcomeSender`.
@@ -391,10 +640,30 @@ pub enum VmmAction {
     /// `VsockDeviceConfig` as input. This action can only be called before the microVM has
     /// booted. The response is sent using the `OutcomeSender`.
     InsertVsockDevice(VsockDeviceConfig, OutcomeSender),
+    /// Load a microVM from the files described by `SnapshotConfig` and resume it. This action
+    /// replaces `StartMicroVm`, so it can only be called before the microVM has booted. The
+    /// response is sent using the `OutcomeSender`.
+    LoadSnapshot(SnapshotConfig, OutcomeSender),
+    /// Receive a microVM migrated by another Firecracker process on the Unix socket described by
+    /// `MigrationConfig` and resume it. This action blocks until the source connects and
+    /// replaces `StartMicroVm`, so it can only be called before the microVM has booted. The
+    /// response is sent using the `OutcomeSender`.
+    ReceiveMigration(MigrationConfig, OutcomeSender),
+    /// Pause the microVM: kick the vCPUs out of `KVM_RUN` and hold them there, and stop
+    /// processing device events. This action can only be called while the microVM is running.
+    /// The response is sent using the `OutcomeSender`.
+    PauseVm(OutcomeSender),
     /// Update the size of an existing block device specified by an ID. The ID is the first data
     /// associated with this enum variant. This action can only be called after the microVM is
     /// started. The response is sent using the `OutcomeSender`.
     RescanBlockDevice(String, OutcomeSender),
+    /// Resume a microVM previously paused with `PauseVm`. The response is sent using the
+    /// `OutcomeSender`.
+    ResumeVm(OutcomeSender),
+    /// Migrate the running microVM to the Firecracker process listening on the Unix socket
+    /// described by `MigrationConfig`. Once the destination resumed the microVM, it stays paused
+    /// here. The response is sent using the `OutcomeSender`.
+    SendMigration(MigrationConfig, OutcomeSender),
     /// Set the microVM configuration (memory & vcpu) using `VmConfig` as input. This
     /// action can only be called before the microVM has booted. The action
     /// response is sent using the `OutcomeSender`.
@@ -405,6 +674,9 @@ pub enum VmmAction {
     /// Send CTRL+ALT+DEL to the microVM, using the i8042 keyboard function. If an AT-keyboard
     /// driver is listening on the guest end, this can be used to shut down the microVM gracefully.
     SendCtrlAltDel(OutcomeSender),
+    /// Change the target size of the balloon. Before boot, this only updates the configuration.
+    /// The response is sent using the `OutcomeSender`.
+    UpdateBalloon(BalloonUpdateConfig, OutcomeSender),
     /// Update the path of an existing block device. The data associated with this variant
     /// represents the `drive_id` and the `path_on_host`. The response is sent using
     /// the `OutcomeSender`.
@@ -418,10 +690,16 @@ pub enum VmmAction {
 /// empty, when no data needs to be sent, or an internal VMM structure.
 #[derive(Debug)]
 pub enum VmmData {
+    /// The balloon device configuration represented by `BalloonConfig`.
+    BalloonConfig(BalloonConfig),
+    /// The memory statistics reported by the guest through the balloon device.
+    BalloonStatistics(virtio::BalloonStatistics),
     /// No data is sent on the channel.
     Empty,
     /// The microVM configuration represented by `VmConfig`.
     MachineConfiguration(VmConfig),
+    /// The whole microVM configuration represented by `VmmConfig`.
+    VmmConfiguration(VmmConfig),
 }
 
 /// Data type used to communicate between the API and the VMM.
@@ -448,6 +726,67 @@ fn get_time_us() -> u64 {
     (chrono::Utc::now().timestamp_nanos() / 1000) as u64
 }
 
+// Returns the flags to open the disk image of a drive with, so that it bypasses the page cache
+// of the host for the `None` cache type.
+fn direct_io_flags(cache_type: CacheType) -> i32 {
+    match cache_type {
+        CacheType::None => libc::O_DIRECT,
+        CacheType::Writeback | CacheType::Unsafe => 0,
+    }
+}
+
+// Connects to the NBD server listening on the socket at `path`, for the export of `nbd`.
+fn open_nbd(
+    path: &Path,
+    nbd: &NbdConfig,
+    writable: bool,
+) -> std::result::Result<Box<DiskFile>, NbdError> {
+    NbdDisk::connect(path, nbd.export_name(), !writable).map(|disk| Box::new(disk) as Box<DiskFile>)
+}
+
+// Encrypts the sectors of `disk_image` with the key of `encryption`.
+fn open_crypt(
+    disk_image: Box<DiskFile>,
+    encryption: &EncryptionConfig,
+) -> std::result::Result<CryptFile, CryptError> {
+    // The key was already checked when the drive was configured.
+    let key = encryption.decode().map_err(|_| CryptError::InvalidKey)?;
+    CryptFile::new(disk_image, &key)
+}
+
+// Gives back their key to the encrypted drives of a saved or migrated microVM, since the keys
+// are not saved along with them. Fails with the id of the first drive whose key is not in `keys`.
+fn restore_encryption_keys(
+    drives: &mut [BlockDeviceConfig],
+    keys: &[DriveEncryptionKey],
+) -> std::result::Result<(), String> {
+    for drive in drives.iter_mut() {
+        if drive.encryption.is_some() {
+            let key = keys
+                .iter()
+                .find(|key| key.drive_id == drive.drive_id)
+                .ok_or_else(|| drive.drive_id.clone())?;
+            drive.encryption = Some(key.encryption.clone());
+        }
+    }
+    Ok(())
+}
+
+// Verifies the reads from `disk_image` against the hash tree of `verity`.
+fn open_verity(
+    disk_image: Box<DiskFile>,
+    verity: &VerityConfig,
+) -> std::result::Result<VerityFile, VerityError> {
+    // The hashes were already checked when the drive was configured.
+    let (root_hash, salt) = verity.decode().map_err(|_| VerityError::InvalidRootHash)?;
+    VerityFile::open(
+        disk_image,
+        &verity.hash_tree_path,
+        &root_hash,
+        salt.as_ref().map(Vec::as_slice),
+    )
+}
+
 /// Describes a KVM context that gets attached to the micro vm instance.
 /// It gives access to the functionality of the KVM wrapper as long as every required
 /// KVM capability is present on the host.
@@ -499,6 +838,7 @@ impl KvmContext {
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum EpollDispatch {
     Exit,
+    GuestPanic,
     Stdin,
     DeviceHandler(usize, DeviceEventT),
     VmmActionRequest,
@@ -528,7 +868,13 @@ struct EpollEvent<T: AsRawFd> {
 // and duping of file descriptors. This issue will be solved when we also implement device removal.
 struct EpollContext {
     epoll_raw_fd: RawFd,
+    // Epoll instance which only holds the VMM's own events (API, exit, stdin, metrics). It is
+    // polled instead of `epoll_raw_fd` while device events are paused.
+    control_epoll_raw_fd: RawFd,
+    device_events_paused: bool,
     stdin_index: u64,
+    // The VMM's own events, as (fd, dispatch index) pairs.
+    vmm_events: Vec<(RawFd, u64)>,
     // FIXME: find a different design as this does not scale. This Vec can only grow.
     dispatch_table: Vec<Option<EpollDispatch>>,
     device_handlers: Vec<MaybeHandler>,
@@ -537,6 +883,11 @@ struct EpollContext {
 impl EpollContext {
     fn new() -> Result<Self> {
         let epoll_raw_fd = epoll::create(true).map_err(Error::EpollFd)?;
+        let control_epoll_raw_fd = epoll::create(true).map_err(|e| {
+            // Safe because we own this fd and nothing else holds it.
+            unsafe { libc::close(epoll_raw_fd) };
+            Error::EpollFd(e)
+        })?;
 
         // Initial capacity needs to be large enough to hold:
         // * 1 exit event
@@ -549,19 +900,17 @@ impl EpollContext {
         dispatch_table.push(None);
         Ok(EpollContext {
             epoll_raw_fd,
+            control_epoll_raw_fd,
+            device_events_paused: false,
             stdin_index,
+            vmm_events: Vec::new(),
             dispatch_table,
             device_handlers: Vec::with_capacity(6),
         })
     }
 
     fn enable_stdin_event(&mut self) -> Result<()> {
-        if let Err(e) = epoll::ctl(
-            self.epoll_raw_fd,
-            epoll::ControlOptions::EPOLL_CTL_ADD,
-            libc::STDIN_FILENO,
-            epoll::Event::new(epoll::Events::EPOLLIN, self.stdin_index),
-        ) {
+        if let Err(e) = self.ctl_all(true, libc::STDIN_FILENO, self.stdin_index) {
             // TODO: We just log this message, and immediately return Ok, instead of returning the
             // actual error because this operation always fails with EPERM when adding a fd which
             // has been redirected to /dev/null via dup2 (this may happen inside the jailer).
@@ -581,13 +930,7 @@ impl EpollContext {
         // Ignore failure to remove from epoll. The only reason for failure is
         // that stdin has closed or changed in which case we won't get
         // any more events on the original event_fd anyway.
-        let _ = epoll::ctl(
-            self.epoll_raw_fd,
-            epoll::ControlOptions::EPOLL_CTL_DEL,
-            libc::STDIN_FILENO,
-            epoll::Event::new(epoll::Events::EPOLLIN, self.stdin_index),
-        )
-        .map_err(Error::EpollFd);
+        let _ = self.ctl_all(false, libc::STDIN_FILENO, self.stdin_index);
         self.dispatch_table[self.stdin_index as usize] = None;
 
         Ok(())
@@ -598,18 +941,87 @@ impl EpollContext {
         T: AsRawFd,
     {
         let dispatch_index = self.dispatch_table.len() as u64;
-        epoll::ctl(
-            self.epoll_raw_fd,
-            epoll::ControlOptions::EPOLL_CTL_ADD,
-            fd.as_raw_fd(),
-            epoll::Event::new(epoll::Events::EPOLLIN, dispatch_index),
-        )
-        .map_err(Error::EpollFd)?;
+        self.ctl_all(true, fd.as_raw_fd(), dispatch_index)?;
         self.dispatch_table.push(Some(token));
 
         Ok(EpollEvent { fd })
     }
 
+    // Applies an operation for a VMM event on both the main and the control epoll instances.
+    // `ControlOptions` is not `Copy`, hence the `add` flag.
+    fn ctl_all(&mut self, add: bool, fd: RawFd, dispatch_index: u64) -> Result<()> {
+        for epoll_raw_fd in &[self.epoll_raw_fd, self.control_epoll_raw_fd] {
+            let op = if add {
+                epoll::ControlOptions::EPOLL_CTL_ADD
+            } else {
+                epoll::ControlOptions::EPOLL_CTL_DEL
+            };
+            epoll::ctl(
+                *epoll_raw_fd,
+                op,
+                fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, dispatch_index),
+            )
+            .map_err(Error::EpollFd)?;
+        }
+        if add {
+            self.vmm_events.push((fd, dispatch_index));
+        } else {
+            self.vmm_events
+                .retain(|&(vmm_fd, index)| vmm_fd != fd || index != dispatch_index);
+        }
+        Ok(())
+    }
+
+    // Forgets about all the device handlers and their events, so that the devices can be
+    // activated again. The devices must be given new tokens, since the old ones stay unused.
+    fn drop_device_handlers(&mut self) -> Result<()> {
+        // The devices registered their events straight on the main epoll instance, so the
+        // simplest way to get rid of them is to start over with a new one.
+        let epoll_raw_fd = epoll::create(true).map_err(Error::EpollFd)?;
+        for &(fd, dispatch_index) in &self.vmm_events {
+            if let Err(e) = epoll::ctl(
+                epoll_raw_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, dispatch_index),
+            ) {
+                // Safe because we own this fd and nothing else holds it.
+                unsafe { libc::close(epoll_raw_fd) };
+                return Err(Error::EpollFd(e));
+            }
+        }
+        // Safe because we own this fd, and nothing uses it anymore once the handlers are gone.
+        unsafe { libc::close(self.epoll_raw_fd) };
+        self.epoll_raw_fd = epoll_raw_fd;
+
+        for dispatch in self.dispatch_table.iter_mut() {
+            if let Some(EpollDispatch::DeviceHandler(_, _)) = *dispatch {
+                *dispatch = None;
+            }
+        }
+        self.device_handlers.clear();
+        Ok(())
+    }
+
+    // Device events are left pending (epoll is level triggered) until `resume_device_events()`.
+    fn pause_device_events(&mut self) {
+        self.device_events_paused = true;
+    }
+
+    fn resume_device_events(&mut self) {
+        self.device_events_paused = false;
+    }
+
+    // Returns the epoll instance the VMM should wait on.
+    fn poll_raw_fd(&self) -> RawFd {
+        if self.device_events_paused {
+            self.control_epoll_raw_fd
+        } else {
+            self.epoll_raw_fd
+        }
+    }
+
     fn allocate_tokens(&mut self, count: usize) -> (u64, Sender<Box<EpollHandler>>) {
         let dispatch_base = self.dispatch_table.len() as u64;
         let device_idx = self.device_handlers.len();
@@ -628,9 +1040,13 @@ impl EpollContext {
     }
 
     // See the below comment for `allocate_virtio_net_tokens`, for an explanation on the returned
-    // values.
-    fn allocate_virtio_block_tokens(&mut self) -> (virtio::block::EpollConfig, usize) {
-        let (dispatch_base, sender) = self.allocate_tokens(virtio::block::BLOCK_EVENTS_COUNT);
+    // values. Each of the `num_queues` queues of the device gets its own token.
+    fn allocate_virtio_block_tokens(
+        &mut self,
+        num_queues: usize,
+    ) -> (virtio::block::EpollConfig, usize) {
+        let (dispatch_base, sender) =
+            self.allocate_tokens(virtio::block::block_events_count(num_queues));
         (
             virtio::block::EpollConfig::new(dispatch_base, self.epoll_raw_fd, sender),
             self.device_handlers.len() - 1,
@@ -651,11 +1067,25 @@ impl EpollContext {
         )
     }
 
-    #[cfg(feature = "vsock")]
-    fn allocate_virtio_vsock_tokens(&mut self) -> virtio::vhost::handle::VhostEpollConfig {
+    // See the above comment for `allocate_virtio_net_tokens`, for an explanation on the returned
+    // values.
+    fn allocate_virtio_balloon_tokens(&mut self) -> (virtio::balloon::EpollConfig, usize) {
+        let (dispatch_base, sender) = self.allocate_tokens(virtio::balloon::BALLOON_EVENTS_COUNT);
+        (
+            virtio::balloon::EpollConfig::new(dispatch_base, self.epoll_raw_fd, sender),
+            self.device_handlers.len() - 1,
+        )
+    }
+
+    // See the above comment for `allocate_virtio_net_tokens`, for an explanation on the returned
+    // values.
+    fn allocate_vhost_tokens(&mut self) -> (virtio::vhost::handle::VhostEpollConfig, usize) {
         let (dispatch_base, sender) =
             self.allocate_tokens(virtio::vhost::handle::VHOST_EVENTS_COUNT);
-        virtio::vhost::handle::VhostEpollConfig::new(dispatch_base, self.epoll_raw_fd, sender)
+        (
+            virtio::vhost::handle::VhostEpollConfig::new(dispatch_base, self.epoll_raw_fd, sender),
+            self.device_handlers.len() - 1,
+        )
     }
 
     fn get_device_handler(&mut self, device_idx: usize) -> Result<&mut EpollHandler> {
@@ -676,6 +1106,37 @@ impl EpollContext {
             }
         }
     }
+
+    // Stops polling the events of a device handler, and drops the handler. The dispatch table
+    // entries of the device are cleared, so that events which are still pending are ignored.
+    // The handler is dropped even if some of its events cannot be removed, in which case the
+    // first error is returned.
+    fn drop_device_handler(&mut self, device_idx: usize) -> Result<()> {
+        let event_fds = self.get_device_handler(device_idx)?.event_fds();
+        let mut result = Ok(());
+        for fd in event_fds {
+            if let Err(e) = epoll::ctl(
+                self.epoll_raw_fd,
+                epoll::ControlOptions::EPOLL_CTL_DEL,
+                fd,
+                epoll::Event::new(epoll::Events::empty(), 0),
+            ) {
+                if result.is_ok() {
+                    result = Err(Error::EpollFd(e));
+                }
+            }
+        }
+
+        for dispatch in self.dispatch_table.iter_mut() {
+            if let Some(EpollDispatch::DeviceHandler(idx, _)) = *dispatch {
+                if idx == device_idx {
+                    *dispatch = None;
+                }
+            }
+        }
+        self.device_handlers[device_idx].handler = None;
+        result
+    }
 }
 
 impl Drop for EpollContext {
@@ -684,6 +1145,10 @@ impl Drop for EpollContext {
         if rc != 0 {
             warn!("Cannot close epoll.");
         }
+        let rc = unsafe { libc::close(self.control_epoll_raw_fd) };
+        if rc != 0 {
+            warn!("Cannot close control epoll.");
+        }
     }
 }
 
@@ -702,15 +1167,26 @@ struct Vmm {
     // Guest VM core resources.
     guest_memory: Option<GuestMemory>,
     kernel_config: Option<KernelConfig>,
+    // The boot source as configured, before the device parameters are added to the command line.
+    boot_source_config: Option<BootSourceConfig>,
     vcpus_handles: Vec<thread::JoinHandle<()>>,
+    vcpus_pause_control: Arc<VcpuPauseControl>,
     exit_evt: Option<EpollEvent<EventFd>>,
+    panic_evt: Option<EpollEvent<EventFd>>,
     vm: Vm,
+    // The state of the VM and of its vCPUs right before the guest started, used for rebooting
+    // in place.
+    #[cfg(target_arch = "x86_64")]
+    power_on_state: Option<(VmKvmState, Vec<VcpuKvmState>)>,
 
     // Guest VM devices.
     mmio_device_manager: Option<MMIODeviceManager>,
     legacy_device_manager: LegacyDeviceManager,
     drive_handler_id_map: HashMap<String, usize>,
     net_handler_id_map: HashMap<String, usize>,
+    // The ID and the MMIO address of the device behind each epoll handler, used to stop the
+    // device when its handler fails.
+    handler_device_map: HashMap<usize, (String, u64)>,
 
     // Device configurations.
     // If there is a Root Block Device, this should be added as the first element of the list.
@@ -719,6 +1195,11 @@ struct Vmm {
     network_interface_configs: NetworkInterfaceConfigs,
     #[cfg(feature = "vsock")]
     vsock_device_configs: VsockDeviceConfigs,
+    balloon_config: Option<BalloonConfig>,
+    // The live balloon device is reached through its epoll handler, like the network devices,
+    // and shares its target size and statistics with the VMM.
+    balloon_handler_idx: Option<usize>,
+    balloon_state: Option<Arc<Mutex<virtio::BalloonState>>>,
 
     epoll_context: EpollContext,
 
@@ -727,9 +1208,17 @@ struct Vmm {
     from_api: Receiver<Box<VmmAction>>,
 
     write_metrics_event: EpollEvent<TimerFd>,
+    logger_config: Option<LoggerConfig>,
 
     // The level of seccomp filtering used. Seccomp filters are loaded before executing guest code.
     seccomp_level: u32,
+
+    // Where the GDB server listens, if the guest is to be debugged.
+    #[cfg(target_arch = "x86_64")]
+    gdb_socket_path: Option<PathBuf>,
+
+    // The thread serving the API, which is pinned and scheduled along with the VMM thread.
+    api_thread: Option<libc::pthread_t>,
 }
 
 impl Vmm {
@@ -763,29 +1252,42 @@ impl Vmm {
             shared_info: api_shared_info,
             guest_memory: None,
             kernel_config: None,
+            boot_source_config: None,
             vcpus_handles: vec![],
+            vcpus_pause_control: Arc::new(VcpuPauseControl::default()),
             exit_evt: None,
+            panic_evt: None,
             vm,
+            #[cfg(target_arch = "x86_64")]
+            power_on_state: None,
             mmio_device_manager: None,
             legacy_device_manager: LegacyDeviceManager::new().map_err(Error::CreateLegacyDevice)?,
             block_device_configs,
             drive_handler_id_map: HashMap::new(),
             net_handler_id_map: HashMap::new(),
+            handler_device_map: HashMap::new(),
             network_interface_configs: NetworkInterfaceConfigs::new(),
             #[cfg(feature = "vsock")]
             vsock_device_configs: VsockDeviceConfigs::new(),
+            balloon_config: None,
+            balloon_handler_idx: None,
+            balloon_state: None,
             epoll_context,
             api_event,
             from_api,
             write_metrics_event,
+            logger_config: None,
             seccomp_level,
+            #[cfg(target_arch = "x86_64")]
+            gdb_socket_path: None,
+            api_thread: None,
         })
     }
 
     fn update_drive_handler(
         &mut self,
         drive_id: &str,
-        disk_image: File,
+        disk_image: Option<Box<DiskFile>>,
     ) -> result::Result<(), DriveError> {
         if let Some(device_idx) = self.drive_handler_id_map.get(drive_id) {
             match self.epoll_context.get_device_handler(*device_idx) {
@@ -818,24 +1320,17 @@ impl Vmm {
     fn attach_block_devices(
         &mut self,
         device_manager: &mut MMIODeviceManager,
+        cmdline: &mut kernel_cmdline::Cmdline,
     ) -> std::result::Result<(), StartMicrovmError> {
-        // We rely on check_health function for making sure kernel_config is not None.
-        let kernel_config = self
-            .kernel_config
-            .as_mut()
-            .ok_or(StartMicrovmError::MissingKernelConfig)?;
-
         if self.block_device_configs.has_root_block_device() {
             // If no PARTUUID was specified for the root device, try with the /dev/vda.
             if !self.block_device_configs.has_partuuid_root() {
-                kernel_config
-                    .cmdline
+                cmdline
                     .insert_str(" root=/dev/vda")
                     .map_err(|e| StartMicrovmError::KernelCmdline(e.to_string()))?;
 
                 if self.block_device_configs.has_read_only_root() {
-                    kernel_config
-                        .cmdline
+                    cmdline
                         .insert_str(" ro")
                         .map_err(|e| StartMicrovmError::KernelCmdline(e.to_string()))?;
                 }
@@ -844,16 +1339,8 @@ impl Vmm {
 
         let epoll_context = &mut self.epoll_context;
         for drive_config in self.block_device_configs.config_list.iter_mut() {
-            // Add the block device from file.
-            let block_file = OpenOptions::new()
-                .read(true)
-                .write(!drive_config.is_read_only)
-                .open(&drive_config.path_on_host)
-                .map_err(StartMicrovmError::OpenBlockDevice)?;
-
             if drive_config.is_root_device && drive_config.get_partuuid().is_some() {
-                kernel_config
-                    .cmdline
+                cmdline
                     .insert_str(format!(
                         " root=PARTUUID={}",
                         //The unwrap is safe as we are firstly checking that partuuid is_some().
@@ -861,14 +1348,100 @@ impl Vmm {
                     ))
                     .map_err(|e| StartMicrovmError::KernelCmdline(e.to_string()))?;
                 if drive_config.is_read_only {
-                    kernel_config
-                        .cmdline
+                    cmdline
                         .insert_str(" ro")
                         .map_err(|e| StartMicrovmError::KernelCmdline(e.to_string()))?;
                 }
             }
 
-            let (epoll_config, handler_idx) = epoll_context.allocate_virtio_block_tokens();
+            let num_queues = drive_config.num_queues();
+            let queue_size = drive_config.queue_size();
+            if drive_config.is_vhost_user() {
+                // The back-end maps the guest memory and processes the queues itself, so the
+                // drive has no handler of its own to look up by id.
+                let guest_mem =
+                    self.guest_memory
+                        .as_ref()
+                        .ok_or(StartMicrovmError::GuestMemory(
+                            memory_model::GuestMemoryError::MemoryNotInitialized,
+                        ))?;
+                let (epoll_config, handler_idx) = epoll_context.allocate_vhost_tokens();
+                let block_box = Box::new(
+                    devices::virtio::vhost::VhostUserBlock::new(
+                        &drive_config.path_on_host,
+                        guest_mem,
+                        drive_config.is_read_only,
+                        vec![queue_size; usize::from(num_queues)],
+                        epoll_config,
+                    )
+                    .map_err(StartMicrovmError::CreateVhostUserBlock)?,
+                );
+                let addr = device_manager
+                    .register_device(
+                        self.vm.get_fd(),
+                        block_box,
+                        cmdline,
+                        Some(drive_config.drive_id.clone()),
+                    )
+                    .map_err(StartMicrovmError::RegisterBlockDevice)?;
+                self.handler_device_map
+                    .insert(handler_idx, (drive_config.drive_id.clone(), addr));
+                continue;
+            }
+
+            // Add the block device from file. The disk image of a drive with an overlay is
+            // only read from.
+            let cache_type = drive_config.cache_type.unwrap_or_default();
+            let writable = !drive_config.is_read_only && drive_config.overlay.is_none();
+            // The guest sees a drive without a disk image as a disk of 0 bytes, until one is
+            // attached after boot.
+            let disk_image = if drive_config.is_empty() {
+                None
+            } else {
+                let mut disk_image = match drive_config.nbd {
+                    Some(ref nbd) => open_nbd(&drive_config.path_on_host, nbd, writable)
+                        .map_err(StartMicrovmError::ConnectNbdServer)?,
+                    None => {
+                        let block_file = OpenOptions::new()
+                            .read(true)
+                            .write(writable)
+                            .custom_flags(direct_io_flags(cache_type))
+                            .open(&drive_config.path_on_host)
+                            .map_err(StartMicrovmError::OpenBlockDevice)?;
+                        devices::virtio::open_disk_image(
+                            block_file,
+                            &drive_config.path_on_host,
+                            drive_config.disk_image_format(),
+                        )
+                        .map_err(StartMicrovmError::InvalidDiskImage)?
+                    }
+                };
+                if let Some(ref encryption) = drive_config.encryption {
+                    disk_image = Box::new(
+                        open_crypt(disk_image, encryption)
+                            .map_err(StartMicrovmError::CreateEncryption)?,
+                    );
+                }
+                if let Some(ref overlay) = drive_config.overlay {
+                    let cow_file = match overlay.path_on_host {
+                        Some(ref path) => devices::virtio::CowFile::open(disk_image, path),
+                        None => devices::virtio::CowFile::in_memory(disk_image),
+                    };
+                    disk_image = Box::new(cow_file.map_err(StartMicrovmError::CreateOverlay)?);
+                }
+                if let Some(ref verity) = drive_config.verity {
+                    disk_image = Box::new(
+                        open_verity(disk_image, verity).map_err(StartMicrovmError::CreateVerity)?,
+                    );
+                }
+                Some(disk_image)
+            };
+            let raw = disk_image
+                .as_ref()
+                .map_or(true, |disk_image| disk_image.is_raw());
+
+            let (epoll_config, handler_idx) =
+                epoll_context.allocate_virtio_block_tokens(usize::from(num_queues));
             self.drive_handler_id_map
                 .insert(drive_config.drive_id.clone(), handler_idx);
             let rate_limiter = match drive_config.rate_limiter {
@@ -879,24 +1452,44 @@ impl Vmm {
                 ),
                 None => None,
             };
+            let io_uring = match drive_config.io_engine.unwrap_or_default() {
+                IoEngine::Sync => None,
+                IoEngine::Async if !raw => return Err(StartMicrovmError::AsyncIoEngineImageFormat),
+                IoEngine::Async => Some(
+                    devices::virtio::IoUringEngine::new(
+                        u32::from(queue_size) * u32::from(num_queues),
+                    )
+                    .map_err(StartMicrovmError::CreateIoUring)?,
+                ),
+            };
+            if cache_type == CacheType::None && !raw {
+                return Err(StartMicrovmError::DirectIoImageFormat);
+            }
 
             let block_box = Box::new(
                 devices::virtio::Block::new(
-                    block_file,
+                    disk_image,
                     drive_config.is_read_only,
                     epoll_config,
                     rate_limiter,
+                    io_uring,
+                    drive_config.discard.unwrap_or(false),
+                    drive_config.write_zeroes.unwrap_or(false),
+                    CacheMode::from(cache_type),
+                    vec![queue_size; usize::from(num_queues)],
                 )
                 .map_err(StartMicrovmError::CreateBlockDevice)?,
             );
-            device_manager
+            let addr = device_manager
                 .register_device(
                     self.vm.get_fd(),
                     block_box,
-                    &mut kernel_config.cmdline,
+                    cmdline,
                     Some(drive_config.drive_id.clone()),
                 )
                 .map_err(StartMicrovmError::RegisterBlockDevice)?;
+            self.handler_device_map
+                .insert(handler_idx, (drive_config.drive_id.clone(), addr));
         }
 
         Ok(())
@@ -905,13 +1498,8 @@ impl Vmm {
     fn attach_net_devices(
         &mut self,
         device_manager: &mut MMIODeviceManager,
+        cmdline: &mut kernel_cmdline::Cmdline,
     ) -> std::result::Result<(), StartMicrovmError> {
-        // We rely on check_health function for making sure kernel_config is not None.
-        let kernel_config = self
-            .kernel_config
-            .as_mut()
-            .ok_or(StartMicrovmError::MissingKernelConfig)?;
-
         for cfg in self.network_interface_configs.iter_mut() {
             let (epoll_config, handler_idx) = self.epoll_context.allocate_virtio_net_tokens();
             self.net_handler_id_map
@@ -933,7 +1521,9 @@ impl Vmm {
                 None => None,
             };
 
-            if let Some(tap) = cfg.take_tap() {
+            // Keep a handle to the tap, so that the device can be created again on reboot.
+            if let Some(tap) = cfg.tap.as_ref() {
+                let tap = tap.try_clone().map_err(StartMicrovmError::CloneTap)?;
                 let net_box = Box::new(
                     devices::virtio::Net::new_with_tap(
                         tap,
@@ -946,9 +1536,11 @@ impl Vmm {
                     .map_err(StartMicrovmError::CreateNetDevice)?,
                 );
 
-                device_manager
-                    .register_device(self.vm.get_fd(), net_box, &mut kernel_config.cmdline, None)
+                let addr = device_manager
+                    .register_device(self.vm.get_fd(), net_box, cmdline, None)
                     .map_err(StartMicrovmError::RegisterNetDevice)?;
+                self.handler_device_map
+                    .insert(handler_idx, (cfg.iface_id.clone(), addr));
             } else {
                 return Err(StartMicrovmError::NetDeviceNotConfigured)?;
             }
@@ -961,27 +1553,47 @@ impl Vmm {
         &mut self,
         device_manager: &mut MMIODeviceManager,
         guest_mem: &GuestMemory,
+        cmdline: &mut kernel_cmdline::Cmdline,
     ) -> std::result::Result<(), StartMicrovmError> {
-        let kernel_config = self
-            .kernel_config
-            .as_mut()
-            .ok_or(StartMicrovmError::MissingKernelConfig)?;
-
         for cfg in self.vsock_device_configs.iter() {
-            let epoll_config = self.epoll_context.allocate_virtio_vsock_tokens();
+            let (epoll_config, handler_idx) = self.epoll_context.allocate_vhost_tokens();
 
             let vsock_box = Box::new(
                 devices::virtio::Vsock::new(u64::from(cfg.guest_cid), guest_mem, epoll_config)
                     .map_err(StartMicrovmError::CreateVsockDevice)?,
             );
-            device_manager
-                .register_device(
-                    self.vm.get_fd(),
-                    vsock_box,
-                    &mut kernel_config.cmdline,
-                    None,
-                )
+            let addr = device_manager
+                .register_device(self.vm.get_fd(), vsock_box, cmdline, None)
                 .map_err(StartMicrovmError::RegisterVsockDevice)?;
+            self.handler_device_map
+                .insert(handler_idx, (cfg.id.clone(), addr));
+        }
+        Ok(())
+    }
+
+    fn attach_balloon_device(
+        &mut self,
+        device_manager: &mut MMIODeviceManager,
+        cmdline: &mut kernel_cmdline::Cmdline,
+    ) -> std::result::Result<(), StartMicrovmError> {
+        if let Some(ref cfg) = self.balloon_config {
+            let (epoll_config, handler_idx) = self.epoll_context.allocate_virtio_balloon_tokens();
+            let balloon = devices::virtio::Balloon::new(
+                cfg.num_pages(),
+                cfg.deflate_on_oom,
+                cfg.stats_polling_interval_s,
+                cfg.free_page_reporting,
+                epoll_config,
+            )
+            .map_err(StartMicrovmError::CreateBalloonDevice)?;
+            self.balloon_handler_idx = Some(handler_idx);
+            self.balloon_state = Some(balloon.state());
+
+            let addr = device_manager
+                .register_device(self.vm.get_fd(), Box::new(balloon), cmdline, None)
+                .map_err(StartMicrovmError::RegisterBalloonDevice)?;
+            self.handler_device_map
+                .insert(handler_idx, ("balloon".to_string(), addr));
         }
         Ok(())
     }
@@ -1031,8 +1643,27 @@ impl Vmm {
             ))?
             << 20;
         let arch_mem_regions = arch::arch_memory_regions(mem_size);
-        self.guest_memory =
-            Some(GuestMemory::new(&arch_mem_regions).map_err(StartMicrovmError::GuestMemory)?);
+        let backing = MemoryBacking::from(
+            self.vm_config
+                .mem_backing
+                .unwrap_or(MemoryBackingType::Anonymous),
+        );
+        let guest_memory = GuestMemory::new(&arch_mem_regions, backing).map_err(|e| match e {
+            GuestMemoryError::MemoryMappingFailed(MemoryMappingError::HugepagesUnavailable(e)) => {
+                StartMicrovmError::HugepagesUnavailable(e)
+            }
+            GuestMemoryError::MemoryMappingFailed(MemoryMappingError::InvalidHugepagesSize(_)) => {
+                StartMicrovmError::InvalidHugepagesMemorySize
+            }
+            e => StartMicrovmError::GuestMemory(e),
+        })?;
+        // Other processes can map the guest memory through /proc/<pid>/fd/<fd>.
+        for index in 0..guest_memory.num_regions() {
+            if let Some(fd) = guest_memory.region_fd(index) {
+                info!("Guest memory region {} is backed by memfd {}.", index, fd);
+            }
+        }
+        self.guest_memory = Some(guest_memory);
         self.vm
             .memory_init(
                 self.guest_memory
@@ -1069,10 +1700,25 @@ impl Vmm {
             (arch::IRQ_BASE, arch::IRQ_MAX),
         );
 
-        self.attach_block_devices(&mut device_manager)?;
-        self.attach_net_devices(&mut device_manager)?;
+        // A microVM loaded from a snapshot does not boot a kernel, so the devices append their
+        // parameters to a scratch command line instead.
+        let mut cmdline = match self.kernel_config {
+            Some(ref kernel_config) => kernel_config.cmdline.clone(),
+            None => kernel_cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE),
+        };
+        self.attach_block_devices(&mut device_manager, &mut cmdline)?;
+        self.attach_net_devices(&mut device_manager, &mut cmdline)?;
         #[cfg(feature = "vsock")]
-        self.attach_vsock_devices(&mut device_manager, &guest_mem)?;
+        self.attach_vsock_devices(&mut device_manager, &guest_mem, &mut cmdline)?;
+        self.attach_balloon_device(&mut device_manager, &mut cmdline)?;
+        // The pvpanic device sits on the I/O Bus on x86_64.
+        #[cfg(not(target_arch = "x86_64"))]
+        device_manager
+            .register_pvpanic(self.legacy_device_manager.pvpanic.clone())
+            .map_err(StartMicrovmError::RegisterPvPanicDevice)?;
+        if let Some(ref mut kernel_config) = self.kernel_config {
+            kernel_config.cmdline = cmdline;
+        }
 
         self.mmio_device_manager = Some(device_manager);
         Ok(())
@@ -1164,6 +1810,77 @@ impl Vmm {
 
         let vcpus_thread_barrier = Arc::new(Barrier::new((vcpu_count + 1) as usize));
 
+        // The vCPU threads need to be kicked out of KVM_RUN when pausing the microVM.
+        Vcpu::register_kick_signal_handler().map_err(StartMicrovmError::Vcpu)?;
+
+        // The GDB server thread is spawned before the seccomp filters are loaded below, so it
+        // runs unfiltered, like the API thread.
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(ref path) = self.gdb_socket_path {
+                let guest_mem = self
+                    .vm
+                    .get_memory()
+                    .ok_or(StartMicrovmError::GuestMemory(
+                        memory_model::GuestMemoryError::MemoryNotInitialized,
+                    ))?
+                    .clone();
+                let debugger = Arc::new(gdb::Debugger::new(vcpu_count));
+                gdb::start_server(path, debugger.clone(), guest_mem)
+                    .map_err(StartMicrovmError::GdbServer)?;
+                for vcpu in vcpus.iter_mut() {
+                    vcpu.set_debugger(debugger.clone());
+                }
+            }
+        }
+
+        // The vCPU threads only go on to run the guest once the rest of the microVM is set up.
+        // If that fails after some of them are spawned, their start senders are dropped, so
+        // that they return right away and can be joined.
+        let mut start_senders = Vec::with_capacity(vcpu_count as usize);
+        let result = self
+            .spawn_vcpus(vcpus, &vcpus_thread_barrier, &mut start_senders)
+            // The vCPU threads are spawned first so that they don't inherit the affinity of the
+            // VMM thread.
+            .and_then(|()| self.schedule_vmm_threads())
+            // Load seccomp filters for the VMM thread.
+            // Execution panics if filters cannot be loaded, use --seccomp-level=0 if skipping
+            // filters altogether is the desired behaviour.
+            .and_then(|()| {
+                default_syscalls::set_seccomp_level(self.seccomp_level)
+                    .map_err(StartMicrovmError::SeccompFilters)
+            });
+        if let Err(e) = result {
+            drop(start_senders);
+            for handle in self.vcpus_handles.drain(..) {
+                if handle.join().is_err() {
+                    error!("A vCPU thread panicked before starting.");
+                }
+            }
+            return Err(e);
+        }
+
+        for start_sender in start_senders {
+            // A vCPU thread only drops its receiver once it got the start message.
+            start_sender
+                .send(())
+                .expect("Failed to start a vCPU thread");
+        }
+        vcpus_thread_barrier.wait();
+
+        Ok(())
+    }
+
+    // Spawns a thread for each of `vcpus`, which waits for a message on its receiver of the
+    // senders pushed to `start_senders` before running the vCPU, and sets the affinity and
+    // scheduling policy of the thread.
+    fn spawn_vcpus(
+        &mut self,
+        mut vcpus: Vec<Vcpu>,
+        vcpus_thread_barrier: &Arc<Barrier>,
+        start_senders: &mut Vec<Sender<()>>,
+    ) -> std::result::Result<(), StartMicrovmError> {
+        let vcpu_count = vcpus.len() as u8;
         // We're going in reverse so we can `.pop()` on the vec and still maintain order.
         for cpu_id in (0..vcpu_count).rev() {
             let vcpu_thread_barrier = vcpus_thread_barrier.clone();
@@ -1176,29 +1893,94 @@ impl Vmm {
                 .get_reset_evt_clone()
                 .map_err(|_| StartMicrovmError::EventFd)?;
 
-            // `unwrap` is safe since we are asserting that the `vcpu_count` is equal to the number
-            // of items of `vcpus` vector.
+            // `unwrap` is safe since we are going through as many vCPU ids as there are items
+            // in the `vcpus` vector.
             let mut vcpu = vcpus.pop().unwrap();
 
             let seccomp_level = self.seccomp_level;
-            self.vcpus_handles.push(
-                thread::Builder::new()
-                    .name(format!("fc_vcpu{}", cpu_id))
-                    .spawn(move || {
-                        vcpu.run(vcpu_thread_barrier, seccomp_level, vcpu_exit_evt);
-                    })
-                    .map_err(StartMicrovmError::VcpuSpawn)?,
-            );
+            let vcpu_pause_control = self.vcpus_pause_control.clone();
+            let (start_sender, start_receiver) = channel();
+            let handle = thread::Builder::new()
+                .name(format!("fc_vcpu{}", cpu_id))
+                .spawn(move || {
+                    // The sender is dropped without sending anything if the microVM fails to
+                    // start.
+                    if start_receiver.recv().is_ok() {
+                        vcpu.run(
+                            vcpu_thread_barrier,
+                            seccomp_level,
+                            vcpu_exit_evt,
+                            vcpu_pause_control,
+                        );
+                    }
+                })
+                .map_err(StartMicrovmError::VcpuSpawn)?;
+            start_senders.push(start_sender);
+            // The vCPU thread waits for the start message before entering KVM_RUN, so it only
+            // ever runs guest code with its own affinity and scheduling policy.
+            let thread = handle.as_pthread_t();
+            self.vcpus_handles.push(handle);
+            self.schedule_vcpu_thread(cpu_id, thread)?;
         }
+        Ok(())
+    }
 
-        // Load seccomp filters for the VMM thread.
-        // Execution panics if filters cannot be loaded, use --seccomp-level=0 if skipping filters
-        // altogether is the desired behaviour.
-        default_syscalls::set_seccomp_level(self.seccomp_level)
-            .map_err(StartMicrovmError::SeccompFilters)?;
-
-        vcpus_thread_barrier.wait();
+    /// Pins the thread of vCPU `cpu_id` to its host CPUs and sets its scheduling policy, as
+    /// configured.
+    fn schedule_vcpu_thread(
+        &self,
+        cpu_id: u8,
+        thread: libc::pthread_t,
+    ) -> std::result::Result<(), StartMicrovmError> {
+        if let Some(cpus) = self
+            .vm_config
+            .vcpu_affinity
+            .as_ref()
+            .and_then(|affinity| affinity.get(cpu_id as usize))
+        {
+            // Safe because the vCPU thread handle has not been joined yet.
+            unsafe { sys_util::set_cpu_affinity(thread, cpus) }
+                .map_err(|e| StartMicrovmError::VcpuAffinity(cpu_id, e))?;
+        }
+        if let Some(ref scheduling) = self.vm_config.vcpu_scheduling {
+            // Safe because the vCPU thread handle has not been joined yet.
+            unsafe {
+                sys_util::set_scheduling_policy(
+                    thread,
+                    scheduling.sched_policy(),
+                    i32::from(scheduling.priority),
+                )
+            }
+            .map_err(|e| StartMicrovmError::VcpuScheduling(cpu_id, e))?;
+        }
+        Ok(())
+    }
 
+    /// Pins the VMM thread, which is the calling thread, and the API thread to their host CPUs
+    /// and sets their scheduling policy, as configured.
+    fn schedule_vmm_threads(&self) -> std::result::Result<(), StartMicrovmError> {
+        // Safe because pthread_self() cannot fail.
+        let mut threads = vec![unsafe { libc::pthread_self() }];
+        threads.extend(self.api_thread);
+
+        for &thread in threads.iter() {
+            if let Some(ref cpus) = self.vm_config.vmm_affinity {
+                // Safe because neither the VMM nor the API thread are ever joined or detached.
+                unsafe { sys_util::set_cpu_affinity(thread, cpus) }
+                    .map_err(StartMicrovmError::VmmAffinity)?;
+            }
+            if let Some(ref scheduling) = self.vm_config.vmm_scheduling {
+                // Safe because neither the VMM nor the API thread are ever joined or detached.
+                unsafe {
+                    sys_util::set_scheduling_policy(
+                        thread,
+                        scheduling.sched_policy(),
+                        i32::from(scheduling.priority),
+                    )
+                }
+                .map_err(StartMicrovmError::VmmScheduling)?;
+            }
+        }
         Ok(())
     }
 
@@ -1277,6 +2059,19 @@ impl Vmm {
             .map_err(|_| StartMicrovmError::RegisterEvent)?;
         self.exit_evt = Some(exit_epoll_evt);
 
+        let event_fd = self
+            .legacy_device_manager
+            .pvpanic
+            .lock()
+            .expect("Failed to register events on the event fd due to poisoned lock")
+            .get_panic_evt_clone()
+            .map_err(|_| StartMicrovmError::EventFd)?;
+        let panic_epoll_evt = self
+            .epoll_context
+            .add_event(event_fd, EpollDispatch::GuestPanic)
+            .map_err(|_| StartMicrovmError::RegisterEvent)?;
+        self.panic_evt = Some(panic_epoll_evt);
+
         self.epoll_context
             .enable_stdin_event()
             .map_err(|_| StartMicrovmError::RegisterEvent)?;
@@ -1316,6 +2111,9 @@ impl Vmm {
 
         let vcpus = self.create_vcpus(entry_addr, request_ts)?;
 
+        #[cfg(target_arch = "x86_64")]
+        self.save_power_on_state(&vcpus)?;
+
         self.start_vcpus(vcpus)?;
         // Use expect() to crash if the other thread poisoned this lock.
         self.shared_info
@@ -1323,24 +2121,617 @@ impl Vmm {
             .expect("Failed to start microVM because shared info couldn't be written due to poisoned lock")
             .state = InstanceState::Running;
 
-        // Arm the log write timer.
-        // TODO: the timer does not stop on InstanceStop.
-        let timer_state = TimerState::Periodic {
-            current: Duration::from_secs(WRITE_METRICS_PERIOD_SECONDS),
-            interval: Duration::from_secs(WRITE_METRICS_PERIOD_SECONDS),
-        };
-        self.write_metrics_event
-            .fd
-            .set_state(timer_state, SetTimeFlags::Default);
+        self.start_metrics_timer();
 
-        // Log the metrics straight away to check the process startup time.
+        Ok(VmmData::Empty)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn save_power_on_state(
+        &mut self,
+        vcpus: &[Vcpu],
+    ) -> std::result::Result<(), StartMicrovmError> {
+        let vm_state = self
+            .vm
+            .save_state()
+            .map_err(StartMicrovmError::ConfigureVm)?;
+        let vcpu_states = vcpus
+            .iter()
+            .map(Vcpu::save_state)
+            .collect::<vstate::Result<Vec<VcpuKvmState>>>()
+            .map_err(StartMicrovmError::VcpuConfigure)?;
+        self.power_on_state = Some((vm_state, vcpu_states));
+        Ok(())
+    }
+
+    fn start_metrics_timer(&mut self) {
+        // Arm the log write timer.
+        // TODO: the timer does not stop on InstanceStop.
+        let timer_state = TimerState::Periodic {
+            current: Duration::from_secs(WRITE_METRICS_PERIOD_SECONDS),
+            interval: Duration::from_secs(WRITE_METRICS_PERIOD_SECONDS),
+        };
+        self.write_metrics_event
+            .fd
+            .set_state(timer_state, SetTimeFlags::Default);
+
+        // Log the metrics straight away to check the process startup time.
         if LOGGER.log_metrics().is_err() {
             METRICS.logger.missed_metrics_count.inc();
         }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn restore_vcpus(
+        &mut self,
+        vcpu_states: &[VcpuKvmState],
+        request_ts: TimestampUs,
+    ) -> std::result::Result<Vec<Vcpu>, VmmActionError> {
+        let device_manager = self
+            .mmio_device_manager
+            .as_ref()
+            .ok_or(StartMicrovmError::DeviceManager)?;
+
+        let mut vcpus = Vec::with_capacity(vcpu_states.len());
+        for (cpu_id, vcpu_state) in vcpu_states.iter().enumerate() {
+            let io_bus = self.legacy_device_manager.io_bus.clone();
+            let mmio_bus = device_manager.bus.clone();
+            let mut vcpu = Vcpu::new(cpu_id as u8, &self.vm, io_bus, mmio_bus, request_ts.clone())
+                .map_err(StartMicrovmError::Vcpu)?;
+            vcpu.restore_state(vcpu_state)
+                .map_err(SnapshotError::RestoreVcpuState)?;
+            vcpus.push(vcpu);
+        }
+        Ok(vcpus)
+    }
+
+    // Copies the configuration of the attached devices, so that another process can open them.
+    fn device_configs(&mut self) -> (Vec<BlockDeviceConfig>, Vec<NetworkInterfaceConfig>) {
+        // The configurations are copied field by field because they hold live objects (e.g. the
+        // tap), which cannot be cloned.
+        let drives = self
+            .block_device_configs
+            .config_list
+            .iter()
+            .map(|cfg| BlockDeviceConfig {
+                drive_id: cfg.drive_id.clone(),
+                path_on_host: cfg.path_on_host.clone(),
+                is_root_device: cfg.is_root_device,
+                partuuid: cfg.partuuid.clone(),
+                is_read_only: cfg.is_read_only,
+                rate_limiter: cfg.rate_limiter,
+                io_engine: cfg.io_engine,
+                discard: cfg.discard,
+                write_zeroes: cfg.write_zeroes,
+                image_format: cfg.image_format,
+                overlay: cfg.overlay.clone(),
+                cache_type: cfg.cache_type,
+                num_queues: cfg.num_queues,
+                queue_size: cfg.queue_size,
+                verity: cfg.verity.clone(),
+                // The keys are not written out, and have to be given again to restore the drives.
+                encryption: cfg
+                    .encryption
+                    .as_ref()
+                    .map(|_| EncryptionConfig { key: String::new() }),
+                nbd: cfg.nbd.clone(),
+                vhost_user: cfg.vhost_user,
+            })
+            .collect();
+        let network_interfaces = self
+            .network_interface_configs
+            .iter_mut()
+            .map(|cfg| NetworkInterfaceConfig {
+                iface_id: cfg.iface_id.clone(),
+                host_dev_name: cfg.host_dev_name.clone(),
+                guest_mac: cfg.guest_mac,
+                rx_rate_limiter: cfg.rx_rate_limiter,
+                tx_rate_limiter: cfg.tx_rate_limiter,
+                allow_mmds_requests: cfg.allow_mmds_requests,
+                tap: None,
+            })
+            .collect();
+        (drives, network_interfaces)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn save_microvm_state(&mut self) -> std::result::Result<MicrovmState, VmmActionError> {
+        // The vCPUs saved their state when they parked.
+        let mut saved_vcpu_states = self.vcpus_pause_control.vcpu_states();
+        let mut vcpu_states = Vec::with_capacity(self.vcpus_handles.len());
+        for cpu_id in 0..self.vcpus_handles.len() as u8 {
+            vcpu_states.push(
+                saved_vcpu_states
+                    .remove(&cpu_id)
+                    .ok_or(SnapshotError::MissingVcpuState(cpu_id))?,
+            );
+        }
+        let vm_state = self.vm.save_state().map_err(SnapshotError::SaveVmState)?;
+        let device_states = self
+            .mmio_device_manager
+            .as_ref()
+            .ok_or(StartMicrovmError::DeviceManager)?
+            .save_state();
+
+        Ok(MicrovmState {
+            vm_state,
+            vcpu_states,
+            device_states,
+        })
+    }
+
+    // Takes over the configuration of a saved or migrated microVM, reopens its devices and
+    // creates an empty guest memory.
+    #[cfg(target_arch = "x86_64")]
+    fn restore_microvm_config(
+        &mut self,
+        vm_config: VmConfig,
+        drives: Vec<BlockDeviceConfig>,
+        network_interfaces: Vec<NetworkInterfaceConfig>,
+        balloon: Option<BalloonConfig>,
+    ) -> std::result::Result<(), VmmActionError> {
+        self.vm_config = vm_config;
+        for drive in drives {
+            self.insert_block_device(drive)?;
+        }
+        for netif in network_interfaces {
+            self.insert_net_device(netif)?;
+        }
+        self.balloon_config = balloon;
+        self.set_instance_state(InstanceState::Starting);
+
+        self.init_guest_memory()?;
+        Ok(())
+    }
+
+    // Brings back the state of a microVM whose guest memory was already restored, then resumes
+    // it.
+    #[cfg(target_arch = "x86_64")]
+    fn restore_microvm_state(
+        &mut self,
+        state: &MicrovmState,
+        request_ts: TimestampUs,
+    ) -> std::result::Result<(), VmmActionError> {
+        if self.vm_config.vcpu_count != Some(state.vcpu_states.len() as u8) {
+            Err(SnapshotError::InvalidVcpuCount)?;
+        }
+
+        self.setup_interrupt_controller()?;
+
+        self.attach_virtio_devices()?;
+        self.mmio_device_manager
+            .as_ref()
+            .ok_or(StartMicrovmError::DeviceManager)?
+            .restore_state(&state.device_states)
+            .map_err(SnapshotError::RestoreDevices)?;
+        self.attach_legacy_devices()?;
+
+        self.register_events()?;
+
+        self.vm
+            .restore_state(&state.vm_state)
+            .map_err(SnapshotError::RestoreVmState)?;
+        let vcpus = self.restore_vcpus(&state.vcpu_states, request_ts)?;
+
+        self.start_vcpus(vcpus)?;
+        self.set_instance_state(InstanceState::Running);
+
+        self.start_metrics_timer();
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn create_snapshot(
+        &mut self,
+        snapshot_config: SnapshotConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        if self.instance_state() != InstanceState::Paused {
+            Err(SnapshotError::MicroVMNotPaused)?;
+        }
+        #[cfg(feature = "vsock")]
+        {
+            if self.vsock_device_configs.iter().next().is_some() {
+                Err(SnapshotError::VsockNotSupported)?;
+            }
+        }
+        // The back-ends of vhost-user drives hold device state the VMM cannot save.
+        if self
+            .block_device_configs
+            .config_list
+            .iter()
+            .any(BlockDeviceConfig::is_vhost_user)
+        {
+            Err(SnapshotError::VhostUserNotSupported)?;
+        }
+        // The contents of an in-memory overlay would be lost.
+        if self
+            .block_device_configs
+            .config_list
+            .iter()
+            .any(BlockDeviceConfig::has_in_memory_overlay)
+        {
+            Err(SnapshotError::InMemoryOverlayNotSupported)?;
+        }
+
+        let (drives, network_interfaces) = self.device_configs();
+        let state = self.save_microvm_state()?;
+        let guest_memory = self
+            .guest_memory
+            .as_ref()
+            .ok_or(SnapshotError::GuestMemory(
+                memory_model::GuestMemoryError::MemoryNotInitialized,
+            ))?;
+        snapshot::save_memory(guest_memory, &snapshot_config.mem_file_path)?;
+
+        Snapshot {
+            version: snapshot::SNAPSHOT_VERSION,
+            vm_config: self.vm_config.clone(),
+            drives,
+            network_interfaces,
+            balloon: self.balloon_config.clone(),
+            memory_regions: snapshot::memory_layout(guest_memory),
+            state,
+        }
+        .save(&snapshot_config.snapshot_path)?;
+        info!("Created a snapshot of the microVM.");
+
+        Ok(VmmData::Empty)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn load_snapshot(
+        &mut self,
+        snapshot_config: SnapshotConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        info!("VMM received snapshot load command");
+        if self.is_instance_initialized() {
+            Err(SnapshotError::MicroVMAlreadyRunning)?;
+        }
+        let request_ts = TimestampUs {
+            time_us: get_time_us(),
+            cputime_us: now_cputime_us(),
+        };
+
+        let mut snapshot = Snapshot::load(&snapshot_config.snapshot_path)?;
+        restore_encryption_keys(
+            &mut snapshot.drives,
+            snapshot_config
+                .encryption_keys
+                .as_ref()
+                .map_or(&[], Vec::as_slice),
+        )
+        .map_err(SnapshotError::MissingEncryptionKey)?;
+        // The snapshot replaces the machine configuration and the devices.
+        self.restore_microvm_config(
+            snapshot.vm_config,
+            snapshot.drives,
+            snapshot.network_interfaces,
+            snapshot.balloon,
+        )?;
+        snapshot::load_memory(
+            self.guest_memory
+                .as_ref()
+                .ok_or(StartMicrovmError::GuestMemory(
+                    memory_model::GuestMemoryError::MemoryNotInitialized,
+                ))?,
+            &snapshot.memory_regions,
+            &snapshot_config.mem_file_path,
+        )?;
+        self.restore_microvm_state(&snapshot.state, request_ts)?;
+        info!("The microVM was loaded from a snapshot.");
+
+        Ok(VmmData::Empty)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn send_migration(
+        &mut self,
+        migration_config: MigrationConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        info!("VMM received migration send command");
+        if self.instance_state() != InstanceState::Running {
+            Err(MigrationError::MicroVMNotRunning)?;
+        }
+        #[cfg(feature = "vsock")]
+        {
+            if self.vsock_device_configs.iter().next().is_some() {
+                Err(MigrationError::VsockNotSupported)?;
+            }
+        }
+        if self
+            .block_device_configs
+            .config_list
+            .iter()
+            .any(BlockDeviceConfig::is_vhost_user)
+        {
+            Err(MigrationError::VhostUserNotSupported)?;
+        }
+        if self
+            .block_device_configs
+            .config_list
+            .iter()
+            .any(BlockDeviceConfig::has_in_memory_overlay)
+        {
+            Err(MigrationError::InMemoryOverlayNotSupported)?;
+        }
+
+        let mut stream =
+            UnixStream::connect(&migration_config.socket_path).map_err(MigrationError::Socket)?;
+        self.migrate_to(&mut stream)
+    }
+
+    // Sends the running microVM over `stream` and leaves it paused once the destination resumed
+    // it.
+    #[cfg(target_arch = "x86_64")]
+    fn migrate_to(
+        &mut self,
+        stream: &mut UnixStream,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        self.vm
+            .set_dirty_page_tracking(true)
+            .map_err(MigrationError::DirtyPageTracking)?;
+        let result = self.send_microvm(stream);
+        // The dirty page metric relies on the tracking too.
+        if let Err(e) = self
+            .vm
+            .set_dirty_page_tracking(LOGGER.flags() & LogOption::LogDirtyPages as usize > 0)
+        {
+            warn!("Cannot reset dirty page tracking after migration: {:?}", e);
+        }
+        result
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn send_microvm(
+        &mut self,
+        stream: &mut UnixStream,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        let guest_memory = self
+            .guest_memory
+            .clone()
+            .ok_or(MigrationError::GuestMemory(
+                memory_model::GuestMemoryError::MemoryNotInitialized,
+            ))?;
+        let (drives, network_interfaces) = self.device_configs();
+        migration::send_header(
+            stream,
+            &MigrationHeader {
+                vm_config: self.vm_config.clone(),
+                drives,
+                network_interfaces,
+                balloon: self.balloon_config.clone(),
+                memory_regions: snapshot::memory_layout(&guest_memory),
+            },
+        )?;
+
+        // Copy the memory while the guest keeps running, then copy what it dirtied meanwhile
+        // until few enough pages are left. The devices are emulated on this thread, so they do
+        // not write to the guest memory until the migration is over, once the requests the
+        // kernel still has in flight are done.
+        self.drain_block_devices()?;
+        migration::send_memory(stream, &guest_memory)?;
+        for _ in 0..migration::MAX_PRECOPY_ROUNDS {
+            let dirty_bitmaps = self
+                .vm
+                .get_dirty_bitmaps()
+                .map_err(MigrationError::DirtyPageTracking)?;
+            if migration::send_dirty_memory(stream, &guest_memory, &dirty_bitmaps)?
+                <= migration::MAX_DOWNTIME_PAGES
+            {
+                break;
+            }
+        }
+
+        self.pause_vm()?;
+        if let Err(e) = self.send_paused_microvm(stream, &guest_memory) {
+            // The destination did not get the whole state, so the microVM lives on here.
+            self.resume_after_failed_migration();
+            return Err(e);
+        }
+        match migration::receive_status(stream) {
+            Ok(()) => {
+                info!("The microVM was migrated.");
+                Ok(VmmData::Empty)
+            }
+            Err(MigrationError::DestinationFailed) => {
+                self.resume_after_failed_migration();
+                Err(MigrationError::DestinationFailed)?
+            }
+            // Without an answer it is unknown whether the destination resumed the microVM, so
+            // it stays paused here rather than risking two copies of it running.
+            Err(e) => Err(e)?,
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn send_paused_microvm(
+        &mut self,
+        stream: &mut UnixStream,
+        guest_memory: &GuestMemory,
+    ) -> std::result::Result<(), VmmActionError> {
+        let dirty_bitmaps = self
+            .vm
+            .get_dirty_bitmaps()
+            .map_err(MigrationError::DirtyPageTracking)?;
+        migration::send_dirty_memory(stream, guest_memory, &dirty_bitmaps)?;
+        let state = self.save_microvm_state()?;
+        migration::send_state(stream, &state)?;
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn resume_after_failed_migration(&mut self) {
+        if let Err(e) = self.resume_vm() {
+            error!("Cannot resume the microVM after a failed migration: {}", e);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn receive_migration(
+        &mut self,
+        migration_config: MigrationConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        info!("VMM received migration receive command");
+        if self.is_instance_initialized() {
+            Err(MigrationError::MicroVMAlreadyRunning)?;
+        }
+
+        let listener =
+            UnixListener::bind(&migration_config.socket_path).map_err(MigrationError::Socket)?;
+        let accepted = listener.accept();
+        // The socket file is only needed until the source connects.
+        if let Err(e) = std::fs::remove_file(&migration_config.socket_path) {
+            warn!("Cannot remove the migration socket: {}", e);
+        }
+        let (mut stream, _) = accepted.map_err(MigrationError::Socket)?;
+        self.migrate_from(
+            &mut stream,
+            migration_config
+                .encryption_keys
+                .as_ref()
+                .map_or(&[], Vec::as_slice),
+        )
+    }
+
+    // Receives a microVM over `stream`, resumes it and tells the source how that went.
+    #[cfg(target_arch = "x86_64")]
+    fn migrate_from(
+        &mut self,
+        stream: &mut UnixStream,
+        encryption_keys: &[DriveEncryptionKey],
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        let request_ts = TimestampUs {
+            time_us: get_time_us(),
+            cputime_us: now_cputime_us(),
+        };
+        let result = self.receive_microvm(stream, encryption_keys, request_ts);
+        if let Err(e) = migration::send_status(stream, result.is_ok()) {
+            warn!("Cannot report the migration outcome to the source: {}", e);
+        }
+        result
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn receive_microvm(
+        &mut self,
+        stream: &mut UnixStream,
+        encryption_keys: &[DriveEncryptionKey],
+        request_ts: TimestampUs,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        let mut header = migration::receive_header(stream)?;
+        restore_encryption_keys(&mut header.drives, encryption_keys)
+            .map_err(MigrationError::MissingEncryptionKey)?;
+        self.restore_microvm_config(
+            header.vm_config,
+            header.drives,
+            header.network_interfaces,
+            header.balloon,
+        )?;
+        let guest_memory = self
+            .guest_memory
+            .clone()
+            .ok_or(MigrationError::GuestMemory(
+                memory_model::GuestMemoryError::MemoryNotInitialized,
+            ))?;
+        if snapshot::memory_layout(&guest_memory) != header.memory_regions {
+            Err(MigrationError::InvalidMemoryLayout)?;
+        }
+
+        let state: MicrovmState = migration::receive_memory(stream, &guest_memory)?;
+        self.restore_microvm_state(&state, request_ts)?;
+        info!("The microVM was received from a migration.");
+
+        Ok(VmmData::Empty)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn create_core_dump(
+        &mut self,
+        coredump_config: CoreDumpConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        match self.instance_state() {
+            InstanceState::Running => {
+                self.pause_vm()?;
+            }
+            InstanceState::Paused => (),
+            _ => Err(CoreDumpError::MicroVMNotRunning)?,
+        }
+
+        // The microVM is resumed when asked to, even if the dump failed.
+        let result = self.write_core_dump(&coredump_config);
+        if coredump_config.resume {
+            self.resume_vm()?;
+        }
+        result?;
+        info!(
+            "Wrote a core dump of the guest to {}.",
+            coredump_config.dump_path.display()
+        );
 
         Ok(VmmData::Empty)
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn write_core_dump(
+        &self,
+        coredump_config: &CoreDumpConfig,
+    ) -> std::result::Result<(), CoreDumpError> {
+        // The vCPUs saved their state when they parked.
+        let vcpu_states = self.vcpus_pause_control.vcpu_states();
+        let mut vcpu_registers = Vec::with_capacity(self.vcpus_handles.len());
+        for cpu_id in 0..self.vcpus_handles.len() as u8 {
+            let state = vcpu_states
+                .get(&cpu_id)
+                .ok_or(CoreDumpError::MissingVcpuState(cpu_id))?;
+            vcpu_registers.push((*state.regs(), *state.sregs()));
+        }
+        let guest_memory = self
+            .guest_memory
+            .as_ref()
+            .ok_or(CoreDumpError::GuestMemory(
+                memory_model::GuestMemoryError::MemoryNotInitialized,
+            ))?;
+        coredump::write_core_dump(&coredump_config.dump_path, guest_memory, &vcpu_registers)
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn create_core_dump(
+        &mut self,
+        _: CoreDumpConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        Err(CoreDumpError::UnsupportedArch)?
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn create_snapshot(
+        &mut self,
+        _: SnapshotConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        Err(SnapshotError::UnsupportedArch)?
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn load_snapshot(&mut self, _: SnapshotConfig) -> std::result::Result<VmmData, VmmActionError> {
+        Err(SnapshotError::UnsupportedArch)?
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn send_migration(
+        &mut self,
+        _: MigrationConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        Err(MigrationError::UnsupportedArch)?
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn receive_migration(
+        &mut self,
+        _: MigrationConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        Err(MigrationError::UnsupportedArch)?
+    }
+
     fn send_ctrl_alt_del(&mut self) -> std::result::Result<VmmData, VmmActionError> {
         self.legacy_device_manager
             .i8042
@@ -1351,6 +2742,289 @@ impl Vmm {
         Ok(VmmData::Empty)
     }
 
+    fn instance_state(&self) -> InstanceState {
+        // Use expect() to crash if the other thread poisoned this lock.
+        self.shared_info
+            .read()
+            .expect("Failed to read instance state due to poisoned lock")
+            .state
+            .clone()
+    }
+
+    fn set_instance_state(&mut self, instance_state: InstanceState) {
+        // Use expect() to crash if the other thread poisoned this lock.
+        self.shared_info
+            .write()
+            .expect("Failed to set instance state due to poisoned lock")
+            .state = instance_state;
+    }
+
+    // Kicks the vCPUs out of KVM_RUN until all of them are parked.
+    fn pause_vcpus(&mut self) -> std::result::Result<(), VmStateError> {
+        self.vcpus_pause_control.request_pause();
+        for _ in 0..VCPU_PAUSE_KICK_ATTEMPTS {
+            // A kick is lost if it lands right before the vCPU enters KVM_RUN, so we keep
+            // kicking until every vCPU thread has parked itself.
+            for handle in self.vcpus_handles.iter() {
+                handle
+                    .kill(VCPU_RTSIG_OFFSET)
+                    .map_err(VmStateError::SignalVcpu)?;
+            }
+            if self.vcpus_pause_control.wait_parked(
+                self.vcpus_handles.len(),
+                Duration::from_millis(VCPU_PAUSE_KICK_INTERVAL_MS),
+            ) {
+                return Ok(());
+            }
+        }
+        // Let the vCPUs that did park go back to running guest code.
+        self.vcpus_pause_control.resume();
+        Err(VmStateError::VcpuPauseTimeout)
+    }
+
+    // Completes the requests the drives using io_uring have in flight, as the kernel would
+    // otherwise keep writing their data to the guest memory after it is saved.
+    fn drain_block_devices(&mut self) -> std::result::Result<(), VmStateError> {
+        for drive_config in self
+            .block_device_configs
+            .config_list
+            .iter()
+            .filter(|cfg| cfg.io_engine.unwrap_or_default() == IoEngine::Async)
+        {
+            let device_idx = match self.drive_handler_id_map.get(&drive_config.drive_id) {
+                Some(device_idx) => *device_idx,
+                None => continue,
+            };
+            // A device the guest did not activate yet has no handler, nor requests in flight.
+            if let Ok(handler) = self.epoll_context.get_device_handler(device_idx) {
+                handler
+                    .handle_event(
+                        virtio::block::DRAIN_EVENT,
+                        device_idx as u32,
+                        EpollHandlerPayload::Empty,
+                    )
+                    .map_err(|_| VmStateError::DrainBlockDevice(drive_config.drive_id.clone()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn pause_vm(&mut self) -> std::result::Result<VmmData, VmmActionError> {
+        if self.instance_state() != InstanceState::Running {
+            Err(VmStateError::MicroVMNotRunning)?;
+        }
+
+        self.pause_vcpus()?;
+        if let Err(e) = self.drain_block_devices() {
+            self.vcpus_pause_control.resume();
+            Err(e)?;
+        }
+        self.epoll_context.pause_device_events();
+        self.set_instance_state(InstanceState::Paused);
+        info!("The microVM was paused.");
+
+        Ok(VmmData::Empty)
+    }
+
+    fn resume_vm(&mut self) -> std::result::Result<VmmData, VmmActionError> {
+        if self.instance_state() != InstanceState::Paused {
+            Err(VmStateError::MicroVMNotPaused)?;
+        }
+
+        self.epoll_context.resume_device_events();
+        self.vcpus_pause_control.resume();
+        self.set_instance_state(InstanceState::Running);
+        info!("The microVM was resumed.");
+
+        Ok(VmmData::Empty)
+    }
+
+    // Applies the lifecycle action configured for the way the guest stopped.
+    fn handle_guest_exit(&mut self, reason: VcpuExitReason) {
+        let (action, exit_code) = match reason {
+            VcpuExitReason::Poweroff => {
+                info!("The guest powered off.");
+                (self.vm_config.on_poweroff, FC_EXIT_CODE_GUEST_POWEROFF)
+            }
+            VcpuExitReason::Reboot => {
+                info!("The guest rebooted.");
+                (self.vm_config.on_reboot, FC_EXIT_CODE_GUEST_REBOOT)
+            }
+            VcpuExitReason::Error => {
+                error!("A vCPU stopped because of an error.");
+                return self.stop(i32::from(FC_EXIT_CODE_GENERIC_ERROR));
+            }
+        };
+
+        match action.unwrap_or(LifecycleAction::Exit) {
+            LifecycleAction::Exit => self.stop(i32::from(exit_code)),
+            LifecycleAction::Halt => {
+                self.set_instance_state(InstanceState::Halting);
+                if let Err(e) = self.pause_vcpus() {
+                    error!("Failed to halt the microVM: {}", e);
+                    return self.stop(i32::from(FC_EXIT_CODE_GENERIC_ERROR));
+                }
+                self.epoll_context.pause_device_events();
+                self.set_instance_state(InstanceState::Halted);
+                info!("The microVM was halted.");
+            }
+            LifecycleAction::Reboot => {
+                if let Err(e) = self.reboot_vm() {
+                    error!("Failed to reboot the microVM: {}", e);
+                    self.stop(i32::from(FC_EXIT_CODE_GENERIC_ERROR));
+                }
+            }
+        }
+    }
+
+    // Passes an event on to the epoll handler of a device. A device whose handler fails is
+    // stopped, while the rest of the microVM keeps running.
+    fn handle_device_event(
+        &mut self,
+        device_idx: usize,
+        device_token: DeviceEventT,
+        event_flags: u32,
+    ) {
+        let result = match self.epoll_context.get_device_handler(device_idx) {
+            Ok(handler) => {
+                handler.handle_event(device_token, event_flags, EpollHandlerPayload::Empty)
+            }
+            Err(e) => {
+                warn!("invalid handler for device {}: {:?}", device_idx, e);
+                return;
+            }
+        };
+        match result {
+            Ok(()) => (),
+            // The rate limiter timer fired without having expired, there is nothing to do.
+            Err(devices::Error::RateLimited(e)) => {
+                warn!(
+                    "Spurious rate limiter event for device {}: {:?}",
+                    device_idx, e
+                )
+            }
+            Err(e) => self.stop_failed_device(device_idx, &e),
+        }
+    }
+
+    // Stops polling the events of a device whose epoll handler failed. The device is marked as
+    // needing a reset, and its ID is reported through the instance information.
+    fn stop_failed_device(&mut self, device_idx: usize, err: &devices::Error) {
+        METRICS.vmm.device_failures.inc();
+        let (id, addr) = match self.handler_device_map.get(&device_idx) {
+            Some(&(ref id, addr)) => (id.clone(), Some(addr)),
+            None => (format!("with handler {}", device_idx), None),
+        };
+        error!("Device {} failed and was stopped: {:?}", id, err);
+
+        if let Err(e) = self.epoll_context.drop_device_handler(device_idx) {
+            error!(
+                "Failed to stop polling the events of device {}: {:?}",
+                id, e
+            );
+        }
+        if let (Some(addr), Some(device_manager)) = (addr, self.mmio_device_manager.as_ref()) {
+            if let Err(e) = device_manager.set_device_needs_reset(addr) {
+                error!("Failed to mark device {} as needing a reset: {}", id, e);
+            }
+        }
+        // Use expect() to crash if the other thread poisoned this lock.
+        self.shared_info
+            .write()
+            .expect("Failed to report the failed device due to poisoned lock")
+            .failed_devices
+            .push(id);
+    }
+
+    // Applies the action configured for when the guest kernel reports a panic through pvpanic.
+    fn handle_guest_panic(&mut self) {
+        match self.vm_config.on_panic.unwrap_or(PanicAction::Ignore) {
+            PanicAction::Exit => self.stop(i32::from(FC_EXIT_CODE_GUEST_PANIC)),
+            PanicAction::Pause => {
+                // The microVM may have been paused already, e.g. through the API.
+                if self.instance_state() == InstanceState::Running {
+                    if let Err(e) = self.pause_vm() {
+                        error!("Failed to pause the microVM after the guest panic: {}", e);
+                    }
+                }
+            }
+            PanicAction::Ignore => (),
+        }
+    }
+
+    // Boots the guest again in place: the VM and the vCPUs go back to their power-on state, the
+    // kernel is loaded again and the devices are reset.
+    #[cfg(target_arch = "x86_64")]
+    fn reboot_vm(&mut self) -> std::result::Result<(), VmmActionError> {
+        let (vm_state, vcpu_states) = self
+            .power_on_state
+            .clone()
+            .ok_or(VmStateError::RebootNotSupported)?;
+
+        self.pause_vcpus()?;
+        self.epoll_context
+            .drop_device_handlers()
+            .map_err(|_| StartMicrovmError::RegisterEvent)?;
+
+        self.vm
+            .restore_state(&vm_state)
+            .map_err(StartMicrovmError::ConfigureVm)?;
+        self.reset_virtio_devices()?;
+        // The devices which failed were replaced as well.
+        self.shared_info
+            .write()
+            .expect("Failed to reset the failed devices due to poisoned lock")
+            .failed_devices
+            .clear();
+        self.load_kernel()?;
+        self.configure_system()?;
+        self.vcpus_pause_control.reset_vcpus_on_resume(&vcpu_states);
+
+        self.epoll_context.resume_device_events();
+        self.vcpus_pause_control.resume();
+        self.set_instance_state(InstanceState::Running);
+        info!("The microVM was rebooted.");
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn reboot_vm(&mut self) -> std::result::Result<(), VmmActionError> {
+        Err(VmStateError::RebootNotSupported)?
+    }
+
+    // Replaces the virtio devices with new ones, built from the same configurations, on the
+    // same MMIO slots. The device handlers must have been dropped beforehand.
+    #[cfg(target_arch = "x86_64")]
+    fn reset_virtio_devices(&mut self) -> std::result::Result<(), StartMicrovmError> {
+        let mut device_manager = self
+            .mmio_device_manager
+            .take()
+            .ok_or(StartMicrovmError::DeviceManager)?;
+        device_manager.start_reset();
+
+        // The kernel command line already holds the device parameters.
+        let mut cmdline = kernel_cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE);
+        let result = self
+            .attach_block_devices(&mut device_manager, &mut cmdline)
+            .and_then(|_| self.attach_net_devices(&mut device_manager, &mut cmdline));
+        #[cfg(feature = "vsock")]
+        let result = result.and_then(|_| {
+            let guest_mem = self
+                .guest_memory
+                .clone()
+                .ok_or(StartMicrovmError::GuestMemory(
+                    memory_model::GuestMemoryError::MemoryNotInitialized,
+                ))?;
+            self.attach_vsock_devices(&mut device_manager, &guest_mem, &mut cmdline)
+        });
+        let result =
+            result.and_then(|_| self.attach_balloon_device(&mut device_manager, &mut cmdline));
+
+        device_manager.finish_reset();
+        self.mmio_device_manager = Some(device_manager);
+        result
+    }
+
     /// Waits for all vCPUs to exit and terminates the Firecracker process.
     fn stop(&mut self, exit_code: i32) {
         info!("Vmm is stopping.");
@@ -1399,10 +3073,10 @@ impl Vmm {
 
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
 
-        let epoll_raw_fd = self.epoll_context.epoll_raw_fd;
-
         // TODO: try handling of errors/failures without breaking this main loop.
         'poll: loop {
+            // While the microVM is paused, only the VMM's own events are polled.
+            let epoll_raw_fd = self.epoll_context.poll_raw_fd();
             let num_events = epoll::wait(epoll_raw_fd, -1, &mut events[..]).map_err(Error::Poll)?;
 
             for event in events.iter().take(num_events) {
@@ -1413,11 +3087,41 @@ impl Vmm {
                         EpollDispatch::Exit => {
                             match self.exit_evt {
                                 Some(ref ev) => {
-                                    ev.fd.read().map_err(Error::EventFd)?;
+                                    if let Err(e) = ev.fd.read() {
+                                        warn!("Failed to read the exit event: {:?}", e);
+                                        continue;
+                                    }
                                 }
                                 None => warn!("leftover exit-evt in epollcontext!"),
                             }
-                            self.stop(i32::from(FC_EXIT_CODE_OK));
+                            // Without a reason from the vCPUs, the guest rebooted through the
+                            // i8042 controller.
+                            let reason = self
+                                .vcpus_pause_control
+                                .take_exit_reason()
+                                .unwrap_or(VcpuExitReason::Reboot);
+                            self.handle_guest_exit(reason);
+                        }
+                        EpollDispatch::GuestPanic => {
+                            match self.panic_evt {
+                                Some(ref ev) => {
+                                    if let Err(e) = ev.fd.read() {
+                                        warn!("Failed to read the panic event: {:?}", e);
+                                        continue;
+                                    }
+                                }
+                                None => warn!("leftover panic-evt in epollcontext!"),
+                            }
+                            // The device already logged the events and counted them.
+                            let events = self
+                                .legacy_device_manager
+                                .pvpanic
+                                .lock()
+                                .expect("Failed to handle the guest panic due to poisoned lock")
+                                .take_events();
+                            if events != 0 {
+                                self.handle_guest_panic();
+                            }
                         }
                         EpollDispatch::Stdin => {
                             let mut out = [0u8; 64];
@@ -1434,42 +3138,33 @@ impl Vmm {
                                 Ok(count) => {
                                     // Use expect() to panic if another thread panicked
                                     // while holding the lock.
-                                    self.legacy_device_manager
+                                    if let Err(e) = self
+                                        .legacy_device_manager
                                         .stdio_serial
                                         .lock()
                                         .expect(
                                             "Failed to process stdin event due to poisoned lock",
                                         )
                                         .queue_input_bytes(&out[..count])
-                                        .map_err(Error::Serial)?;
+                                    {
+                                        warn!("Failed to queue the stdin input: {:?}", e);
+                                    }
                                 }
                             }
                         }
                         EpollDispatch::DeviceHandler(device_idx, device_token) => {
-                            METRICS.vmm.device_events.inc();
-                            match self.epoll_context.get_device_handler(device_idx) {
-                                Ok(handler) => {
-                                    match handler.handle_event(
-                                        device_token,
-                                        event.events,
-                                        EpollHandlerPayload::Empty,
-                                    ) {
-                                        Err(devices::Error::PayloadExpected) => panic!(
-                                            "Received update disk image event with empty payload."
-                                        ),
-                                        Err(devices::Error::UnknownEvent { device, event }) => {
-                                            panic!("Unknown event: {:?} {:?}", device, event)
-                                        }
-                                        _ => (),
-                                    }
-                                }
-                                Err(e) => {
-                                    warn!("invalid handler for device {}: {:?}", device_idx, e)
-                                }
+                            // The microVM may have been paused by an event from this same batch.
+                            // The device event stays pending until the microVM is resumed.
+                            if self.epoll_context.device_events_paused {
+                                continue;
                             }
+                            METRICS.vmm.device_events.inc();
+                            self.handle_device_event(device_idx, device_token, event.events);
                         }
                         EpollDispatch::VmmActionRequest => {
-                            self.api_event.fd.read().map_err(Error::EventFd)?;
+                            if let Err(e) = self.api_event.fd.read() {
+                                warn!("Failed to read the API event: {:?}", e);
+                            }
                             self.run_vmm_action().unwrap_or_else(|_| {
                                 warn!("got spurious notification from api thread");
                             });
@@ -1527,18 +3222,17 @@ impl Vmm {
             ));
         }
 
-        let kernel_file = File::open(kernel_image_path).map_err(|_| {
+        let kernel_file = File::open(&kernel_image_path).map_err(|_| {
             VmmActionError::BootSource(ErrorKind::User, BootSourceConfigError::InvalidKernelPath)
         })?;
+        let boot_args = kernel_cmdline.unwrap_or_else(|| String::from(DEFAULT_KERNEL_CMDLINE));
         let mut cmdline = kernel_cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE);
-        cmdline
-            .insert_str(kernel_cmdline.unwrap_or_else(|| String::from(DEFAULT_KERNEL_CMDLINE)))
-            .map_err(|_| {
-                VmmActionError::BootSource(
-                    ErrorKind::User,
-                    BootSourceConfigError::InvalidKernelCommandLine,
-                )
-            })?;
+        cmdline.insert_str(boot_args.as_str()).map_err(|_| {
+            VmmActionError::BootSource(
+                ErrorKind::User,
+                BootSourceConfigError::InvalidKernelCommandLine,
+            )
+        })?;
 
         let kernel_config = KernelConfig {
             kernel_file,
@@ -1546,6 +3240,10 @@ impl Vmm {
             cmdline_addr: GuestAddress(arch::CMDLINE_START),
         };
         self.configure_kernel(kernel_config);
+        self.boot_source_config = Some(BootSourceConfig {
+            kernel_image_path,
+            boot_args: Some(boot_args),
+        });
 
         Ok(VmmData::Empty)
     }
@@ -1588,6 +3286,33 @@ impl Vmm {
             Err(VmConfigError::InvalidVcpuCount)?;
         }
 
+        // The vCPU affinity needs a set of host CPUs for each vCPU, including when only the
+        // vCPU count changes in this call.
+        let vcpu_affinity = machine_config
+            .vcpu_affinity
+            .as_ref()
+            .or_else(|| self.vm_config.vcpu_affinity.as_ref());
+        if let Some(vcpu_affinity) = vcpu_affinity {
+            if vcpu_affinity.len() != vcpu_count_value as usize {
+                Err(VmConfigError::InvalidVcpuAffinityCount)?;
+            }
+            for cpus in vcpu_affinity {
+                validate_cpu_affinity(cpus)?;
+            }
+        }
+
+        if let Some(ref cpus) = machine_config.vmm_affinity {
+            validate_cpu_affinity(cpus)?;
+        }
+
+        for scheduling in machine_config
+            .vcpu_scheduling
+            .iter()
+            .chain(machine_config.vmm_scheduling.iter())
+        {
+            scheduling.validate()?;
+        }
+
         // Update all the fields that have a new value.
         self.vm_config.vcpu_count = Some(vcpu_count_value);
         self.vm_config.ht_enabled = Some(ht_enabled);
@@ -1599,21 +3324,144 @@ impl Vmm {
         if machine_config.cpu_template.is_some() {
             self.vm_config.cpu_template = machine_config.cpu_template;
         }
-
+
+        if machine_config.on_poweroff.is_some() {
+            self.vm_config.on_poweroff = machine_config.on_poweroff;
+        }
+
+        if machine_config.on_reboot.is_some() {
+            self.vm_config.on_reboot = machine_config.on_reboot;
+        }
+
+        if machine_config.on_panic.is_some() {
+            self.vm_config.on_panic = machine_config.on_panic;
+        }
+
+        if machine_config.mem_backing.is_some() {
+            self.vm_config.mem_backing = machine_config.mem_backing;
+        }
+
+        if machine_config.vcpu_affinity.is_some() {
+            self.vm_config.vcpu_affinity = machine_config.vcpu_affinity;
+        }
+
+        if machine_config.vcpu_scheduling.is_some() {
+            self.vm_config.vcpu_scheduling = machine_config.vcpu_scheduling;
+        }
+
+        if machine_config.vmm_affinity.is_some() {
+            self.vm_config.vmm_affinity = machine_config.vmm_affinity;
+        }
+
+        if machine_config.vmm_scheduling.is_some() {
+            self.vm_config.vmm_scheduling = machine_config.vmm_scheduling;
+        }
+
+        Ok(VmmData::Empty)
+    }
+
+    fn insert_net_device(
+        &mut self,
+        body: NetworkInterfaceConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        if self.is_instance_initialized() {
+            Err(NetworkInterfaceError::UpdateNotAllowedPostBoot)?;
+        }
+        self.network_interface_configs
+            .insert(body)
+            .map(|_| VmmData::Empty)
+            .map_err(|e| VmmActionError::NetworkConfig(ErrorKind::User, e))
+    }
+
+    // Checks that the balloon can hold `amount_mib` without exceeding the guest memory.
+    fn check_balloon_size(&self, amount_mib: u32) -> std::result::Result<(), BalloonError> {
+        match self.vm_config.mem_size_mib {
+            Some(mem_size_mib) if amount_mib as usize > mem_size_mib => {
+                Err(BalloonError::TooManyPagesRequested)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn insert_balloon_device(
+        &mut self,
+        balloon_config: BalloonConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        if self.is_instance_initialized() {
+            Err(BalloonError::UpdateNotAllowedPostBoot)?;
+        }
+        self.check_balloon_size(balloon_config.amount_mib)?;
+        self.balloon_config = Some(balloon_config);
+        Ok(VmmData::Empty)
+    }
+
+    fn update_balloon(
+        &mut self,
+        new_cfg: BalloonUpdateConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        self.check_balloon_size(new_cfg.amount_mib)?;
+        let num_pages = {
+            let balloon_config = self
+                .balloon_config
+                .as_mut()
+                .ok_or(BalloonError::DeviceNotFound)?;
+            // The configuration follows the live device, so that it is created again with the
+            // same size on reboot.
+            balloon_config.amount_mib = new_cfg.amount_mib;
+            balloon_config.num_pages()
+        };
+
+        if let Some(ref state) = self.balloon_state {
+            state
+                .lock()
+                .expect("Poisoned balloon state lock")
+                .set_target_pages(num_pages);
+        }
+        if let Some(handler_idx) = self.balloon_handler_idx {
+            // If the driver did not activate the device yet, there is no handler to notify. The
+            // driver reads the new target size from the shared state once it does.
+            if let Ok(handler) = self.epoll_context.get_device_handler(handler_idx) {
+                handler
+                    .handle_event(
+                        virtio::balloon::CONFIG_CHANGE_FAKE_EVENT,
+                        handler_idx as u32,
+                        EpollHandlerPayload::Empty,
+                    )
+                    .map_err(BalloonError::ConfigChangeFailed)?;
+            }
+        }
         Ok(VmmData::Empty)
     }
 
-    fn insert_net_device(
-        &mut self,
-        body: NetworkInterfaceConfig,
-    ) -> std::result::Result<VmmData, VmmActionError> {
-        if self.is_instance_initialized() {
-            Err(NetworkInterfaceError::UpdateNotAllowedPostBoot)?;
+    fn get_balloon_config(&self) -> std::result::Result<VmmData, VmmActionError> {
+        self.balloon_config
+            .clone()
+            .map(VmmData::BalloonConfig)
+            .ok_or_else(|| BalloonError::DeviceNotFound.into())
+    }
+
+    fn get_balloon_stats(&self) -> std::result::Result<VmmData, VmmActionError> {
+        let balloon_config = self
+            .balloon_config
+            .as_ref()
+            .ok_or(BalloonError::DeviceNotFound)?;
+        if balloon_config.stats_polling_interval_s == 0 {
+            Err(BalloonError::StatisticsDisabled)?;
         }
-        self.network_interface_configs
-            .insert(body)
-            .map(|_| VmmData::Empty)
-            .map_err(|e| VmmActionError::NetworkConfig(ErrorKind::User, e))
+        let statistics = match self.balloon_state {
+            Some(ref state) => state
+                .lock()
+                .expect("Poisoned balloon state lock")
+                .statistics(),
+            // Nothing was reported before boot, except the target size.
+            None => {
+                let mut statistics = virtio::BalloonStatistics::default();
+                statistics.target_pages = balloon_config.num_pages();
+                statistics.target_mib = balloon_config.amount_mib;
+                statistics
+            }
+        };
+        Ok(VmmData::BalloonStatistics(statistics))
     }
 
     fn update_net_device(
@@ -1623,34 +3471,7 @@ impl Vmm {
         if !self.is_instance_initialized() {
             // VM not started yet, so we only need to update the device configs, not the actual
             // live device.
-            let old_cfg = self
-                .network_interface_configs
-                .iter_mut()
-                .find(|&&mut ref c| c.iface_id == new_cfg.iface_id)
-                .ok_or(NetworkInterfaceError::DeviceIdNotFound)?;
-
-            // Check if we need to update the RX rate limiter.
-            if let Some(new_rlim_cfg) = new_cfg.rx_rate_limiter {
-                if let Some(ref mut old_rlim_cfg) = old_cfg.rx_rate_limiter {
-                    // We already have an RX rate limiter set, so we'll update it.
-                    old_rlim_cfg.update(&new_rlim_cfg);
-                } else {
-                    // No old RX rate limiter; create one now.
-                    old_cfg.rx_rate_limiter = Some(new_rlim_cfg);
-                }
-            }
-
-            // Check if we need to update the TX rate limiter.
-            if let Some(new_rlim_cfg) = new_cfg.tx_rate_limiter {
-                if let Some(ref mut old_rlim_cfg) = old_cfg.tx_rate_limiter {
-                    // We already have a TX rate limiter set, so we'll update it.
-                    old_rlim_cfg.update(&new_rlim_cfg);
-                } else {
-                    // No old TX rate limiter; create one now.
-                    old_cfg.tx_rate_limiter = Some(new_rlim_cfg);
-                }
-            }
-
+            self.update_net_device_config(&new_cfg)?;
             return Ok(VmmData::Empty);
         }
 
@@ -1694,9 +3515,45 @@ impl Vmm {
             )
             .map_err(NetworkInterfaceError::RateLimiterUpdateFailed)?;
 
+        // Keep the device configs in sync with the live device.
+        self.update_net_device_config(&new_cfg)?;
         Ok(VmmData::Empty)
     }
 
+    fn update_net_device_config(
+        &mut self,
+        new_cfg: &NetworkInterfaceUpdateConfig,
+    ) -> std::result::Result<(), NetworkInterfaceError> {
+        let old_cfg = self
+            .network_interface_configs
+            .iter_mut()
+            .find(|&&mut ref c| c.iface_id == new_cfg.iface_id)
+            .ok_or(NetworkInterfaceError::DeviceIdNotFound)?;
+
+        // Check if we need to update the RX rate limiter.
+        if let Some(new_rlim_cfg) = new_cfg.rx_rate_limiter {
+            if let Some(ref mut old_rlim_cfg) = old_cfg.rx_rate_limiter {
+                // We already have an RX rate limiter set, so we'll update it.
+                old_rlim_cfg.update(&new_rlim_cfg);
+            } else {
+                // No old RX rate limiter; create one now.
+                old_cfg.rx_rate_limiter = Some(new_rlim_cfg);
+            }
+        }
+
+        // Check if we need to update the TX rate limiter.
+        if let Some(new_rlim_cfg) = new_cfg.tx_rate_limiter {
+            if let Some(ref mut old_rlim_cfg) = old_cfg.tx_rate_limiter {
+                // We already have a TX rate limiter set, so we'll update it.
+                old_rlim_cfg.update(&new_rlim_cfg);
+            } else {
+                // No old TX rate limiter; create one now.
+                old_cfg.tx_rate_limiter = Some(new_rlim_cfg);
+            }
+        }
+        Ok(())
+    }
+
     #[cfg(feature = "vsock")]
     fn insert_vsock_device(
         &mut self,
@@ -1726,21 +3583,92 @@ impl Vmm {
             .ok_or(DriveError::InvalidBlockDeviceID)?;
 
         let file_path = PathBuf::from(path_on_host);
+        let drive_config = &self.block_device_configs.config_list[block_device_index];
+        // The overlay only makes sense on top of the base image it was written over.
+        if drive_config.overlay.is_some() && self.is_instance_initialized() {
+            Err(DriveError::BaseImageUpdateNotAllowed)?;
+        }
+        // The device is set up with the back-end when it is created, so only the socket of the
+        // back-end to connect to at boot can be changed.
+        if drive_config.is_vhost_user() {
+            if self.is_instance_initialized() {
+                Err(DriveError::VhostUserUpdateNotAllowed)?;
+            }
+            if !file_path.exists() {
+                Err(DriveError::InvalidBlockDevicePath)?;
+            }
+            self.block_device_configs.config_list[block_device_index].path_on_host = file_path;
+            return Ok(VmmData::Empty);
+        }
+        // An empty path detaches the disk image, and the drive keeps its options for the next
+        // disk image attached to it.
+        if file_path.as_os_str().is_empty() {
+            if drive_config.is_root_device || drive_config.overlay.is_some() {
+                Err(DriveError::EmptyDriveOption)?;
+            }
+            self.block_device_configs.config_list[block_device_index].path_on_host = file_path;
+            if self.is_instance_initialized() {
+                self.update_drive_handler(&drive_id, None)?;
+                self.update_block_device_size(&drive_id, 0)?;
+            }
+            return Ok(VmmData::Empty);
+        }
         // Try to open the file specified by path_on_host using the permissions of the block_device.
-        let disk_file = OpenOptions::new()
-            .read(true)
-            .write(!self.block_device_configs.config_list[block_device_index].is_read_only())
-            .open(&file_path)
-            .map_err(|_| DriveError::CannotOpenBlockDevice)?;
+        let cache_type = drive_config.cache_type.unwrap_or_default();
+        let writable = !drive_config.is_read_only() && drive_config.overlay.is_none();
+        let mut disk_image = match drive_config.nbd {
+            // The new path is the socket of another NBD server.
+            Some(ref nbd) => open_nbd(&file_path, nbd, writable)
+                .map_err(|e| DriveError::ConnectNbdServer(e.to_string()))?,
+            None => {
+                let disk_file = OpenOptions::new()
+                    .read(true)
+                    .write(writable)
+                    .custom_flags(direct_io_flags(cache_type))
+                    .open(&file_path)
+                    .map_err(|_| DriveError::CannotOpenBlockDevice)?;
+                devices::virtio::open_disk_image(
+                    disk_file,
+                    &file_path,
+                    drive_config.disk_image_format(),
+                )
+                .map_err(|e| DriveError::InvalidDiskImage(e.to_string()))?
+            }
+        };
+        // The new disk image is encrypted with the same key.
+        if let Some(ref encryption) = drive_config.encryption {
+            disk_image = Box::new(
+                open_crypt(disk_image, encryption)
+                    .map_err(|e| DriveError::InvalidDiskImage(e.to_string()))?,
+            );
+        }
+        // The new disk image has to match the hash tree too.
+        if let Some(ref verity) = drive_config.verity {
+            disk_image = Box::new(
+                open_verity(disk_image, verity)
+                    .map_err(|e| DriveError::InvalidDiskImage(e.to_string()))?,
+            );
+        }
+        if drive_config.io_engine.unwrap_or_default() == IoEngine::Async && !disk_image.is_raw() {
+            Err(DriveError::AsyncIoEngineImageFormat)?;
+        }
+        if cache_type == CacheType::None && !disk_image.is_raw() {
+            Err(DriveError::DirectIoImageFormat)?;
+        }
+        // The guest sees the virtual size of the image, whatever its format.
+        let disk_size = disk_image
+            .seek(SeekFrom::End(0))
+            .map_err(|_| DriveError::BlockDeviceUpdateFailed)?;
 
         // Update the path of the block device with the specified path_on_host.
         self.block_device_configs.config_list[block_device_index].path_on_host = file_path;
 
-        // When the microvm is running, we also need to update the drive handler and send a
-        // rescan command to the drive.
+        // When the microvm is running, we also need to update the drive handler and tell the
+        // drive the size of the new disk image, which is taken from the image already opened
+        // rather than by opening it again.
         if self.is_instance_initialized() {
-            self.update_drive_handler(&drive_id, disk_file)?;
-            self.rescan_block_device(&drive_id)?;
+            self.update_drive_handler(&drive_id, Some(disk_image))?;
+            self.update_block_device_size(&drive_id, disk_size)?;
         }
         Ok(VmmData::Empty)
     }
@@ -1757,31 +3685,68 @@ impl Vmm {
         // Safe to unwrap() because mmio_device_manager is initialized in init_devices(), which is
         // called before the guest boots, and this function is called after boot.
         let device_manager = self.mmio_device_manager.as_ref().unwrap();
-        match device_manager.get_address(drive_id) {
-            Some(&address) => {
-                for drive_config in self.block_device_configs.config_list.iter() {
-                    if drive_config.drive_id == *drive_id {
-                        let metadata = metadata(&drive_config.path_on_host)
-                            .map_err(|_| DriveError::BlockDeviceUpdateFailed)?;
-                        let new_size = metadata.len();
-                        if new_size % virtio::block::SECTOR_SIZE != 0 {
-                            warn!(
-                                "Disk size {} is not a multiple of sector size {}; \
-                                 the remainder will not be visible to the guest.",
-                                new_size,
-                                virtio::block::SECTOR_SIZE
-                            );
-                        }
-                        return device_manager
-                            .update_drive(address, new_size)
-                            .map(|_| VmmData::Empty)
-                            .map_err(|_| VmmActionError::from(DriveError::BlockDeviceUpdateFailed));
-                    }
-                }
-                Err(VmmActionError::from(DriveError::BlockDeviceUpdateFailed))
-            }
-            _ => Err(VmmActionError::from(DriveError::InvalidBlockDeviceID)),
+        if device_manager.get_address(drive_id).is_none() {
+            Err(DriveError::InvalidBlockDeviceID)?;
+        }
+        let drive_config = self
+            .block_device_configs
+            .config_list
+            .iter()
+            .find(|drive_config| drive_config.drive_id == *drive_id)
+            .ok_or(DriveError::BlockDeviceUpdateFailed)?;
+        // The configuration space of the drive comes from its back-end.
+        if drive_config.is_vhost_user() {
+            Err(DriveError::VhostUserUpdateNotAllowed)?;
+        }
+        // A drive without a disk image has a capacity of 0.
+        let new_size = if drive_config.is_empty() {
+            0
+        } else if drive_config.nbd.is_some() {
+            // The size of an NBD export is the one the server sent when the drive connected, and
+            // the guest already sees it. Connecting again would only read back the same size.
+            return Ok(VmmData::Empty);
+        } else {
+            let file = OpenOptions::new()
+                .read(true)
+                .open(&drive_config.path_on_host)
+                .map_err(|_| DriveError::BlockDeviceUpdateFailed)?;
+            let mut disk_image = devices::virtio::open_disk_image(
+                file,
+                &drive_config.path_on_host,
+                drive_config.disk_image_format(),
+            )
+            .map_err(|e| DriveError::InvalidDiskImage(e.to_string()))?;
+            // The guest sees the virtual size of the image, whatever its format.
+            disk_image
+                .seek(SeekFrom::End(0))
+                .map_err(|_| DriveError::BlockDeviceUpdateFailed)?
+        };
+        self.update_block_device_size(drive_id, new_size)
+    }
+
+    // Updates the capacity of a running drive, which the guest is then notified of.
+    fn update_block_device_size(
+        &self,
+        drive_id: &str,
+        new_size: u64,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        // Safe to unwrap() because this is only called after boot, see rescan_block_device().
+        let device_manager = self.mmio_device_manager.as_ref().unwrap();
+        let address = *device_manager
+            .get_address(drive_id)
+            .ok_or(DriveError::InvalidBlockDeviceID)?;
+        if new_size % virtio::block::SECTOR_SIZE != 0 {
+            warn!(
+                "Disk size {} is not a multiple of sector size {}; \
+                 the remainder will not be visible to the guest.",
+                new_size,
+                virtio::block::SECTOR_SIZE
+            );
         }
+        device_manager
+            .update_drive(address, new_size)
+            .map(|_| VmmData::Empty)
+            .map_err(|_| VmmActionError::from(DriveError::BlockDeviceUpdateFailed))
     }
 
     // Only call this function as part of the API.
@@ -1801,7 +3766,7 @@ impl Vmm {
     }
 
     fn init_logger(
-        &self,
+        &mut self,
         api_logger: LoggerConfig,
     ) -> std::result::Result<VmmData, VmmActionError> {
         if self.is_instance_initialized() {
@@ -1830,6 +3795,7 @@ impl Vmm {
 
         LOGGER.set_include_origin(api_logger.show_log_origin, api_logger.show_log_origin);
         LOGGER.set_include_level(api_logger.show_level);
+        let logger_config = api_logger.clone();
 
         #[cfg(target_arch = "aarch64")]
         let options: &Vec<Value> = &vec![];
@@ -1844,13 +3810,14 @@ impl Vmm {
                 api_logger.metrics_fifo,
                 options,
             )
-            .map(|_| VmmData::Empty)
             .map_err(|e| {
                 VmmActionError::Logger(
                     ErrorKind::User,
                     LoggerConfigError::InitializationFailure(e.to_string()),
                 )
-            })
+            })?;
+        self.logger_config = Some(logger_config);
+        Ok(VmmData::Empty)
     }
 
     fn send_response(outcome: VmmRequestOutcome, sender: OutcomeSender) {
@@ -1884,15 +3851,33 @@ impl Vmm {
             VmmAction::ConfigureLogger(logger_description, sender) => {
                 Vmm::send_response(self.init_logger(logger_description), sender);
             }
+            VmmAction::CreateCoreDump(coredump_config, sender) => {
+                Vmm::send_response(self.create_core_dump(coredump_config), sender);
+            }
+            VmmAction::CreateSnapshot(snapshot_config, sender) => {
+                Vmm::send_response(self.create_snapshot(snapshot_config), sender);
+            }
             VmmAction::FlushMetrics(sender) => {
                 Vmm::send_response(self.flush_metrics(), sender);
             }
+            VmmAction::GetBalloonConfig(sender) => {
+                Vmm::send_response(self.get_balloon_config(), sender);
+            }
+            VmmAction::GetBalloonStats(sender) => {
+                Vmm::send_response(self.get_balloon_stats(), sender);
+            }
             VmmAction::GetVmConfiguration(sender) => {
                 Vmm::send_response(
                     Ok(VmmData::MachineConfiguration(self.vm_config.clone())),
                     sender,
                 );
             }
+            VmmAction::GetVmmConfiguration(sender) => {
+                Vmm::send_response(Ok(VmmData::VmmConfiguration(self.vmm_config())), sender);
+            }
+            VmmAction::InsertBalloonDevice(balloon_config, sender) => {
+                Vmm::send_response(self.insert_balloon_device(balloon_config), sender);
+            }
             VmmAction::InsertBlockDevice(block_device_config, sender) => {
                 Vmm::send_response(self.insert_block_device(block_device_config), sender);
             }
@@ -1903,18 +3888,36 @@ impl Vmm {
             VmmAction::InsertVsockDevice(vsock_cfg, sender) => {
                 Vmm::send_response(self.insert_vsock_device(vsock_cfg), sender);
             }
+            VmmAction::LoadSnapshot(snapshot_config, sender) => {
+                Vmm::send_response(self.load_snapshot(snapshot_config), sender);
+            }
+            VmmAction::PauseVm(sender) => {
+                Vmm::send_response(self.pause_vm(), sender);
+            }
+            VmmAction::ReceiveMigration(migration_config, sender) => {
+                Vmm::send_response(self.receive_migration(migration_config), sender);
+            }
             VmmAction::RescanBlockDevice(drive_id, sender) => {
                 Vmm::send_response(self.rescan_block_device(&drive_id), sender);
             }
+            VmmAction::ResumeVm(sender) => {
+                Vmm::send_response(self.resume_vm(), sender);
+            }
             VmmAction::StartMicroVm(sender) => {
                 Vmm::send_response(self.start_microvm(), sender);
             }
             VmmAction::SendCtrlAltDel(sender) => {
                 Vmm::send_response(self.send_ctrl_alt_del(), sender);
             }
+            VmmAction::SendMigration(migration_config, sender) => {
+                Vmm::send_response(self.send_migration(migration_config), sender);
+            }
             VmmAction::SetVmConfiguration(machine_config_body, sender) => {
                 Vmm::send_response(self.set_vm_configuration(machine_config_body), sender);
             }
+            VmmAction::UpdateBalloon(balloon_update, sender) => {
+                Vmm::send_response(self.update_balloon(balloon_update), sender);
+            }
             VmmAction::UpdateBlockDevicePath(drive_id, path_on_host, sender) => {
                 Vmm::send_response(self.set_block_device_path(drive_id, path_on_host), sender);
             }
@@ -1925,6 +3928,75 @@ impl Vmm {
         Ok(())
     }
 
+    // Applies the configuration file in the same order an orchestrator would issue the API
+    // requests, then starts the microVM.
+    fn boot_from_config(
+        &mut self,
+        vmm_config: VmmConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        if let Some(logger) = vmm_config.logger {
+            self.init_logger(logger)?;
+        }
+        if let Some(machine_config) = vmm_config.machine_config {
+            self.set_vm_configuration(machine_config)?;
+        }
+        if let Some(boot_source) = vmm_config.boot_source {
+            self.configure_boot_source(boot_source.kernel_image_path, boot_source.boot_args)?;
+        }
+        for block_device_config in vmm_config.block_devices {
+            self.insert_block_device(block_device_config)?;
+        }
+        for netif_config in vmm_config.net_devices {
+            self.insert_net_device(netif_config)?;
+        }
+        #[cfg(feature = "vsock")]
+        for vsock_config in vmm_config.vsock_devices {
+            self.insert_vsock_device(vsock_config)?;
+        }
+        if let Some(balloon_config) = vmm_config.balloon {
+            self.insert_balloon_device(balloon_config)?;
+        }
+        self.start_microvm()
+    }
+
+    // Gathers the effective configuration of the microVM, in the format of a configuration file
+    // which boots the same microVM.
+    fn vmm_config(&mut self) -> VmmConfig {
+        let (mut block_devices, net_devices) = self.device_configs();
+        // The drives are reported with the values they are set up with when not configured.
+        for drive in block_devices.iter_mut() {
+            drive.num_queues = Some(drive.num_queues());
+            drive.queue_size = Some(drive.queue_size());
+            // The back-end of a vhost-user drive takes none of the other options.
+            if !drive.is_vhost_user() {
+                drive.io_engine = Some(drive.io_engine.unwrap_or_default());
+                drive.cache_type = Some(drive.cache_type.unwrap_or_default());
+                drive.discard = Some(drive.discard.unwrap_or(false));
+                drive.write_zeroes = Some(drive.write_zeroes.unwrap_or(false));
+            }
+            // The encryption keys are not reported back, and have to be given again for the
+            // configuration to be loaded.
+            if let Some(ref mut encryption) = drive.encryption {
+                encryption.key = String::from("<redacted>");
+            }
+        }
+        VmmConfig {
+            balloon: self.balloon_config.clone(),
+            boot_source: self.boot_source_config.clone(),
+            block_devices,
+            logger: self.logger_config.clone(),
+            machine_config: Some(self.vm_config.clone()),
+            mmds: mmds::MMDS
+                .lock()
+                .expect("Poisoned MMDS lock")
+                .get_data()
+                .cloned(),
+            net_devices,
+            #[cfg(feature = "vsock")]
+            vsock_devices: self.vsock_device_configs.iter().cloned().collect(),
+        }
+    }
+
     fn log_boot_time(t0_ts: &TimestampUs) {
         let now_cpu_us = now_cputime_us();
         let now_us = get_time_us();
@@ -1982,6 +4054,39 @@ impl PartialEq for VmmAction {
             (&VmmAction::StartMicroVm(_), &VmmAction::StartMicroVm(_)) => true,
             (&VmmAction::SendCtrlAltDel(_), &VmmAction::SendCtrlAltDel(_)) => true,
             (&VmmAction::FlushMetrics(_), &VmmAction::FlushMetrics(_)) => true,
+            (&VmmAction::GetVmmConfiguration(_), &VmmAction::GetVmmConfiguration(_)) => true,
+            (
+                &VmmAction::CreateCoreDump(ref coredump_config, _),
+                &VmmAction::CreateCoreDump(ref other_coredump_config, _),
+            ) => coredump_config == other_coredump_config,
+            (
+                &VmmAction::CreateSnapshot(ref snapshot_config, _),
+                &VmmAction::CreateSnapshot(ref other_snapshot_config, _),
+            ) => snapshot_config == other_snapshot_config,
+            (
+                &VmmAction::LoadSnapshot(ref snapshot_config, _),
+                &VmmAction::LoadSnapshot(ref other_snapshot_config, _),
+            ) => snapshot_config == other_snapshot_config,
+            (&VmmAction::PauseVm(_), &VmmAction::PauseVm(_)) => true,
+            (
+                &VmmAction::ReceiveMigration(ref migration_config, _),
+                &VmmAction::ReceiveMigration(ref other_migration_config, _),
+            ) => migration_config == other_migration_config,
+            (
+                &VmmAction::SendMigration(ref migration_config, _),
+                &VmmAction::SendMigration(ref other_migration_config, _),
+            ) => migration_config == other_migration_config,
+            (&VmmAction::ResumeVm(_), &VmmAction::ResumeVm(_)) => true,
+            (
+                &VmmAction::InsertBalloonDevice(ref balloon_config, _),
+                &VmmAction::InsertBalloonDevice(ref other_balloon_config, _),
+            ) => balloon_config == other_balloon_config,
+            (
+                &VmmAction::UpdateBalloon(ref balloon_update, _),
+                &VmmAction::UpdateBalloon(ref other_balloon_update, _),
+            ) => balloon_update == other_balloon_update,
+            (&VmmAction::GetBalloonConfig(_), &VmmAction::GetBalloonConfig(_)) => true,
+            (&VmmAction::GetBalloonStats(_), &VmmAction::GetBalloonStats(_)) => true,
             _ => false,
         }
     }
@@ -1999,18 +4104,45 @@ impl PartialEq for VmmAction {
 ///                     number) or 2 (filter by syscall number and argument values).
 /// * `kvm_fd` - Provides the option of supplying an already existing raw file descriptor
 ///              associated with `/dev/kvm`.
+/// * `vmm_config` - The microVM to boot straight away, as read from a configuration file. If it
+///                  cannot be booted, the process exits with `FC_EXIT_CODE_BAD_CONFIGURATION`.
+/// * `gdb_socket_path` - The Unix domain socket on which a GDB server waits for a client to
+///                       debug the guest kernel, x86_64 only. The vCPUs stay stopped until the
+///                       client lets them run.
 pub fn start_vmm_thread(
     api_shared_info: Arc<RwLock<InstanceInfo>>,
     api_event_fd: EventFd,
     from_api: Receiver<Box<VmmAction>>,
     seccomp_level: u32,
+    vmm_config: Option<VmmConfig>,
+    gdb_socket_path: Option<PathBuf>,
 ) -> thread::JoinHandle<()> {
+    // The API server runs on the thread which starts the VMM thread.
+    // Safe because pthread_self() cannot fail.
+    let api_thread = unsafe { libc::pthread_self() };
     thread::Builder::new()
         .name("fc_vmm".to_string())
         .spawn(move || {
             // If this fails, consider it fatal. Use expect().
             let mut vmm = Vmm::new(api_shared_info, api_event_fd, from_api, seccomp_level)
                 .expect("Cannot create VMM");
+            vmm.api_thread = Some(api_thread);
+            #[cfg(target_arch = "x86_64")]
+            {
+                vmm.gdb_socket_path = gdb_socket_path;
+            }
+            #[cfg(not(target_arch = "x86_64"))]
+            {
+                if gdb_socket_path.is_some() {
+                    warn!("The GDB server is only available on x86_64.");
+                }
+            }
+            if let Some(vmm_config) = vmm_config {
+                if let Err(e) = vmm.boot_from_config(vmm_config) {
+                    error!("Cannot boot the microVM from the configuration file: {}", e);
+                    vmm.stop(i32::from(FC_EXIT_CODE_BAD_CONFIGURATION));
+                }
+            }
             match vmm.run_control() {
                 Ok(()) => {
                     info!("Gracefully terminated VMM control loop");
@@ -2040,7 +4172,8 @@ mod tests {
     use self::tempfile::NamedTempFile;
     use devices::virtio::ActivateResult;
     use net_util::MacAddr;
-    use vmm_config::machine_config::CpuFeaturesTemplate;
+    use vmm_config::drive::OverlayConfig;
+    use vmm_config::machine_config::{CpuFeaturesTemplate, SchedulingPolicy, ThreadScheduling};
     use vmm_config::{RateLimiterConfig, TokenBucketConfig};
 
     fn good_kernel_file() -> PathBuf {
@@ -2058,14 +4191,6 @@ mod tests {
     }
 
     impl Vmm {
-        fn get_kernel_cmdline_str(&self) -> &str {
-            if let Some(ref k) = self.kernel_config {
-                k.cmdline.as_str()
-            } else {
-                ""
-            }
-        }
-
         fn remove_addr(&mut self, id: &str) {
             self.mmio_device_manager
                 .as_mut()
@@ -2092,10 +4217,6 @@ mod tests {
             self.configure_kernel(kernel_cfg);
         }
 
-        fn set_instance_state(&mut self, instance_state: InstanceState) {
-            self.shared_info.write().unwrap().state = instance_state;
-        }
-
         fn update_block_device_path(&mut self, block_device_id: &str, new_path: PathBuf) {
             for config in self.block_device_configs.config_list.iter_mut() {
                 if config.drive_id == block_device_id {
@@ -2133,6 +4254,38 @@ mod tests {
             self.payload = Some(payload);
             Ok(())
         }
+
+        fn event_fds(&self) -> Vec<RawFd> {
+            vec![]
+        }
+    }
+
+    // An epoll handler which fails on its first event, and only complains about a spurious rate
+    // limiter event on the second one.
+    struct FailingEpollHandler {
+        evt: EventFd,
+    }
+
+    impl EpollHandler for FailingEpollHandler {
+        fn handle_event(
+            &mut self,
+            device_event: DeviceEventT,
+            _: u32,
+            _: EpollHandlerPayload,
+        ) -> std::result::Result<(), devices::Error> {
+            match device_event {
+                0 => Err(devices::Error::IoError(io::Error::from_raw_os_error(
+                    libc::EIO,
+                ))),
+                _ => Err(devices::Error::RateLimited(
+                    rate_limiter::Error::SpuriousRateLimiterEvent("no timer"),
+                )),
+            }
+        }
+
+        fn event_fds(&self) -> Vec<RawFd> {
+            vec![self.evt.as_raw_fd()]
+        }
     }
 
     #[allow(dead_code)]
@@ -2184,6 +4337,7 @@ mod tests {
             state,
             id: "TEST_ID".to_string(),
             vmm_version: "1.0".to_string(),
+            failed_devices: Vec::new(),
         }));
 
         let (_to_vmm, from_api) = channel();
@@ -2212,6 +4366,55 @@ mod tests {
         assert!(ep.get_device_handler(0).is_ok());
     }
 
+    #[test]
+    fn test_failed_device() {
+        let mut vmm = create_vmm_object(InstanceState::Running);
+        let (base, sender) = vmm.epoll_context.allocate_tokens(2);
+        let evt = EventFd::new().unwrap();
+        let handler = FailingEpollHandler {
+            evt: evt.try_clone().unwrap(),
+        };
+        // Like the devices, register the event through the handle kept by the handler.
+        epoll::ctl(
+            vmm.epoll_context.epoll_raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            handler.evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, base),
+        )
+        .unwrap();
+        assert!(sender.send(Box::new(handler)).is_ok());
+        vmm.handler_device_map
+            .insert(0, ("foo".to_string(), 0xd000_0000));
+
+        // A spurious rate limiter event leaves the device running.
+        let failures = METRICS.vmm.device_failures.count();
+        vmm.handle_device_event(0, 1, 0);
+        assert_eq!(METRICS.vmm.device_failures.count(), failures);
+        assert!(vmm.shared_info.read().unwrap().failed_devices.is_empty());
+
+        vmm.handle_device_event(0, 0, 0);
+        assert_eq!(METRICS.vmm.device_failures.count(), failures + 1);
+        assert_eq!(
+            vmm.shared_info.read().unwrap().failed_devices,
+            vec!["foo".to_string()]
+        );
+        assert!(vmm.epoll_context.dispatch_table[base as usize].is_none());
+        assert!(vmm.epoll_context.dispatch_table[base as usize + 1].is_none());
+        assert!(vmm.epoll_context.device_handlers[0].handler.is_none());
+        // The rest of the microVM keeps running.
+        assert_eq!(vmm.instance_state(), InstanceState::Running);
+
+        // The events of the device are not polled anymore.
+        evt.write(1).unwrap();
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 10];
+        let num_events = epoll::wait(vmm.epoll_context.epoll_raw_fd, 0, &mut events[..]).unwrap();
+        assert!(events[..num_events].iter().all(|event| event.data != base));
+
+        // Leftover events of the stopped device are ignored.
+        vmm.handle_device_event(0, 0, 0);
+        assert_eq!(METRICS.vmm.device_failures.count(), failures + 1);
+    }
+
     #[test]
     fn test_insert_block_device() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2224,6 +4427,18 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
         assert!(vmm
@@ -2239,6 +4454,18 @@ mod tests {
             partuuid: None,
             is_read_only: true,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
         assert!(vmm
@@ -2254,45 +4481,457 @@ mod tests {
             partuuid: None,
             is_read_only: true,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
         assert!(vmm.insert_block_device(root_block_device.clone()).is_err());
 
-        // Test inserting a second drive is ok.
-        let f = NamedTempFile::new().unwrap();
-        // Test that creating a new block device returns the correct output.
-        let non_root = BlockDeviceConfig {
-            drive_id: String::from("non_root"),
-            path_on_host: f.path().to_path_buf(),
+        // Test inserting a second drive is ok.
+        let f = NamedTempFile::new().unwrap();
+        // Test that creating a new block device returns the correct output.
+        let non_root = BlockDeviceConfig {
+            drive_id: String::from("non_root"),
+            path_on_host: f.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+        assert!(vmm.insert_block_device(non_root).is_ok());
+
+        // Test that making the second device root fails (it would result in 2 root block
+        // devices.
+        let non_root = BlockDeviceConfig {
+            drive_id: String::from("non_root"),
+            path_on_host: f.path().to_path_buf(),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+        assert!(vmm.insert_block_device(non_root).is_err());
+
+        // Test update after boot.
+        vmm.set_instance_state(InstanceState::Running);
+        let root_block_device = BlockDeviceConfig {
+            drive_id: String::from("root"),
+            path_on_host: f.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: true,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+        assert!(vmm.insert_block_device(root_block_device).is_err())
+    }
+
+    #[test]
+    fn test_vhost_user_drive() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let socket = NamedTempFile::new().unwrap();
+        let block_device = BlockDeviceConfig {
+            drive_id: String::from("vhost_user"),
+            path_on_host: socket.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: Some(2),
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: Some(true),
+        };
+        assert!(vmm.insert_block_device(block_device).is_ok());
+
+        // Before boot, the drive can be pointed at another back-end.
+        let other_socket = NamedTempFile::new().unwrap();
+        assert!(vmm
+            .set_block_device_path(
+                String::from("vhost_user"),
+                other_socket.path().to_str().unwrap().to_string()
+            )
+            .is_ok());
+        assert_eq!(
+            vmm.block_device_configs.config_list[0].path_on_host,
+            other_socket.path()
+        );
+        match vmm.set_block_device_path(String::from("vhost_user"), String::from("/foo/bar")) {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::InvalidBlockDevicePath,
+            )) => (),
+            _ => panic!("The socket of the back-end does not exist."),
+        }
+
+        // The back-end holds the state of the device.
+        #[cfg(target_arch = "x86_64")]
+        {
+            vmm.set_instance_state(InstanceState::Paused);
+            let snapshot_config = SnapshotConfig {
+                snapshot_path: PathBuf::from("/foo/snapshot"),
+                mem_file_path: PathBuf::from("/foo/mem"),
+                encryption_keys: None,
+            };
+            match vmm.create_snapshot(snapshot_config) {
+                Err(VmmActionError::Snapshot(
+                    ErrorKind::User,
+                    SnapshotError::VhostUserNotSupported,
+                )) => (),
+                _ => panic!("Snapshots of vhost-user drives are not supported."),
+            }
+        }
+
+        vmm.set_instance_state(InstanceState::Running);
+        match vmm.set_block_device_path(
+            String::from("vhost_user"),
+            socket.path().to_str().unwrap().to_string(),
+        ) {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::VhostUserUpdateNotAllowed,
+            )) => (),
+            _ => panic!("The back-end cannot be changed after boot."),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_in_memory_overlay_snapshot() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let disk_file = NamedTempFile::new().unwrap();
+        let block_device = BlockDeviceConfig {
+            drive_id: String::from("overlay"),
+            path_on_host: disk_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: Some(OverlayConfig { path_on_host: None }),
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+        assert!(vmm.insert_block_device(block_device).is_ok());
+
+        // The writes of the guest only live in the memory of this process.
+        vmm.set_instance_state(InstanceState::Paused);
+        let snapshot_config = SnapshotConfig {
+            snapshot_path: PathBuf::from("/foo/snapshot"),
+            mem_file_path: PathBuf::from("/foo/mem"),
+            encryption_keys: None,
+        };
+        match vmm.create_snapshot(snapshot_config) {
+            Err(VmmActionError::Snapshot(
+                ErrorKind::User,
+                SnapshotError::InMemoryOverlayNotSupported,
+            )) => (),
+            _ => panic!("Snapshots of in-memory overlays are not supported."),
+        }
+        vmm.set_instance_state(InstanceState::Running);
+        let migration_config = MigrationConfig {
+            socket_path: PathBuf::from("/foo/migration.sock"),
+            encryption_keys: None,
+        };
+        match vmm.send_migration(migration_config.clone()) {
+            Err(VmmActionError::Migration(
+                ErrorKind::User,
+                MigrationError::InMemoryOverlayNotSupported,
+            )) => (),
+            _ => panic!("Migrating in-memory overlays is not supported."),
+        }
+
+        // An overlay file is opened again along with the disk image.
+        let overlay_file = NamedTempFile::new().unwrap();
+        vmm.block_device_configs.config_list[0].overlay = Some(OverlayConfig {
+            path_on_host: Some(overlay_file.path().to_path_buf()),
+        });
+        match vmm.send_migration(migration_config) {
+            Err(VmmActionError::Migration(ErrorKind::User, MigrationError::Socket(_))) => (),
+            _ => panic!("The migration socket does not exist."),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_encryption_keys() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let disk_file = NamedTempFile::new().unwrap();
+        let key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        let block_device = BlockDeviceConfig {
+            drive_id: String::from("encrypted"),
+            path_on_host: disk_file.path().to_path_buf(),
             is_root_device: false,
             partuuid: None,
             is_read_only: false,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: Some(EncryptionConfig {
+                key: String::from(key),
+            }),
+            nbd: None,
+            vhost_user: None,
         };
-        assert!(vmm.insert_block_device(non_root).is_ok());
+        assert!(vmm.insert_block_device(block_device).is_ok());
 
-        // Test that making the second device root fails (it would result in 2 root block
-        // devices.
-        let non_root = BlockDeviceConfig {
-            drive_id: String::from("non_root"),
-            path_on_host: f.path().to_path_buf(),
+        // The keys are neither saved nor sent.
+        let (mut drives, _) = vmm.device_configs();
+        assert_eq!(
+            drives[0].encryption,
+            Some(EncryptionConfig { key: String::new() })
+        );
+        assert_eq!(
+            vmm.block_device_configs.config_list[0].encryption,
+            Some(EncryptionConfig {
+                key: String::from(key),
+            })
+        );
+
+        // A received microVM needs the keys of its drives.
+        let (mut source, mut destination) = UnixStream::pair().unwrap();
+        migration::send_header(
+            &mut source,
+            &MigrationHeader {
+                vm_config: VmConfig::default(),
+                drives: vmm.device_configs().0,
+                network_interfaces: vec![],
+                balloon: None,
+                memory_regions: vec![],
+            },
+        )
+        .unwrap();
+        let mut destination_vmm = create_vmm_object(InstanceState::Uninitialized);
+        match destination_vmm.migrate_from(&mut destination, &[]) {
+            Err(VmmActionError::Migration(
+                ErrorKind::User,
+                MigrationError::MissingEncryptionKey(ref drive_id),
+            )) if drive_id == "encrypted" => (),
+            _ => panic!("The key of the drive was not given."),
+        }
+        assert!(destination_vmm.block_device_configs.config_list.is_empty());
+
+        let mut keys = vec![DriveEncryptionKey {
+            drive_id: String::from("other"),
+            encryption: EncryptionConfig {
+                key: String::from(key),
+            },
+        }];
+        assert_eq!(
+            restore_encryption_keys(&mut drives, &keys),
+            Err(String::from("encrypted"))
+        );
+        keys[0].drive_id = String::from("encrypted");
+        assert!(restore_encryption_keys(&mut drives, &keys).is_ok());
+        assert_eq!(
+            drives[0].encryption,
+            Some(EncryptionConfig {
+                key: String::from(key),
+            })
+        );
+    }
+
+    #[test]
+    fn test_empty_drive() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let root_file = NamedTempFile::new().unwrap();
+        let mut block_device = BlockDeviceConfig {
+            drive_id: String::from("root"),
+            path_on_host: root_file.path().to_path_buf(),
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
-        assert!(vmm.insert_block_device(non_root).is_err());
+        assert!(vmm.insert_block_device(block_device.clone()).is_ok());
+        block_device.is_root_device = false;
+        block_device.path_on_host = PathBuf::new();
+        for drive_id in &["slot0", "slot1"] {
+            block_device.drive_id = drive_id.to_string();
+            assert!(vmm.insert_block_device(block_device.clone()).is_ok());
+        }
+
+        // A disk image can be attached to an empty drive and detached from it before boot, but
+        // the root device needs one.
+        let disk_file = NamedTempFile::new().unwrap();
+        assert!(vmm
+            .set_block_device_path(
+                String::from("slot0"),
+                disk_file.path().to_str().unwrap().to_string()
+            )
+            .is_ok());
+        assert!(!vmm.block_device_configs.config_list[1].is_empty());
+        assert!(vmm
+            .set_block_device_path(String::from("slot0"), String::new())
+            .is_ok());
+        assert!(vmm.block_device_configs.config_list[1].is_empty());
+        match vmm.set_block_device_path(String::from("root"), String::new()) {
+            Err(VmmActionError::DriveConfig(ErrorKind::User, DriveError::EmptyDriveOption)) => (),
+            _ => panic!("The root device cannot be empty."),
+        }
 
-        // Test update after boot.
         vmm.set_instance_state(InstanceState::Running);
-        let root_block_device = BlockDeviceConfig {
-            drive_id: String::from("root"),
-            path_on_host: f.path().to_path_buf(),
-            is_root_device: false,
-            partuuid: None,
-            is_read_only: true,
-            rate_limiter: None,
+        match vmm.set_block_device_path(String::from("root"), String::new()) {
+            Err(VmmActionError::DriveConfig(ErrorKind::User, DriveError::EmptyDriveOption)) => (),
+            _ => panic!("The disk image of the root device cannot be detached."),
+        }
+    }
+
+    #[test]
+    fn test_balloon() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+
+        // Nothing to resize or report before the balloon is configured.
+        match vmm.update_balloon(BalloonUpdateConfig { amount_mib: 16 }) {
+            Err(VmmActionError::Balloon(ErrorKind::User, BalloonError::DeviceNotFound)) => (),
+            _ => panic!("Resizing a missing balloon should fail."),
+        }
+        match vmm.get_balloon_config() {
+            Err(VmmActionError::Balloon(ErrorKind::User, BalloonError::DeviceNotFound)) => (),
+            _ => panic!("A missing balloon has no configuration."),
+        }
+
+        // The balloon cannot be larger than the guest memory.
+        let mut balloon_config = BalloonConfig {
+            amount_mib: 256,
+            deflate_on_oom: true,
+            stats_polling_interval_s: 0,
+            free_page_reporting: false,
         };
-        assert!(vmm.insert_block_device(root_block_device).is_err())
+        match vmm.insert_balloon_device(balloon_config.clone()) {
+            Err(VmmActionError::Balloon(ErrorKind::User, BalloonError::TooManyPagesRequested)) => {}
+            _ => panic!("The balloon should not exceed the guest memory."),
+        }
+        balloon_config.amount_mib = 64;
+        assert!(vmm.insert_balloon_device(balloon_config.clone()).is_ok());
+        match vmm.get_balloon_stats() {
+            Err(VmmActionError::Balloon(ErrorKind::User, BalloonError::StatisticsDisabled)) => (),
+            _ => panic!("The statistics were not enabled."),
+        }
+
+        // Before boot, resizing only changes the configuration.
+        assert!(vmm
+            .update_balloon(BalloonUpdateConfig { amount_mib: 32 })
+            .is_ok());
+        balloon_config.amount_mib = 32;
+        match vmm.get_balloon_config() {
+            Ok(VmmData::BalloonConfig(config)) => assert_eq!(config, balloon_config),
+            _ => panic!("Expected the balloon configuration."),
+        }
+        assert!(vmm
+            .update_balloon(BalloonUpdateConfig { amount_mib: 1024 })
+            .is_err());
+
+        balloon_config.stats_polling_interval_s = 1;
+        assert!(vmm.insert_balloon_device(balloon_config.clone()).is_ok());
+        match vmm.get_balloon_stats() {
+            Ok(VmmData::BalloonStatistics(statistics)) => {
+                assert_eq!(statistics.target_mib, 32);
+                assert_eq!(statistics.target_pages, 32 * 256);
+                assert_eq!(statistics.actual_pages, 0);
+            }
+            _ => panic!("Expected the balloon statistics."),
+        }
+        assert_eq!(vmm.vmm_config().balloon, Some(balloon_config.clone()));
+
+        // A running microVM shares the target size with the live device, which is not activated
+        // here, so there is no guest to notify.
+        let state = Arc::new(Mutex::new(virtio::BalloonState::default()));
+        vmm.balloon_state = Some(state.clone());
+        vmm.set_instance_state(InstanceState::Running);
+        assert!(vmm
+            .update_balloon(BalloonUpdateConfig { amount_mib: 16 })
+            .is_ok());
+        assert_eq!(state.lock().unwrap().target_pages(), 16 * 256);
+        assert_eq!(vmm.balloon_config.as_ref().unwrap().amount_mib, 16);
+
+        // The balloon cannot be configured anew after boot.
+        match vmm.insert_balloon_device(balloon_config) {
+            Err(VmmActionError::Balloon(
+                ErrorKind::User,
+                BalloonError::UpdateNotAllowedPostBoot,
+            )) => (),
+            _ => panic!("Configuring the balloon after boot should fail."),
+        }
     }
 
     #[test]
@@ -2413,7 +5052,11 @@ mod tests {
             arch::get_reserved_mem_addr() as u64,
             (arch::IRQ_BASE, arch::IRQ_MAX),
         );
-        vmm.attach_net_devices(&mut device_manager).unwrap();
+        vmm.attach_net_devices(
+            &mut device_manager,
+            &mut kernel_cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE),
+        )
+        .unwrap();
         vmm.set_instance_state(InstanceState::Running);
 
         // The update should fail before device activation.
@@ -2467,6 +5110,14 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: None,
             cpu_template: None,
+            on_poweroff: None,
+            on_reboot: None,
+            on_panic: None,
+            mem_backing: None,
+            vcpu_affinity: None,
+            vcpu_scheduling: None,
+            vmm_affinity: None,
+            vmm_scheduling: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_ok());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2479,6 +5130,14 @@ mod tests {
             mem_size_mib: Some(256),
             ht_enabled: None,
             cpu_template: None,
+            on_poweroff: None,
+            on_reboot: None,
+            on_panic: None,
+            mem_backing: None,
+            vcpu_affinity: None,
+            vcpu_scheduling: None,
+            vmm_affinity: None,
+            vmm_scheduling: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_ok());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2492,6 +5151,14 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: None,
             cpu_template: None,
+            on_poweroff: None,
+            on_reboot: None,
+            on_panic: None,
+            mem_backing: None,
+            vcpu_affinity: None,
+            vcpu_scheduling: None,
+            vmm_affinity: None,
+            vmm_scheduling: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2503,6 +5170,14 @@ mod tests {
             mem_size_mib: Some(0),
             ht_enabled: Some(false),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            on_poweroff: None,
+            on_reboot: None,
+            on_panic: None,
+            mem_backing: None,
+            vcpu_affinity: None,
+            vcpu_scheduling: None,
+            vmm_affinity: None,
+            vmm_scheduling: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2518,6 +5193,14 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: Some(true),
             cpu_template: None,
+            on_poweroff: None,
+            on_reboot: None,
+            on_panic: None,
+            mem_backing: None,
+            vcpu_affinity: None,
+            vcpu_scheduling: None,
+            vmm_affinity: None,
+            vmm_scheduling: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
         assert_eq!(vmm.vm_config.ht_enabled, Some(false));
@@ -2528,11 +5211,127 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            on_poweroff: None,
+            on_reboot: None,
+            on_panic: None,
+            mem_backing: None,
+            vcpu_affinity: None,
+            vcpu_scheduling: None,
+            vmm_affinity: None,
+            vmm_scheduling: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_ok());
         assert_eq!(vmm.vm_config.vcpu_count, Some(2));
         assert_eq!(vmm.vm_config.ht_enabled, Some(true));
         assert_eq!(vmm.vm_config.cpu_template, Some(CpuFeaturesTemplate::T2));
+        assert_eq!(vmm.vm_config.on_poweroff, Some(LifecycleAction::Exit));
+        assert_eq!(vmm.vm_config.on_reboot, Some(LifecycleAction::Exit));
+        assert_eq!(vmm.vm_config.on_panic, Some(PanicAction::Ignore));
+
+        // Test that the lifecycle actions can be changed on their own.
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            on_poweroff: Some(LifecycleAction::Halt),
+            on_reboot: Some(LifecycleAction::Reboot),
+            on_panic: Some(PanicAction::Pause),
+            mem_backing: None,
+            vcpu_affinity: None,
+            vcpu_scheduling: None,
+            vmm_affinity: None,
+            vmm_scheduling: None,
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.vcpu_count, Some(2));
+        assert_eq!(vmm.vm_config.on_poweroff, Some(LifecycleAction::Halt));
+        assert_eq!(vmm.vm_config.on_reboot, Some(LifecycleAction::Reboot));
+        assert_eq!(vmm.vm_config.on_panic, Some(PanicAction::Pause));
+        assert_eq!(
+            vmm.vm_config.mem_backing,
+            Some(MemoryBackingType::Anonymous)
+        );
+
+        // Test that the memory backing can be changed on its own.
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            on_poweroff: None,
+            on_reboot: None,
+            on_panic: None,
+            mem_backing: Some(MemoryBackingType::Memfd),
+            vcpu_affinity: None,
+            vcpu_scheduling: None,
+            vmm_affinity: None,
+            vmm_scheduling: None,
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.mem_backing, Some(MemoryBackingType::Memfd));
+        assert_eq!(vmm.vm_config.on_reboot, Some(LifecycleAction::Reboot));
+
+        // Test the thread affinity and scheduling.
+        let mut machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            on_poweroff: None,
+            on_reboot: None,
+            on_panic: None,
+            mem_backing: None,
+            vcpu_affinity: Some(vec![vec![0], vec![1, 2]]),
+            vcpu_scheduling: Some(ThreadScheduling {
+                policy: SchedulingPolicy::Fifo,
+                priority: 0,
+            }),
+            vmm_affinity: Some(vec![3]),
+            vmm_scheduling: None,
+        };
+        assert_eq!(
+            vmm.set_vm_configuration(machine_config.clone())
+                .unwrap_err()
+                .to_string(),
+            VmConfigError::InvalidSchedulingPriority.to_string()
+        );
+        machine_config.vcpu_scheduling = Some(ThreadScheduling {
+            policy: SchedulingPolicy::Fifo,
+            priority: 10,
+        });
+        assert!(vmm.set_vm_configuration(machine_config.clone()).is_ok());
+        assert_eq!(vmm.vm_config.vcpu_affinity, Some(vec![vec![0], vec![1, 2]]));
+        assert_eq!(vmm.vm_config.vmm_affinity, Some(vec![3]));
+        assert!(vmm.vm_config.vmm_scheduling.is_none());
+
+        // The vCPU affinity has to follow the vCPU count.
+        machine_config.vcpu_affinity = None;
+        machine_config.vcpu_count = Some(4);
+        assert_eq!(
+            vmm.set_vm_configuration(machine_config.clone())
+                .unwrap_err()
+                .to_string(),
+            VmConfigError::InvalidVcpuAffinityCount.to_string()
+        );
+        assert_eq!(vmm.vm_config.vcpu_count, Some(2));
+        machine_config.vcpu_count = None;
+        machine_config.vmm_affinity = Some(vec![]);
+        assert_eq!(
+            vmm.set_vm_configuration(machine_config.clone())
+                .unwrap_err()
+                .to_string(),
+            VmConfigError::InvalidCpuAffinity.to_string()
+        );
+        machine_config.vmm_affinity = None;
+        machine_config.vcpu_affinity = Some(vec![vec![0], vec![sys_util::MAX_HOST_CPUS]]);
+        assert_eq!(
+            vmm.set_vm_configuration(machine_config)
+                .unwrap_err()
+                .to_string(),
+            VmConfigError::InvalidCpuAffinity.to_string()
+        );
+        assert_eq!(vmm.vm_config.vcpu_affinity, Some(vec![vec![0], vec![1, 2]]));
 
         // 3. Test update vm configuration after boot.
         vmm.set_instance_state(InstanceState::Running);
@@ -2541,10 +5340,83 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            on_poweroff: None,
+            on_reboot: None,
+            on_panic: None,
+            mem_backing: None,
+            vcpu_affinity: None,
+            vcpu_scheduling: None,
+            vmm_affinity: None,
+            vmm_scheduling: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
     }
 
+    #[test]
+    fn test_schedule_threads() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let (stop_sender, stop_receiver) = channel::<()>();
+        let vcpu_thread = thread::spawn(move || stop_receiver.recv());
+        let thread = vcpu_thread.as_pthread_t();
+
+        // Nothing to do without an affinity or scheduling policy.
+        assert!(vmm.schedule_vcpu_thread(0, thread).is_ok());
+        assert!(vmm.schedule_vmm_threads().is_ok());
+
+        // The last CPU of a CPU set is not online on any test host.
+        vmm.vm_config.vcpu_affinity = Some(vec![vec![sys_util::MAX_HOST_CPUS - 1]]);
+        match vmm.schedule_vcpu_thread(0, thread) {
+            Err(StartMicrovmError::VcpuAffinity(0, e)) => {
+                assert_eq!(e.raw_os_error(), Some(libc::EINVAL))
+            }
+            _ => panic!("Unexpected result"),
+        }
+        // vCPUs without a CPU set keep their affinity.
+        assert!(vmm.schedule_vcpu_thread(1, thread).is_ok());
+
+        vmm.vm_config.vcpu_affinity = None;
+        vmm.vm_config.vcpu_scheduling = Some(ThreadScheduling {
+            policy: SchedulingPolicy::Other,
+            priority: 0,
+        });
+        assert!(vmm.schedule_vcpu_thread(0, thread).is_ok());
+        drop(stop_sender);
+        assert!(vcpu_thread.join().is_ok());
+
+        vmm.vm_config.vmm_affinity = Some(vec![sys_util::MAX_HOST_CPUS - 1]);
+        match vmm.schedule_vmm_threads() {
+            Err(StartMicrovmError::VmmAffinity(e)) => {
+                assert_eq!(e.raw_os_error(), Some(libc::EINVAL))
+            }
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_start_vcpus_failure() {
+        let mut vmm = create_unfiltered_vmm_object();
+        vmm.vm_config.vcpu_count = Some(2);
+        vmm.vm_config.mem_size_mib = Some(32);
+        vmm.set_instance_state(InstanceState::Starting);
+        vmm.init_guest_memory().unwrap();
+        vmm.setup_interrupt_controller().unwrap();
+        vmm.attach_virtio_devices().unwrap();
+        vmm.attach_legacy_devices().unwrap();
+        let vcpus = vmm
+            .create_vcpus(GuestAddress(0), TimestampUs::default())
+            .unwrap();
+
+        // The thread of vCPU 1 is spawned before vCPU 0 fails to be pinned, and it returns
+        // without running the guest.
+        vmm.vm_config.vcpu_affinity = Some(vec![vec![sys_util::MAX_HOST_CPUS - 1], vec![0]]);
+        match vmm.start_vcpus(vcpus) {
+            Err(StartMicrovmError::VcpuAffinity(0, _)) => (),
+            _ => panic!("Unexpected result"),
+        }
+        assert!(vmm.vcpus_handles.is_empty());
+    }
+
     #[test]
     fn new_epoll_context_test() {
         assert!(EpollContext::new().is_ok());
@@ -2616,53 +5488,468 @@ mod tests {
     }
 
     #[test]
-    fn test_kvm_context() {
-        use std::os::unix::fs::MetadataExt;
-        use std::os::unix::io::FromRawFd;
+    fn test_drop_device_handlers() {
+        let mut ep = EpollContext::new().unwrap();
+        let epev = ep
+            .add_event(EventFd::new().unwrap(), EpollDispatch::Exit)
+            .unwrap();
+        let device_token = ep.dispatch_table.len() as u64;
+        let (_, handler_idx) = ep.allocate_virtio_block_tokens(1);
+        assert_eq!(handler_idx, 0);
+        // Devices register their events on the main epoll instance when they get activated.
+        let device_evt = EventFd::new().unwrap();
+        epoll::ctl(
+            ep.epoll_raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            device_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, device_token),
+        )
+        .unwrap();
+
+        assert!(ep.drop_device_handlers().is_ok());
+        assert!(ep.device_handlers.is_empty());
+        assert!(ep.dispatch_table.iter().all(|dispatch| match dispatch {
+            Some(EpollDispatch::DeviceHandler(_, _)) => false,
+            _ => true,
+        }));
+
+        // The device events are gone, while the VMM's own events are still there.
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 10];
+        device_evt.write(1).unwrap();
+        assert_eq!(epoll::wait(ep.epoll_raw_fd, 0, &mut events[..]).unwrap(), 0);
+        epev.fd.write(1).unwrap();
+        assert_eq!(epoll::wait(ep.epoll_raw_fd, 0, &mut events[..]).unwrap(), 1);
+        assert_eq!(
+            ep.dispatch_table[events[0].data as usize],
+            Some(EpollDispatch::Exit)
+        );
+
+        // New devices start over from the first handler.
+        let (_, handler_idx) = ep.allocate_virtio_block_tokens(1);
+        assert_eq!(handler_idx, 0);
+    }
+
+    #[test]
+    fn test_allocate_virtio_block_tokens() {
+        let mut ep = EpollContext::new().unwrap();
+        let (_, handler_idx) = ep.allocate_virtio_block_tokens(1);
+        assert_eq!(handler_idx, 0);
+
+        // Every queue of the device gets an event of its own.
+        let dispatch_base = ep.dispatch_table.len();
+        let (_, handler_idx) = ep.allocate_virtio_block_tokens(4);
+        assert_eq!(handler_idx, 1);
+        let events_count = virtio::block::block_events_count(4);
+        assert_eq!(events_count, virtio::block::block_events_count(1) + 3);
+        assert_eq!(ep.dispatch_table.len(), dispatch_base + events_count);
+        for (event, dispatch) in ep.dispatch_table[dispatch_base..].iter().enumerate() {
+            assert_eq!(
+                *dispatch,
+                Some(EpollDispatch::DeviceHandler(1, event as DeviceEventT))
+            );
+        }
+    }
+
+    #[test]
+    fn test_kvm_context() {
+        use std::os::unix::fs::MetadataExt;
+        use std::os::unix::io::FromRawFd;
+
+        let c = KvmContext::new().unwrap();
+
+        assert!(c.max_memslots >= 32);
+
+        let kvm = Kvm::new().unwrap();
+        let f = unsafe { File::from_raw_fd(kvm.as_raw_fd()) };
+        let m1 = f.metadata().unwrap();
+        let m2 = File::open("/dev/kvm").unwrap().metadata().unwrap();
+
+        assert_eq!(m1.dev(), m2.dev());
+        assert_eq!(m1.ino(), m2.ino());
+    }
+
+    #[test]
+    fn test_check_health() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm.check_health().is_err());
+
+        let dummy_addr = GuestAddress(0x1000);
+        vmm.configure_kernel(KernelConfig {
+            cmdline_addr: dummy_addr,
+            cmdline: kernel_cmdline::Cmdline::new(10),
+            kernel_file: tempfile::tempfile().unwrap(),
+        });
+        assert!(vmm.check_health().is_ok());
+    }
+
+    #[test]
+    fn test_is_instance_initialized() {
+        let vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert_eq!(vmm.is_instance_initialized(), false);
+
+        let vmm = create_vmm_object(InstanceState::Starting);
+        assert_eq!(vmm.is_instance_initialized(), true);
+
+        let vmm = create_vmm_object(InstanceState::Halting);
+        assert_eq!(vmm.is_instance_initialized(), true);
+
+        let vmm = create_vmm_object(InstanceState::Halted);
+        assert_eq!(vmm.is_instance_initialized(), true);
+
+        let vmm = create_vmm_object(InstanceState::Running);
+        assert_eq!(vmm.is_instance_initialized(), true);
+
+        let vmm = create_vmm_object(InstanceState::Paused);
+        assert_eq!(vmm.is_instance_initialized(), true);
+    }
+
+    #[test]
+    fn test_guest_lifecycle() {
+        let mut vmm = create_vmm_object(InstanceState::Running);
+
+        // Without vCPU threads, halting only affects the devices and the instance state.
+        vmm.vm_config.on_poweroff = Some(LifecycleAction::Halt);
+        vmm.handle_guest_exit(VcpuExitReason::Poweroff);
+        assert_eq!(vmm.instance_state(), InstanceState::Halted);
+        assert!(vmm.epoll_context.device_events_paused);
+        // A halted microVM cannot be resumed.
+        assert!(vmm.resume_vm().is_err());
+
+        // A microVM which did not boot from a kernel cannot be rebooted in place.
+        vmm.set_instance_state(InstanceState::Running);
+        match vmm.reboot_vm() {
+            Err(VmmActionError::VmState(ErrorKind::Internal, VmStateError::RebootNotSupported)) => {
+                ()
+            }
+            _ => panic!("Rebooting without a power-on state should fail."),
+        }
+        assert_eq!(vmm.instance_state(), InstanceState::Running);
+    }
+
+    #[test]
+    fn test_guest_panic() {
+        let mut vmm = create_vmm_object(InstanceState::Running);
+
+        // By default, a guest panic is only logged.
+        vmm.handle_guest_panic();
+        assert_eq!(vmm.instance_state(), InstanceState::Running);
+
+        vmm.vm_config.on_panic = Some(PanicAction::Ignore);
+        vmm.handle_guest_panic();
+        assert_eq!(vmm.instance_state(), InstanceState::Running);
+        assert!(!vmm.epoll_context.device_events_paused);
+
+        // Without vCPU threads, pausing only affects the devices and the instance state.
+        vmm.vm_config.on_panic = Some(PanicAction::Pause);
+        vmm.handle_guest_panic();
+        assert_eq!(vmm.instance_state(), InstanceState::Paused);
+        assert!(vmm.epoll_context.device_events_paused);
+        // Another panic leaves the paused microVM as it is.
+        vmm.handle_guest_panic();
+        assert_eq!(vmm.instance_state(), InstanceState::Paused);
+        assert!(vmm.resume_vm().is_ok());
+    }
+
+    #[test]
+    fn test_pause_resume_vm() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        match vmm.pause_vm() {
+            Err(VmmActionError::VmState(ErrorKind::User, VmStateError::MicroVMNotRunning)) => (),
+            _ => panic!("Pausing a microVM that is not running should fail."),
+        }
+        match vmm.resume_vm() {
+            Err(VmmActionError::VmState(ErrorKind::User, VmStateError::MicroVMNotPaused)) => (),
+            _ => panic!("Resuming a microVM that is not paused should fail."),
+        }
+
+        // Without vCPU threads, only the devices and the instance state are affected.
+        vmm.set_instance_state(InstanceState::Running);
+        assert!(vmm.pause_vm().is_ok());
+        assert_eq!(vmm.instance_state(), InstanceState::Paused);
+        assert!(vmm.epoll_context.device_events_paused);
+        assert_eq!(
+            vmm.epoll_context.poll_raw_fd(),
+            vmm.epoll_context.control_epoll_raw_fd
+        );
+        match vmm.pause_vm() {
+            Err(VmmActionError::VmState(ErrorKind::User, VmStateError::MicroVMNotRunning)) => (),
+            _ => panic!("Pausing a paused microVM should fail."),
+        }
+
+        assert!(vmm.resume_vm().is_ok());
+        assert_eq!(vmm.instance_state(), InstanceState::Running);
+        assert!(!vmm.epoll_context.device_events_paused);
+        assert_eq!(
+            vmm.epoll_context.poll_raw_fd(),
+            vmm.epoll_context.epoll_raw_fd
+        );
+        match vmm.resume_vm() {
+            Err(VmmActionError::VmState(ErrorKind::User, VmStateError::MicroVMNotPaused)) => (),
+            _ => panic!("Resuming a running microVM should fail."),
+        }
+    }
+
+    #[test]
+    fn test_pause_drains_block_devices() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let disk_file = NamedTempFile::new().unwrap();
+        let block_device = BlockDeviceConfig {
+            drive_id: String::from("drive"),
+            path_on_host: disk_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+        assert!(vmm.insert_block_device(block_device).is_ok());
+        vmm.set_instance_state(InstanceState::Running);
+        // The handler fails whatever event it gets.
+        let (_, handler_idx) = vmm.epoll_context.allocate_virtio_block_tokens(1);
+        vmm.drive_handler_id_map
+            .insert(String::from("drive"), handler_idx);
+        vmm.epoll_context.device_handlers[handler_idx].handler =
+            Some(Box::new(FailingEpollHandler {
+                evt: EventFd::new().unwrap(),
+            }));
+
+        // Requests to drives without io_uring are never in flight.
+        assert!(vmm.pause_vm().is_ok());
+        assert!(vmm.resume_vm().is_ok());
+
+        vmm.block_device_configs.config_list[0].io_engine = Some(IoEngine::Async);
+        match vmm.pause_vm() {
+            Err(VmmActionError::VmState(
+                ErrorKind::Internal,
+                VmStateError::DrainBlockDevice(ref drive_id),
+            )) if drive_id == "drive" => (),
+            _ => panic!("The drive could not complete its requests in flight."),
+        }
+        // The microVM keeps running.
+        assert_eq!(vmm.instance_state(), InstanceState::Running);
+        assert!(!vmm.epoll_context.device_events_paused);
+    }
+
+    #[test]
+    fn test_snapshot_instance_state() {
+        let snapshot_config = SnapshotConfig {
+            snapshot_path: PathBuf::from("/foo/snapshot"),
+            mem_file_path: PathBuf::from("/foo/mem"),
+            encryption_keys: None,
+        };
 
-        let c = KvmContext::new().unwrap();
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        match vmm.create_snapshot(snapshot_config.clone()) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::MicroVMNotPaused)) => (),
+            _ => panic!("Creating a snapshot of a microVM that is not paused should fail."),
+        }
+        vmm.set_instance_state(InstanceState::Running);
+        match vmm.create_snapshot(snapshot_config.clone()) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::MicroVMNotPaused)) => (),
+            _ => panic!("Creating a snapshot of a running microVM should fail."),
+        }
+        match vmm.load_snapshot(snapshot_config.clone()) {
+            Err(VmmActionError::Snapshot(
+                ErrorKind::User,
+                SnapshotError::MicroVMAlreadyRunning,
+            )) => {}
+            _ => panic!("Loading a snapshot into a running microVM should fail."),
+        }
 
-        assert!(c.max_memslots >= 32);
+        // Loading a snapshot that does not exist leaves the microVM uninitialized.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        match vmm.load_snapshot(snapshot_config) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::SnapshotFile(_))) => (),
+            _ => panic!("Loading a missing snapshot should fail."),
+        }
+        assert_eq!(vmm.instance_state(), InstanceState::Uninitialized);
+    }
 
-        let kvm = Kvm::new().unwrap();
-        let f = unsafe { File::from_raw_fd(kvm.as_raw_fd()) };
-        let m1 = f.metadata().unwrap();
-        let m2 = File::open("/dev/kvm").unwrap().metadata().unwrap();
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_coredump_instance_state() {
+        let mut coredump_config = CoreDumpConfig {
+            dump_path: PathBuf::from("/foo/core"),
+            resume: false,
+        };
 
-        assert_eq!(m1.dev(), m2.dev());
-        assert_eq!(m1.ino(), m2.ino());
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        match vmm.create_core_dump(coredump_config.clone()) {
+            Err(VmmActionError::CoreDump(ErrorKind::User, CoreDumpError::MicroVMNotRunning)) => (),
+            _ => panic!("Writing a core dump of a microVM that is not started should fail."),
+        }
+
+        // A failed dump leaves the microVM paused, unless it is asked to resume.
+        vmm.set_instance_state(InstanceState::Paused);
+        match vmm.create_core_dump(coredump_config.clone()) {
+            Err(VmmActionError::CoreDump(ErrorKind::Internal, CoreDumpError::GuestMemory(_))) => (),
+            _ => panic!("Writing a core dump without guest memory should fail."),
+        }
+        assert_eq!(vmm.instance_state(), InstanceState::Paused);
+        coredump_config.resume = true;
+        match vmm.create_core_dump(coredump_config) {
+            Err(VmmActionError::CoreDump(ErrorKind::Internal, CoreDumpError::GuestMemory(_))) => (),
+            _ => panic!("Writing a core dump without guest memory should fail."),
+        }
+        assert_eq!(vmm.instance_state(), InstanceState::Running);
     }
 
     #[test]
-    fn test_check_health() {
+    fn test_migration_instance_state() {
+        let migration_config = MigrationConfig {
+            socket_path: PathBuf::from("/foo/migration.sock"),
+            encryption_keys: None,
+        };
+
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
-        assert!(vmm.check_health().is_err());
+        match vmm.send_migration(migration_config.clone()) {
+            Err(VmmActionError::Migration(ErrorKind::User, MigrationError::MicroVMNotRunning)) => {}
+            _ => panic!("Migrating a microVM that is not running should fail."),
+        }
+        vmm.set_instance_state(InstanceState::Paused);
+        match vmm.send_migration(migration_config.clone()) {
+            Err(VmmActionError::Migration(ErrorKind::User, MigrationError::MicroVMNotRunning)) => {}
+            _ => panic!("Migrating a paused microVM should fail."),
+        }
+        match vmm.receive_migration(migration_config.clone()) {
+            Err(VmmActionError::Migration(
+                ErrorKind::User,
+                MigrationError::MicroVMAlreadyRunning,
+            )) => {}
+            _ => panic!("Receiving a microVM into a started one should fail."),
+        }
 
-        let dummy_addr = GuestAddress(0x1000);
-        vmm.configure_kernel(KernelConfig {
-            cmdline_addr: dummy_addr,
-            cmdline: kernel_cmdline::Cmdline::new(10),
-            kernel_file: tempfile::tempfile().unwrap(),
-        });
-        assert!(vmm.check_health().is_ok());
+        // Nobody listens on the socket.
+        vmm.set_instance_state(InstanceState::Running);
+        match vmm.send_migration(migration_config) {
+            Err(VmmActionError::Migration(ErrorKind::User, MigrationError::Socket(_))) => (),
+            _ => panic!("Migrating to a missing socket should fail."),
+        }
+        assert_eq!(vmm.instance_state(), InstanceState::Running);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn create_unfiltered_vmm_object() -> Vmm {
+        let shared_info = Arc::new(RwLock::new(InstanceInfo {
+            state: InstanceState::Uninitialized,
+            id: "TEST_ID".to_string(),
+            vmm_version: "1.0".to_string(),
+            failed_devices: Vec::new(),
+        }));
+
+        let (_to_vmm, from_api) = channel();
+        // The vCPUs are started on the test threads, which must not be filtered.
+        Vmm::new(
+            shared_info,
+            EventFd::new().expect("cannot create eventFD"),
+            from_api,
+            seccomp::SECCOMP_LEVEL_NONE,
+        )
+        .expect("Cannot Create VMM")
     }
 
     #[test]
-    fn test_is_instance_initialized() {
-        let vmm = create_vmm_object(InstanceState::Uninitialized);
-        assert_eq!(vmm.is_instance_initialized(), false);
+    #[cfg(target_arch = "x86_64")]
+    fn test_migrate_microvm() {
+        const CODE_ADDR: usize = 0x10_0000;
+        const COUNTER_ADDR: usize = 0x20_0000;
+        // mov rax, COUNTER_ADDR; loop: inc qword [rax]; jmp loop
+        let code: [u8; 12] = [
+            0x48, 0xc7, 0xc0, 0x00, 0x00, 0x20, 0x00, 0x48, 0xff, 0x00, 0xeb, 0xfb,
+        ];
+
+        let (mut source_stream, mut destination_stream) = UnixStream::pair().unwrap();
+        let source = thread::spawn(move || {
+            let mut vmm = create_unfiltered_vmm_object();
+            vmm.vm_config.mem_size_mib = Some(32);
+            vmm.set_instance_state(InstanceState::Starting);
+            vmm.init_guest_memory().unwrap();
+            vmm.guest_memory
+                .as_ref()
+                .unwrap()
+                .write_slice_at_addr(&code, GuestAddress(CODE_ADDR))
+                .unwrap();
+            vmm.setup_interrupt_controller().unwrap();
+            vmm.attach_virtio_devices().unwrap();
+            vmm.attach_legacy_devices().unwrap();
+            vmm.register_events().unwrap();
+            let vcpus = vmm
+                .create_vcpus(GuestAddress(CODE_ADDR), TimestampUs::default())
+                .unwrap();
+            vmm.start_vcpus(vcpus).unwrap();
+            vmm.set_instance_state(InstanceState::Running);
+
+            // Let the guest count for a while.
+            thread::sleep(Duration::from_millis(100));
+            assert!(vmm.migrate_to(&mut source_stream).is_ok());
+
+            // The source stays paused, with the counter as it was sent.
+            assert_eq!(vmm.instance_state(), InstanceState::Paused);
+            vmm.guest_memory
+                .as_ref()
+                .unwrap()
+                .read_obj_from_addr::<u64>(GuestAddress(COUNTER_ADDR))
+                .unwrap()
+        });
 
-        let vmm = create_vmm_object(InstanceState::Starting);
-        assert_eq!(vmm.is_instance_initialized(), true);
+        let mut vmm = create_unfiltered_vmm_object();
+        assert!(vmm.migrate_from(&mut destination_stream, &[]).is_ok());
+        let source_counter = source.join().unwrap();
+        assert!(source_counter > 0);
+
+        assert_eq!(vmm.instance_state(), InstanceState::Running);
+        assert_eq!(vmm.vm_config.mem_size_mib, Some(32));
+        let guest_memory = vmm.guest_memory.clone().unwrap();
+        let mut received_code = [0u8; 12];
+        guest_memory
+            .read_slice_at_addr(&mut received_code, GuestAddress(CODE_ADDR))
+            .unwrap();
+        assert_eq!(received_code, code);
 
-        let vmm = create_vmm_object(InstanceState::Halting);
-        assert_eq!(vmm.is_instance_initialized(), true);
+        // The guest keeps counting from where it stopped on the source.
+        let counter = guest_memory
+            .read_obj_from_addr::<u64>(GuestAddress(COUNTER_ADDR))
+            .unwrap();
+        assert!(counter >= source_counter);
+        thread::sleep(Duration::from_millis(100));
+        assert!(
+            guest_memory
+                .read_obj_from_addr::<u64>(GuestAddress(COUNTER_ADDR))
+                .unwrap()
+                > counter
+        );
+    }
 
-        let vmm = create_vmm_object(InstanceState::Halted);
-        assert_eq!(vmm.is_instance_initialized(), true);
+    #[test]
+    fn test_init_guest_memory_backing() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.mem_backing = Some(MemoryBackingType::Memfd);
+        assert!(vmm.init_guest_memory().is_ok());
+        let guest_mem = vmm.guest_memory.clone().unwrap();
+        assert_eq!(
+            guest_mem.backing(),
+            MemoryBacking::Memfd { hugepages: false }
+        );
+        assert!(guest_mem.region_fd(0).is_some());
 
-        let vmm = create_vmm_object(InstanceState::Running);
-        assert_eq!(vmm.is_instance_initialized(), true);
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.mem_size_mib = Some(129);
+        vmm.vm_config.mem_backing = Some(MemoryBackingType::Hugepages);
+        match vmm.init_guest_memory() {
+            Err(StartMicrovmError::InvalidHugepagesMemorySize) => (),
+            _ => panic!("Expected an invalid hugepages memory size error."),
+        }
     }
 
     #[test]
@@ -2678,6 +5965,18 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
         // Test that creating a new block device returns the correct output.
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
@@ -2692,8 +5991,11 @@ mod tests {
             arch::get_reserved_mem_addr() as u64,
             (arch::IRQ_BASE, arch::IRQ_MAX),
         );
-        assert!(vmm.attach_block_devices(&mut device_manager).is_ok());
-        assert!(vmm.get_kernel_cmdline_str().contains("root=/dev/vda"));
+        let mut cmdline = kernel_cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE);
+        assert!(vmm
+            .attach_block_devices(&mut device_manager, &mut cmdline)
+            .is_ok());
+        assert!(cmdline.as_str().contains("root=/dev/vda"));
 
         // Use Case 2: Root Block Device is specified through PARTUUID.
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2704,6 +6006,18 @@ mod tests {
             partuuid: Some("0eaa91a0-01".to_string()),
             is_read_only: false,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         // Test that creating a new block device returns the correct output.
@@ -2719,10 +6033,11 @@ mod tests {
             arch::get_reserved_mem_addr() as u64,
             (arch::IRQ_BASE, arch::IRQ_MAX),
         );
-        assert!(vmm.attach_block_devices(&mut device_manager).is_ok());
+        let mut cmdline = kernel_cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE);
         assert!(vmm
-            .get_kernel_cmdline_str()
-            .contains("root=PARTUUID=0eaa91a0-01"));
+            .attach_block_devices(&mut device_manager, &mut cmdline)
+            .is_ok());
+        assert!(cmdline.as_str().contains("root=PARTUUID=0eaa91a0-01"));
 
         // Use Case 3: Root Block Device is not added at all.
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2733,6 +6048,18 @@ mod tests {
             partuuid: Some("0eaa91a0-01".to_string()),
             is_read_only: false,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         // Test that creating a new block device returns the correct output.
@@ -2750,10 +6077,13 @@ mod tests {
             arch::get_reserved_mem_addr() as u64,
             (arch::IRQ_BASE, arch::IRQ_MAX),
         );
-        assert!(vmm.attach_block_devices(&mut device_manager).is_ok());
+        let mut cmdline = kernel_cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE);
+        assert!(vmm
+            .attach_block_devices(&mut device_manager, &mut cmdline)
+            .is_ok());
         // Test that kernel commandline does not contain either /dev/vda or PARTUUID.
-        assert!(!vmm.get_kernel_cmdline_str().contains("root=PARTUUID="));
-        assert!(!vmm.get_kernel_cmdline_str().contains("root=/dev/vda"));
+        assert!(!cmdline.as_str().contains("root=PARTUUID="));
+        assert!(!cmdline.as_str().contains("root=/dev/vda"));
 
         // Test that the non root device is attached.
         assert!(device_manager
@@ -2801,10 +6131,15 @@ mod tests {
 
         assert!(vmm.insert_net_device(network_interface).is_ok());
 
-        assert!(vmm.attach_net_devices(&mut device_manager).is_ok());
+        let mut cmdline = kernel_cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE);
+        assert!(vmm
+            .attach_net_devices(&mut device_manager, &mut cmdline)
+            .is_ok());
         // a second call to attach_net_devices should fail because when
         // we are creating the virtio::Net object, we are taking the tap.
-        assert!(vmm.attach_net_devices(&mut device_manager).is_err());
+        assert!(vmm
+            .attach_net_devices(&mut device_manager, &mut cmdline)
+            .is_err());
     }
 
     #[test]
@@ -2846,6 +6181,183 @@ mod tests {
             .is_err());
     }
 
+    #[test]
+    fn test_boot_from_config() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+
+        // The sections are applied in order, so the machine configuration is in place by the
+        // time the invalid boot source is rejected.
+        let vmm_config: VmmConfig = serde_json::from_str(
+            r#"{
+                "boot-source": {"kernel_image_path": "dummy-path"},
+                "machine-config": {"vcpu_count": 2, "mem_size_mib": 256}
+            }"#,
+        )
+        .unwrap();
+        match vmm.boot_from_config(vmm_config) {
+            Err(VmmActionError::BootSource(
+                ErrorKind::User,
+                BootSourceConfigError::InvalidKernelPath,
+            )) => (),
+            _ => panic!("Booting from an invalid kernel path should fail."),
+        }
+        assert_eq!(vmm.vm_config.vcpu_count, Some(2));
+        assert_eq!(vmm.vm_config.mem_size_mib, Some(256));
+        assert_eq!(vmm.instance_state(), InstanceState::Uninitialized);
+
+        let kernel_file = NamedTempFile::new().expect("Failed to create temporary kernel file.");
+        let vmm_config: VmmConfig = serde_json::from_str(&format!(
+            r#"{{
+                "boot-source": {{"kernel_image_path": "{}"}},
+                "drives": [{{
+                    "drive_id": "rootfs",
+                    "path_on_host": "dummy-path",
+                    "is_root_device": true,
+                    "is_read_only": false
+                }}]
+            }}"#,
+            kernel_file.path().to_str().unwrap()
+        ))
+        .unwrap();
+        match vmm.boot_from_config(vmm_config) {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::InvalidBlockDevicePath,
+            )) => (),
+            _ => panic!("Booting with an invalid drive path should fail."),
+        }
+        assert!(vmm.kernel_config.is_some());
+        assert_eq!(vmm.instance_state(), InstanceState::Uninitialized);
+    }
+
+    #[test]
+    fn test_vmm_config() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let vmm_config = vmm.vmm_config();
+        assert!(vmm_config.boot_source.is_none());
+        assert!(vmm_config.block_devices.is_empty());
+        assert!(vmm_config.logger.is_none());
+        assert_eq!(vmm_config.machine_config, Some(VmConfig::default()));
+        assert!(vmm_config.net_devices.is_empty());
+
+        let kernel_file = NamedTempFile::new().expect("Failed to create temporary kernel file.");
+        let kernel_path = String::from(kernel_file.path().to_str().unwrap());
+        assert!(vmm.configure_boot_source(kernel_path.clone(), None).is_ok());
+        let root_file = NamedTempFile::new().unwrap();
+        let root_block_device = BlockDeviceConfig {
+            drive_id: String::from("root"),
+            path_on_host: root_file.path().to_path_buf(),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+        assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
+        let scratch_file = NamedTempFile::new().unwrap();
+        let scratch_block_device = BlockDeviceConfig {
+            drive_id: String::from("scratch"),
+            path_on_host: scratch_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: None,
+            discard: Some(true),
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: Some(CacheType::Unsafe),
+            num_queues: Some(2),
+            queue_size: None,
+            verity: None,
+            encryption: Some(EncryptionConfig {
+                key: String::from(
+                    "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+                ),
+            }),
+            nbd: None,
+            vhost_user: None,
+        };
+        assert!(vmm.insert_block_device(scratch_block_device).is_ok());
+        let network_interface = NetworkInterfaceConfig {
+            iface_id: String::from("netif"),
+            host_dev_name: String::from("hostname"),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            allow_mmds_requests: true,
+            tap: None,
+        };
+        assert!(vmm.insert_net_device(network_interface).is_ok());
+        let rate_limiter = RateLimiterConfig {
+            bandwidth: Some(TokenBucketConfig {
+                size: 1024,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+            ops: None,
+        };
+        assert!(vmm
+            .update_net_device(NetworkInterfaceUpdateConfig {
+                iface_id: String::from("netif"),
+                rx_rate_limiter: Some(rate_limiter),
+                tx_rate_limiter: None,
+            })
+            .is_ok());
+
+        // The default command line is filled in, and the rate limiter update is taken into
+        // account.
+        let vmm_config = vmm.vmm_config();
+        assert_eq!(
+            vmm_config.boot_source,
+            Some(BootSourceConfig {
+                kernel_image_path: kernel_path,
+                boot_args: Some(String::from(DEFAULT_KERNEL_CMDLINE)),
+            })
+        );
+        // The drives are reported with the values they are set up with, and without their
+        // encryption keys.
+        let mut expected_root_block_device = root_block_device.clone();
+        expected_root_block_device.io_engine = Some(IoEngine::Sync);
+        expected_root_block_device.discard = Some(false);
+        expected_root_block_device.write_zeroes = Some(false);
+        expected_root_block_device.cache_type = Some(CacheType::Writeback);
+        expected_root_block_device.num_queues = Some(1);
+        expected_root_block_device.queue_size = Some(virtio::block::QUEUE_SIZE);
+        assert_eq!(vmm_config.block_devices.len(), 2);
+        assert_eq!(vmm_config.block_devices[0], expected_root_block_device);
+        let scratch = &vmm_config.block_devices[1];
+        assert_eq!(scratch.io_engine, Some(IoEngine::Sync));
+        assert_eq!(scratch.discard, Some(true));
+        assert_eq!(scratch.write_zeroes, Some(false));
+        assert_eq!(scratch.cache_type, Some(CacheType::Unsafe));
+        assert_eq!(scratch.num_queues, Some(2));
+        assert_eq!(scratch.queue_size, Some(virtio::block::QUEUE_SIZE));
+        let encryption = scratch.encryption.as_ref().unwrap();
+        assert_eq!(encryption.key, "<redacted>");
+        // The configuration cannot be loaded without giving the key again.
+        assert_eq!(encryption.decode(), Err(DriveError::InvalidEncryptionKey));
+        assert_eq!(vmm_config.net_devices.len(), 1);
+        assert_eq!(vmm_config.net_devices[0].iface_id, "netif");
+        assert!(vmm_config.net_devices[0].allow_mmds_requests);
+        assert_eq!(
+            vmm_config.net_devices[0].rx_rate_limiter,
+            Some(rate_limiter)
+        );
+    }
+
     #[test]
     fn test_rescan() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2862,6 +6374,18 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
         let non_root_block_device = BlockDeviceConfig {
             drive_id: scratch_id.clone(),
@@ -2870,6 +6394,18 @@ mod tests {
             partuuid: None,
             is_read_only: true,
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
@@ -2919,6 +6455,18 @@ mod tests {
             )) => (),
             _ => assert!(false),
         }
+        vmm.update_block_device_path(&scratch_id, prev_path.clone());
+
+        // Test rescan of a drive served by NBD, which does not connect to the server again.
+        let scratch_index = vmm
+            .block_device_configs
+            .get_index_of_drive_id(&scratch_id)
+            .unwrap();
+        vmm.update_block_device_path(&scratch_id, PathBuf::from("/foo/nbd.sock"));
+        vmm.block_device_configs.config_list[scratch_index].nbd =
+            Some(NbdConfig { export_name: None });
+        assert!(vmm.rescan_block_device(&scratch_id).is_ok());
+        vmm.block_device_configs.config_list[scratch_index].nbd = None;
         vmm.update_block_device_path(&scratch_id, prev_path);
 
         // Test rescan_block_device with invalid ID.
@@ -3250,6 +6798,43 @@ mod tests {
             error_kind(DriveError::RootBlockDeviceAlreadyAdded),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(DriveError::InvalidDiskImage(String::new())),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(DriveError::AsyncIoEngineImageFormat),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(DriveError::ReadOnlyOverlay), ErrorKind::User);
+        assert_eq!(
+            error_kind(DriveError::BaseImageUpdateNotAllowed),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(DriveError::DirectIoImageFormat), ErrorKind::User);
+        assert_eq!(error_kind(DriveError::InvalidQueueCount), ErrorKind::User);
+        assert_eq!(error_kind(DriveError::InvalidQueueSize), ErrorKind::User);
+        assert_eq!(error_kind(DriveError::VerityNotReadOnly), ErrorKind::User);
+        assert_eq!(error_kind(DriveError::InvalidVerityHash), ErrorKind::User);
+        assert_eq!(
+            error_kind(DriveError::InvalidEncryptionKey),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(DriveError::EncryptedImageFormat),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(DriveError::NbdImageFormat), ErrorKind::User);
+        assert_eq!(
+            error_kind(DriveError::ConnectNbdServer(String::from("foo"))),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(DriveError::VhostUserOption), ErrorKind::User);
+        assert_eq!(
+            error_kind(DriveError::VhostUserUpdateNotAllowed),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(DriveError::EmptyDriveOption), ErrorKind::User);
 
         // Test `VmConfigError` conversion
         assert_eq!(error_kind(VmConfigError::InvalidVcpuCount), ErrorKind::User);
@@ -3261,6 +6846,18 @@ mod tests {
             error_kind(VmConfigError::UpdateNotAllowedPostBoot),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(VmConfigError::InvalidCpuAffinity),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(VmConfigError::InvalidVcpuAffinityCount),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(VmConfigError::InvalidSchedulingPriority),
+            ErrorKind::User
+        );
 
         // Test `NetworkInterfaceError` conversion
         assert_eq!(
@@ -3319,38 +6916,98 @@ mod tests {
             ErrorKind::Internal
         );
         assert_eq!(
-            error_kind(NetworkInterfaceError::UpdateNotAllowedPostBoot),
+            error_kind(NetworkInterfaceError::UpdateNotAllowedPostBoot),
+            ErrorKind::User
+        );
+
+        // Test `StartMicrovmError` conversion
+        assert_eq!(
+            error_kind(StartMicrovmError::CloneTap(io::Error::from_raw_os_error(0))),
+            ErrorKind::Internal
+        );
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            error_kind(StartMicrovmError::ConfigureSystem(
+                arch::Error::X86_64Setup(arch::x86_64::Error::ZeroPageSetup)
+            )),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::ConfigureVm(
+                vstate::Error::NotEnoughMemorySlots
+            )),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::ConnectNbdServer(
+                devices::virtio::NbdError::InvalidHandshake
+            )),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::CreateBlockDevice(
+                io::Error::from_raw_os_error(0)
+            )),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::CreateIoUring(
+                io::Error::from_raw_os_error(0)
+            )),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::AsyncIoEngineImageFormat),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::InvalidDiskImage(
+                devices::virtio::QcowError::EncryptedImage
+            )),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::CreateNetDevice(
+                devices::virtio::Error::TapOpen(TapError::CreateTap(io::Error::from_raw_os_error(
+                    0
+                )))
+            )),
             ErrorKind::User
         );
-
-        // Test `StartMicrovmError` conversion
-        #[cfg(target_arch = "x86_64")]
         assert_eq!(
-            error_kind(StartMicrovmError::ConfigureSystem(
-                arch::Error::X86_64Setup(arch::x86_64::Error::ZeroPageSetup)
+            error_kind(StartMicrovmError::CreateOverlay(
+                devices::virtio::CowError::InvalidHeader
             )),
-            ErrorKind::Internal
+            ErrorKind::User
         );
         assert_eq!(
-            error_kind(StartMicrovmError::ConfigureVm(
-                vstate::Error::NotEnoughMemorySlots
+            error_kind(StartMicrovmError::CreateVerity(
+                devices::virtio::VerityError::RootHashMismatch
             )),
-            ErrorKind::Internal
+            ErrorKind::User
         );
         assert_eq!(
-            error_kind(StartMicrovmError::CreateBlockDevice(
-                io::Error::from_raw_os_error(0)
+            error_kind(StartMicrovmError::CreateVhostUserBlock(
+                devices::virtio::vhost::Error::VhostUserConfigNotSupported
             )),
             ErrorKind::User
         );
         assert_eq!(
-            error_kind(StartMicrovmError::CreateNetDevice(
-                devices::virtio::Error::TapOpen(TapError::CreateTap(io::Error::from_raw_os_error(
-                    0
-                )))
+            error_kind(StartMicrovmError::CreateEncryption(
+                devices::virtio::CryptError::InvalidKey
             )),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::DirectIoImageFormat),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::CreateBalloonDevice(
+                io::Error::from_raw_os_error(0)
+            )),
+            ErrorKind::Internal
+        );
         assert_eq!(
             error_kind(StartMicrovmError::CreateRateLimiter(
                 io::Error::from_raw_os_error(0)
@@ -3369,12 +7026,37 @@ mod tests {
             ErrorKind::Internal
         );
         assert_eq!(error_kind(StartMicrovmError::EventFd), ErrorKind::Internal);
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!(
+                error_kind(StartMicrovmError::GdbServer(gdb::Error::Bind(
+                    io::Error::from_raw_os_error(libc::EADDRINUSE)
+                ))),
+                ErrorKind::User
+            );
+            assert_eq!(
+                error_kind(StartMicrovmError::GdbServer(gdb::Error::Spawn(
+                    io::Error::from_raw_os_error(libc::EAGAIN)
+                ))),
+                ErrorKind::Internal
+            );
+        }
         assert_eq!(
             error_kind(StartMicrovmError::GuestMemory(
                 memory_model::GuestMemoryError::NoMemoryRegions
             )),
             ErrorKind::Internal
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::HugepagesUnavailable(
+                io::Error::from_raw_os_error(libc::ENOMEM)
+            )),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::InvalidHugepagesMemorySize),
+            ErrorKind::User
+        );
         assert_eq!(
             error_kind(StartMicrovmError::KernelCmdline(String::new())),
             ErrorKind::User
@@ -3415,6 +7097,12 @@ mod tests {
             )),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::RegisterBalloonDevice(
+                device_manager::mmio::Error::IrqsExhausted
+            )),
+            ErrorKind::Internal
+        );
         assert_eq!(
             error_kind(StartMicrovmError::RegisterBlockDevice(
                 device_manager::mmio::Error::IrqsExhausted
@@ -3464,6 +7152,261 @@ mod tests {
             ))),
             ErrorKind::Internal
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::VcpuAffinity(
+                0,
+                io::Error::from_raw_os_error(libc::EINVAL)
+            )),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::VcpuScheduling(
+                0,
+                io::Error::from_raw_os_error(libc::EPERM)
+            )),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::VmmAffinity(
+                io::Error::from_raw_os_error(libc::EINVAL)
+            )),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::VmmScheduling(
+                io::Error::from_raw_os_error(libc::EPERM)
+            )),
+            ErrorKind::User
+        );
+
+        // Test `BalloonError` conversion
+        assert_eq!(error_kind(BalloonError::DeviceNotFound), ErrorKind::User);
+        assert_eq!(
+            error_kind(BalloonError::TooManyPagesRequested),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(BalloonError::StatisticsDisabled),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(BalloonError::UpdateNotAllowedPostBoot),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(BalloonError::ConfigChangeFailed(
+                devices::Error::PayloadExpected
+            )),
+            ErrorKind::Internal
+        );
+
+        // Test `VmStateError` conversion
+        assert_eq!(error_kind(VmStateError::MicroVMNotRunning), ErrorKind::User);
+        assert_eq!(error_kind(VmStateError::MicroVMNotPaused), ErrorKind::User);
+        assert_eq!(
+            error_kind(VmStateError::SignalVcpu(io::Error::from_raw_os_error(0))),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(VmStateError::VcpuPauseTimeout),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(VmStateError::RebootNotSupported),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(VmStateError::DrainBlockDevice(String::from("foo"))),
+            ErrorKind::Internal
+        );
+
+        // Test `CoreDumpError` conversion
+        assert_eq!(
+            error_kind(CoreDumpError::MicroVMNotRunning),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(CoreDumpError::UnsupportedArch), ErrorKind::User);
+        assert_eq!(
+            error_kind(CoreDumpError::DumpFile(io::Error::from_raw_os_error(0))),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(CoreDumpError::GuestMemory(
+                memory_model::GuestMemoryError::MemoryNotInitialized
+            )),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(CoreDumpError::MissingVcpuState(0)),
+            ErrorKind::Internal
+        );
+
+        // Test `SnapshotError` conversion
+        assert_eq!(error_kind(SnapshotError::MicroVMNotPaused), ErrorKind::User);
+        assert_eq!(
+            error_kind(SnapshotError::MicroVMAlreadyRunning),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(SnapshotError::VsockNotSupported),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(SnapshotError::VhostUserNotSupported),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(SnapshotError::InMemoryOverlayNotSupported),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(SnapshotError::UnsupportedArch), ErrorKind::User);
+        assert_eq!(
+            error_kind(SnapshotError::SnapshotFile(io::Error::from_raw_os_error(0))),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(SnapshotError::MemoryFile(io::Error::from_raw_os_error(0))),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(SnapshotError::GuestMemory(
+                memory_model::GuestMemoryError::MemoryNotInitialized
+            )),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(SnapshotError::Serialize(
+                serde_json::from_str::<u32>("").unwrap_err()
+            )),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(SnapshotError::Deserialize(
+                serde_json::from_str::<u32>("").unwrap_err()
+            )),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(SnapshotError::InvalidVersion(0)),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(SnapshotError::InvalidMemoryLayout),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(SnapshotError::InvalidVcpuCount), ErrorKind::User);
+        assert_eq!(
+            error_kind(SnapshotError::MissingEncryptionKey(String::from("foo"))),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(SnapshotError::MissingVcpuState(0)),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(SnapshotError::SaveVmState(
+                vstate::Error::VcpuCountNotInitialized
+            )),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(SnapshotError::RestoreVmState(
+                vstate::Error::VcpuCountNotInitialized
+            )),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(SnapshotError::RestoreVcpuState(
+                vstate::Error::VcpuCountNotInitialized
+            )),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(SnapshotError::RestoreDevices(
+                device_manager::mmio::Error::DeviceStateMismatch
+            )),
+            ErrorKind::User
+        );
+
+        // Test `MigrationError` conversion
+        assert_eq!(
+            error_kind(MigrationError::MicroVMNotRunning),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(MigrationError::MicroVMAlreadyRunning),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(MigrationError::VsockNotSupported),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(MigrationError::VhostUserNotSupported),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(MigrationError::InMemoryOverlayNotSupported),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(MigrationError::UnsupportedArch), ErrorKind::User);
+        assert_eq!(
+            error_kind(MigrationError::Socket(io::Error::from_raw_os_error(0))),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(MigrationError::Send(io::Error::from_raw_os_error(0))),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(MigrationError::Receive(io::Error::from_raw_os_error(0))),
+            ErrorKind::Internal
+        );
+        assert_eq!(error_kind(MigrationError::InvalidStream), ErrorKind::User);
+        assert_eq!(
+            error_kind(MigrationError::InvalidVersion(0)),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(MigrationError::UnexpectedMessage(0)),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(MigrationError::InvalidMemoryLayout),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(MigrationError::MissingEncryptionKey(String::from("foo"))),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(MigrationError::GuestMemory(
+                memory_model::GuestMemoryError::MemoryNotInitialized
+            )),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(MigrationError::Serialize(
+                serde_json::from_str::<u32>("").unwrap_err()
+            )),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(MigrationError::Deserialize(
+                serde_json::from_str::<u32>("").unwrap_err()
+            )),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(MigrationError::DirtyPageTracking(
+                vstate::Error::VcpuCountNotInitialized
+            )),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(MigrationError::DestinationFailed),
+            ErrorKind::Internal
+        );
     }
 
     #[test]
@@ -3601,6 +7544,50 @@ mod tests {
             ),
             "SendCtrlAltDel(User, InternalBufferFull)"
         );
+        assert_eq!(
+            format!(
+                "{:?}",
+                VmmActionError::VmState(ErrorKind::User, VmStateError::MicroVMNotPaused)
+            ),
+            "VmState(User, MicroVMNotPaused)"
+        );
+        assert_eq!(
+            VmmActionError::from(VmStateError::MicroVMNotRunning).to_string(),
+            "The microVM is not running."
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                VmmActionError::Balloon(ErrorKind::User, BalloonError::DeviceNotFound)
+            ),
+            "Balloon(User, DeviceNotFound)"
+        );
+        assert_eq!(
+            VmmActionError::from(BalloonError::DeviceNotFound).to_string(),
+            "No balloon device was configured."
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                VmmActionError::Snapshot(ErrorKind::User, SnapshotError::MicroVMNotPaused)
+            ),
+            "Snapshot(User, MicroVMNotPaused)"
+        );
+        assert_eq!(
+            VmmActionError::from(SnapshotError::InvalidVersion(2)).to_string(),
+            "Unsupported snapshot format version: 2."
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                VmmActionError::Migration(ErrorKind::User, MigrationError::MicroVMNotRunning)
+            ),
+            "Migration(User, MicroVMNotRunning)"
+        );
+        assert_eq!(
+            VmmActionError::from(MigrationError::DestinationFailed).to_string(),
+            "The destination could not resume the microVM."
+        );
         #[cfg(feature = "vsock")]
         assert_eq!(
             format!(
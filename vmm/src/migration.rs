@@ -0,0 +1,405 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{self, Read, Write};
+use std::result;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+
+use memory_model::{GuestAddress, GuestMemory};
+use snapshot::MemoryRegion;
+use vmm_config::balloon::BalloonConfig;
+use vmm_config::drive::BlockDeviceConfig;
+use vmm_config::machine_config::VmConfig;
+use vmm_config::migration::MigrationError;
+use vmm_config::net::NetworkInterfaceConfig;
+
+type Result<T> = result::Result<T, MigrationError>;
+
+/// Marks the start of a migration stream ("FCMI").
+const MIGRATION_MAGIC: u32 = 0x4643_4d49;
+
+/// Version of the migration protocol. It must be bumped whenever the messages, or the state
+/// they carry, change.
+pub const MIGRATION_VERSION: u32 = 2;
+
+/// Granularity of the KVM dirty page log.
+const PAGE_SIZE: usize = 4096;
+
+// Every message starts with its kind and the length of its payload.
+const MSG_HEADER: u32 = 1;
+const MSG_PAGES: u32 = 2;
+const MSG_STATE: u32 = 3;
+
+// The destination answers the state message with one of these.
+const STATUS_OK: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+
+/// Number of passes over the pages dirtied by the running guest before it is paused.
+pub const MAX_PRECOPY_ROUNDS: usize = 8;
+
+/// The guest is paused as soon as a pass finds at most this many dirty pages.
+pub const MAX_DOWNTIME_PAGES: usize = 256;
+
+/// The configuration of the microVM, sent first so that the destination can open the devices
+/// and create the guest memory before the pages start coming in.
+#[derive(Deserialize, Serialize)]
+pub struct MigrationHeader {
+    /// The machine configuration of the microVM.
+    pub vm_config: VmConfig,
+    /// The block devices, in the order they were attached.
+    pub drives: Vec<BlockDeviceConfig>,
+    /// The network interfaces, in the order they were attached.
+    pub network_interfaces: Vec<NetworkInterfaceConfig>,
+    /// The balloon device, attached after all the others.
+    pub balloon: Option<BalloonConfig>,
+    /// The layout of the guest memory.
+    pub memory_regions: Vec<MemoryRegion>,
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn send_message_header<W: Write>(writer: &mut W, kind: u32, len: u64) -> Result<()> {
+    write_u32(writer, kind)
+        .and_then(|_| write_u64(writer, len))
+        .map_err(MigrationError::Send)
+}
+
+fn receive_message_header<R: Read>(reader: &mut R) -> Result<(u32, u64)> {
+    let kind = read_u32(reader).map_err(MigrationError::Receive)?;
+    let len = read_u64(reader).map_err(MigrationError::Receive)?;
+    Ok((kind, len))
+}
+
+fn send_json<W: Write, T: Serialize>(writer: &mut W, kind: u32, value: &T) -> Result<()> {
+    let payload = serde_json::to_vec(value).map_err(MigrationError::Serialize)?;
+    send_message_header(writer, kind, payload.len() as u64)?;
+    writer
+        .write_all(&payload)
+        .and_then(|_| writer.flush())
+        .map_err(MigrationError::Send)
+}
+
+fn receive_json<R: Read, T: DeserializeOwned>(reader: &mut R, len: u64) -> Result<T> {
+    serde_json::from_reader(reader.by_ref().take(len)).map_err(MigrationError::Deserialize)
+}
+
+/// Starts the migration stream.
+pub fn send_header<W: Write>(writer: &mut W, header: &MigrationHeader) -> Result<()> {
+    write_u32(writer, MIGRATION_MAGIC)
+        .and_then(|_| write_u32(writer, MIGRATION_VERSION))
+        .map_err(MigrationError::Send)?;
+    send_json(writer, MSG_HEADER, header)
+}
+
+/// Reads the start of a migration stream, checking that this build understands it.
+pub fn receive_header<R: Read>(reader: &mut R) -> Result<MigrationHeader> {
+    if read_u32(reader).map_err(MigrationError::Receive)? != MIGRATION_MAGIC {
+        return Err(MigrationError::InvalidStream);
+    }
+    let version = read_u32(reader).map_err(MigrationError::Receive)?;
+    if version != MIGRATION_VERSION {
+        return Err(MigrationError::InvalidVersion(version));
+    }
+    match receive_message_header(reader)? {
+        (MSG_HEADER, len) => receive_json(reader, len),
+        (kind, _) => Err(MigrationError::UnexpectedMessage(kind)),
+    }
+}
+
+fn send_pages<W: Write>(
+    writer: &mut W,
+    guest_memory: &GuestMemory,
+    guest_addr: GuestAddress,
+    len: usize,
+) -> Result<()> {
+    send_message_header(writer, MSG_PAGES, (len + 8) as u64)?;
+    write_u64(writer, guest_addr.offset() as u64).map_err(MigrationError::Send)?;
+    guest_memory
+        .write_from_memory(guest_addr, writer, len)
+        .map_err(MigrationError::GuestMemory)
+}
+
+/// Sends the whole guest memory.
+pub fn send_memory<W: Write>(writer: &mut W, guest_memory: &GuestMemory) -> Result<()> {
+    guest_memory.with_regions_mut(|_, guest_addr, size, _| {
+        send_pages(writer, guest_memory, guest_addr, size)
+    })
+}
+
+fn is_page_dirty(bitmap: &[u64], page: usize) -> bool {
+    bitmap
+        .get(page / 64)
+        .map_or(false, |bits| bits & (1 << (page % 64)) != 0)
+}
+
+/// Sends the guest pages marked in `dirty_bitmaps`, which holds one bitmap per memory region.
+/// Contiguous dirty pages go out in a single message. Returns the number of pages sent.
+pub fn send_dirty_memory<W: Write>(
+    writer: &mut W,
+    guest_memory: &GuestMemory,
+    dirty_bitmaps: &[Vec<u64>],
+) -> Result<usize> {
+    let mut pages_sent = 0;
+    guest_memory.with_regions_mut(|index, guest_addr, size, _| {
+        let bitmap = dirty_bitmaps.get(index).map_or(&[][..], Vec::as_slice);
+        let num_pages = size / PAGE_SIZE;
+        let mut page = 0;
+        while page < num_pages {
+            if !is_page_dirty(bitmap, page) {
+                page += 1;
+                continue;
+            }
+            let first_page = page;
+            while page < num_pages && is_page_dirty(bitmap, page) {
+                page += 1;
+            }
+            send_pages(
+                writer,
+                guest_memory,
+                GuestAddress(guest_addr.offset() + first_page * PAGE_SIZE),
+                (page - first_page) * PAGE_SIZE,
+            )?;
+            pages_sent += page - first_page;
+        }
+        Ok(())
+    })?;
+    Ok(pages_sent)
+}
+
+/// Ends the memory transfer with the state of the paused microVM.
+pub fn send_state<W: Write, T: Serialize>(writer: &mut W, state: &T) -> Result<()> {
+    send_json(writer, MSG_STATE, state)
+}
+
+/// Copies the incoming guest pages into `guest_memory` until the state of the microVM arrives.
+pub fn receive_memory<R: Read, T: DeserializeOwned>(
+    reader: &mut R,
+    guest_memory: &GuestMemory,
+) -> Result<T> {
+    loop {
+        match receive_message_header(reader)? {
+            (MSG_PAGES, len) if len >= 8 => {
+                let guest_addr = read_u64(reader).map_err(MigrationError::Receive)?;
+                guest_memory
+                    .read_to_memory(
+                        GuestAddress(guest_addr as usize),
+                        reader,
+                        (len - 8) as usize,
+                    )
+                    .map_err(MigrationError::GuestMemory)?;
+            }
+            (MSG_STATE, len) => return receive_json(reader, len),
+            (kind, _) => return Err(MigrationError::UnexpectedMessage(kind)),
+        }
+    }
+}
+
+/// Tells the source whether the microVM was resumed on the destination.
+pub fn send_status<W: Write>(writer: &mut W, resumed: bool) -> Result<()> {
+    let status = if resumed { STATUS_OK } else { STATUS_FAILED };
+    writer
+        .write_all(&[status])
+        .and_then(|_| writer.flush())
+        .map_err(MigrationError::Send)
+}
+
+/// Waits for the destination to report whether it resumed the microVM.
+pub fn receive_status<R: Read>(reader: &mut R) -> Result<()> {
+    let mut status = [0u8];
+    reader
+        .read_exact(&mut status)
+        .map_err(MigrationError::Receive)?;
+    match status[0] {
+        STATUS_OK => Ok(()),
+        _ => Err(MigrationError::DestinationFailed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use memory_model::MemoryBacking;
+    use snapshot::memory_layout;
+
+    fn fill_page(guest_memory: &GuestMemory, guest_addr: usize, value: u8) {
+        guest_memory
+            .write_slice_at_addr(&[value; PAGE_SIZE], GuestAddress(guest_addr))
+            .unwrap();
+    }
+
+    fn page_value(guest_memory: &GuestMemory, guest_addr: usize) -> u8 {
+        let mut page = [0u8; PAGE_SIZE];
+        guest_memory
+            .read_slice_at_addr(&mut page, GuestAddress(guest_addr))
+            .unwrap();
+        assert!(page.iter().all(|byte| *byte == page[0]));
+        page[0]
+    }
+
+    #[test]
+    fn test_header() {
+        let guest_memory = GuestMemory::new(
+            &[(GuestAddress(0), 0x4000), (GuestAddress(0x10000), 0x4000)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
+        let (mut source, mut destination) = UnixStream::pair().unwrap();
+
+        let header = MigrationHeader {
+            vm_config: VmConfig::default(),
+            drives: vec![],
+            network_interfaces: vec![],
+            balloon: None,
+            memory_regions: memory_layout(&guest_memory),
+        };
+        send_header(&mut source, &header).unwrap();
+        let received = receive_header(&mut destination).unwrap();
+        assert_eq!(received.vm_config, header.vm_config);
+        assert_eq!(received.memory_regions, header.memory_regions);
+
+        // Garbage is not a migration stream.
+        source.write_all(&[0u8; 4]).unwrap();
+        match receive_header(&mut destination) {
+            Err(MigrationError::InvalidStream) => (),
+            _ => panic!("Expected InvalidStream"),
+        }
+
+        // Neither is a stream from a newer build.
+        write_u32(&mut source, MIGRATION_MAGIC).unwrap();
+        write_u32(&mut source, MIGRATION_VERSION + 1).unwrap();
+        match receive_header(&mut destination) {
+            Err(MigrationError::InvalidVersion(version)) => {
+                assert_eq!(version, MIGRATION_VERSION + 1)
+            }
+            _ => panic!("Expected InvalidVersion"),
+        }
+
+        // The stream must start with the header.
+        write_u32(&mut source, MIGRATION_MAGIC).unwrap();
+        write_u32(&mut source, MIGRATION_VERSION).unwrap();
+        send_state(&mut source, &0u32).unwrap();
+        match receive_header(&mut destination) {
+            Err(MigrationError::UnexpectedMessage(kind)) => assert_eq!(kind, MSG_STATE),
+            _ => panic!("Expected UnexpectedMessage"),
+        }
+    }
+
+    #[test]
+    fn test_send_receive_memory() {
+        let regions = [(GuestAddress(0), 0x4000), (GuestAddress(0x10000), 0x8000)];
+        let source_memory = GuestMemory::new(&regions, MemoryBacking::Anonymous).unwrap();
+        let destination_memory = GuestMemory::new(&regions, MemoryBacking::Anonymous).unwrap();
+        for page in 0..4 {
+            fill_page(&source_memory, page * PAGE_SIZE, 1);
+        }
+        for page in 0..8 {
+            fill_page(&source_memory, 0x10000 + page * PAGE_SIZE, 1);
+        }
+
+        let (mut source, mut destination) = UnixStream::pair().unwrap();
+        let sender_memory = source_memory.clone();
+        let sender = thread::spawn(move || {
+            send_memory(&mut source, &sender_memory).unwrap();
+
+            // Dirty a few pages, but only report some of them.
+            fill_page(&sender_memory, 0x1000, 2);
+            fill_page(&sender_memory, 0x3000, 2);
+            fill_page(&sender_memory, 0x11000, 2);
+            fill_page(&sender_memory, 0x12000, 2);
+            fill_page(&sender_memory, 0x15000, 2);
+            let dirty_bitmaps = vec![vec![0b1010], vec![0b0110]];
+            assert_eq!(
+                send_dirty_memory(&mut source, &sender_memory, &dirty_bitmaps).unwrap(),
+                4
+            );
+            // No bitmaps, no pages.
+            assert_eq!(
+                send_dirty_memory(&mut source, &sender_memory, &[]).unwrap(),
+                0
+            );
+
+            send_state(&mut source, &String::from("state")).unwrap();
+            let status = receive_status(&mut source);
+            (source, status)
+        });
+
+        let state: String = receive_memory(&mut destination, &destination_memory).unwrap();
+        assert_eq!(state, "state");
+        send_status(&mut destination, true).unwrap();
+        let (mut source, status) = sender.join().unwrap();
+        assert!(status.is_ok());
+
+        assert_eq!(page_value(&destination_memory, 0), 1);
+        assert_eq!(page_value(&destination_memory, 0x1000), 2);
+        assert_eq!(page_value(&destination_memory, 0x2000), 1);
+        assert_eq!(page_value(&destination_memory, 0x3000), 2);
+        assert_eq!(page_value(&destination_memory, 0x10000), 1);
+        assert_eq!(page_value(&destination_memory, 0x11000), 2);
+        assert_eq!(page_value(&destination_memory, 0x12000), 2);
+        // This page was dirtied but not reported.
+        assert_eq!(page_value(&destination_memory, 0x15000), 1);
+
+        // A failed resume is reported to the source.
+        send_status(&mut destination, false).unwrap();
+        match receive_status(&mut source) {
+            Err(MigrationError::DestinationFailed) => (),
+            _ => panic!("Expected DestinationFailed"),
+        }
+    }
+
+    #[test]
+    fn test_receive_invalid_pages() {
+        let guest_memory =
+            GuestMemory::new(&[(GuestAddress(0), 0x4000)], MemoryBacking::Anonymous).unwrap();
+        let (mut source, mut destination) = UnixStream::pair().unwrap();
+
+        // Pages outside of the guest memory are rejected.
+        send_message_header(&mut source, MSG_PAGES, 8 + 0x10).unwrap();
+        write_u64(&mut source, 0x10_0000).unwrap();
+        source.write_all(&[0u8; 0x10]).unwrap();
+        match receive_memory::<_, String>(&mut destination, &guest_memory) {
+            Err(MigrationError::GuestMemory(_)) => (),
+            _ => panic!("Expected GuestMemory"),
+        }
+
+        // A second header is not expected.
+        let (mut source, mut destination) = UnixStream::pair().unwrap();
+        send_json(&mut source, MSG_HEADER, &0u32).unwrap();
+        match receive_memory::<_, String>(&mut destination, &guest_memory) {
+            Err(MigrationError::UnexpectedMessage(kind)) => assert_eq!(kind, MSG_HEADER),
+            _ => panic!("Expected UnexpectedMessage"),
+        }
+
+        // The source going away is an error.
+        drop(source);
+        match receive_memory::<_, String>(&mut destination, &guest_memory) {
+            Err(MigrationError::Receive(_)) => (),
+            _ => panic!("Expected Receive"),
+        }
+    }
+}
@@ -0,0 +1,337 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::result;
+
+use serde_json;
+
+#[cfg(target_arch = "x86_64")]
+use devices::virtio::MmioDeviceState;
+use memory_model::GuestMemory;
+use vmm_config::balloon::BalloonConfig;
+use vmm_config::drive::BlockDeviceConfig;
+use vmm_config::machine_config::VmConfig;
+use vmm_config::net::NetworkInterfaceConfig;
+use vmm_config::snapshot::SnapshotError;
+#[cfg(target_arch = "x86_64")]
+use vstate::{VcpuKvmState, VmKvmState};
+
+type Result<T> = result::Result<T, SnapshotError>;
+
+/// Version of the snapshot file format. It must be bumped whenever the layout of `Snapshot`,
+/// or of any of the structures it holds, changes.
+pub const SNAPSHOT_VERSION: u32 = 3;
+
+/// Types for which any sequence of `size_of::<Self>()` bytes is a valid value, so they can be
+/// saved and restored as raw bytes.
+pub unsafe trait Pod: Copy {}
+
+#[cfg(target_arch = "x86_64")]
+mod pod_impls {
+    use super::Pod;
+    use kvm_bindings::*;
+
+    unsafe impl Pod for kvm_clock_data {}
+    unsafe impl Pod for kvm_cpuid_entry2 {}
+    unsafe impl Pod for kvm_fpu {}
+    unsafe impl Pod for kvm_irqchip {}
+    unsafe impl Pod for kvm_lapic_state {}
+    unsafe impl Pod for kvm_mp_state {}
+    unsafe impl Pod for kvm_msr_entry {}
+    unsafe impl Pod for kvm_pit_state2 {}
+    unsafe impl Pod for kvm_regs {}
+    unsafe impl Pod for kvm_sregs {}
+    unsafe impl Pod for kvm_vcpu_events {}
+    unsafe impl Pod for kvm_xcrs {}
+    unsafe impl Pod for kvm_xsave {}
+}
+
+fn pod_as_bytes<T: Pod>(values: &[T]) -> &[u8] {
+    // Safe because `T` is plain old data and the slice covers exactly the memory of `values`.
+    unsafe {
+        std::slice::from_raw_parts(values.as_ptr() as *const u8, values.len() * size_of::<T>())
+    }
+}
+
+fn pod_from_bytes<T: Pod>(bytes: &[u8]) -> Option<Vec<T>> {
+    if size_of::<T>() == 0 || bytes.len() % size_of::<T>() != 0 {
+        return None;
+    }
+    let count = bytes.len() / size_of::<T>();
+    let mut values = Vec::with_capacity(count);
+    // Safe because `T` is plain old data, the vector has room for `count` values and we copy
+    // exactly `count * size_of::<T>()` bytes into it.
+    unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), values.as_mut_ptr() as *mut u8, bytes.len());
+        values.set_len(count);
+    }
+    Some(values)
+}
+
+/// Serde helpers for saving a `Pod` value as raw bytes. Use with `#[serde(with = "...")]`.
+pub mod pod {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::{pod_as_bytes, pod_from_bytes, Pod};
+
+    /// Serializes `value` as a byte array.
+    pub fn serialize<T: Pod, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(pod_as_bytes(::std::slice::from_ref(value)))
+    }
+
+    /// Deserializes a value saved by `serialize`.
+    pub fn deserialize<'de, T: Pod, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        let bytes: Vec<u8> = Deserialize::deserialize(deserializer)?;
+        match pod_from_bytes::<T>(&bytes) {
+            Some(ref values) if values.len() == 1 => Ok(values[0]),
+            _ => Err(D::Error::invalid_length(
+                bytes.len(),
+                &"the size of the saved structure",
+            )),
+        }
+    }
+}
+
+/// Serde helpers for saving a vector of `Pod` values as raw bytes.
+pub mod pod_vec {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::{pod_as_bytes, pod_from_bytes, Pod};
+
+    /// Serializes `values` as a byte array.
+    pub fn serialize<T: Pod, S: Serializer>(
+        values: &[T],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(pod_as_bytes(values))
+    }
+
+    /// Deserializes values saved by `serialize`.
+    pub fn deserialize<'de, T: Pod, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<T>, D::Error> {
+        let bytes: Vec<u8> = Deserialize::deserialize(deserializer)?;
+        pod_from_bytes(&bytes).ok_or_else(|| {
+            D::Error::invalid_length(
+                bytes.len(),
+                &"a multiple of the size of the saved structure",
+            )
+        })
+    }
+}
+
+/// A guest memory region, stored in the memory file right after the previous one.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MemoryRegion {
+    /// Guest physical address at which the region starts.
+    pub guest_addr: u64,
+    /// Size of the region in bytes.
+    pub size: usize,
+}
+
+/// The state a paused microVM keeps in KVM and in the device emulation.
+#[cfg(target_arch = "x86_64")]
+#[derive(Deserialize, Serialize)]
+pub struct MicrovmState {
+    /// The state of the in-kernel interrupt controllers, PIT and clock.
+    pub vm_state: VmKvmState,
+    /// The state of each vCPU, ordered by vCPU id.
+    pub vcpu_states: Vec<VcpuKvmState>,
+    /// The state of the MMIO transport of each virtio device, in registration order.
+    pub device_states: Vec<MmioDeviceState>,
+}
+
+/// Everything needed to bring a paused microVM back to life in a new process, except for the
+/// contents of the guest memory, which live in a separate file.
+#[derive(Deserialize, Serialize)]
+pub struct Snapshot {
+    /// Version of the snapshot format.
+    pub version: u32,
+    /// The machine configuration of the microVM.
+    pub vm_config: VmConfig,
+    /// The block devices, in the order they were attached.
+    pub drives: Vec<BlockDeviceConfig>,
+    /// The network interfaces, in the order they were attached.
+    pub network_interfaces: Vec<NetworkInterfaceConfig>,
+    /// The balloon device, attached after all the others.
+    pub balloon: Option<BalloonConfig>,
+    /// The layout of the guest memory file.
+    pub memory_regions: Vec<MemoryRegion>,
+    #[cfg(target_arch = "x86_64")]
+    /// The state of the paused microVM.
+    #[serde(flatten)]
+    pub state: MicrovmState,
+}
+
+impl Snapshot {
+    /// Writes the snapshot to `path`, replacing the file if it exists.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(SnapshotError::SnapshotFile)?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self).map_err(SnapshotError::Serialize)?;
+        writer.flush().map_err(SnapshotError::SnapshotFile)
+    }
+
+    /// Reads a snapshot from `path`, checking that this build understands its format.
+    pub fn load(path: &Path) -> Result<Snapshot> {
+        let file = File::open(path).map_err(SnapshotError::SnapshotFile)?;
+        // Check the version first, so that an old snapshot fails with a meaningful error
+        // rather than with whatever field happens to have changed.
+        let value: serde_json::Value =
+            serde_json::from_reader(BufReader::new(file)).map_err(SnapshotError::Deserialize)?;
+        let version = value
+            .get("version")
+            .and_then(|version| version.as_u64())
+            .unwrap_or(0) as u32;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::InvalidVersion(version));
+        }
+        serde_json::from_value(value).map_err(SnapshotError::Deserialize)
+    }
+}
+
+/// Returns the layout of `guest_memory`, which is also the layout of the memory file.
+pub fn memory_layout(guest_memory: &GuestMemory) -> Vec<MemoryRegion> {
+    let mut regions = Vec::with_capacity(guest_memory.num_regions());
+    let _: Result<()> = guest_memory.with_regions_mut(|_, guest_addr, size, _| {
+        regions.push(MemoryRegion {
+            guest_addr: guest_addr.offset() as u64,
+            size,
+        });
+        Ok(())
+    });
+    regions
+}
+
+/// Writes the contents of `guest_memory` to the file at `path`, one region after the other.
+pub fn save_memory(guest_memory: &GuestMemory, path: &Path) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(SnapshotError::MemoryFile)?;
+    guest_memory.with_regions_mut(|_, guest_addr, size, _| {
+        guest_memory
+            .write_from_memory(guest_addr, &mut file, size)
+            .map_err(SnapshotError::GuestMemory)
+    })?;
+    file.sync_all().map_err(SnapshotError::MemoryFile)
+}
+
+/// Fills `guest_memory` with the contents of the memory file at `path`, which must have been
+/// written by `save_memory` for the same memory layout.
+pub fn load_memory(
+    guest_memory: &GuestMemory,
+    regions: &[MemoryRegion],
+    path: &Path,
+) -> Result<()> {
+    if memory_layout(guest_memory) != regions {
+        return Err(SnapshotError::InvalidMemoryLayout);
+    }
+    let mut file = File::open(path).map_err(SnapshotError::MemoryFile)?;
+    guest_memory.with_regions_mut(|_, guest_addr, size, _| {
+        guest_memory
+            .read_to_memory(guest_addr, &mut file, size)
+            .map_err(SnapshotError::GuestMemory)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::NamedTempFile;
+    use super::*;
+    use memory_model::{GuestAddress, MemoryBacking};
+
+    #[test]
+    fn test_pod_serialization() {
+        #[derive(Debug, Deserialize, PartialEq, Serialize)]
+        struct Wrapper {
+            #[serde(with = "pod")]
+            value: u64,
+            #[serde(with = "pod_vec")]
+            values: Vec<u32>,
+        }
+        unsafe impl Pod for u32 {}
+        unsafe impl Pod for u64 {}
+
+        let wrapper = Wrapper {
+            value: 0xdead_beef_cafe,
+            values: vec![1, 2, 3],
+        };
+        let json = serde_json::to_string(&wrapper).unwrap();
+        assert_eq!(serde_json::from_str::<Wrapper>(&json).unwrap(), wrapper);
+
+        // The byte arrays must match the size of the saved types.
+        assert!(serde_json::from_str::<Wrapper>(r#"{"value":[1,2,3],"values":[]}"#).is_err());
+        assert!(
+            serde_json::from_str::<Wrapper>(r#"{"value":[0,0,0,0,0,0,0,0],"values":[1]}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn test_save_load_memory() {
+        let regions = vec![(GuestAddress(0), 0x1000), (GuestAddress(0x10000), 0x2000)];
+        let guest_memory = GuestMemory::new(&regions, MemoryBacking::Anonymous).unwrap();
+        guest_memory
+            .write_obj_at_addr(0x1234_5678u32, GuestAddress(0x10))
+            .unwrap();
+        guest_memory
+            .write_obj_at_addr(0x9abc_def0u32, GuestAddress(0x11ff0))
+            .unwrap();
+
+        let mem_file = NamedTempFile::new().unwrap();
+        save_memory(&guest_memory, mem_file.path()).unwrap();
+        let layout = memory_layout(&guest_memory);
+        assert_eq!(
+            layout,
+            vec![
+                MemoryRegion {
+                    guest_addr: 0,
+                    size: 0x1000
+                },
+                MemoryRegion {
+                    guest_addr: 0x10000,
+                    size: 0x2000
+                },
+            ]
+        );
+        assert_eq!(mem_file.as_file().metadata().unwrap().len(), 0x3000);
+
+        let restored_memory = GuestMemory::new(&regions, MemoryBacking::Anonymous).unwrap();
+        load_memory(&restored_memory, &layout, mem_file.path()).unwrap();
+        assert_eq!(
+            restored_memory
+                .read_obj_from_addr::<u32>(GuestAddress(0x10))
+                .unwrap(),
+            0x1234_5678
+        );
+        assert_eq!(
+            restored_memory
+                .read_obj_from_addr::<u32>(GuestAddress(0x11ff0))
+                .unwrap(),
+            0x9abc_def0
+        );
+
+        // A different memory layout is rejected.
+        let other_memory =
+            GuestMemory::new(&[(GuestAddress(0), 0x3000)], MemoryBacking::Anonymous).unwrap();
+        match load_memory(&other_memory, &layout, mem_file.path()) {
+            Err(SnapshotError::InvalidMemoryLayout) => (),
+            _ => panic!("Expected InvalidMemoryLayout"),
+        }
+    }
+}
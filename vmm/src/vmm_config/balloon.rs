@@ -0,0 +1,120 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+
+use devices;
+
+/// This struct represents the strongly typed equivalent of the json body
+/// from balloon related requests.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BalloonConfig {
+    /// Amount of guest memory the balloon should hold, in MiB.
+    pub amount_mib: u32,
+    /// Lets the guest take memory back from the balloon when it runs out of memory.
+    #[serde(default)]
+    pub deflate_on_oom: bool,
+    /// Interval, in seconds, at which the guest reports its memory statistics. Zero disables
+    /// the statistics.
+    #[serde(default)]
+    pub stats_polling_interval_s: u16,
+    /// Lets the guest report its free pages, which are then released to the host.
+    #[serde(default)]
+    pub free_page_reporting: bool,
+}
+
+impl BalloonConfig {
+    /// Returns the target size of the balloon, in 4 KiB pages.
+    pub fn num_pages(&self) -> u32 {
+        self.amount_mib * devices::virtio::PAGES_PER_MIB
+    }
+}
+
+/// The body of a PATCH /balloon request, which changes the target size of the balloon.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BalloonUpdateConfig {
+    /// Amount of guest memory the balloon should hold, in MiB.
+    pub amount_mib: u32,
+}
+
+/// Errors associated with the balloon device.
+#[derive(Debug)]
+pub enum BalloonError {
+    /// The balloon device was not configured.
+    DeviceNotFound,
+    /// The balloon cannot hold more memory than the guest has.
+    TooManyPagesRequested,
+    /// The statistics were not enabled when the balloon was configured.
+    StatisticsDisabled,
+    /// The balloon device cannot be configured after boot, only resized.
+    UpdateNotAllowedPostBoot,
+    /// The live device failed to notify the guest of the new target size.
+    ConfigChangeFailed(devices::Error),
+}
+
+impl Display for BalloonError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::BalloonError::*;
+        match *self {
+            DeviceNotFound => write!(f, "No balloon device was configured."),
+            TooManyPagesRequested => write!(
+                f,
+                "The balloon cannot hold more memory than the microVM has."
+            ),
+            StatisticsDisabled => write!(f, "The balloon statistics are not enabled."),
+            UpdateNotAllowedPostBoot => write!(
+                f,
+                "The balloon device cannot be configured after boot. Use PATCH to resize it."
+            ),
+            ConfigChangeFailed(ref e) => {
+                write!(
+                    f,
+                    "Cannot notify the guest of the new balloon size: {:?}",
+                    e
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+
+    #[test]
+    fn test_balloon_config() {
+        let config: BalloonConfig = serde_json::from_str(r#"{"amount_mib": 64}"#).unwrap();
+        assert_eq!(
+            config,
+            BalloonConfig {
+                amount_mib: 64,
+                deflate_on_oom: false,
+                stats_polling_interval_s: 0,
+                free_page_reporting: false,
+            }
+        );
+        assert_eq!(config.num_pages(), 64 * 256);
+
+        assert!(serde_json::from_str::<BalloonConfig>(r#"{"deflate_on_oom": true}"#).is_err());
+        assert!(serde_json::from_str::<BalloonUpdateConfig>(
+            r#"{"amount_mib": 64, "deflate_on_oom": true}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_display_balloon_error() {
+        assert_eq!(
+            BalloonError::StatisticsDisabled.to_string(),
+            "The balloon statistics are not enabled."
+        );
+        assert_eq!(
+            BalloonError::TooManyPagesRequested.to_string(),
+            "The balloon cannot hold more memory than the microVM has."
+        );
+    }
+}
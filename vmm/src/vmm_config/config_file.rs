@@ -0,0 +1,249 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use serde_json::{self, Value};
+
+use super::balloon::BalloonConfig;
+use super::boot_source::BootSourceConfig;
+use super::drive::BlockDeviceConfig;
+use super::logger::LoggerConfig;
+use super::machine_config::VmConfig;
+use super::net::NetworkInterfaceConfig;
+#[cfg(feature = "vsock")]
+use super::vsock::VsockDeviceConfig;
+use mmds::data_store::Mmds;
+
+/// Strongly typed data structure holding the whole configuration of a microVM, as read from a
+/// JSON configuration file or reported by the API. Each section has the same format as the body
+/// of the matching API request.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VmmConfig {
+    /// The balloon device.
+    #[serde(rename = "balloon", skip_serializing_if = "Option::is_none")]
+    pub balloon: Option<BalloonConfig>,
+    /// The kernel and its command line. Mandatory in a configuration file, but unknown for a
+    /// microVM loaded from a snapshot.
+    #[serde(rename = "boot-source", skip_serializing_if = "Option::is_none")]
+    pub boot_source: Option<BootSourceConfig>,
+    /// The block devices.
+    #[serde(rename = "drives", default)]
+    pub block_devices: Vec<BlockDeviceConfig>,
+    /// The logger, which is configured before anything else.
+    #[serde(rename = "logger", skip_serializing_if = "Option::is_none")]
+    pub logger: Option<LoggerConfig>,
+    /// The vCPUs and memory of the microVM.
+    #[serde(rename = "machine-config")]
+    pub machine_config: Option<VmConfig>,
+    /// The initial contents of the microVM metadata service.
+    #[serde(rename = "mmds", skip_serializing_if = "Option::is_none")]
+    pub mmds: Option<Value>,
+    /// The network interfaces.
+    #[serde(rename = "network-interfaces", default)]
+    pub net_devices: Vec<NetworkInterfaceConfig>,
+    /// The vsock devices.
+    #[cfg(feature = "vsock")]
+    #[serde(rename = "vsock", default)]
+    pub vsock_devices: Vec<VsockDeviceConfig>,
+}
+
+impl VmmConfig {
+    /// Reads and validates the configuration file at `path`.
+    pub fn from_file(path: &Path) -> std::result::Result<Self, ConfigFileError> {
+        let file = File::open(path).map_err(ConfigFileError::Read)?;
+        let config: VmmConfig = serde_json::from_reader(file).map_err(ConfigFileError::Parse)?;
+        if config.boot_source.is_none() {
+            return Err(ConfigFileError::MissingBootSource);
+        }
+        if let Some(ref data) = config.mmds {
+            Mmds::check_data_valid(data).map_err(|_| ConfigFileError::InvalidMmdsData)?;
+        }
+        Ok(config)
+    }
+}
+
+/// Errors associated with reading the configuration file.
+#[derive(Debug)]
+pub enum ConfigFileError {
+    /// Cannot read the configuration file.
+    Read(io::Error),
+    /// The configuration file is not valid JSON, or does not match the expected format.
+    Parse(serde_json::Error),
+    /// The configuration file has no boot source.
+    MissingBootSource,
+    /// The MMDS section holds values other than strings, arrays and objects.
+    InvalidMmdsData,
+}
+
+impl Display for ConfigFileError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::ConfigFileError::*;
+        match *self {
+            Read(ref err) => write!(f, "Cannot read the configuration file. {}", err),
+            Parse(ref err) => write!(f, "Cannot parse the configuration file. {}", err),
+            MissingBootSource => write!(f, "The configuration file has no boot-source section."),
+            InvalidMmdsData => write!(
+                f,
+                "The MMDS section can only hold strings, arrays and objects."
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use self::tempfile::NamedTempFile;
+    use super::*;
+
+    fn write_config(contents: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_from_file() {
+        let file = write_config(
+            r#"{
+                "boot-source": {
+                    "kernel_image_path": "/path/to/vmlinux",
+                    "boot_args": "console=ttyS0"
+                },
+                "drives": [{
+                    "drive_id": "rootfs",
+                    "path_on_host": "/path/to/rootfs.ext4",
+                    "is_root_device": true,
+                    "is_read_only": false
+                }],
+                "machine-config": {
+                    "vcpu_count": 2,
+                    "mem_size_mib": 256,
+                    "ht_enabled": false
+                },
+                "network-interfaces": [{
+                    "iface_id": "eth0",
+                    "host_dev_name": "tap0"
+                }],
+                "mmds": {
+                    "latest": {"meta-data": {"ami-id": "ami-12345678"}}
+                },
+                "balloon": {
+                    "amount_mib": 64,
+                    "deflate_on_oom": true,
+                    "free_page_reporting": true
+                }
+            }"#,
+        );
+        let config = VmmConfig::from_file(file.path()).unwrap();
+        let boot_source = config.boot_source.unwrap();
+        assert_eq!(
+            boot_source.kernel_image_path,
+            "/path/to/vmlinux".to_string()
+        );
+        assert_eq!(boot_source.boot_args, Some("console=ttyS0".to_string()));
+        assert_eq!(config.block_devices.len(), 1);
+        assert_eq!(config.block_devices[0].drive_id, "rootfs".to_string());
+        assert_eq!(
+            config.block_devices[0].path_on_host,
+            PathBuf::from("/path/to/rootfs.ext4")
+        );
+        assert!(config.logger.is_none());
+        let machine_config = config.machine_config.unwrap();
+        assert_eq!(machine_config.vcpu_count, Some(2));
+        assert_eq!(machine_config.mem_size_mib, Some(256));
+        assert_eq!(config.net_devices.len(), 1);
+        assert_eq!(config.net_devices[0].host_dev_name, "tap0".to_string());
+        assert!(config.mmds.is_some());
+        let balloon = config.balloon.unwrap();
+        assert_eq!(balloon.amount_mib, 64);
+        assert!(balloon.deflate_on_oom);
+        assert_eq!(balloon.stats_polling_interval_s, 0);
+        assert!(balloon.free_page_reporting);
+
+        // Only the boot source is mandatory.
+        let file = write_config(r#"{"boot-source": {"kernel_image_path": "/vmlinux"}}"#);
+        let config = VmmConfig::from_file(file.path()).unwrap();
+        assert!(config.block_devices.is_empty());
+        assert!(config.machine_config.is_none());
+        assert!(config.net_devices.is_empty());
+        assert!(config.mmds.is_none());
+        assert!(config.balloon.is_none());
+    }
+
+    #[test]
+    fn test_from_file_errors() {
+        match VmmConfig::from_file(Path::new("/invalid/config/path")) {
+            Err(ConfigFileError::Read(_)) => (),
+            _ => panic!("Reading a missing file should fail."),
+        }
+
+        let file = write_config(r#"{"drives": []}"#);
+        match VmmConfig::from_file(file.path()) {
+            Err(ConfigFileError::MissingBootSource) => (),
+            _ => panic!("A configuration without a boot source should be rejected."),
+        }
+
+        let file = write_config(
+            r#"{"boot-source": {"kernel_image_path": "/vmlinux"}, "unknown-section": {}}"#,
+        );
+        match VmmConfig::from_file(file.path()) {
+            Err(ConfigFileError::Parse(_)) => (),
+            _ => panic!("Unknown sections should be rejected."),
+        }
+
+        let file = write_config(
+            r#"{"boot-source": {"kernel_image_path": "/vmlinux"}, "mmds": {"answer": 42}}"#,
+        );
+        match VmmConfig::from_file(file.path()) {
+            Err(ConfigFileError::InvalidMmdsData) => (),
+            _ => panic!("Non-string MMDS values should be rejected."),
+        }
+    }
+
+    #[test]
+    fn test_serialize() {
+        let file = write_config(
+            r#"{
+                "boot-source": {"kernel_image_path": "/vmlinux"},
+                "machine-config": {"vcpu_count": 1, "mem_size_mib": 128}
+            }"#,
+        );
+        let config = VmmConfig::from_file(file.path()).unwrap();
+
+        // The empty optional sections are left out, and the result reads back the same.
+        let json = serde_json::to_value(&config).unwrap();
+        assert!(json.get("logger").is_none());
+        assert!(json.get("mmds").is_none());
+        assert!(json.get("balloon").is_none());
+        assert!(json["drives"].as_array().unwrap().is_empty());
+        let file = write_config(&json.to_string());
+        let read_back = VmmConfig::from_file(file.path()).unwrap();
+        assert_eq!(read_back.boot_source, config.boot_source);
+        assert_eq!(read_back.machine_config, config.machine_config);
+    }
+
+    #[test]
+    fn test_display_config_file_error() {
+        assert_eq!(
+            ConfigFileError::InvalidMmdsData.to_string(),
+            "The MMDS section can only hold strings, arrays and objects."
+        );
+        assert_eq!(
+            ConfigFileError::MissingBootSource.to_string(),
+            "The configuration file has no boot-source section."
+        );
+        assert!(ConfigFileError::Read(io::Error::from_raw_os_error(2))
+            .to_string()
+            .starts_with("Cannot read the configuration file."));
+    }
+}
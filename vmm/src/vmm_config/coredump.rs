@@ -0,0 +1,50 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+use std::io;
+use std::path::PathBuf;
+
+use memory_model::GuestMemoryError;
+
+/// Strongly typed data structure used to write a core dump of the guest.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CoreDumpConfig {
+    /// Host path of the ELF core file.
+    pub dump_path: PathBuf,
+    /// Resume the microVM once the dump is written. Otherwise it stays paused.
+    #[serde(default)]
+    pub resume: bool,
+}
+
+/// Errors associated with writing a core dump of the guest.
+#[derive(Debug)]
+pub enum CoreDumpError {
+    /// A core dump can only be written once the microVM is started.
+    MicroVMNotRunning,
+    /// Core dumps are not supported on this architecture.
+    UnsupportedArch,
+    /// Cannot create or write the core file.
+    DumpFile(io::Error),
+    /// Cannot copy the guest memory to the core file.
+    GuestMemory(GuestMemoryError),
+    /// A vCPU did not save its state when the microVM was paused.
+    MissingVcpuState(u8),
+}
+
+impl Display for CoreDumpError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::CoreDumpError::*;
+        match *self {
+            MicroVMNotRunning => write!(
+                f,
+                "A core dump can only be written while the microVM is running or paused."
+            ),
+            UnsupportedArch => write!(f, "Core dumps are not supported on this architecture."),
+            DumpFile(ref err) => write!(f, "Cannot write the core file. {}", err),
+            GuestMemory(ref err) => write!(f, "Cannot copy the guest memory. {:?}", err),
+            MissingVcpuState(id) => write!(f, "The state of vCPU {} was not saved.", id),
+        }
+    }
+}
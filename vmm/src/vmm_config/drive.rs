@@ -8,6 +8,8 @@ use std::path::PathBuf;
 use std::result;
 
 use super::RateLimiterConfig;
+use devices::virtio::block::{MAX_QUEUES, MAX_QUEUE_SIZE, QUEUE_SIZE};
+use devices::virtio::{CacheMode, ImageFormat};
 
 type Result<T> = result::Result<T, DriveError>;
 
@@ -30,6 +32,40 @@ pub enum DriveError {
     UpdateNotAllowedPostBoot,
     /// A root block device was already added.
     RootBlockDeviceAlreadyAdded,
+    /// The disk image is not valid in its format, or uses unsupported features.
+    InvalidDiskImage(String),
+    /// The `Async` I/O engine cannot use disk images which are not raw.
+    AsyncIoEngineImageFormat,
+    /// A read-only drive cannot have an overlay.
+    ReadOnlyOverlay,
+    /// The base image of a drive with an overlay cannot be changed after boot.
+    BaseImageUpdateNotAllowed,
+    /// The `None` cache type cannot use disk images which are not raw.
+    DirectIoImageFormat,
+    /// The number of queues is 0 or too large.
+    InvalidQueueCount,
+    /// The queue size is not a power of 2, or is too large.
+    InvalidQueueSize,
+    /// A drive verified against a hash tree has to be read-only.
+    VerityNotReadOnly,
+    /// The root hash or the salt of a verified drive are not valid.
+    InvalidVerityHash,
+    /// The encryption key of a drive is not valid.
+    InvalidEncryptionKey,
+    /// An encrypted drive has to use a raw disk image, without an overlay.
+    EncryptedImageFormat,
+    /// A drive served by an NBD server cannot use a qcow2 disk image.
+    NbdImageFormat,
+    /// Cannot connect to the NBD server of the drive.
+    ConnectNbdServer(String),
+    /// A drive served by a vhost-user back-end cannot have options which are up to the
+    /// back-end.
+    VhostUserOption,
+    /// The back-end of a vhost-user drive cannot be changed after boot.
+    VhostUserUpdateNotAllowed,
+    /// A drive without a disk image cannot be the root device, nor have an overlay or a
+    /// vhost-user back-end.
+    EmptyDriveOption,
 }
 
 impl Display for DriveError {
@@ -51,17 +87,243 @@ impl Display for DriveError {
             UpdateNotAllowedPostBoot => {
                 write!(f, "The update operation is not allowed after boot.")
             }
+            InvalidDiskImage(ref err) => write!(f, "Invalid disk image. {}", err),
+            AsyncIoEngineImageFormat => {
+                write!(f, "The Async I/O engine only supports raw disk images.")
+            }
+            ReadOnlyOverlay => write!(f, "A read-only drive cannot have an overlay."),
+            BaseImageUpdateNotAllowed => write!(
+                f,
+                "The base image of a drive with an overlay cannot be changed after boot."
+            ),
+            DirectIoImageFormat => write!(
+                f,
+                "The None cache type only supports raw disk images, without an overlay."
+            ),
+            InvalidQueueCount => write!(
+                f,
+                "The number of queues must be between 1 and {}.",
+                MAX_QUEUES
+            ),
+            InvalidQueueSize => write!(
+                f,
+                "The queue size must be a power of 2, no larger than {}.",
+                MAX_QUEUE_SIZE
+            ),
+            VerityNotReadOnly => write!(
+                f,
+                "A drive verified against a hash tree has to be read-only."
+            ),
+            InvalidVerityHash => write!(
+                f,
+                "The root hash has to be a SHA-256 digest and the salt at most 256 bytes, \
+                 both in hex."
+            ),
+            InvalidEncryptionKey => write!(
+                f,
+                "The encryption key has to be 32 or 64 bytes in hex, with different halves."
+            ),
+            EncryptedImageFormat => write!(
+                f,
+                "An encrypted drive has to use a raw disk image, without an overlay."
+            ),
+            NbdImageFormat => write!(
+                f,
+                "A drive served by an NBD server has to use a raw disk image."
+            ),
+            ConnectNbdServer(ref err) => write!(f, "Cannot connect to the NBD server. {}", err),
+            VhostUserOption => write!(
+                f,
+                "A drive served by a vhost-user back-end can only set the root device, \
+                 read-only and queue options."
+            ),
+            VhostUserUpdateNotAllowed => write!(
+                f,
+                "The back-end of a vhost-user drive cannot be changed after boot."
+            ),
+            EmptyDriveOption => write!(
+                f,
+                "A drive without a disk image cannot be the root device, nor have an overlay \
+                 or a vhost-user back-end."
+            ),
+        }
+    }
+}
+
+/// The engines which can execute the I/O requests of a drive.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum IoEngine {
+    /// Blocking reads and writes on the VMM thread.
+    Sync,
+    /// Asynchronous reads and writes through an io_uring, which needs Linux 5.6 or newer.
+    Async,
+}
+
+impl Default for IoEngine {
+    fn default() -> Self {
+        IoEngine::Sync
+    }
+}
+
+/// The formats the disk image of a drive can be stored in.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ImageFormatType {
+    /// The disk contents as they are.
+    Raw,
+    /// The QEMU copy-on-write format, with its backing files.
+    Qcow2,
+}
+
+impl From<ImageFormatType> for ImageFormat {
+    fn from(format: ImageFormatType) -> Self {
+        match format {
+            ImageFormatType::Raw => ImageFormat::Raw,
+            ImageFormatType::Qcow2 => ImageFormat::Qcow2,
+        }
+    }
+}
+
+/// How the writes of the guest reach the storage of the disk image of a drive.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum CacheType {
+    /// Through the page cache of the host, syncing the disk image when the guest flushes.
+    Writeback,
+    /// Around the page cache of the host, opening the disk image with O_DIRECT. Only for raw
+    /// disk images.
+    None,
+    /// Through the page cache of the host, ignoring the flushes of the guest.
+    Unsafe,
+}
+
+impl Default for CacheType {
+    fn default() -> Self {
+        CacheType::Writeback
+    }
+}
+
+impl From<CacheType> for CacheMode {
+    fn from(cache_type: CacheType) -> Self {
+        match cache_type {
+            CacheType::Writeback => CacheMode::Writeback,
+            CacheType::None => CacheMode::Direct,
+            CacheType::Unsafe => CacheMode::Unsafe,
+        }
+    }
+}
+
+/// The copy-on-write overlay of a drive, which gets the writes of the guest so that the disk
+/// image of the drive is only read from, and can be shared between microVMs.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverlayConfig {
+    /// Path of the overlay file, which is created if it does not exist. If not set, the
+    /// overlay is kept in memory and discarded when the microVM exits.
+    pub path_on_host: Option<PathBuf>,
+}
+
+/// The hash tree a read-only drive is verified against, in the format of dm-verity, with
+/// SHA-256 hashes and 4 KiB blocks.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VerityConfig {
+    /// Path of the hash tree file.
+    pub hash_tree_path: PathBuf,
+    /// The root hash of the tree, in hex.
+    pub root_hash: String,
+    /// The salt of the hashes, in hex. Only needed for hash trees without a superblock.
+    pub salt: Option<String>,
+}
+
+impl VerityConfig {
+    /// Returns the root hash and the salt, decoded from hex.
+    pub fn decode(&self) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+        let root_hash = decode_hex(&self.root_hash).ok_or(DriveError::InvalidVerityHash)?;
+        let salt = match self.salt {
+            Some(ref salt) => Some(decode_hex(salt).ok_or(DriveError::InvalidVerityHash)?),
+            None => None,
+        };
+        // The root hash is a SHA-256 digest, and dm-verity salts are at most 256 bytes.
+        if root_hash.len() != 32 || salt.as_ref().map_or(false, |salt| salt.len() > 256) {
+            return Err(DriveError::InvalidVerityHash);
         }
+        Ok((root_hash, salt))
+    }
+}
+
+/// The key a drive is encrypted at rest with, with AES-XTS. The key is never logged nor
+/// reported back through the API.
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// The key, in hex: 32 bytes for AES-128-XTS, or 64 bytes for AES-256-XTS.
+    pub key: String,
+}
+
+impl EncryptionConfig {
+    /// Returns the key, decoded from hex.
+    pub fn decode(&self) -> Result<Vec<u8>> {
+        let key = decode_hex(&self.key).ok_or(DriveError::InvalidEncryptionKey)?;
+        // The halves of an XTS key have to be different.
+        if (key.len() != 32 && key.len() != 64) || key[..key.len() / 2] == key[key.len() / 2..] {
+            return Err(DriveError::InvalidEncryptionKey);
+        }
+        Ok(key)
+    }
+}
+
+impl std::fmt::Debug for EncryptionConfig {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("EncryptionConfig")
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
+
+/// The encryption key of a drive. The keys are neither saved in snapshots nor sent along with
+/// migrated microVMs, so they are given again to restore their drives.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DriveEncryptionKey {
+    /// The id of the encrypted drive.
+    pub drive_id: String,
+    /// The key of the drive.
+    pub encryption: EncryptionConfig,
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The NBD server a drive is served by, instead of a disk image on the host.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NbdConfig {
+    /// The name of the export of the server. Defaults to the empty name, which selects the
+    /// default export.
+    pub export_name: Option<String>,
+}
+
+impl NbdConfig {
+    /// Returns the name of the export to ask the server for.
+    pub fn export_name(&self) -> &str {
+        self.export_name.as_ref().map_or("", String::as_str)
     }
 }
 
 /// Use this structure to set up the Block Device before booting the kernel.
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct BlockDeviceConfig {
     /// Unique identifier of the drive.
     pub drive_id: String,
-    /// Path of the drive.
+    /// Path of the drive. If empty, the drive has no disk image, and the guest sees it as a
+    /// disk of 0 bytes until one is attached by updating the path after boot.
     pub path_on_host: PathBuf,
     /// If set to true, it makes the current device the root block device.
     /// Setting this flag to true will mount the block device in the
@@ -75,6 +337,43 @@ pub struct BlockDeviceConfig {
     pub is_read_only: bool,
     /// Rate Limiter for I/O operations.
     pub rate_limiter: Option<RateLimiterConfig>,
+    /// The engine which executes the I/O requests of the drive. Defaults to `Sync`.
+    pub io_engine: Option<IoEngine>,
+    /// If set to true, the guest can discard ranges of the drive, which are deallocated
+    /// from the backing file. Ignored for read-only drives.
+    pub discard: Option<bool>,
+    /// If set to true, the guest can zero ranges of the drive without transferring the
+    /// zeroes. Ignored for read-only drives.
+    pub write_zeroes: Option<bool>,
+    /// The format of the disk image. If not set, the format of read-only disk images is
+    /// detected from their contents, and other disk images are raw, since the guest could
+    /// make an image it can write to look like another format.
+    pub image_format: Option<ImageFormatType>,
+    /// If set, the disk image is a read-only base image, and the writes of the guest go to
+    /// this overlay instead.
+    pub overlay: Option<OverlayConfig>,
+    /// How the writes of the guest reach the storage of the disk image. Defaults to
+    /// `Writeback`.
+    pub cache_type: Option<CacheType>,
+    /// The number of virtio queues the guest can submit requests on in parallel, which all
+    /// share the rate limiter. Defaults to 1.
+    pub num_queues: Option<u16>,
+    /// The size of each virtio queue. Defaults to 256.
+    pub queue_size: Option<u16>,
+    /// If set, every block read from the drive is checked against this hash tree, so that
+    /// changes to the disk image on the host are not fed to the guest. Only for read-only
+    /// drives.
+    pub verity: Option<VerityConfig>,
+    /// If set, the sectors of the drive are encrypted before being written to the disk image,
+    /// and decrypted when read back. Only for raw disk images.
+    pub encryption: Option<EncryptionConfig>,
+    /// If set, the drive is served by the NBD server listening on the Unix socket at
+    /// `path_on_host`. Only for raw disk images.
+    pub nbd: Option<NbdConfig>,
+    /// If set to true, the requests of the guest are processed by the vhost-user back-end
+    /// listening on the Unix socket at `path_on_host`, in another process. The guest memory
+    /// has to be backed by memfds.
+    pub vhost_user: Option<bool>,
 }
 
 impl BlockDeviceConfig {
@@ -92,6 +391,122 @@ impl BlockDeviceConfig {
     pub fn path_on_host(&self) -> &PathBuf {
         &self.path_on_host
     }
+
+    /// Checks whether the drive has no disk image.
+    pub fn is_empty(&self) -> bool {
+        self.path_on_host.as_os_str().is_empty()
+    }
+
+    /// Returns the number of virtio queues of the drive.
+    pub fn num_queues(&self) -> u16 {
+        self.num_queues.unwrap_or(1)
+    }
+
+    /// Returns the size of each virtio queue of the drive.
+    pub fn queue_size(&self) -> u16 {
+        self.queue_size.unwrap_or(QUEUE_SIZE)
+    }
+
+    /// Checks whether the drive is served by a vhost-user back-end.
+    pub fn is_vhost_user(&self) -> bool {
+        self.vhost_user.unwrap_or(false)
+    }
+
+    /// Checks whether the overlay of the drive is kept in memory rather than in a file.
+    pub fn has_in_memory_overlay(&self) -> bool {
+        self.overlay
+            .as_ref()
+            .map_or(false, |overlay| overlay.path_on_host.is_none())
+    }
+
+    /// Returns the format to open the disk image with, or `None` to detect it.
+    pub fn disk_image_format(&self) -> Option<ImageFormat> {
+        // The ciphertext of an encrypted disk image could look like any format.
+        if self.encryption.is_some() {
+            return Some(ImageFormat::Raw);
+        }
+        match self.image_format {
+            Some(format) => Some(ImageFormat::from(format)),
+            // A qcow2 header written by the guest could name any host file as the backing
+            // file, to be served to the guest the next time the image is opened.
+            None if self.is_read_only => None,
+            None => Some(ImageFormat::Raw),
+        }
+    }
+
+    fn check_verity(&self) -> Result<()> {
+        if let Some(ref verity) = self.verity {
+            if !self.is_read_only {
+                return Err(DriveError::VerityNotReadOnly);
+            }
+            verity.decode()?;
+        }
+        Ok(())
+    }
+
+    fn check_encryption(&self) -> Result<()> {
+        if let Some(ref encryption) = self.encryption {
+            let raw = self
+                .image_format
+                .map_or(true, |format| format == ImageFormatType::Raw);
+            if !raw || self.overlay.is_some() {
+                return Err(DriveError::EncryptedImageFormat);
+            }
+            encryption.decode()?;
+        }
+        Ok(())
+    }
+
+    fn check_nbd(&self) -> Result<()> {
+        if self.nbd.is_some() && self.image_format == Some(ImageFormatType::Qcow2) {
+            return Err(DriveError::NbdImageFormat);
+        }
+        Ok(())
+    }
+
+    fn check_vhost_user(&self) -> Result<()> {
+        // The back-end opens the disk image, and processes the requests as it sees fit.
+        if self.is_vhost_user()
+            && (self.rate_limiter.is_some()
+                || self.io_engine.is_some()
+                || self.discard.is_some()
+                || self.write_zeroes.is_some()
+                || self.image_format.is_some()
+                || self.overlay.is_some()
+                || self.cache_type.is_some()
+                || self.verity.is_some()
+                || self.encryption.is_some()
+                || self.nbd.is_some())
+        {
+            return Err(DriveError::VhostUserOption);
+        }
+        Ok(())
+    }
+
+    fn check_empty(&self) -> Result<()> {
+        // The guest boots from the root device, and the overlay and the vhost-user back-end
+        // need a disk image when the drive is set up.
+        if self.is_empty()
+            && (self.is_root_device || self.overlay.is_some() || self.is_vhost_user())
+        {
+            return Err(DriveError::EmptyDriveOption);
+        }
+        Ok(())
+    }
+
+    fn check_queues(&self) -> Result<()> {
+        if let Some(num_queues) = self.num_queues {
+            if num_queues == 0 || num_queues > MAX_QUEUES {
+                return Err(DriveError::InvalidQueueCount);
+            }
+        }
+        if let Some(queue_size) = self.queue_size {
+            if !queue_size.is_power_of_two() || queue_size > MAX_QUEUE_SIZE {
+                return Err(DriveError::InvalidQueueSize);
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Wrapper for the collection that holds all the Block Devices Configs
@@ -138,6 +553,10 @@ impl BlockDeviceConfigs {
     }
 
     fn get_index_of_drive_path(&self, drive_path: &PathBuf) -> Option<usize> {
+        // Any number of drives can be empty.
+        if drive_path.as_os_str().is_empty() {
+            return None;
+        }
         self.config_list
             .iter()
             .position(|cfg| cfg.path_on_host.eq(drive_path))
@@ -157,10 +576,20 @@ impl BlockDeviceConfigs {
 
     fn create(&mut self, block_device_config: BlockDeviceConfig) -> Result<()> {
         // check if the path exists
-        if !block_device_config.path_on_host.exists() {
+        if !block_device_config.is_empty() && !block_device_config.path_on_host.exists() {
             return Err(DriveError::InvalidBlockDevicePath);
         }
 
+        if block_device_config.is_read_only && block_device_config.overlay.is_some() {
+            return Err(DriveError::ReadOnlyOverlay);
+        }
+        block_device_config.check_queues()?;
+        block_device_config.check_verity()?;
+        block_device_config.check_encryption()?;
+        block_device_config.check_nbd()?;
+        block_device_config.check_vhost_user()?;
+        block_device_config.check_empty()?;
+
         if self
             .get_index_of_drive_path(&block_device_config.path_on_host)
             .is_some()
@@ -193,10 +622,20 @@ impl BlockDeviceConfigs {
     /// root block devices.
     fn update(&mut self, mut index: usize, new_config: BlockDeviceConfig) -> Result<()> {
         // Check if the path exists
-        if !new_config.path_on_host.exists() {
+        if !new_config.is_empty() && !new_config.path_on_host.exists() {
             return Err(DriveError::InvalidBlockDevicePath);
         }
 
+        if new_config.is_read_only && new_config.overlay.is_some() {
+            return Err(DriveError::ReadOnlyOverlay);
+        }
+        new_config.check_queues()?;
+        new_config.check_verity()?;
+        new_config.check_encryption()?;
+        new_config.check_nbd()?;
+        new_config.check_vhost_user()?;
+        new_config.check_empty()?;
+
         // Check if the root block device is being updated.
         if self.config_list[index].is_root_device {
             self.has_root_block = new_config.is_root_device;
@@ -231,6 +670,8 @@ mod tests {
 
     use self::tempfile::NamedTempFile;
     use super::*;
+    use devices::virtio::{detect_image_format, open_disk_image};
+    use std::io::{Read, Write};
 
     // This implementation is used only in tests.
     // We cannot directly derive clone because RateLimiter does not implement clone.
@@ -243,6 +684,18 @@ mod tests {
                 is_read_only: self.is_read_only,
                 drive_id: self.drive_id.clone(),
                 rate_limiter: None,
+                io_engine: None,
+                discard: None,
+                write_zeroes: None,
+                image_format: self.image_format,
+                overlay: self.overlay.clone(),
+                cache_type: None,
+                num_queues: self.num_queues,
+                queue_size: self.queue_size,
+                verity: self.verity.clone(),
+                encryption: self.encryption.clone(),
+                nbd: self.nbd.clone(),
+                vhost_user: self.vhost_user,
             }
         }
     }
@@ -266,6 +719,18 @@ mod tests {
             is_read_only: false,
             drive_id: dummy_id.clone(),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -298,6 +763,18 @@ mod tests {
             is_read_only: true,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -323,6 +800,18 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -334,6 +823,18 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -358,6 +859,18 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -369,6 +882,18 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         let dummy_file_3 = NamedTempFile::new().unwrap();
@@ -380,6 +905,18 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("3"),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -415,6 +952,18 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -426,6 +975,18 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         let dummy_file_3 = NamedTempFile::new().unwrap();
@@ -437,6 +998,18 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("3"),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -473,6 +1046,18 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -484,6 +1069,18 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -551,6 +1148,18 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
         let root_block_device_new = BlockDeviceConfig {
             path_on_host: dummy_path_2,
@@ -559,6 +1168,18 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
         };
         let index1 = block_devices_configs
             .get_index_of_drive_id(&root_block_device_old.drive_id)
@@ -574,4 +1195,440 @@ mod tests {
             .is_ok());
         assert!(block_devices_configs.has_partuuid_root);
     }
+
+    #[test]
+    fn test_image_format() {
+        // The guest wrote a qcow2 header to its raw disk, naming a host file as the backing file.
+        let mut disk_file = NamedTempFile::new().unwrap();
+        let mut header = vec![0u8; 0x200];
+        header[..4].copy_from_slice(b"QFI\xfb");
+        header[4..8].copy_from_slice(&3u32.to_be_bytes());
+        header[8..16].copy_from_slice(&0x100u64.to_be_bytes());
+        header[16..20].copy_from_slice(&11u32.to_be_bytes());
+        header[0x100..0x10b].copy_from_slice(b"/etc/passwd");
+        disk_file.write_all(&header).unwrap();
+
+        let mut block_device = BlockDeviceConfig {
+            path_on_host: disk_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+
+        // A writable disk image is still read as raw.
+        assert_eq!(block_device.disk_image_format(), Some(ImageFormat::Raw));
+        let mut disk_image = open_disk_image(
+            disk_file.reopen().unwrap(),
+            disk_file.path(),
+            block_device.disk_image_format(),
+        )
+        .unwrap();
+        assert!(disk_image.is_raw());
+        let mut contents = vec![0u8; header.len()];
+        disk_image.read_exact(&mut contents).unwrap();
+        assert_eq!(contents, header);
+
+        // The format of read-only disk images is detected, unless it is set.
+        block_device.is_read_only = true;
+        assert_eq!(block_device.disk_image_format(), None);
+        assert_eq!(
+            detect_image_format(&mut disk_file.reopen().unwrap()).unwrap(),
+            ImageFormat::Qcow2
+        );
+        block_device.image_format = Some(ImageFormatType::Raw);
+        assert_eq!(block_device.disk_image_format(), Some(ImageFormat::Raw));
+        block_device.is_read_only = false;
+        block_device.image_format = Some(ImageFormatType::Qcow2);
+        assert_eq!(block_device.disk_image_format(), Some(ImageFormat::Qcow2));
+    }
+
+    #[test]
+    fn test_overlay() {
+        let dummy_file = NamedTempFile::new().unwrap();
+        let mut block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: true,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: Some(OverlayConfig { path_on_host: None }),
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+
+        // Read-only drives have nothing to write to an overlay.
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::ReadOnlyOverlay)
+        );
+        block_device.is_read_only = false;
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+        assert_eq!(
+            block_devices_configs.config_list[0].overlay,
+            Some(OverlayConfig { path_on_host: None })
+        );
+        assert!(block_devices_configs.config_list[0].has_in_memory_overlay());
+        block_device.overlay = Some(OverlayConfig {
+            path_on_host: Some(PathBuf::from("/foo/overlay")),
+        });
+        assert!(!block_device.has_in_memory_overlay());
+        block_device.overlay = None;
+        assert!(!block_device.has_in_memory_overlay());
+        block_device.overlay = Some(OverlayConfig { path_on_host: None });
+
+        block_device.is_read_only = true;
+        assert_eq!(
+            block_devices_configs.insert(block_device),
+            Err(DriveError::ReadOnlyOverlay)
+        );
+        assert!(!block_devices_configs.config_list[0].is_read_only);
+    }
+
+    #[test]
+    fn test_queues() {
+        let dummy_file = NamedTempFile::new().unwrap();
+        let mut block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: Some(0),
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::InvalidQueueCount)
+        );
+        block_device.num_queues = Some(MAX_QUEUES + 1);
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::InvalidQueueCount)
+        );
+        block_device.num_queues = Some(4);
+        block_device.queue_size = Some(100);
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::InvalidQueueSize)
+        );
+        block_device.queue_size = Some(MAX_QUEUE_SIZE);
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+
+        // Updates are checked too.
+        block_device.queue_size = Some(2 * MAX_QUEUE_SIZE);
+        assert_eq!(
+            block_devices_configs.insert(block_device),
+            Err(DriveError::InvalidQueueSize)
+        );
+        assert_eq!(
+            block_devices_configs.config_list[0].queue_size,
+            Some(MAX_QUEUE_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_verity() {
+        let dummy_file = NamedTempFile::new().unwrap();
+        let root_hash = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF";
+        let mut block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: Some(VerityConfig {
+                hash_tree_path: PathBuf::from("/foo/hash_tree"),
+                root_hash: String::from(root_hash),
+                salt: Some(String::from("a5a5")),
+            }),
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+
+        // The guest could write blocks the hash tree does not know of.
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::VerityNotReadOnly)
+        );
+        block_device.is_read_only = true;
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+        let (hash, salt) = block_device.verity.as_ref().unwrap().decode().unwrap();
+        assert_eq!(&hash[..4], &[0x00, 0x11, 0x22, 0x33]);
+        assert_eq!(&hash[28..], &[0xcc, 0xdd, 0xee, 0xff]);
+        assert_eq!(salt, Some(vec![0xa5, 0xa5]));
+
+        let invalid_hashes = vec![
+            (&root_hash[2..], None),
+            (root_hash, Some("a5a")),
+            (root_hash, Some("a5g5")),
+        ];
+        for (root_hash, salt) in invalid_hashes {
+            block_device.verity = Some(VerityConfig {
+                hash_tree_path: PathBuf::from("/foo/hash_tree"),
+                root_hash: String::from(root_hash),
+                salt: salt.map(String::from),
+            });
+            assert_eq!(
+                block_devices_configs.insert(block_device.clone()),
+                Err(DriveError::InvalidVerityHash)
+            );
+        }
+    }
+
+    #[test]
+    fn test_encryption() {
+        let dummy_file = NamedTempFile::new().unwrap();
+        let key = "000102030405060708090a0b0c0d0e0f101112131415161718191A1B1C1D1E1F";
+        let mut block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: Some(ImageFormatType::Raw),
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: Some(EncryptionConfig {
+                key: String::from(key),
+            }),
+            nbd: None,
+            vhost_user: None,
+        };
+
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+        let encryption = block_device.encryption.clone().unwrap();
+        assert_eq!(encryption.decode().unwrap(), (0..32).collect::<Vec<u8>>());
+        // The key stays out of the logs.
+        assert!(!format!("{:?}", block_device).contains(key));
+        // Detecting the format could mistake the ciphertext for another format.
+        block_device.image_format = None;
+        assert_eq!(block_device.disk_image_format(), Some(ImageFormat::Raw));
+
+        // The ciphertext is stored in the disk image as it is.
+        block_device.image_format = Some(ImageFormatType::Qcow2);
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::EncryptedImageFormat)
+        );
+        block_device.image_format = None;
+        block_device.overlay = Some(OverlayConfig { path_on_host: None });
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::EncryptedImageFormat)
+        );
+        block_device.overlay = None;
+
+        let invalid_keys = vec![
+            String::from(&key[2..]),
+            String::from(&key[..32]),
+            format!("{}0", key),
+            key.replace("0a", "0g"),
+            // The halves of the key are the same.
+            key[..32].repeat(2),
+        ];
+        for key in invalid_keys {
+            block_device.encryption = Some(EncryptionConfig { key });
+            assert_eq!(
+                block_devices_configs.insert(block_device.clone()),
+                Err(DriveError::InvalidEncryptionKey)
+            );
+        }
+    }
+
+    #[test]
+    fn test_nbd() {
+        // The path is the socket of the server, which only has to exist when configured.
+        let dummy_file = NamedTempFile::new().unwrap();
+        let mut block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: Some(NbdConfig { export_name: None }),
+            vhost_user: None,
+        };
+
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+        assert_eq!(block_device.nbd.as_ref().unwrap().export_name(), "");
+        block_device.nbd = Some(NbdConfig {
+            export_name: Some(String::from("disk")),
+        });
+        assert_eq!(block_device.nbd.as_ref().unwrap().export_name(), "disk");
+        block_device.image_format = Some(ImageFormatType::Raw);
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+
+        // The server serves the disk as the guest sees it.
+        block_device.image_format = Some(ImageFormatType::Qcow2);
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::NbdImageFormat)
+        );
+    }
+
+    #[test]
+    fn test_vhost_user() {
+        // The path is the socket of the back-end, which only has to exist when configured.
+        let dummy_file = NamedTempFile::new().unwrap();
+        let mut block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: true,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: Some(4),
+            queue_size: Some(128),
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: Some(true),
+        };
+
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+        assert!(block_devices_configs.config_list[0].is_vhost_user());
+
+        // The back-end decides how the disk image is opened and accessed.
+        block_device.image_format = Some(ImageFormatType::Raw);
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::VhostUserOption)
+        );
+        block_device.image_format = None;
+        block_device.nbd = Some(NbdConfig { export_name: None });
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::VhostUserOption)
+        );
+        // The other options are fine without vhost-user.
+        block_device.vhost_user = Some(false);
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+        assert!(!block_devices_configs.config_list[0].is_vhost_user());
+    }
+
+    #[test]
+    fn test_empty_drive() {
+        let mut block_device = BlockDeviceConfig {
+            path_on_host: PathBuf::new(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_engine: None,
+            discard: None,
+            write_zeroes: None,
+            image_format: None,
+            overlay: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            verity: None,
+            encryption: None,
+            nbd: None,
+            vhost_user: None,
+        };
+
+        // Any number of drives can be empty.
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+        block_device.drive_id = String::from("2");
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+        assert_eq!(block_devices_configs.config_list.len(), 2);
+        assert!(block_devices_configs.config_list[1].is_empty());
+
+        block_device.is_root_device = true;
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::EmptyDriveOption)
+        );
+        block_device.is_root_device = false;
+        block_device.overlay = Some(OverlayConfig { path_on_host: None });
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::EmptyDriveOption)
+        );
+        block_device.overlay = None;
+        block_device.vhost_user = Some(true);
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::EmptyDriveOption)
+        );
+    }
 }
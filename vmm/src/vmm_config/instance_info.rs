@@ -6,6 +6,8 @@ use std::fmt::{Display, Formatter, Result};
 
 use device_manager;
 use devices;
+#[cfg(target_arch = "x86_64")]
+use gdb;
 use kernel::loader as kernel_loader;
 use memory_model::GuestMemoryError;
 use seccomp;
@@ -14,7 +16,9 @@ use vstate;
 /// The microvm state. When Firecracker starts, the instance state is Uninitialized.
 /// Once start_microvm method is called, the state goes from Uninitialized to Starting.
 /// The state is changed to Running before ending the start_microvm method.
-/// Halting and Halted are currently unsupported.
+/// A running microVM can be moved to Paused and back to Running through the API.
+/// When the guest powers off or reboots and the matching lifecycle action is `Halt`, the
+/// microVM goes through Halting and stays Halted until Firecracker is killed.
 #[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum InstanceState {
     /// Microvm is not initialized.
@@ -23,7 +27,9 @@ pub enum InstanceState {
     Starting,
     /// Microvm is running.
     Running,
-    /// Microvm received a halt instruction.
+    /// Microvm is paused: the vCPUs and the devices are stopped.
+    Paused,
+    /// Microvm powered off or rebooted, and is being stopped.
     Halting,
     /// Microvm is halted.
     Halted,
@@ -38,35 +44,67 @@ pub struct InstanceInfo {
     pub state: InstanceState,
     /// The version of the VMM that runs the microVM.
     pub vmm_version: String,
+    /// The IDs of the devices which were stopped because of an error, and need a reset.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed_devices: Vec<String>,
 }
 
 /// Errors associated with starting the instance.
 // TODO: add error kind to these variants because not all these errors are user or internal.
 #[derive(Debug)]
 pub enum StartMicrovmError {
+    /// A block device using the `Async` I/O engine has a disk image which is not raw.
+    AsyncIoEngineImageFormat,
+    /// Cannot duplicate the file descriptor of a tap device.
+    CloneTap(std::io::Error),
     /// This error is thrown by the minimal boot loader implementation.
     /// It is related to a faulty memory configuration.
     ConfigureSystem(arch::Error),
     /// Cannot configure the VM.
     ConfigureVm(vstate::Error),
+    /// Cannot connect to the NBD server of a block device.
+    ConnectNbdServer(devices::virtio::NbdError),
+    /// Cannot create the timer which polls the balloon statistics.
+    CreateBalloonDevice(std::io::Error),
     /// Unable to seek the block device backing file due to invalid permissions or
     /// the file was deleted/corrupted.
     CreateBlockDevice(std::io::Error),
+    /// Cannot set up the encryption of a block device.
+    CreateEncryption(devices::virtio::CryptError),
+    /// Cannot set up the io_uring of a block device using the `Async` I/O engine.
+    CreateIoUring(std::io::Error),
     /// Split this at some point.
     /// Internal errors are due to resource exhaustion.
     /// Users errors are due to invalid permissions.
     CreateNetDevice(devices::virtio::Error),
+    /// Cannot open or set up the copy-on-write overlay of a block device.
+    CreateOverlay(devices::virtio::CowError),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(std::io::Error),
+    /// Cannot set up the verification of a block device against its hash tree.
+    CreateVerity(devices::virtio::VerityError),
+    /// Cannot connect to or set up the vhost-user back-end of a block device.
+    CreateVhostUserBlock(devices::virtio::vhost::Error),
     #[cfg(feature = "vsock")]
     /// Creating a vsock device can only fail if the /dev/vhost-vsock device cannot be open.
     CreateVsockDevice(devices::virtio::vhost::Error),
     /// The device manager was not configured.
     DeviceManager,
+    /// A block device opened with O_DIRECT has a disk image which is not raw.
+    DirectIoImageFormat,
     /// Cannot read from an Event file descriptor.
     EventFd,
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot start the GDB server.
+    GdbServer(gdb::Error),
     /// Memory regions are overlapping or mmap fails.
     GuestMemory(GuestMemoryError),
+    /// The hugepages backing the guest memory cannot be allocated.
+    HugepagesUnavailable(std::io::Error),
+    /// The memory size is not a multiple of the hugepage size.
+    InvalidHugepagesMemorySize,
+    /// The disk image of a block device is not valid, or uses unsupported features.
+    InvalidDiskImage(devices::virtio::QcowError),
     /// The kernel command line is invalid.
     KernelCmdline(String),
     /// Cannot load kernel due to invalid memory configuration or invalid kernel image.
@@ -83,10 +121,15 @@ pub enum StartMicrovmError {
     NetDeviceNotConfigured,
     /// Cannot open the block device backing file.
     OpenBlockDevice(std::io::Error),
+    /// Cannot initialize a MMIO Balloon Device or add a device to the MMIO Bus.
+    RegisterBalloonDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Block Device or add a device to the MMIO Bus.
     RegisterBlockDevice(device_manager::mmio::Error),
     /// Cannot add event to Epoll.
     RegisterEvent,
+    #[cfg(not(target_arch = "x86_64"))]
+    /// Cannot add the pvpanic device to the MMIO Bus.
+    RegisterPvPanicDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Network Device or add a device to the MMIO Bus.
     RegisterNetDevice(device_manager::mmio::Error),
     #[cfg(feature = "vsock")]
@@ -96,18 +139,30 @@ pub enum StartMicrovmError {
     SeccompFilters(seccomp::Error),
     /// Cannot create a new vCPU file descriptor.
     Vcpu(vstate::Error),
+    /// Cannot pin a vCPU thread to its host CPUs.
+    VcpuAffinity(u8, std::io::Error),
     /// vCPU configuration failed.
     VcpuConfigure(vstate::Error),
+    /// Cannot set the scheduling policy of a vCPU thread.
+    VcpuScheduling(u8, std::io::Error),
     /// vCPUs were not configured.
     VcpusNotConfigured,
     /// Cannot spawn a new vCPU thread.
     VcpuSpawn(std::io::Error),
+    /// Cannot pin the VMM and API threads to their host CPUs.
+    VmmAffinity(std::io::Error),
+    /// Cannot set the scheduling policy of the VMM and API threads.
+    VmmScheduling(std::io::Error),
 }
 
 impl Display for StartMicrovmError {
     fn fmt(&self, f: &mut Formatter) -> Result {
         use self::StartMicrovmError::*;
         match *self {
+            AsyncIoEngineImageFormat => {
+                write!(f, "The Async I/O engine only supports raw disk images.")
+            }
+            CloneTap(ref err) => write!(f, "Cannot clone the tap device. {}", err),
             ConfigureSystem(ref err) => {
                 let mut err_msg = format!("{:?}", err);
                 err_msg = err_msg.replace("\"", "");
@@ -120,13 +175,36 @@ impl Display for StartMicrovmError {
 
                 write!(f, "Cannot configure virtual machine. {}", err_msg)
             }
+            ConnectNbdServer(ref err) => write!(f, "Cannot connect to the NBD server. {}", err),
+            CreateBalloonDevice(ref err) => write!(f, "Cannot create balloon device. {}", err),
             CreateBlockDevice(ref err) => write!(
                 f,
                 "Unable to seek the block device backing file due to invalid permissions or \
                  the file was deleted/corrupted. Error number: {}",
                 err
             ),
+            CreateEncryption(ref err) => write!(
+                f,
+                "Cannot set up the encryption of the block device. {}",
+                err
+            ),
+            CreateIoUring(ref err) => write!(
+                f,
+                "Cannot set up the io_uring of the block device. The Async I/O engine needs \
+                 Linux 5.6 or newer. {}",
+                err
+            ),
             CreateRateLimiter(ref err) => write!(f, "Cannot create RateLimiter: {}", err),
+            CreateVerity(ref err) => write!(
+                f,
+                "Cannot set up the verification of the block device. {}",
+                err
+            ),
+            CreateVhostUserBlock(ref err) => write!(
+                f,
+                "Cannot set up the vhost-user back-end of the block device. {:?}",
+                err
+            ),
             #[cfg(feature = "vsock")]
             CreateVsockDevice(ref err) => {
                 let mut err_msg = format!("{:?}", err);
@@ -140,14 +218,36 @@ impl Display for StartMicrovmError {
 
                 write!(f, "Cannot create network device. {}", err_msg)
             }
+            CreateOverlay(ref err) => {
+                write!(f, "Cannot set up the overlay of the block device. {}", err)
+            }
             DeviceManager => write!(f, "The device manager was not configured."),
+            DirectIoImageFormat => write!(
+                f,
+                "The None cache type only supports raw disk images, without an overlay."
+            ),
             EventFd => write!(f, "Cannot read from an Event file descriptor."),
+            #[cfg(target_arch = "x86_64")]
+            GdbServer(ref err) => write!(f, "Cannot start the GDB server. {}", err),
             GuestMemory(ref err) => {
                 // Remove imbricated quotes from error message.
                 let mut err_msg = format!("{:?}", err);
                 err_msg = err_msg.replace("\"", "");
                 write!(f, "Invalid Memory Configuration: {}", err_msg)
             }
+            HugepagesUnavailable(ref err) => write!(
+                f,
+                "Cannot allocate the hugepages backing the guest memory. Check that enough 2 MiB \
+                 hugepages are reserved in /proc/sys/vm/nr_hugepages. {}",
+                err
+            ),
+            InvalidHugepagesMemorySize => write!(
+                f,
+                "The memory size must be a multiple of 2 MiB when it is backed by hugepages."
+            ),
+            InvalidDiskImage(ref err) => {
+                write!(f, "Invalid disk image of the block device. {}", err)
+            }
             KernelCmdline(ref err) => write!(f, "Invalid kernel command line: {}", err),
             KernelLoader(ref err) => {
                 let mut err_msg = format!("{}", err);
@@ -181,6 +281,15 @@ impl Display for StartMicrovmError {
 
                 write!(f, "Cannot open the block device backing file. {}", err_msg)
             }
+            RegisterBalloonDevice(ref err) => {
+                let mut err_msg = format!("{:?}", err);
+                err_msg = err_msg.replace("\"", "");
+                write!(
+                    f,
+                    "Cannot initialize a MMIO Balloon Device or add a device to the MMIO Bus. {}",
+                    err_msg
+                )
+            }
             RegisterBlockDevice(ref err) => {
                 let mut err_msg = format!("{:?}", err);
                 err_msg = err_msg.replace("\"", "");
@@ -191,6 +300,10 @@ impl Display for StartMicrovmError {
                 )
             }
             RegisterEvent => write!(f, "Cannot add event to Epoll."),
+            #[cfg(not(target_arch = "x86_64"))]
+            RegisterPvPanicDevice(ref err) => {
+                write!(f, "Cannot add the pvpanic device to the MMIO Bus. {}", err)
+            }
             RegisterNetDevice(ref err) => {
                 let mut err_msg = format!("{:?}", err);
                 err_msg = err_msg.replace("\"", "");
@@ -237,6 +350,26 @@ impl Display for StartMicrovmError {
 
                 write!(f, "Cannot spawn vCPU thread. {}", err_msg)
             }
+            VcpuAffinity(id, ref err) => write!(
+                f,
+                "Cannot pin the thread of vCPU {} to its host CPUs: {}",
+                id, err
+            ),
+            VcpuScheduling(id, ref err) => write!(
+                f,
+                "Cannot set the scheduling policy of the thread of vCPU {}: {}",
+                id, err
+            ),
+            VmmAffinity(ref err) => write!(
+                f,
+                "Cannot pin the VMM and API threads to their host CPUs: {}",
+                err
+            ),
+            VmmScheduling(ref err) => write!(
+                f,
+                "Cannot set the scheduling policy of the VMM and API threads: {}",
+                err
+            ),
         }
     }
 }
@@ -1,13 +1,20 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use libc::{c_int, SCHED_FIFO, SCHED_OTHER, SCHED_RR};
+use memory_model::MemoryBacking;
 use serde::{de, Deserialize};
 use std::fmt::{Display, Formatter, Result};
+use sys_util::MAX_HOST_CPUS;
 
 /// Firecracker aims to support small scale workloads only, so limit the maximum
 /// vCPUs supported.
 pub const MAX_SUPPORTED_VCPUS: u8 = 32;
 
+/// The range of the priorities of the real-time scheduling policies.
+const MIN_RT_PRIORITY: u8 = 1;
+const MAX_RT_PRIORITY: u8 = 99;
+
 /// Errors associated with configuring the microVM.
 #[derive(Debug, PartialEq)]
 pub enum VmConfigError {
@@ -18,6 +25,12 @@ pub enum VmConfigError {
     InvalidMemorySize,
     /// Cannot update the configuration of the microvm post boot.
     UpdateNotAllowedPostBoot,
+    /// A CPU affinity is an empty set or holds a host CPU which does not exist.
+    InvalidCpuAffinity,
+    /// The vCPU affinity does not hold one CPU set for each vCPU.
+    InvalidVcpuAffinityCount,
+    /// The scheduling priority is out of the range of the scheduling policy.
+    InvalidSchedulingPriority,
 }
 
 impl Display for VmConfigError {
@@ -33,6 +46,22 @@ impl Display for VmConfigError {
             UpdateNotAllowedPostBoot => {
                 write!(f, "The update operation is not allowed after boot.")
             }
+            InvalidCpuAffinity => write!(
+                f,
+                "The CPU affinity is invalid. The sets of host CPUs cannot be empty and the host \
+                 CPU IDs must be lower than {}.",
+                MAX_HOST_CPUS
+            ),
+            InvalidVcpuAffinityCount => write!(
+                f,
+                "The vCPU affinity is invalid. It must hold one set of host CPUs for each vCPU."
+            ),
+            InvalidSchedulingPriority => write!(
+                f,
+                "The scheduling priority is invalid. It must be between {} and {} for the Fifo \
+                 and RoundRobin policies, and 0 for the Other policy.",
+                MIN_RT_PRIORITY, MAX_RT_PRIORITY
+            ),
         }
     }
 }
@@ -58,6 +87,30 @@ pub struct VmConfig {
     /// A CPU template that it is used to filter the CPU features exposed to the guest.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// What happens when the guest powers off.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_poweroff: Option<LifecycleAction>,
+    /// What happens when the guest reboots.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_reboot: Option<LifecycleAction>,
+    /// What happens when the guest kernel reports a panic through the pvpanic device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_panic: Option<PanicAction>,
+    /// The memory which backs the guest RAM.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mem_backing: Option<MemoryBackingType>,
+    /// The host CPUs each vCPU thread may run on, indexed by vCPU ID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vcpu_affinity: Option<Vec<Vec<usize>>>,
+    /// How the host schedules the vCPU threads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vcpu_scheduling: Option<ThreadScheduling>,
+    /// The host CPUs the VMM and API threads may run on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vmm_affinity: Option<Vec<usize>>,
+    /// How the host schedules the VMM and API threads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vmm_scheduling: Option<ThreadScheduling>,
 }
 
 impl Default for VmConfig {
@@ -67,6 +120,14 @@ impl Default for VmConfig {
             mem_size_mib: Some(128),
             ht_enabled: Some(false),
             cpu_template: None,
+            on_poweroff: Some(LifecycleAction::Exit),
+            on_reboot: Some(LifecycleAction::Exit),
+            on_panic: Some(PanicAction::Ignore),
+            mem_backing: Some(MemoryBackingType::Anonymous),
+            vcpu_affinity: None,
+            vcpu_scheduling: None,
+            vmm_affinity: None,
+            vmm_scheduling: None,
         }
     }
 }
@@ -106,9 +167,152 @@ impl Display for CpuFeaturesTemplate {
     }
 }
 
+/// The actions available when the guest powers off or reboots.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum LifecycleAction {
+    /// Firecracker exits, with an exit code telling whether the guest powered off or rebooted.
+    Exit,
+    /// The microVM stays in the `Halted` state until Firecracker is killed.
+    Halt,
+    /// The microVM boots again in the same Firecracker process.
+    Reboot,
+}
+
+impl Display for LifecycleAction {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            LifecycleAction::Exit => write!(f, "Exit"),
+            LifecycleAction::Halt => write!(f, "Halt"),
+            LifecycleAction::Reboot => write!(f, "Reboot"),
+        }
+    }
+}
+
+/// The actions available when the guest kernel reports a panic.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum PanicAction {
+    /// Firecracker exits, with an exit code telling that the guest kernel panicked.
+    Exit,
+    /// The microVM is paused, so that it can be inspected.
+    Pause,
+    /// The panic is only logged, and the guest handles it on its own.
+    Ignore,
+}
+
+impl Display for PanicAction {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            PanicAction::Exit => write!(f, "Exit"),
+            PanicAction::Pause => write!(f, "Pause"),
+            PanicAction::Ignore => write!(f, "Ignore"),
+        }
+    }
+}
+
+/// The kinds of memory that can back the guest RAM.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MemoryBackingType {
+    /// Private anonymous memory made of regular pages.
+    Anonymous,
+    /// Private anonymous memory made of 2 MiB hugetlbfs pages.
+    Hugepages,
+    /// A shared `memfd` which other processes can map through `/proc/<pid>/fd`.
+    Memfd,
+    /// A shared `memfd` made of 2 MiB hugetlbfs pages.
+    MemfdHugepages,
+}
+
+impl Display for MemoryBackingType {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            MemoryBackingType::Anonymous => write!(f, "Anonymous"),
+            MemoryBackingType::Hugepages => write!(f, "Hugepages"),
+            MemoryBackingType::Memfd => write!(f, "Memfd"),
+            MemoryBackingType::MemfdHugepages => write!(f, "MemfdHugepages"),
+        }
+    }
+}
+
+impl From<MemoryBackingType> for MemoryBacking {
+    fn from(backing: MemoryBackingType) -> Self {
+        match backing {
+            MemoryBackingType::Anonymous => MemoryBacking::Anonymous,
+            MemoryBackingType::Hugepages => MemoryBacking::Hugepages,
+            MemoryBackingType::Memfd => MemoryBacking::Memfd { hugepages: false },
+            MemoryBackingType::MemfdHugepages => MemoryBacking::Memfd { hugepages: true },
+        }
+    }
+}
+
+/// Checks that a set of host CPUs can be used as a CPU affinity.
+pub fn validate_cpu_affinity(cpus: &[usize]) -> std::result::Result<(), VmConfigError> {
+    if cpus.is_empty() || cpus.iter().any(|&cpu| cpu >= MAX_HOST_CPUS) {
+        return Err(VmConfigError::InvalidCpuAffinity);
+    }
+    Ok(())
+}
+
+/// The scheduling policies available for the vCPU and VMM threads.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum SchedulingPolicy {
+    /// The default time-sharing policy of the host.
+    Other,
+    /// The real-time first-in first-out policy.
+    Fifo,
+    /// The real-time round-robin policy.
+    RoundRobin,
+}
+
+impl Display for SchedulingPolicy {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            SchedulingPolicy::Other => write!(f, "Other"),
+            SchedulingPolicy::Fifo => write!(f, "Fifo"),
+            SchedulingPolicy::RoundRobin => write!(f, "RoundRobin"),
+        }
+    }
+}
+
+/// How the host schedules a group of threads.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThreadScheduling {
+    /// The scheduling policy of the threads.
+    pub policy: SchedulingPolicy,
+    /// The static priority of the threads, between 1 and 99 for the real-time policies.
+    #[serde(default)]
+    pub priority: u8,
+}
+
+impl ThreadScheduling {
+    /// Checks that the priority is allowed for the policy.
+    pub fn validate(&self) -> std::result::Result<(), VmConfigError> {
+        let valid = match self.policy {
+            SchedulingPolicy::Other => self.priority == 0,
+            SchedulingPolicy::Fifo | SchedulingPolicy::RoundRobin => {
+                self.priority >= MIN_RT_PRIORITY && self.priority <= MAX_RT_PRIORITY
+            }
+        };
+        if !valid {
+            return Err(VmConfigError::InvalidSchedulingPriority);
+        }
+        Ok(())
+    }
+
+    /// Returns the `SCHED_*` constant of the policy.
+    pub fn sched_policy(&self) -> c_int {
+        match self.policy {
+            SchedulingPolicy::Other => SCHED_OTHER,
+            SchedulingPolicy::Fifo => SCHED_FIFO,
+            SchedulingPolicy::RoundRobin => SCHED_RR,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json;
 
     #[test]
     fn test_display_cpu_features_template() {
@@ -116,6 +320,133 @@ mod tests {
         assert_eq!(CpuFeaturesTemplate::T2.to_string(), "T2".to_string());
     }
 
+    #[test]
+    fn test_display_lifecycle_action() {
+        assert_eq!(LifecycleAction::Exit.to_string(), "Exit".to_string());
+        assert_eq!(LifecycleAction::Halt.to_string(), "Halt".to_string());
+        assert_eq!(LifecycleAction::Reboot.to_string(), "Reboot".to_string());
+    }
+
+    #[test]
+    fn test_lifecycle_actions() {
+        let vm_config: VmConfig =
+            serde_json::from_str(r#"{"on_poweroff": "Halt", "on_reboot": "Reboot"}"#).unwrap();
+        assert_eq!(vm_config.on_poweroff, Some(LifecycleAction::Halt));
+        assert_eq!(vm_config.on_reboot, Some(LifecycleAction::Reboot));
+        assert!(serde_json::from_str::<VmConfig>(r#"{"on_reboot": "Restart"}"#).is_err());
+
+        let vm_config = VmConfig::default();
+        assert_eq!(vm_config.on_poweroff, Some(LifecycleAction::Exit));
+        assert_eq!(vm_config.on_reboot, Some(LifecycleAction::Exit));
+    }
+
+    #[test]
+    fn test_panic_action() {
+        let vm_config: VmConfig = serde_json::from_str(r#"{"on_panic": "Pause"}"#).unwrap();
+        assert_eq!(vm_config.on_panic, Some(PanicAction::Pause));
+        assert!(serde_json::from_str::<VmConfig>(r#"{"on_panic": "Halt"}"#).is_err());
+        assert_eq!(VmConfig::default().on_panic, Some(PanicAction::Ignore));
+
+        assert_eq!(PanicAction::Exit.to_string(), "Exit".to_string());
+        assert_eq!(PanicAction::Pause.to_string(), "Pause".to_string());
+        assert_eq!(PanicAction::Ignore.to_string(), "Ignore".to_string());
+    }
+
+    #[test]
+    fn test_memory_backing() {
+        let vm_config: VmConfig =
+            serde_json::from_str(r#"{"mem_backing": "MemfdHugepages"}"#).unwrap();
+        assert_eq!(
+            vm_config.mem_backing,
+            Some(MemoryBackingType::MemfdHugepages)
+        );
+        assert!(serde_json::from_str::<VmConfig>(r#"{"mem_backing": "Shared"}"#).is_err());
+        assert_eq!(
+            VmConfig::default().mem_backing,
+            Some(MemoryBackingType::Anonymous)
+        );
+
+        assert_eq!(MemoryBackingType::Memfd.to_string(), "Memfd".to_string());
+        assert_eq!(
+            MemoryBacking::from(MemoryBackingType::Hugepages),
+            MemoryBacking::Hugepages
+        );
+        assert_eq!(
+            MemoryBacking::from(MemoryBackingType::MemfdHugepages),
+            MemoryBacking::Memfd { hugepages: true }
+        );
+    }
+
+    #[test]
+    fn test_thread_affinity() {
+        let vm_config: VmConfig =
+            serde_json::from_str(r#"{"vcpu_affinity": [[0, 1], [2]], "vmm_affinity": [3]}"#)
+                .unwrap();
+        assert_eq!(vm_config.vcpu_affinity, Some(vec![vec![0, 1], vec![2]]));
+        assert_eq!(vm_config.vmm_affinity, Some(vec![3]));
+        assert!(VmConfig::default().vcpu_affinity.is_none());
+        assert!(VmConfig::default().vmm_affinity.is_none());
+
+        assert!(validate_cpu_affinity(&[0, 1023]).is_ok());
+        assert_eq!(
+            validate_cpu_affinity(&[]),
+            Err(VmConfigError::InvalidCpuAffinity)
+        );
+        assert_eq!(
+            validate_cpu_affinity(&[1, 1024]),
+            Err(VmConfigError::InvalidCpuAffinity)
+        );
+    }
+
+    #[test]
+    fn test_thread_scheduling() {
+        let vm_config: VmConfig = serde_json::from_str(
+            r#"{"vcpu_scheduling": {"policy": "Fifo", "priority": 10},
+                "vmm_scheduling": {"policy": "Other"}}"#,
+        )
+        .unwrap();
+        let vcpu_scheduling = vm_config.vcpu_scheduling.unwrap();
+        assert_eq!(vcpu_scheduling.policy, SchedulingPolicy::Fifo);
+        assert_eq!(vcpu_scheduling.priority, 10);
+        assert_eq!(vcpu_scheduling.sched_policy(), SCHED_FIFO);
+        assert!(vcpu_scheduling.validate().is_ok());
+        let vmm_scheduling = vm_config.vmm_scheduling.unwrap();
+        assert_eq!(vmm_scheduling.priority, 0);
+        assert_eq!(vmm_scheduling.sched_policy(), SCHED_OTHER);
+        assert!(vmm_scheduling.validate().is_ok());
+        assert!(
+            serde_json::from_str::<VmConfig>(r#"{"vcpu_scheduling": {"policy": "Deadline"}}"#)
+                .is_err()
+        );
+
+        let invalid = [
+            (SchedulingPolicy::Other, 1),
+            (SchedulingPolicy::Fifo, 0),
+            (SchedulingPolicy::RoundRobin, 100),
+        ];
+        for &(policy, priority) in invalid.iter() {
+            assert_eq!(
+                ThreadScheduling { policy, priority }.validate(),
+                Err(VmConfigError::InvalidSchedulingPriority)
+            );
+        }
+        assert_eq!(
+            ThreadScheduling {
+                policy: SchedulingPolicy::RoundRobin,
+                priority: 99
+            }
+            .sched_policy(),
+            SCHED_RR
+        );
+
+        assert_eq!(SchedulingPolicy::Other.to_string(), "Other".to_string());
+        assert_eq!(SchedulingPolicy::Fifo.to_string(), "Fifo".to_string());
+        assert_eq!(
+            SchedulingPolicy::RoundRobin.to_string(),
+            "RoundRobin".to_string()
+        );
+    }
+
     #[test]
     fn test_display_vm_config_error() {
         let expected_str = "The vCPU number is invalid! The vCPU number can only \
@@ -130,5 +461,23 @@ mod tests {
             VmConfigError::UpdateNotAllowedPostBoot.to_string(),
             expected_str
         );
+
+        let expected_str = "The CPU affinity is invalid. The sets of host CPUs cannot be empty \
+                            and the host CPU IDs must be lower than 1024.";
+        assert_eq!(VmConfigError::InvalidCpuAffinity.to_string(), expected_str);
+
+        let expected_str = "The vCPU affinity is invalid. It must hold one set of host CPUs for \
+                            each vCPU.";
+        assert_eq!(
+            VmConfigError::InvalidVcpuAffinityCount.to_string(),
+            expected_str
+        );
+
+        let expected_str = "The scheduling priority is invalid. It must be between 1 and 99 for \
+                            the Fifo and RoundRobin policies, and 0 for the Other policy.";
+        assert_eq!(
+            VmConfigError::InvalidSchedulingPriority.to_string(),
+            expected_str
+        );
     }
 }
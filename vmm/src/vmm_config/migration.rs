@@ -0,0 +1,114 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+use std::io;
+use std::path::PathBuf;
+
+use serde_json;
+
+use memory_model::GuestMemoryError;
+use vmm_config::drive::DriveEncryptionKey;
+use vstate;
+
+/// Strongly typed data structure used to send a running microVM to another Firecracker process
+/// or to receive one.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MigrationConfig {
+    /// Path of the Unix socket the destination listens on.
+    pub socket_path: PathBuf,
+    /// The keys of the encrypted drives, which are not sent along with the microVM. Only used
+    /// when receiving a microVM.
+    pub encryption_keys: Option<Vec<DriveEncryptionKey>>,
+}
+
+/// Errors associated with migrating a microVM.
+#[derive(Debug)]
+pub enum MigrationError {
+    /// Only a running microVM can be migrated.
+    MicroVMNotRunning,
+    /// A microVM can only be received before it is started.
+    MicroVMAlreadyRunning,
+    /// Migrating microVMs with vsock devices is not supported.
+    VsockNotSupported,
+    /// Migrating microVMs with vhost-user drives is not supported.
+    VhostUserNotSupported,
+    /// Migrating microVMs with drives whose overlay is kept in memory is not supported.
+    InMemoryOverlayNotSupported,
+    /// Migration is not supported on this architecture.
+    UnsupportedArch,
+    /// Cannot connect to, bind or accept on the migration socket.
+    Socket(io::Error),
+    /// Cannot send data to the destination.
+    Send(io::Error),
+    /// Cannot receive data from the source.
+    Receive(io::Error),
+    /// The other end does not speak the migration protocol.
+    InvalidStream,
+    /// The other end uses a migration protocol version this build does not understand.
+    InvalidVersion(u32),
+    /// A message that is not valid at this point of the migration was received.
+    UnexpectedMessage(u32),
+    /// The source memory layout does not match the one of its machine configuration.
+    InvalidMemoryLayout,
+    /// No key was given for the encrypted drive with the given id.
+    MissingEncryptionKey(String),
+    /// Cannot copy the guest memory to or from the migration socket.
+    GuestMemory(GuestMemoryError),
+    /// Cannot serialize a migration message.
+    Serialize(serde_json::Error),
+    /// Cannot deserialize a migration message.
+    Deserialize(serde_json::Error),
+    /// Cannot turn on dirty page tracking or read the dirty page log.
+    DirtyPageTracking(vstate::Error),
+    /// The destination could not resume the microVM.
+    DestinationFailed,
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::MigrationError::*;
+        match *self {
+            MicroVMNotRunning => write!(f, "The microVM must be running to be migrated."),
+            MicroVMAlreadyRunning => write!(
+                f,
+                "A microVM can only be received before the microVM is started."
+            ),
+            VsockNotSupported => {
+                write!(f, "Migrating microVMs with vsock devices is not supported.")
+            }
+            VhostUserNotSupported => write!(
+                f,
+                "Migrating microVMs with vhost-user drives is not supported."
+            ),
+            InMemoryOverlayNotSupported => write!(
+                f,
+                "Migrating microVMs with in-memory drive overlays is not supported."
+            ),
+            UnsupportedArch => write!(f, "Migration is not supported on this architecture."),
+            Socket(ref err) => write!(f, "Cannot set up the migration socket. {}", err),
+            Send(ref err) => write!(f, "Cannot send the migration data. {}", err),
+            Receive(ref err) => write!(f, "Cannot receive the migration data. {}", err),
+            InvalidStream => write!(f, "The peer does not speak the migration protocol."),
+            InvalidVersion(version) => {
+                write!(f, "Unsupported migration protocol version: {}.", version)
+            }
+            UnexpectedMessage(kind) => write!(f, "Unexpected migration message: {}.", kind),
+            InvalidMemoryLayout => write!(
+                f,
+                "The guest memory layout of the source does not match the memory size."
+            ),
+            MissingEncryptionKey(ref drive_id) => {
+                write!(f, "No encryption key was given for the drive {}.", drive_id)
+            }
+            GuestMemory(ref err) => write!(f, "Cannot copy the guest memory. {:?}", err),
+            Serialize(ref err) => write!(f, "Cannot serialize the migration data. {}", err),
+            Deserialize(ref err) => write!(f, "Cannot deserialize the migration data. {}", err),
+            DirtyPageTracking(ref err) => {
+                write!(f, "Cannot track the dirty guest pages. {:?}", err)
+            }
+            DestinationFailed => write!(f, "The destination could not resume the microVM."),
+        }
+    }
+}
@@ -4,8 +4,14 @@
 use rate_limiter::{RateLimiter, TokenBucket};
 use std::io;
 
+/// Wrapper for configuring the balloon device.
+pub mod balloon;
 /// Wrapper for configuring the microVM boot source.
 pub mod boot_source;
+/// Wrapper for booting a microVM from a JSON configuration file.
+pub mod config_file;
+/// Wrapper for writing a core dump of the guest.
+pub mod coredump;
 /// Wrapper for configuring the block devices.
 pub mod drive;
 /// Wrapper over the microVM general information attached to the microVM.
@@ -14,8 +20,14 @@ pub mod instance_info;
 pub mod logger;
 /// Wrapper for configuring the memory and CPU of the microVM.
 pub mod machine_config;
+/// Wrapper for migrating a running microVM to another Firecracker process.
+pub mod migration;
 /// Wrapper for configuring the network devices attached to the microVM.
 pub mod net;
+/// Wrapper for saving a paused microVM to a snapshot and loading it back.
+pub mod snapshot;
+/// Wrapper for changing the state (paused or resumed) of a running microVM.
+pub mod vm_state;
 #[cfg(feature = "vsock")]
 /// Wrapper for configuring the vsock devices attached to the microVM.
 pub mod vsock;
@@ -31,7 +43,7 @@ pub mod vsock;
 
 /// A public-facing, stateless structure, holding all the data we need to create a TokenBucket
 /// (live) object.
-#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct TokenBucketConfig {
     /// See TokenBucket::size.
     pub size: u64,
@@ -54,7 +66,7 @@ impl TokenBucketConfig {
 
 /// A public-facing, stateless structure, holding all the data we need to create a RateLimiter
 /// (live) object.
-#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct RateLimiterConfig {
     /// Data used to initialize the RateLimiter::bandwidth bucket.
     pub bandwidth: Option<TokenBucketConfig>,
@@ -0,0 +1,121 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+use std::io;
+use std::path::PathBuf;
+
+use serde_json;
+
+use device_manager;
+use memory_model::GuestMemoryError;
+use vmm_config::drive::DriveEncryptionKey;
+use vstate;
+
+/// Strongly typed data structure used to create a snapshot of the microVM or to load one.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// Path of the file holding the state of the vCPUs, the VM and the devices.
+    pub snapshot_path: PathBuf,
+    /// Path of the file holding the contents of the guest memory.
+    pub mem_file_path: PathBuf,
+    /// The keys of the encrypted drives, which are not saved in the snapshot. Only used when
+    /// loading a snapshot.
+    pub encryption_keys: Option<Vec<DriveEncryptionKey>>,
+}
+
+/// Errors associated with creating and loading snapshots.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// A snapshot can only be created while the microVM is paused.
+    MicroVMNotPaused,
+    /// A snapshot can only be loaded before the microVM is started.
+    MicroVMAlreadyRunning,
+    /// Snapshots of microVMs with vsock devices are not supported.
+    VsockNotSupported,
+    /// Snapshots of microVMs with vhost-user drives are not supported.
+    VhostUserNotSupported,
+    /// Snapshots of microVMs with drives whose overlay is kept in memory are not supported.
+    InMemoryOverlayNotSupported,
+    /// Snapshots are not supported on this architecture.
+    UnsupportedArch,
+    /// Cannot create, read or write the snapshot file.
+    SnapshotFile(io::Error),
+    /// Cannot create, read or write the guest memory file.
+    MemoryFile(io::Error),
+    /// Cannot copy the guest memory to or from the memory file.
+    GuestMemory(GuestMemoryError),
+    /// Cannot serialize the snapshot.
+    Serialize(serde_json::Error),
+    /// Cannot deserialize the snapshot.
+    Deserialize(serde_json::Error),
+    /// The snapshot was written in a format version this build does not understand.
+    InvalidVersion(u32),
+    /// The memory layout in the snapshot does not match the one of the machine configuration.
+    InvalidMemoryLayout,
+    /// The vCPU count in the snapshot does not match the number of saved vCPU states.
+    InvalidVcpuCount,
+    /// A vCPU did not save its state when the microVM was paused.
+    MissingVcpuState(u8),
+    /// No key was given for the encrypted drive with the given id.
+    MissingEncryptionKey(String),
+    /// Cannot save the state of the VM.
+    SaveVmState(vstate::Error),
+    /// Cannot restore the state of the VM.
+    RestoreVmState(vstate::Error),
+    /// Cannot restore the state of a vCPU.
+    RestoreVcpuState(vstate::Error),
+    /// Cannot restore the state of the devices.
+    RestoreDevices(device_manager::mmio::Error),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::SnapshotError::*;
+        match *self {
+            MicroVMNotPaused => write!(f, "The microVM must be paused to create a snapshot."),
+            MicroVMAlreadyRunning => write!(
+                f,
+                "A snapshot can only be loaded before the microVM is started."
+            ),
+            VsockNotSupported => write!(
+                f,
+                "Snapshots of microVMs with vsock devices are not supported."
+            ),
+            VhostUserNotSupported => write!(
+                f,
+                "Snapshots of microVMs with vhost-user drives are not supported."
+            ),
+            InMemoryOverlayNotSupported => write!(
+                f,
+                "Snapshots of microVMs with in-memory drive overlays are not supported."
+            ),
+            UnsupportedArch => write!(f, "Snapshots are not supported on this architecture."),
+            SnapshotFile(ref err) => write!(f, "Cannot access the snapshot file. {}", err),
+            MemoryFile(ref err) => write!(f, "Cannot access the memory file. {}", err),
+            GuestMemory(ref err) => write!(f, "Cannot copy the guest memory. {:?}", err),
+            Serialize(ref err) => write!(f, "Cannot serialize the snapshot. {}", err),
+            Deserialize(ref err) => write!(f, "Cannot deserialize the snapshot. {}", err),
+            InvalidVersion(version) => {
+                write!(f, "Unsupported snapshot format version: {}.", version)
+            }
+            InvalidMemoryLayout => write!(
+                f,
+                "The guest memory layout in the snapshot does not match the memory size."
+            ),
+            InvalidVcpuCount => write!(
+                f,
+                "The number of vCPU states in the snapshot does not match the vCPU count."
+            ),
+            MissingVcpuState(id) => write!(f, "The state of vCPU {} was not saved.", id),
+            MissingEncryptionKey(ref drive_id) => {
+                write!(f, "No encryption key was given for the drive {}.", drive_id)
+            }
+            SaveVmState(ref err) => write!(f, "Cannot save the VM state. {:?}", err),
+            RestoreVmState(ref err) => write!(f, "Cannot restore the VM state. {:?}", err),
+            RestoreVcpuState(ref err) => write!(f, "Cannot restore the vCPU state. {:?}", err),
+            RestoreDevices(ref err) => write!(f, "Cannot restore the devices. {}", err),
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+use std::io;
+
+/// The states a running microVM can be moved to through the API.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum VmState {
+    /// The vCPUs are held outside of `KVM_RUN` and the devices are not processing events.
+    Paused,
+    /// The vCPUs and the devices are running.
+    Resumed,
+}
+
+/// Strongly typed data structure used to change the state of the microVM.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VmStateConfig {
+    /// The state the microVM should be moved to.
+    pub state: VmState,
+}
+
+/// Errors associated with changing the state of the microVM.
+#[derive(Debug)]
+pub enum VmStateError {
+    /// The microVM cannot be paused because it is not running.
+    MicroVMNotRunning,
+    /// The microVM cannot be resumed because it is not paused.
+    MicroVMNotPaused,
+    /// The microVM cannot be rebooted in place because its power-on state is unknown, which is
+    /// the case when it was loaded from a snapshot.
+    RebootNotSupported,
+    /// A vCPU thread could not be kicked out of `KVM_RUN`.
+    SignalVcpu(io::Error),
+    /// The vCPU threads did not stop in a timely manner.
+    VcpuPauseTimeout,
+    /// The requests in flight on the drive with the given id could not be completed.
+    DrainBlockDevice(String),
+}
+
+impl Display for VmStateError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::VmStateError::*;
+        match *self {
+            MicroVMNotRunning => write!(f, "The microVM is not running."),
+            MicroVMNotPaused => write!(f, "The microVM is not paused."),
+            RebootNotSupported => write!(f, "The microVM cannot be rebooted in place."),
+            SignalVcpu(ref err) => write!(f, "Cannot signal the vCPU thread. {}", err),
+            VcpuPauseTimeout => write!(f, "Timed out while waiting for the vCPUs to pause."),
+            DrainBlockDevice(ref drive_id) => write!(
+                f,
+                "Cannot complete the requests in flight on the drive {}.",
+                drive_id
+            ),
+        }
+    }
+}
@@ -5,21 +5,41 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+#[cfg(target_arch = "x86_64")]
+use std::collections::BTreeMap;
 use std::io;
+#[cfg(target_arch = "x86_64")]
+use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::result;
-use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use super::{KvmContext, TimestampUs};
 use arch;
 #[cfg(target_arch = "x86_64")]
 use cpuid::{c3, filter_cpuid, t2};
 use default_syscalls;
+#[cfg(target_arch = "x86_64")]
+use gdb::{Debugger, VcpuRegisters, VcpuRequest};
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{
+    kvm_clock_data, kvm_cpuid_entry2, kvm_fpu, kvm_guest_debug, kvm_irqchip, kvm_lapic_state,
+    kvm_mp_state, kvm_msr_entry, kvm_msrs, kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events,
+    kvm_xcrs, kvm_xsave, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+};
 use kvm_bindings::{kvm_pit_config, kvm_userspace_memory_region, KVM_PIT_SPEAKER_DUMMY};
 use kvm_ioctls::*;
+#[cfg(target_arch = "x86_64")]
+use libc::c_ulong;
+use libc::{c_int, c_void, siginfo_t};
+use logger::metrics::PerVcpuMetrics;
 use logger::{LogOption, Metric, LOGGER, METRICS};
 use memory_model::{GuestAddress, GuestMemory, GuestMemoryError};
-use sys_util::EventFd;
+#[cfg(target_arch = "x86_64")]
+use sys_util::{ioctl_with_mut_ref, ioctl_with_ref};
+use sys_util::{register_signal_handler, EventFd, SignalHandler};
 #[cfg(target_arch = "x86_64")]
 use vmm_config::machine_config::CpuFeaturesTemplate;
 use vmm_config::machine_config::VmConfig;
@@ -29,6 +49,80 @@ const KVM_MEM_LOG_DIRTY_PAGES: u32 = 0x1;
 const MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE: u16 = 0x03f0;
 const MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE: u8 = 123;
 
+/// Offset from `SIGRTMIN` of the signal used to kick vCPU threads out of `KVM_RUN`.
+pub const VCPU_RTSIG_OFFSET: i32 = 0;
+
+// KVM ioctls needed for saving and restoring the state of a microVM, and for debugging the
+// guest, which are not exposed by the kvm-ioctls crate.
+#[cfg(target_arch = "x86_64")]
+mod kvm_state_ioctls {
+    use kvm_bindings::*;
+
+    ioctl_iowr_nr!(KVM_GET_IRQCHIP, KVMIO, 0x62, kvm_irqchip);
+    ioctl_ior_nr!(KVM_SET_IRQCHIP, KVMIO, 0x63, kvm_irqchip);
+    ioctl_iow_nr!(KVM_SET_CLOCK, KVMIO, 0x7b, kvm_clock_data);
+    ioctl_ior_nr!(KVM_GET_CLOCK, KVMIO, 0x7c, kvm_clock_data);
+    ioctl_iow_nr!(KVM_SET_MSRS, KVMIO, 0x89, kvm_msrs);
+    ioctl_ior_nr!(KVM_GET_MP_STATE, KVMIO, 0x98, kvm_mp_state);
+    ioctl_iow_nr!(KVM_SET_MP_STATE, KVMIO, 0x99, kvm_mp_state);
+    ioctl_iow_nr!(KVM_SET_GUEST_DEBUG, KVMIO, 0x9b, kvm_guest_debug);
+    ioctl_ior_nr!(KVM_GET_PIT2, KVMIO, 0x9f, kvm_pit_state2);
+    ioctl_iow_nr!(KVM_SET_PIT2, KVMIO, 0xa0, kvm_pit_state2);
+    ioctl_ior_nr!(KVM_GET_VCPU_EVENTS, KVMIO, 0x9f, kvm_vcpu_events);
+    ioctl_iow_nr!(KVM_SET_VCPU_EVENTS, KVMIO, 0xa0, kvm_vcpu_events);
+    ioctl_ior_nr!(KVM_GET_XSAVE, KVMIO, 0xa4, kvm_xsave);
+    ioctl_iow_nr!(KVM_SET_XSAVE, KVMIO, 0xa5, kvm_xsave);
+    ioctl_ior_nr!(KVM_GET_XCRS, KVMIO, 0xa6, kvm_xcrs);
+    ioctl_iow_nr!(KVM_SET_XCRS, KVMIO, 0xa7, kvm_xcrs);
+}
+#[cfg(target_arch = "x86_64")]
+use self::kvm_state_ioctls::*;
+
+// Issues an ioctl which fills in `value`.
+#[cfg(target_arch = "x86_64")]
+fn kvm_get<F: AsRawFd, T>(fd: &F, request: c_ulong, value: &mut T) -> io::Result<()> {
+    // Safe because the size of `T` is encoded in `request`, so the kernel will not write past
+    // the end of `value`.
+    let ret = unsafe { ioctl_with_mut_ref(fd, request, value) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Issues an ioctl which only reads `value`.
+#[cfg(target_arch = "x86_64")]
+fn kvm_set<F: AsRawFd, T>(fd: &F, request: c_ulong, value: &T) -> io::Result<c_int> {
+    // Safe because the size of `T` is encoded in `request`, so the kernel will not read past
+    // the end of `value`.
+    let ret = unsafe { ioctl_with_ref(fd, request, value) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+// Returns a `kvm_msrs` holding `entries`, backed by a vector of `kvm_msrs` which is large enough
+// to also fit the flexible array member.
+#[cfg(target_arch = "x86_64")]
+fn kvm_msrs_from_entries(entries: &[kvm_msr_entry]) -> Vec<kvm_msrs> {
+    let vec_size_bytes = size_of::<kvm_msrs>() + entries.len() * size_of::<kvm_msr_entry>();
+    let vec_len = (vec_size_bytes + size_of::<kvm_msrs>() - 1) / size_of::<kvm_msrs>();
+    let mut msrs = Vec::with_capacity(vec_len);
+    for _ in 0..vec_len {
+        msrs.push(kvm_msrs::default());
+    }
+    msrs[0].nmsrs = entries.len() as u32;
+    // Safe because the vector was sized above to fit `entries.len()` entries.
+    unsafe {
+        msrs[0]
+            .entries
+            .as_mut_slice(entries.len())
+            .copy_from_slice(entries);
+    }
+    msrs
+}
+
 /// Errors associated with the wrappers over KVM ioctls.
 #[derive(Debug)]
 pub enum Error {
@@ -58,6 +152,8 @@ pub enum Error {
     LocalIntConfiguration(arch::x86_64::interrupts::Error),
     /// Cannot set the memory regions.
     SetUserMemoryRegion(io::Error),
+    /// Cannot get the log of the guest pages dirtied by the vCPUs.
+    GetDirtyLog(io::Error),
     #[cfg(target_arch = "x86_64")]
     /// Error configuring the MSR registers
     MSRSConfiguration(arch::x86_64::regs::Error),
@@ -77,8 +173,28 @@ pub enum Error {
     Irq(io::Error),
     /// Cannot spawn a new vCPU thread.
     VcpuSpawn(io::Error),
+    /// Cannot register the handler for the signal that kicks vCPUs out of KVM_RUN.
+    RegisterSignalHandler(io::Error),
     /// Unexpected KVM_RUN exit reason
     VcpuUnhandledKvmExit,
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot get the list of MSRs supported by KVM.
+    MsrIndexList(io::Error),
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot save the state of the VM.
+    SaveVmState(io::Error),
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot restore the state of the VM.
+    RestoreVmState(io::Error),
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot save the state of a vCPU.
+    SaveVcpuState(io::Error),
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot restore the state of a vCPU.
+    RestoreVcpuState(io::Error),
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot set the debugging features of a vCPU.
+    SetGuestDebug(io::Error),
     #[cfg(target_arch = "aarch64")]
     /// Error setting up the global interrupt controller.
     SetupGIC(arch::aarch64::gic::Error),
@@ -91,6 +207,62 @@ pub enum Error {
 }
 pub type Result<T> = result::Result<T, Error>;
 
+/// The state KVM keeps for a VM, which has to be saved alongside the guest memory in order to
+/// restore the microVM later on.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmKvmState {
+    #[serde(with = "::snapshot::pod")]
+    pic_master: kvm_irqchip,
+    #[serde(with = "::snapshot::pod")]
+    pic_slave: kvm_irqchip,
+    #[serde(with = "::snapshot::pod")]
+    ioapic: kvm_irqchip,
+    #[serde(with = "::snapshot::pod")]
+    pit: kvm_pit_state2,
+    #[serde(with = "::snapshot::pod")]
+    clock: kvm_clock_data,
+}
+
+/// The state KVM keeps for a vCPU.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VcpuKvmState {
+    #[serde(with = "::snapshot::pod_vec")]
+    cpuid: Vec<kvm_cpuid_entry2>,
+    #[serde(with = "::snapshot::pod_vec")]
+    msrs: Vec<kvm_msr_entry>,
+    #[serde(with = "::snapshot::pod")]
+    regs: kvm_regs,
+    #[serde(with = "::snapshot::pod")]
+    sregs: kvm_sregs,
+    #[serde(with = "::snapshot::pod")]
+    fpu: kvm_fpu,
+    #[serde(with = "::snapshot::pod")]
+    lapic: kvm_lapic_state,
+    #[serde(with = "::snapshot::pod")]
+    xsave: kvm_xsave,
+    #[serde(with = "::snapshot::pod")]
+    xcrs: kvm_xcrs,
+    #[serde(with = "::snapshot::pod")]
+    vcpu_events: kvm_vcpu_events,
+    #[serde(with = "::snapshot::pod")]
+    mp_state: kvm_mp_state,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl VcpuKvmState {
+    /// Returns the general purpose registers.
+    pub fn regs(&self) -> &kvm_regs {
+        &self.regs
+    }
+
+    /// Returns the special registers.
+    pub fn sregs(&self) -> &kvm_sregs {
+        &self.sregs
+    }
+}
+
 /// A wrapper around creating and using a VM.
 pub struct Vm {
     fd: VmFd,
@@ -99,6 +271,9 @@ pub struct Vm {
     // X86 specific fields.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     supported_cpuid: CpuId,
+    // The MSRs which are saved and restored along with the state of the vCPUs.
+    #[cfg(target_arch = "x86_64")]
+    msr_list: Vec<u32>,
 
     // Arm specific fields.
     // On aarch64 we need to keep around the fd obtained by creating the VGIC device.
@@ -115,10 +290,14 @@ impl Vm {
         let cpuid = kvm
             .get_supported_cpuid(MAX_KVM_CPUID_ENTRIES)
             .map_err(Error::VmFd)?;
+        #[cfg(target_arch = "x86_64")]
+        let msr_list = kvm.get_msr_index_list().map_err(Error::MsrIndexList)?;
         Ok(Vm {
             fd: vm_fd,
             #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
             supported_cpuid: cpuid,
+            #[cfg(target_arch = "x86_64")]
+            msr_list,
             guest_mem: None,
             #[cfg(target_arch = "aarch64")]
             irqchip_handle: None,
@@ -136,16 +315,34 @@ impl Vm {
         if guest_mem.num_regions() > kvm_context.max_memslots() {
             return Err(Error::NotEnoughMemorySlots);
         }
-        guest_mem
-            .with_regions(|index, guest_addr, size, host_addr| {
-                info!("Guest memory starts at {:x?}", host_addr);
+        guest_mem.with_regions::<_, Error>(|_, _, _, host_addr| {
+            info!("Guest memory starts at {:x?}", host_addr);
+            Ok(())
+        })?;
+        let log_dirty_pages = LOGGER.flags() & LogOption::LogDirtyPages as usize > 0;
+        Vm::set_user_memory_regions(&self.fd, &guest_mem, log_dirty_pages)?;
+        self.guest_mem = Some(guest_mem);
 
-                let flags = if LOGGER.flags() & LogOption::LogDirtyPages as usize > 0 {
-                    KVM_MEM_LOG_DIRTY_PAGES
-                } else {
-                    0
-                };
+        #[cfg(target_arch = "x86_64")]
+        self.fd
+            .set_tss_address(GuestAddress(arch::x86_64::layout::KVM_TSS_ADDRESS).offset())
+            .map_err(Error::VmSetup)?;
 
+        Ok(())
+    }
+
+    fn set_user_memory_regions(
+        fd: &VmFd,
+        guest_mem: &GuestMemory,
+        log_dirty_pages: bool,
+    ) -> Result<()> {
+        let flags = if log_dirty_pages {
+            KVM_MEM_LOG_DIRTY_PAGES
+        } else {
+            0
+        };
+        guest_mem
+            .with_regions(|index, guest_addr, size, host_addr| {
                 let memory_region = kvm_userspace_memory_region {
                     slot: index as u32,
                     guest_phys_addr: guest_addr.offset() as u64,
@@ -153,17 +350,37 @@ impl Vm {
                     userspace_addr: host_addr as u64,
                     flags,
                 };
-                self.fd.set_user_memory_region(memory_region)
+                fd.set_user_memory_region(memory_region)
             })
-            .map_err(Error::SetUserMemoryRegion)?;
-        self.guest_mem = Some(guest_mem);
+            .map_err(Error::SetUserMemoryRegion)
+    }
 
-        #[cfg(target_arch = "x86_64")]
-        self.fd
-            .set_tss_address(GuestAddress(arch::x86_64::layout::KVM_TSS_ADDRESS).offset())
-            .map_err(Error::VmSetup)?;
+    /// Turns KVM dirty page tracking on or off for the whole guest memory.
+    pub fn set_dirty_page_tracking(&self, enable: bool) -> Result<()> {
+        let guest_mem = self
+            .guest_mem
+            .as_ref()
+            .ok_or(Error::GuestMemory(GuestMemoryError::MemoryNotInitialized))?;
+        Vm::set_user_memory_regions(&self.fd, guest_mem, enable)
+    }
 
-        Ok(())
+    /// Returns, for each guest memory region, the bitmap of the pages dirtied since the previous
+    /// call. Dirty page tracking must be turned on.
+    pub fn get_dirty_bitmaps(&self) -> Result<Vec<Vec<u64>>> {
+        let guest_mem = self
+            .guest_mem
+            .as_ref()
+            .ok_or(Error::GuestMemory(GuestMemoryError::MemoryNotInitialized))?;
+        let mut bitmaps = Vec::with_capacity(guest_mem.num_regions());
+        guest_mem.with_regions_mut(|index, _, size, _| {
+            bitmaps.push(
+                self.fd
+                    .get_dirty_log(index as u32, size)
+                    .map_err(Error::GetDirtyLog)?,
+            );
+            Ok(())
+        })?;
+        Ok(bitmaps)
     }
 
     /// This function creates the irq chip and adds 3 interrupt events to the IRQ.
@@ -221,17 +438,240 @@ impl Vm {
     pub fn get_fd(&self) -> &VmFd {
         &self.fd
     }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_irqchip(&self, chip_id: u32) -> Result<kvm_irqchip> {
+        // Safe because `kvm_irqchip` is a plain old data structure.
+        let mut irqchip: kvm_irqchip = unsafe { std::mem::zeroed() };
+        irqchip.chip_id = chip_id;
+        kvm_get(&self.fd, KVM_GET_IRQCHIP(), &mut irqchip).map_err(Error::SaveVmState)?;
+        Ok(irqchip)
+    }
+
+    /// Saves the state of the in-kernel interrupt controllers, PIT and clock.
+    ///
+    /// The vCPUs must not be running while the state is saved.
+    #[cfg(target_arch = "x86_64")]
+    pub fn save_state(&self) -> Result<VmKvmState> {
+        let mut pit = kvm_pit_state2::default();
+        kvm_get(&self.fd, KVM_GET_PIT2(), &mut pit).map_err(Error::SaveVmState)?;
+        let mut clock = kvm_clock_data::default();
+        kvm_get(&self.fd, KVM_GET_CLOCK(), &mut clock).map_err(Error::SaveVmState)?;
+
+        Ok(VmKvmState {
+            pic_master: self.get_irqchip(KVM_IRQCHIP_PIC_MASTER)?,
+            pic_slave: self.get_irqchip(KVM_IRQCHIP_PIC_SLAVE)?,
+            ioapic: self.get_irqchip(KVM_IRQCHIP_IOAPIC)?,
+            pit,
+            clock,
+        })
+    }
+
+    /// Restores a state saved by `save_state`.
+    ///
+    /// The interrupt controllers and the PIT must have already been created.
+    #[cfg(target_arch = "x86_64")]
+    pub fn restore_state(&self, state: &VmKvmState) -> Result<()> {
+        for irqchip in &[state.pic_master, state.pic_slave, state.ioapic] {
+            kvm_set(&self.fd, KVM_SET_IRQCHIP(), irqchip).map_err(Error::RestoreVmState)?;
+        }
+        kvm_set(&self.fd, KVM_SET_PIT2(), &state.pit).map_err(Error::RestoreVmState)?;
+
+        // The flags returned by KVM_GET_CLOCK only describe the clock source of the host the
+        // state was saved on, and older kernels refuse any flags on KVM_SET_CLOCK.
+        let mut clock = state.clock;
+        clock.flags = 0;
+        kvm_set(&self.fd, KVM_SET_CLOCK(), &clock).map_err(Error::RestoreVmState)?;
+        Ok(())
+    }
+}
+
+/// Why a vCPU stopped running guest code on its own.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VcpuExitReason {
+    /// The guest powered off.
+    Poweroff,
+    /// The guest asked for a reboot.
+    Reboot,
+    /// The vCPU hit an error it cannot recover from.
+    Error,
+}
+
+#[derive(Default)]
+struct PauseState {
+    paused: bool,
+    parked: usize,
+    // The states of the parked vCPUs, indexed by vCPU id.
+    #[cfg(target_arch = "x86_64")]
+    vcpu_states: BTreeMap<u8, VcpuKvmState>,
+    // The states the vCPUs load when they get resumed, indexed by vCPU id.
+    #[cfg(target_arch = "x86_64")]
+    reset_states: BTreeMap<u8, VcpuKvmState>,
+    // The first reason a vCPU gave for stopping, if any.
+    exit_reason: Option<VcpuExitReason>,
+}
+
+/// Coordinates parking the vCPU threads outside of `KVM_RUN` while the microVM is paused.
+///
+/// The VMM thread requests a pause and then kicks every vCPU thread with the signal at
+/// `VCPU_RTSIG_OFFSET`. Each vCPU thread checks for a pending pause request before re-entering
+/// `KVM_RUN` and, if there is one, saves its state and parks itself until the VMM resumes it.
+#[derive(Default)]
+pub struct VcpuPauseControl {
+    // Fast path flag checked by the vCPU threads before each `KVM_RUN`.
+    pause_requested: AtomicBool,
+    state: Mutex<PauseState>,
+    cvar: Condvar,
+}
+
+impl VcpuPauseControl {
+    /// Asks the vCPU threads to park themselves before their next `KVM_RUN`.
+    pub fn request_pause(&self) {
+        // Use expect() to crash if another thread poisoned this lock.
+        self.state.lock().expect("Poisoned vCPU pause lock").paused = true;
+        self.pause_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Waits at most `timeout` for `count` vCPU threads to be parked. Returns whether
+    /// they are all parked.
+    pub fn wait_parked(&self, count: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().expect("Poisoned vCPU pause lock");
+        while state.parked < count {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self
+                .cvar
+                .wait_timeout(state, deadline - now)
+                .expect("Poisoned vCPU pause lock")
+                .0;
+        }
+        true
+    }
+
+    /// Lets the parked vCPU threads go back to running guest code.
+    pub fn resume(&self) {
+        self.pause_requested.store(false, Ordering::SeqCst);
+        let mut state = self.state.lock().expect("Poisoned vCPU pause lock");
+        state.paused = false;
+        // The saved states become stale as soon as the vCPUs run again.
+        #[cfg(target_arch = "x86_64")]
+        state.vcpu_states.clear();
+        self.cvar.notify_all();
+    }
+
+    /// Makes the parked vCPUs load `vcpu_states` before they go back to running guest code.
+    /// This is how the vCPUs are reset when the guest reboots.
+    #[cfg(target_arch = "x86_64")]
+    pub fn reset_vcpus_on_resume(&self, vcpu_states: &[VcpuKvmState]) {
+        let mut state = self.state.lock().expect("Poisoned vCPU pause lock");
+        state.reset_states = vcpu_states
+            .iter()
+            .enumerate()
+            .map(|(id, vcpu_state)| (id as u8, vcpu_state.clone()))
+            .collect();
+    }
+
+    /// Returns why the vCPUs stopped on their own, if they did, and forgets about it.
+    pub fn take_exit_reason(&self) -> Option<VcpuExitReason> {
+        self.state
+            .lock()
+            .expect("Poisoned vCPU pause lock")
+            .exit_reason
+            .take()
+    }
+
+    /// Returns the states the parked vCPUs saved, indexed by vCPU id. A vCPU which failed to
+    /// save its state is missing from the map.
+    #[cfg(target_arch = "x86_64")]
+    pub fn vcpu_states(&self) -> BTreeMap<u8, VcpuKvmState> {
+        self.state
+            .lock()
+            .expect("Poisoned vCPU pause lock")
+            .vcpu_states
+            .clone()
+    }
+
+    // Called from the vCPU thread right before parking.
+    #[cfg(target_arch = "x86_64")]
+    fn store_vcpu_state(&self, id: u8, vcpu_state: VcpuKvmState) {
+        self.state
+            .lock()
+            .expect("Poisoned vCPU pause lock")
+            .vcpu_states
+            .insert(id, vcpu_state);
+    }
+
+    // Called from the vCPU thread once it got resumed.
+    #[cfg(target_arch = "x86_64")]
+    fn take_reset_state(&self, id: u8) -> Option<VcpuKvmState> {
+        self.state
+            .lock()
+            .expect("Poisoned vCPU pause lock")
+            .reset_states
+            .remove(&id)
+    }
+
+    // Called from the vCPU thread when it stops on its own. Only the first reason is kept,
+    // since the others are usually a consequence of it.
+    fn record_exit(&self, reason: VcpuExitReason) {
+        let mut state = self.state.lock().expect("Poisoned vCPU pause lock");
+        if state.exit_reason.is_none() {
+            state.exit_reason = Some(reason);
+        }
+    }
+
+    // Called from the vCPU thread. Blocks for as long as the pause request stands.
+    fn park(&self) {
+        let mut state = self.state.lock().expect("Poisoned vCPU pause lock");
+        state.parked += 1;
+        self.cvar.notify_all();
+        while state.paused {
+            state = self.cvar.wait(state).expect("Poisoned vCPU pause lock");
+        }
+        state.parked -= 1;
+    }
+}
+
+// The outcome of a successful `KVM_RUN`.
+#[derive(Debug, PartialEq)]
+enum VcpuEmulation {
+    // The exit was handled and the vCPU can go back into KVM_RUN.
+    Handled,
+    // KVM_RUN was interrupted by a signal, with no pending exit left to complete.
+    Interrupted,
+    // The guest stopped the vCPU, which must not go back into KVM_RUN until the VMM decides
+    // what to do about it.
+    Stopped(VcpuExitReason),
+    // The vCPU hit a breakpoint or completed a single step, for the GDB server to handle.
+    #[cfg(target_arch = "x86_64")]
+    DebugExit,
 }
 
 /// A wrapper around creating and using a kvm-based VCPU.
 pub struct Vcpu {
     #[cfg(target_arch = "x86_64")]
     cpuid: CpuId,
+    #[cfg(target_arch = "x86_64")]
+    msr_list: Vec<u32>,
     fd: VcpuFd,
     id: u8,
     io_bus: devices::Bus,
     mmio_bus: devices::Bus,
     create_ts: TimestampUs,
+    #[cfg(target_arch = "x86_64")]
+    debugger: Option<Arc<Debugger>>,
+    metrics: Arc<PerVcpuMetrics>,
+}
+
+// Converts `duration` to nanoseconds, saturating at `usize::MAX`.
+fn duration_to_ns(duration: Duration) -> usize {
+    duration
+        .as_secs()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(u64::from(duration.subsec_nanos())) as usize
 }
 
 impl Vcpu {
@@ -254,14 +694,26 @@ impl Vcpu {
         Ok(Vcpu {
             #[cfg(target_arch = "x86_64")]
             cpuid: vm.get_supported_cpuid(),
+            #[cfg(target_arch = "x86_64")]
+            msr_list: vm.msr_list.clone(),
             fd: kvm_vcpu,
             id,
             io_bus,
             mmio_bus,
             create_ts,
+            #[cfg(target_arch = "x86_64")]
+            debugger: None,
+            metrics: METRICS.per_vcpu.register(id),
         })
     }
 
+    /// Hands the vCPU over to the GDB server behind `debugger`. Debug exits are fatal
+    /// otherwise.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_debugger(&mut self, debugger: Arc<Debugger>) {
+        self.debugger = Some(debugger);
+    }
+
     #[cfg(target_arch = "x86_64")]
     /// Configures a x86_64 specific vcpu and should be called once per vcpu from the vcpu's thread.
     ///
@@ -348,13 +800,253 @@ impl Vcpu {
         Ok(())
     }
 
-    fn run_emulation(&mut self) -> Result<()> {
-        match self.fd.run() {
+    #[cfg(target_arch = "x86_64")]
+    fn save_msrs(&self) -> Result<Vec<kvm_msr_entry>> {
+        let mut saved = Vec::with_capacity(self.msr_list.len());
+        let mut remaining = &self.msr_list[..];
+        while !remaining.is_empty() {
+            let entries: Vec<kvm_msr_entry> = remaining
+                .iter()
+                .map(|&index| kvm_msr_entry {
+                    index,
+                    ..Default::default()
+                })
+                .collect();
+            let mut msrs = kvm_msrs_from_entries(&entries);
+            let read = self
+                .fd
+                .get_msrs(&mut msrs[0])
+                .map_err(Error::SaveVcpuState)? as usize;
+            // Safe because `msrs` was built to hold `remaining.len()` entries.
+            saved.extend_from_slice(unsafe { &msrs[0].entries.as_slice(remaining.len())[..read] });
+            // KVM stops at the first MSR it cannot read. Skip that one and carry on.
+            remaining = &remaining[std::cmp::min(read + 1, remaining.len())..];
+        }
+        Ok(saved)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn restore_msrs(&self, entries: &[kvm_msr_entry]) -> Result<()> {
+        let mut remaining = entries;
+        while !remaining.is_empty() {
+            let msrs = kvm_msrs_from_entries(remaining);
+            let written = kvm_set(&self.fd, KVM_SET_MSRS(), &msrs[0])
+                .map_err(Error::RestoreVcpuState)? as usize;
+            // KVM stops at the first MSR it cannot write, which happens for the read-only ones.
+            if written < remaining.len() {
+                warn!(
+                    "Could not restore MSR {:#x} on vCPU {}",
+                    remaining[written].index, self.id
+                );
+            }
+            remaining = &remaining[std::cmp::min(written + 1, remaining.len())..];
+        }
+        Ok(())
+    }
+
+    /// Saves the state KVM keeps for this vCPU.
+    ///
+    /// Must be called from the vCPU thread, while it is out of KVM_RUN and has no pending exit.
+    #[cfg(target_arch = "x86_64")]
+    pub fn save_state(&self) -> Result<VcpuKvmState> {
+        let mut xsave = kvm_xsave { region: [0; 1024] };
+        kvm_get(&self.fd, KVM_GET_XSAVE(), &mut xsave).map_err(Error::SaveVcpuState)?;
+        let mut xcrs = kvm_xcrs::default();
+        kvm_get(&self.fd, KVM_GET_XCRS(), &mut xcrs).map_err(Error::SaveVcpuState)?;
+        let mut vcpu_events = kvm_vcpu_events::default();
+        kvm_get(&self.fd, KVM_GET_VCPU_EVENTS(), &mut vcpu_events).map_err(Error::SaveVcpuState)?;
+        let mut mp_state = kvm_mp_state::default();
+        kvm_get(&self.fd, KVM_GET_MP_STATE(), &mut mp_state).map_err(Error::SaveVcpuState)?;
+
+        Ok(VcpuKvmState {
+            cpuid: self.cpuid.clone().mut_entries_slice().to_vec(),
+            msrs: self.save_msrs()?,
+            regs: self.fd.get_regs().map_err(Error::SaveVcpuState)?,
+            sregs: self.fd.get_sregs().map_err(Error::SaveVcpuState)?,
+            fpu: self.fd.get_fpu().map_err(Error::SaveVcpuState)?,
+            lapic: self.fd.get_lapic().map_err(Error::SaveVcpuState)?,
+            xsave,
+            xcrs,
+            vcpu_events,
+            mp_state,
+        })
+    }
+
+    /// Restores a state saved by `save_state`. Takes the place of `configure` when the
+    /// microVM is loaded from a snapshot.
+    #[cfg(target_arch = "x86_64")]
+    pub fn restore_state(&mut self, state: &VcpuKvmState) -> Result<()> {
+        self.cpuid = CpuId::from_entries(&state.cpuid);
+        self.fd
+            .set_cpuid2(&self.cpuid)
+            .map_err(Error::SetSupportedCpusFailed)?;
+        self.restore_registers(state)
+    }
+
+    // Restores everything but the CPUID. KVM refuses to change the CPUID of a vCPU that has
+    // already run, and it stays the same across a guest reboot anyway.
+    #[cfg(target_arch = "x86_64")]
+    fn restore_registers(&self, state: &VcpuKvmState) -> Result<()> {
+        self.restore_msrs(&state.msrs)?;
+        self.fd
+            .set_regs(&state.regs)
+            .map_err(Error::RestoreVcpuState)?;
+        self.fd
+            .set_sregs(&state.sregs)
+            .map_err(Error::RestoreVcpuState)?;
+        self.fd
+            .set_fpu(&state.fpu)
+            .map_err(Error::RestoreVcpuState)?;
+        kvm_set(&self.fd, KVM_SET_XSAVE(), &state.xsave).map_err(Error::RestoreVcpuState)?;
+        kvm_set(&self.fd, KVM_SET_XCRS(), &state.xcrs).map_err(Error::RestoreVcpuState)?;
+        self.fd
+            .set_lapic(&state.lapic)
+            .map_err(Error::RestoreVcpuState)?;
+        kvm_set(&self.fd, KVM_SET_VCPU_EVENTS(), &state.vcpu_events)
+            .map_err(Error::RestoreVcpuState)?;
+        // A vCPU which was halted when the guest rebooted would otherwise stay halted.
+        kvm_set(&self.fd, KVM_SET_MP_STATE(), &state.mp_state).map_err(Error::RestoreVcpuState)?;
+        Ok(())
+    }
+
+    // Saves the vCPU state and parks the vCPU thread until the VMM resumes it. Loads the reset
+    // state, if the VMM left one for this vCPU in the meantime.
+    fn park(&mut self, pause_control: &VcpuPauseControl) -> Result<()> {
+        #[cfg(target_arch = "x86_64")]
+        match self.save_state() {
+            Ok(state) => pause_control.store_vcpu_state(self.id, state),
+            Err(e) => error!("Failed to save the state of vCPU {}: {:?}", self.id, e),
+        }
+        pause_control.park();
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(state) = pause_control.take_reset_state(self.id) {
+                if let Err(e) = self.restore_registers(&state) {
+                    METRICS.vcpu.failures.inc();
+                    error!("Failed to reset vCPU {}: {:?}", self.id, e);
+                    pause_control.record_exit(VcpuExitReason::Error);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Parks the vCPU thread until the GDB server lets it run again, reading and writing the
+    // registers on behalf of the server in the meantime. Then applies the debugging features
+    // the server asks for, such as breakpoints and single-stepping.
+    #[cfg(target_arch = "x86_64")]
+    fn debug_park(&self, debugger: &Debugger) -> Result<()> {
+        debugger.park(self.id, |request| {
+            if let VcpuRequest::SetRegisters(regs) = request {
+                self.fd.set_regs(&regs)?;
+            }
+            Ok(VcpuRegisters {
+                regs: self.fd.get_regs()?,
+                sregs: self.fd.get_sregs()?,
+            })
+        });
+        self.set_guest_debug(&debugger.guest_debug(self.id, false))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_guest_debug(&self, debug: &kvm_guest_debug) -> Result<()> {
+        kvm_set(&self.fd, KVM_SET_GUEST_DEBUG(), debug).map_err(Error::SetGuestDebug)?;
+        Ok(())
+    }
+
+    // Hands a debug exit over to the GDB server, which either parks the vCPU or has the
+    // breakpoint exception delivered to the guest, when the guest hit an `int3` of its own.
+    #[cfg(target_arch = "x86_64")]
+    fn handle_debug_exit(&self, debugger: &Debugger) -> Result<()> {
+        let rip = self.fd.get_regs().map_err(Error::VcpuRun)?.rip;
+        if debugger.handle_debug_exit(self.id, rip) {
+            self.debug_park(debugger)
+        } else {
+            self.set_guest_debug(&debugger.guest_debug(self.id, true))
+        }
+    }
+
+    /// Registers a no-op handler for the signal used to kick vCPU threads out of KVM_RUN.
+    /// Signal handlers are process-wide, so this only needs to be called once, before any vCPU
+    /// thread is signaled.
+    pub fn register_kick_signal_handler() -> Result<()> {
+        extern "C" fn handle_signal(_: c_int, _: *mut siginfo_t, _: *mut c_void) {}
+
+        // This is safe because the handler does nothing, which makes it async-signal-safe.
+        unsafe {
+            register_signal_handler(
+                VCPU_RTSIG_OFFSET,
+                SignalHandler::Siginfo(handle_signal),
+                true,
+            )
+        }
+        .map_err(Error::RegisterSignalHandler)
+    }
+
+    // Counts a KVM exit in the metrics of this vCPU.
+    fn count_exit(&self, exit: &VcpuExit) {
+        let metric = match *exit {
+            VcpuExit::IoIn(..) => &self.metrics.exit_io_in,
+            VcpuExit::IoOut(..) => &self.metrics.exit_io_out,
+            VcpuExit::MmioRead(..) => &self.metrics.exit_mmio_read,
+            VcpuExit::MmioWrite(..) => &self.metrics.exit_mmio_write,
+            VcpuExit::Unknown => &self.metrics.exit_unknown,
+            VcpuExit::Exception => &self.metrics.exit_exception,
+            VcpuExit::Hypercall => &self.metrics.exit_hypercall,
+            VcpuExit::Debug => &self.metrics.exit_debug,
+            VcpuExit::Hlt => &self.metrics.exit_hlt,
+            VcpuExit::IrqWindowOpen => &self.metrics.exit_irq_window_open,
+            VcpuExit::Shutdown => &self.metrics.exit_shutdown,
+            VcpuExit::FailEntry => &self.metrics.exit_fail_entry,
+            VcpuExit::Intr => &self.metrics.exit_intr,
+            VcpuExit::SetTpr => &self.metrics.exit_set_tpr,
+            VcpuExit::TprAccess => &self.metrics.exit_tpr_access,
+            VcpuExit::S390Sieic => &self.metrics.exit_s390_sieic,
+            VcpuExit::S390Reset => &self.metrics.exit_s390_reset,
+            VcpuExit::Dcr => &self.metrics.exit_dcr,
+            VcpuExit::Nmi => &self.metrics.exit_nmi,
+            VcpuExit::InternalError => &self.metrics.exit_internal_error,
+            VcpuExit::Osi => &self.metrics.exit_osi,
+            VcpuExit::PaprHcall => &self.metrics.exit_papr_hcall,
+            VcpuExit::S390Ucontrol => &self.metrics.exit_s390_ucontrol,
+            VcpuExit::Watchdog => &self.metrics.exit_watchdog,
+            VcpuExit::S390Tsch => &self.metrics.exit_s390_tsch,
+            VcpuExit::Epr => &self.metrics.exit_epr,
+            VcpuExit::SystemEvent => &self.metrics.exit_system_event,
+            VcpuExit::S390Stsi => &self.metrics.exit_s390_stsi,
+            VcpuExit::IoapicEoi => &self.metrics.exit_ioapic_eoi,
+            VcpuExit::Hyperv => &self.metrics.exit_hyperv,
+        };
+        metric.inc();
+    }
+
+    fn run_emulation(&mut self) -> Result<VcpuEmulation> {
+        let run_start = Instant::now();
+        let run = self.fd.run();
+        let emulation_start = Instant::now();
+        self.metrics
+            .kvm_run_ns
+            .add(duration_to_ns(emulation_start - run_start));
+
+        let result = self.handle_exit(run);
+        self.metrics
+            .emulation_ns
+            .add(duration_to_ns(emulation_start.elapsed()));
+        result
+    }
+
+    fn handle_exit(&self, run: result::Result<VcpuExit, io::Error>) -> Result<VcpuEmulation> {
+        if let Ok(ref exit) = run {
+            self.count_exit(exit);
+        }
+        match run {
             Ok(run) => match run {
                 VcpuExit::IoIn(addr, data) => {
                     self.io_bus.read(u64::from(addr), data);
                     METRICS.vcpu.exit_io_in.inc();
-                    Ok(())
+                    Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::IoOut(addr, data) => {
                     if addr == MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE
@@ -364,25 +1056,26 @@ impl Vcpu {
                     }
                     self.io_bus.write(u64::from(addr), data);
                     METRICS.vcpu.exit_io_out.inc();
-                    Ok(())
+                    Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioRead(addr, data) => {
                     self.mmio_bus.read(addr, data);
                     METRICS.vcpu.exit_mmio_read.inc();
-                    Ok(())
+                    Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioWrite(addr, data) => {
                     self.mmio_bus.write(addr, data);
                     METRICS.vcpu.exit_mmio_write.inc();
-                    Ok(())
+                    Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::Hlt => {
                     info!("Received KVM_EXIT_HLT signal");
-                    Err(Error::VcpuUnhandledKvmExit)
+                    Ok(VcpuEmulation::Stopped(VcpuExitReason::Poweroff))
                 }
+                // A triple fault, which is also how some guests reboot.
                 VcpuExit::Shutdown => {
                     info!("Received KVM_EXIT_SHUTDOWN signal");
-                    Err(Error::VcpuUnhandledKvmExit)
+                    Ok(VcpuEmulation::Stopped(VcpuExitReason::Reboot))
                 }
                 // Documentation specifies that below kvm exits are considered
                 // errors.
@@ -396,6 +1089,9 @@ impl Vcpu {
                     error!("Received KVM_EXIT_INTERNAL_ERROR signal");
                     Err(Error::VcpuUnhandledKvmExit)
                 }
+                // Debug exits only happen when the GDB server enabled them.
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::Debug if self.debugger.is_some() => Ok(VcpuEmulation::DebugExit),
                 r => {
                     METRICS.vcpu.failures.inc();
                     // TODO: Are we sure we want to finish running a vcpu upon
@@ -409,8 +1105,12 @@ impl Vcpu {
             Err(ref e) => {
                 match e.raw_os_error().unwrap() {
                     // Why do we check for these if we only return EINVAL?
-                    libc::EAGAIN | libc::EINTR => Ok(()),
+                    libc::EAGAIN | libc::EINTR => {
+                        self.metrics.run_interrupted.inc();
+                        Ok(VcpuEmulation::Interrupted)
+                    }
                     _ => {
+                        self.metrics.run_failures.inc();
                         METRICS.vcpu.failures.inc();
                         error!("Failure during vcpu run: {}", e);
                         Err(Error::VcpuUnhandledKvmExit)
@@ -424,7 +1124,14 @@ impl Vcpu {
     ///
     ///
     /// Runs the vCPU in KVM context in a loop. Handles KVM_EXITs then goes back in.
-    /// Also registers a signal handler to be able to kick this thread out of KVM_RUN.
+    /// When KVM_RUN gets interrupted while `pause_control` holds a pending pause request, the
+    /// thread saves the vCPU state and parks itself. The VMM kicks this thread out of KVM_RUN
+    /// with the `VCPU_RTSIG_OFFSET` signal.
+    /// When the guest powers off or reboots, the thread records the reason in `pause_control`,
+    /// signals `vcpu_exit_evt` and parks itself. If the VMM resets the vCPUs before resuming
+    /// them, the thread loads the reset state first.
+    /// With a debugger set, the thread also parks itself when the GDB server stops the vCPUs,
+    /// starting before the first KVM_RUN, and on debug exits.
     /// Note that the state of the VCPU and associated VM must be setup first for this to do
     /// anything useful.
     pub fn run(
@@ -432,6 +1139,7 @@ impl Vcpu {
         thread_barrier: Arc<Barrier>,
         seccomp_level: u32,
         vcpu_exit_evt: EventFd,
+        pause_control: Arc<VcpuPauseControl>,
     ) {
         // Load seccomp filters for this vCPU thread.
         // Execution panics if filters cannot be loaded, use --seccomp-level=0 if skipping filters
@@ -445,7 +1153,76 @@ impl Vcpu {
 
         thread_barrier.wait();
 
-        while self.run_emulation().is_ok() {}
+        #[cfg(target_arch = "x86_64")]
+        let debugger = self.debugger.clone();
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(ref debugger) = debugger {
+                debugger.register_vcpu(self.id);
+                if debugger.stop_requested() {
+                    if let Err(e) = self.debug_park(debugger) {
+                        error!("Failed to debug vCPU {}: {:?}", self.id, e);
+                    }
+                }
+            }
+        }
+
+        loop {
+            match self.run_emulation() {
+                Ok(VcpuEmulation::Handled) => (),
+                // Only park after an interrupted KVM_RUN, so that no MMIO or PIO exit is left
+                // half way through when the vCPU state is saved.
+                Ok(VcpuEmulation::Interrupted) => {
+                    if pause_control.pause_requested.load(Ordering::SeqCst)
+                        && self.park(&pause_control).is_err()
+                    {
+                        break;
+                    }
+                    #[cfg(target_arch = "x86_64")]
+                    {
+                        if let Some(ref debugger) = debugger {
+                            if debugger.stop_requested() {
+                                if let Err(e) = self.debug_park(debugger) {
+                                    error!("Failed to debug vCPU {}: {:?}", self.id, e);
+                                }
+                            }
+                        }
+                    }
+                }
+                #[cfg(target_arch = "x86_64")]
+                Ok(VcpuEmulation::DebugExit) => {
+                    // Only set along with the debugger.
+                    if let Some(ref debugger) = debugger {
+                        if let Err(e) = self.handle_debug_exit(debugger) {
+                            error!("Failed to debug vCPU {}: {:?}", self.id, e);
+                        }
+                    }
+                }
+                Ok(VcpuEmulation::Stopped(reason)) => {
+                    // Stop the other vCPUs as well, so the VMM finds the microVM paused.
+                    pause_control.request_pause();
+                    pause_control.record_exit(reason);
+                    if let Err(e) = vcpu_exit_evt.write(1) {
+                        METRICS.vcpu.failures.inc();
+                        error!("Failed signaling vcpu exit event: {}", e);
+                    }
+                    if self.park(&pause_control).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => {
+                    pause_control.record_exit(VcpuExitReason::Error);
+                    break;
+                }
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(ref debugger) = debugger {
+                debugger.unregister_vcpu(self.id);
+            }
+        }
 
         // Nothing we need do for the success case.
         if let Err(e) = vcpu_exit_evt.write(1) {
@@ -463,13 +1240,15 @@ mod tests {
     use super::super::devices;
     use super::*;
 
-    use libc::{c_int, c_void, siginfo_t};
-    use sys_util::{register_signal_handler, Killable, SignalHandler};
+    #[cfg(target_arch = "x86_64")]
+    use kvm_bindings::KVM_MP_STATE_HALTED;
+    use memory_model::MemoryBacking;
+    use sys_util::Killable;
 
     // Auxiliary function being used throughout the tests.
     fn setup_vcpu() -> (Vm, Vcpu) {
         let kvm = KvmContext::new().unwrap();
-        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let mut vm = Vm::new(kvm.fd()).expect("Cannot create new vm");
         assert!(vm.memory_init(gm, &kvm).is_ok());
 
@@ -520,7 +1299,7 @@ mod tests {
     #[test]
     fn test_vm_memory_init_success() {
         let kvm = KvmContext::new().unwrap();
-        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)], MemoryBacking::Anonymous).unwrap();
         let mut vm = Vm::new(kvm.fd()).expect("Cannot create new vm");
         assert!(vm.memory_init(gm, &kvm).is_ok());
         let obj_addr = GuestAddress(0xf0);
@@ -547,7 +1326,11 @@ mod tests {
         };
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
-        let gm = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        let gm = GuestMemory::new(
+            &[(start_addr1, 0x1000), (start_addr2, 0x1000)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
 
         assert!(vm.memory_init(gm, &kvm).is_err());
     }
@@ -664,7 +1447,7 @@ mod tests {
     #[test]
     fn test_configure_vcpu() {
         let kvm = KvmContext::new().unwrap();
-        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)], MemoryBacking::Anonymous).unwrap();
         let mut vm = Vm::new(kvm.fd()).expect("new vm failed");
         assert!(vm.memory_init(gm, &kvm).is_ok());
 
@@ -721,10 +1504,18 @@ mod tests {
         let vcpu_exit_evt = exit_evt.try_clone().expect("eventfd clone failed");
         let seccomp_level = 0;
 
+        let pause_control = Arc::new(VcpuPauseControl::default());
+        let vcpu_pause_control = pause_control.clone();
+
         let thread = thread::Builder::new()
             .name("fc_vcpu0".to_string())
             .spawn(move || {
-                vcpu.run(vcpu_thread_barrier, seccomp_level, vcpu_exit_evt);
+                vcpu.run(
+                    vcpu_thread_barrier,
+                    seccomp_level,
+                    vcpu_exit_evt,
+                    vcpu_pause_control,
+                );
             })
             .expect("failed to spawn thread ");
 
@@ -742,6 +1533,167 @@ mod tests {
         // Validate vcpu handled the EINTR gracefully and didn't exit.
         let err = exit_evt.read().unwrap_err();
         assert_eq!(err.raw_os_error().unwrap(), libc::EAGAIN);
+
+        // Pause the vcpu: it should park itself after being kicked out of KVM_RUN.
+        pause_control.request_pause();
+        thread.kill(signum).expect("failed to signal thread");
+        assert!(pause_control.wait_parked(1, Duration::from_secs(1)));
+        // The vCPU saved its state before parking.
+        assert!(pause_control.vcpu_states().contains_key(&1));
+
+        // Resume it and make sure it's no longer parked.
+        pause_control.resume();
+        thread::sleep(Duration::from_millis(100));
+        assert!(!pause_control.wait_parked(1, Duration::from_millis(0)));
+        assert!(pause_control.vcpu_states().is_empty());
+        let err = exit_evt.read().unwrap_err();
+        assert_eq!(err.raw_os_error().unwrap(), libc::EAGAIN);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_save_restore_state() {
+        let (vm, mut vcpu) = setup_vcpu();
+        let vm_config = VmConfig::default();
+        assert!(vcpu.configure(&vm_config, GuestAddress(0), &vm).is_ok());
+
+        let vm_state = vm.save_state().unwrap();
+        let vcpu_state = vcpu.save_state().unwrap();
+        assert!(!vcpu_state.msrs.is_empty());
+
+        let (other_vm, mut other_vcpu) = setup_vcpu();
+        other_vm.restore_state(&vm_state).unwrap();
+        other_vcpu.restore_state(&vcpu_state).unwrap();
+
+        let restored_vm_state = other_vm.save_state().unwrap();
+        // The load times of the PIT channels are host timestamps, so they cannot match.
+        for (restored, saved) in restored_vm_state
+            .pit
+            .channels
+            .iter()
+            .zip(vm_state.pit.channels.iter())
+        {
+            assert_eq!(restored.count, saved.count);
+            assert_eq!(restored.mode, saved.mode);
+            assert_eq!(restored.gate, saved.gate);
+        }
+        let restored_vcpu_state = other_vcpu.save_state().unwrap();
+        assert_eq!(restored_vcpu_state.regs, vcpu_state.regs);
+        assert_eq!(restored_vcpu_state.sregs, vcpu_state.sregs);
+        assert_eq!(restored_vcpu_state.xcrs, vcpu_state.xcrs);
+        assert_eq!(restored_vcpu_state.cpuid, vcpu_state.cpuid);
+        assert_eq!(restored_vcpu_state.mp_state, vcpu_state.mp_state);
+        // Restoring skips the read-only MSRs, so only check that a well known one made it.
+        let msr_value = |state: &VcpuKvmState, index: u32| {
+            state
+                .msrs
+                .iter()
+                .find(|entry| entry.index == index)
+                .map(|entry| entry.data)
+        };
+        // MSR_IA32_SYSENTER_CS
+        assert_eq!(
+            msr_value(&restored_vcpu_state, 0x174),
+            msr_value(&vcpu_state, 0x174)
+        );
+    }
+
+    #[test]
+    fn test_vcpu_pause_control() {
+        let pause_control = Arc::new(VcpuPauseControl::default());
+        assert!(!pause_control.wait_parked(1, Duration::from_millis(0)));
+
+        pause_control.request_pause();
+        let vcpu_pause_control = pause_control.clone();
+        let thread = thread::spawn(move || vcpu_pause_control.park());
+
+        assert!(pause_control.wait_parked(1, Duration::from_secs(1)));
+        pause_control.resume();
+        thread.join().unwrap();
+        assert!(!pause_control.wait_parked(1, Duration::from_millis(0)));
+    }
+
+    #[test]
+    fn test_vcpu_exit_metrics() {
+        let (_vm, mut vcpu) = setup_vcpu();
+        vcpu.metrics = Arc::new(PerVcpuMetrics::default());
+
+        let mut data = [0u8; 4];
+        assert!(vcpu
+            .handle_exit(Ok(VcpuExit::IoIn(0x1000, &mut data)))
+            .is_ok());
+        assert!(vcpu.handle_exit(Ok(VcpuExit::Hlt)).is_ok());
+        // Exits which are not handled still get counted.
+        assert!(vcpu.handle_exit(Ok(VcpuExit::Nmi)).is_err());
+        assert!(vcpu
+            .handle_exit(Err(io::Error::from_raw_os_error(libc::EINTR)))
+            .is_ok());
+        assert!(vcpu
+            .handle_exit(Err(io::Error::from_raw_os_error(libc::EINVAL)))
+            .is_err());
+
+        assert_eq!(vcpu.metrics.exit_io_in.count(), 1);
+        assert_eq!(vcpu.metrics.exit_hlt.count(), 1);
+        assert_eq!(vcpu.metrics.exit_nmi.count(), 1);
+        assert_eq!(vcpu.metrics.exit_shutdown.count(), 0);
+        assert_eq!(vcpu.metrics.run_interrupted.count(), 1);
+        assert_eq!(vcpu.metrics.run_failures.count(), 1);
+
+        assert_eq!(duration_to_ns(Duration::new(2, 5)), 2_000_000_005);
+    }
+
+    #[test]
+    fn test_vcpu_exit_reason() {
+        let pause_control = VcpuPauseControl::default();
+        assert!(pause_control.take_exit_reason().is_none());
+
+        // Only the first reason is kept.
+        pause_control.record_exit(VcpuExitReason::Reboot);
+        pause_control.record_exit(VcpuExitReason::Error);
+        assert_eq!(
+            pause_control.take_exit_reason(),
+            Some(VcpuExitReason::Reboot)
+        );
+        assert!(pause_control.take_exit_reason().is_none());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_reset_vcpu_on_resume() {
+        let (vm, mut vcpu) = setup_vcpu();
+        let vm_config = VmConfig::default();
+        assert!(vcpu.configure(&vm_config, GuestAddress(0), &vm).is_ok());
+        let power_on_state = vcpu.save_state().unwrap();
+
+        let mut regs = vcpu.fd.get_regs().unwrap();
+        regs.rip += 0x1000;
+        vcpu.fd.set_regs(&regs).unwrap();
+        let halted = kvm_mp_state {
+            mp_state: KVM_MP_STATE_HALTED,
+        };
+        kvm_set(&vcpu.fd, KVM_SET_MP_STATE(), &halted).unwrap();
+
+        let pause_control = Arc::new(VcpuPauseControl::default());
+        pause_control.request_pause();
+        // The vCPU has id 1, so it loads the second state.
+        pause_control.reset_vcpus_on_resume(&[power_on_state.clone(), power_on_state.clone()]);
+        let vcpu_pause_control = pause_control.clone();
+        let thread = thread::spawn(move || {
+            vcpu.park(&vcpu_pause_control).unwrap();
+            vcpu
+        });
+
+        assert!(pause_control.wait_parked(1, Duration::from_secs(1)));
+        // The state saved when parking is the one before the reset.
+        assert_eq!(pause_control.vcpu_states()[&1].regs.rip, regs.rip);
+        pause_control.resume();
+        let vcpu = thread.join().unwrap();
+        assert_eq!(vcpu.fd.get_regs().unwrap().rip, power_on_state.regs.rip);
+        // A vCPU halted by the guest is runnable again.
+        assert_eq!(vcpu.save_state().unwrap().mp_state, power_on_state.mp_state);
+        assert_ne!(power_on_state.mp_state, halted);
+        // The reset states are used up.
+        assert!(pause_control.take_reset_state(1).is_none());
     }
 
     #[test]
@@ -755,7 +1707,11 @@ mod tests {
         };
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
-        let gm = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        let gm = GuestMemory::new(
+            &[(start_addr1, 0x1000), (start_addr2, 0x1000)],
+            MemoryBacking::Anonymous,
+        )
+        .unwrap();
 
         assert!(vm.memory_init(gm, &kvm).is_err());
     }